@@ -0,0 +1,85 @@
+//! Throughput of the output path: a client reading all `cat` prints of a large file, through the
+//! server on localhost, in either encoding
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures_util::{SinkExt, StreamExt};
+use sh_over_ws_actuator::{
+    config::Config,
+    data::{Encoding, Message},
+    server::Server,
+};
+use std::{io::Write, net::TcpListener, path::Path, time::Duration};
+use tempfile::NamedTempFile;
+use tokio::{net::TcpStream, runtime::Runtime, time};
+use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
+use uuid::Uuid;
+
+/// Bytes of the file `cat` prints, about
+const FILE_SIZE: usize = 16 * 1024 * 1024;
+
+fn cat_large_file(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    // lines of text, like a log
+    let mut file = NamedTempFile::new().unwrap();
+    let line = b"the quick brown fox jumps over the lazy dog 0123456789\n";
+    for _ in 0..FILE_SIZE / line.len() {
+        file.write_all(line).unwrap();
+    }
+    file.flush().unwrap();
+    let size = file.as_file().metadata().unwrap().len();
+
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = Config {
+        listen: address,
+        ..Config::default()
+    };
+    runtime.spawn(Server::new(config).run());
+    runtime.block_on(async {
+        while TcpStream::connect(address).await.is_err() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    });
+
+    let mut group = c.benchmark_group("output");
+    group.throughput(Throughput::Bytes(size));
+    group.sample_size(10);
+    for encoding in [Encoding::Json, Encoding::MsgPack] {
+        let url = format!("ws://{}/?encoding={}", address, encoding);
+        group.bench_function(format!("cat large file, {}", encoding), |b| {
+            b.to_async(&runtime).iter(|| cat(&url, file.path()))
+        });
+    }
+    group.finish();
+}
+
+/// Run `cat path` over a new connection to `url`, the number of bytes it output
+async fn cat(url: &str, path: &Path) -> usize {
+    let (mut ws, _) = connect_async(url).await.unwrap();
+    let run = serde_json::json!({
+        "type": "run",
+        "session": Uuid::new_v4(),
+        "program": "/bin/cat",
+        "args": [path],
+    });
+    ws.send(WsMessage::Text(run.to_string())).await.unwrap();
+    let mut output = 0;
+    while let Some(frame) = ws.next().await {
+        let message = match frame.unwrap() {
+            WsMessage::Text(text) => Encoding::Json.decode(text.as_bytes()),
+            WsMessage::Binary(bytes) => Encoding::MsgPack.decode(&bytes),
+            _ => continue,
+        };
+        match message.unwrap() {
+            Message::Output { data, .. } => output += data.0.len(),
+            Message::Exit { .. } => break,
+            Message::Error { error, .. } => panic!("{:?}", error),
+            _ => {},
+        }
+    }
+    output
+}
+
+criterion_group!(benches, cat_large_file);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    {
+        // the service of `grpc`
+        println!("cargo:rerun-if-changed=proto/actuator.proto");
+        let descriptors = protox::compile(["proto/actuator.proto"], ["proto"])?;
+        tonic_prost_build::configure()
+            .build_client(false)
+            .btree_map(".")
+            .compile_fds(descriptors)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,27 @@
+//! Messages as the server decodes them from the frames clients send, which may be anything. Text
+//! frames hold JSON, binary ones MessagePack, compressed with zstd if the client asked for it.
+//! Whatever decodes has to encode again.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sh_over_ws_actuator::data::{Compression, Encoding, Message};
+
+fn decoded(encoding: Encoding, message: Message) {
+    encoding
+        .encode(&message)
+        .expect("decoded messages encode again");
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = Encoding::Json.decode(data) {
+        decoded(Encoding::Json, message);
+    }
+    if let Ok(message) = Encoding::MsgPack.decode(data) {
+        decoded(Encoding::MsgPack, message);
+    }
+    if let Ok(bytes) = Compression::Zstd.decompress(data.to_vec()) {
+        if let Ok(message) = Encoding::MsgPack.decode(&bytes) {
+            decoded(Encoding::MsgPack, message);
+        }
+    }
+});
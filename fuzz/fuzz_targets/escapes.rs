@@ -0,0 +1,42 @@
+//! The filters parsing escape sequences out of the output of commands. Output arrives in reads
+//! cut anywhere, so besides not panicking the filters have to come to the same however it is cut.
+//! The first byte of the input says where to cut the rest.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sh_over_ws_actuator::filter::{
+    InterceptOsc, OutputEvent, OutputFilter, StripAnsi, Utf8Boundaries,
+};
+
+const ESC: u8 = 0x1b;
+
+/// What `filter` makes of `reads` one after another and the events it reported
+fn run(mut filter: impl OutputFilter, reads: &[&[u8]]) -> (Vec<u8>, Vec<OutputEvent>) {
+    let mut output = vec![];
+    let mut events = vec![];
+    for read in reads {
+        output.extend(filter.filter(read));
+        events.extend(filter.events());
+    }
+    output.extend(filter.finish());
+    events.extend(filter.events());
+    (output, events)
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&cut, data)) = data.split_first() else {
+        return;
+    };
+    let (first, second) = data.split_at(data.len() * usize::from(cut) / 255);
+
+    let stripped = run(StripAnsi::default(), &[data]);
+    assert!(!stripped.0.contains(&ESC), "escape left in {:?}", stripped.0);
+    assert_eq!(run(StripAnsi::default(), &[first, second]), stripped);
+
+    for flags in 0..8 {
+        let intercept = || InterceptOsc::new(flags & 1 != 0, flags & 2 != 0, flags & 4 != 0);
+        assert_eq!(run(intercept(), &[first, second]), run(intercept(), &[data]));
+    }
+
+    assert_eq!(run(Utf8Boundaries::default(), &[first, second]).0, data);
+});
@@ -0,0 +1,514 @@
+//! Plain HTTP listener for operators, separate from the WebSocket one so that it can be kept off
+//! the network clients come from. It serves
+//!
+//! - `/metrics`, the [metrics](crate::metrics) in the Prometheus text format
+//! - `/healthz`, answering as long as the server runs, for liveness probes
+//! - `/readyz`, failing with 503 while the server can't take new sessions, for readiness probes
+//!   and load balancers
+//!
+//! Both probes describe the state of the server as JSON, see [`Health`].
+//!
+//! Operators presenting one of the [admin tokens](crate::config::AdminConfig::tokens) can manage
+//! sessions without attaching to them:
+//!
+//! - `GET /sessions` lists all sessions
+//! - `GET /sessions/{id}` describes one of them, see [`SessionInfo`](crate::session::SessionInfo)
+//! - `DELETE /sessions/{id}` kills its command and hangs it up, its clients are told so with
+//!   [`ExitReason::Killed`](crate::data::ExitReason::Killed)
+//! - `GET /sessions/{id}/stream` watches it like a viewer does, whoever opened it, for dashboards
+//!   that can't hold a WebSocket, streaming its output as server-sent events, see
+//!   [`stream_session`]
+//!
+//! Browsers can't add headers to the requests of an `EventSource`, so the token may be given as
+//! `?token=` as well.
+//!
+//! and look up the [job history](crate::history), if the server keeps one:
+//!
+//! - `GET /jobs` lists the latest jobs, `?limit=` and `?since=` an RFC 3339 time narrow it down
+//! - `GET /jobs/{id}` describes one of them along with the tail of its output
+//!
+//! Scripts of web pages whose [origin](crate::config::OriginConfig) the server allows may use
+//! the endpoints too, they get the CORS headers letting them. Requests from pages of other
+//! origins are refused.
+use crate::{
+    audit::{AuditLog, Client},
+    auth::is_known_token,
+    config::Config,
+    data::{Message, SessionId},
+    http,
+    metrics::METRICS,
+    os_io::find_command,
+    session::{SessionManager, SessionNotFound, SessionRegistry},
+};
+use anyhow::{Context, Result};
+use hyper::{
+    body::Bytes,
+    header::{HeaderValue, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, WWW_AUTHENTICATE},
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{Encoder, TextEncoder};
+use serde::Serialize;
+use std::{
+    convert::Infallible, fs, net::SocketAddr, os::unix::fs::PermissionsExt, path::PathBuf,
+    sync::Arc,
+};
+use tokio::{
+    sync::{mpsc, Semaphore},
+    time,
+};
+use tracing::{info, warn};
+
+/// What the admin endpoints serve
+#[derive(Clone)]
+pub struct AdminState {
+    pub config: Arc<Config>,
+    pub registry: Arc<SessionRegistry>,
+    pub audit: Arc<AuditLog>,
+    /// Permits for connections, none are left while the server is full
+    pub connections: Arc<Semaphore>,
+}
+
+/// State of the server as reported by the probes
+#[derive(Debug, Serialize)]
+pub struct Health {
+    /// Address the WebSocket listener is bound to
+    pub listen: SocketAddr,
+    /// Whether another connection would be accepted
+    pub accepting: bool,
+    /// Sessions running, attached or not
+    pub sessions: usize,
+    pub shell: PathBuf,
+    /// Whether the default shell exists and may be executed
+    pub shell_executable: bool,
+}
+
+impl Health {
+    pub fn is_ready(&self) -> bool {
+        self.accepting && self.shell_executable
+    }
+}
+
+impl AdminState {
+    pub fn health(&self) -> Health {
+        Health {
+            listen: self.config.listen,
+            accepting: self.connections.available_permits() > 0,
+            sessions: self.registry.count(),
+            shell: self.config.shell.command.clone(),
+            shell_executable: self.shell_is_executable(),
+        }
+    }
+
+    fn shell_is_executable(&self) -> bool {
+        let shell = &self.config.shell;
+        // chrooted commands are looked up inside the jail
+        let path = match self.config.jail.as_ref() {
+            Some(jail) if jail.chroot => Some(
+                jail.root
+                    .join(shell.command.strip_prefix("/").unwrap_or(&shell.command)),
+            ),
+            _ => find_command(shell),
+        };
+        path.and_then(|path| fs::metadata(path).ok())
+            .is_some_and(|metadata| {
+                metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+            })
+    }
+}
+
+/// Bind the admin listener to `listen` and serve it in the background
+pub fn spawn(listen: SocketAddr, state: AdminState) -> Result<()> {
+    let server = Server::try_bind(&listen)
+        .with_context(|| format!("failed to listen on {}", listen))?
+        .serve(make_service_fn(move |connection: &AddrStream| {
+            let state = state.clone();
+            let peer = connection.remote_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let state = state.clone();
+                    async move { Ok::<_, Infallible>(handle_request(request, peer, &state)) }
+                }))
+            }
+        }));
+    info!("admin endpoints listening on http://{}", listen);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("admin listener failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+fn handle_request(request: Request<Body>, peer: SocketAddr, state: &AdminState) -> Response<Body> {
+    let origins = &state.config.origins;
+    http::cors(
+        request,
+        origins,
+        "GET, DELETE",
+        "authorization, last-event-id",
+        |request| route_request(request, peer, state),
+    )
+}
+
+fn route_request(request: Request<Body>, peer: SocketAddr, state: &AdminState) -> Response<Body> {
+    if let Some(id) = request.uri().path().strip_prefix("/sessions") {
+        return handle_sessions_request(&request, peer, id, state);
+    }
+    #[cfg(feature = "history")]
+    if let Some(id) = request.uri().path().strip_prefix("/jobs") {
+        return handle_jobs_request(&request, id, state);
+    }
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => match METRICS.render() {
+            Ok(metrics) => {
+                let content_type = TextEncoder::new().format_type().to_string();
+                respond(StatusCode::OK, &content_type, metrics)
+            },
+            Err(e) => respond_text(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}\n", e)),
+        },
+        (&Method::GET, "/healthz") => respond_json(StatusCode::OK, &state.health()),
+        (&Method::GET, "/readyz") => {
+            let health = state.health();
+            let status = match health.is_ready() {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            respond_json(status, &health)
+        },
+        _ => respond_text(StatusCode::NOT_FOUND, "not found\n".to_string()),
+    }
+}
+
+/// Serve `/sessions` with the rest of the path, `id`, naming a session if not empty
+fn handle_sessions_request(
+    request: &Request<Body>,
+    peer: SocketAddr,
+    id: &str,
+    state: &AdminState,
+) -> Response<Body> {
+    if let Err(response) = authorize(request, state) {
+        return *response;
+    }
+    if let Some(id) = id.strip_suffix("/stream") {
+        let id = match parse_id(id) {
+            Ok(Some(id)) => id,
+            Ok(None) => return respond_text(StatusCode::NOT_FOUND, "not found\n".to_string()),
+            Err(response) => return *response,
+        };
+        if request.method() != Method::GET {
+            return respond_text(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method not allowed\n".to_string(),
+            );
+        }
+        return stream_session(request, peer, id, state);
+    }
+    let id = match parse_id(id) {
+        Ok(id) => id,
+        Err(response) => return *response,
+    };
+    match (request.method(), id) {
+        (&Method::GET, None) => respond_json(StatusCode::OK, &state.registry.list()),
+        (&Method::GET, Some(id)) => match state.registry.get(id) {
+            Some(session) => respond_json(StatusCode::OK, &session),
+            None => respond_text(StatusCode::NOT_FOUND, "no such session\n".to_string()),
+        },
+        (&Method::DELETE, Some(id)) => match state.registry.kill(id) {
+            Ok(()) => respond_text(StatusCode::ACCEPTED, "killed\n".to_string()),
+            Err(e) if e.is::<SessionNotFound>() => {
+                respond_text(StatusCode::NOT_FOUND, format!("{:#}\n", e))
+            },
+            Err(e) => respond_text(StatusCode::CONFLICT, format!("{:#}\n", e)),
+        },
+        _ => respond_text(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method not allowed\n".to_string(),
+        ),
+    }
+}
+
+/// Watch session `id` for the client at `peer`, streaming what happens to it as server-sent
+/// events until its command exited or the client went away:
+///
+/// ```text
+/// event: output
+/// id: 42
+/// data: $ make
+/// data: cc -o sh-over-ws main.c
+///
+/// event: exit
+/// data: {"type":"exit","session":"0b9e6c2a-5d41-4f3e-8a7b-2c1d9e8f4a3b","code":0}
+/// ```
+///
+/// Output is sent as text, a `data` line per line of it. The `id` of each output event is the
+/// `seq` of the session's output up to its end, clients reconnecting with the `Last-Event-ID`
+/// they saw last get the output that followed, as far as the scrollback still holds it.
+fn stream_session(
+    request: &Request<Body>,
+    peer: SocketAddr,
+    id: SessionId,
+    state: &AdminState,
+) -> Response<Body> {
+    let seq = match request.headers().get("last-event-id") {
+        Some(seq) => match seq.to_str().ok().and_then(|seq| seq.parse().ok()) {
+            Some(seq) => seq,
+            None => return respond_text(StatusCode::BAD_REQUEST, "invalid event id\n".to_string()),
+        },
+        None => 0,
+    };
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    // viewers never start commands
+    let (due_tx, _) = mpsc::unbounded_channel();
+    let sessions = SessionManager::new(
+        state.config.clone(),
+        state.registry.clone(),
+        state.audit.clone(),
+        Client::new(peer),
+        events_tx,
+        due_tx,
+    );
+    if let Err(e) = sessions.watch(id, seq) {
+        let status = match e.chain().any(|cause| cause.is::<SessionNotFound>()) {
+            true => StatusCode::NOT_FOUND,
+            false => StatusCode::CONFLICT,
+        };
+        return respond_text(status, format!("{:#}\n", e));
+    }
+
+    let (mut body, response) = Body::channel();
+    let keepalive = state.config.keepalive.interval();
+    tokio::spawn(async move {
+        let backlog = sessions.backlog();
+        let mut keepalive = time::interval_at(time::Instant::now() + keepalive, keepalive);
+        loop {
+            tokio::select! {
+                Some(message) = events_rx.recv() => {
+                    let len = message.data_len();
+                    let (event, done) = match &message {
+                        Message::Output { data, seq, .. } => {
+                            (Some(output_event(*seq, &data.0)), false)
+                        },
+                        Message::Exit { .. } => (Some(json_event("exit", &message)), true),
+                        _ => (None, false),
+                    };
+                    if let Some(event) = event {
+                        if body.send_data(Bytes::from(event)).await.is_err() {
+                            break;
+                        }
+                    }
+                    backlog.sent(len);
+                    if done {
+                        break;
+                    }
+                },
+                // comments keep proxies from giving up on quiet sessions, and tell when the client
+                // went away
+                _ = keepalive.tick() => {
+                    let comment = Bytes::from_static(b": keepalive\n\n");
+                    if body.send_data(comment).await.is_err() {
+                        break;
+                    }
+                },
+            }
+        }
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(response)
+        .expect("valid response")
+}
+
+/// Output of a session up to `seq` as event, with a `data` line per line of `data`
+fn output_event(seq: u64, data: &[u8]) -> String {
+    let text = String::from_utf8_lossy(data).replace("\r\n", "\n");
+    let mut event = format!("event: output\nid: {}\n", seq);
+    // either ends a line of an event stream
+    for line in text.split(['\r', '\n']) {
+        event.push_str("data: ");
+        event.push_str(line);
+        event.push('\n');
+    }
+    event.push('\n');
+    event
+}
+
+/// `message` as event of type `name`, encoded as JSON
+fn json_event(name: &str, message: &Message) -> String {
+    // serializing a message doesn't fail
+    let json = serde_json::to_string(message).unwrap_or_default();
+    format!("event: {}\ndata: {}\n\n", name, json)
+}
+
+/// Serve `/jobs` with the rest of the path, `id`, naming a job if not empty
+#[cfg(feature = "history")]
+fn handle_jobs_request(request: &Request<Body>, id: &str, state: &AdminState) -> Response<Body> {
+    let Some(history) = state.registry.history() else {
+        return respond_text(StatusCode::NOT_FOUND, "not found\n".to_string());
+    };
+    if let Err(response) = authorize(request, state) {
+        return *response;
+    }
+    let id = match parse_id(id) {
+        Ok(id) => id,
+        Err(response) => return *response,
+    };
+    if request.method() != Method::GET {
+        return respond_text(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method not allowed\n".to_string(),
+        );
+    }
+    let result = match id {
+        Some(id) => history.get(id, None).map(|job| match job {
+            Some(job) => respond_json(StatusCode::OK, &job),
+            None => respond_text(StatusCode::NOT_FOUND, "no such job\n".to_string()),
+        }),
+        None => {
+            let mut limit = None;
+            let mut since = None;
+            for (name, value) in query(request) {
+                match name.as_str() {
+                    "limit" => match value.parse() {
+                        Ok(value) => limit = Some(value),
+                        Err(e) => {
+                            let message = format!("invalid limit: {}\n", e);
+                            return respond_text(StatusCode::BAD_REQUEST, message);
+                        },
+                    },
+                    "since" => match humantime::parse_rfc3339_weak(&value) {
+                        Ok(value) => since = Some(value),
+                        Err(e) => {
+                            let message = format!("invalid time: {}\n", e);
+                            return respond_text(StatusCode::BAD_REQUEST, message);
+                        },
+                    },
+                    _ => {},
+                }
+            }
+            history
+                .list(None, since, limit)
+                .map(|jobs| respond_json(StatusCode::OK, &jobs))
+        },
+    };
+    result.unwrap_or_else(|e| respond_text(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}\n", e)))
+}
+
+/// The parameters of the query string of `request`, percent-decoded
+fn query(request: &Request<Body>) -> Vec<(String, String)> {
+    let decode = |text: &str| {
+        let bytes = text.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let escaped = bytes
+                .get(i + 1..i + 3)
+                .filter(|_| bytes[i] == b'%')
+                .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+            match escaped {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 3;
+                },
+                None => {
+                    decoded.push(match bytes[i] {
+                        b'+' => b' ',
+                        byte => byte,
+                    });
+                    i += 1;
+                },
+            }
+        }
+        String::from_utf8_lossy(&decoded).into_owned()
+    };
+    request
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|parameter| parameter.split_once('='))
+        .map(|(name, value)| (decode(name), decode(value)))
+        .collect()
+}
+
+/// Check that `request` presents one of the admin tokens, the response to send otherwise
+fn authorize(request: &Request<Body>, state: &AdminState) -> Result<(), Box<Response<Body>>> {
+    let tokens = state
+        .config
+        .admin
+        .as_ref()
+        .map_or(&[][..], |admin| &admin.tokens[..]);
+    if tokens.is_empty() {
+        return Err(Box::new(respond_text(
+            StatusCode::NOT_FOUND,
+            "not found\n".to_string(),
+        )));
+    }
+    let bearer = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let presented = bearer.or_else(|| {
+        query(request)
+            .into_iter()
+            .find_map(|(name, value)| (name == "token").then_some(value))
+    });
+    if !presented.is_some_and(|presented| is_known_token(tokens, &presented)) {
+        warn!(
+            "rejecting admin request for {}, not authorized",
+            request.uri()
+        );
+        let mut response = respond_text(
+            StatusCode::UNAUTHORIZED,
+            "missing or invalid token\n".to_string(),
+        );
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return Err(Box::new(response));
+    }
+    Ok(())
+}
+
+/// The session named by the rest of a path, `None` for the path itself, the response to send if
+/// it names none
+fn parse_id(id: &str) -> Result<Option<SessionId>, Box<Response<Body>>> {
+    match id.strip_prefix('/') {
+        None if id.is_empty() => Ok(None),
+        Some(id) if !id.is_empty() => match id.parse::<SessionId>() {
+            Ok(id) => Ok(Some(id)),
+            Err(e) => Err(Box::new(respond_text(
+                StatusCode::BAD_REQUEST,
+                format!("invalid session id: {}\n", e),
+            ))),
+        },
+        _ => Err(Box::new(respond_text(
+            StatusCode::NOT_FOUND,
+            "not found\n".to_string(),
+        ))),
+    }
+}
+
+fn respond_json(status: StatusCode, value: &impl Serialize) -> Response<Body> {
+    match serde_json::to_string(value) {
+        Ok(json) => respond(status, "application/json", json + "\n"),
+        Err(e) => respond_text(StatusCode::INTERNAL_SERVER_ERROR, format!("{}\n", e)),
+    }
+}
+
+fn respond_text(status: StatusCode, body: String) -> Response<Body> {
+    respond(status, "text/plain; charset=utf-8", body)
+}
+
+fn respond(status: StatusCode, content_type: &str, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .expect("valid response")
+}
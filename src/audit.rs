@@ -0,0 +1,301 @@
+//! Audit trail of the commands clients ran. Every session leaves a [`Record`] when its command
+//! is started and another one when it exited, input broadcast to several sessions at once
+//! leaves a [`BroadcastRecord`], clients [locked out](crate::auth::Lockout) for failing to
+//! authenticate leave a [`LockoutRecord`]. Records are written as one JSON object per line to a
+//! file, to syslog, or both.
+use crate::{
+    auth::Identity, command::RunCommand, config::AuditConfig, data::SessionId,
+    redact::RedactionConfig,
+};
+use anyhow::{Context, Result};
+use ring::digest::{digest, SHA256};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    os::unix::net::UnixDatagram,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use tracing::warn;
+
+/// Socket the local syslog daemon receives messages on
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// Facility `authpriv`, severity `info`
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 6;
+
+/// Who is on the other end of a connection
+#[derive(Clone, Debug, Serialize)]
+pub struct Client {
+    pub address: SocketAddr,
+    /// Digest of the token the client authenticated with, the token itself stays secret
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// SHA-256 fingerprint of the certificate the client presented
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<String>,
+    /// User the client authenticated as, if its token names one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Claims of the JWT the client authenticated with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub claims: Option<Map<String, Value>>,
+}
+
+impl Client {
+    pub fn new(address: SocketAddr) -> Self {
+        Client {
+            address,
+            token: None,
+            certificate: None,
+            user: None,
+            claims: None,
+        }
+    }
+
+    /// Identify the client by `token`, by its first 64 bits of SHA-256 which tell tokens apart
+    /// without giving any away
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(format!(
+            "sha256:{}",
+            &hex(digest(&SHA256, token.as_bytes()))[..16]
+        ));
+        self
+    }
+
+    /// Take the user and claims the client authenticated with along
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.user = identity.user;
+        self.claims = identity.claims;
+        self
+    }
+
+    /// What tells this client apart from others: its certificate, else its user, else its token,
+    /// else its IP address
+    pub fn identity(&self) -> String {
+        self.certificate
+            .clone()
+            .or_else(|| self.user.clone())
+            .or_else(|| self.token.clone())
+            .unwrap_or_else(|| self.address.ip().to_string())
+    }
+
+    /// Identify the client by the DER encoded certificate it presented
+    pub fn with_certificate(mut self, der: &[u8]) -> Self {
+        self.certificate = Some(format!("sha256:{}", hex(digest(&SHA256, der))));
+        self
+    }
+}
+
+/// Lowercase hex encoding of `digest`
+pub(crate) fn hex(digest: impl AsRef<[u8]>) -> String {
+    digest
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Started,
+    Exited,
+    Broadcast,
+    LockedOut,
+}
+
+/// What happened to a session
+#[derive(Debug, Serialize)]
+pub struct Record<'a> {
+    pub event: Event,
+    pub session: SessionId,
+    /// The client that opened the session
+    pub client: &'a Client,
+    pub command: String,
+    #[serde(serialize_with = "serialize_time")]
+    pub started_at: SystemTime,
+    #[serde(
+        serialize_with = "serialize_optional_time",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ended_at: Option<SystemTime>,
+    /// `None` if the command was killed by a signal or hasn't exited yet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// The signal that killed the command, if one did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+    /// Bytes the clients wrote to the session
+    pub bytes_in: u64,
+    /// Bytes the command output
+    pub bytes_out: u64,
+}
+
+impl<'a> Record<'a> {
+    /// Record of session `id` running `command` for `client` having been started
+    pub fn started(
+        id: SessionId,
+        client: &'a Client,
+        command: &RunCommand,
+        at: SystemTime,
+    ) -> Self {
+        Record {
+            event: Event::Started,
+            session: id,
+            client,
+            command: command.to_string(),
+            started_at: at,
+            ended_at: None,
+            exit_code: None,
+            signal: None,
+            bytes_in: 0,
+            bytes_out: 0,
+        }
+    }
+}
+
+/// Input a client wrote to several sessions at once. What it wrote isn't recorded, it may be a
+/// password as well as a command.
+#[derive(Debug, Serialize)]
+pub struct BroadcastRecord<'a> {
+    pub event: Event,
+    pub sessions: &'a [SessionId],
+    pub client: &'a Client,
+    pub bytes: usize,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+}
+
+impl<'a> BroadcastRecord<'a> {
+    pub fn new(sessions: &'a [SessionId], client: &'a Client, bytes: usize) -> Self {
+        BroadcastRecord {
+            event: Event::Broadcast,
+            sessions,
+            client,
+            bytes,
+            at: SystemTime::now(),
+        }
+    }
+}
+
+/// A client that failed to authenticate too often having been locked out
+#[derive(Debug, Serialize)]
+pub struct LockoutRecord<'a> {
+    pub event: Event,
+    pub client: &'a Client,
+    /// Failures in a row that got it locked out
+    pub failures: u32,
+    /// Seconds it is locked out for
+    pub seconds: u64,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+}
+
+impl<'a> LockoutRecord<'a> {
+    pub fn new(client: &'a Client, failures: u32, duration: Duration) -> Self {
+        LockoutRecord {
+            event: Event::LockedOut,
+            client,
+            failures,
+            seconds: duration.as_secs(),
+            at: SystemTime::now(),
+        }
+    }
+}
+
+pub(crate) fn serialize_time<S: serde::Serializer>(
+    time: &SystemTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&humantime::format_rfc3339_millis(*time))
+}
+
+fn serialize_optional_time<S: serde::Serializer>(
+    time: &Option<SystemTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => serialize_time(time, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Where records go, see [`AuditConfig`]
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+    syslog: Option<UnixDatagram>,
+    /// Scrubs secrets from records, say from the commands run
+    redaction: RedactionConfig,
+}
+
+impl AuditLog {
+    /// Open the sinks `config` asks for, a log that discards everything if none. What
+    /// `redaction` asks for is scrubbed from records.
+    pub fn open(config: &AuditConfig, redaction: &RedactionConfig) -> Result<Self> {
+        let file = match config.file.as_ref() {
+            Some(path) => {
+                let err_context = || format!("failed to open audit log '{}'", path.display());
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(err_context)?;
+                Some(Mutex::new(file))
+            },
+            None => None,
+        };
+        let syslog = match config.syslog {
+            true => {
+                let err_context = || format!("failed to connect to syslog at {}", SYSLOG_SOCKET);
+                let socket = UnixDatagram::unbound().with_context(err_context)?;
+                socket.connect(SYSLOG_SOCKET).with_context(err_context)?;
+                Some(socket)
+            },
+            false => None,
+        };
+        Ok(AuditLog {
+            file,
+            syslog,
+            redaction: redaction.clone(),
+        })
+    }
+
+    /// Write `record` to every sink. Failing to is logged but doesn't stop the session, the
+    /// audit trail having a gap is better than the server falling over.
+    pub fn record(&self, record: &impl Serialize) {
+        if self.file.is_none() && self.syslog.is_none() {
+            return;
+        }
+        let line = match self.redaction.patterns.is_empty() {
+            true => serde_json::to_string(record),
+            false => serde_json::to_value(record)
+                .and_then(|record| serde_json::to_string(&self.redaction.redact_json(record))),
+        };
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("failed to encode audit record: {}", e);
+                return;
+            },
+        };
+        if let Some(file) = self.file.as_ref() {
+            let written = file
+                .lock()
+                .map_err(|_| std::io::Error::other("audit log poisoned"))
+                .and_then(|mut file| writeln!(file, "{}", line));
+            if let Err(e) = written {
+                warn!("failed to write audit record: {}", e);
+            }
+        }
+        if let Some(syslog) = self.syslog.as_ref() {
+            let message = format!("<{}>shws: {}", SYSLOG_PRIORITY, line);
+            if let Err(e) = syslog.send(message.as_bytes()) {
+                warn!("failed to send audit record to syslog: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,705 @@
+//! Authentication of clients. Clients present a token with their upgrade request, either as
+//! `Authorization: Bearer <token>` header or as `token` query parameter, which the
+//! [`Authenticator`] selected by the [configuration](crate::config::AuthConfig) checks:
+//!
+//! - [`StaticTokens`] looks it up in a list of tokens
+//! - [`Jwt`] validates it as JSON Web Token signed with HS256 or RS256 and takes its claims along
+//! - [`ExternalCommand`] leaves the decision to another program, for PAM and similar setups
+//! - a [plugin](crate::plugin) decides, if the server is built with the `plugins` feature
+//!
+//! ```toml
+//! [auth]
+//! backend = "jwt"
+//!
+//! [auth.jwt]
+//! algorithm = "RS256"
+//! public_key = "/etc/shws/jwt.pem"
+//! issuer = "https://sso.example.com"
+//! audience = "shws"
+//! ```
+//!
+//! ```toml
+//! [auth]
+//! backend = "command"
+//!
+//! [auth.command]
+//! command = "/usr/local/libexec/shws-check-token"
+//! timeout = 5
+//! ```
+//!
+//! Browsers can't set headers on WebSocket connections, and tokens in URLs end up in logs and
+//! histories. If [`ticket_ttl`](crate::config::AuthConfig::ticket_ttl) is set, clients can trade
+//! their token for a [ticket](Tickets) instead, which is good for a single connection from the
+//! same address within a few seconds:
+//!
+//! ```text
+//! POST /tickets HTTP/1.1
+//! Authorization: Bearer s3cr3t
+//!
+//! HTTP/1.1 200 OK
+//! {"ticket":"kq3C0l_8v1Jx7T0h9Vq3yYkA9kz5uXH0V2e5SLd2c4M","expires_in":10}
+//! ```
+//!
+//! and connect to `wss://shws.example.com/?ticket=kq3C0l_8v1Jx7T0h9Vq3yYkA9kz5uXH0V2e5SLd2c4M`.
+//!
+//! Tokens can be guessed by trying one after the other. With `[auth.lockout]` a client that
+//! failed to authenticate `max_failures` times in a row is turned away without its token being
+//! checked for `duration` seconds, twice as long each time again up to `max_duration`, see
+//! [`Lockout`]. Lockouts are counted and go to the [audit trail](crate::audit).
+//!
+//! ```toml
+//! [auth.lockout]
+//! max_failures = 5
+//! duration = 60
+//! max_duration = 3600
+//! ```
+use crate::{
+    audit::{AuditLog, Client, LockoutRecord},
+    config::{AuthBackend, Config},
+    metrics::METRICS,
+};
+#[cfg(feature = "plugins")]
+use crate::plugin::{Plugin, PluginAuthenticator};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+    signature::{UnparsedPublicKey, RSA_PKCS1_2048_8192_SHA256},
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+use tracing::warn;
+
+/// How often a running [`ExternalCommand`] is checked for having exited
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Who a client authenticated as, kept with everything it does
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Identity {
+    /// User the token belongs to, if it names one
+    pub user: Option<String>,
+    /// Claims of the JWT the client authenticated with
+    pub claims: Option<Map<String, Value>>,
+    /// When the token stops granting access, its connection is closed then
+    pub expires: Option<SystemTime>,
+}
+
+/// Decides whether a token grants access to the server
+pub trait Authenticator: Send + Sync {
+    /// Who `token`, presented by the client at `address`, belongs to, an error if it doesn't
+    /// grant access. May block while checking it.
+    fn authenticate(&self, token: &str, address: SocketAddr) -> Result<Identity>;
+}
+
+/// The authenticator the `[auth]` section of `config` selects, `None` if anybody may connect
+pub fn authenticator(config: &Config) -> Result<Option<Box<dyn Authenticator>>> {
+    let auth = &config.auth;
+    let err_context = || format!("failed to set up {} authentication", auth.backend);
+    let missing = |table| anyhow!("[auth.{}] is missing", table);
+
+    Ok(match auth.backend {
+        AuthBackend::Token if auth.tokens.is_empty() => None,
+        AuthBackend::Token => Some(Box::new(StaticTokens::new(auth.tokens.clone()))),
+        AuthBackend::Jwt => {
+            let jwt = auth.jwt.as_ref().ok_or_else(|| missing("jwt"));
+            Some(Box::new(jwt.and_then(Jwt::new).with_context(err_context)?))
+        },
+        AuthBackend::Command => {
+            let command = auth.command.clone().ok_or_else(|| missing("command"));
+            Some(Box::new(
+                command
+                    .map(ExternalCommand::new)
+                    .with_context(err_context)?,
+            ))
+        },
+        #[cfg(feature = "plugins")]
+        AuthBackend::Plugin => {
+            let name = auth
+                .plugin
+                .as_deref()
+                .ok_or_else(|| anyhow!("auth.plugin is missing"))
+                .with_context(err_context)?;
+            let plugin = config
+                .plugins
+                .get(name)
+                .ok_or_else(|| anyhow!("no plugin named '{}'", name))
+                .and_then(|plugin| Plugin::load(name, plugin))
+                .with_context(err_context)?;
+            Some(Box::new(
+                PluginAuthenticator::new(&plugin).with_context(err_context)?,
+            ))
+        },
+        #[cfg(not(feature = "plugins"))]
+        AuthBackend::Plugin => {
+            return Err(anyhow!("the server is built without plugin support"))
+                .with_context(err_context)
+        },
+    })
+}
+
+/// Accepts the tokens of a fixed list
+pub struct StaticTokens {
+    tokens: Vec<String>,
+}
+
+impl StaticTokens {
+    pub fn new(tokens: Vec<String>) -> Self {
+        StaticTokens { tokens }
+    }
+}
+
+impl Authenticator for StaticTokens {
+    fn authenticate(&self, token: &str, _address: SocketAddr) -> Result<Identity> {
+        match is_known_token(&self.tokens, token) {
+            true => Ok(Identity::default()),
+            false => Err(anyhow!("unknown token")),
+        }
+    }
+}
+
+/// Whether `presented` is one of `tokens`. Their MACs are compared in constant time, all of
+/// them, so that how long it takes tells nothing about how close a guess came.
+pub(crate) fn is_known_token(tokens: &[String], presented: &str) -> bool {
+    // the key only has to make the tags as long as each other
+    let key = hmac::Key::new(hmac::HMAC_SHA256, &[]);
+    let presented = hmac::sign(&key, presented.as_bytes());
+    tokens.iter().fold(false, |found, known| {
+        found | hmac::verify(&key, known.as_bytes(), presented.as_ref()).is_ok()
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum JwtAlgorithm {
+    /// HMAC with SHA-256, tokens are signed with a secret shared with the server
+    #[serde(rename = "HS256")]
+    Hs256,
+    /// RSA PKCS#1 v1.5 with SHA-256, tokens are signed with a private key the server only knows
+    /// the public half of
+    #[serde(rename = "RS256")]
+    Rs256,
+}
+
+impl JwtAlgorithm {
+    /// Name of the algorithm in the `alg` header of tokens
+    fn name(self) -> &'static str {
+        match self {
+            JwtAlgorithm::Hs256 => "HS256",
+            JwtAlgorithm::Rs256 => "RS256",
+        }
+    }
+}
+
+/// How [`Jwt`] validates tokens
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    /// The one algorithm tokens may be signed with, whatever their header says
+    pub algorithm: JwtAlgorithm,
+    /// Secret HS256 tokens are signed with
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// PEM file with the RSA public key RS256 tokens are verified with
+    #[serde(default)]
+    pub public_key: Option<PathBuf>,
+    /// The `iss` claim tokens must have, if any
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// A value the `aud` claim of tokens must have, if any
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// Seconds the clocks of the server and of the token issuer may be apart
+    #[serde(default)]
+    pub leeway: u64,
+}
+
+enum JwtKey {
+    Hmac(hmac::Key),
+    /// DER encoded RSAPublicKey
+    Rsa(Vec<u8>),
+}
+
+/// Accepts JSON Web Tokens with a valid signature that haven't expired. The user is taken from
+/// the `sub` claim, all claims are kept with the [`Identity`].
+pub struct Jwt {
+    algorithm: JwtAlgorithm,
+    key: JwtKey,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: u64,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+impl Jwt {
+    pub fn new(config: &JwtConfig) -> Result<Self> {
+        let key = match config.algorithm {
+            JwtAlgorithm::Hs256 => {
+                let secret = config
+                    .secret
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("HS256 needs a secret"))?;
+                JwtKey::Hmac(hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()))
+            },
+            JwtAlgorithm::Rs256 => {
+                let path = config
+                    .public_key
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("RS256 needs a public key"))?;
+                JwtKey::Rsa(load_rsa_public_key(path)?)
+            },
+        };
+        Ok(Jwt {
+            algorithm: config.algorithm,
+            key,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            leeway: config.leeway,
+        })
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<()> {
+        let verified = match &self.key {
+            JwtKey::Hmac(key) => hmac::verify(key, message, signature).is_ok(),
+            JwtKey::Rsa(key) => UnparsedPublicKey::new(&RSA_PKCS1_2048_8192_SHA256, key)
+                .verify(message, signature)
+                .is_ok(),
+        };
+        match verified {
+            true => Ok(()),
+            false => Err(anyhow!("invalid signature")),
+        }
+    }
+
+    /// Check the registered claims among `claims`
+    fn check_claims(&self, claims: &Map<String, Value>) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let time = |name| match claims.get(name) {
+            None => Ok(None),
+            Some(value) => value
+                .as_f64()
+                .map(|time| Some(time as u64))
+                .ok_or_else(|| anyhow!("'{}' is not a time", name)),
+        };
+        if time("exp")?.is_some_and(|exp| now > exp.saturating_add(self.leeway)) {
+            bail!("token expired");
+        }
+        if time("nbf")?.is_some_and(|nbf| now.saturating_add(self.leeway) < nbf) {
+            bail!("token not valid yet");
+        }
+        if let Some(issuer) = self.issuer.as_deref() {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                bail!("token not issued by '{}'", issuer);
+            }
+        }
+        if let Some(audience) = self.audience.as_deref() {
+            let intended = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(aud)) => aud.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !intended {
+                bail!("token not meant for '{}'", audience);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Authenticator for Jwt {
+    fn authenticate(&self, token: &str, _address: SocketAddr) -> Result<Identity> {
+        let err_context = || "invalid JWT";
+
+        let (signed, signature) = token
+            .rsplit_once('.')
+            .ok_or_else(|| anyhow!("not a JWT"))
+            .with_context(err_context)?;
+        let (header, claims) = signed
+            .split_once('.')
+            .ok_or_else(|| anyhow!("not a JWT"))
+            .with_context(err_context)?;
+        // trusting the header would let tokens pick an algorithm that is easier to forge
+        let header: JwtHeader = decode_part(header).with_context(err_context)?;
+        if header.alg != self.algorithm.name() {
+            return Err(anyhow!(
+                "signed with {} instead of {}",
+                header.alg,
+                self.algorithm.name()
+            ))
+            .with_context(err_context);
+        }
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .context("malformed signature")
+            .with_context(err_context)?;
+        self.verify(signed.as_bytes(), &signature)
+            .with_context(err_context)?;
+
+        let claims: Map<String, Value> = decode_part(claims).with_context(err_context)?;
+        self.check_claims(&claims).with_context(err_context)?;
+        let expires = claims
+            .get("exp")
+            .and_then(Value::as_f64)
+            .map(|exp| UNIX_EPOCH + Duration::from_secs((exp as u64).saturating_add(self.leeway)));
+        Ok(Identity {
+            user: claims
+                .get("sub")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            claims: Some(claims),
+            expires,
+        })
+    }
+}
+
+/// Decode the base64url encoded JSON `part` of a token
+fn decode_part<T: DeserializeOwned>(part: &str) -> Result<T> {
+    let json = URL_SAFE_NO_PAD.decode(part).context("malformed base64")?;
+    serde_json::from_slice(&json).context("malformed JSON")
+}
+
+/// Read the RSA public key from the PEM file at `path`, as ring wants it
+fn load_rsa_public_key(path: &Path) -> Result<Vec<u8>> {
+    let err_context = || format!("failed to load public key from '{}'", path.display());
+
+    let mut reader = BufReader::new(File::open(path).with_context(err_context)?);
+    let spki = rustls_pemfile::public_keys(&mut reader)
+        .next()
+        .ok_or_else(|| anyhow!("no public key found"))
+        .with_context(err_context)?
+        .with_context(err_context)?;
+    rsa_public_key(spki.as_ref())
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| anyhow!("not an RSA public key"))
+        .with_context(err_context)
+}
+
+/// The RSAPublicKey within the DER encoded SubjectPublicKeyInfo `spki`
+fn rsa_public_key(spki: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const BIT_STRING: u8 = 0x03;
+    let (spki, _) = der_element(spki, SEQUENCE)?;
+    let (_algorithm, rest) = der_element(spki, SEQUENCE)?;
+    let (key, _) = der_element(rest, BIT_STRING)?;
+    // bit strings start with the number of unused bits in their last byte
+    key.strip_prefix(&[0])
+}
+
+/// The content of the DER element with `tag` that `data` starts with, and what follows it
+fn der_element(data: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&first, rest) = data.split_first()?;
+    if first != tag {
+        return None;
+    }
+    let (&len, rest) = rest.split_first()?;
+    let (len, rest) = match len {
+        0..=0x7f => (len as usize, rest),
+        // long form, the length follows in as many bytes
+        0x81..=0x84 => {
+            let (len, rest) = rest.split_at_checked(usize::from(len & 0x7f))?;
+            let len = len
+                .iter()
+                .fold(0, |len, &byte| len << 8 | usize::from(byte));
+            (len, rest)
+        },
+        _ => return None,
+    };
+    rest.split_at_checked(len)
+}
+
+/// Which program [`ExternalCommand`] runs
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthCommand {
+    pub command: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Seconds the program may take to decide, it is killed and the client refused after
+    #[serde(default = "default_auth_command_timeout")]
+    pub timeout: u64,
+}
+
+fn default_auth_command_timeout() -> u64 {
+    5
+}
+
+/// Leaves the decision to a program, which is run for every connection with the token on its
+/// stdin, followed by a newline, and the address of the client in `SHWS_CLIENT_ADDRESS`. It
+/// accepts the token by exiting with 0, and may name the user it belongs to on the first line of
+/// its stdout.
+pub struct ExternalCommand {
+    command: AuthCommand,
+}
+
+impl ExternalCommand {
+    pub fn new(command: AuthCommand) -> Self {
+        ExternalCommand { command }
+    }
+}
+
+impl Authenticator for ExternalCommand {
+    fn authenticate(&self, token: &str, address: SocketAddr) -> Result<Identity> {
+        let program = self.command.command.display();
+        let err_context = || format!("failed to run '{}'", program);
+
+        let mut child = Command::new(&self.command.command)
+            .args(&self.command.args)
+            .env("SHWS_CLIENT_ADDRESS", address.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(err_context)?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // programs that decide without reading the token may have closed their stdin
+            let _ = writeln!(stdin, "{}", token);
+        }
+        // read while the program runs, it blocks once it filled the pipe otherwise
+        let (output_tx, output_rx) = mpsc::channel();
+        if let Some(mut stdout) = child.stdout.take() {
+            thread::spawn(move || {
+                let mut output = String::new();
+                let _ = output_tx.send(stdout.read_to_string(&mut output).map(|_| output));
+            });
+        }
+        let deadline = Instant::now() + Duration::from_secs(self.command.timeout);
+        let status = loop {
+            if let Some(status) = child.try_wait().with_context(err_context)? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                bail!("'{}' took longer than {}s", program, self.command.timeout);
+            }
+            thread::sleep(COMMAND_POLL_INTERVAL);
+        };
+        if !status.success() {
+            bail!("refused by '{}', {}", program, status);
+        }
+        // whatever the program left running may hold on to its stdout
+        let left = deadline.saturating_duration_since(Instant::now());
+        let output = match output_rx.recv_timeout(left) {
+            Ok(output) => output.with_context(err_context)?,
+            Err(RecvTimeoutError::Timeout) => {
+                bail!("output of '{}' didn't end within {}s", program, self.command.timeout)
+            },
+            Err(RecvTimeoutError::Disconnected) => String::new(),
+        };
+        let user = output.lines().next().map(str::trim).unwrap_or_default();
+        Ok(Identity {
+            user: (!user.is_empty()).then(|| user.to_string()),
+            claims: None,
+            expires: None,
+        })
+    }
+}
+
+/// One-time tickets standing in for the token of a client, see the [module docs](self)
+pub struct Tickets {
+    ttl: Duration,
+    issued: Mutex<HashMap<String, Ticket>>,
+}
+
+struct Ticket {
+    token: String,
+    identity: Identity,
+    /// Only a client at this address may redeem it
+    address: IpAddr,
+    expires: time::Instant,
+}
+
+impl Tickets {
+    pub fn new(ttl: Duration) -> Self {
+        Tickets {
+            ttl,
+            issued: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long tickets stay valid
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Issue a ticket to the client at `address` for `token`, which authenticated it as
+    /// `identity`
+    pub fn issue(&self, token: &str, identity: Identity, address: IpAddr) -> Result<String> {
+        let mut bytes = [0; 32];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(|_| anyhow!("failed to generate ticket"))?;
+        let ticket = URL_SAFE_NO_PAD.encode(bytes);
+        let now = time::Instant::now();
+        let mut issued = self.issued.lock().unwrap();
+        issued.retain(|_, ticket| ticket.expires > now);
+        issued.insert(
+            ticket.clone(),
+            Ticket {
+                token: token.to_string(),
+                identity,
+                address,
+                expires: now + self.ttl,
+            },
+        );
+        Ok(ticket)
+    }
+
+    /// The token and identity `ticket` stands for if it was issued to the client at `address`
+    /// and is still valid. It can't be redeemed again, whether it was valid or not.
+    pub fn redeem(&self, ticket: &str, address: IpAddr) -> Result<(String, Identity)> {
+        let ticket = self
+            .issued
+            .lock()
+            .unwrap()
+            .remove(ticket)
+            .ok_or_else(|| anyhow!("unknown ticket"))?;
+        if ticket.expires <= time::Instant::now() {
+            bail!("ticket expired");
+        }
+        if ticket.address != address {
+            bail!("ticket was issued to {}", ticket.address);
+        }
+        Ok((ticket.token, ticket.identity))
+    }
+}
+
+/// When clients failing to authenticate are locked out, see [`Lockout`]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutConfig {
+    /// Failures in a row after which a client is locked out
+    pub max_failures: u32,
+    /// Seconds the first lockout of a client lasts, each one following it twice as long
+    pub duration: u64,
+    /// Seconds a lockout lasts at most. Clients that didn't fail for as long since their last
+    /// failure or lockout start over.
+    pub max_duration: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            max_failures: 5,
+            duration: 60,
+            max_duration: 3600,
+        }
+    }
+}
+
+/// Clients that failed to authenticate too often, by their certificate if they presented one
+/// and by their IP address otherwise. Guessing tokens gets slower with every lockout.
+pub struct Lockout {
+    config: LockoutConfig,
+    clients: Mutex<HashMap<String, Failures>>,
+}
+
+struct Failures {
+    /// Failures since the last lockout
+    count: u32,
+    /// Lockouts so far, each lasts twice as long as the one before
+    lockouts: u32,
+    last: time::Instant,
+    locked_until: Option<time::Instant>,
+}
+
+impl Lockout {
+    pub fn new(config: LockoutConfig) -> Self {
+        Lockout {
+            config,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether `client` may try to authenticate, an error telling until when it is locked
+    /// out otherwise
+    pub fn check(&self, client: &Client) -> Result<()> {
+        let now = time::Instant::now();
+        if let Some(Failures {
+            locked_until: Some(until),
+            ..
+        }) = self.clients.lock().unwrap().get(&client.identity())
+        {
+            if *until > now {
+                bail!("locked out for another {}s", (*until - now).as_secs() + 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a failure of `client` to authenticate, locking it out if that was one too many
+    pub fn fail(&self, client: &Client, audit: &AuditLog) {
+        let now = time::Instant::now();
+        let forget_after = Duration::from_secs(self.config.max_duration);
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|_, failures| {
+            // time spent locked out is no time without failing
+            let quiet_since = failures.locked_until.map_or(failures.last, |until| {
+                until.max(failures.last)
+            });
+            now.saturating_duration_since(quiet_since) < forget_after
+        });
+        let failures = clients.entry(client.identity()).or_insert(Failures {
+            count: 0,
+            lockouts: 0,
+            last: now,
+            locked_until: None,
+        });
+        failures.count += 1;
+        failures.last = now;
+        if failures.count < self.config.max_failures {
+            return;
+        }
+        let factor = 2u64.saturating_pow(failures.lockouts);
+        let duration = self
+            .config
+            .duration
+            .saturating_mul(factor)
+            .min(self.config.max_duration);
+        let failed = failures.count;
+        failures.count = 0;
+        failures.lockouts += 1;
+        failures.locked_until = Some(now + Duration::from_secs(duration));
+        drop(clients);
+        warn!(
+            "locking out {} for {}s after {} failures to authenticate",
+            client.identity(),
+            duration,
+            failed
+        );
+        METRICS.lockouts.inc();
+        audit.record(&LockoutRecord::new(
+            client,
+            failed,
+            Duration::from_secs(duration),
+        ));
+    }
+
+    /// Forget the failures of `client`, which just authenticated. Its lockouts are kept, having
+    /// one valid token doesn't make guessing others any cheaper.
+    pub fn succeed(&self, client: &Client) {
+        if let Some(failures) = self.clients.lock().unwrap().get_mut(&client.identity()) {
+            failures.count = 0;
+        }
+    }
+}
@@ -0,0 +1,324 @@
+//! Load generator. Connects any number of synthetic clients to a server, each running the same
+//! workload for a while, and reports the throughput and latencies they saw:
+//!
+//! - `typing` types into `cat` at a steady rate and times each key until its echo is back
+//! - `bulk` runs commands writing a lot of output and times each until all of it arrived
+//! - `resize` types like `typing` while resizing the terminal all the time
+//!
+//! The server has to let clients run `cat` and `head`, commands the workloads start on their own.
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, ValueEnum};
+use serde::Serialize;
+use sh_over_ws_actuator::{
+    client::{ActuatorClient, ClientOptions},
+    command::RunCommand,
+    data::{Compression, Encoding, WindowSize},
+    tls::load_client_config,
+};
+use std::{
+    fmt,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    time::{self, MissedTickBehavior},
+};
+
+/// What is typed, the carriage return keeps lines short of what the terminal buffers
+const KEYS: &[u8] = b"abcdefghijklmnopqrstuvwxyz\r";
+
+/// Resizes for every key typed by the `resize` workload
+const RESIZES_PER_KEY: u16 = 10;
+
+/// Command line of the load generator
+#[derive(Debug, Parser)]
+#[command(version, about = "Load a sh-over-ws-actuator server with synthetic clients")]
+struct Cli {
+    /// URL of the server, `ws://` or `wss://`
+    url: String,
+    /// Token to authenticate with
+    #[arg(short, long, env = "SHWS_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// How messages are encoded on the wire, `json` or `msgpack`
+    #[arg(long, default_value_t = Encoding::Json)]
+    encoding: Encoding,
+    /// Compress frames, `zstd` needs the `msgpack` encoding
+    #[arg(long, default_value_t = Compression::None)]
+    compression: Compression,
+    /// PEM file with the CAs the server certificate must be signed by, the Mozilla root
+    /// certificates if not given
+    #[arg(long, env = "SHWS_TLS_CA", value_name = "FILE")]
+    tls_ca: Option<PathBuf>,
+    /// Clients connected at the same time, each with a connection of its own
+    #[arg(short = 'n', long, default_value_t = 10)]
+    clients: usize,
+    #[arg(short, long, value_enum, default_value_t = Workload::Typing)]
+    workload: Workload,
+    /// Seconds to run the workload for
+    #[arg(short, long, default_value_t = 10)]
+    duration: u64,
+    /// Keys typed per second by each client
+    #[arg(long, default_value_t = 20)]
+    rate: u32,
+    /// Bytes of output of every command of the `bulk` workload
+    #[arg(long, default_value_t = 1 << 20)]
+    bytes: u64,
+    /// Report as JSON instead of text
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+enum Workload {
+    Typing,
+    Bulk,
+    Resize,
+}
+
+impl fmt::Display for Workload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Workload::Typing => write!(f, "typing"),
+            Workload::Bulk => write!(f, "bulk"),
+            Workload::Resize => write!(f, "resize"),
+        }
+    }
+}
+
+/// What a client saw
+#[derive(Default)]
+struct Stats {
+    /// How long each key or command took
+    latencies: Vec<Duration>,
+    /// Bytes of output received
+    bytes: u64,
+}
+
+/// What all clients saw together
+#[derive(Serialize)]
+struct Report {
+    workload: Workload,
+    clients: usize,
+    /// Clients that failed before the time was up
+    failed: usize,
+    seconds: f64,
+    operations: usize,
+    operations_per_second: f64,
+    bytes: u64,
+    bytes_per_second: f64,
+    /// Milliseconds
+    latency: Percentiles,
+}
+
+#[derive(Serialize)]
+struct Percentiles {
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl Percentiles {
+    /// The percentiles of `latencies` in milliseconds, all 0 if there are none
+    fn of(latencies: &mut [Duration]) -> Self {
+        latencies.sort_unstable();
+        let at = |p: f64| match latencies.len() {
+            0 => 0.0,
+            n => {
+                let index = ((p * n as f64).ceil() as usize).clamp(1, n) - 1;
+                latencies[index].as_secs_f64() * 1000.0
+            },
+        };
+        Percentiles {
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            max: at(1.0),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "workload    {}", self.workload)?;
+        writeln!(f, "clients     {} ({} failed)", self.clients, self.failed)?;
+        writeln!(f, "duration    {:.2}s", self.seconds)?;
+        writeln!(
+            f,
+            "operations  {} ({:.1}/s)",
+            self.operations, self.operations_per_second
+        )?;
+        writeln!(
+            f,
+            "received    {:.2} MiB ({:.2} MiB/s)",
+            self.bytes as f64 / (1 << 20) as f64,
+            self.bytes_per_second / (1 << 20) as f64
+        )?;
+        write!(
+            f,
+            "latency     p50 {:.2}ms  p90 {:.2}ms  p99 {:.2}ms  max {:.2}ms",
+            self.latency.p50, self.latency.p90, self.latency.p99, self.latency.max
+        )
+    }
+}
+
+fn size(cols: u16, rows: u16) -> WindowSize {
+    WindowSize {
+        cols,
+        rows,
+        width_in_pixels: None,
+        height_in_pixels: None,
+    }
+}
+
+fn command(command: &str, args: &[&str]) -> RunCommand {
+    RunCommand {
+        command: command.into(),
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+        ..Default::default()
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprintln!("sh-over-ws-bench: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    if cli.rate == 0 {
+        return Err(anyhow!("--rate must be at least 1"));
+    }
+    let tls = match cli.tls_ca.as_deref() {
+        Some(ca) => Some(load_client_config(Some(ca), None)?),
+        None => None,
+    };
+    let options = ClientOptions {
+        token: cli.token.clone(),
+        encoding: cli.encoding,
+        compression: cli.compression,
+        tls,
+        terminal: None,
+    };
+    let duration = Duration::from_secs(cli.duration);
+    // everyone connects first, so that connecting doesn't count towards the workload
+    let mut clients = Vec::with_capacity(cli.clients);
+    for i in 0..cli.clients {
+        let client = ActuatorClient::connect(&cli.url, options.clone())
+            .await
+            .with_context(|| format!("client {} failed", i))?;
+        clients.push(client);
+    }
+    let started = Instant::now();
+    let until = started + duration;
+    let tasks = clients
+        .into_iter()
+        .map(|client| {
+            let (workload, rate, bytes) = (cli.workload, cli.rate, cli.bytes);
+            tokio::spawn(async move {
+                let mut stats = Stats::default();
+                let result = match workload {
+                    Workload::Typing => typing(&client, until, rate, false, &mut stats).await,
+                    Workload::Resize => typing(&client, until, rate, true, &mut stats).await,
+                    Workload::Bulk => bulk(&client, until, bytes, &mut stats).await,
+                };
+                (stats, result)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut total = Stats::default();
+    let mut failed = 0;
+    for task in tasks {
+        let (stats, result) = task.await.context("client panicked")?;
+        if let Err(e) = result {
+            eprintln!("sh-over-ws-bench: client failed: {:#}", e);
+            failed += 1;
+        }
+        total.latencies.extend(stats.latencies);
+        total.bytes += stats.bytes;
+    }
+    let seconds = started.elapsed().as_secs_f64();
+    let report = Report {
+        workload: cli.workload,
+        clients: cli.clients,
+        failed,
+        seconds,
+        operations: total.latencies.len(),
+        operations_per_second: total.latencies.len() as f64 / seconds,
+        bytes: total.bytes,
+        bytes_per_second: total.bytes as f64 / seconds,
+        latency: Percentiles::of(&mut total.latencies),
+    };
+    match cli.json {
+        true => println!("{}", serde_json::to_string_pretty(&report)?),
+        false => println!("{}", report),
+    }
+    Ok(())
+}
+
+/// Type `rate` keys a second into `cat` until `until`, timing each until the terminal echoed it.
+/// With `resize` the terminal changes its size between keys as well.
+async fn typing(
+    client: &ActuatorClient,
+    until: Instant,
+    rate: u32,
+    resize: bool,
+    stats: &mut Stats,
+) -> Result<()> {
+    let mut session = client.open(Some(command("cat", &[])), size(80, 24)).await?;
+    let mut pace = time::interval(Duration::from_secs(1) / rate);
+    pace.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut buf = vec![0; 4096];
+    for (i, &key) in KEYS.iter().cycle().enumerate() {
+        pace.tick().await;
+        if Instant::now() >= until {
+            break;
+        }
+        if resize {
+            for j in 0..RESIZES_PER_KEY {
+                let cols = 40 + ((i as u16).wrapping_mul(RESIZES_PER_KEY) + j) % 160;
+                session.resize(size(cols, 24))?;
+            }
+        }
+        let sent = Instant::now();
+        session.write_all(&[key]).await?;
+        loop {
+            let n = session.read(&mut buf).await?;
+            if n == 0 {
+                return Err(anyhow!("session ended"));
+            }
+            stats.bytes += n as u64;
+            if buf[..n].contains(&key) {
+                break;
+            }
+        }
+        stats.latencies.push(sent.elapsed());
+    }
+    session.close()
+}
+
+/// Run commands writing `bytes` each one after another until `until`, timing each from opening
+/// its session until all output arrived
+async fn bulk(
+    client: &ActuatorClient,
+    until: Instant,
+    bytes: u64,
+    stats: &mut Stats,
+) -> Result<()> {
+    let count = bytes.to_string();
+    while Instant::now() < until {
+        let sent = Instant::now();
+        let mut session = client
+            .open(Some(command("head", &["-c", &count, "/dev/zero"])), size(80, 24))
+            .await?;
+        stats.bytes += io::copy(&mut session, &mut io::sink()).await?;
+        stats.latencies.push(sent.elapsed());
+    }
+    Ok(())
+}
@@ -0,0 +1,453 @@
+//! Interactive client. Opens a session on a server and hands the local terminal over to it, much
+//! like `ssh` does: keys are sent as typed, output is written as is and the remote terminal
+//! follows the size of the local one.
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use nix::sys::termios::{self, SetArg, Termios};
+use sh_over_ws_actuator::{
+    client::{ActuatorClient, ClientOptions, ClientSession, Event},
+    command::RunCommand,
+    data::{ColorDepth, Compression, Encoding, IdleAction, SessionId, TerminalInfo, WindowSize},
+    socks,
+    tls::load_client_config,
+};
+use std::{
+    fs,
+    io::IsTerminal,
+    os::unix::{
+        fs::MetadataExt,
+        io::{AsRawFd, RawFd},
+    },
+    path::PathBuf,
+    str::FromStr,
+};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+};
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
+
+/// What a terminal sends for Ctrl-D, which ends the input of a program reading from it
+const END_OF_TRANSMISSION: u8 = 0x04;
+
+/// Command line of the client
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Open a shell session on a sh-over-ws-actuator server"
+)]
+struct Cli {
+    /// URL of the server, `ws://` or `wss://`
+    url: String,
+    /// Token to authenticate with
+    #[arg(short, long, env = "SHWS_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// How messages are encoded on the wire, `json` or `msgpack`
+    #[arg(long, default_value_t = Encoding::Json)]
+    encoding: Encoding,
+    /// Compress frames, `zstd` needs the `msgpack` encoding
+    #[arg(long, default_value_t = Compression::None)]
+    compression: Compression,
+    /// PEM file with the CAs the server certificate must be signed by, the Mozilla root
+    /// certificates if not given
+    #[arg(long, env = "SHWS_TLS_CA", value_name = "FILE")]
+    tls_ca: Option<PathBuf>,
+    /// PEM file with the certificate chain to authenticate with
+    #[arg(long, env = "SHWS_TLS_CERT", value_name = "FILE", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of the certificate
+    #[arg(long, env = "SHWS_TLS_KEY", value_name = "FILE", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Take over a session left running instead of opening a new one
+    #[arg(long, value_name = "SESSION", conflicts_with = "command")]
+    attach: Option<SessionId>,
+    /// Run the server's profile NAME instead of the default shell
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["attach", "command"])]
+    profile: Option<String>,
+    /// Upload file LOCAL to REMOTE, relative to the file root of the server
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["LOCAL", "REMOTE"],
+        conflicts_with_all = ["attach", "profile", "command", "get"]
+    )]
+    put: Option<Vec<PathBuf>>,
+    /// Download file REMOTE, relative to the file root of the server, to LOCAL
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["REMOTE", "LOCAL"],
+        conflicts_with_all = ["attach", "profile", "command"]
+    )]
+    get: Option<Vec<PathBuf>>,
+    /// Forward connections to local port LOCAL_PORT to PORT of HOST, as the server sees it
+    #[arg(
+        short = 'L',
+        long,
+        value_name = "LOCAL_PORT:HOST:PORT",
+        conflicts_with_all = ["attach", "profile", "command", "put", "get"]
+    )]
+    forward: Option<Forward>,
+    /// Run a SOCKS5 proxy on local port LOCAL_PORT, connecting to where its clients ask for as the
+    /// server sees it
+    #[arg(
+        short = 'D',
+        long,
+        value_name = "LOCAL_PORT",
+        conflicts_with_all = ["attach", "profile", "command", "put", "get", "forward"]
+    )]
+    dynamic: Option<u16>,
+    /// Command to run instead of the default shell of the server
+    #[arg(trailing_var_arg = true, value_name = "COMMAND")]
+    command: Vec<String>,
+}
+
+/// Where `--forward` forwards to
+#[derive(Clone, Debug)]
+struct Forward {
+    local_port: u16,
+    host: String,
+    port: u16,
+}
+
+impl FromStr for Forward {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let err_context = || format!("'{}' is not LOCAL_PORT:HOST:PORT", s);
+
+        let (local_port, rest) = s.split_once(':').ok_or_else(|| anyhow!(err_context()))?;
+        let (host, port) = rest
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!(err_context()))?;
+        Ok(Forward {
+            local_port: local_port.parse().with_context(err_context)?,
+            // IPv6 addresses are bracketed to tell them from the port
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: port.parse().with_context(err_context)?,
+        })
+    }
+}
+
+/// Keeps the local terminal in raw mode until dropped, restoring the attributes it had before
+struct RawMode {
+    fd: RawFd,
+    original: Termios,
+}
+
+impl RawMode {
+    fn enable(fd: RawFd) -> Result<Self> {
+        let err_context = || "failed to put the terminal in raw mode";
+
+        let original = termios::tcgetattr(fd).with_context(err_context)?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(fd, SetArg::TCSAFLUSH, &raw).with_context(err_context)?;
+        Ok(RawMode { fd, original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(self.fd, SetArg::TCSAFLUSH, &self.original);
+    }
+}
+
+/// Size of the terminal `fd` refers to, that of a classic terminal if it isn't one
+fn terminal_size(fd: RawFd) -> WindowSize {
+    let mut winsize = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // TIOCGWINSZ is an u32, but the second argument to ioctl is u64 on some platforms
+    #[allow(clippy::useless_conversion)]
+    let result = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ.into(), &mut winsize) };
+    if result != 0 || winsize.ws_col == 0 || winsize.ws_row == 0 {
+        return WindowSize {
+            cols: 80,
+            rows: 24,
+            width_in_pixels: None,
+            height_in_pixels: None,
+        };
+    }
+    let pixels = |n: u16| (n != 0).then_some(n);
+    WindowSize {
+        cols: winsize.ws_col,
+        rows: winsize.ws_row,
+        width_in_pixels: pixels(winsize.ws_xpixel),
+        height_in_pixels: pixels(winsize.ws_ypixel),
+    }
+}
+
+/// The terminal the client runs on, as its environment tells
+fn local_terminal() -> Option<TerminalInfo> {
+    let term = std::env::var("TERM").ok().filter(|term| !term.is_empty())?;
+    let colors = match std::env::var("COLORTERM").as_deref() {
+        Ok("truecolor" | "24bit") => Some(ColorDepth::TrueColor),
+        _ if term.ends_with("256color") => Some(ColorDepth::Ansi256),
+        _ => None,
+    };
+    Some(TerminalInfo { term, colors })
+}
+
+#[tokio::main]
+async fn main() {
+    // only errors unless RUST_LOG asks for more
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::ERROR.into())
+        .from_env_lossy();
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+    let code = match run(Cli::parse()).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("sh-over-ws: {:#}", e);
+            255
+        },
+    };
+    // reading stdin blocks a thread the runtime would wait for on shutdown
+    std::process::exit(code);
+}
+
+/// Run the session, returning the exit code of its command
+async fn run(cli: Cli) -> Result<i32> {
+    let identity = cli.tls_cert.as_deref().zip(cli.tls_key.as_deref());
+    let tls = match cli.tls_ca.is_some() || identity.is_some() {
+        true => Some(load_client_config(cli.tls_ca.as_deref(), identity)?),
+        false => None,
+    };
+    let options = ClientOptions {
+        token: cli.token,
+        encoding: cli.encoding,
+        compression: cli.compression,
+        tls,
+        terminal: local_terminal(),
+    };
+    let mut client = ActuatorClient::connect(&cli.url, options).await?;
+
+    if let Some([local, remote]) = cli.put.as_deref() {
+        let err_context = || format!("failed to read '{}'", local.display());
+        let content = fs::read(local).with_context(err_context)?;
+        let mode = fs::metadata(local).with_context(err_context)?.mode() & 0o777;
+        client.upload(remote, &content, Some(mode)).await?;
+        return Ok(0);
+    }
+    if let Some([remote, local]) = cli.get.as_deref() {
+        let content = client.download(remote).await?;
+        fs::write(local, content)
+            .with_context(|| format!("failed to write '{}'", local.display()))?;
+        return Ok(0);
+    }
+    if let Some(forward) = &cli.forward {
+        return forward_port(&mut client, forward).await;
+    }
+    if let Some(port) = cli.dynamic {
+        return run_socks_proxy(&mut client, port).await;
+    }
+
+    let stdout_fd = std::io::stdout().as_raw_fd();
+    let size = terminal_size(stdout_fd);
+    let mut session = match (cli.attach, cli.profile.as_deref()) {
+        (Some(id), _) => {
+            let session = client.attach(id).await?;
+            // the session still has the size of the terminal it was detached from
+            session.resize(size)?;
+            session
+        },
+        (None, Some(profile)) => client.open_profile(profile, size).await?,
+        (None, None) => {
+            let command = cli.command.split_first().map(|(command, args)| RunCommand {
+                command: command.into(),
+                args: args.to_vec(),
+                ..Default::default()
+            });
+            client.open(command, size).await?
+        },
+    };
+
+    let stdin = std::io::stdin();
+    let _raw_mode = match stdin.is_terminal() {
+        true => Some(RawMode::enable(stdin.as_raw_fd())?),
+        false => None,
+    };
+    let last_event = forward(&mut client, &mut session, stdout_fd).await?;
+    drop(_raw_mode);
+
+    match last_event {
+        Some(Event::Exited {
+            code,
+            signal,
+            reason,
+            ..
+        }) => {
+            if let Some(reason) = reason {
+                eprintln!("session exited: {}", reason);
+            }
+            if let Some(signal) = signal {
+                eprintln!("session terminated by {}", signal);
+                // what shells report for commands killed by a signal
+                return Ok(128 + signal.number);
+            }
+            Ok(code.unwrap_or(1))
+        },
+        Some(Event::Detached { session }) => {
+            eprintln!("detached from session {}", session);
+            Ok(0)
+        },
+        Some(Event::Closed { message, .. }) => {
+            Err(anyhow!("server closed the connection: {}", message))
+        },
+        _ => Err(anyhow!("connection closed")),
+    }
+}
+
+/// Shuttle stdin to `session` and its output to stdout until it exits or is detached, which is
+/// the event returned. `None` if the connection closed first.
+async fn forward(
+    client: &mut ActuatorClient,
+    session: &mut ClientSession,
+    stdout_fd: RawFd,
+) -> Result<Option<Event>> {
+    let mut window_changes =
+        signal(SignalKind::window_change()).context("failed to watch for window changes")?;
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+    let mut input = [0; 4096];
+    let mut output = [0; 4096];
+    let mut stdin_open = true;
+    let mut output_open = true;
+    loop {
+        tokio::select! {
+            read = session.read(&mut output), if output_open => {
+                match read.context("failed to read session output")? {
+                    // the event saying why follows
+                    0 => output_open = false,
+                    n => write_output(&mut stdout, &output[..n]).await?,
+                }
+            },
+            read = stdin.read(&mut input), if stdin_open => {
+                match read.context("failed to read stdin")? {
+                    // input piped in ran out, end it like a terminal would
+                    0 => {
+                        stdin_open = false;
+                        session.write_all(&[END_OF_TRANSMISSION]).await?;
+                    },
+                    n => session.write_all(&input[..n]).await?,
+                }
+            },
+            Some(()) = window_changes.recv() => session.resize(terminal_size(stdout_fd))?,
+            event = client.next_event() => match event {
+                Some(Event::Error { error, .. }) => eprint!("\r\n{}\r\n", error),
+                Some(
+                    Event::Exited { session: id, .. } | Event::Detached { session: id },
+                ) if id != session.id() => {},
+                Some(Event::RoleChanged { role, .. }) => {
+                    eprint!("\r\nthis terminal is the session's {} now\r\n", role)
+                },
+                Some(Event::Idle {
+                    action: IdleAction::Suspend,
+                    seconds: 0,
+                    ..
+                }) => eprint!("\r\nthe session was suspended for idling, typing continues it\r\n"),
+                // the exit that follows says why
+                Some(Event::Idle { seconds: 0, .. }) => {},
+                Some(Event::Idle { action, seconds, .. }) => {
+                    eprint!("\r\nthe session is idle and will be {} in {}s\r\n", action, seconds)
+                },
+                // backpressure, reading the output faster is all there is to do about it
+                Some(Event::Paused { .. } | Event::Resumed { .. }) => {},
+                // never asked for, OSC 52 reaches the local terminal as it is
+                Some(
+                    Event::AttachedClients { .. }
+                    | Event::EnvironmentSnapshot { .. }
+                    | Event::JobList { .. }
+                    | Event::JobDetails { .. }
+                    | Event::Scheduled { .. }
+                    | Event::Queued { .. }
+                    | Event::Started { .. }
+                    | Event::Meta { .. }
+                    | Event::ClipboardSet { .. }
+                    | Event::ClipboardGet { .. },
+                ) => {},
+                // output still buffered comes first
+                event => {
+                    let mut rest = vec![];
+                    session.read_to_end(&mut rest).await?;
+                    write_output(&mut stdout, &rest).await?;
+                    return Ok(event);
+                },
+            },
+        }
+    }
+}
+
+/// Accept connections on the local port of `forward` and have the server connect each to its
+/// destination, until interrupted
+async fn forward_port(client: &mut ActuatorClient, forward: &Forward) -> Result<i32> {
+    let listener = TcpListener::bind(("127.0.0.1", forward.local_port))
+        .await
+        .with_context(|| format!("failed to listen on port {}", forward.local_port))?;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (mut stream, peer) = accepted.context("failed to accept connection")?;
+                let mut channel = match client.forward(&forward.host, forward.port).await {
+                    Ok(channel) => channel,
+                    Err(e) => {
+                        eprintln!("sh-over-ws: {:#}", e);
+                        continue;
+                    },
+                };
+                tokio::spawn(async move {
+                    if let Err(e) = io::copy_bidirectional(&mut stream, &mut channel).await {
+                        eprintln!("sh-over-ws: forwarding for {} failed: {}", peer, e);
+                    }
+                });
+            },
+            _ = tokio::signal::ctrl_c() => return Ok(0),
+            event = client.next_event() => match event {
+                Some(Event::Closed { message, .. }) => {
+                    return Err(anyhow!("server closed the connection: {}", message))
+                },
+                None => return Err(anyhow!("connection closed")),
+                Some(_) => {},
+            },
+        }
+    }
+}
+
+/// Serve SOCKS clients on local port `port` with channels over the connection, until interrupted
+async fn run_socks_proxy(client: &mut ActuatorClient, port: u16) -> Result<i32> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .with_context(|| format!("failed to listen on port {}", port))?;
+    let proxy = socks::serve(listener, client.forwarder());
+    tokio::pin!(proxy);
+    loop {
+        tokio::select! {
+            result = &mut proxy => return result.map(|()| 0),
+            _ = tokio::signal::ctrl_c() => return Ok(0),
+            event = client.next_event() => match event {
+                Some(Event::Closed { message, .. }) => {
+                    return Err(anyhow!("server closed the connection: {}", message))
+                },
+                None => return Err(anyhow!("connection closed")),
+                Some(_) => {},
+            },
+        }
+    }
+}
+
+async fn write_output(stdout: &mut tokio::io::Stdout, data: &[u8]) -> Result<()> {
+    let err_context = || "failed to write to stdout";
+    stdout.write_all(data).await.with_context(err_context)?;
+    stdout.flush().await.with_context(err_context)
+}
@@ -0,0 +1,168 @@
+//! Plain copies of the output of sessions, for collecting logs of what commands wrote rather
+//! than replaying terminals like [recordings](crate::recording) do. Each session writes to a file
+//! of its own in the configured directory:
+//!
+//! ```toml
+//! [capture]
+//! dir = "/var/log/shws/output"
+//! file_name = "{time}-{session}.log"
+//! max_size = 10485760
+//! keep = 5
+//! compress = true
+//! ```
+//!
+//! Once a file would grow beyond `max_size` bytes it's renamed to `<file>.1`, `<file>.2` and so
+//! on, the highest number being the latest, and a new one is started. Rotated files are
+//! compressed with gzip to `<file>.<n>.gz` if asked for, only the latest `keep` of them are kept.
+use crate::{
+    config::CaptureConfig,
+    data::SessionId,
+    redact::{RedactedOutput, RedactionConfig},
+};
+use anyhow::{Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+/// The output of one session captured to a file
+#[derive(Debug)]
+pub struct Capture {
+    path: PathBuf,
+    file: File,
+    /// Bytes written to the current file
+    written: u64,
+    max_size: Option<u64>,
+    keep: usize,
+    compress: bool,
+    /// Files rotated so far
+    rotated: usize,
+    redacted: RedactedOutput,
+}
+
+impl Capture {
+    /// Start capturing the output of session `id` to a new file in the configured directory,
+    /// scrubbing what `redaction` asks for
+    pub fn create(
+        config: &CaptureConfig,
+        redaction: &RedactionConfig,
+        id: SessionId,
+    ) -> Result<Self> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = config.dir.join(
+            config
+                .file_name
+                .replace("{session}", &id.to_string())
+                .replace("{time}", &now.to_string()),
+        );
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("failed to start capturing to '{}'", path.display()))?;
+        Ok(Capture {
+            path,
+            file,
+            written: 0,
+            max_size: config.max_size,
+            keep: config.keep,
+            compress: config.compress,
+            rotated: 0,
+            redacted: RedactedOutput::new(redaction),
+        })
+    }
+
+    /// Capture `data` the command output, starting a new file first if it would grow too large
+    pub fn output(&mut self, data: &[u8]) {
+        let data = self.redacted.push(data);
+        self.write(&data);
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        let full = self.max_size.is_some_and(|max_size| {
+            self.written > 0 && self.written + data.len() as u64 > max_size
+        });
+        if full {
+            if let Err(e) = self.rotate() {
+                warn!("failed to rotate '{}': {}", self.path.display(), e);
+            }
+        }
+        match self.file.write_all(data) {
+            Ok(()) => self.written += data.len() as u64,
+            Err(e) => warn!("failed to write '{}': {}", self.path.display(), e),
+        }
+    }
+
+    /// Set the current file aside and start a new one, compressing the old one and removing
+    /// those that are too old in the background
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = numbered(&self.path, self.rotated + 1, "");
+        fs::rename(&self.path, &rotated)?;
+        self.rotated += 1;
+        self.file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&self.path)?;
+        self.written = 0;
+
+        let expired = self
+            .rotated
+            .checked_sub(self.keep)
+            .filter(|expired| *expired > 0)
+            .map(|expired| numbered(&self.path, expired, ""));
+        let compress = self.compress;
+        // compressing may take a while, much longer than sessions may wait for their output
+        tokio::task::spawn_blocking(move || {
+            if compress {
+                if let Err(e) = gzip(&rotated) {
+                    warn!("failed to compress '{}': {}", rotated.display(), e);
+                }
+            }
+            if let Some(expired) = expired {
+                for path in [expired.clone(), numbered(&expired, 0, ".gz")] {
+                    match fs::remove_file(&path) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                            warn!("failed to remove '{}': {}", path.display(), e)
+                        },
+                        _ => {},
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let rest = self.redacted.finish();
+        self.write(&rest);
+    }
+}
+
+/// `path` with `.<n>` and `suffix` appended, or just `suffix` if `n` is 0
+fn numbered(path: &Path, n: usize, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path);
+    if n > 0 {
+        name.push(format!(".{}", n));
+    }
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Compress the file at `path` to `<path>.gz`, removing the original
+fn gzip(path: &Path) -> io::Result<()> {
+    let compressed = numbered(path, 0, ".gz");
+    let mut encoder = GzEncoder::new(File::create(&compressed)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
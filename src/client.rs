@@ -0,0 +1,1214 @@
+//! Async client for Rust programs driving the server. An [`ActuatorClient`] owns one connection,
+//! any number of [`ClientSession`]s can be opened over it. Sessions are [`AsyncRead`] and
+//! [`AsyncWrite`] handles of their command's output and input, everything else the server
+//! reports arrives as [`Event`]s.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use sh_over_ws_actuator::{
+//!     client::{ActuatorClient, ClientOptions},
+//!     data::WindowSize,
+//! };
+//! use tokio::io::{AsyncReadExt, AsyncWriteExt};
+//!
+//! let options = ClientOptions {
+//!     token: Some("s3cr3t".to_string()),
+//!     ..Default::default()
+//! };
+//! let client = ActuatorClient::connect("ws://127.0.0.1:8080/", options).await?;
+//! let size = WindowSize {
+//!     cols: 80,
+//!     rows: 24,
+//!     width_in_pixels: None,
+//!     height_in_pixels: None,
+//! };
+//! let mut session = client.open(None, size).await?;
+//! session.write_all(b"echo hello; exit\n").await?;
+//! let mut output = vec![];
+//! session.read_to_end(&mut output).await?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::{
+    audit::hex,
+    command::RunCommand,
+    data::{
+        AttachRole, AttachedClient, Blob, Capability, ChannelId, CloseReason, Compression,
+        Encoding, ExitReason, ExitSignal, IdleAction, JobRecord, LineMode, Message, Payload,
+        SerialSettings, SessionId, SignalSpec, TermMode, TerminalInfo, TransferId, WindowSize,
+        CAPABILITIES, PROTOCOL_VERSION, SUBPROTOCOL,
+    },
+    error::{ErrorCode, ProtocolError, ToAnyhow},
+    transfer::CHUNK_SIZE,
+};
+use anyhow::{anyhow, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use ring::digest::{self, SHA256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context as TaskContext, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig},
+    TlsConnector,
+};
+use tokio_tungstenite::{
+    client_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        handshake::client::Request,
+        http::{
+            header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL},
+            HeaderValue,
+        },
+        protocol::frame::coding::CloseCode,
+        Error as WsError, Message as WsMessage,
+    },
+};
+use tracing::warn;
+
+/// How to connect
+#[derive(Clone, Default)]
+pub struct ClientOptions {
+    /// Token to authenticate with, sent as bearer token
+    pub token: Option<String>,
+    pub encoding: Encoding,
+    /// Compression of binary frames, needs [`Encoding::MsgPack`]
+    pub compression: Compression,
+    /// TLS config for `wss://` URLs, see
+    /// [`load_client_config`](crate::tls::load_client_config). The Mozilla root certificates
+    /// are trusted if not set.
+    pub tls: Option<Arc<ClientConfig>>,
+    /// The terminal sessions are opened on, so that their commands get the right `TERM`
+    pub terminal: Option<TerminalInfo>,
+}
+
+/// Something the server reported besides the output of a session
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The command of `session` exited, its [`ClientSession`] reads end-of-file from now on
+    Exited {
+        session: SessionId,
+        code: Option<i32>,
+        signal: Option<ExitSignal>,
+        reason: Option<ExitReason>,
+    },
+    /// `session` is no longer attached to this connection
+    Detached { session: SessionId },
+    /// This connection is `session`'s `role` now, after it or another client took control
+    RoleChanged {
+        session: SessionId,
+        role: AttachRole,
+    },
+    /// The clients attached to `session`, as asked for with
+    /// [`ClientSession::list_attached_clients`]
+    AttachedClients {
+        session: SessionId,
+        clients: Vec<AttachedClient>,
+    },
+    /// Jobs of this client the server kept, as asked for with [`ActuatorClient::list_jobs`]
+    JobList { jobs: Vec<JobRecord> },
+    /// A job of this client, as asked for with [`ActuatorClient::fetch_job`]
+    JobDetails { job: JobRecord },
+    /// The command of `session` is to be started at `at`, an RFC 3339 time
+    Scheduled { session: SessionId, at: String },
+    /// The command of `session` waits for others to exit, as the `position`th in the queue
+    Queued { session: SessionId, position: usize },
+    /// The scheduled or queued command of `session` is started now
+    Started { session: SessionId },
+    /// The environment the command of `session` was started with, as asked for with
+    /// [`ClientSession::snapshot_environment`]
+    EnvironmentSnapshot {
+        session: SessionId,
+        env: BTreeMap<String, String>,
+    },
+    /// The server stopped reading the output of `session` because this client doesn't keep up
+    /// with it
+    Paused { session: SessionId },
+    /// The server reads the output of `session` again
+    Resumed { session: SessionId },
+    /// `session` saw neither input nor output for a while and is `action`ed in `seconds` unless
+    /// it is used, or just was if `seconds` is 0
+    Idle {
+        session: SessionId,
+        action: IdleAction,
+        seconds: u64,
+    },
+    /// The command of `session` set the window title or changed its directory, to what is not
+    /// `None`
+    Meta {
+        session: SessionId,
+        title: Option<String>,
+        cwd: Option<PathBuf>,
+    },
+    /// The command of `session` put `data` on the clipboard
+    ClipboardSet { session: SessionId, data: Vec<u8> },
+    /// The command of `session` asks what is on the clipboard, to be answered with
+    /// [`ClientSession::answer_clipboard`]
+    ClipboardGet { session: SessionId },
+    /// A failure that isn't the answer to opening a session
+    Error {
+        session: Option<SessionId>,
+        error: ProtocolError,
+    },
+    /// The server closed the connection, the last event. `reason` is `None` for close codes
+    /// this client doesn't know.
+    Closed {
+        reason: Option<CloseReason>,
+        message: String,
+    },
+}
+
+/// Where messages from the server go
+/// Whether the output and the input of a session are recorded
+type Recorded = (bool, bool);
+
+#[derive(Default)]
+struct Routes {
+    /// Output of the sessions the client has handles for, along with its `seq`
+    outputs: HashMap<SessionId, mpsc::UnboundedSender<(u64, Vec<u8>)>>,
+    /// Sessions waiting for the server to confirm them, answered with whether they are recorded
+    pending: HashMap<SessionId, oneshot::Sender<Result<Recorded, ProtocolError>>>,
+    /// Messages about the file transfers in progress
+    transfers: HashMap<TransferId, mpsc::UnboundedSender<Message>>,
+    /// Messages about the forwarding channels open
+    channels: HashMap<ChannelId, mpsc::UnboundedSender<Message>>,
+    /// Waiting for the server's [`Message::Hello`], answered with its version and capabilities
+    hello: Option<oneshot::Sender<(u32, Vec<Capability>)>>,
+}
+
+/// A connection to the server
+pub struct ActuatorClient {
+    outgoing: mpsc::UnboundedSender<Message>,
+    routes: Arc<Mutex<Routes>>,
+    events: mpsc::UnboundedReceiver<Event>,
+    /// What the server supports of the protocol
+    capabilities: Vec<Capability>,
+    /// Advertised when opening sessions
+    terminal: Option<TerminalInfo>,
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+impl ActuatorClient {
+    /// Connect to the server at `url`, a `ws://` or `wss://` URL
+    pub async fn connect(url: &str, options: ClientOptions) -> Result<Self> {
+        let err_context = || format!("failed to connect to {}", url);
+
+        let mut request = url.into_client_request().with_context(err_context)?;
+        request.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(SUBPROTOCOL),
+        );
+        if options.encoding != Encoding::Json {
+            add_query_param(&mut request, "encoding", &options.encoding.to_string())
+                .with_context(err_context)?;
+        }
+        if options.compression != Compression::None {
+            add_query_param(
+                &mut request,
+                "compression",
+                &options.compression.to_string(),
+            )
+            .with_context(err_context)?;
+        }
+        if let Some(token) = options.token.as_ref() {
+            let value =
+                HeaderValue::from_str(&format!("Bearer {}", token)).with_context(err_context)?;
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+
+        let uri = request.uri().clone();
+        let secure = uri.scheme_str() == Some("wss");
+        let host = uri
+            .host()
+            .ok_or_else(|| anyhow!("URL has no host"))
+            .with_context(err_context)?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+        let tcp = TcpStream::connect((host.as_str(), port))
+            .await
+            .with_context(err_context)?;
+        let stream: Box<dyn Io> = match secure {
+            true => {
+                let tls = match options.tls {
+                    Some(tls) => tls,
+                    None => crate::tls::load_client_config(None, None)?,
+                };
+                let name = ServerName::try_from(host).with_context(err_context)?;
+                let stream = TlsConnector::from(tls)
+                    .connect(name, tcp)
+                    .await
+                    .with_context(err_context)?;
+                Box::new(stream)
+            },
+            false => Box::new(tcp),
+        };
+        let (ws, _) = client_async(request, stream)
+            .await
+            .map_err(rejection)
+            .with_context(err_context)?;
+
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let (hello, server_hello) = oneshot::channel();
+        let routes = Arc::new(Mutex::new(Routes {
+            hello: Some(hello),
+            ..Default::default()
+        }));
+        let _ = outgoing.send(Message::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.to_vec(),
+        });
+        tokio::spawn(serve_connection(
+            ws,
+            options.encoding,
+            options.compression,
+            outgoing_rx,
+            outgoing.clone(),
+            routes.clone(),
+            events_tx,
+        ));
+        let (version, capabilities) = server_hello
+            .await
+            .map_err(|_| anyhow!("connection closed before the server said hello"))
+            .with_context(err_context)?;
+        if version != PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "server speaks protocol version {}, client {}",
+                version,
+                PROTOCOL_VERSION
+            ))
+            .with_context(err_context);
+        }
+        Ok(ActuatorClient {
+            outgoing,
+            routes,
+            events,
+            capabilities,
+            terminal: options.terminal,
+        })
+    }
+
+    /// Open a session running `command` on a terminal of `size`, the server's default shell if
+    /// `command` is `None`
+    pub async fn open(
+        &self,
+        command: Option<RunCommand>,
+        size: WindowSize,
+    ) -> Result<ClientSession> {
+        self.open_terminal(command, None, size).await
+    }
+
+    /// Open a session running the server's profile named `profile` on a terminal of `size`
+    pub async fn open_profile(&self, profile: &str, size: WindowSize) -> Result<ClientSession> {
+        self.open_terminal(None, Some(profile.to_string()), size)
+            .await
+    }
+
+    async fn open_terminal(
+        &self,
+        command: Option<RunCommand>,
+        profile: Option<String>,
+        size: WindowSize,
+    ) -> Result<ClientSession> {
+        let session = SessionId::new_v4();
+        self.start(
+            session,
+            Message::Open {
+                session,
+                command,
+                profile,
+                size: Some(size),
+                terminal: self.terminal.clone(),
+                cwd: None,
+                env: Default::default(),
+                clear_env: false,
+                strip_env: vec![],
+                timeout: None,
+                raw_output: false,
+                strip_ansi: false,
+                clipboard: false,
+                meta: false,
+                strip_meta: false,
+            },
+            0,
+        )
+        .await
+    }
+
+    /// Run `program` with `args` without a terminal. Its stdout and stderr are both read from
+    /// the session. The session is returned once the command started, which it may only after
+    /// waiting in the server's queue, see [`Event::Queued`].
+    pub async fn run(&self, program: &str, args: &[&str]) -> Result<ClientSession> {
+        self.run_command(program, args, None).await
+    }
+
+    /// Run `program` with `args` without a terminal `delay` seconds from now. The session is
+    /// announced with [`Event::Scheduled`] and returned once it started, it may be
+    /// [cancelled](ActuatorClient::cancel_scheduled) until then.
+    pub async fn run_after(
+        &self,
+        program: &str,
+        args: &[&str],
+        delay: u64,
+    ) -> Result<ClientSession> {
+        self.run_command(program, args, Some(delay)).await
+    }
+
+    /// Ask for up to `limit` of the jobs this client ran that started `since`, an RFC 3339 time,
+    /// or later, answered with [`Event::JobList`]
+    pub fn list_jobs(&self, limit: Option<usize>, since: Option<&str>) -> Result<()> {
+        self.outgoing
+            .send(Message::ListJobs {
+                limit,
+                since: since.map(str::to_string),
+            })
+            .map_err(|_| anyhow!("connection closed"))
+    }
+
+    /// Ask for job `session` this client ran along with the tail of its output, answered with
+    /// [`Event::JobDetails`]
+    pub fn fetch_job(&self, session: SessionId) -> Result<()> {
+        self.outgoing
+            .send(Message::FetchJob { session })
+            .map_err(|_| anyhow!("connection closed"))
+    }
+
+    /// Don't start the command of `session` [scheduled](ActuatorClient::run_after) or queued on
+    /// this connection after all
+    pub fn cancel_scheduled(&self, session: SessionId) -> Result<()> {
+        self.outgoing
+            .send(Message::CancelScheduled { session })
+            .map_err(|_| anyhow!("connection closed"))
+    }
+
+    async fn run_command(
+        &self,
+        program: &str,
+        args: &[&str],
+        delay: Option<u64>,
+    ) -> Result<ClientSession> {
+        let session = SessionId::new_v4();
+        self.start(
+            session,
+            Message::Run {
+                session,
+                program: program.into(),
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+                env: Default::default(),
+                clear_env: false,
+                strip_env: vec![],
+                cwd: None,
+                merge_stderr: false,
+                timeout: None,
+                raw_output: false,
+                strip_ansi: false,
+                start_at: None,
+                delay,
+            },
+            0,
+        )
+        .await
+    }
+
+    /// Open a session on serial `device` of the server. Reading yields what the device receives,
+    /// writing sends to it.
+    pub async fn open_serial(
+        &self,
+        device: &Path,
+        settings: SerialSettings,
+    ) -> Result<ClientSession> {
+        let session = SessionId::new_v4();
+        self.start(
+            session,
+            Message::SerialOpen {
+                session,
+                device: device.into(),
+                settings,
+                raw_output: false,
+            },
+            0,
+        )
+        .await
+    }
+
+    /// Take over `session`, which keeps running on the server, e.g. after reconnecting. Its
+    /// recent output is read first.
+    pub async fn attach(&self, session: SessionId) -> Result<ClientSession> {
+        let role = AttachRole::Writer;
+        self.start(session, Message::Attach { session, role }, 0)
+            .await
+    }
+
+    /// Take over `session` like [`ActuatorClient::attach`] does, reading only the output that
+    /// followed `seq`, the [`ClientSession::position`] of the handle the session was read from
+    /// over a lost connection
+    pub async fn resume(&self, session: SessionId, seq: u64) -> Result<ClientSession> {
+        let role = AttachRole::Writer;
+        let request = Message::ResumeFrom {
+            session,
+            seq: Some(seq),
+            role,
+        };
+        self.start(session, request, seq).await
+    }
+
+    /// Watch `session` alongside its writer, writing to the returned handle fails. See
+    /// [`ClientSession::take_control`] for taking over from the writer.
+    pub async fn watch(&self, session: SessionId) -> Result<ClientSession> {
+        let role = AttachRole::Viewer;
+        self.start(session, Message::Attach { session, role }, 0)
+            .await
+    }
+
+    /// Write `data` to the input of all of `sessions`, which this connection has to be the writer
+    /// of. Sessions it couldn't be written to are reported as [`Event::Error`]s.
+    pub fn broadcast_input(&self, sessions: &[SessionId], data: &[u8]) -> Result<()> {
+        self.outgoing
+            .send(Message::BroadcastInput {
+                sessions: sessions.to_vec(),
+                data: Payload::from(data.to_vec()),
+            })
+            .map_err(|_| anyhow!("connection closed"))
+    }
+
+    /// Open a new session like `from`, which this connection is the writer of, on a terminal of
+    /// `size`. It runs the same command in the directory the command of `from` is in now, as far
+    /// as the server can tell.
+    pub async fn clone_session(&self, from: SessionId, size: WindowSize) -> Result<ClientSession> {
+        let session = SessionId::new_v4();
+        let request = Message::CloneSession {
+            session,
+            from,
+            size: Some(size),
+        };
+        self.start(session, request, 0).await
+    }
+
+    /// Send `request` for `session` and wait for the server to confirm it. The output read
+    /// follows `seq`.
+    async fn start(&self, session: SessionId, request: Message, seq: u64) -> Result<ClientSession> {
+        let err_context = || format!("failed to start session {}", session);
+
+        let (output_tx, output) = mpsc::unbounded_channel();
+        let (confirmed_tx, confirmed) = oneshot::channel();
+        {
+            let mut routes = self.routes.lock().to_anyhow().with_context(err_context)?;
+            routes.outputs.insert(session, output_tx);
+            routes.pending.insert(session, confirmed_tx);
+        }
+        self.outgoing
+            .send(request)
+            .map_err(|_| anyhow!("connection closed"))
+            .with_context(err_context)?;
+        let (recording, recording_input) = match confirmed.await {
+            Ok(Ok(recording)) => recording,
+            // the server's message already names the session
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err(anyhow!("connection closed")).with_context(err_context),
+        };
+        Ok(ClientSession {
+            id: session,
+            recording,
+            recording_input,
+            outgoing: self.outgoing.clone(),
+            output,
+            received: seq,
+            unread: vec![],
+        })
+    }
+
+    /// Store `content` as the file at `path` below the file root of the server, with permissions
+    /// `mode` or 0644
+    pub async fn upload(&self, path: &Path, content: &[u8], mode: Option<u32>) -> Result<()> {
+        let err_context = || format!("failed to upload '{}'", path.display());
+
+        let transfer = TransferId::new_v4();
+        let mut messages = self.start_transfer(transfer).with_context(err_context)?;
+        let result = async {
+            self.send(Message::FileUploadStart {
+                transfer,
+                path: path.into(),
+                size: content.len() as u64,
+                mode,
+            })?;
+            for chunk in content.chunks(CHUNK_SIZE) {
+                self.send(Message::FileUploadChunk {
+                    transfer,
+                    data: Blob(chunk.to_vec()),
+                })?;
+            }
+            self.send(Message::FileUploadEnd {
+                transfer,
+                sha256: hex(digest::digest(&SHA256, content)),
+            })?;
+            match messages.recv().await {
+                Some(Message::FileUploaded { .. }) => Ok(()),
+                // the server's message already names the file
+                Some(Message::TransferFailed { error, .. }) => Err(error.into()),
+                _ => Err(anyhow!("connection closed")).with_context(err_context),
+            }
+        }
+        .await;
+        self.finish_transfer(transfer);
+        result
+    }
+
+    /// Fetch the file at `path` below the file root of the server
+    pub async fn download(&self, path: &Path) -> Result<Vec<u8>> {
+        let err_context = || format!("failed to download '{}'", path.display());
+
+        let transfer = TransferId::new_v4();
+        let mut messages = self.start_transfer(transfer).with_context(err_context)?;
+        let result = async {
+            self.send(Message::FileDownloadRequest {
+                transfer,
+                path: path.into(),
+            })?;
+            let mut content = vec![];
+            let mut size = None;
+            loop {
+                match messages.recv().await {
+                    Some(Message::FileDownloadStart {
+                        size: announced, ..
+                    }) => {
+                        content.reserve(announced.try_into().unwrap_or(0));
+                        size = Some(announced);
+                    },
+                    Some(Message::FileDownloadChunk { data, .. }) => {
+                        content.extend_from_slice(&data.0);
+                    },
+                    Some(Message::FileDownloadEnd { sha256, .. }) => {
+                        if size != Some(content.len() as u64) {
+                            return Err(anyhow!("file arrived incomplete"))
+                                .with_context(err_context);
+                        }
+                        let actual = hex(digest::digest(&SHA256, &content));
+                        if !actual.eq_ignore_ascii_case(&sha256) {
+                            return Err(anyhow!("file arrived with a different SHA-256 digest"))
+                                .with_context(err_context);
+                        }
+                        return Ok(content);
+                    },
+                    Some(Message::TransferFailed { error, .. }) => return Err(error.into()),
+                    _ => return Err(anyhow!("connection closed")).with_context(err_context),
+                }
+            }
+        }
+        .await;
+        self.finish_transfer(transfer);
+        result
+    }
+
+    /// Route the messages about `transfer` to the receiver returned
+    fn start_transfer(&self, transfer: TransferId) -> Result<mpsc::UnboundedReceiver<Message>> {
+        let (messages_tx, messages) = mpsc::unbounded_channel();
+        self.routes
+            .lock()
+            .to_anyhow()?
+            .transfers
+            .insert(transfer, messages_tx);
+        Ok(messages)
+    }
+
+    fn finish_transfer(&self, transfer: TransferId) {
+        if let Ok(mut routes) = self.routes.lock() {
+            routes.transfers.remove(&transfer);
+        }
+    }
+
+    /// Open a channel to TCP `port` of `host`, as the server sees it. The server's
+    /// [allow list](crate::config::ForwardingConfig::allow) needs to have the destination.
+    pub async fn forward(&self, host: &str, port: u16) -> Result<ClientChannel> {
+        self.forwarder().forward(host, port).await
+    }
+
+    /// A handle opening channels like [`ActuatorClient::forward`] does, for tasks of their own
+    pub fn forwarder(&self) -> Forwarder {
+        Forwarder {
+            outgoing: self.outgoing.clone(),
+            routes: self.routes.clone(),
+        }
+    }
+
+    fn send(&self, message: Message) -> Result<()> {
+        self.outgoing
+            .send(message)
+            .map_err(|_| anyhow!("connection closed"))
+    }
+
+    /// What the server supports of the protocol, which [`Capability::Unknown`] stands in for if
+    /// this client doesn't know it
+    pub fn server_capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+
+    /// The next [`Event`], `None` once the connection is closed
+    pub async fn next_event(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+}
+
+/// The [`ProtocolError`] the server refused the upgrade with, `error` itself if it gave none
+fn rejection(error: WsError) -> anyhow::Error {
+    if let WsError::Http(response) = &error {
+        let body = response.body().as_deref().unwrap_or_default();
+        if let Ok(refused) = serde_json::from_slice::<ProtocolError>(body) {
+            return refused.into();
+        }
+    }
+    error.into()
+}
+
+fn add_query_param(request: &mut Request, name: &str, value: &str) -> Result<()> {
+    let uri = request.uri().to_string();
+    let separator = if uri.contains('?') { '&' } else { '?' };
+    *request.uri_mut() = format!("{}{}{}={}", uri, separator, name, value).parse()?;
+    Ok(())
+}
+
+/// Shuttle messages between the WebSocket and the client until either side goes away
+async fn serve_connection<S>(
+    ws: tokio_tungstenite::WebSocketStream<S>,
+    encoding: Encoding,
+    compression: Compression,
+    mut outgoing_rx: mpsc::UnboundedReceiver<Message>,
+    outgoing: mpsc::UnboundedSender<Message>,
+    routes: Arc<Mutex<Routes>>,
+    events: mpsc::UnboundedSender<Event>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut sink, mut source) = ws.split();
+    loop {
+        tokio::select! {
+            frame = source.next() => {
+                let decoded = match frame {
+                    Some(Ok(WsMessage::Text(text))) => Encoding::Json.decode(text.as_bytes()),
+                    Some(Ok(WsMessage::Binary(bytes))) => compression
+                        .decompress(bytes)
+                        .and_then(|bytes| Encoding::MsgPack.decode(&bytes)),
+                    Some(Ok(WsMessage::Close(Some(frame)))) if frame.code != CloseCode::Normal => {
+                        let _ = events.send(Event::Closed {
+                            reason: CloseReason::from_code(frame.code.into()),
+                            message: frame.reason.into_owned(),
+                        });
+                        break;
+                    },
+                    Some(Ok(WsMessage::Close(_))) | None => break,
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        warn!("connection failed: {}", e);
+                        break;
+                    },
+                };
+                match decoded {
+                    Ok(message) => route_message(message, &routes, &outgoing, &events),
+                    Err(e) => warn!("{:#}", e),
+                }
+            },
+            Some(message) = outgoing_rx.recv() => {
+                let frame = encoding.encode(&message).and_then(|bytes| {
+                    Ok(match encoding {
+                        Encoding::Json => WsMessage::Text(String::from_utf8(bytes)?),
+                        Encoding::MsgPack => WsMessage::Binary(compression.compress(bytes)?),
+                    })
+                });
+                let sent = match frame {
+                    Ok(frame) => sink.send(frame).await.context("failed to send message"),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = sent {
+                    warn!("{:#}", e);
+                    break;
+                }
+            },
+        }
+    }
+    // readers see end-of-file and waiting openers fail once their senders are gone
+    if let Ok(mut routes) = routes.lock() {
+        routes.outputs.clear();
+        routes.pending.clear();
+        routes.transfers.clear();
+        routes.channels.clear();
+        routes.hello = None;
+    }
+}
+
+fn route_message(
+    message: Message,
+    routes: &Mutex<Routes>,
+    outgoing: &mpsc::UnboundedSender<Message>,
+    events: &mpsc::UnboundedSender<Event>,
+) {
+    let Ok(mut routes) = routes.lock() else {
+        return;
+    };
+    if let Some(transfer) = message.transfer() {
+        if let Some(messages) = routes.transfers.get(&transfer) {
+            let _ = messages.send(message);
+        }
+        return;
+    }
+    if let Some(channel) = message.channel() {
+        if let Some(messages) = routes.channels.get(&channel) {
+            let _ = messages.send(message);
+        }
+        return;
+    }
+    let event = match message {
+        Message::Hello {
+            version,
+            capabilities,
+        } => {
+            if let Some(hello) = routes.hello.take() {
+                let _ = hello.send((version, capabilities));
+            }
+            return;
+        },
+        Message::Opened {
+            session,
+            recording,
+            recording_input,
+            ..
+        } => {
+            if let Some(confirmed) = routes.pending.remove(&session) {
+                let _ = confirmed.send(Ok((recording, recording_input)));
+            }
+            return;
+        },
+        Message::Output {
+            session, data, seq, ..
+        } => {
+            if let Some(output) = routes.outputs.get(&session) {
+                let _ = output.send((seq, data.0.into()));
+            }
+            return;
+        },
+        Message::Error {
+            session: Some(session),
+            error,
+        } if routes.pending.contains_key(&session) => {
+            routes.outputs.remove(&session);
+            if let Some(confirmed) = routes.pending.remove(&session) {
+                let _ = confirmed.send(Err(error));
+            }
+            return;
+        },
+        Message::Ping { nonce } => {
+            let _ = outgoing.send(Message::Pong { nonce });
+            return;
+        },
+        Message::Exit {
+            session,
+            code,
+            signal,
+            reason,
+        } => {
+            routes.outputs.remove(&session);
+            // scheduled commands are cancelled before they are confirmed
+            if let Some(confirmed) = routes.pending.remove(&session) {
+                let cancelled = ProtocolError::new(ErrorCode::Other, "cancelled before it started");
+                let _ = confirmed.send(Err(cancelled));
+            }
+            Event::Exited {
+                session,
+                code,
+                signal,
+                reason,
+            }
+        },
+        Message::Detached { session } => {
+            routes.outputs.remove(&session);
+            Event::Detached { session }
+        },
+        Message::JobList { jobs } => Event::JobList { jobs },
+        Message::JobDetails { job } => Event::JobDetails { job },
+        Message::Scheduled { session, at } => Event::Scheduled { session, at },
+        Message::Queued { session, position } => Event::Queued { session, position },
+        Message::Started { session } => Event::Started { session },
+        Message::RoleChanged { session, role } => Event::RoleChanged { session, role },
+        Message::AttachedClients { session, clients } => {
+            Event::AttachedClients { session, clients }
+        },
+        Message::EnvironmentSnapshot { session, env } => {
+            Event::EnvironmentSnapshot { session, env }
+        },
+        Message::Paused { session } => Event::Paused { session },
+        Message::Resumed { session } => Event::Resumed { session },
+        Message::Idle {
+            session,
+            action,
+            seconds,
+        } => Event::Idle {
+            session,
+            action,
+            seconds,
+        },
+        Message::SessionMeta {
+            session,
+            title,
+            cwd,
+        } => Event::Meta {
+            session,
+            title,
+            cwd,
+        },
+        Message::ClipboardSet { session, data } => Event::ClipboardSet {
+            session,
+            data: data.0.into(),
+        },
+        Message::ClipboardGet { session } => Event::ClipboardGet { session },
+        Message::Error { session, error } => Event::Error { session, error },
+        _ => return,
+    };
+    let _ = events.send(event);
+}
+
+/// A session opened by an [`ActuatorClient`]. Reading yields the output of its command until it
+/// exits, writing sends input to it. Dropping the handle leaves the session running, see
+/// [`ClientSession::close`].
+pub struct ClientSession {
+    id: SessionId,
+    recording: bool,
+    recording_input: bool,
+    outgoing: mpsc::UnboundedSender<Message>,
+    output: mpsc::UnboundedReceiver<(u64, Vec<u8>)>,
+    /// The `seq` of the latest output received
+    received: u64,
+    /// Output received but not read yet
+    unread: Vec<u8>,
+}
+
+impl ClientSession {
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// Whether the server records the output of the session
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// Whether the server records what is typed into the session as well
+    pub fn is_recording_input(&self) -> bool {
+        self.recording_input
+    }
+
+    /// How many bytes the session output up to what was read from the handle, where to
+    /// [resume](ActuatorClient::resume) from after reconnecting
+    pub fn position(&self) -> u64 {
+        self.received - self.unread.len() as u64
+    }
+
+    /// Tell the server that the output was read up to [`ClientSession::position`], which it
+    /// resumes from if a [`Message::ResumeFrom`] doesn't say
+    pub fn ack(&self) -> Result<()> {
+        self.send(Message::Ack {
+            session: self.id,
+            seq: self.position(),
+        })
+    }
+
+    pub fn resize(&self, size: WindowSize) -> Result<()> {
+        self.send(Message::Resize {
+            session: self.id,
+            size,
+        })
+    }
+
+    /// Switch the terminal of the session to `mode`
+    pub fn set_term_mode(&self, mode: TermMode) -> Result<()> {
+        self.send(Message::SetTermMode {
+            session: self.id,
+            mode: Some(mode),
+            echo: None,
+            isig: None,
+            icrnl: None,
+        })
+    }
+
+    /// Become the writer of a session this connection watches, the current writer watches it
+    /// from then on
+    pub fn take_control(&self) -> Result<()> {
+        self.send(Message::TakeControl { session: self.id })
+    }
+
+    /// Ask who is attached to the session, answered with [`Event::AttachedClients`]
+    pub fn list_attached_clients(&self) -> Result<()> {
+        self.send(Message::ListAttachedClients { session: self.id })
+    }
+
+    /// Ask for the environment the command was started with, answered with
+    /// [`Event::EnvironmentSnapshot`]
+    pub fn snapshot_environment(&self) -> Result<()> {
+        self.send(Message::SnapshotEnvironment { session: self.id })
+    }
+
+    /// Change who echoes and edits the input of the session
+    pub fn set_line_mode(&self, mode: LineMode) -> Result<()> {
+        self.send(Message::SetLineMode {
+            session: self.id,
+            mode,
+        })
+    }
+
+    /// Tell the command what is on the clipboard, after it asked with [`Event::ClipboardGet`]
+    pub fn answer_clipboard(&self, data: &[u8]) -> Result<()> {
+        self.send(Message::ClipboardContent {
+            session: self.id,
+            data: Payload::from(data.to_vec()),
+        })
+    }
+
+    /// Deliver `signal` to the programs in the foreground of the session
+    pub fn signal(&self, signal: SignalSpec) -> Result<()> {
+        self.send(Message::Signal {
+            session: self.id,
+            signal,
+        })
+    }
+
+    /// Stop receiving the output of the session, which keeps running on the server
+    pub fn detach(self) -> Result<()> {
+        self.send(Message::Detach { session: self.id })
+    }
+
+    /// End the session
+    pub fn close(self) -> Result<()> {
+        self.send(Message::Close { session: self.id })
+    }
+
+    fn send(&self, message: Message) -> Result<()> {
+        self.outgoing
+            .send(message)
+            .map_err(|_| anyhow!("connection closed"))
+    }
+}
+
+impl AsyncRead for ClientSession {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.unread.is_empty() {
+            match ready!(self.output.poll_recv(cx)) {
+                Some((seq, data)) => {
+                    self.received = seq;
+                    self.unread = data;
+                },
+                // the session exited
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = self.unread.len().min(buf.remaining());
+        buf.put_slice(&self.unread[..n]);
+        self.unread.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ClientSession {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let input = Message::Input {
+            session: self.id,
+            data: Payload::from(buf.to_vec()),
+        };
+        Poll::Ready(match self.outgoing.send(input) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "connection closed",
+            )),
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Opens forwarding channels over the connection of an [`ActuatorClient`], see
+/// [`ActuatorClient::forwarder`]
+#[derive(Clone)]
+pub struct Forwarder {
+    outgoing: mpsc::UnboundedSender<Message>,
+    routes: Arc<Mutex<Routes>>,
+}
+
+impl Forwarder {
+    /// See [`ActuatorClient::forward`]
+    pub async fn forward(&self, host: &str, port: u16) -> Result<ClientChannel> {
+        let err_context = || format!("failed to forward to {}:{}", host, port);
+
+        let channel = ChannelId::new_v4();
+        let (messages_tx, mut messages) = mpsc::unbounded_channel();
+        self.routes
+            .lock()
+            .to_anyhow()
+            .with_context(err_context)?
+            .channels
+            .insert(channel, messages_tx);
+        let opened = async {
+            self.outgoing
+                .send(Message::TcpForward {
+                    channel,
+                    host: host.to_string(),
+                    port,
+                })
+                .map_err(|_| anyhow!("connection closed"))?;
+            match messages.recv().await {
+                Some(Message::ForwardOpened { .. }) => Ok(()),
+                // the server's message already names the destination
+                Some(Message::ForwardFailed { error, .. }) => Err(error.into()),
+                _ => Err(anyhow!("connection closed")).with_context(err_context),
+            }
+        }
+        .await;
+        if let Err(e) = opened {
+            if let Ok(mut routes) = self.routes.lock() {
+                routes.channels.remove(&channel);
+            }
+            return Err(e);
+        }
+        Ok(ClientChannel {
+            id: channel,
+            outgoing: self.outgoing.clone(),
+            routes: self.routes.clone(),
+            messages,
+            unread: vec![],
+            eof: false,
+            shut_down: false,
+        })
+    }
+}
+
+/// A TCP connection the server made for an [`ActuatorClient`], see [`ActuatorClient::forward`].
+/// Reading yields what the destination sends, writing sends to it and shutting down tells it
+/// nothing more follows. Dropping the handle before both sides are done aborts the connection.
+pub struct ClientChannel {
+    id: ChannelId,
+    outgoing: mpsc::UnboundedSender<Message>,
+    routes: Arc<Mutex<Routes>>,
+    messages: mpsc::UnboundedReceiver<Message>,
+    /// Data received but not read yet
+    unread: Vec<u8>,
+    /// The destination is done sending, or the channel failed
+    eof: bool,
+    /// This side is done sending
+    shut_down: bool,
+}
+
+impl ClientChannel {
+    pub fn id(&self) -> ChannelId {
+        self.id
+    }
+}
+
+impl AsyncRead for ClientChannel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.unread.is_empty() && !self.eof {
+            match ready!(self.messages.poll_recv(cx)) {
+                Some(Message::ForwardData { data, .. }) => self.unread = data.0,
+                Some(Message::ForwardClose { .. }) => self.eof = true,
+                Some(Message::ForwardFailed { error, .. }) => {
+                    self.eof = true;
+                    return Poll::Ready(Err(io::Error::other(error)));
+                },
+                Some(_) => {},
+                None => {
+                    self.eof = true;
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "connection closed",
+                    )));
+                },
+            }
+        }
+        let n = self.unread.len().min(buf.remaining());
+        buf.put_slice(&self.unread[..n]);
+        self.unread.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ClientChannel {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.shut_down {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "channel was shut down",
+            )));
+        }
+        let data = Message::ForwardData {
+            channel: self.id,
+            data: Blob(buf.to_vec()),
+        };
+        Poll::Ready(match self.outgoing.send(data) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "connection closed",
+            )),
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        if !self.shut_down {
+            self.shut_down = true;
+            let _ = self
+                .outgoing
+                .send(Message::ForwardClose { channel: self.id });
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for ClientChannel {
+    fn drop(&mut self) {
+        if let Ok(mut routes) = self.routes.lock() {
+            routes.channels.remove(&self.id);
+        }
+        if !(self.eof && self.shut_down) {
+            let _ = self.outgoing.send(Message::ForwardFailed {
+                channel: self.id,
+                error: ProtocolError::new(ErrorCode::Other, "the client dropped the channel"),
+            });
+        }
+    }
+}
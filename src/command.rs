@@ -1,7 +1,16 @@
 //! Trigger a command
-use crate::data::Direction;
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use crate::{
+    audit::Client, data::{Direction, SignalSpec},
+    hardening::{set_no_new_privs, Hardening, SeccompFilter}, limits::ResourceLimits,
+    metrics::METRICS, namespaces::{BindMount, Isolation, Namespaces}, os_io::find_command,
+    recording::InputLogging,
+};
+use anyhow::{anyhow, Context, Result};
+use glob::{MatchOptions, Pattern};
+use nix::{errno::Errno, sys::{resource::{setrlimit, Resource as Rlimit}, signal::Signal}, unistd::{self, Gid, Pid, Uid, User}};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Number, Value};
+use std::{collections::BTreeMap, env, ffi::CString, fmt, fs, os::unix::io::RawFd, path::{Path, PathBuf}, str::FromStr};
 
 #[derive(Debug, Clone)]
 pub enum TerminalAction {
@@ -22,6 +31,9 @@ pub struct RunCommand {
     pub hold_on_close: bool,
     #[serde(default)]
     pub hold_on_start: bool,
+    /// What the command is told its name is, its `argv[0]`, the command itself if not set
+    #[serde(skip)]
+    pub arg0: Option<String>,
 }
 
 impl std::fmt::Display for RunCommand {
@@ -65,6 +77,752 @@ impl From<RunCommandAction> for RunCommand {
             cwd: action.cwd,
             hold_on_close: action.hold_on_close,
             hold_on_start: action.hold_on_start,
+            arg0: None,
         }
     }
 }
+
+/// Environment of a spawned command, based on the environment of the server
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Environment {
+    /// Variables to set
+    pub vars: BTreeMap<String, String>,
+    /// Start out with an empty environment instead of the server's
+    pub clear: bool,
+    /// Variables to remove from the server's environment, e.g. `SSH_AUTH_SOCK`
+    pub strip: Vec<String>,
+}
+
+impl Environment {
+    /// This environment with `overrides` applied on top. Variables `overrides` sets or strips win
+    /// over those set here.
+    pub fn overridden_by(&self, overrides: &Environment) -> Environment {
+        let mut vars = self.vars.clone();
+        for name in overrides.strip.iter() {
+            vars.remove(name);
+        }
+        vars.extend(overrides.vars.clone());
+        Environment {
+            vars,
+            clear: self.clear || overrides.clear,
+            strip: self.strip.iter().chain(overrides.strip.iter()).cloned().collect(),
+        }
+    }
+
+    /// The variables a command started with this environment gets, from those of the server
+    pub fn resolve(&self) -> BTreeMap<String, String> {
+        let mut vars: BTreeMap<String, String> = match self.clear {
+            true => BTreeMap::new(),
+            // like tokio::process::Command, which passes variables that aren't UTF-8 on
+            // nonetheless, but they can't be shown
+            false => env::vars_os()
+                .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+                .collect(),
+        };
+        for name in self.strip.iter() {
+            vars.remove(name);
+        }
+        vars.extend(self.vars.clone());
+        vars
+    }
+
+    pub fn apply(&self, command: &mut tokio::process::Command) {
+        if self.clear {
+            command.env_clear();
+        }
+        for name in self.strip.iter() {
+            command.env_remove(name);
+        }
+        command.envs(&self.vars);
+    }
+}
+
+/// A command clients start by name instead of giving a command line, for servers that offer a
+/// few known programs rather than arbitrary ones:
+///
+/// ```toml
+/// [profiles.bash-login]
+/// cmd = "/bin/bash"
+/// args = ["-l"]
+///
+/// [profiles.python-repl]
+/// cmd = "/usr/bin/python3"
+/// env = { vars = { PYTHONSTARTUP = "/etc/shws/startup.py" } }
+/// timeout = 3600
+/// resources = { memory = 268435456, processes = 16 }
+///
+/// [profiles.login]
+/// cmd = "/bin/bash"
+/// login = { shell = true, user_env = true, utmp = true }
+/// ```
+///
+/// Like the default shell, profiles are started whatever the command policies say.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Where the command runs
+    #[serde(default)]
+    pub backend: Backend,
+    /// Container the command runs in with the `docker` backend, the container within the pod with
+    /// the `kubernetes` backend, where the pod's default container is used if not set
+    #[serde(default)]
+    pub container: Option<String>,
+    /// Pod the command runs in with the `kubernetes` backend
+    #[serde(default)]
+    pub pod: Option<String>,
+    /// Namespace of the pod, the default one of the kubeconfig if not set
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Host the command runs on with the `ssh` backend, as `host` or `host:port`
+    #[serde(default)]
+    pub host: Option<String>,
+    /// User to log in to the host as
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Private key to log in to the host with
+    #[serde(default)]
+    pub key: Option<PathBuf>,
+    /// Keys the host may have, `~/.ssh/known_hosts` of the server's user if not set
+    #[serde(default)]
+    pub known_hosts: Option<PathBuf>,
+    /// Plugin the command runs in with the `plugin` backend
+    #[serde(default)]
+    pub plugin: Option<String>,
+    #[serde(alias = "cmd")]
+    pub command: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    /// Applied on top of whatever the client asked for, the server's `env` still wins
+    #[serde(default)]
+    pub env: Environment,
+    /// Seconds the command may run, clients can only ask for less
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// Overrides the server's resource limits where set
+    #[serde(default)]
+    pub resources: ResourceLimits,
+    /// Overrides the server's hardening where set, only applies to the `local` backend
+    #[serde(default)]
+    pub hardening: Hardening,
+    /// Isolates the command further, only applies to the `local` backend
+    #[serde(default)]
+    pub isolation: Isolation,
+    /// Root directory of the command with `isolation = "namespaces"`, see
+    /// [`namespaces`](crate::namespaces)
+    #[serde(default)]
+    pub rootfs: Option<PathBuf>,
+    /// Directories mounted for the command with `isolation = "namespaces"`
+    #[serde(default)]
+    pub binds: Vec<BindMount>,
+    /// Keep the network of the server with `isolation = "namespaces"`
+    #[serde(default)]
+    pub share_network: bool,
+    /// What is recorded of the input of its sessions, if they are recorded, instead of what
+    /// [`RecordingConfig::input`](crate::config::RecordingConfig::input) says
+    #[serde(default)]
+    pub input: Option<InputLogging>,
+    /// Names of the [filters](crate::filter) the output of its sessions passes through
+    #[serde(default)]
+    pub output_filters: Vec<String>,
+    /// Names of the filters the input of its sessions passes through
+    #[serde(default)]
+    pub input_filters: Vec<String>,
+    /// Starts the command like a user logging in would, only applies to the `local` backend
+    #[serde(default)]
+    pub login: Login,
+}
+
+impl Profile {
+    pub fn command(&self) -> RunCommand {
+        // shells source the profiles of the user if their name starts with a dash
+        let arg0 = self.login.shell.then(|| {
+            let name = self.command.file_name().unwrap_or(self.command.as_os_str());
+            format!("-{}", name.to_string_lossy())
+        });
+        RunCommand {
+            command: self.command.clone(),
+            args: self.args.clone(),
+            cwd: self.cwd.clone(),
+            arg0,
+            ..Default::default()
+        }
+    }
+}
+
+/// How the command of a [`Profile`] is started like a user logging in would. The user is the one
+/// commands run as, see [`RunAs`], or the one the server runs as.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Login {
+    /// Start the command as a login shell, with a dash in front of its name, so that it sources
+    /// `/etc/profile` and the profiles of the user
+    pub shell: bool,
+    /// Set `HOME`, `USER` and `LOGNAME` to those of the user, whatever the client and the profile
+    /// set them to
+    pub user_env: bool,
+    /// Record the sessions as logins in utmp and wtmp, see [`utmp`](crate::utmp)
+    pub utmp: bool,
+}
+
+/// Where the command of a [`Profile`] runs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// On the server, like any other command
+    #[default]
+    Local,
+    /// In a running container, see [`docker`](crate::docker). The jail, the user commands run as
+    /// and the resource limits don't apply there.
+    Docker,
+    /// In a running pod, if the server is built with the `kubernetes` feature. Like in
+    /// containers, the jail, the user and the resource limits don't apply.
+    Kubernetes,
+    /// On another host over SSH, if the server is built with the `ssh` feature. The command runs
+    /// as the user logged in as, the jail and the resource limits don't apply.
+    Ssh,
+    /// In a [plugin](crate::plugin), if the server is built with the `plugins` feature. There is
+    /// no process on the server for the jail, the user and the resource limits to apply to.
+    Plugin,
+}
+
+/// A client asked for a [`Profile`] the server doesn't have
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownProfile {
+    pub name: String,
+}
+
+impl fmt::Display for UnknownProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no profile named '{}'", self.name)
+    }
+}
+
+impl std::error::Error for UnknownProfile {}
+
+/// Directory tree sessions are confined to. Clients can only ask for working directories within
+/// `root`; with `chroot` commands are also run with `root` as their root directory, which
+/// requires the server to run as root.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Jail {
+    pub root: PathBuf,
+    #[serde(default)]
+    pub chroot: bool,
+}
+
+/// A working directory outside the [`Jail`] was asked for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutsideJail {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for OutsideJail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' is outside of the jail", self.path.display())
+    }
+}
+
+impl std::error::Error for OutsideJail {}
+
+impl Jail {
+    /// The directory `cwd` refers to, the root of the jail if `None`. Relative paths are taken
+    /// relative to the root, and so are absolute ones when chrooting, since that is how the command
+    /// will see them.
+    pub fn resolve(&self, cwd: Option<&Path>) -> Result<PathBuf> {
+        let root = fs::canonicalize(&self.root)
+            .with_context(|| format!("failed to find jail '{}'", self.root.display()))?;
+        let requested = match cwd {
+            None => root.clone(),
+            Some(cwd) if self.chroot || cwd.is_relative() => {
+                root.join(cwd.strip_prefix("/").unwrap_or(cwd))
+            },
+            Some(cwd) => cwd.to_path_buf(),
+        };
+        let err_context = || format!("failed to change into '{}'", requested.display());
+
+        // resolving `..` and symlinks first catches every way out of the jail
+        let resolved = fs::canonicalize(&requested).with_context(err_context)?;
+        if !resolved.starts_with(&root) {
+            return Err(OutsideJail {
+                path: cwd.map(Path::to_path_buf).unwrap_or_default(),
+            }
+            .into());
+        }
+        if !resolved.is_dir() {
+            return Err(anyhow!("not a directory")).with_context(err_context);
+        }
+        Ok(resolved)
+    }
+
+    /// The [`Sandbox`] for commands started in this jail
+    pub fn sandbox(&self) -> Result<Sandbox> {
+        let chroot = match self.chroot {
+            true => Some(fs::canonicalize(&self.root)?),
+            false => None,
+        };
+        Ok(Sandbox {
+            chroot,
+            ..Default::default()
+        })
+    }
+}
+
+/// User spawned commands run as instead of the user the server runs as, which has to be root then
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunAs {
+    /// Name of the user
+    pub user: String,
+    /// Permit `user` to be root, which is refused otherwise to keep a typo from handing out root
+    /// shells
+    #[serde(default)]
+    pub allow_root: bool,
+}
+
+impl RunAs {
+    /// Look up the user to run commands as
+    pub fn credentials(&self) -> Result<Credentials> {
+        let err_context = || format!("failed to look up user '{}'", self.user);
+
+        let user = User::from_name(&self.user)
+            .with_context(err_context)?
+            .ok_or_else(|| anyhow!("no such user"))
+            .with_context(err_context)?;
+        if user.uid.is_root() && !self.allow_root {
+            return Err(anyhow!("refusing to run commands as root unless allowed explicitly"))
+                .with_context(err_context);
+        }
+        let name = CString::new(user.name.as_str()).with_context(err_context)?;
+        // what initgroups(3) would set, looked up here since the child must not touch NSS once
+        // forked, and /etc/group may be out of reach after chrooting anyway
+        let groups = unistd::getgrouplist(&name, user.gid).with_context(err_context)?;
+        Ok(Credentials {
+            name: user.name,
+            uid: user.uid,
+            gid: user.gid,
+            groups,
+            home: user.dir,
+        })
+    }
+}
+
+/// Identity a command is switched to before executing it
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub name: String,
+    pub uid: Uid,
+    pub gid: Gid,
+    /// Supplementary groups
+    pub groups: Vec<Gid>,
+    pub home: PathBuf,
+}
+
+impl Credentials {
+    /// The identity of the server, which commands keep unless they run as another user
+    pub fn current() -> Result<Credentials> {
+        let err_context = || "failed to look up the user of the server";
+
+        let user = User::from_uid(unistd::getuid())
+            .with_context(err_context)?
+            .ok_or_else(|| anyhow!("no such user"))
+            .with_context(err_context)?;
+        Ok(Credentials {
+            name: user.name,
+            uid: user.uid,
+            gid: user.gid,
+            groups: unistd::getgroups().with_context(err_context)?,
+            home: user.dir,
+        })
+    }
+
+    /// The variables a login as this user would set
+    pub fn environment(&self) -> Environment {
+        let mut vars = BTreeMap::new();
+        vars.insert("HOME".to_string(), self.home.to_string_lossy().into_owned());
+        vars.insert("USER".to_string(), self.name.clone());
+        vars.insert("LOGNAME".to_string(), self.name.clone());
+        Environment {
+            vars,
+            ..Default::default()
+        }
+    }
+}
+
+/// Restrictions applied to a command between forking and executing it
+#[derive(Clone, Debug, Default)]
+pub struct Sandbox {
+    /// Change the root directory to this. The working directory of the command, which has to be
+    /// inside, is kept.
+    pub chroot: Option<PathBuf>,
+    /// Resource limits to set, soft and hard
+    pub rlimits: Vec<(Rlimit, u64, u64)>,
+    /// `cgroup.procs` of the cgroup to move the command into, see [`Cgroup`](crate::limits::Cgroup)
+    pub cgroup_procs: Option<RawFd>,
+    /// Drop privileges to this user, after everything else that requires them
+    pub user: Option<Credentials>,
+    /// Set `PR_SET_NO_NEW_PRIVS`
+    pub no_new_privs: bool,
+    /// Installed last, once nothing the filter might deny is left to do
+    pub seccomp: Option<SeccompFilter>,
+    /// Namespaces to create, before anything the command is confined to in them
+    pub namespaces: Option<Namespaces>,
+}
+
+impl Sandbox {
+    /// Whether the command gets a root directory of its own, where it can't be looked up from
+    /// the server
+    pub fn changes_root(&self) -> bool {
+        self.chroot.is_some() || self.namespaces.as_ref().is_some_and(Namespaces::changes_root)
+    }
+
+    /// Register the restrictions with `command`, after whatever else has to happen between forking
+    /// and executing it
+    pub fn apply(&self, command: &mut tokio::process::Command, cwd: Option<&Path>) {
+        if let Some(procs) = self.cgroup_procs {
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::write(procs, b"0".as_ptr().cast(), 1) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        if !self.rlimits.is_empty() {
+            let rlimits = self.rlimits.clone();
+            unsafe {
+                command.pre_exec(move || {
+                    for (resource, soft, hard) in rlimits.iter() {
+                        setrlimit(*resource, *soft, *hard)?;
+                    }
+                    Ok(())
+                });
+            }
+        }
+        if let Some(namespaces) = self.namespaces.clone() {
+            unsafe {
+                command.pre_exec(move || namespaces.enter());
+            }
+        }
+        if let Some(root) = self.chroot.clone() {
+            let cwd = Path::new("/").join(
+                cwd.and_then(|cwd| cwd.strip_prefix(&root).ok())
+                    .unwrap_or_else(|| Path::new("")),
+            );
+            unsafe {
+                command.pre_exec(move || {
+                    unistd::chroot(&root)?;
+                    unistd::chdir(&cwd)?;
+                    Ok(())
+                });
+            }
+        }
+        if let Some(user) = self.user.clone() {
+            unsafe {
+                command.pre_exec(move || {
+                    // groups first, changing them takes privileges the uid gives up
+                    unistd::setgroups(&user.groups)?;
+                    unistd::setgid(user.gid)?;
+                    unistd::setuid(user.uid)?;
+                    Ok(())
+                });
+            }
+        }
+        if self.no_new_privs {
+            unsafe {
+                command.pre_exec(set_no_new_privs);
+            }
+        }
+        if let Some(filter) = self.seccomp.clone() {
+            unsafe {
+                command.pre_exec(move || filter.install());
+            }
+        }
+    }
+}
+
+/// The number of a signal, accepting names with or without the `SIG` prefix in any case
+pub fn signal_number(signal: &SignalSpec) -> Result<i32> {
+    match signal {
+        SignalSpec::Number(number) => Ok(*number),
+        SignalSpec::Name(name) => {
+            let name = name.to_ascii_uppercase();
+            let name = if name.starts_with("SIG") {
+                name
+            } else {
+                format!("SIG{}", name)
+            };
+            Signal::from_str(&name)
+                .map(|signal| signal as i32)
+                .map_err(|_| anyhow!("unknown signal '{}'", name))
+        },
+    }
+}
+
+/// Deliver `signal` to the process `pid`. Like kill(2), a negative `pid` addresses the process
+/// group `-pid`. Numbers nix has no name for, such as real-time signals, are passed on as is.
+pub fn send_signal(pid: Pid, signal: &SignalSpec) -> Result<()> {
+    let number = signal_number(signal)?;
+    if unsafe { libc::kill(pid.as_raw(), number) } == 0 {
+        return Ok(());
+    }
+    Err(match Errno::last() {
+        Errno::ESRCH => anyhow!("process {} does not exist anymore", pid),
+        Errno::EPERM => anyhow!("not permitted to send {} to process {}", signal, pid),
+        Errno::EINVAL => anyhow!("invalid signal {}", number),
+        errno => anyhow!("failed to send {} to process {}: {}", signal, pid, errno),
+    })
+}
+
+/// Which executables clients may start. Rules are exact paths or glob patterns like
+/// `/usr/bin/*`, where `*` doesn't cross `/`. A command is allowed if it matches no deny rule
+/// and, unless the allow list is empty, at least one allow rule. Commands are matched after
+/// looking them up in `PATH` and again after resolving symlinks, so neither `ls` nor a link to a
+/// denied binary slip through.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandPolicy {
+    #[serde(deserialize_with = "deserialize_patterns")]
+    pub allow: Vec<Pattern>,
+    #[serde(deserialize_with = "deserialize_patterns")]
+    pub deny: Vec<Pattern>,
+    /// Refuse every command, leaving clients the [profiles](Profile) and the default shell
+    pub profiles_only: bool,
+}
+
+/// A command was refused by the [`CommandPolicy`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PolicyViolation {
+    pub command: PathBuf,
+    /// The deny rule the command matched, `None` if it just isn't on the allow list
+    pub rule: Option<String>,
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rule.as_ref() {
+            Some(rule) => write!(
+                f,
+                "command '{}' is denied by rule '{}'",
+                self.command.display(),
+                rule
+            ),
+            None => write!(f, "command '{}' is not allowed", self.command.display()),
+        }
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+impl CommandPolicy {
+    /// Check whether `cmd` may be started
+    pub fn check(&self, cmd: &RunCommand) -> Result<(), PolicyViolation> {
+        if self.profiles_only {
+            METRICS.policy_violations.inc();
+            return Err(PolicyViolation { command: cmd.command.clone(), rule: None });
+        }
+        self.check_rules(cmd)
+    }
+
+    /// Check `cmd` against the allow and deny rules only
+    fn check_rules(&self, cmd: &RunCommand) -> Result<(), PolicyViolation> {
+        let found = find_command(cmd).unwrap_or_else(|| cmd.command.clone());
+        let mut candidates = vec![found];
+        if let Ok(canonical) = fs::canonicalize(&candidates[0]) {
+            candidates.push(canonical);
+        }
+
+        let matches =
+            |pattern: &Pattern| candidates.iter().any(|path| matches_path(pattern, path));
+        if let Some(rule) = self.deny.iter().find(|pattern| matches(pattern)) {
+            METRICS.policy_violations.inc();
+            return Err(PolicyViolation {
+                command: cmd.command.clone(),
+                rule: Some(rule.to_string()),
+            });
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(matches) {
+            METRICS.policy_violations.inc();
+            return Err(PolicyViolation {
+                command: cmd.command.clone(),
+                rule: None,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// What the clients a policy applies to may do on top of what the server allows anybody. Policies
+/// apply to clients by the user they authenticated as, by the claims of their token, or both:
+///
+/// ```toml
+/// [[policies]]
+/// subject = "alice"
+/// commands = { allow = ["/usr/bin/*"] }
+/// cwd = ["/srv/alice", "/srv/alice/*"]
+///
+/// [[policies]]
+/// claims = { role = "ci" }
+/// pty = false
+/// max_sessions = 2
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserPolicy {
+    /// User a client has to have authenticated as, like the `sub` claim of its JWT
+    #[serde(default)]
+    pub subject: Option<String>,
+    /// Claims the token of a client has to have. A claim holding a list matches if the value is
+    /// one of its elements.
+    #[serde(default)]
+    pub claims: BTreeMap<String, String>,
+    /// Executables the clients may start, the server's command policy applies as well. The
+    /// commands of profiles and the default shell have to be allowed by the rules too, just not
+    /// by `profiles_only`.
+    #[serde(default)]
+    pub commands: CommandPolicy,
+    /// Whether the clients get sessions on a terminal, they may only execute commands if not
+    #[serde(default = "default_pty")]
+    pub pty: bool,
+    /// Sessions the clients may have running at the same time, over all their connections
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
+    /// Working directories commands may be started in, exact paths or glob patterns like for
+    /// [`CommandPolicy`]. Anywhere if empty.
+    #[serde(default, deserialize_with = "deserialize_patterns")]
+    pub cwd: Vec<Pattern>,
+}
+
+fn default_pty() -> bool {
+    true
+}
+
+/// A command was to be started in a working directory the [`UserPolicy`] of the client doesn't
+/// allow
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirectoryNotAllowed {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for DirectoryNotAllowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "working directory '{}' is not allowed", self.path.display())
+    }
+}
+
+impl std::error::Error for DirectoryNotAllowed {}
+
+/// A client only allowed to execute commands asked for a terminal, see [`UserPolicy::pty`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TerminalNotAllowed;
+
+impl fmt::Display for TerminalNotAllowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sessions on a terminal are not allowed")
+    }
+}
+
+impl std::error::Error for TerminalNotAllowed {}
+
+impl UserPolicy {
+    /// Whether the policy applies to `client`
+    pub fn applies_to(&self, client: &Client) -> bool {
+        if self.subject.as_ref().is_some_and(|subject| client.user.as_ref() != Some(subject)) {
+            return false;
+        }
+        self.claims.iter().all(|(name, expected)| {
+            let claim = client.claims.as_ref().and_then(|claims| claims.get(name));
+            claim.is_some_and(|claim| claim_matches(claim, expected))
+        })
+    }
+
+    /// Check whether the clients may have a terminal
+    pub fn check_pty(&self) -> Result<(), TerminalNotAllowed> {
+        match self.pty {
+            true => Ok(()),
+            false => {
+                METRICS.policy_violations.inc();
+                Err(TerminalNotAllowed)
+            },
+        }
+    }
+
+    /// Check whether `cmd` may be started
+    pub fn check(&self, cmd: &RunCommand) -> Result<(), PolicyViolation> {
+        self.commands.check(cmd)
+    }
+
+    /// Check whether a profile or the default shell running `cmd` may be started
+    pub fn check_profile(&self, cmd: &RunCommand) -> Result<(), PolicyViolation> {
+        self.commands.check_rules(cmd)
+    }
+
+    /// Check whether commands may be started in `cwd`, the server's working directory if `None`
+    pub fn check_cwd(&self, cwd: Option<&Path>) -> Result<()> {
+        if self.cwd.is_empty() {
+            return Ok(());
+        }
+        let cwd = match cwd {
+            Some(cwd) => cwd.to_path_buf(),
+            None => env::current_dir()?,
+        };
+        // resolving `..` and symlinks first, patterns are no help against climbing out of the
+        // directories they name
+        let resolved = fs::canonicalize(&cwd)
+            .with_context(|| format!("failed to find '{}'", cwd.display()))?;
+        if !self.cwd.iter().any(|pattern| matches_path(pattern, &resolved)) {
+            METRICS.policy_violations.inc();
+            return Err(DirectoryNotAllowed { path: cwd }.into());
+        }
+        Ok(())
+    }
+
+    /// Check whether commands may be started in `cwd` in a container or on another host, where
+    /// it can't be resolved. Paths with `..` in them are refused, there is no telling where they
+    /// end up.
+    pub fn check_remote_cwd(&self, cwd: &Path) -> Result<(), DirectoryNotAllowed> {
+        if self.cwd.is_empty() {
+            return Ok(());
+        }
+        let climbs = cwd
+            .components()
+            .any(|component| component == std::path::Component::ParentDir);
+        if climbs || !self.cwd.iter().any(|pattern| matches_path(pattern, cwd)) {
+            METRICS.policy_violations.inc();
+            return Err(DirectoryNotAllowed { path: cwd.to_path_buf() });
+        }
+        Ok(())
+    }
+}
+
+/// Whether the claim `value` of a token is or, for lists, holds `expected`
+fn claim_matches(value: &Value, expected: &str) -> bool {
+    match value {
+        Value::String(value) => value == expected,
+        Value::Array(values) => values.iter().any(|value| claim_matches(value, expected)),
+        Value::Number(value) => expected.parse().is_ok_and(|expected: Number| expected == *value),
+        Value::Bool(value) => expected.parse() == Ok(*value),
+        Value::Null | Value::Object(_) => false,
+    }
+}
+
+pub(crate) fn matches_path(pattern: &Pattern, path: &Path) -> bool {
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    pattern.matches_path_with(path, options)
+}
+
+pub(crate) fn deserialize_patterns<'de, D>(deserializer: D) -> Result<Vec<Pattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|pattern| Pattern::new(pattern).map_err(serde::de::Error::custom))
+        .collect()
+}
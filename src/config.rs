@@ -0,0 +1,1427 @@
+//! Server configuration. Settings are read from a TOML file and every one of them can be
+//! overridden on the command line, see [`Cli`]. Everything not set anywhere keeps its default.
+//!
+//! `SIGHUP` reads the file again. Connections made from then on get the new settings, those
+//! already open and their sessions keep what they started with. What the server set up when it
+//! started, like its listeners, only changes with a restart, see [`Config::restart_needed`].
+//!
+//! ```toml
+//! listen = "0.0.0.0:8443"
+//! shell = "/bin/bash"
+//!
+//! [env]
+//! strip = ["SSH_AUTH_SOCK"]
+//! vars = { TERM = "xterm-256color" }
+//!
+//! [jail]
+//! root = "/srv/shws"
+//! chroot = false
+//!
+//! [run_as]
+//! user = "shws"
+//!
+//! [hardening]
+//! no_new_privs = true
+//!
+//! [commands]
+//! allow = ["/usr/bin/*", "/bin/bash"]
+//! deny = ["/usr/bin/sudo", "/usr/bin/su"]
+//!
+//! [profiles.bash-login]
+//! cmd = "/bin/bash"
+//! args = ["-l"]
+//!
+//! [profiles.restricted-rbash]
+//! cmd = "/bin/rbash"
+//! env = { clear = true, vars = { PATH = "/srv/shws/bin" } }
+//! resources = { processes = 8 }
+//! hardening = { deny_syscalls = ["ptrace", "mount", "network"] }
+//!
+//! [profiles.isolated]
+//! cmd = "/bin/sh"
+//! isolation = "namespaces"
+//! rootfs = "/srv/shws/rootfs"
+//! binds = [{ source = "/usr" }]
+//!
+//! [profiles.web-console]
+//! backend = "docker"
+//! container = "web"
+//! cmd = "/bin/sh"
+//!
+//! [docker]
+//! socket = "/var/run/docker.sock"
+//!
+//! [proxy]
+//! protocol = true
+//! trusted = ["10.0.0.0/8"]
+//!
+//! [addresses]
+//! allow = ["10.0.0.0/8", "192.168.0.0/16"]
+//! deny = ["10.66.0.0/16"]
+//!
+//! [tls]
+//! cert = "/etc/shws/cert.pem"
+//! key = "/etc/shws/key.pem"
+//!
+//! [unix_socket]
+//! path = "/run/shws/shws.sock"
+//! mode = 0o660
+//! group = "www-data"
+//!
+//! [[listeners]]
+//! name = "internal"
+//! unix_socket = { path = "/run/shws/internal.sock", mode = 0o600 }
+//! auth = { tokens = ["1nt3rn4l"] }
+//! profiles = ["bash-login"]
+//!
+//! [auth]
+//! tokens = ["s3cr3t"]
+//!
+//! [admin]
+//! listen = "127.0.0.1:9090"
+//! tokens = ["0p3r4t0r"]
+//!
+//! [audit]
+//! file = "/var/log/shws/audit.jsonl"
+//! syslog = false
+//!
+//! [[webhooks]]
+//! url = "https://hooks.example.com/shws"
+//! events = ["session_ended", "auth_failed"]
+//!
+//! [recording]
+//! dir = "/var/log/shws/recordings"
+//! file_name = "{time}-{session}.cast"
+//!
+//! [capture]
+//! dir = "/var/log/shws/output"
+//! max_size = 10485760
+//! compress = true
+//!
+//! [redaction]
+//! patterns = ['AKIA[0-9A-Z]{16}', '(?i)bearer\s+[a-z0-9._~+/-]+=*']
+//!
+//! [files]
+//! root = "/srv/shws/files"
+//! max_size = 104857600
+//!
+//! [serial]
+//! devices = ["/dev/ttyUSB*", "/dev/serial/by-id/*"]
+//!
+//! [forwarding]
+//! allow = ["localhost:9229", "127.0.0.1:50*"]
+//!
+//! [origins]
+//! allow = ["https://console.example.com", "https://*.example.com"]
+//!
+//! [limits]
+//! max_connections = 16
+//! max_connections_per_ip = 4
+//! handshake_timeout = 5
+//! max_sessions = 4
+//! max_total_sessions = 64
+//! max_broadcast_sessions = 8
+//! max_scheduled = 16
+//! max_running_commands = 8
+//! command_timeout = 3600
+//! connections_per_ip = { per_minute = 30, burst = 10 }
+//! session_bandwidth = { bytes_per_second = 1048576 }
+//! total_bandwidth = { bytes_per_second = 8388608, burst = 16777216 }
+//!
+//! [limits.resources]
+//! cpu_time = 3600
+//! memory = 1073741824
+//! cgroup = "/sys/fs/cgroup/shws"
+//!
+//! [sessions]
+//! scrollback = 65536
+//! detach_on_disconnect = true
+//! session_ttl = 300
+//! idle_timeout = 3600
+//! idle_action = "suspend"
+//!
+//! [keepalive]
+//! interval = 30
+//! timeout = 90
+//!
+//! [flow_control]
+//! high_watermark = 1048576
+//! low_watermark = 262144
+//!
+//! [coalescing]
+//! window = 4
+//! max_delay = 20
+//! adaptive = true
+//!
+//! [log]
+//! format = "json"
+//! filter = "info"
+//!
+//! [otel]
+//! endpoint = "http://collector:4318"
+//! sampling = 0.1
+//! ```
+use crate::{
+    audit::Client,
+    auth::{AuthCommand, JwtConfig, LockoutConfig},
+    command::{
+        deserialize_patterns, matches_path, CommandPolicy, Environment, Jail, Profile, RunAs,
+        RunCommand, UnknownProfile, UserPolicy,
+    },
+    data::IdleAction,
+    docker::DockerConfig,
+    firewall::AddressPolicy,
+    hardening::Hardening,
+    limits::{Bandwidth, RateLimit, ResourceLimits},
+    logging::{LogConfig, LogFormat},
+    namespaces::Isolation,
+    notify::WebhookConfig,
+    proxy::{parse_network, ProxyConfig},
+    recording::InputLogging,
+    redact::RedactionConfig,
+    tls::TlsConfig,
+};
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use glob::Pattern;
+use ipnet::IpNet;
+use nix::unistd::Uid;
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+/// Address the server listens on unless configured otherwise
+pub const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:8080";
+
+/// Seconds clients get to finish the handshake unless configured otherwise
+const DEFAULT_HANDSHAKE_TIMEOUT: u64 = 10;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the WebSocket listener binds to
+    pub listen: SocketAddr,
+    /// Also listen on this Unix domain socket, see [`listener`](crate::listener)
+    pub unix_socket: Option<UnixSocketConfig>,
+    /// More places to accept connections at, each with policies of its own
+    pub listeners: Vec<ListenerConfig>,
+    /// Command spawned for sessions that don't ask for a specific one, either a path or a table
+    /// like `{ cmd = "/bin/bash", args = ["-l"] }`
+    #[serde(deserialize_with = "deserialize_shell")]
+    pub shell: RunCommand,
+    /// Applied to the environment of every spawned command, on top of whatever the client asked
+    /// for
+    pub env: Environment,
+    /// Executables clients may ask for. The default shell is always allowed.
+    pub commands: CommandPolicy,
+    /// What particular users may do, the first policy applying to a client restricts it further
+    pub policies: Vec<UserPolicy>,
+    /// Commands clients may start by name
+    pub profiles: BTreeMap<String, Profile>,
+    /// Where the Docker Engine API is, for profiles running in containers
+    pub docker: DockerConfig,
+    /// Directory tree sessions are confined to, anywhere if not set
+    pub jail: Option<Jail>,
+    /// User commands are spawned as, the one the server runs as if not set
+    pub run_as: Option<RunAs>,
+    /// Restricts commands spawned on the server further, see [`hardening`](crate::hardening)
+    pub hardening: Hardening,
+    /// Serve `wss://` instead of `ws://` if set
+    pub tls: Option<TlsConfig>,
+    /// Load balancers and reverse proxies in front of the server, see [`proxy`](crate::proxy)
+    pub proxy: ProxyConfig,
+    /// Networks clients may and may not connect from, see [`firewall`](crate::firewall)
+    pub addresses: AddressPolicy,
+    pub auth: AuthConfig,
+    /// HTTP listener for metrics and health probes, not served if not set
+    pub admin: Option<AdminConfig>,
+    pub audit: AuditConfig,
+    /// Told about sessions starting and ending, clients failing to authenticate and policy
+    /// violations, see [`notify`](crate::notify)
+    pub webhooks: Vec<WebhookConfig>,
+    /// Record terminal sessions to this directory, not at all if not set
+    pub recording: Option<RecordingConfig>,
+    /// Copy the output of every session to files in this directory, not at all if not set
+    pub capture: Option<CaptureConfig>,
+    /// Secrets scrubbed from recordings, captured output and the audit trail, see
+    /// [`redact`](crate::redact)
+    pub redaction: RedactionConfig,
+    /// Let clients transfer files within this directory tree, not at all if not set
+    pub files: Option<FileTransferConfig>,
+    pub serial: SerialConfig,
+    pub forwarding: ForwardingConfig,
+    pub origins: OriginConfig,
+    pub limits: Limits,
+    pub sessions: SessionConfig,
+    pub keepalive: Keepalive,
+    pub flow_control: FlowControl,
+    pub coalescing: Coalescing,
+    pub log: LogConfig,
+    /// Export traces and metrics over OTLP, not at all if not set
+    #[cfg(feature = "otel")]
+    pub otel: Option<crate::otel::OtelConfig>,
+    /// Keep the commands run without a terminal in a database, not at all if not set
+    #[cfg(feature = "history")]
+    pub history: Option<crate::history::HistoryConfig>,
+    /// Publish events to and take commands from an MQTT broker, not at all if not set
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Option<crate::mqtt::MqttConfig>,
+    /// Serve the gRPC service as well, not at all if not set
+    #[cfg(feature = "grpc")]
+    pub grpc: Option<crate::grpc::GrpcConfig>,
+    /// Accept WebTransport sessions as well, not at all if not set. Needs `tls`.
+    #[cfg(feature = "webtransport")]
+    pub webtransport: Option<crate::server::webtransport::WebTransportConfig>,
+    /// Run the hooks of a Rhai script on what happens, none if not set
+    #[cfg(feature = "scripting")]
+    pub scripting: Option<crate::scripting::ScriptingConfig>,
+    /// WebAssembly modules providing authenticators, filters and backends, by name
+    #[cfg(feature = "plugins")]
+    pub plugins: BTreeMap<String, crate::plugin::PluginConfig>,
+}
+
+/// How clients are [authenticated](crate::auth)
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Which of the settings below tokens are checked with
+    pub backend: AuthBackend,
+    /// For the `token` backend, clients have to present one of these. Anybody may connect if
+    /// this is empty.
+    pub tokens: Vec<String>,
+    /// For the `jwt` backend
+    pub jwt: Option<JwtConfig>,
+    /// For the `command` backend
+    pub command: Option<AuthCommand>,
+    /// For the `plugin` backend, the [plugin](crate::plugin) tokens are checked by
+    pub plugin: Option<String>,
+    /// Seconds the [tickets](crate::auth::Tickets) clients may connect with stay valid, none are
+    /// issued if not set
+    pub ticket_ttl: Option<u64>,
+    /// When clients failing to authenticate are locked out, never if not set
+    pub lockout: Option<LockoutConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthBackend {
+    /// Tokens from a list, see [`StaticTokens`](crate::auth::StaticTokens)
+    #[default]
+    Token,
+    /// JSON Web Tokens, see [`Jwt`](crate::auth::Jwt)
+    Jwt,
+    /// Tokens checked by a program, see [`ExternalCommand`](crate::auth::ExternalCommand)
+    Command,
+    /// Tokens checked by a plugin, if the server is built with the `plugins` feature
+    Plugin,
+}
+
+impl fmt::Display for AuthBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthBackend::Token => write!(f, "token"),
+            AuthBackend::Jwt => write!(f, "JWT"),
+            AuthBackend::Command => write!(f, "command"),
+            AuthBackend::Plugin => write!(f, "plugin"),
+        }
+    }
+}
+
+/// Unix domain socket for a reverse proxy on the same host, which terminates TLS for its clients.
+/// Connections through it are never TLS, whatever [`Config::tls`] says.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnixSocketConfig {
+    /// Where the socket is created, replacing a socket an earlier run left there
+    pub path: PathBuf,
+    /// Permissions of the socket like `0o660`, connecting needs write permission. What the
+    /// umask leaves if not set.
+    pub mode: Option<u32>,
+    /// User the socket belongs to, the one the server runs as if not set
+    pub owner: Option<String>,
+    /// Group the socket belongs to, say the one the proxy runs as
+    pub group: Option<String>,
+}
+
+/// Another place the server accepts connections at, see [`listener`](crate::listener). Clients
+/// connecting there are served under the server's configuration with the policies set here.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Tells the listener apart from the others in the logs
+    pub name: String,
+    /// TCP address to listen on
+    pub listen: Option<SocketAddr>,
+    /// Unix domain socket to listen on, instead of or besides the TCP address
+    pub unix_socket: Option<UnixSocketConfig>,
+    /// Serve TCP connections without TLS even if [`Config::tls`] is set
+    #[serde(default)]
+    pub plaintext: bool,
+    /// How clients of this listener are authenticated, like the server's if not set. Tickets
+    /// issued here are only good here then.
+    pub auth: Option<AuthConfig>,
+    /// The only profiles clients of this listener may start, they may not start commands either.
+    /// Any the server has if not set.
+    pub profiles: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Address the admin listener binds to, better not reachable by clients
+    pub listen: SocketAddr,
+    /// Operators have to present one of these as `Authorization: Bearer <token>` header to list
+    /// and kill sessions. The session endpoints are off if this is empty.
+    #[serde(default)]
+    pub tokens: Vec<String>,
+}
+
+/// Where the [audit trail](crate::audit) of sessions goes, nowhere by default
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// File records are appended to as JSON lines
+    pub file: Option<PathBuf>,
+    /// Send records to the local syslog daemon as well, with facility `authpriv`
+    pub syslog: bool,
+}
+
+/// Where [recordings](crate::recording) of terminal sessions are kept
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordingConfig {
+    pub dir: PathBuf,
+    /// Name of the file of each session, `{session}` is replaced by the id of the session and
+    /// `{time}` by the Unix time it started at
+    #[serde(default = "default_recording_file_name")]
+    pub file_name: String,
+    /// What is recorded of the input of sessions, that of their profile if it says
+    #[serde(default)]
+    pub input: InputLogging,
+}
+
+fn default_recording_file_name() -> String {
+    "{time}-{session}.cast".to_string()
+}
+
+/// Where the output of sessions is [captured](crate::capture) to
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureConfig {
+    pub dir: PathBuf,
+    /// Name of the file of each session, `{session}` is replaced by the id of the session and
+    /// `{time}` by the Unix time it started at
+    #[serde(default = "default_capture_file_name")]
+    pub file_name: String,
+    /// Bytes a file may grow to before it's rotated, it never is if not set
+    pub max_size: Option<u64>,
+    /// Rotated files kept per session, the oldest are removed
+    #[serde(default = "default_capture_keep")]
+    pub keep: usize,
+    /// Compress rotated files with gzip
+    #[serde(default)]
+    pub compress: bool,
+}
+
+fn default_capture_file_name() -> String {
+    "{time}-{session}.log".to_string()
+}
+
+fn default_capture_keep() -> usize {
+    5
+}
+
+/// Where clients may upload files to and download them from, see [`transfer`](crate::transfer)
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileTransferConfig {
+    /// Paths clients ask for are taken relative to this directory and may not leave it
+    pub root: PathBuf,
+    /// Largest file that may be transferred, in bytes
+    #[serde(default = "default_max_file_size")]
+    pub max_size: u64,
+}
+
+fn default_max_file_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+/// Serial devices clients may open sessions on, none by default
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SerialConfig {
+    /// Device paths or glob patterns like `/dev/ttyUSB*`, where `*` doesn't cross `/`. Devices
+    /// are matched as given and after resolving symlinks, so `/dev/serial/by-id/*` works.
+    #[serde(deserialize_with = "deserialize_patterns")]
+    pub devices: Vec<Pattern>,
+}
+
+impl SerialConfig {
+    /// Check whether a session may be opened on `device`
+    pub fn check(&self, device: &Path) -> Result<()> {
+        let refused = || anyhow!("serial device '{}' is not allowed", device.display());
+
+        // patterns are no help against climbing out of the directories they name
+        if !device.is_absolute() || device.components().any(|c| c == Component::ParentDir) {
+            return Err(refused());
+        }
+        let mut candidates = vec![device.to_path_buf()];
+        if let Ok(canonical) = fs::canonicalize(device) {
+            candidates.push(canonical);
+        }
+        let allowed = self
+            .devices
+            .iter()
+            .any(|pattern| candidates.iter().any(|path| matches_path(pattern, path)));
+        match allowed {
+            true => Ok(()),
+            false => Err(refused()),
+        }
+    }
+}
+
+/// TCP ports clients may forward to, see [`forward`](crate::forward). None by default.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardingConfig {
+    /// Destinations as `host:port` or glob patterns like `localhost:*`. Hosts are matched by the
+    /// name clients give, before it is resolved.
+    #[serde(deserialize_with = "deserialize_patterns")]
+    pub allow: Vec<Pattern>,
+}
+
+impl ForwardingConfig {
+    /// Check whether a channel may be opened to `port` of `host`
+    pub fn check(&self, host: &str, port: u16) -> Result<()> {
+        let destination = format!("{}:{}", host, port);
+        match self
+            .allow
+            .iter()
+            .any(|pattern| pattern.matches(&destination))
+        {
+            true => Ok(()),
+            false => Err(anyhow!("forwarding to {} is not allowed", destination)),
+        }
+    }
+}
+
+/// Web pages whose scripts may use the server, by the `Origin` header browsers send along with
+/// their requests. Without it any page a user visits could connect with the user's credentials,
+/// so only pages served from where the request went are allowed by default. Requests without
+/// the header don't come from browsers and are always allowed.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OriginConfig {
+    /// Origins as `scheme://host[:port]` or glob patterns like `https://*.example.com`, `*` for
+    /// any page. Behind a reverse proxy changing the `Host` header the proxy's origin has to be
+    /// listed.
+    #[serde(deserialize_with = "deserialize_patterns")]
+    pub allow: Vec<Pattern>,
+}
+
+impl OriginConfig {
+    /// Check whether a page from `origin` may use the server, reached at `host` as the `Host`
+    /// header of the request gives it
+    pub fn check(&self, origin: &str, host: Option<&str>) -> Result<()> {
+        let same_origin = origin.split_once("://").is_some_and(|(_, authority)| {
+            host.is_some_and(|host| authority.eq_ignore_ascii_case(host))
+        });
+        match same_origin || self.allow.iter().any(|pattern| pattern.matches(origin)) {
+            true => Ok(()),
+            false => Err(anyhow!("requests from pages of {} are not allowed", origin)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Connections served at the same time, further ones are turned away
+    pub max_connections: Option<usize>,
+    /// Connections served at the same time for the same IP address. Connections of trusted
+    /// proxies count for the client they forward.
+    pub max_connections_per_ip: Option<usize>,
+    /// Seconds clients get from connecting until the WebSocket handshake is done, the PROXY
+    /// protocol header, TLS and authentication included. 10 if not set.
+    pub handshake_timeout: Option<u64>,
+    /// Sessions a single connection may have open at the same time
+    pub max_sessions: Option<usize>,
+    /// Sessions a single client may have running at the same time, over all its connections.
+    /// Clients are told apart by their certificate, else by the user they authenticated as, else
+    /// by their token, else by their IP address.
+    pub max_client_sessions: Option<usize>,
+    /// Sessions that may run on the server at the same time
+    pub max_total_sessions: Option<usize>,
+    /// Forwarding channels a single connection may have open at the same time
+    pub max_channels: Option<usize>,
+    /// Seconds the command of a session may run before it is terminated. Clients may ask for a
+    /// shorter timeout, not for a longer one.
+    pub command_timeout: Option<u64>,
+    /// How often clients may connect from the same IP address
+    pub connections_per_ip: Option<RateLimit>,
+    /// How often clients may connect with the same token
+    pub connections_per_token: Option<RateLimit>,
+    /// How fast the output of each session may be sent
+    pub session_bandwidth: Option<Bandwidth>,
+    /// How fast the output of all sessions together may be sent
+    pub total_bandwidth: Option<Bandwidth>,
+    /// Bytes a single input message may carry, larger ones are refused rather than cut short
+    pub max_input_size: Option<usize>,
+    /// How fast input may be written to each session, input beyond it is refused. Input messages
+    /// larger than the burst are never taken.
+    pub input_rate: Option<Bandwidth>,
+    /// Sessions a single message may broadcast input to
+    pub max_broadcast_sessions: Option<usize>,
+    /// Commands a single connection may have waiting for their start time
+    pub max_scheduled: Option<usize>,
+    /// Commands without a terminal that may run on the server at the same time, further ones
+    /// wait in a queue serving clients in turn
+    pub max_running_commands: Option<usize>,
+    /// Caps on what the command of each session may use
+    pub resources: ResourceLimits,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Bytes of recent output kept per session and replayed to clients attaching to it
+    pub scrollback: usize,
+    /// Keep sessions running when their connection drops, so that the client can attach them
+    /// again after reconnecting. They are hung up with the connection otherwise.
+    pub detach_on_disconnect: bool,
+    /// Seconds a session may stay detached before it is hung up, 0 to keep it forever
+    pub session_ttl: u64,
+    /// Seconds a terminal session may see neither input nor output before its clients are warned
+    /// that it will be closed or suspended, 0 to leave idle sessions alone
+    pub idle_timeout: u64,
+    /// Seconds between the warning and closing or suspending the session
+    pub idle_grace: u64,
+    /// What is done to sessions that stayed idle
+    pub idle_action: IdleAction,
+    /// Let commands of sessions intercepting OSC 52 read the clipboard of their writer, which is
+    /// asked for it. Off by default, as whatever the user copied anywhere could be read.
+    pub clipboard_read: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            scrollback: 64 * 1024,
+            detach_on_disconnect: false,
+            session_ttl: 300,
+            idle_timeout: 0,
+            idle_grace: 60,
+            idle_action: IdleAction::default(),
+            clipboard_read: false,
+        }
+    }
+}
+
+impl SessionConfig {
+    /// How long sessions may stay detached, `None` if forever
+    pub fn session_ttl(&self) -> Option<Duration> {
+        (self.session_ttl > 0).then(|| Duration::from_secs(self.session_ttl))
+    }
+
+    /// How long terminal sessions may idle before their clients are warned, `None` if forever
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout > 0).then(|| Duration::from_secs(self.idle_timeout))
+    }
+}
+
+/// Backpressure on the output of sessions. Once the output queued for a connection reaches the
+/// high watermark, its sessions' commands aren't read from until the client caught up to the low
+/// watermark, so that a slow client blocks the commands rather than having their output pile up
+/// in memory.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlowControl {
+    /// Bytes of output queued for a connection at which its sessions are paused
+    pub high_watermark: usize,
+    /// Bytes of output queued for a connection at which paused sessions are resumed
+    pub low_watermark: usize,
+}
+
+impl Default for FlowControl {
+    fn default() -> Self {
+        FlowControl {
+            high_watermark: 1024 * 1024,
+            low_watermark: 256 * 1024,
+        }
+    }
+}
+
+/// Output of a session arriving in reads shortly after one another is sent in one frame, sparing
+/// chatty commands the overhead of a frame for every read. Off unless a window is set.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Coalescing {
+    /// Milliseconds to wait for more output after each read, 0 to send every read right away
+    pub window: u64,
+    /// Milliseconds output may be held back at most however much more keeps arriving, so that
+    /// typing into a busy terminal still echoes in time
+    pub max_delay: u64,
+    /// Choose the window and the size of frames from the round trip to the clients measured by
+    /// pings instead: small frames sent right away to clients close by, bigger ones gathered
+    /// for a while for those far away. Still no longer than `max_delay`.
+    pub adaptive: bool,
+}
+
+impl Default for Coalescing {
+    fn default() -> Self {
+        Coalescing {
+            window: 0,
+            max_delay: 20,
+            adaptive: false,
+        }
+    }
+}
+
+impl Coalescing {
+    /// How long to wait for more output after each read, `None` if not at all
+    pub fn window(&self) -> Option<Duration> {
+        (self.window > 0).then(|| Duration::from_millis(self.window))
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_millis(self.max_delay)
+    }
+}
+
+/// Detection of clients that went away without closing their connection. Their sessions would
+/// keep running forever otherwise.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Keepalive {
+    /// Seconds between pings sent to the client
+    pub interval: u64,
+    /// Seconds after which a client that sent nothing, not even a pong, is disconnected and its
+    /// sessions are terminated
+    pub timeout: u64,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            interval: 30,
+            timeout: 90,
+        }
+    }
+}
+
+impl Limits {
+    /// How long clients get to finish the handshake
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT))
+    }
+}
+
+impl Keepalive {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: DEFAULT_LISTEN_ADDR.parse().expect("valid default address"),
+            unix_socket: None,
+            listeners: vec![],
+            shell: default_shell(),
+            env: Environment::default(),
+            commands: CommandPolicy::default(),
+            policies: vec![],
+            profiles: BTreeMap::new(),
+            docker: DockerConfig::default(),
+            jail: None,
+            run_as: None,
+            hardening: Hardening::default(),
+            tls: None,
+            proxy: ProxyConfig::default(),
+            addresses: AddressPolicy::default(),
+            auth: AuthConfig::default(),
+            admin: None,
+            audit: AuditConfig::default(),
+            webhooks: vec![],
+            recording: None,
+            capture: None,
+            redaction: RedactionConfig::default(),
+            files: None,
+            serial: SerialConfig::default(),
+            forwarding: ForwardingConfig::default(),
+            origins: OriginConfig::default(),
+            limits: Limits::default(),
+            sessions: SessionConfig::default(),
+            keepalive: Keepalive::default(),
+            flow_control: FlowControl::default(),
+            coalescing: Coalescing::default(),
+            log: LogConfig::default(),
+            #[cfg(feature = "otel")]
+            otel: None,
+            #[cfg(feature = "history")]
+            history: None,
+            #[cfg(feature = "mqtt")]
+            mqtt: None,
+            #[cfg(feature = "grpc")]
+            grpc: None,
+            #[cfg(feature = "webtransport")]
+            webtransport: None,
+            #[cfg(feature = "scripting")]
+            scripting: None,
+            #[cfg(feature = "plugins")]
+            plugins: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// The policy applying to `client`, if any
+    pub fn policy_for(&self, client: &Client) -> Option<&UserPolicy> {
+        self.policies
+            .iter()
+            .find(|policy| policy.applies_to(client))
+    }
+
+    /// The profile named `name`
+    pub fn profile(&self, name: &str) -> Result<&Profile, UnknownProfile> {
+        self.profiles.get(name).ok_or_else(|| UnknownProfile {
+            name: name.to_string(),
+        })
+    }
+
+    /// The configuration clients of `listener` are served under: this one, with the policies of
+    /// the listener instead of the server's
+    pub fn for_listener(&self, listener: &ListenerConfig) -> Config {
+        let mut config = self.clone();
+        if let Some(auth) = listener.auth.as_ref() {
+            config.auth = auth.clone();
+        }
+        if let Some(profiles) = listener.profiles.as_ref() {
+            config.profiles.retain(|name, _| profiles.contains(name));
+            config.commands.profiles_only = true;
+        }
+        config
+    }
+
+    /// Read the configuration from the TOML file at `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        let err_context = || format!("failed to load config from '{}'", path.display());
+
+        let contents = fs::read_to_string(path).with_context(err_context)?;
+        toml::from_str(&contents).with_context(err_context)
+    }
+
+    /// The settings `reloaded` changes that are only taken over by restarting the server
+    pub fn restart_needed(&self, reloaded: &Config) -> Vec<&'static str> {
+        // most of these have no `PartialEq`, and they're only compared on reloads
+        fn changed<T: fmt::Debug>(old: &T, new: &T) -> bool {
+            format!("{:?}", old) != format!("{:?}", new)
+        }
+
+        let mut needed = vec![];
+        let mut check = |name, changes| {
+            if changes {
+                needed.push(name);
+            }
+        };
+        check("listen", self.listen != reloaded.listen);
+        check("unix_socket", changed(&self.unix_socket, &reloaded.unix_socket));
+        // the policies of the listeners are reloaded, where they listen is not
+        let bound = |config: &Config| {
+            config
+                .listeners
+                .iter()
+                .map(|listener| {
+                    let unix_socket = format!("{:?}", listener.unix_socket);
+                    (listener.name.clone(), listener.listen, unix_socket, listener.plaintext)
+                })
+                .collect::<Vec<_>>()
+        };
+        check("listeners", bound(self) != bound(reloaded));
+        check("tls", changed(&self.tls, &reloaded.tls));
+        check("admin", changed(&self.admin, &reloaded.admin));
+        check("audit", changed(&self.audit, &reloaded.audit));
+        check("webhooks", changed(&self.webhooks, &reloaded.webhooks));
+        check(
+            "limits.total_bandwidth",
+            changed(&self.limits.total_bandwidth, &reloaded.limits.total_bandwidth),
+        );
+        check(
+            "limits.max_running_commands",
+            self.limits.max_running_commands != reloaded.limits.max_running_commands,
+        );
+        check("log.format", self.log.format != reloaded.log.format);
+        #[cfg(feature = "otel")]
+        check("otel", changed(&self.otel, &reloaded.otel));
+        #[cfg(feature = "history")]
+        check("history", changed(&self.history, &reloaded.history));
+        #[cfg(feature = "mqtt")]
+        check("mqtt", changed(&self.mqtt, &reloaded.mqtt));
+        #[cfg(feature = "grpc")]
+        check("grpc", changed(&self.grpc, &reloaded.grpc));
+        #[cfg(feature = "webtransport")]
+        check("webtransport", changed(&self.webtransport, &reloaded.webtransport));
+        #[cfg(feature = "scripting")]
+        check("scripting", changed(&self.scripting, &reloaded.scripting));
+        #[cfg(feature = "plugins")]
+        check("plugins", changed(&self.plugins, &reloaded.plugins));
+        needed
+    }
+
+    /// Check the settings for consistency
+    pub fn validate(&self) -> Result<()> {
+        if self.keepalive.interval == 0 || self.keepalive.timeout < self.keepalive.interval {
+            return Err(anyhow!(
+                "keepalive interval must be positive and not longer than the timeout"
+            ));
+        }
+        if self.flow_control.high_watermark == 0
+            || self.flow_control.low_watermark > self.flow_control.high_watermark
+        {
+            return Err(anyhow!(
+                "flow control high watermark must be positive and not below the low watermark"
+            ));
+        }
+        if self.coalescing.max_delay < self.coalescing.window {
+            return Err(anyhow!("coalescing max_delay must not be shorter than the window"));
+        }
+        if self.limits.max_running_commands == Some(0) {
+            return Err(anyhow!("max_running_commands must be positive"));
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if listener.listen.is_none() && listener.unix_socket.is_none() {
+                return Err(anyhow!(
+                    "listener '{}' needs an address or a Unix socket",
+                    listener.name
+                ));
+            }
+            if self.listeners[..i]
+                .iter()
+                .any(|other| other.name == listener.name)
+            {
+                return Err(anyhow!("there is more than one listener '{}'", listener.name));
+            }
+            let mut profiles = listener.profiles.iter().flatten();
+            if let Some(name) = profiles.find(|name| self.profile(name).is_err()) {
+                return Err(anyhow!(
+                    "listener '{}' allows profile '{}' that doesn't exist",
+                    listener.name,
+                    name
+                ));
+            }
+        }
+        if let Some(jail) = self.jail.as_ref() {
+            if !jail.root.is_dir() {
+                return Err(anyhow!("jail '{}' is not a directory", jail.root.display()));
+            }
+            if jail.chroot && !Uid::effective().is_root() {
+                return Err(anyhow!("chrooting into the jail requires running as root"));
+            }
+        }
+        for (name, profile) in self.profiles.iter() {
+            let rootfs = match profile.isolation {
+                Isolation::Namespaces => profile.rootfs.as_ref(),
+                Isolation::None => None,
+            };
+            if let Some(rootfs) = rootfs {
+                if !rootfs.is_dir() {
+                    return Err(anyhow!(
+                        "root filesystem '{}' of profile '{}' is not a directory",
+                        rootfs.display(),
+                        name
+                    ));
+                }
+                if self.jail.is_some() {
+                    return Err(anyhow!(
+                        "profile '{}' can't have a root filesystem of its own with the jail",
+                        name
+                    ));
+                }
+            }
+        }
+        if let Some(recording) = self.recording.as_ref() {
+            if !recording.dir.is_dir() {
+                return Err(anyhow!(
+                    "recording directory '{}' is not a directory",
+                    recording.dir.display()
+                ));
+            }
+        }
+        if let Some(capture) = self.capture.as_ref() {
+            if !capture.dir.is_dir() {
+                return Err(anyhow!(
+                    "capture directory '{}' is not a directory",
+                    capture.dir.display()
+                ));
+            }
+            if capture.max_size == Some(0) {
+                return Err(anyhow!("captured files need a max_size above 0"));
+            }
+        }
+        if let Some(files) = self.files.as_ref() {
+            if !files.root.is_dir() {
+                return Err(anyhow!(
+                    "file root '{}' is not a directory",
+                    files.root.display()
+                ));
+            }
+        }
+        if let Some(run_as) = self.run_as.as_ref() {
+            let user = run_as.credentials()?;
+            let euid = Uid::effective();
+            if !euid.is_root() && user.uid != euid {
+                return Err(anyhow!(
+                    "running commands as '{}' requires running as root",
+                    run_as.user
+                ));
+            }
+        }
+        #[cfg(feature = "webtransport")]
+        if self.webtransport.is_some() && self.tls.is_none() {
+            return Err(anyhow!("WebTransport needs TLS, set up `tls` as well"));
+        }
+        Ok(())
+    }
+}
+
+/// The shell specified by environment variable `SHELL`, falling back to `/bin/sh`.
+pub fn default_shell() -> RunCommand {
+    RunCommand {
+        command: env::var_os("SHELL")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("/bin/sh")),
+        ..Default::default()
+    }
+}
+
+fn deserialize_shell<'de, D>(deserializer: D) -> Result<RunCommand, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Shell {
+        Path(PathBuf),
+        Command(RunCommand),
+    }
+
+    Ok(match Shell::deserialize(deserializer)? {
+        Shell::Path(command) => RunCommand {
+            command,
+            ..Default::default()
+        },
+        Shell::Command(command) => command,
+    })
+}
+
+/// Command line of the server. Flags take precedence over the config file.
+#[derive(Clone, Debug, Parser)]
+#[command(version, about = "Serve shell sessions over WebSockets")]
+pub struct Cli {
+    /// TOML file to read the configuration from
+    #[arg(short, long, env = "SHWS_CONFIG", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(short, long, env = "SHWS_LISTEN", value_name = "ADDR")]
+    pub listen: Option<SocketAddr>,
+    /// Unix domain socket to listen on as well
+    #[arg(long, env = "SHWS_UNIX_SOCKET", value_name = "PATH")]
+    pub unix_socket: Option<PathBuf>,
+    /// Command spawned for sessions that don't ask for a specific one
+    #[arg(long, value_name = "PATH")]
+    pub shell: Option<PathBuf>,
+    /// Executable, or glob pattern of executables, clients may start. Can be given multiple times.
+    #[arg(long = "allow-command", value_name = "PATTERN")]
+    pub allowed_commands: Vec<Pattern>,
+    /// Executable, or glob pattern of executables, clients may not start. Can be given multiple
+    /// times.
+    #[arg(long = "deny-command", value_name = "PATTERN")]
+    pub denied_commands: Vec<Pattern>,
+    /// PEM file with the certificate chain, enables TLS
+    #[arg(long, env = "SHWS_TLS_CERT", value_name = "FILE", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of the certificate
+    #[arg(long, env = "SHWS_TLS_KEY", value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// PEM file with the CAs client certificates must be signed by
+    #[arg(long, env = "SHWS_TLS_CLIENT_CA", value_name = "FILE")]
+    pub tls_client_ca: Option<PathBuf>,
+    /// Expect the PROXY protocol header at the start of every TCP connection
+    #[arg(long)]
+    pub proxy_protocol: bool,
+    /// Address, or network like `10.0.0.0/8`, of proxies whose `Forwarded` and
+    /// `X-Forwarded-For` headers are believed. Can be given multiple times.
+    #[arg(long = "trust-proxy", value_name = "NETWORK", value_parser = parse_network)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Network, or address, clients may connect from. Can be given multiple times.
+    #[arg(long = "allow-address", value_name = "NETWORK", value_parser = parse_network)]
+    pub allowed_addresses: Vec<IpNet>,
+    /// Network, or address, clients may not connect from. Can be given multiple times.
+    #[arg(long = "deny-address", value_name = "NETWORK", value_parser = parse_network)]
+    pub denied_addresses: Vec<IpNet>,
+    /// Token clients have to present, can be given multiple times
+    #[arg(
+        long = "auth-token",
+        env = "SHWS_AUTH_TOKENS",
+        value_name = "TOKEN",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    pub auth_tokens: Vec<String>,
+    /// Address to serve metrics and health probes on
+    #[arg(long, env = "SHWS_ADMIN_LISTEN", value_name = "ADDR")]
+    pub admin_listen: Option<SocketAddr>,
+    /// Token operators may list and kill sessions with, can be given multiple times
+    #[arg(
+        long = "admin-token",
+        env = "SHWS_ADMIN_TOKENS",
+        value_name = "TOKEN",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    pub admin_tokens: Vec<String>,
+    /// File to append the audit trail of sessions to
+    #[arg(long, env = "SHWS_AUDIT_FILE", value_name = "FILE")]
+    pub audit_file: Option<PathBuf>,
+    /// Send the audit trail of sessions to syslog
+    #[arg(long)]
+    pub audit_syslog: bool,
+    /// Directory to record terminal sessions to
+    #[arg(long, env = "SHWS_RECORDING_DIR", value_name = "DIR")]
+    pub recording_dir: Option<PathBuf>,
+    /// Directory clients may transfer files within
+    #[arg(long, env = "SHWS_FILE_ROOT", value_name = "DIR")]
+    pub file_root: Option<PathBuf>,
+    /// Largest file clients may transfer, in bytes
+    #[arg(long, value_name = "BYTES")]
+    pub max_file_size: Option<u64>,
+    /// Serial device, or glob pattern of devices, clients may open sessions on. Can be given
+    /// multiple times.
+    #[arg(long = "serial-device", value_name = "PATTERN")]
+    pub serial_devices: Vec<Pattern>,
+    /// Destination `host:port`, or glob pattern of destinations, clients may forward TCP ports
+    /// to. Can be given multiple times.
+    #[arg(long = "forward-allow", value_name = "PATTERN")]
+    pub forward_allow: Vec<Pattern>,
+    /// Origin, or glob pattern of origins, of web pages that may use the server. Can be given
+    /// multiple times.
+    #[arg(long = "allow-origin", value_name = "PATTERN")]
+    pub allowed_origins: Vec<Pattern>,
+    /// Connections served at the same time
+    #[arg(long, value_name = "N")]
+    pub max_connections: Option<usize>,
+    /// Connections served at the same time for the same IP address
+    #[arg(long, value_name = "N")]
+    pub max_connections_per_ip: Option<usize>,
+    /// Sessions a single connection may have open at the same time
+    #[arg(long, value_name = "N")]
+    pub max_sessions: Option<usize>,
+    /// Sessions a single client may have running at the same time
+    #[arg(long, value_name = "N")]
+    pub max_client_sessions: Option<usize>,
+    /// Sessions that may run on the server at the same time
+    #[arg(long, value_name = "N")]
+    pub max_total_sessions: Option<usize>,
+    /// Seconds the command of a session may run before it is terminated
+    #[arg(long, value_name = "SECS")]
+    pub command_timeout: Option<u64>,
+    /// Connections per minute accepted from the same IP address
+    #[arg(long, value_name = "N")]
+    pub ip_rate_limit: Option<u32>,
+    /// Connections per minute accepted with the same token
+    #[arg(long, value_name = "N")]
+    pub token_rate_limit: Option<u32>,
+    /// Bytes per second of output sent for each session
+    #[arg(long, value_name = "BYTES")]
+    pub session_bandwidth: Option<u64>,
+    /// Bytes per second of output sent for all sessions together
+    #[arg(long, value_name = "BYTES")]
+    pub total_bandwidth: Option<u64>,
+    /// Bytes of recent output kept per session for clients attaching to it
+    #[arg(long, value_name = "BYTES")]
+    pub scrollback: Option<usize>,
+    /// Keep sessions running when their connection drops
+    #[arg(long)]
+    pub detach_on_disconnect: bool,
+    /// Seconds a session may stay detached before it is hung up, 0 to keep it forever
+    #[arg(long, value_name = "SECS")]
+    pub session_ttl: Option<u64>,
+    /// Seconds a terminal session may idle before it is closed or suspended, 0 for forever
+    #[arg(long, value_name = "SECS")]
+    pub idle_timeout: Option<u64>,
+    /// Directory tree sessions are confined to
+    #[arg(long, value_name = "DIR")]
+    pub jail: Option<PathBuf>,
+    /// Also change the root directory of commands to the jail, requires running as root
+    #[arg(long, requires = "jail")]
+    pub chroot: bool,
+    /// User to spawn commands as, requires running as root
+    #[arg(long, value_name = "USER")]
+    pub run_as: Option<String>,
+    /// Permit spawning commands as root with --run-as
+    #[arg(long, requires = "run_as")]
+    pub allow_root: bool,
+    /// Seconds of CPU time each process may use
+    #[arg(long, value_name = "SECS")]
+    pub cpu_time_limit: Option<u64>,
+    /// Bytes of memory a session may use
+    #[arg(long, value_name = "BYTES")]
+    pub memory_limit: Option<u64>,
+    /// Files each process may have open
+    #[arg(long, value_name = "N")]
+    pub open_files_limit: Option<u64>,
+    /// Processes a session may run at the same time
+    #[arg(long, value_name = "N")]
+    pub processes_limit: Option<u64>,
+    /// cgroup v2 directory sessions get their own cgroup in
+    #[arg(long, value_name = "DIR")]
+    pub cgroup: Option<PathBuf>,
+    /// Seconds between pings sent to clients
+    #[arg(long, value_name = "SECS")]
+    pub keepalive_interval: Option<u64>,
+    /// Seconds of silence after which a client is disconnected and its sessions are terminated
+    #[arg(long, value_name = "SECS")]
+    pub keepalive_timeout: Option<u64>,
+    /// How to write the log, `text` or `json`
+    #[arg(long, env = "SHWS_LOG_FORMAT", value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Which events to log, in the syntax of `RUST_LOG`, which still overrides it
+    #[arg(long, env = "SHWS_LOG_FILTER", value_name = "DIRECTIVES")]
+    pub log_filter: Option<String>,
+    /// Export traces and metrics to the OTLP/HTTP collector at this URL
+    #[cfg(feature = "otel")]
+    #[arg(long, env = "SHWS_OTEL_ENDPOINT", value_name = "URL")]
+    pub otel_endpoint: Option<String>,
+}
+
+impl Cli {
+    /// Load the config file, if any, and apply the command line on top of it
+    pub fn into_config(self) -> Result<Config> {
+        let mut config = match self.config.as_ref() {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if let Some(listen) = self.listen {
+            config.listen = listen;
+        }
+        if let Some(path) = self.unix_socket {
+            match config.unix_socket.as_mut() {
+                Some(unix_socket) => unix_socket.path = path,
+                None => {
+                    config.unix_socket = Some(UnixSocketConfig {
+                        path,
+                        mode: None,
+                        owner: None,
+                        group: None,
+                    })
+                },
+            }
+        }
+        if let Some(shell) = self.shell {
+            config.shell = RunCommand {
+                command: shell,
+                ..Default::default()
+            };
+        }
+        if !self.allowed_commands.is_empty() {
+            config.commands.allow = self.allowed_commands;
+        }
+        if !self.denied_commands.is_empty() {
+            config.commands.deny = self.denied_commands;
+        }
+        if let (Some(cert), Some(key)) = (self.tls_cert, self.tls_key) {
+            let client_ca = config.tls.take().and_then(|tls| tls.client_ca);
+            config.tls = Some(TlsConfig {
+                cert,
+                key,
+                client_ca,
+            });
+        }
+        if let Some(client_ca) = self.tls_client_ca {
+            config
+                .tls
+                .as_mut()
+                .ok_or_else(|| anyhow!("client certificates can only be required with TLS"))?
+                .client_ca = Some(client_ca);
+        }
+        if self.proxy_protocol {
+            config.proxy.protocol = true;
+        }
+        if !self.trusted_proxies.is_empty() {
+            config.proxy.trusted = self.trusted_proxies;
+        }
+        if !self.allowed_addresses.is_empty() {
+            config.addresses.allow = self.allowed_addresses;
+        }
+        if !self.denied_addresses.is_empty() {
+            config.addresses.deny = self.denied_addresses;
+        }
+        if !self.auth_tokens.is_empty() {
+            config.auth.tokens = self.auth_tokens;
+        }
+        if let Some(listen) = self.admin_listen {
+            match config.admin.as_mut() {
+                Some(admin) => admin.listen = listen,
+                None => {
+                    config.admin = Some(AdminConfig {
+                        listen,
+                        tokens: vec![],
+                    })
+                },
+            }
+        }
+        if !self.admin_tokens.is_empty() {
+            config
+                .admin
+                .as_mut()
+                .ok_or_else(|| anyhow!("admin tokens need an admin listener"))?
+                .tokens = self.admin_tokens;
+        }
+        if let Some(file) = self.audit_file {
+            config.audit.file = Some(file);
+        }
+        if self.audit_syslog {
+            config.audit.syslog = true;
+        }
+        if let Some(dir) = self.recording_dir {
+            let (file_name, input) = config.recording.take().map_or_else(
+                || (default_recording_file_name(), InputLogging::default()),
+                |recording| (recording.file_name, recording.input),
+            );
+            config.recording = Some(RecordingConfig {
+                dir,
+                file_name,
+                input,
+            });
+        }
+        if let Some(root) = self.file_root {
+            let max_size = config
+                .files
+                .as_ref()
+                .map_or_else(default_max_file_size, |files| files.max_size);
+            config.files = Some(FileTransferConfig { root, max_size });
+        }
+        if let (Some(max_size), Some(files)) = (self.max_file_size, config.files.as_mut()) {
+            files.max_size = max_size;
+        }
+        if !self.serial_devices.is_empty() {
+            config.serial.devices = self.serial_devices;
+        }
+        if !self.forward_allow.is_empty() {
+            config.forwarding.allow = self.forward_allow;
+        }
+        if !self.allowed_origins.is_empty() {
+            config.origins.allow = self.allowed_origins;
+        }
+        if let Some(max_connections) = self.max_connections {
+            config.limits.max_connections = Some(max_connections);
+        }
+        if let Some(max_connections) = self.max_connections_per_ip {
+            config.limits.max_connections_per_ip = Some(max_connections);
+        }
+        if let Some(max_sessions) = self.max_sessions {
+            config.limits.max_sessions = Some(max_sessions);
+        }
+        if let Some(max_client_sessions) = self.max_client_sessions {
+            config.limits.max_client_sessions = Some(max_client_sessions);
+        }
+        if let Some(max_total_sessions) = self.max_total_sessions {
+            config.limits.max_total_sessions = Some(max_total_sessions);
+        }
+        if let Some(command_timeout) = self.command_timeout {
+            config.limits.command_timeout = Some(command_timeout);
+        }
+        if let Some(per_minute) = self.ip_rate_limit {
+            config.limits.connections_per_ip = Some(RateLimit {
+                per_minute,
+                burst: None,
+            });
+        }
+        if let Some(per_minute) = self.token_rate_limit {
+            config.limits.connections_per_token = Some(RateLimit {
+                per_minute,
+                burst: None,
+            });
+        }
+        if let Some(bytes_per_second) = self.session_bandwidth {
+            config.limits.session_bandwidth = Some(Bandwidth {
+                bytes_per_second,
+                burst: None,
+            });
+        }
+        if let Some(bytes_per_second) = self.total_bandwidth {
+            config.limits.total_bandwidth = Some(Bandwidth {
+                bytes_per_second,
+                burst: None,
+            });
+        }
+        if let Some(scrollback) = self.scrollback {
+            config.sessions.scrollback = scrollback;
+        }
+        let resources = &mut config.limits.resources;
+        if let Some(cpu_time) = self.cpu_time_limit {
+            resources.cpu_time = Some(cpu_time);
+        }
+        if let Some(memory) = self.memory_limit {
+            resources.memory = Some(memory);
+        }
+        if let Some(open_files) = self.open_files_limit {
+            resources.open_files = Some(open_files);
+        }
+        if let Some(processes) = self.processes_limit {
+            resources.processes = Some(processes);
+        }
+        if let Some(cgroup) = self.cgroup {
+            resources.cgroup = Some(cgroup);
+        }
+        if let Some(root) = self.jail {
+            config.jail = Some(Jail {
+                root,
+                chroot: self.chroot,
+            });
+        }
+        if let Some(user) = self.run_as {
+            config.run_as = Some(RunAs {
+                user,
+                allow_root: self.allow_root,
+            });
+        }
+        if self.detach_on_disconnect {
+            config.sessions.detach_on_disconnect = true;
+        }
+        if let Some(session_ttl) = self.session_ttl {
+            config.sessions.session_ttl = session_ttl;
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            config.sessions.idle_timeout = idle_timeout;
+        }
+        if let Some(interval) = self.keepalive_interval {
+            config.keepalive.interval = interval;
+        }
+        if let Some(timeout) = self.keepalive_timeout {
+            config.keepalive.timeout = timeout;
+        }
+        if let Some(format) = self.log_format {
+            config.log.format = format;
+        }
+        if let Some(filter) = self.log_filter {
+            config.log.filter = filter;
+        }
+        #[cfg(feature = "otel")]
+        if let Some(endpoint) = self.otel_endpoint {
+            config.otel.get_or_insert_with(Default::default).endpoint = Some(endpoint);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
@@ -1,6 +1,12 @@
-use std::{fmt, str::FromStr};
+use std::{collections::BTreeMap, fmt, net::SocketAddr, path::PathBuf, str::FromStr};
 
-use serde::{Deserialize, Serialize};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use uuid::Uuid;
+
+use crate::{command::RunCommand, error::ProtocolError};
 
 #[derive(Eq, Clone, Copy, Debug, PartialEq, Hash, Deserialize, Serialize, PartialOrd, Ord)]
 pub enum Direction {
@@ -55,3 +61,1177 @@ impl FromStr for Direction {
         }
     }
 }
+
+/// Major version of the [`Message`] protocol spoken by this build. Peers announce theirs with
+/// [`Message::Hello`] and only talk to peers of the same version, what was added since is told
+/// apart by [`Capability`].
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// WebSocket subprotocol of [`PROTOCOL_VERSION`], clients may offer it in the
+/// `Sec-WebSocket-Protocol` header of the upgrade request
+pub const SUBPROTOCOL: &str = "shws.v1";
+
+/// Why the server closed a connection, sent as the code of its WebSocket close frame. The reason
+/// of the frame says more, for humans.
+#[derive(Eq, Clone, Copy, Debug, PartialEq, Hash)]
+pub enum CloseReason {
+    /// The client speaks another major version of the protocol
+    UnsupportedVersion,
+    /// The token the client authenticated with stopped granting access, like a JWT expiring
+    AuthFailed,
+    /// The client broke one of the server's rules, like sending messages larger than allowed
+    PolicyViolation,
+    /// The server received nothing from the client for longer than its keepalive timeout
+    IdleTimeout,
+    /// The server is shutting down
+    Shutdown,
+    /// The client sent something that isn't WebSocket
+    ProtocolError,
+}
+
+impl CloseReason {
+    pub const ALL: [CloseReason; 6] = [
+        CloseReason::UnsupportedVersion,
+        CloseReason::AuthFailed,
+        CloseReason::PolicyViolation,
+        CloseReason::IdleTimeout,
+        CloseReason::Shutdown,
+        CloseReason::ProtocolError,
+    ];
+
+    /// Close code of the reason, in the range WebSocket leaves to applications
+    pub fn code(self) -> u16 {
+        match self {
+            CloseReason::UnsupportedVersion => 4000,
+            CloseReason::AuthFailed => 4001,
+            CloseReason::PolicyViolation => 4002,
+            CloseReason::IdleTimeout => 4003,
+            CloseReason::Shutdown => 4004,
+            CloseReason::ProtocolError => 4005,
+        }
+    }
+
+    /// The reason of close code `code`, `None` for codes this side doesn't know
+    pub fn from_code(code: u16) -> Option<Self> {
+        CloseReason::ALL
+            .into_iter()
+            .find(|reason| reason.code() == code)
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            CloseReason::UnsupportedVersion => "unsupported protocol version",
+            CloseReason::AuthFailed => "no longer authorized",
+            CloseReason::PolicyViolation => "policy violation",
+            CloseReason::IdleTimeout => "idle timeout",
+            CloseReason::Shutdown => "server shutting down",
+            CloseReason::ProtocolError => "protocol error",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Optional parts of the protocol, announced in [`Message::Hello`] so peers only use what the
+/// other side understands
+#[derive(Eq, Clone, Copy, Debug, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// The MessagePack [`Encoding`]
+    #[serde(rename = "msgpack")]
+    MsgPack,
+    /// [`Compression::Zstd`] of binary frames
+    Zstd,
+    /// [`Message::ResumeFrom`] and [`Message::Ack`]
+    Resume,
+    /// [`Message::FileUploadStart`] and [`Message::FileDownloadRequest`]
+    Transfer,
+    /// [`Message::ClipboardSet`] and friends, for sessions opened with `clipboard`
+    Clipboard,
+    /// [`Message::SessionMeta`], for sessions opened with `meta`
+    Meta,
+    /// [`Message::TcpForward`] and the channels it opens
+    Forward,
+    /// Anything a newer peer supports that this side doesn't know
+    #[serde(other)]
+    Unknown,
+}
+
+/// What this build supports of the protocol
+pub const CAPABILITIES: &[Capability] = &[
+    Capability::MsgPack,
+    Capability::Zstd,
+    Capability::Resume,
+    Capability::Transfer,
+    Capability::Clipboard,
+    Capability::Meta,
+    Capability::Forward,
+];
+
+/// Identifies one of the sessions hosted by a connection. Chosen by the client when opening the
+/// session.
+pub type SessionId = Uuid;
+
+/// Bytes read from or written to a terminal. Human readable encodings like JSON carry them as a
+/// string, replacing invalid UTF-8; binary encodings like MessagePack carry the raw bytes.
+///
+/// Clones share the bytes rather than copying them, output sent to several clients is held once.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Payload(pub Bytes);
+
+impl From<Vec<u8>> for Payload {
+    fn from(bytes: Vec<u8>) -> Self {
+        Payload(bytes.into())
+    }
+}
+
+impl From<Bytes> for Payload {
+    fn from(bytes: Bytes) -> Self {
+        Payload(bytes)
+    }
+}
+
+impl From<&str> for Payload {
+    fn from(text: &str) -> Self {
+        Payload(Bytes::copy_from_slice(text.as_bytes()))
+    }
+}
+
+impl Serialize for Payload {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&String::from_utf8_lossy(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Payload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PayloadVisitor;
+
+        impl<'de> de::Visitor<'de> for PayloadVisitor {
+            type Value = Payload;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string or bytes")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Payload, E> {
+                Ok(Payload::from(v))
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Payload, E> {
+                Ok(Payload(Bytes::copy_from_slice(v)))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Payload, E> {
+                Ok(Payload::from(v))
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Payload, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(Payload::from(bytes))
+            }
+        }
+
+        deserializer.deserialize_any(PayloadVisitor)
+    }
+}
+
+/// Identifies a file transfer of a connection. Chosen by the client when starting it.
+pub type TransferId = Uuid;
+
+/// Identifies a forwarding channel of a connection. Chosen by the client when opening it.
+pub type ChannelId = Uuid;
+
+/// Content of a file. Unlike [`Payload`], human readable encodings carry it as base64 so that
+/// files arrive byte for byte; binary encodings carry the raw bytes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Blob(pub Vec<u8>);
+
+impl Serialize for Blob {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&BASE64.encode(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Blob {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BlobVisitor;
+
+        impl<'de> de::Visitor<'de> for BlobVisitor {
+            type Value = Blob;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a base64 string or bytes")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Blob, E> {
+                BASE64.decode(v).map(Blob).map_err(E::custom)
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Blob, E> {
+                Ok(Blob(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Blob, E> {
+                Ok(Blob(v))
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Blob, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(Blob(bytes))
+            }
+        }
+
+        deserializer.deserialize_any(BlobVisitor)
+    }
+}
+
+/// Room in an encoded [`Message`] for everything besides the data it carries, so that encoding
+/// it rarely has to grow the buffer
+const ENCODING_OVERHEAD: usize = 256;
+
+/// How [`Message`]s are encoded on the wire. Picked by the client when connecting, JSON
+/// messages travel in text frames and MessagePack messages in binary frames.
+#[derive(Eq, Clone, Copy, Debug, Default, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    MsgPack,
+}
+
+impl Encoding {
+    /// Encode `message` into a buffer sized for the data it carries up front
+    pub fn encode(&self, message: &Message) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(message.data_len() + ENCODING_OVERHEAD);
+        match self {
+            Encoding::Json => serde_json::to_writer(&mut buf, message)
+                .context("failed to encode JSON message")?,
+            // internally tagged enums need structs encoded as maps rather than arrays
+            Encoding::MsgPack => message
+                .serialize(&mut rmp_serde::Serializer::new(&mut buf).with_struct_map())
+                .context("failed to encode MessagePack message")?,
+        }
+        Ok(buf)
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Result<Message> {
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes).context("invalid JSON message"),
+            Encoding::MsgPack => {
+                rmp_serde::from_slice(bytes).context("invalid MessagePack message")
+            },
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Json => write!(f, "json"),
+            Encoding::MsgPack => write!(f, "msgpack"),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Encoding::Json),
+            "msgpack" => Ok(Encoding::MsgPack),
+            _ => Err(format!("Failed to parse Encoding. Unknown Encoding: {}", s)),
+        }
+    }
+}
+
+/// Largest message a compressed frame may expand to, guarding against frames that decompress to
+/// far more than any message should be
+pub const MAX_DECOMPRESSED_LEN: usize = 16 * 1024 * 1024;
+
+/// Compression of binary frames, picked by the client when connecting. Compressed frames carry a
+/// zstd frame each, which holds the encoded message. Only MessagePack messages travel in binary
+/// frames, so JSON is never compressed.
+#[derive(Eq, Clone, Copy, Debug, Default, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Compression {
+    pub fn compress(&self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes),
+            Compression::Zstd => {
+                zstd::bulk::compress(&bytes, 0).context("failed to compress frame")
+            },
+        }
+    }
+
+    pub fn decompress(&self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes),
+            Compression::Zstd => zstd::bulk::decompress(&bytes, MAX_DECOMPRESSED_LEN)
+                .context("failed to decompress frame"),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Failed to parse Compression. Unknown Compression: {}", s)),
+        }
+    }
+}
+
+/// A signal given by its name, like `SIGINT` or `INT`, or by its number
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum SignalSpec {
+    Number(i32),
+    Name(String),
+}
+
+impl fmt::Display for SignalSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignalSpec::Number(number) => write!(f, "signal {}", number),
+            SignalSpec::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Output stream of a command running without a terminal
+#[derive(Eq, Clone, Copy, Debug, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StdStream {
+    Stdout,
+    Stderr,
+}
+
+/// Specifics of a [`ProtocolError`], for failures clients may want to handle
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ErrorDetail {
+    /// The server's command policy refused to start `command`. `rule` is the deny rule it
+    /// matched, if any, otherwise it isn't on the allow list.
+    PolicyViolation {
+        command: PathBuf,
+        #[serde(default)]
+        rule: Option<String>,
+    },
+    /// The working directory `path` is outside of the directory tree sessions are confined to
+    OutsideJail { path: PathBuf },
+    /// The client's policy doesn't allow commands to be started in the working directory `path`
+    DirectoryNotAllowed { path: PathBuf },
+    /// The client's policy only allows it to execute commands, not to have a terminal
+    TerminalNotAllowed,
+    /// The server has no profile named `profile`
+    UnknownProfile { profile: String },
+    /// No more than `limit` sessions may run within `scope`
+    TooManySessions { limit: usize, scope: LimitScope },
+    /// The file `path` is outside of the directory tree file transfers are confined to
+    OutsideFileRoot { path: PathBuf },
+    /// Files transferred may be no larger than `limit` bytes
+    FileTooLarge { limit: u64 },
+    /// The file arrived with SHA-256 digest `actual` rather than `expected`
+    ChecksumMismatch { expected: String, actual: String },
+    /// Input messages may carry no more than `limit` bytes
+    InputTooLarge { limit: usize },
+    /// Input may arrive no faster than `bytes_per_second`
+    InputTooFast { bytes_per_second: u64 },
+}
+
+/// What a limit on the number of sessions applies to
+#[derive(Eq, Clone, Copy, Debug, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitScope {
+    /// The connection the client asked on
+    Connection,
+    /// All connections of the client
+    Client,
+    /// The whole server
+    Server,
+}
+
+/// A resource the server limits for spawned commands
+#[derive(Eq, Clone, Copy, Debug, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    CpuTime,
+    Memory,
+    OpenFiles,
+    Processes,
+}
+
+/// Why the command of a session ended, see [`Message::Exit`]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ExitReason {
+    /// The command used up its share of `resource`
+    ResourceExceeded { resource: Resource },
+    /// The command ran for longer than the `timeout` seconds it was given and was terminated
+    TimedOut { timeout: u64 },
+    /// An operator killed the command
+    Killed,
+    /// The terminal saw neither input nor output for `timeout` seconds and was closed
+    Idle { timeout: u64 },
+    /// The command was scheduled and cancelled before it started
+    Cancelled,
+}
+
+/// What is done to terminal sessions left idle, see [`Message::Idle`]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdleAction {
+    /// Hang the session up
+    #[default]
+    Close,
+    /// Stop its programs with `SIGSTOP` until the session is used again
+    Suspend,
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::CpuTime => write!(f, "CPU time"),
+            Resource::Memory => write!(f, "memory"),
+            Resource::OpenFiles => write!(f, "open files"),
+            Resource::Processes => write!(f, "processes"),
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitReason::ResourceExceeded { resource } => write!(f, "out of {}", resource),
+            ExitReason::TimedOut { timeout } => write!(f, "timed out after {}s", timeout),
+            ExitReason::Killed => write!(f, "killed by an operator"),
+            ExitReason::Idle { timeout } => write!(f, "closed after {}s of idling", timeout),
+            ExitReason::Cancelled => write!(f, "cancelled before it started"),
+        }
+    }
+}
+
+impl fmt::Display for IdleAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdleAction::Close => write!(f, "closed"),
+            IdleAction::Suspend => write!(f, "suspended"),
+        }
+    }
+}
+
+/// Signal that terminated the command of a session, see [`Message::Exit`]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct ExitSignal {
+    /// Signal numbers differ between platforms, the name tells which signal it is
+    pub number: i32,
+    /// Like `SIGKILL`, `None` for signals the server has no name for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub core_dumped: bool,
+}
+
+impl fmt::Display for ExitSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name.as_ref() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "signal {}", self.number)?,
+        }
+        if self.core_dumped {
+            write!(f, " (core dumped)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Size of a session's terminal as seen by the client
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct WindowSize {
+    pub cols: u16,
+    pub rows: u16,
+    #[serde(default)]
+    pub width_in_pixels: Option<u16>,
+    #[serde(default)]
+    pub height_in_pixels: Option<u16>,
+}
+
+/// The terminal a client emulates, advertised when opening a session, see
+/// [`terminfo`](crate::terminfo)
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct TerminalInfo {
+    /// Name of its terminfo entry, such as `xterm-256color`
+    pub term: String,
+    /// Colors it shows, if the client says. Colors of the output beyond them are brought down to
+    /// them, see [`ColorDowngrade`](crate::filter::ColorDowngrade).
+    #[serde(default)]
+    pub colors: Option<ColorDepth>,
+}
+
+/// How many colors a terminal shows
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ColorDepth {
+    /// The 8 colors of ANSI terminals and their bright variants
+    #[serde(rename = "16")]
+    Ansi16,
+    /// The 256 colors of xterm
+    #[serde(rename = "256")]
+    Ansi256,
+    /// 24-bit colors
+    #[serde(rename = "truecolor")]
+    TrueColor,
+}
+
+/// Line discipline of a session's terminal, see [`Message::SetTermMode`]
+#[derive(Eq, Clone, Copy, Debug, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TermMode {
+    /// Every byte reaches the program as is: nothing is echoed, edited, translated or turned
+    /// into a signal
+    Raw,
+    /// Keys reach the program one by one without being echoed, ^C and friends still send signals
+    Cbreak,
+    /// Input is echoed and edited a line at a time, the mode terminals start out in
+    Canonical,
+}
+
+/// What a client attached to a session may do with it. A session has at most one writer, any
+/// number of clients may watch it.
+#[derive(Eq, Clone, Copy, Debug, Default, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachRole {
+    /// Receives the output and controls the session: sends input, resizes it, signals it...
+    #[default]
+    Writer,
+    /// Only receives the output
+    Viewer,
+}
+
+impl AttachRole {
+    pub fn is_writer(&self) -> bool {
+        *self == AttachRole::Writer
+    }
+}
+
+impl fmt::Display for AttachRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttachRole::Writer => write!(f, "writer"),
+            AttachRole::Viewer => write!(f, "viewer"),
+        }
+    }
+}
+
+/// A client attached to a session, see [`Message::AttachedClients`]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AttachedClient {
+    #[serde(with = "address_as_string")]
+    pub address: SocketAddr,
+    /// Tells clients apart across connections: the fingerprint of their certificate, the digest
+    /// of their token or else their IP address
+    pub identity: String,
+    pub role: AttachRole,
+}
+
+/// A command run without a terminal that exited, as kept in the [job history](crate::history)
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct JobRecord {
+    pub session: SessionId,
+    pub command: String,
+    /// The [identity](AttachedClient::identity) of the client that ran it
+    pub submitter: String,
+    /// RFC 3339 times
+    pub started_at: String,
+    pub ended_at: String,
+    pub code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<ExitSignal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<ExitReason>,
+    /// The last of its stdout and stderr together, only sent for [`Message::FetchJob`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<Payload>,
+}
+
+/// Socket addresses as strings in every encoding. Binary encodings would carry them as enums
+/// otherwise, which can't be read back from within a tagged [`Message`].
+mod address_as_string {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::net::SocketAddr;
+
+    pub fn serialize<S: Serializer>(
+        address: &SocketAddr,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(address)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SocketAddr, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Who echoes and edits the input of a session, see [`Message::SetLineMode`]
+#[derive(Eq, Clone, Copy, Debug, Default, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LineMode {
+    /// Input reaches the program as it arrives, echoing and editing it is up to the program and
+    /// its terminal
+    #[default]
+    Passthrough,
+    /// The server echoes input right away and lets it be edited, passing on only complete lines.
+    /// Meant for links too slow to wait for the echo of the program; the echo of the terminal is
+    /// best turned off with [`Message::SetTermMode`] meanwhile, or input shows up twice.
+    Server,
+}
+
+/// Parity bit of a serial line
+#[derive(Eq, Clone, Copy, Debug, Default, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Parity {
+    #[default]
+    None,
+    Even,
+    Odd,
+}
+
+/// Line settings of a serial device, 115200 baud 8N1 unless given otherwise
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct SerialSettings {
+    #[serde(default = "default_baud_rate")]
+    pub baud_rate: u32,
+    /// Bits per character, 5 to 8
+    #[serde(default = "default_data_bits")]
+    pub data_bits: u8,
+    #[serde(default)]
+    pub parity: Parity,
+    /// 1 or 2
+    #[serde(default = "default_stop_bits")]
+    pub stop_bits: u8,
+}
+
+impl Default for SerialSettings {
+    fn default() -> Self {
+        SerialSettings {
+            baud_rate: default_baud_rate(),
+            data_bits: default_data_bits(),
+            parity: Parity::None,
+            stop_bits: default_stop_bits(),
+        }
+    }
+}
+
+fn default_baud_rate() -> u32 {
+    115200
+}
+
+fn default_data_bits() -> u8 {
+    8
+}
+
+fn default_stop_bits() -> u8 {
+    1
+}
+
+/// Messages exchanged with a client, encoded as negotiated with [`Encoding`]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// First message of both peers, announcing the protocol version they speak and what they
+    /// support of it
+    Hello {
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    /// Client asks to start a new session, running `command`, the server's profile named
+    /// `profile` or the server's default shell
+    Open {
+        session: SessionId,
+        #[serde(default)]
+        command: Option<RunCommand>,
+        /// Name of a profile configured on the server, instead of `command`
+        #[serde(default)]
+        profile: Option<String>,
+        /// Initial size of the session's terminal, 80x24 if not given
+        #[serde(default)]
+        size: Option<WindowSize>,
+        /// The terminal of the client, setting `TERM` of the command unless `env` does
+        #[serde(default)]
+        terminal: Option<TerminalInfo>,
+        /// Working directory, taking precedence over the one of `command`
+        #[serde(default)]
+        cwd: Option<PathBuf>,
+        /// Variables added to the environment of the server
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        /// Start from an empty environment instead of the server's
+        #[serde(default)]
+        clear_env: bool,
+        /// Variables removed from the environment of the server
+        #[serde(default)]
+        strip_env: Vec<String>,
+        /// Seconds the command may run before it is terminated, the server may cap it
+        #[serde(default)]
+        timeout: Option<u64>,
+        /// Send output as read instead of holding back UTF-8 sequences split between reads
+        #[serde(default)]
+        raw_output: bool,
+        /// Remove ANSI escape sequences from the output, leaving its plain text
+        #[serde(default)]
+        strip_ansi: bool,
+        /// Take the OSC 52 sequences the command accesses the clipboard with out of the output,
+        /// sending [`Message::ClipboardSet`] and [`Message::ClipboardGet`] instead
+        #[serde(default)]
+        clipboard: bool,
+        /// Report the window title and current directory the command sets with OSC 0, 2 and 7
+        /// in [`Message::SessionMeta`]
+        #[serde(default)]
+        meta: bool,
+        /// Take the OSC 0, 2 and 7 sequences out of the output, reported or not
+        #[serde(default)]
+        strip_meta: bool,
+    },
+    /// Client asks to run `program` without a terminal, its stdout and stderr are sent as
+    /// separately tagged [`Message::Output`] unless merged
+    Run {
+        session: SessionId,
+        program: PathBuf,
+        #[serde(default)]
+        args: Vec<String>,
+        /// Variables added to the environment of the server
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        /// Start from an empty environment instead of the server's
+        #[serde(default)]
+        clear_env: bool,
+        /// Variables removed from the environment of the server
+        #[serde(default)]
+        strip_env: Vec<String>,
+        #[serde(default)]
+        cwd: Option<PathBuf>,
+        /// Send stderr along with stdout as untagged output, in the order the command wrote it
+        #[serde(default)]
+        merge_stderr: bool,
+        /// Seconds the command may run before it is terminated, the server may cap it
+        #[serde(default)]
+        timeout: Option<u64>,
+        /// Send output as read instead of holding back UTF-8 sequences split between reads
+        #[serde(default)]
+        raw_output: bool,
+        /// Remove ANSI escape sequences from the output, leaving its plain text
+        #[serde(default)]
+        strip_ansi: bool,
+        /// Start the command at this RFC 3339 time in UTC instead of right away, see
+        /// [`schedule`](crate::schedule)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        start_at: Option<String>,
+        /// Start the command this many seconds from now instead of right away
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delay: Option<u64>,
+    },
+    /// Server tells the client that the command of a session was queued to start at `at`, an
+    /// RFC 3339 time
+    Scheduled { session: SessionId, at: String },
+    /// Server tells the client that the command of a session waits for others to exit, behind
+    /// `position - 1` commands in the [queue](crate::schedule::JobQueue). Sent again whenever
+    /// the position changes.
+    Queued { session: SessionId, position: usize },
+    /// Server tells the client that a scheduled or queued command is started now
+    Started { session: SessionId },
+    /// Client asks not to start a scheduled or queued command after all, answered with a
+    /// [`Message::Exit`] for the session
+    CancelScheduled { session: SessionId },
+    /// Client asks to open a session on serial `device`, bridging its input and output like
+    /// those of a terminal
+    SerialOpen {
+        session: SessionId,
+        device: PathBuf,
+        #[serde(flatten)]
+        settings: SerialSettings,
+        /// Send output as read instead of holding back UTF-8 sequences split between reads
+        #[serde(default)]
+        raw_output: bool,
+    },
+    /// Client asks to start a new session like terminal session `from`, which it is the writer
+    /// of: running the same command or profile with the same environment and options, in the
+    /// directory the command of `from` is in now as far as the server can tell
+    CloneSession {
+        session: SessionId,
+        from: SessionId,
+        /// Initial size of the session's terminal, 80x24 if not given
+        #[serde(default)]
+        size: Option<WindowSize>,
+    },
+    /// Server confirms a session was started or attached. `recording` tells that the output of
+    /// the session is being recorded, `recording_input` that what is typed is as well.
+    Opened {
+        session: SessionId,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        recording: bool,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        recording_input: bool,
+        /// What the client may do with the session
+        #[serde(default, skip_serializing_if = "AttachRole::is_writer")]
+        role: AttachRole,
+    },
+    /// Client attaches to a running session, e.g. after reconnecting. The server answers with
+    /// [`Message::Opened`] followed by the session's recent output. A writer takes the session
+    /// over from its current writer, which is detached; viewers are added to those watching it.
+    /// Sessions opened by clients of another identity are reported not to exist.
+    Attach {
+        session: SessionId,
+        #[serde(default)]
+        role: AttachRole,
+    },
+    /// Client attaches to a running session it saw the output of up to `seq` before losing its
+    /// connection, up to the output its writer acknowledged last if not given. The server
+    /// answers like it does [`Message::Attach`], replaying only the output that followed. Output
+    /// already gone from the scrollback is lost, which the client tells from the `seq` of the
+    /// first output replayed.
+    ResumeFrom {
+        session: SessionId,
+        #[serde(default)]
+        seq: Option<u64>,
+        #[serde(default)]
+        role: AttachRole,
+    },
+    /// Viewer of a session becomes its writer, the current writer becomes a viewer
+    TakeControl { session: SessionId },
+    /// Server tells a client attached to a session that it is its `role` now
+    RoleChanged { session: SessionId, role: AttachRole },
+    /// Client asks who is attached to a session, answered with [`Message::AttachedClients`]
+    ListAttachedClients { session: SessionId },
+    /// Server lists the clients attached to a session
+    AttachedClients {
+        session: SessionId,
+        clients: Vec<AttachedClient>,
+    },
+    /// Client asks for the jobs it ran that the server keeps in its history, the latest first,
+    /// answered with [`Message::JobList`]
+    ListJobs {
+        /// The most jobs listed, the server may cap it
+        #[serde(default)]
+        limit: Option<usize>,
+        /// Only list jobs started at this RFC 3339 time or later
+        #[serde(default)]
+        since: Option<String>,
+    },
+    /// Server lists jobs of the client, without their output
+    JobList { jobs: Vec<JobRecord> },
+    /// Client asks for a job it ran that exited, answered with [`Message::JobDetails`]
+    FetchJob { session: SessionId },
+    /// Server describes a job of the client along with the tail of its output
+    JobDetails { job: JobRecord },
+    /// Writer of a session asks for the environment its command was started with, answered
+    /// with [`Message::EnvironmentSnapshot`]
+    SnapshotEnvironment { session: SessionId },
+    /// Server tells the environment the command of a session was started with. Commands in
+    /// containers, pods or on other hosts start out with the environment there, only the
+    /// variables passed on to them are known.
+    EnvironmentSnapshot {
+        session: SessionId,
+        env: BTreeMap<String, String>,
+    },
+    /// Client stops receiving the output of a session without ending it, so that it can be
+    /// attached again later
+    Detach { session: SessionId },
+    /// Server tells the client that a session is no longer attached to this connection, because
+    /// the client asked for it or another connection attached the session
+    Detached { session: SessionId },
+    /// Client input for a session
+    Input { session: SessionId, data: Payload },
+    /// Client input for all of `sessions` at once, which it has to be the writer of. Sessions
+    /// the input can't be written to are reported with a [`Message::Error`] each, the others
+    /// get it all the same.
+    BroadcastInput {
+        sessions: Vec<SessionId>,
+        data: Payload,
+    },
+    /// Output of a session, tagged with the stream it came from for sessions started with
+    /// [`Message::Run`]. `seq` counts the bytes the session output up to the end of `data`,
+    /// replayed output keeps the `seq` it was first sent with.
+    Output {
+        session: SessionId,
+        data: Payload,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stream: Option<StdStream>,
+        #[serde(default)]
+        seq: u64,
+    },
+    /// Client received the output of a session up to `seq`, see [`Message::ResumeFrom`]
+    Ack { session: SessionId, seq: u64 },
+    /// Server stopped reading the output of a session because the client doesn't keep up with
+    /// it, the command blocks once the terminal's buffer is full
+    Paused { session: SessionId },
+    /// Server reads the output of a paused session again
+    Resumed { session: SessionId },
+    /// Server warns that a terminal session saw neither input nor output for a while and is
+    /// `action`ed in `seconds` unless it is used again, or tells that it just was if `seconds` is
+    /// 0. Suspended sessions continue once they get input.
+    Idle {
+        session: SessionId,
+        action: IdleAction,
+        seconds: u64,
+    },
+    /// Client changed the size of a session's terminal
+    Resize {
+        session: SessionId,
+        #[serde(flatten)]
+        size: WindowSize,
+    },
+    /// Client switches the terminal of a session to `mode`, then turns individual flags on or
+    /// off. Flags not given are left as they are.
+    SetTermMode {
+        session: SessionId,
+        #[serde(default)]
+        mode: Option<TermMode>,
+        /// Echo input back
+        #[serde(default)]
+        echo: Option<bool>,
+        /// Turn ^C, ^Z and friends into signals
+        #[serde(default)]
+        isig: Option<bool>,
+        /// Translate carriage returns in the input to newlines
+        #[serde(default)]
+        icrnl: Option<bool>,
+    },
+    /// Client changes who echoes and edits the input of a session
+    SetLineMode { session: SessionId, mode: LineMode },
+    /// Server tells the clients attached to a session what its command set the window title or
+    /// its current directory to, for sessions opened with `meta`. Sent with both as far as known
+    /// after the session was attached, with the one that changed after that.
+    SessionMeta {
+        session: SessionId,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cwd: Option<PathBuf>,
+    },
+    /// Server tells the clients attached to a session that its command put `data` on the
+    /// clipboard
+    ClipboardSet { session: SessionId, data: Payload },
+    /// Server asks the writer of a session what is on the clipboard, for its command to read.
+    /// Only sent if the server lets commands read the clipboard.
+    ClipboardGet { session: SessionId },
+    /// Client answers [`Message::ClipboardGet`] with what is on the clipboard
+    ClipboardContent { session: SessionId, data: Payload },
+    /// Client asks to deliver a signal to the programs in the foreground of a session
+    Signal {
+        session: SessionId,
+        signal: SignalSpec,
+    },
+    /// Client asks to end a session
+    Close { session: SessionId },
+    /// Server reports that a session ended, with the exit code of its command if it had one or
+    /// the signal that terminated it
+    Exit {
+        session: SessionId,
+        code: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signal: Option<ExitSignal>,
+        /// Set if the command was ended for a reason the client may want to tell its user about
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<ExitReason>,
+    },
+    /// Server reports a failure, related to `session` if given
+    Error {
+        #[serde(default)]
+        session: Option<SessionId>,
+        #[serde(flatten)]
+        error: ProtocolError,
+    },
+    /// Client starts uploading a file of `size` bytes to `path`, relative to the file root of
+    /// the server. The content follows in [`Message::FileUploadChunk`]s, the file is only stored
+    /// once [`Message::FileUploadEnd`] confirmed that all of it arrived intact.
+    FileUploadStart {
+        transfer: TransferId,
+        path: PathBuf,
+        size: u64,
+        /// Permission bits of the file, `0o644` if not given
+        #[serde(default)]
+        mode: Option<u32>,
+    },
+    /// Next part of the content of an upload
+    FileUploadChunk { transfer: TransferId, data: Blob },
+    /// Client sent all of an upload, `sha256` is the hex encoded digest of its content
+    FileUploadEnd { transfer: TransferId, sha256: String },
+    /// Server stored an uploaded file
+    FileUploaded { transfer: TransferId },
+    /// Client asks for the file at `path`, relative to the file root of the server
+    FileDownloadRequest { transfer: TransferId, path: PathBuf },
+    /// Server starts sending a file of `size` bytes in [`Message::FileDownloadChunk`]s
+    FileDownloadStart { transfer: TransferId, size: u64 },
+    /// Next part of the content of a download
+    FileDownloadChunk { transfer: TransferId, data: Blob },
+    /// Server sent all of a download, `sha256` is the hex encoded digest of its content
+    FileDownloadEnd { transfer: TransferId, sha256: String },
+    /// Either peer gives up on a transfer
+    TransferFailed {
+        transfer: TransferId,
+        #[serde(flatten)]
+        error: ProtocolError,
+    },
+    /// Client asks to open `channel` to TCP `port` of `host`, as the server sees it. Answered
+    /// with [`Message::ForwardOpened`] once connected, or with [`Message::ForwardFailed`].
+    TcpForward {
+        channel: ChannelId,
+        host: String,
+        port: u16,
+    },
+    /// Server connected a channel to its destination
+    ForwardOpened { channel: ChannelId },
+    /// Next bytes of a channel, from the client to the destination or the other way around
+    ForwardData { channel: ChannelId, data: Blob },
+    /// Either peer is done sending on a channel, which is gone once both are
+    ForwardClose { channel: ChannelId },
+    /// Either peer gives up on a channel
+    ForwardFailed {
+        channel: ChannelId,
+        #[serde(flatten)]
+        error: ProtocolError,
+    },
+    /// Either peer checks whether the other one is still there, answered with [`Message::Pong`]
+    /// carrying the same `nonce`
+    Ping { nonce: u64 },
+    /// Answer to [`Message::Ping`]
+    Pong { nonce: u64 },
+}
+
+impl Message {
+    /// The session a message refers to, if any
+    pub fn session(&self) -> Option<SessionId> {
+        match self {
+            Message::Open { session, .. }
+            | Message::Run { session, .. }
+            | Message::Scheduled { session, .. }
+            | Message::Queued { session, .. }
+            | Message::Started { session }
+            | Message::CancelScheduled { session }
+            | Message::SerialOpen { session, .. }
+            | Message::CloneSession { session, .. }
+            | Message::Opened { session, .. }
+            | Message::Attach { session, .. }
+            | Message::ResumeFrom { session, .. }
+            | Message::TakeControl { session }
+            | Message::RoleChanged { session, .. }
+            | Message::ListAttachedClients { session }
+            | Message::AttachedClients { session, .. }
+            | Message::FetchJob { session }
+            | Message::SnapshotEnvironment { session }
+            | Message::EnvironmentSnapshot { session, .. }
+            | Message::Detach { session }
+            | Message::Detached { session }
+            | Message::Input { session, .. }
+            | Message::Output { session, .. }
+            | Message::Ack { session, .. }
+            | Message::Paused { session }
+            | Message::Resumed { session }
+            | Message::Idle { session, .. }
+            | Message::Resize { session, .. }
+            | Message::SetTermMode { session, .. }
+            | Message::SetLineMode { session, .. }
+            | Message::SessionMeta { session, .. }
+            | Message::ClipboardSet { session, .. }
+            | Message::ClipboardGet { session }
+            | Message::ClipboardContent { session, .. }
+            | Message::Signal { session, .. }
+            | Message::Close { session }
+            | Message::Exit { session, .. } => Some(*session),
+            Message::JobDetails { job } => Some(job.session),
+            Message::Error { session, .. } => *session,
+            Message::Hello { .. }
+            | Message::Ping { .. }
+            | Message::Pong { .. }
+            | Message::BroadcastInput { .. }
+            | Message::ListJobs { .. }
+            | Message::JobList { .. }
+            | Message::FileUploadStart { .. }
+            | Message::FileUploadChunk { .. }
+            | Message::FileUploadEnd { .. }
+            | Message::FileUploaded { .. }
+            | Message::FileDownloadRequest { .. }
+            | Message::FileDownloadStart { .. }
+            | Message::FileDownloadChunk { .. }
+            | Message::FileDownloadEnd { .. }
+            | Message::TransferFailed { .. }
+            | Message::TcpForward { .. }
+            | Message::ForwardOpened { .. }
+            | Message::ForwardData { .. }
+            | Message::ForwardClose { .. }
+            | Message::ForwardFailed { .. } => None,
+        }
+    }
+
+    /// The file transfer a message is about, if any
+    pub fn transfer(&self) -> Option<TransferId> {
+        match self {
+            Message::FileUploadStart { transfer, .. }
+            | Message::FileUploadChunk { transfer, .. }
+            | Message::FileUploadEnd { transfer, .. }
+            | Message::FileUploaded { transfer }
+            | Message::FileDownloadRequest { transfer, .. }
+            | Message::FileDownloadStart { transfer, .. }
+            | Message::FileDownloadChunk { transfer, .. }
+            | Message::FileDownloadEnd { transfer, .. }
+            | Message::TransferFailed { transfer, .. } => Some(*transfer),
+            _ => None,
+        }
+    }
+
+    /// The forwarding channel a message is about, if any
+    pub fn channel(&self) -> Option<ChannelId> {
+        match self {
+            Message::TcpForward { channel, .. }
+            | Message::ForwardOpened { channel }
+            | Message::ForwardData { channel, .. }
+            | Message::ForwardClose { channel }
+            | Message::ForwardFailed { channel, .. } => Some(*channel),
+            _ => None,
+        }
+    }
+
+    /// Bytes of session output, file content or forwarded data the message carries, what flow
+    /// control accounts for
+    pub fn data_len(&self) -> usize {
+        match self {
+            Message::Output { data, .. } => data.0.len(),
+            Message::FileDownloadChunk { data, .. } => data.0.len(),
+            Message::ForwardData { data, .. } => data.0.len(),
+            _ => 0,
+        }
+    }
+}
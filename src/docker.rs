@@ -0,0 +1,362 @@
+//! Sessions running in Docker containers, for using the server as a web console of containers.
+//! A [profile](crate::command::Profile) with the `docker` backend starts its command in a running
+//! container through the Docker Engine API, on a terminal of the container:
+//!
+//! ```toml
+//! [docker]
+//! socket = "/var/run/docker.sock"
+//!
+//! [profiles.web-shell]
+//! backend = "docker"
+//! container = "web"
+//! cmd = "/bin/sh"
+//! ```
+//!
+//! Docker has no way to signal what an exec started. Signals go to the process Docker reports
+//! instead, as long as the server can see it and it is in the container, which needs the server
+//! to run in the host's pid namespace.
+use crate::{
+    command::{Environment, RunCommand},
+    os_io::{PtySize, PtyWriter, Socket},
+};
+use anyhow::{anyhow, Context, Result};
+use hyper::{
+    body::{self, Body},
+    client::conn,
+    header, Method, Request, StatusCode,
+};
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    fs,
+    future::Future,
+    io::Cursor,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::UnixStream,
+    sync::{oneshot, watch},
+    time,
+};
+use tracing::{error, warn};
+
+/// How often Docker is asked whether the command of an exec is still running
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time the command of a closed session gets to exit after its hangup signal before it is killed
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DockerConfig {
+    /// Unix socket the Docker Engine API is served on
+    pub socket: PathBuf,
+}
+
+impl Default for DockerConfig {
+    fn default() -> Self {
+        DockerConfig {
+            socket: PathBuf::from("/var/run/docker.sock"),
+        }
+    }
+}
+
+/// What Docker reports about an exec
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ExecState {
+    #[serde(rename = "ContainerID")]
+    container_id: String,
+    running: bool,
+    exit_code: Option<i32>,
+    pid: i32,
+}
+
+/// A command running on a terminal in a container. Dropping the `ContainerExec` hangs it up like
+/// closing a terminal would, and kills it if it is still around after [`KILL_GRACE_PERIOD`].
+pub struct ContainerExec {
+    id: String,
+    socket: PathBuf,
+    terminal: Socket,
+    /// What Docker read of the terminal's output along with its answer to starting the exec
+    read_ahead: Vec<u8>,
+    pid: Option<Pid>,
+    exit_status: watch::Receiver<Option<ExitStatus>>,
+    // dropped together with the `ContainerExec` to tell its task the command should go away
+    _hangup: oneshot::Sender<()>,
+}
+
+impl ContainerExec {
+    /// Start `cmd` in `container` on a terminal of the given size. Only the variables of `env`
+    /// are passed on, the command starts out with the environment of the container.
+    pub async fn start(
+        config: &DockerConfig,
+        container: &str,
+        cmd: &RunCommand,
+        env: &Environment,
+        size: PtySize,
+    ) -> Result<ContainerExec> {
+        let err_context = || format!("failed to start '{}' in container '{}'", cmd, container);
+
+        size.check().with_context(err_context)?;
+        let mut command = vec![cmd.command.to_string_lossy().into_owned()];
+        command.extend(cmd.args.iter().cloned());
+        let mut spec = json!({
+            "AttachStdin": true,
+            "AttachStdout": true,
+            "AttachStderr": true,
+            "Tty": true,
+            "Cmd": command,
+            "Env": env
+                .vars
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>(),
+            "ConsoleSize": [size.rows, size.cols],
+        });
+        if let Some(cwd) = cmd.cwd.as_ref() {
+            spec["WorkingDir"] = json!(cwd);
+        }
+        let created = request(
+            &config.socket,
+            Method::POST,
+            &format!("/containers/{}/exec", container),
+            Some(spec),
+        )
+        .await
+        .with_context(err_context)?;
+        let id = created["Id"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Docker returned no exec id"))
+            .with_context(err_context)?
+            .to_string();
+
+        let (stream, read_ahead) = attach(&config.socket, &id)
+            .await
+            .with_context(err_context)?;
+        let terminal = Socket::new(stream).with_context(err_context)?;
+        let pid = host_pid(&config.socket, &id).await;
+        if pid.is_none() {
+            warn!("pid of exec {} unknown, signals can't be delivered", id);
+        }
+
+        let (exit_tx, exit_status) = watch::channel(None);
+        let (hangup, hangup_rx) = oneshot::channel();
+        tokio::spawn(watch_exec(
+            config.socket.clone(),
+            id.clone(),
+            pid,
+            exit_tx,
+            hangup_rx,
+        ));
+        Ok(ContainerExec {
+            id,
+            socket: config.socket.clone(),
+            terminal,
+            read_ahead,
+            pid,
+            exit_status,
+            _hangup: hangup,
+        })
+    }
+
+    /// The command as the server sees it, `None` if it can't
+    pub fn pid(&self) -> Option<Pid> {
+        self.pid
+    }
+
+    /// A handle reading the terminal's output, to be taken only once
+    pub fn reader(&mut self) -> impl AsyncRead + Unpin + Send + 'static {
+        Cursor::new(std::mem::take(&mut self.read_ahead)).chain(self.terminal.reader())
+    }
+
+    /// A handle writing to the input of the command
+    pub fn writer(&self) -> PtyWriter {
+        self.terminal.writer()
+    }
+
+    /// Change the size of the terminal. Docker is told in the background, failures are only
+    /// logged.
+    pub fn resize(&self, size: PtySize) -> Result<()> {
+        size.check()?;
+        let socket = self.socket.clone();
+        let path = format!("/exec/{}/resize?h={}&w={}", self.id, size.rows, size.cols);
+        tokio::spawn(async move {
+            if let Err(e) = request(&socket, Method::POST, &path, None).await {
+                warn!("failed to resize exec terminal: {:#}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Resolves to the exit status of the command once it exited
+    pub fn exited(&self) -> impl Future<Output = Option<ExitStatus>> + Send + 'static {
+        let mut exit_status = self.exit_status.clone();
+        async move {
+            loop {
+                if let Some(status) = *exit_status.borrow() {
+                    return Some(status);
+                }
+                if exit_status.changed().await.is_err() {
+                    return *exit_status.borrow();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ContainerExec {
+    fn drop(&mut self) {
+        // the output of a command that ignores its hangup would keep the session around
+        self.terminal.shutdown();
+    }
+}
+
+/// Report the exit status of exec `id` once its command exited. Once told to hang up, the
+/// command gets SIGHUP and then SIGKILL if it is still running after [`KILL_GRACE_PERIOD`].
+async fn watch_exec(
+    socket: PathBuf,
+    id: String,
+    pid: Option<Pid>,
+    exit_tx: watch::Sender<Option<ExitStatus>>,
+    mut hangup: oneshot::Receiver<()>,
+) {
+    let mut hung_up = None;
+    loop {
+        tokio::select! {
+            _ = time::sleep(POLL_INTERVAL) => {},
+            _ = &mut hangup, if hung_up.is_none() => {
+                hung_up = Some(time::Instant::now());
+                if let Some(pid) = pid {
+                    let _ = kill(pid, Signal::SIGHUP);
+                }
+            },
+        }
+        match inspect(&socket, &id).await {
+            Ok(state) if !state.running => {
+                // Docker reports commands killed by a signal like shells do, as 128 plus its
+                // number
+                let status = ExitStatus::from_raw(state.exit_code.unwrap_or(-1) << 8);
+                let _ = exit_tx.send(Some(status));
+                return;
+            },
+            Ok(_) => {},
+            Err(e) => {
+                error!("failed to inspect exec {}: {:#}", id, e);
+                return;
+            },
+        }
+        if let (Some(pid), Some(since)) = (pid, hung_up) {
+            if since.elapsed() >= KILL_GRACE_PERIOD {
+                warn!("exec {} ignored SIGHUP, killing it", id);
+                let _ = kill(pid, Signal::SIGKILL);
+            }
+        }
+    }
+}
+
+async fn inspect(socket: &Path, id: &str) -> Result<ExecState> {
+    let state = request(socket, Method::GET, &format!("/exec/{}/json", id), None).await?;
+    serde_json::from_value(state).context("unexpected answer from Docker")
+}
+
+/// The pid of the command of exec `id`, if the server sees it as the one in the container
+async fn host_pid(socket: &Path, id: &str) -> Option<Pid> {
+    // Docker knows the pid only once the command runs
+    for _ in 0..20 {
+        let state = inspect(socket, id).await.ok()?;
+        if state.pid != 0 {
+            // the cgroup of a container is named after it
+            let cgroup = fs::read_to_string(format!("/proc/{}/cgroup", state.pid)).ok()?;
+            return cgroup
+                .contains(&state.container_id)
+                .then(|| Pid::from_raw(state.pid));
+        }
+        if !state.running {
+            return None;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    None
+}
+
+/// Start exec `id`, taking over the connection for its terminal. Docker may have sent some output
+/// along with its answer, which is returned with the connection.
+async fn attach(socket: &Path, id: &str) -> Result<(std::os::unix::net::UnixStream, Vec<u8>)> {
+    let mut sender = connect(socket).await?;
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("/exec/{}/start", id))
+        .header(header::HOST, "docker")
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::UPGRADE, "tcp")
+        .body(Body::from(
+            json!({ "Detach": false, "Tty": true }).to_string(),
+        ))?;
+    let response = sender.send_request(request).await?;
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Err(error_response(response).await);
+    }
+    let upgraded = hyper::upgrade::on(response).await?;
+    let parts = upgraded
+        .downcast::<UnixStream>()
+        .map_err(|_| anyhow!("Docker handed over an unexpected connection"))?;
+    Ok((parts.io.into_std()?, parts.read_buf.to_vec()))
+}
+
+/// Send a request to the Docker Engine API, returning its JSON answer, `null` if there is none
+async fn request(socket: &Path, method: Method, path: &str, body: Option<Value>) -> Result<Value> {
+    let mut sender = connect(socket).await?;
+    let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(header::HOST, "docker")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))?;
+    let response = sender.send_request(request).await?;
+    if !response.status().is_success() {
+        return Err(error_response(response).await);
+    }
+    let body = body::to_bytes(response.into_body()).await?;
+    match body.is_empty() {
+        true => Ok(Value::Null),
+        false => serde_json::from_slice(&body).context("unexpected answer from Docker"),
+    }
+}
+
+/// A connection to the Docker Engine API, handed over to the terminal of an exec it starts
+async fn connect(socket: &Path) -> Result<conn::SendRequest<Body>> {
+    let stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("failed to connect to Docker at '{}'", socket.display()))?;
+    let (sender, connection) = conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            warn!("connection to Docker failed: {}", e);
+        }
+    });
+    Ok(sender)
+}
+
+/// The error Docker answered with
+async fn error_response(response: hyper::Response<Body>) -> anyhow::Error {
+    let status = response.status();
+    let message = body::to_bytes(response.into_body())
+        .await
+        .ok()
+        .and_then(|body| serde_json::from_slice::<Value>(&body).ok())
+        .and_then(|body| body["message"].as_str().map(str::to_string));
+    match message {
+        Some(message) => anyhow!("Docker answered {}: {}", status, message),
+        None => anyhow!("Docker answered {}", status),
+    }
+}
@@ -1,5 +1,150 @@
 
 use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::{
+    command::{
+        DirectoryNotAllowed, OutsideJail, PolicyViolation, TerminalNotAllowed, UnknownProfile,
+    },
+    data::ErrorDetail,
+    os_io::SpawnFailed,
+    session::{InputTooFast, InputTooLarge, SessionNotFound, TooManySessions},
+    transfer::{ChecksumMismatch, FileTooLarge, OutsideFileRoot},
+};
+
+/// What kind of failure a [`ProtocolError`] is, for clients to act on without parsing messages
+#[derive(Eq, Clone, Copy, Debug, Default, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The client presented no token or one the server doesn't accept
+    AuthFailed,
+    /// The client connected more often than the server allows
+    RateLimited,
+    /// The server could not make sense of what the client sent, or didn't expect it
+    BadRequest,
+    /// The session asked for doesn't exist (anymore)
+    SessionNotFound,
+    /// The command of a session could not be started
+    SpawnFailed,
+    /// The server's policy doesn't allow what the client asked for
+    PolicyViolation,
+    /// One of the server's limits was reached
+    LimitExceeded,
+    /// A file arrived with another digest than announced
+    ChecksumMismatch,
+    /// The peer speaks another major version of the protocol
+    UnsupportedVersion,
+    /// Any other failure, including those of codes this side doesn't know yet
+    #[default]
+    #[serde(other)]
+    Other,
+}
+
+/// A failure as the server reports it to clients. The message is meant for humans, `code` and
+/// `detail` for programs.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ProtocolError {
+    #[serde(default)]
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<ErrorDetail>,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ProtocolError { code, message: message.into(), detail: None }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Reports `error` with all of its context, with the code and detail of the first typed failure
+/// found in it
+impl From<&anyhow::Error> for ProtocolError {
+    fn from(error: &anyhow::Error) -> Self {
+        let detail = error_detail(error);
+        let code = match &detail {
+            Some(ErrorDetail::PolicyViolation { .. })
+            | Some(ErrorDetail::OutsideJail { .. })
+            | Some(ErrorDetail::DirectoryNotAllowed { .. })
+            | Some(ErrorDetail::TerminalNotAllowed)
+            | Some(ErrorDetail::OutsideFileRoot { .. }) => ErrorCode::PolicyViolation,
+            Some(ErrorDetail::TooManySessions { .. })
+            | Some(ErrorDetail::FileTooLarge { .. })
+            | Some(ErrorDetail::InputTooLarge { .. }) => ErrorCode::LimitExceeded,
+            Some(ErrorDetail::InputTooFast { .. }) => ErrorCode::RateLimited,
+            Some(ErrorDetail::ChecksumMismatch { .. }) => ErrorCode::ChecksumMismatch,
+            Some(ErrorDetail::UnknownProfile { .. }) => ErrorCode::BadRequest,
+            // spawn failures are attached as context, which only downcasting the error finds
+            None if error.downcast_ref::<SpawnFailed>().is_some() => ErrorCode::SpawnFailed,
+            None if error.chain().any(|cause| cause.is::<SessionNotFound>()) => {
+                ErrorCode::SessionNotFound
+            },
+            None => ErrorCode::Other,
+        };
+        ProtocolError { code, message: format!("{:#}", error), detail }
+    }
+}
+
+/// The [`ErrorDetail`] telling the client about typed failures anywhere in the chain of `error`
+fn error_detail(error: &anyhow::Error) -> Option<ErrorDetail> {
+    error.chain().find_map(|cause| {
+        if let Some(violation) = cause.downcast_ref::<PolicyViolation>() {
+            return Some(ErrorDetail::PolicyViolation {
+                command: violation.command.clone(),
+                rule: violation.rule.clone(),
+            });
+        }
+        if let Some(outside) = cause.downcast_ref::<OutsideJail>() {
+            return Some(ErrorDetail::OutsideJail { path: outside.path.clone() });
+        }
+        if let Some(not_allowed) = cause.downcast_ref::<DirectoryNotAllowed>() {
+            return Some(ErrorDetail::DirectoryNotAllowed { path: not_allowed.path.clone() });
+        }
+        if cause.is::<TerminalNotAllowed>() {
+            return Some(ErrorDetail::TerminalNotAllowed);
+        }
+        if let Some(unknown) = cause.downcast_ref::<UnknownProfile>() {
+            return Some(ErrorDetail::UnknownProfile { profile: unknown.name.clone() });
+        }
+        if let Some(too_many) = cause.downcast_ref::<TooManySessions>() {
+            return Some(ErrorDetail::TooManySessions {
+                limit: too_many.limit,
+                scope: too_many.scope,
+            });
+        }
+        if let Some(outside) = cause.downcast_ref::<OutsideFileRoot>() {
+            return Some(ErrorDetail::OutsideFileRoot { path: outside.path.clone() });
+        }
+        if let Some(too_large) = cause.downcast_ref::<FileTooLarge>() {
+            return Some(ErrorDetail::FileTooLarge { limit: too_large.limit });
+        }
+        if let Some(too_large) = cause.downcast_ref::<InputTooLarge>() {
+            return Some(ErrorDetail::InputTooLarge { limit: too_large.limit });
+        }
+        if let Some(too_fast) = cause.downcast_ref::<InputTooFast>() {
+            return Some(ErrorDetail::InputTooFast {
+                bytes_per_second: too_fast.bytes_per_second,
+            });
+        }
+        if let Some(mismatch) = cause.downcast_ref::<ChecksumMismatch>() {
+            return Some(ErrorDetail::ChecksumMismatch {
+                expected: mismatch.expected.clone(),
+                actual: mismatch.actual.clone(),
+            });
+        }
+        None
+    })
+}
+
 /// Helper trait to convert error types that don't satisfy `anyhow`s trait requirements to
 /// anyhow errors.
 pub trait ToAnyhow<U> {
@@ -18,6 +163,13 @@ impl<U> ToAnyhow<U> for Result<U, std::sync::PoisonError<U>> {
         }
     }
 }
+
+#[cfg(feature = "plugins")]
+impl<U> ToAnyhow<U> for Result<U, wasmtime::Error> {
+    fn to_anyhow(self) -> crate::anyhow::Result<U> {
+        self.map_err(crate::anyhow::Error::from)
+    }
+}
 pub trait FatalError<T> {
     /// Mark results as being non-fatal.
     ///
@@ -65,7 +217,7 @@ impl<T> FatalError<T> for anyhow::Result<T> {
 ///
 /// The `print_error` function takes a closure which takes a `&str` and fares with it as necessary
 /// to log the error to some usable location. For convenience, logging to stdout, stderr and
-/// `log::error!` is already implemented.
+/// `tracing::error!` is already implemented.
 ///
 /// Note that the trait functions pass the error through unmodified, so they can be chained with
 /// the usual handling of [`std::result::Result`] types.
@@ -77,7 +229,7 @@ pub trait LoggableError<T>: Sized {
     ///
     /// ```should_panic
     /// use anyhow;
-    /// use zellij_utils::errors::LoggableError;
+    /// use sh_over_ws_actuator::error::LoggableError;
     ///
     /// let my_err: anyhow::Result<&str> = Err(anyhow::anyhow!("Test error"));
     /// my_err
@@ -89,30 +241,15 @@ pub trait LoggableError<T>: Sized {
 
     /// Convenienve function, calls `print_error` and logs the result as error.
     ///
-    /// This is not a wrapper around `log::error!`, because the `log` crate uses a lot of compile
-    /// time macros from `std` to determine caller locations/module names etc. Since these are
-    /// resolved at compile time in the location they are written, they would always resolve to the
-    /// location in this function where `log::error!` is called, masking the real caller location.
-    /// Hence, we build the log message ourselves. This means that we lose the information about
-    /// the calling module (Because it can only be resolved at compile time), however the callers
-    /// file and line number are preserved.
+    /// `tracing` takes the file and line of an event from the place its macro is written, which
+    /// would always be this function, masking the real caller location. Hence, the location of
+    /// the caller is recorded as `caller` field of the event instead, while the event's own
+    /// location and module path point here.
     #[track_caller]
     fn to_log(self) -> Self {
         let caller = std::panic::Location::caller();
         self.print_error(|msg| {
-            // Build the log entry manually
-            // NOTE: The log entry has no module path associated with it. This is because `log`
-            // gets the module path from the `std::module_path!()` macro, which is replaced at
-            // compile time in the location it is written!
-            log::logger().log(
-                &log::Record::builder()
-                    .level(log::Level::Error)
-                    .args(format_args!("{}", msg))
-                    .file(Some(caller.file()))
-                    .line(Some(caller.line()))
-                    .module_path(None)
-                    .build(),
-            );
+            tracing::error!(caller = %caller, "{}", msg);
         })
     }
 
@@ -0,0 +1,958 @@
+//! Filters the output of a session passes through before it is sent, scrolled back and recorded.
+//! Every [`OutputFilter`] sees the output in the pieces it was read in and keeps whatever state it
+//! needs between them, so that sequences cut in two by a read are handled like whole ones.
+//! Filters are combined with a [`FilterChain`]. Input passes through [`InputFilter`]s the same
+//! way before it is written to the command.
+//!
+//! Profiles name the filters their sessions get on top of those the client asks for, which see
+//! the output first:
+//!
+//! ```toml
+//! [profiles.audited-shell]
+//! cmd = "/bin/bash"
+//! output_filters = ["redact", "meter"]
+//! input_filters = ["utf8_repair", "meter"]
+//! ```
+//!
+//! The filters built in are
+//!
+//! - `strip_ansi`, see [`StripAnsi`], for output only
+//! - `colors_256` and `colors_16`, see [`ColorDowngrade`], for output only. Sessions get one of
+//!   them on their own when the client opening them says its terminal shows fewer colors.
+//! - `utf8_repair`, see [`Utf8Repair`]
+//! - `redact`, scrubbing the [configured secrets](crate::redact) from what clients see as well,
+//!   for output only
+//! - `meter`, see [`Meter`]
+//!
+//! Programs embedding the server add their own to the [`FilterRegistry`] with
+//! [`Server::with_output_filter`](crate::server::Server::with_output_filter) and
+//! [`Server::with_input_filter`](crate::server::Server::with_input_filter).
+use crate::{
+    config::Config,
+    data::{ColorDepth, SessionId},
+    metrics::METRICS,
+    redact::RedactedOutput,
+};
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use prometheus::IntCounter;
+use std::{
+    collections::HashMap, ffi::OsString, os::unix::ffi::OsStringExt, path::PathBuf, sync::Arc,
+};
+
+/// A stage of the output pipeline of a session
+pub trait OutputFilter: Send {
+    /// The output to send for `data`, the next piece read
+    fn filter(&mut self, data: &[u8]) -> Vec<u8>;
+
+    /// The output still held back once nothing more is read
+    fn finish(&mut self) -> Vec<u8> {
+        vec![]
+    }
+
+    /// What the output told the terminal since this was called last, for filters looking for
+    /// such sequences
+    fn events(&mut self) -> Vec<OutputEvent> {
+        vec![]
+    }
+}
+
+/// A stage of the input pipeline of a session
+pub trait InputFilter: Send {
+    /// The input to write to the command for `data`, the next piece a client sent
+    fn filter(&mut self, data: &[u8]) -> Vec<u8>;
+}
+
+/// Something a program told the terminal it runs on, rather than its user
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OutputEvent {
+    /// Put `data` on the clipboard
+    ClipboardSet(Vec<u8>),
+    /// Tell the program what is on the clipboard, answering for `selection`
+    ClipboardGet { selection: String },
+    /// Show `title` as the title of the window
+    Title(String),
+    /// The program is in directory `cwd` now
+    Directory(PathBuf),
+}
+
+/// Runs output through several filters, in the order they were added
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn OutputFilter>>,
+}
+
+impl FilterChain {
+    /// Add `filter` to the end of the chain
+    pub fn push(&mut self, filter: impl OutputFilter + 'static) {
+        self.filters.push(Box::new(filter));
+    }
+
+    /// Run `data` through the chain like [`OutputFilter::filter`], passing it on without copying
+    /// it if there are no filters
+    pub fn filter_bytes(&mut self, data: Bytes) -> Bytes {
+        match self.filters.is_empty() {
+            true => data,
+            false => self.filter(&data).into(),
+        }
+    }
+}
+
+impl OutputFilter for FilterChain {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut filters = self.filters.iter_mut();
+        let Some(first) = filters.next() else {
+            return data.to_vec();
+        };
+        filters.fold(first.filter(data), |data, filter| filter.filter(&data))
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        // what one filter held back still has to pass through the ones after it
+        self.filters.iter_mut().fold(vec![], |data, filter| {
+            let mut output = match data.is_empty() {
+                true => vec![],
+                false => filter.filter(&data),
+            };
+            output.extend(filter.finish());
+            output
+        })
+    }
+
+    fn events(&mut self) -> Vec<OutputEvent> {
+        self.filters
+            .iter_mut()
+            .flat_map(|filter| filter.events())
+            .collect()
+    }
+}
+
+/// Runs input through several filters, in the order they were added
+#[derive(Default)]
+pub struct InputChain {
+    filters: Vec<Box<dyn InputFilter>>,
+}
+
+impl InputChain {
+    /// Add `filter` to the end of the chain
+    pub fn push(&mut self, filter: impl InputFilter + 'static) {
+        self.filters.push(Box::new(filter));
+    }
+}
+
+impl InputFilter for InputChain {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        self.filters
+            .iter_mut()
+            .fold(data.to_vec(), |data, filter| filter.filter(&data))
+    }
+}
+
+/// What the filters of a session are made for
+pub struct FilterContext<'a> {
+    pub session: SessionId,
+    /// The profile naming the filters
+    pub profile: &'a str,
+    pub config: &'a Config,
+}
+
+type OutputFactory = Arc<dyn Fn(&FilterContext) -> Box<dyn OutputFilter> + Send + Sync>;
+type InputFactory = Arc<dyn Fn(&FilterContext) -> Box<dyn InputFilter> + Send + Sync>;
+
+/// The filters profiles can name, those built in to begin with, see the [module docs](self)
+#[derive(Clone)]
+pub struct FilterRegistry {
+    output: HashMap<String, OutputFactory>,
+    input: HashMap<String, InputFactory>,
+}
+
+impl Default for FilterRegistry {
+    fn default() -> Self {
+        let mut filters = FilterRegistry {
+            output: HashMap::new(),
+            input: HashMap::new(),
+        };
+        filters.register_output("strip_ansi", |_| Box::new(StripAnsi::default()));
+        filters.register_output("colors_256", |_| {
+            Box::new(ColorDowngrade::new(ColorDepth::Ansi256))
+        });
+        filters.register_output("colors_16", |_| Box::new(ColorDowngrade::new(ColorDepth::Ansi16)));
+        filters.register_output("utf8_repair", |_| Box::new(Utf8Repair::default()));
+        filters.register_input("utf8_repair", |_| Box::new(Utf8Repair::default()));
+        filters.register_output("redact", |context| {
+            Box::new(RedactedOutput::new(&context.config.redaction))
+        });
+        filters.register_output("meter", |context| {
+            Box::new(Meter::new(METRICS.metered_bytes(context.profile, "out")))
+        });
+        filters.register_input("meter", |context| {
+            Box::new(Meter::new(METRICS.metered_bytes(context.profile, "in")))
+        });
+        filters
+    }
+}
+
+impl FilterRegistry {
+    /// Let profiles name the output filters `factory` makes `name`, replacing a filter of that
+    /// name if there is one. A filter is made for every stream of every session.
+    pub fn register_output<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(&FilterContext) -> Box<dyn OutputFilter> + Send + Sync + 'static,
+    {
+        self.output.insert(name.to_string(), Arc::new(factory));
+    }
+
+    /// Let profiles name the input filters `factory` makes `name`, replacing a filter of that
+    /// name if there is one. A filter is made for every session.
+    pub fn register_input<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(&FilterContext) -> Box<dyn InputFilter> + Send + Sync + 'static,
+    {
+        self.input.insert(name.to_string(), Arc::new(factory));
+    }
+
+    /// Check that the filters every profile of `config` names are known
+    pub fn check(&self, config: &Config) -> Result<()> {
+        for (name, profile) in config.profiles.iter() {
+            let unknown = profile
+                .output_filters
+                .iter()
+                .find(|filter| !self.output.contains_key(*filter))
+                .or_else(|| {
+                    profile
+                        .input_filters
+                        .iter()
+                        .find(|filter| !self.input.contains_key(*filter))
+                });
+            if let Some(filter) = unknown {
+                bail!("profile '{}' names unknown filter '{}'", name, filter);
+            }
+        }
+        Ok(())
+    }
+
+    /// The output filters `names`, in this order
+    pub fn output_chain(&self, names: &[String], context: &FilterContext) -> Result<FilterChain> {
+        let mut chain = FilterChain::default();
+        for name in names {
+            let Some(factory) = self.output.get(name) else {
+                bail!("unknown output filter '{}'", name);
+            };
+            chain.filters.push(factory(context));
+        }
+        Ok(chain)
+    }
+
+    /// The input filters `names`, in this order
+    pub fn input_chain(&self, names: &[String], context: &FilterContext) -> Result<InputChain> {
+        let mut chain = InputChain::default();
+        for name in names {
+            let Some(factory) = self.input.get(name) else {
+                bail!("unknown input filter '{}'", name);
+            };
+            chain.filters.push(factory(context));
+        }
+        Ok(chain)
+    }
+}
+
+/// Replaces what isn't valid UTF-8 with U+FFFD, for clients that can't cope with anything else.
+/// Like [`Utf8Boundaries`] it holds back sequences cut off at the end of a piece.
+#[derive(Default)]
+pub struct Utf8Repair {
+    boundaries: Utf8Boundaries,
+}
+
+impl OutputFilter for Utf8Repair {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let data = self.boundaries.filter(data);
+        String::from_utf8_lossy(&data).into_owned().into_bytes()
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        let data = self.boundaries.finish();
+        String::from_utf8_lossy(&data).into_owned().into_bytes()
+    }
+}
+
+impl InputFilter for Utf8Repair {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        OutputFilter::filter(self, data)
+    }
+}
+
+/// Counts the bytes passing through it, changing nothing. Where it is in a chain decides what
+/// it counts, the bytes read before any filter if it comes first.
+pub struct Meter {
+    counter: IntCounter,
+}
+
+impl Meter {
+    pub fn new(counter: IntCounter) -> Self {
+        Meter { counter }
+    }
+}
+
+impl OutputFilter for Meter {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        self.counter.inc_by(data.len() as u64);
+        data.to_vec()
+    }
+}
+
+impl InputFilter for Meter {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        self.counter.inc_by(data.len() as u64);
+        data.to_vec()
+    }
+}
+
+/// Holds back a UTF-8 sequence cut off at the end of a read until the rest of it arrives, so that
+/// every chunk of output can be decoded on its own. Bytes that aren't valid UTF-8 either way are
+/// passed on right away.
+#[derive(Default)]
+pub struct Utf8Boundaries {
+    pending: Vec<u8>,
+}
+
+impl OutputFilter for Utf8Boundaries {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut chunk = std::mem::take(&mut self.pending);
+        chunk.extend_from_slice(data);
+        let complete = chunk.len() - incomplete_utf8_suffix(&chunk);
+        self.pending = chunk.split_off(complete);
+        chunk
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Length of the incomplete UTF-8 sequence `data` ends with, 0 if it ends with a complete one or
+/// with bytes that can't become valid UTF-8
+fn incomplete_utf8_suffix(data: &[u8]) -> usize {
+    // the longest sequence has 4 bytes, of which 3 may have arrived
+    let Some(start) = (data.len().saturating_sub(3)..data.len())
+        .rev()
+        .find(|&i| data[i] & 0b1100_0000 != 0b1000_0000)
+    else {
+        return 0;
+    };
+    match std::str::from_utf8(&data[start..]) {
+        Err(e) if e.error_len().is_none() => data.len() - start,
+        _ => 0,
+    }
+}
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// Where [`StripAnsi`] is within an escape sequence
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Escape {
+    /// Not in one, bytes are text
+    #[default]
+    None,
+    /// Right after ESC
+    Start,
+    /// Intermediate bytes of an escape sequence like `ESC ( B`
+    Intermediate,
+    /// Parameters of a control sequence like `ESC [ 1 ; 31 m`
+    Csi,
+    /// Content of a control string like `ESC ] 0 ; title BEL`, ended by ST and, for operating
+    /// system commands, by BEL
+    String { osc: bool },
+    /// ESC within a control string, the start of ST if a backslash follows
+    StringEnd { osc: bool },
+}
+
+/// Removes ANSI and VT escape sequences, leaving the plain text of the output. Control characters
+/// like newlines and carriage returns are kept.
+#[derive(Default)]
+pub struct StripAnsi {
+    escape: Escape,
+}
+
+impl StripAnsi {
+    /// The state after `byte`, adding it to `output` if it is text
+    fn next(&self, state: Escape, byte: u8, output: &mut Vec<u8>) -> Escape {
+        match state {
+            Escape::None if byte == ESC => Escape::Start,
+            Escape::None => {
+                output.push(byte);
+                Escape::None
+            },
+            Escape::Start => match byte {
+                b'[' => Escape::Csi,
+                b']' => Escape::String { osc: true },
+                b'P' | b'X' | b'^' | b'_' => Escape::String { osc: false },
+                ESC => Escape::Start,
+                0x20..=0x2f => Escape::Intermediate,
+                0x30..=0x7e => Escape::None,
+                // not an escape sequence after all
+                _ => self.next(Escape::None, byte, output),
+            },
+            Escape::Intermediate => match byte {
+                0x20..=0x2f => Escape::Intermediate,
+                0x30..=0x7e => Escape::None,
+                _ => self.next(Escape::None, byte, output),
+            },
+            Escape::Csi => match byte {
+                0x20..=0x3f => Escape::Csi,
+                0x40..=0x7e => Escape::None,
+                _ => self.next(Escape::None, byte, output),
+            },
+            Escape::String { osc } => match byte {
+                ESC => Escape::StringEnd { osc },
+                BEL if osc => Escape::None,
+                _ => Escape::String { osc },
+            },
+            Escape::StringEnd { .. } if byte == b'\\' => Escape::None,
+            // terminals end the string there and start on the next sequence
+            Escape::StringEnd { .. } => self.next(Escape::Start, byte, output),
+        }
+    }
+}
+
+impl OutputFilter for StripAnsi {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(data.len());
+        for &byte in data {
+            self.escape = self.next(self.escape, byte, &mut output);
+        }
+        output
+    }
+}
+
+/// Longest control sequence [`ColorDowngrade`] holds back to look at, longer ones pass as they are
+const MAX_CSI_LEN: usize = 256;
+
+/// Where [`ColorDowngrade`] is within a control sequence
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Sgr {
+    /// Not in one
+    #[default]
+    None,
+    /// Right after ESC
+    Start,
+    /// Within the parameters of `ESC [ parameters final`, held back until the final byte
+    Params,
+    /// Within the parameters of a sequence too long to hold back, passed on as they are
+    Passing,
+}
+
+/// Brings the colors of SGR sequences down to those a terminal shows: 24-bit colors to the
+/// closest of the 256 colors of xterm, or those and the 256 colors to the closest of the 16 ANSI
+/// colors. Underline colors, which terminals showing 16 colors don't know, are dropped there.
+/// Everything else passes through, sequences without colors to bring down unchanged.
+pub struct ColorDowngrade {
+    depth: ColorDepth,
+    state: Sgr,
+    /// The control sequence so far
+    sequence: Vec<u8>,
+}
+
+impl ColorDowngrade {
+    /// A filter for terminals showing `depth` colors
+    pub fn new(depth: ColorDepth) -> Self {
+        ColorDowngrade {
+            depth,
+            state: Sgr::None,
+            sequence: Vec::new(),
+        }
+    }
+
+    fn next(&mut self, byte: u8, output: &mut Vec<u8>) {
+        self.state = match (self.state, byte) {
+            (Sgr::None | Sgr::Start, ESC) => {
+                output.append(&mut self.sequence);
+                self.sequence.push(byte);
+                Sgr::Start
+            },
+            (Sgr::None, byte) => {
+                output.push(byte);
+                Sgr::None
+            },
+            (Sgr::Start, b'[') => {
+                self.sequence.push(byte);
+                Sgr::Params
+            },
+            (Sgr::Params, 0x20..=0x3f) if self.sequence.len() < MAX_CSI_LEN => {
+                self.sequence.push(byte);
+                Sgr::Params
+            },
+            (Sgr::Params, 0x20..=0x3f) => {
+                output.append(&mut self.sequence);
+                output.push(byte);
+                Sgr::Passing
+            },
+            (Sgr::Params, b'm') => {
+                let sequence = std::mem::take(&mut self.sequence);
+                match downgrade_sgr(self.depth, &sequence[2..]) {
+                    // all of it dropped, rather than resetting all attributes without parameters
+                    Some(params) if params.is_empty() => {},
+                    Some(params) => {
+                        output.extend_from_slice(b"\x1b[");
+                        output.extend(params);
+                        output.push(byte);
+                    },
+                    None => {
+                        output.extend(sequence);
+                        output.push(byte);
+                    },
+                }
+                Sgr::None
+            },
+            (Sgr::Passing, 0x20..=0x3f) => {
+                output.push(byte);
+                Sgr::Passing
+            },
+            // any other sequence, or none after all
+            (Sgr::Start | Sgr::Params | Sgr::Passing, byte) => {
+                output.append(&mut self.sequence);
+                output.push(byte);
+                Sgr::None
+            },
+        };
+    }
+}
+
+impl OutputFilter for ColorDowngrade {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(data.len());
+        for &byte in data {
+            self.next(byte, &mut output);
+        }
+        output
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        self.state = Sgr::None;
+        std::mem::take(&mut self.sequence)
+    }
+}
+
+/// The parameters of an SGR sequence with `params` for terminals showing `depth` colors, empty
+/// if nothing is left of them. `None` if there are no colors to bring down, or they aren't the
+/// parameters of an SGR sequence at all.
+fn downgrade_sgr(depth: ColorDepth, params: &[u8]) -> Option<Vec<u8>> {
+    // private parameters, or intermediate bytes making it another sequence
+    if !params.iter().all(|byte| byte.is_ascii_digit() || *byte == b';' || *byte == b':') {
+        return None;
+    }
+    let params: Vec<&[u8]> = params.split(|byte| *byte == b';').collect();
+    let mut downgraded: Vec<Vec<u8>> = Vec::with_capacity(params.len());
+    let mut changed = false;
+    let mut i = 0;
+    while i < params.len() {
+        let Some((target, color, used)) = parse_color(&params[i..]) else {
+            downgraded.push(params[i].to_vec());
+            i += 1;
+            continue;
+        };
+        match downgrade_color(depth, target, color) {
+            Some(replaced) => {
+                changed = true;
+                downgraded.extend(replaced.map(String::into_bytes));
+            },
+            None => downgraded.extend(params[i..i + used].iter().map(|param| param.to_vec())),
+        }
+        i += used;
+    }
+    changed.then(|| downgraded.join(&b';'))
+}
+
+/// A color of an SGR sequence
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Color {
+    /// One of the 256 colors of xterm
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// The color `params` start with, if they do: whether it is that of the foreground (38), the
+/// background (48) or underlines (58), the color and how many of `params` it takes up. Colors
+/// are given as `38;5;index` and `38;2;r;g;b`, or with colons in a single parameter.
+fn parse_color(params: &[&[u8]]) -> Option<(u16, Color, usize)> {
+    let number = |param: &[u8]| -> u16 {
+        // missing numbers are 0, those too large for a color are as good as 255
+        param
+            .iter()
+            .take_while(|byte| byte.is_ascii_digit())
+            .fold(0u16, |n, digit| n.saturating_mul(10).saturating_add(u16::from(digit - b'0')))
+    };
+    let component = |param: &[u8]| number(param).min(255) as u8;
+    let (parts, used): (Vec<&[u8]>, usize) = match params[0].contains(&b':') {
+        true => (params[0].split(|byte| *byte == b':').collect(), 1),
+        false => (params.to_vec(), 0),
+    };
+    let target = number(parts[0]);
+    if !matches!(target, 38 | 48 | 58) {
+        return None;
+    }
+    match (parts.get(1).map(|kind| number(kind)), used) {
+        (Some(5), 1) if parts.len() >= 3 => Some((target, Color::Indexed(component(parts[2])), 1)),
+        (Some(5), _) if parts.len() >= 3 => Some((target, Color::Indexed(component(parts[2])), 3)),
+        // with colons the components may follow a color space
+        (Some(2), 1) if parts.len() >= 5 => {
+            let rgb = &parts[parts.len() - 3..];
+            let color = Color::Rgb(component(rgb[0]), component(rgb[1]), component(rgb[2]));
+            Some((target, color, 1))
+        },
+        (Some(2), _) if parts.len() >= 5 => {
+            let color = Color::Rgb(component(parts[2]), component(parts[3]), component(parts[4]));
+            Some((target, color, 5))
+        },
+        _ => None,
+    }
+}
+
+/// The parameters setting `color` for `target` on terminals showing `depth` colors, `None` if
+/// those show it as it is
+fn downgrade_color(depth: ColorDepth, target: u16, color: Color) -> Option<Option<String>> {
+    match (depth, color) {
+        (ColorDepth::TrueColor, _) | (ColorDepth::Ansi256, Color::Indexed(_)) => None,
+        (ColorDepth::Ansi256, Color::Rgb(r, g, b)) => {
+            Some(Some(format!("{};5;{}", target, rgb_to_256(r, g, b))))
+        },
+        // terminals showing 16 colors have no colors of underlines
+        (ColorDepth::Ansi16, _) if target == 58 => Some(None),
+        (ColorDepth::Ansi16, color) => {
+            let index = match color {
+                Color::Indexed(index) if index < 16 => index,
+                Color::Indexed(index) => nearest_ansi(xterm_rgb(index)),
+                Color::Rgb(r, g, b) => nearest_ansi((r, g, b)),
+            };
+            // 30-37 and 90-97 for the foreground, 40-47 and 100-107 for the background
+            let base = if index < 8 { target - 8 } else { target + 52 };
+            Some(Some((base + u16::from(index % 8)).to_string()))
+        },
+    }
+}
+
+/// Levels of the red, green and blue components of the color cube of xterm, colors 16 to 231
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// The 16 ANSI colors as xterm shows them
+const ANSI_COLORS: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+/// The red, green and blue components of color `index` of xterm
+fn xterm_rgb(index: u8) -> (u8, u8, u8) {
+    match index {
+        0..=15 => ANSI_COLORS[index as usize],
+        16..=231 => {
+            let cube = index - 16;
+            let level = |n: u8| CUBE_LEVELS[n as usize];
+            (level(cube / 36), level(cube / 6 % 6), level(cube % 6))
+        },
+        // the grays from 8 to 238
+        _ => {
+            let gray = 8 + (index - 232) * 10;
+            (gray, gray, gray)
+        },
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).unsigned_abs().pow(2);
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+/// The closest of the 256 colors of xterm, of the color cube and the grays. The 16 ANSI colors
+/// are left out, terminals show them as they like.
+fn rgb_to_256(r: u8, g: u8, b: u8) -> u8 {
+    let nearest_level = |c: u8| {
+        (0..CUBE_LEVELS.len())
+            .min_by_key(|&i| CUBE_LEVELS[i].abs_diff(c))
+            .expect("there are levels") as u8
+    };
+    let cube = 16 + 36 * nearest_level(r) + 6 * nearest_level(g) + nearest_level(b);
+    let average = ((u16::from(r) + u16::from(g) + u16::from(b)) / 3) as u8;
+    let gray = 232 + (average.saturating_sub(3) / 10).min(23);
+    [cube, gray]
+        .into_iter()
+        .min_by_key(|&index| distance(xterm_rgb(index), (r, g, b)))
+        .expect("there are candidates")
+}
+
+/// The index of the closest of the 16 ANSI colors
+fn nearest_ansi(rgb: (u8, u8, u8)) -> u8 {
+    (0..ANSI_COLORS.len() as u8)
+        .min_by_key(|&index| distance(ANSI_COLORS[index as usize], rgb))
+        .expect("there are colors")
+}
+
+/// Longest operating system command taken out of the output, longer ones are dropped
+const MAX_OSC_LEN: usize = 1024 * 1024;
+
+/// The operating system commands [`InterceptOsc`] looks for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Osc {
+    /// OSC 0 and OSC 2, setting the title of the window
+    Title,
+    /// OSC 7, telling the current directory as a `file://` URL
+    Directory,
+    /// OSC 52, setting or querying the clipboard
+    Clipboard,
+}
+
+/// Where [`InterceptOsc`] is within an operating system command
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum OscState {
+    /// Not in one
+    #[default]
+    None,
+    /// Right after ESC
+    Start,
+    /// Within the number of an `ESC ] number ; content` sequence
+    Number,
+    /// Within the content of a command looked for, ended by BEL or ST
+    Content(Osc),
+    /// ESC within the content, the start of ST if a backslash follows
+    ContentEnd(Osc),
+}
+
+/// Looks for the operating system commands that set the title of the window (OSC 0 and 2), tell
+/// the current directory (OSC 7) and access the clipboard (OSC 52), turning them into
+/// [`OutputEvent`]s so that clients don't have to parse escape sequences for them. Clipboard
+/// sequences are always taken out of the output, the others only if asked to. Everything else
+/// passes through.
+#[derive(Default)]
+pub struct InterceptOsc {
+    clipboard: bool,
+    meta: bool,
+    strip_meta: bool,
+    state: OscState,
+    /// The sequence so far, held back until it is known what to do with it
+    sequence: Vec<u8>,
+    /// Whether the sequence grew longer than [`MAX_OSC_LEN`]
+    overlong: bool,
+    events: Vec<OutputEvent>,
+}
+
+impl InterceptOsc {
+    /// A filter looking for the clipboard sequences if `clipboard`, reporting titles and
+    /// directories if `meta` and taking those out of the output if `strip_meta`
+    pub fn new(clipboard: bool, meta: bool, strip_meta: bool) -> Self {
+        InterceptOsc {
+            clipboard,
+            meta,
+            strip_meta,
+            ..Default::default()
+        }
+    }
+
+    /// The command numbered `number` if it is looked for
+    fn looked_for(&self, number: &[u8]) -> Option<Osc> {
+        match number {
+            b"0" | b"2" if self.meta || self.strip_meta => Some(Osc::Title),
+            b"7" if self.meta || self.strip_meta => Some(Osc::Directory),
+            b"52" if self.clipboard => Some(Osc::Clipboard),
+            _ => None,
+        }
+    }
+
+    /// Whether the sequences of `osc` stay in the output
+    fn passes(&self, osc: Osc) -> bool {
+        osc != Osc::Clipboard && !self.strip_meta
+    }
+
+    fn next(&mut self, byte: u8, output: &mut Vec<u8>) {
+        self.state = match (self.state, byte) {
+            (OscState::None, ESC) => {
+                self.sequence.push(byte);
+                OscState::Start
+            },
+            (OscState::None, byte) => {
+                output.push(byte);
+                OscState::None
+            },
+            (OscState::Start, b']') => {
+                self.sequence.push(byte);
+                OscState::Number
+            },
+            (OscState::Number, b'0'..=b'9') if self.sequence.len() < 8 => {
+                self.sequence.push(byte);
+                OscState::Number
+            },
+            (OscState::Number, b';') => match self.looked_for(&self.sequence[2..]) {
+                Some(osc) => {
+                    self.sequence.push(byte);
+                    OscState::Content(osc)
+                },
+                None => {
+                    output.append(&mut self.sequence);
+                    output.push(byte);
+                    OscState::None
+                },
+            },
+            // not a command looked for after all
+            (OscState::Start | OscState::Number, _) => {
+                output.append(&mut self.sequence);
+                self.state = OscState::None;
+                self.next(byte, output);
+                return;
+            },
+            (OscState::Content(osc), BEL) => {
+                self.sequence.push(byte);
+                self.end(osc, 1, output);
+                OscState::None
+            },
+            (OscState::Content(osc), ESC) => {
+                self.push(byte);
+                OscState::ContentEnd(osc)
+            },
+            (OscState::Content(osc), byte) => {
+                self.push(byte);
+                OscState::Content(osc)
+            },
+            (OscState::ContentEnd(osc), b'\\') => {
+                self.sequence.push(byte);
+                self.end(osc, 2, output);
+                OscState::None
+            },
+            // terminals give up on the command there and start on the next sequence
+            (OscState::ContentEnd(osc), _) => {
+                let mut sequence = std::mem::take(&mut self.sequence);
+                sequence.pop();
+                let overlong = std::mem::take(&mut self.overlong);
+                if self.passes(osc) && !overlong {
+                    output.append(&mut sequence);
+                }
+                self.sequence.push(ESC);
+                self.state = OscState::Start;
+                self.next(byte, output);
+                return;
+            },
+        };
+    }
+
+    /// Add `byte` to the content, as long as it fits
+    fn push(&mut self, byte: u8) {
+        match self.sequence.len() < MAX_OSC_LEN {
+            true => self.sequence.push(byte),
+            false => {
+                // only the last byte is needed to tell where the command ends
+                self.overlong = true;
+                self.sequence.pop();
+                self.sequence.push(byte);
+            },
+        }
+    }
+
+    /// Handle the command of `osc` that just ended with a terminator of `terminator` bytes
+    fn end(&mut self, osc: Osc, terminator: usize, output: &mut Vec<u8>) {
+        let sequence = std::mem::take(&mut self.sequence);
+        if std::mem::take(&mut self.overlong) {
+            return;
+        }
+        let start = sequence.iter().position(|byte| *byte == b';').unwrap_or(0) + 1;
+        let event = osc_event(osc, &sequence[start..sequence.len() - terminator]);
+        match (osc, event) {
+            (Osc::Clipboard, Some(event)) => self.events.push(event),
+            (_, Some(event)) if self.meta => self.events.push(event),
+            _ => {},
+        }
+        if self.passes(osc) {
+            output.extend(sequence);
+        }
+    }
+}
+
+/// The event of the command of `osc` with `content`, `None` if it makes no sense
+fn osc_event(osc: Osc, content: &[u8]) -> Option<OutputEvent> {
+    match osc {
+        Osc::Title => Some(OutputEvent::Title(
+            String::from_utf8_lossy(content).into_owned(),
+        )),
+        Osc::Directory => {
+            // `file://host/path`, the host is that of the server or one it runs commands on
+            let path = match content.strip_prefix(b"file://") {
+                Some(url) => &url[url.iter().position(|byte| *byte == b'/')?..],
+                None if content.starts_with(b"/") => content,
+                None => return None,
+            };
+            Some(OutputEvent::Directory(PathBuf::from(OsString::from_vec(
+                percent_decode(path),
+            ))))
+        },
+        Osc::Clipboard => {
+            let split = content.iter().position(|byte| *byte == b';')?;
+            let (selection, data) = (&content[..split], &content[split + 1..]);
+            match data {
+                b"?" => Some(OutputEvent::ClipboardGet {
+                    selection: String::from_utf8_lossy(selection).into_owned(),
+                }),
+                // as garbled as that, nobody would miss it
+                data => BASE64.decode(data).ok().map(OutputEvent::ClipboardSet),
+            }
+        },
+    }
+}
+
+/// `data` with the `%XX` escapes of URLs replaced by the bytes they stand for
+fn percent_decode(data: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let escaped = data
+            .get(i + 1..i + 3)
+            .filter(|_| data[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(data[i]);
+                i += 1;
+            },
+        }
+    }
+    decoded
+}
+
+impl OutputFilter for InterceptOsc {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(data.len());
+        for &byte in data {
+            self.next(byte, &mut output);
+        }
+        output
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        // a command that never ended is dropped, unless it would have stayed in the output
+        let sequence = std::mem::take(&mut self.sequence);
+        match std::mem::take(&mut self.state) {
+            OscState::None | OscState::Start | OscState::Number => sequence,
+            OscState::Content(osc) | OscState::ContentEnd(osc) if self.passes(osc) => sequence,
+            OscState::Content(_) | OscState::ContentEnd(_) => vec![],
+        }
+    }
+
+    fn events(&mut self) -> Vec<OutputEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
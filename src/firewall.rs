@@ -0,0 +1,63 @@
+//! Which addresses clients may connect to the WebSocket listener from, a cheap first line of
+//! defense for a server reachable from the internet:
+//!
+//! ```toml
+//! [addresses]
+//! allow = ["10.0.0.0/8", "2001:db8::/32"]
+//! deny = ["10.66.0.0/16"]
+//! ```
+//!
+//! Connections are checked as soon as the address of the client is known, before anything but
+//! a PROXY protocol header was read from them, those of [trusted proxies](crate::proxy) once the
+//! upgrade request told whom they forward it for.
+//!
+//! Programs embedding the server can add checks of their own, say by GeoIP, with an
+//! [`AddressFilter`] handed to
+//! [`Server::with_address_filter`](crate::server::Server::with_address_filter).
+use crate::{metrics::METRICS, proxy::deserialize_networks};
+use anyhow::{bail, Result};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::{net::IpAddr, sync::Arc};
+
+/// Decides whether clients at an address may connect
+pub trait AddressFilter: Send + Sync {
+    /// Check whether a client at `address` may connect, an error telling why not otherwise.
+    /// Called for every connection before it is served, so better quick.
+    fn check(&self, address: IpAddr) -> Result<()>;
+}
+
+/// Networks clients may and may not connect from, see the [module docs](self)
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AddressPolicy {
+    /// Networks like `10.0.0.0/8`, or single addresses, clients may connect from. Any if empty.
+    #[serde(deserialize_with = "deserialize_networks")]
+    pub allow: Vec<IpNet>,
+    /// Networks clients may not connect from, whether allowed or not
+    #[serde(deserialize_with = "deserialize_networks")]
+    pub deny: Vec<IpNet>,
+}
+
+impl AddressFilter for AddressPolicy {
+    fn check(&self, address: IpAddr) -> Result<()> {
+        // IPv4 clients of a dual-stack listener show up as IPv6 addresses
+        let address = address.to_canonical();
+        if let Some(network) = self.deny.iter().find(|network| network.contains(&address)) {
+            bail!("{} is denied by rule '{}'", address, network);
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|network| network.contains(&address)) {
+            bail!("{} is not allowed", address);
+        }
+        Ok(())
+    }
+}
+
+/// Check `address` with each of `filters`, counting it if one turns it away
+pub fn check_address(filters: &[Arc<dyn AddressFilter>], address: IpAddr) -> Result<()> {
+    let checked = filters.iter().try_for_each(|filter| filter.check(address));
+    if checked.is_err() {
+        METRICS.address_rejections.inc();
+    }
+    checked
+}
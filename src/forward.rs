@@ -0,0 +1,272 @@
+//! TCP port forwarding over the connection of a client, for reaching services next to the server
+//! like a debugger's port through the same authenticated connection. The client asks for a
+//! destination with [`Message::TcpForward`], which the server connects to if the
+//! [allow list](crate::config::ForwardingConfig::allow) has it. Bytes then flow both ways in
+//! [`Message::ForwardData`], from the destination paced by the [`Backlog`] of the connection like
+//! session output is.
+//!
+//! Each direction ends on its own with [`Message::ForwardClose`], the channel is gone once both
+//! did. Either peer gives up on a channel with [`Message::ForwardFailed`].
+use crate::{
+    config::Config,
+    data::{Blob, ChannelId, Message},
+    error::{ProtocolError, ToAnyhow},
+    session::Backlog,
+};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+    task::AbortHandle,
+    time,
+};
+use tracing::info;
+
+/// Size of the chunks read from destinations
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Time a destination has to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Messages of data from the client queued for a destination before the connection waits for
+/// it to take them
+const INPUT_QUEUE: usize = 16;
+
+/// A channel in use
+struct Channel {
+    /// Takes what the client sends to the destination, `None` once the client is done sending
+    input: Option<mpsc::Sender<Vec<u8>>>,
+    task: AbortHandle,
+}
+
+/// The forwarding channels of one connection. Messages for the client are sent to the `events`
+/// channel handed to [`Forwards::new`].
+pub struct Forwards {
+    config: Arc<Config>,
+    channels: Arc<Mutex<HashMap<ChannelId, Channel>>>,
+    events: mpsc::UnboundedSender<Message>,
+    backlog: Arc<Backlog>,
+}
+
+impl Forwards {
+    pub fn new(
+        config: Arc<Config>,
+        events: mpsc::UnboundedSender<Message>,
+        backlog: Arc<Backlog>,
+    ) -> Self {
+        Forwards {
+            config,
+            channels: Arc::default(),
+            events,
+            backlog,
+        }
+    }
+
+    /// Act on a forwarding message received from the client. Failures are reported back to the
+    /// client as [`Message::ForwardFailed`] and end the channel.
+    pub async fn handle_message(&self, message: Message) {
+        let Some(channel) = message.channel() else {
+            return;
+        };
+        let result = match message {
+            Message::TcpForward {
+                channel,
+                host,
+                port,
+            } => self.open(channel, host, port),
+            Message::ForwardData { channel, data } => self.write(channel, data.0).await,
+            Message::ForwardClose { channel } => self.close_input(channel),
+            Message::ForwardFailed { channel, .. } => {
+                self.cancel(channel);
+                Ok(())
+            },
+            _ => Err(anyhow!("unexpected message from client")),
+        };
+        if let Err(e) = result {
+            self.cancel(channel);
+            let _ = self.events.send(Message::ForwardFailed {
+                channel,
+                error: ProtocolError::from(&e),
+            });
+        }
+    }
+
+    /// Connect channel `id` to port `port` of `host` in the background
+    fn open(&self, id: ChannelId, host: String, port: u16) -> Result<()> {
+        let err_context = || format!("failed to forward to {}:{}", host, port);
+
+        self.config
+            .forwarding
+            .check(&host, port)
+            .with_context(err_context)?;
+        let mut channels = self.channels.lock().to_anyhow().with_context(err_context)?;
+        if channels.contains_key(&id) {
+            return Err(anyhow!("channel {} is open already", id));
+        }
+        if let Some(limit) = self.config.limits.max_channels {
+            if channels.len() >= limit {
+                return Err(anyhow!(
+                    "no more than {} channels allowed per connection",
+                    limit
+                ))
+                .with_context(err_context);
+            }
+        }
+        let (input, inputs) = mpsc::channel(INPUT_QUEUE);
+        let relay = Relay {
+            id,
+            events: self.events.clone(),
+            backlog: self.backlog.clone(),
+            high_watermark: self.config.flow_control.high_watermark,
+            low_watermark: self.config.flow_control.low_watermark,
+        };
+        let channels_left = self.channels.clone();
+        let task = tokio::spawn(async move {
+            info!("channel {}: forwarding to {}:{}", id, host, port);
+            match relay.run(&host, port, inputs).await {
+                Ok(()) => info!("channel {}: closed", id),
+                Err(e) => {
+                    let e = e.context(format!("failed to forward to {}:{}", host, port));
+                    info!("channel {}: {:#}", id, e);
+                    // the connection may be gone already, then nobody is left to tell
+                    let _ = relay.events.send(Message::ForwardFailed {
+                        channel: id,
+                        error: ProtocolError::from(&e),
+                    });
+                },
+            }
+            if let Ok(mut channels) = channels_left.lock() {
+                channels.remove(&id);
+            }
+        });
+        channels.insert(
+            id,
+            Channel {
+                input: Some(input),
+                task: task.abort_handle(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Pass `data` on to the destination of channel `id`, waiting while it has enough to do
+    async fn write(&self, id: ChannelId, data: Vec<u8>) -> Result<()> {
+        let input = self
+            .channels
+            .lock()
+            .to_anyhow()?
+            .get(&id)
+            .map(|channel| channel.input.clone());
+        let input = match input {
+            Some(Some(input)) => input,
+            Some(None) => return Err(anyhow!("channel {} was closed for sending", id)),
+            // the channel failed and the client was told already
+            None => return Ok(()),
+        };
+        // the channel fails if the relay is gone
+        let _ = input.send(data).await;
+        Ok(())
+    }
+
+    /// Let the destination of channel `id` know the client is done sending
+    fn close_input(&self, id: ChannelId) -> Result<()> {
+        if let Some(channel) = self.channels.lock().to_anyhow()?.get_mut(&id) {
+            channel.input = None;
+        }
+        Ok(())
+    }
+
+    /// Stop channel `id`, dropping its connection to the destination
+    fn cancel(&self, id: ChannelId) {
+        let channel = self
+            .channels
+            .lock()
+            .ok()
+            .and_then(|mut channels| channels.remove(&id));
+        if let Some(channel) = channel {
+            channel.task.abort();
+            info!("channel {}: cancelled", id);
+        }
+    }
+}
+
+impl Drop for Forwards {
+    fn drop(&mut self) {
+        if let Ok(channels) = self.channels.lock() {
+            for channel in channels.values() {
+                channel.task.abort();
+            }
+        }
+    }
+}
+
+/// Relays the bytes of one channel
+struct Relay {
+    id: ChannelId,
+    events: mpsc::UnboundedSender<Message>,
+    backlog: Arc<Backlog>,
+    high_watermark: usize,
+    low_watermark: usize,
+}
+
+impl Relay {
+    /// Connect to port `port` of `host` and relay between it and the client until both are done
+    /// sending
+    async fn run(&self, host: &str, port: u16, mut inputs: mpsc::Receiver<Vec<u8>>) -> Result<()> {
+        let stream = time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| anyhow!("timed out connecting"))??;
+        // what is written is sent right away, much of it is typed
+        stream.set_nodelay(true)?;
+        self.send(Message::ForwardOpened { channel: self.id })?;
+        let (mut reader, mut writer) = stream.into_split();
+
+        let upstream = async {
+            while let Some(data) = inputs.recv().await {
+                writer
+                    .write_all(&data)
+                    .await
+                    .context("failed to write to destination")?;
+            }
+            writer
+                .shutdown()
+                .await
+                .context("failed to write to destination")
+        };
+        let downstream = async {
+            let mut buf = vec![0; CHUNK_SIZE];
+            loop {
+                let n = reader
+                    .read(&mut buf)
+                    .await
+                    .context("failed to read from destination")?;
+                if n == 0 {
+                    return self.send(Message::ForwardClose { channel: self.id });
+                }
+                self.backlog.queued(n);
+                self.send(Message::ForwardData {
+                    channel: self.id,
+                    data: Blob(buf[..n].to_vec()),
+                })?;
+                if self.backlog.len() >= self.high_watermark {
+                    tokio::select! {
+                        _ = self.backlog.drained_to(self.low_watermark) => {},
+                        _ = self.events.closed() => return Err(anyhow!("connection closed")),
+                    }
+                }
+            }
+        };
+        tokio::try_join!(upstream, downstream)?;
+        Ok(())
+    }
+
+    fn send(&self, message: Message) -> Result<()> {
+        self.events
+            .send(message)
+            .map_err(|_| anyhow!("connection closed"))
+    }
+}
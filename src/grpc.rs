@@ -0,0 +1,586 @@
+//! gRPC service next to the WebSocket protocol, for backends that would rather call RPCs than
+//! keep WebSocket connections:
+//!
+//! ```toml
+//! [grpc]
+//! listen = "127.0.0.1:9002"
+//! ```
+//!
+//! The `shws.v1.Actuator` service of `proto/actuator.proto` opens, runs and attaches sessions
+//! with server-streaming calls, each stream following one session the way a WebSocket
+//! connection would until its command exited. Sessions are the same as those of WebSocket
+//! clients, either may attach those of the other. A stream going away is a connection going
+//! away, its session is ended unless
+//! [`detach_on_disconnect`](crate::config::SessionConfig::detach_on_disconnect) is set.
+//!
+//! Input, resizes and signals are sent with unary calls, for sessions a stream of the same
+//! client is the writer of.
+//!
+//! Clients authenticate like WebSocket clients do, presenting their token as
+//! `authorization: Bearer <token>` metadata with every call. The listener speaks plain HTTP/2,
+//! it's better kept off networks the server doesn't trust or put behind a proxy terminating TLS.
+//!
+//! Only built with the `grpc` feature.
+use crate::{
+    audit::{AuditLog, Client},
+    auth::{Authenticator, Lockout},
+    command::RunCommand,
+    config::Config,
+    data::{AttachRole, Message, SessionId, SignalSpec, StdStream, WindowSize},
+    error::{ErrorCode, ProtocolError},
+    logging::connection_span,
+    metrics::METRICS,
+    notify::{Notification, NotifyEvent},
+    session::{SessionManager, SessionRegistry},
+};
+use anyhow::{anyhow, Context, Result};
+use futures_util::stream::{self, BoxStream};
+use proto::{
+    actuator_server::{Actuator, ActuatorServer},
+    open_request, session_event, AttachRequest, Empty, ExecRequest, KillRequest, OpenRequest,
+    OutputStream, ResizeRequest, Role, SessionEvent, WriteRequest,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+    time::SystemTime,
+};
+use tokio::{
+    sync::{mpsc, watch},
+    task::block_in_place,
+    time::{self, Instant},
+};
+use tonic::{
+    metadata::MetadataMap,
+    transport::{server::TcpIncoming, Server},
+    Code, Request, Response, Status,
+};
+use tracing::{info, warn, Instrument};
+use uuid::Uuid;
+
+/// The messages and service generated from `proto/actuator.proto`
+pub mod proto {
+    tonic::include_proto!("shws.v1");
+}
+
+/// Events of a session the client didn't take yet before the session waits for it
+const STREAM_CAPACITY: usize = 64;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// Address the gRPC listener binds to
+    pub listen: SocketAddr,
+}
+
+/// The `Actuator` service, sharing the sessions of the server
+#[derive(Clone)]
+pub struct GrpcService {
+    config: Arc<Config>,
+    registry: Arc<SessionRegistry>,
+    audit: Arc<AuditLog>,
+    /// Checks the tokens of clients, `None` if anybody may call
+    authenticator: Option<Arc<dyn Authenticator>>,
+    /// Clients that failed to authenticate too often, shared with the WebSocket listener
+    lockout: Option<Arc<Lockout>>,
+    /// The streams sessions are written to through, for the unary calls
+    writers: Arc<Mutex<HashMap<SessionId, Writer>>>,
+    /// Set once the server shuts down, streams end when they see it
+    shutdown: watch::Receiver<bool>,
+}
+
+/// The stream a session is written to through
+struct Writer {
+    /// The client the stream is of
+    identity: String,
+    sessions: Weak<SessionManager>,
+}
+
+type EventStream = BoxStream<'static, Result<SessionEvent, Status>>;
+
+impl GrpcService {
+    pub fn new(
+        config: Arc<Config>,
+        registry: Arc<SessionRegistry>,
+        audit: Arc<AuditLog>,
+        authenticator: Option<Arc<dyn Authenticator>>,
+        lockout: Option<Arc<Lockout>>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        GrpcService {
+            config,
+            registry,
+            audit,
+            authenticator,
+            lockout,
+            writers: Arc::default(),
+            shutdown,
+        }
+    }
+
+    /// Bind the gRPC listener to `listen` and serve the service in the background until the
+    /// server shuts down
+    pub fn spawn(self, listen: SocketAddr) -> Result<()> {
+        let incoming =
+            TcpIncoming::bind(listen).with_context(|| format!("failed to listen on {}", listen))?;
+        let mut shutdown = self.shutdown.clone();
+        let server = Server::builder()
+            .add_service(ActuatorServer::new(self))
+            .serve_with_incoming_shutdown(incoming, async move {
+                let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+            });
+        info!("gRPC service listening on {}", listen);
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                warn!("gRPC listener failed: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// The client making `request` and when its token expires, if it does
+    fn authenticate<T>(
+        &self,
+        request: &Request<T>,
+    ) -> Result<(Client, Option<SystemTime>), Status> {
+        let address = request
+            .remote_addr()
+            .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+        let client = Client::new(address);
+        let Some(authenticator) = self.authenticator.as_deref() else {
+            return Ok((client, None));
+        };
+        let lockout = self.lockout.as_deref();
+        if let Err(e) = lockout.map_or(Ok(()), |lockout| lockout.check(&client)) {
+            warn!("rejecting gRPC call from {}, {}", address, e);
+            METRICS.auth_rejections.inc();
+            return Err(Status::resource_exhausted(e.to_string()));
+        }
+        let checked = presented_token(request.metadata())
+            .ok_or_else(|| anyhow!("no token presented"))
+            .and_then(|token| {
+                // checking a token may take a while, running a command especially
+                let identity = block_in_place(|| authenticator.authenticate(token, address))?;
+                Ok((token, identity))
+            });
+        match checked {
+            Ok((token, identity)) => {
+                if let Some(lockout) = lockout {
+                    lockout.succeed(&client);
+                }
+                let expires = identity.expires;
+                Ok((client.with_token(token).with_identity(identity), expires))
+            },
+            Err(e) => {
+                warn!(
+                    "rejecting gRPC call from {}, not authorized: {:#}",
+                    address, e
+                );
+                METRICS.auth_rejections.inc();
+                if let Some(lockout) = lockout {
+                    lockout.fail(&client, &self.audit);
+                }
+                self.registry.notify(&Notification {
+                    message: Some(format!("{:#}", e)),
+                    ..Notification::new(NotifyEvent::AuthFailed, &client)
+                });
+                Err(Status::unauthenticated("missing or invalid token"))
+            },
+        }
+    }
+
+    /// Stream what happens to session `id` once `start` asked for it
+    fn stream(
+        &self,
+        client: Client,
+        expires: Option<SystemTime>,
+        id: SessionId,
+        start: Message,
+    ) -> Response<EventStream> {
+        let (events_tx, events_rx) = mpsc::channel(STREAM_CAPACITY);
+        let span = connection_span(client.address);
+        span.record("identity", client.identity());
+        tokio::spawn(
+            self.clone()
+                .follow(client, expires, id, start, events_tx)
+                .instrument(span),
+        );
+        let events = stream::unfold(events_rx, |mut events_rx| async move {
+            let event = events_rx.recv().await?;
+            Some((event, events_rx))
+        });
+        Response::new(Box::pin(events))
+    }
+
+    /// Act on `start` like a connection of `client` would, passing what happens to session `id`
+    /// on to `stream` until the session is done with the stream or the client with the session
+    async fn follow(
+        self,
+        client: Client,
+        expires: Option<SystemTime>,
+        id: SessionId,
+        start: Message,
+        stream: mpsc::Sender<Result<SessionEvent, Status>>,
+    ) {
+        let identity = client.identity();
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let (due_tx, mut due_rx) = mpsc::unbounded_channel();
+        let sessions = Arc::new(SessionManager::new(
+            self.config.clone(),
+            self.registry.clone(),
+            self.audit.clone(),
+            client,
+            events_tx,
+            due_tx,
+        ));
+        let backlog = sessions.backlog();
+        let mut shutdown = self.shutdown.clone();
+        let expired = time::sleep_until(expires.map_or_else(Instant::now, |expires| {
+            Instant::now()
+                + expires
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+        }));
+        tokio::pin!(expired);
+        info!("streaming session {}", id);
+        sessions.handle_message(start).await;
+
+        let mut opened = false;
+        loop {
+            tokio::select! {
+                Some(message) = events_rx.recv() => {
+                    let done = match &message {
+                        Message::Opened { session, role, .. } if *session == id => {
+                            opened = true;
+                            if role.is_writer() {
+                                self.add_writer(id, &identity, &sessions);
+                            }
+                            false
+                        },
+                        Message::Exit { session, .. } | Message::Detached { session } => {
+                            *session == id
+                        },
+                        // the session never started, nothing else will follow
+                        Message::Error { session, .. } => !opened && *session == Some(id),
+                        _ => false,
+                    };
+                    let len = message.data_len();
+                    if let Some(event) = event(message) {
+                        if stream.send(Ok(event)).await.is_err() {
+                            break;
+                        }
+                    }
+                    backlog.sent(len);
+                    if done {
+                        break;
+                    }
+                },
+                Some(due) = due_rx.recv() => sessions.start_due(due),
+                _ = stream.closed() => break,
+                _ = &mut expired, if expires.is_some() => {
+                    warn!("ending stream, token expired");
+                    let _ = stream.send(Err(Status::unauthenticated("token expired"))).await;
+                    break;
+                },
+                _ = shutdown.changed() => {
+                    let _ = stream.send(Err(Status::unavailable("server shutting down"))).await;
+                    break;
+                },
+            }
+        }
+
+        if let Ok(mut writers) = self.writers.lock() {
+            let ours = Arc::downgrade(&sessions);
+            writers.retain(|_, writer| !writer.sessions.ptr_eq(&ours));
+        }
+        info!("stream of session {} ended", id);
+    }
+
+    /// Send the unary calls of `identity` for session `id` to `sessions`
+    fn add_writer(&self, id: SessionId, identity: &str, sessions: &Arc<SessionManager>) {
+        if let Ok(mut writers) = self.writers.lock() {
+            writers.insert(
+                id,
+                Writer {
+                    identity: identity.to_string(),
+                    sessions: Arc::downgrade(sessions),
+                },
+            );
+        }
+    }
+
+    /// The sessions of the stream of `client` that writes to session `id`
+    fn writer(&self, client: &Client, id: SessionId) -> Result<Arc<SessionManager>, Status> {
+        let writers = self
+            .writers
+            .lock()
+            .map_err(|_| Status::internal("writers poisoned"))?;
+        writers
+            .get(&id)
+            .filter(|writer| writer.identity == client.identity())
+            .and_then(|writer| writer.sessions.upgrade())
+            .ok_or_else(|| {
+                Status::failed_precondition("session is not written to by a stream of this client")
+            })
+    }
+}
+
+#[tonic::async_trait]
+impl Actuator for GrpcService {
+    type OpenStream = EventStream;
+    type ExecStream = EventStream;
+    type AttachStream = EventStream;
+
+    async fn open(&self, request: Request<OpenRequest>) -> Result<Response<EventStream>, Status> {
+        let (client, expires) = self.authenticate(&request)?;
+        let request = request.into_inner();
+        let id = new_session_id(&request.session)?;
+        let (command, profile) = match request.program {
+            Some(open_request::Program::Command(command)) => {
+                let command = RunCommand {
+                    command: PathBuf::from(command.program),
+                    args: command.args,
+                    ..Default::default()
+                };
+                (Some(command), None)
+            },
+            Some(open_request::Program::Profile(profile)) => (None, Some(profile)),
+            None => (None, None),
+        };
+        let open = Message::Open {
+            session: id,
+            command,
+            profile,
+            size: request.size.map(window_size).transpose()?,
+            terminal: None,
+            cwd: request.cwd.map(PathBuf::from),
+            env: request.env,
+            clear_env: request.clear_env,
+            strip_env: request.strip_env,
+            timeout: request.timeout,
+            raw_output: request.raw_output,
+            strip_ansi: request.strip_ansi,
+            // there is no terminal on the other end to handle those
+            clipboard: false,
+            meta: false,
+            strip_meta: false,
+        };
+        Ok(self.stream(client, expires, id, open))
+    }
+
+    async fn exec(&self, request: Request<ExecRequest>) -> Result<Response<EventStream>, Status> {
+        let (client, expires) = self.authenticate(&request)?;
+        let request = request.into_inner();
+        let id = new_session_id(&request.session)?;
+        let run = Message::Run {
+            session: id,
+            program: PathBuf::from(request.program),
+            args: request.args,
+            env: request.env,
+            clear_env: request.clear_env,
+            strip_env: request.strip_env,
+            cwd: request.cwd.map(PathBuf::from),
+            merge_stderr: request.merge_stderr,
+            timeout: request.timeout,
+            raw_output: request.raw_output,
+            strip_ansi: request.strip_ansi,
+            start_at: request.start_at,
+            delay: request.delay,
+        };
+        Ok(self.stream(client, expires, id, run))
+    }
+
+    async fn attach(
+        &self,
+        request: Request<AttachRequest>,
+    ) -> Result<Response<EventStream>, Status> {
+        let (client, expires) = self.authenticate(&request)?;
+        let role = match request.get_ref().role() {
+            Role::Writer => AttachRole::Writer,
+            Role::Viewer => AttachRole::Viewer,
+        };
+        let request = request.into_inner();
+        let id = session_id(&request.session)?;
+        let attach = match request.seq {
+            Some(seq) => Message::ResumeFrom {
+                session: id,
+                seq: Some(seq),
+                role,
+            },
+            None => Message::Attach { session: id, role },
+        };
+        Ok(self.stream(client, expires, id, attach))
+    }
+
+    async fn write(&self, request: Request<WriteRequest>) -> Result<Response<Empty>, Status> {
+        let (client, _) = self.authenticate(&request)?;
+        let request = request.into_inner();
+        let id = session_id(&request.session)?;
+        let sessions = self.writer(&client, id)?;
+        sessions
+            .write(id, &request.data)
+            .await
+            .map_err(|e| status(&e))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn resize(&self, request: Request<ResizeRequest>) -> Result<Response<Empty>, Status> {
+        let (client, _) = self.authenticate(&request)?;
+        let request = request.into_inner();
+        let id = session_id(&request.session)?;
+        let size = request
+            .size
+            .ok_or_else(|| Status::invalid_argument("no size given"))
+            .and_then(window_size)?;
+        let sessions = self.writer(&client, id)?;
+        sessions.resize(id, size.into()).map_err(|e| status(&e))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn kill(&self, request: Request<KillRequest>) -> Result<Response<Empty>, Status> {
+        let (client, _) = self.authenticate(&request)?;
+        let request = request.into_inner();
+        let id = session_id(&request.session)?;
+        let sessions = self.writer(&client, id)?;
+        match request.signal {
+            Some(signal) => sessions.signal(id, SignalSpec::Name(signal)),
+            None => sessions.close(id),
+        }
+        .map_err(|e| status(&e))?;
+        Ok(Response::new(Empty {}))
+    }
+}
+
+/// The token `metadata` carries as bearer token
+fn presented_token(metadata: &MetadataMap) -> Option<&str> {
+    metadata
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn session_id(session: &str) -> Result<SessionId, Status> {
+    session
+        .parse()
+        .map_err(|e| Status::invalid_argument(format!("invalid session '{}': {}", session, e)))
+}
+
+/// Session `session` for new sessions, a new one if empty
+fn new_session_id(session: &str) -> Result<SessionId, Status> {
+    match session {
+        "" => Ok(Uuid::new_v4()),
+        session => session_id(session),
+    }
+}
+
+fn window_size(size: proto::WindowSize) -> Result<WindowSize, Status> {
+    let too_large = |_| Status::invalid_argument("terminal too large");
+    Ok(WindowSize {
+        cols: u16::try_from(size.cols).map_err(too_large)?,
+        rows: u16::try_from(size.rows).map_err(too_large)?,
+        width_in_pixels: None,
+        height_in_pixels: None,
+    })
+}
+
+/// What the client is told of `message`, `None` for what has no place in the gRPC protocol
+fn event(message: Message) -> Option<SessionEvent> {
+    use session_event::Event;
+
+    let (session, event) = match message {
+        Message::Opened {
+            session,
+            recording,
+            recording_input,
+            role,
+        } => {
+            let role = match role {
+                AttachRole::Writer => Role::Writer,
+                AttachRole::Viewer => Role::Viewer,
+            };
+            let opened = proto::Opened {
+                recording,
+                role: role.into(),
+                recording_input,
+            };
+            (Some(session), Event::Opened(opened))
+        },
+        Message::Scheduled { session, at } => {
+            (Some(session), Event::Scheduled(proto::Scheduled { at }))
+        },
+        Message::Queued { session, position } => {
+            let queued = proto::Queued {
+                position: position as u64,
+            };
+            (Some(session), Event::Queued(queued))
+        },
+        Message::Started { session } => (Some(session), Event::Started(proto::Started {})),
+        Message::Output {
+            session,
+            data,
+            stream,
+            seq,
+        } => {
+            let stream = match stream {
+                None => OutputStream::Terminal,
+                Some(StdStream::Stdout) => OutputStream::Stdout,
+                Some(StdStream::Stderr) => OutputStream::Stderr,
+            };
+            let output = proto::Output {
+                data: data.0.into(),
+                stream: stream.into(),
+                seq,
+            };
+            (Some(session), Event::Output(output))
+        },
+        Message::Exit {
+            session,
+            code,
+            signal,
+            reason,
+        } => {
+            let exited = proto::Exited {
+                code,
+                signal: signal.map(|signal| signal.to_string()),
+                reason: reason.map(|reason| reason.to_string()),
+            };
+            (Some(session), Event::Exited(exited))
+        },
+        Message::Detached { session } => (Some(session), Event::Detached(proto::Detached {})),
+        Message::Error { session, error } => {
+            let code = match serde_json::to_value(error.code) {
+                Ok(serde_json::Value::String(code)) => code,
+                _ => String::new(),
+            };
+            let error = proto::Error {
+                code,
+                message: error.message,
+            };
+            (session, Event::Error(error))
+        },
+        _ => return None,
+    };
+    Some(SessionEvent {
+        session: session.map_or_else(String::new, |session| session.to_string()),
+        event: Some(event),
+    })
+}
+
+/// `error` as gRPC status, with the code closest to its [`ErrorCode`]
+fn status(error: &anyhow::Error) -> Status {
+    let error = ProtocolError::from(error);
+    let code = match error.code {
+        ErrorCode::AuthFailed => Code::Unauthenticated,
+        ErrorCode::RateLimited | ErrorCode::LimitExceeded => Code::ResourceExhausted,
+        ErrorCode::BadRequest | ErrorCode::UnsupportedVersion => Code::InvalidArgument,
+        ErrorCode::SessionNotFound => Code::NotFound,
+        ErrorCode::SpawnFailed => Code::Internal,
+        ErrorCode::PolicyViolation => Code::PermissionDenied,
+        ErrorCode::ChecksumMismatch => Code::DataLoss,
+        ErrorCode::Other => Code::Unknown,
+    };
+    Status::new(code, error.message)
+}
@@ -0,0 +1,248 @@
+//! Restrictions of commands spawned on the server on top of [resource limits](crate::limits)
+//! and dropping privileges: `PR_SET_NO_NEW_PRIVS`, which keeps setuid executables and file
+//! capabilities from granting anything, and seccomp filters making groups of system calls fail
+//! with `EPERM`. Set for all commands in the config, or per profile:
+//!
+//! ```toml
+//! [profiles.restricted]
+//! cmd = "/bin/rbash"
+//! hardening = { no_new_privs = true, deny_syscalls = ["ptrace", "mount", "network"] }
+//! ```
+//!
+//! Both are inherited by everything the command starts and can't be undone. Filters are only
+//! available on Linux, for x86_64 and aarch64.
+use anyhow::Result;
+use serde::Deserialize;
+use std::io;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hardening {
+    /// Set `PR_SET_NO_NEW_PRIVS` for the command, which seccomp filters always do
+    pub no_new_privs: Option<bool>,
+    /// System calls that fail for the command
+    pub deny_syscalls: Option<Vec<SyscallGroup>>,
+}
+
+impl Hardening {
+    /// These settings with those `overrides` sets instead
+    pub fn overridden_by(&self, overrides: &Hardening) -> Hardening {
+        Hardening {
+            no_new_privs: overrides.no_new_privs.or(self.no_new_privs),
+            deny_syscalls: overrides
+                .deny_syscalls
+                .clone()
+                .or_else(|| self.deny_syscalls.clone()),
+        }
+    }
+
+    /// Whether `PR_SET_NO_NEW_PRIVS` is set for the command
+    pub fn no_new_privs(&self) -> bool {
+        self.no_new_privs.unwrap_or(false) || !self.denied().is_empty()
+    }
+
+    /// The filter denying the system calls, `None` if none are denied
+    pub fn seccomp_filter(&self) -> Result<Option<SeccompFilter>> {
+        match self.denied().is_empty() {
+            true => Ok(None),
+            false => SeccompFilter::new(self.denied()).map(Some),
+        }
+    }
+
+    fn denied(&self) -> &[SyscallGroup] {
+        self.deny_syscalls.as_deref().unwrap_or_default()
+    }
+}
+
+/// System calls denied together
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyscallGroup {
+    /// Tracing other processes and reading or writing their memory
+    Ptrace,
+    /// Mounting and unmounting filesystems and changing the root
+    Mount,
+    /// Creating and entering namespaces
+    Namespaces,
+    /// Sockets other than Unix domain sockets
+    Network,
+    /// Loading and unloading kernel modules
+    KernelModules,
+    /// Rebooting and loading kernels to reboot into
+    Reboot,
+    /// Setting the system clock
+    Clock,
+    /// Loading BPF programs and perf events
+    Bpf,
+}
+
+#[cfg(target_os = "linux")]
+impl SyscallGroup {
+    /// The system calls of the group failing no matter their arguments
+    fn syscalls(self) -> &'static [libc::c_long] {
+        match self {
+            SyscallGroup::Ptrace => &[
+                libc::SYS_ptrace,
+                libc::SYS_process_vm_readv,
+                libc::SYS_process_vm_writev,
+            ],
+            SyscallGroup::Mount => &[libc::SYS_mount, libc::SYS_umount2, libc::SYS_pivot_root],
+            SyscallGroup::Namespaces => &[libc::SYS_unshare, libc::SYS_setns],
+            // checked for their address family instead
+            SyscallGroup::Network => &[],
+            SyscallGroup::KernelModules => &[
+                libc::SYS_init_module,
+                libc::SYS_finit_module,
+                libc::SYS_delete_module,
+            ],
+            SyscallGroup::Reboot => &[
+                libc::SYS_reboot,
+                libc::SYS_kexec_load,
+                libc::SYS_kexec_file_load,
+            ],
+            SyscallGroup::Clock => &[
+                libc::SYS_settimeofday,
+                libc::SYS_clock_settime,
+                libc::SYS_adjtimex,
+                libc::SYS_clock_adjtime,
+            ],
+            SyscallGroup::Bpf => &[libc::SYS_bpf, libc::SYS_perf_event_open],
+        }
+    }
+}
+
+/// A classic BPF instruction, laid out like the kernel's `struct sock_filter`
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct Instruction {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+/// A seccomp filter program, compiled before forking as nothing may be allocated in between
+/// forking and executing the command
+#[derive(Clone, Debug)]
+pub struct SeccompFilter {
+    program: Vec<Instruction>,
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+impl SeccompFilter {
+    /// Offsets of the fields of `struct seccomp_data` programs load
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    /// The lower half of the first argument, on little endian machines
+    const ARG0: u32 = 16;
+
+    /// `AUDIT_ARCH_*` of the architecture the server was built for, system call numbers differ
+    /// between them
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    fn new(denied: &[SyscallGroup]) -> Result<Self> {
+        let load = |offset| Instruction {
+            code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16,
+            jt: 0,
+            jf: 0,
+            k: offset,
+        };
+        let jump_if_equal = |value, jt, jf| Instruction {
+            code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+            jt,
+            jf,
+            k: value,
+        };
+        let ret = |action| Instruction {
+            code: (libc::BPF_RET | libc::BPF_K) as u16,
+            jt: 0,
+            jf: 0,
+            k: action,
+        };
+        let deny = ret(libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+
+        let mut program = vec![
+            load(Self::ARCH),
+            // system calls of other architectures would bypass the numbers below
+            jump_if_equal(Self::AUDIT_ARCH, 1, 0),
+            ret(libc::SECCOMP_RET_KILL_PROCESS),
+            load(Self::NR),
+        ];
+        for group in denied {
+            for syscall in group.syscalls() {
+                program.push(jump_if_equal(*syscall as u32, 0, 1));
+                program.push(deny);
+            }
+        }
+        if denied.contains(&SyscallGroup::Network) {
+            for syscall in [libc::SYS_socket, libc::SYS_socketpair] {
+                program.extend([
+                    jump_if_equal(syscall as u32, 0, 4),
+                    load(Self::ARG0),
+                    jump_if_equal(libc::AF_UNIX as u32, 1, 0),
+                    deny,
+                    load(Self::NR),
+                ]);
+            }
+        }
+        program.push(ret(libc::SECCOMP_RET_ALLOW));
+        Ok(SeccompFilter { program })
+    }
+
+    /// Install the filter for the calling process, only meant to be called between forking and
+    /// executing a command. `PR_SET_NO_NEW_PRIVS` has to be set already.
+    pub fn install(&self) -> io::Result<()> {
+        let program = libc::sock_fprog {
+            len: self.program.len() as u16,
+            filter: self.program.as_ptr() as *mut libc::sock_filter,
+        };
+        let result = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &program as *const libc::sock_fprog,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+impl SeccompFilter {
+    fn new(_denied: &[SyscallGroup]) -> Result<Self> {
+        Err(anyhow!(
+            "seccomp filters are not supported on this platform"
+        ))
+    }
+
+    pub fn install(&self) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Set `PR_SET_NO_NEW_PRIVS` for the calling process, only meant to be called between forking
+/// and executing a command
+#[cfg(target_os = "linux")]
+pub fn set_no_new_privs() -> io::Result<()> {
+    match unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_no_new_privs() -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
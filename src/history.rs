@@ -0,0 +1,236 @@
+//! History of the commands clients ran without a terminal, kept in an SQLite database so that
+//! they can look up what ran while nobody watched:
+//!
+//! ```toml
+//! [history]
+//! path = "/var/lib/shws/history.sqlite"
+//! output_tail = 4096
+//! retention = 30
+//! ```
+//!
+//! Every such command leaves a [`JobRecord`] once it exited, with the tail of its output. Clients
+//! list and fetch their own jobs with [`Message::ListJobs`](crate::data::Message::ListJobs) and
+//! [`Message::FetchJob`](crate::data::Message::FetchJob), operators those of everybody on the
+//! [admin listener](crate::admin).
+//!
+//! Only built with the `history` feature.
+use crate::data::{JobRecord, Payload, SessionId};
+use anyhow::{anyhow, Context, Result};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Deserialize;
+use std::{
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Jobs listed unless asked for fewer
+const MAX_JOBS_LISTED: usize = 100;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// The database, created if it doesn't exist
+    pub path: PathBuf,
+    /// Bytes of output kept per job, the last it wrote. No more than the
+    /// [scrollback](crate::config::SessionConfig::scrollback) keeps.
+    #[serde(default = "default_output_tail")]
+    pub output_tail: usize,
+    /// Days jobs are kept for, 0 to keep them forever
+    #[serde(default = "default_retention")]
+    pub retention: u64,
+}
+
+fn default_output_tail() -> usize {
+    4096
+}
+
+fn default_retention() -> u64 {
+    30
+}
+
+/// The database jobs are kept in
+pub struct History {
+    db: Mutex<Connection>,
+    output_tail: usize,
+    retention: Option<Duration>,
+}
+
+impl History {
+    /// Open the database of `config`, creating it if needed
+    pub fn open(config: &HistoryConfig) -> Result<Self> {
+        let err_context = || format!("failed to open job history '{}'", config.path.display());
+
+        let db = Connection::open(&config.path).with_context(err_context)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS jobs (
+                session TEXT PRIMARY KEY,
+                command TEXT NOT NULL,
+                submitter TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                ended_at INTEGER NOT NULL,
+                code INTEGER,
+                signal TEXT,
+                reason TEXT,
+                output BLOB NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS jobs_by_start ON jobs (started_at);
+            CREATE INDEX IF NOT EXISTS jobs_by_submitter ON jobs (submitter, started_at);",
+        )
+        .with_context(err_context)?;
+        Ok(History {
+            db: Mutex::new(db),
+            output_tail: config.output_tail,
+            retention: match config.retention {
+                0 => None,
+                days => Some(Duration::from_secs(days * 24 * 60 * 60)),
+            },
+        })
+    }
+
+    /// Bytes of output kept per job
+    pub fn output_tail(&self) -> usize {
+        self.output_tail
+    }
+
+    /// Keep `job`, forgetting those that were kept for long enough
+    pub fn record(&self, job: &JobRecord) -> Result<()> {
+        let err_context = || format!("failed to record job {}", job.session);
+
+        let db = self
+            .db
+            .lock()
+            .map_err(|_| anyhow!("job history poisoned"))
+            .with_context(err_context)?;
+        let signal = job.signal.as_ref().map(serde_json::to_string).transpose()?;
+        let reason = job.reason.as_ref().map(serde_json::to_string).transpose()?;
+        let output = job.output.as_ref().map_or(&[][..], |output| &output.0[..]);
+        db.execute(
+            "INSERT OR REPLACE INTO jobs
+                (session, command, submitter, started_at, ended_at, code, signal, reason, output)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                job.session.to_string(),
+                job.command,
+                job.submitter,
+                millis(&job.started_at).with_context(err_context)?,
+                millis(&job.ended_at).with_context(err_context)?,
+                job.code,
+                signal,
+                reason,
+                output,
+            ],
+        )
+        .with_context(err_context)?;
+        if let Some(retention) = self.retention {
+            let expired = SystemTime::now()
+                .checked_sub(retention)
+                .map_or(0, unix_millis);
+            db.execute("DELETE FROM jobs WHERE ended_at < ?1", [expired])
+                .with_context(err_context)?;
+        }
+        Ok(())
+    }
+
+    /// Up to `limit` jobs of `submitter`, or of everybody if `None`, started `since` or later,
+    /// the latest first. Their output is left out.
+    pub fn list(
+        &self,
+        submitter: Option<&str>,
+        since: Option<SystemTime>,
+        limit: Option<usize>,
+    ) -> Result<Vec<JobRecord>> {
+        let err_context = || "failed to list jobs";
+
+        let db = self
+            .db
+            .lock()
+            .map_err(|_| anyhow!("job history poisoned"))
+            .with_context(err_context)?;
+        let limit = limit.unwrap_or(MAX_JOBS_LISTED).min(MAX_JOBS_LISTED);
+        let mut statement = db
+            .prepare(
+                "SELECT session, command, submitter, started_at, ended_at, code, signal, reason
+                FROM jobs
+                WHERE (?1 IS NULL OR submitter = ?1) AND started_at >= ?2
+                ORDER BY started_at DESC
+                LIMIT ?3",
+            )
+            .with_context(err_context)?;
+        let since = since.map_or(0, unix_millis);
+        let jobs = statement
+            .query_map(params![submitter, since, limit as i64], |row| {
+                job(row, false)
+            })
+            .and_then(|rows| rows.collect())
+            .with_context(err_context)?;
+        Ok(jobs)
+    }
+
+    /// Job `id` with the tail of its output, if `submitter` ran it or is `None`
+    pub fn get(&self, id: SessionId, submitter: Option<&str>) -> Result<Option<JobRecord>> {
+        let err_context = || format!("failed to look up job {}", id);
+
+        let db = self
+            .db
+            .lock()
+            .map_err(|_| anyhow!("job history poisoned"))
+            .with_context(err_context)?;
+        db.query_row(
+            "SELECT session, command, submitter, started_at, ended_at, code, signal, reason, output
+            FROM jobs
+            WHERE session = ?1 AND (?2 IS NULL OR submitter = ?2)",
+            params![id.to_string(), submitter],
+            |row| job(row, true),
+        )
+        .optional()
+        .with_context(err_context)
+    }
+}
+
+/// The job in `row`, along with its output if `with_output`
+fn job(row: &Row, with_output: bool) -> rusqlite::Result<JobRecord> {
+    let invalid = |column, e: Box<dyn std::error::Error + Send + Sync>| {
+        rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, e)
+    };
+    let session: String = row.get(0)?;
+    let signal: Option<String> = row.get(6)?;
+    let reason: Option<String> = row.get(7)?;
+    Ok(JobRecord {
+        session: session.parse().map_err(|e| invalid(0, Box::new(e)))?,
+        command: row.get(1)?,
+        submitter: row.get(2)?,
+        started_at: rfc3339(row.get(3)?),
+        ended_at: rfc3339(row.get(4)?),
+        code: row.get(5)?,
+        signal: signal
+            .map(|signal| serde_json::from_str(&signal))
+            .transpose()
+            .map_err(|e| invalid(6, Box::new(e)))?,
+        reason: reason
+            .map(|reason| serde_json::from_str(&reason))
+            .transpose()
+            .map_err(|e| invalid(7, Box::new(e)))?,
+        output: match with_output {
+            true => Some(Payload::from(row.get::<_, Vec<u8>>(8)?)),
+            false => None,
+        },
+    })
+}
+
+fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as i64)
+}
+
+/// Milliseconds since the Unix epoch of RFC 3339 `time`
+fn millis(time: &str) -> Result<i64> {
+    humantime::parse_rfc3339_weak(time)
+        .map(unix_millis)
+        .with_context(|| format!("invalid time '{}'", time))
+}
+
+fn rfc3339(millis: i64) -> String {
+    let time = UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64);
+    humantime::format_rfc3339_millis(time).to_string()
+}
@@ -0,0 +1,193 @@
+//! Plain HTTP requests to the WebSocket listener, those that don't ask for an upgrade. They are
+//! told apart from upgrade requests by their head, which is then read again by whichever serves
+//! them. The listener serves
+//!
+//! - `POST /tickets`, issuing [tickets](crate::auth::Tickets) if the server is configured to
+//! - `/` and the rest of the web terminal, if built with the `web` feature, see `web`
+use crate::config::OriginConfig;
+use anyhow::Result;
+use hyper::{
+    header::{
+        HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+        ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, CONTENT_TYPE, HOST, ORIGIN, VARY,
+    },
+    server::conn::Http,
+    service::service_fn,
+    Body, Method, Request, Response, StatusCode,
+};
+use std::{
+    convert::Infallible,
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tracing::warn;
+
+/// Most bytes read of a request to tell whether it asks for an upgrade
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+/// A stream with what was read from it already put back in front
+pub struct Rewind<S> {
+    head: Vec<u8>,
+    /// Bytes of `head` read again so far
+    read: usize,
+    stream: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.read < this.head.len() {
+            let len = buf.remaining().min(this.head.len() - this.read);
+            buf.put_slice(&this.head[this.read..this.read + len]);
+            this.read += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Read the head of the request on `stream`, whether it asks for a WebSocket upgrade along with
+/// the stream to read it from again
+pub async fn peek_upgrade<S: AsyncRead + Unpin>(mut stream: S) -> io::Result<(bool, Rewind<S>)> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|end| end == b"\r\n\r\n") && head.len() < MAX_HEAD_LEN {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..len]);
+    }
+    let upgrade = String::from_utf8_lossy(&head)
+        .lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .any(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("upgrade")
+                && value.trim().eq_ignore_ascii_case("websocket")
+        });
+    let stream = Rewind {
+        head,
+        read: 0,
+        stream,
+    };
+    Ok((upgrade, stream))
+}
+
+/// Serve the requests on `stream` with `handle`, a request per connection so that a WebSocket
+/// of the same client gets one of its own
+pub async fn serve<S, F>(stream: S, handle: F) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(Request<Body>) -> Response<Body> + Send + 'static,
+{
+    let service = service_fn(move |request| {
+        let response = handle(request);
+        async move { Ok::<_, Infallible>(response) }
+    });
+    Http::new()
+        .http1_keep_alive(false)
+        .serve_connection(stream, service)
+        .await?;
+    Ok(())
+}
+
+/// Serve `request` with `handle`, along with the CORS headers letting scripts of the page it
+/// comes from read the response if `origins` allows its origin. Preflight requests are
+/// answered for `methods` and `headers`, requests of pages from other origins are refused.
+pub fn cors<F>(
+    request: Request<Body>,
+    origins: &OriginConfig,
+    methods: &'static str,
+    headers: &'static str,
+    handle: F,
+) -> Response<Body>
+where
+    F: FnOnce(Request<Body>) -> Response<Body>,
+{
+    // requests of anything but browsers come without
+    let Some(origin) = request.headers().get(ORIGIN).cloned() else {
+        return handle(request);
+    };
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok());
+    let checked = origin
+        .to_str()
+        .map_err(anyhow::Error::from)
+        .and_then(|origin| origins.check(origin, host));
+    if let Err(e) = checked {
+        warn!("refusing request for {}, {}", request.uri(), e);
+        return respond_text(StatusCode::FORBIDDEN, format!("{}\n", e));
+    }
+    let mut response = match request.method() == Method::OPTIONS {
+        true => {
+            let mut response = respond_text(StatusCode::NO_CONTENT, "");
+            let response_headers = response.headers_mut();
+            response_headers.insert(
+                ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_static(methods),
+            );
+            response_headers.insert(
+                ACCESS_CONTROL_ALLOW_HEADERS,
+                HeaderValue::from_static(headers),
+            );
+            response_headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("600"));
+            response
+        },
+        false => handle(request),
+    };
+    response
+        .headers_mut()
+        .insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    response
+        .headers_mut()
+        .insert(VARY, HeaderValue::from_static("origin"));
+    response
+}
+
+pub fn respond_text(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(body.into())
+        .expect("valid response")
+}
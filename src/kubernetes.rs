@@ -0,0 +1,243 @@
+//! Sessions running in Kubernetes pods, putting the server's protocol in front of the exec API of
+//! a cluster. A [profile](crate::command::Profile) with the `kubernetes` backend starts its
+//! command in a running pod, on a terminal of the pod:
+//!
+//! ```toml
+//! [profiles.api-shell]
+//! backend = "kubernetes"
+//! namespace = "shop"
+//! pod = "api-0"
+//! container = "api"
+//! cmd = "/bin/sh"
+//! ```
+//!
+//! The cluster is the one of the kubeconfig, or the one the server runs in. Exec has no way to
+//! signal a command, so only the signals a terminal sends for ^C and friends can be delivered.
+//!
+//! Only built with the `kubernetes` feature.
+use crate::{
+    command::{signal_number, Environment, RunCommand},
+    data::SignalSpec,
+    os_io::{PtyReader, PtySize, PtyWriter, Socket},
+};
+use anyhow::{anyhow, Context, Result};
+use futures_util::SinkExt;
+use k8s_openapi::{api::core::v1::Pod, apimachinery::pkg::apis::meta::v1::Status};
+use kube::{
+    api::{AttachParams, AttachedProcess, TerminalSize},
+    Api, Client,
+};
+use nix::sys::signal::Signal;
+use std::{
+    future::Future,
+    os::unix::{net::UnixStream as StdUnixStream, process::ExitStatusExt},
+    process::ExitStatus,
+};
+use tokio::{
+    io::{self, AsyncWriteExt},
+    net::UnixStream,
+    sync::{mpsc, watch, OnceCell},
+};
+use tracing::warn;
+
+/// Shared by all sessions, it is set up from the kubeconfig on first use
+static CLIENT: OnceCell<Client> = OnceCell::const_new();
+
+/// A command running on a terminal in a pod. Dropping the `PodExec` closes the exec, which hangs
+/// up the terminal in the pod.
+pub struct PodExec {
+    // the process relays between the pod and the far end of `terminal`
+    _process: AttachedProcess,
+    terminal: Socket,
+    resize: mpsc::Sender<TerminalSize>,
+    exit_status: watch::Receiver<Option<ExitStatus>>,
+}
+
+impl PodExec {
+    /// Start `cmd` in `pod` of `namespace`, or of the default namespace, on a terminal of the
+    /// given size. `container` is the pod's default container if not given. Variables of `env`
+    /// are set through `env`, which the pod needs to have.
+    pub async fn start(
+        namespace: Option<&str>,
+        pod: &str,
+        container: Option<&str>,
+        cmd: &RunCommand,
+        env: &Environment,
+        size: PtySize,
+    ) -> Result<PodExec> {
+        let err_context = || format!("failed to start '{}' in pod '{}'", cmd, pod);
+
+        size.check().with_context(err_context)?;
+        if cmd.cwd.is_some() {
+            return Err(anyhow!("working directories can't be chosen in pods"))
+                .with_context(err_context);
+        }
+        let client = CLIENT
+            .get_or_try_init(Client::try_default)
+            .await
+            .context("failed to set up the Kubernetes client")
+            .with_context(err_context)?
+            .clone();
+        let pods: Api<Pod> = match namespace {
+            Some(namespace) => Api::namespaced(client, namespace),
+            None => Api::default_namespaced(client),
+        };
+        let mut command = vec![];
+        if !env.vars.is_empty() {
+            command.push("env".to_string());
+            command.extend(
+                env.vars
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, value)),
+            );
+        }
+        command.push(cmd.command.to_string_lossy().into_owned());
+        command.extend(cmd.args.iter().cloned());
+        let mut params = AttachParams::interactive_tty();
+        if let Some(container) = container {
+            params = params.container(container);
+        }
+        let mut process = pods
+            .exec(pod, command, &params)
+            .await
+            .with_context(err_context)?;
+
+        // what kube hands out to resize with wants to be owned by who sends
+        let (resize, mut resizes) = mpsc::channel(8);
+        let mut sizes = process
+            .terminal_size()
+            .ok_or_else(|| anyhow!("exec has no terminal"))
+            .with_context(err_context)?;
+        tokio::spawn(async move {
+            while let Some(size) = resizes.recv().await {
+                if sizes.send(size).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let _ = resize.try_send(terminal_size(size));
+        let (local, remote) = StdUnixStream::pair().with_context(err_context)?;
+        remote.set_nonblocking(true).with_context(err_context)?;
+        let remote = UnixStream::from_std(remote).with_context(err_context)?;
+        let mut stdin = process
+            .stdin()
+            .ok_or_else(|| anyhow!("exec has no stdin"))
+            .with_context(err_context)?;
+        let mut stdout = process
+            .stdout()
+            .ok_or_else(|| anyhow!("exec has no stdout"))
+            .with_context(err_context)?;
+        tokio::spawn(async move {
+            let (mut from_session, mut to_session) = remote.into_split();
+            let result = tokio::select! {
+                result = io::copy(&mut stdout, &mut to_session) => result,
+                result = io::copy(&mut from_session, &mut stdin) => result,
+            };
+            if let Err(e) = result {
+                warn!("failed to relay exec terminal: {}", e);
+            }
+            // the session's reads end once the pod's output did
+            let _ = to_session.shutdown().await;
+        });
+
+        let (exit_tx, exit_status) = watch::channel(None);
+        let status = process
+            .take_status()
+            .ok_or_else(|| anyhow!("exec has no status"))
+            .with_context(err_context)?;
+        tokio::spawn(async move {
+            let status = status.await.map(|status| exit_status_of(&status));
+            let _ = exit_tx.send(status);
+        });
+        Ok(PodExec {
+            _process: process,
+            terminal: Socket::new(local).with_context(err_context)?,
+            resize,
+            exit_status,
+        })
+    }
+
+    /// A handle reading the terminal's output
+    pub fn reader(&self) -> PtyReader {
+        self.terminal.reader()
+    }
+
+    /// A handle writing to the input of the command
+    pub fn writer(&self) -> PtyWriter {
+        self.terminal.writer()
+    }
+
+    /// Change the size of the terminal
+    pub fn resize(&self, size: PtySize) -> Result<()> {
+        size.check()?;
+        self.resize
+            .try_send(terminal_size(size))
+            .map_err(|e| anyhow!("failed to resize the terminal: {}", e))
+    }
+
+    /// Deliver `signal` by typing what makes the terminal send it to the foreground, which only
+    /// SIGINT, SIGQUIT and SIGTSTP have
+    pub fn signal(&self, signal: &SignalSpec) -> Result<()> {
+        let key = match Signal::try_from(signal_number(signal)?) {
+            Ok(Signal::SIGINT) => 0x03,
+            Ok(Signal::SIGQUIT) => 0x1c,
+            Ok(Signal::SIGTSTP) => 0x1a,
+            _ => return Err(anyhow!("{} can't be delivered in pods", signal)),
+        };
+        let mut writer = self.writer();
+        tokio::spawn(async move {
+            if let Err(e) = writer.write_all(&[key]).await {
+                warn!("failed to send signal to exec: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Resolves to the exit status of the command once it exited
+    pub fn exited(&self) -> impl Future<Output = Option<ExitStatus>> + Send + 'static {
+        let mut exit_status = self.exit_status.clone();
+        async move {
+            loop {
+                if let Some(status) = *exit_status.borrow() {
+                    return Some(status);
+                }
+                if exit_status.changed().await.is_err() {
+                    return *exit_status.borrow();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for PodExec {
+    fn drop(&mut self) {
+        self.terminal.shutdown();
+    }
+}
+
+fn terminal_size(size: PtySize) -> TerminalSize {
+    TerminalSize {
+        width: size.cols,
+        height: size.rows,
+    }
+}
+
+/// The exit status of a command as the exec API reports it. Failures other than exiting with a
+/// code, like the container going away, count as exiting with 1.
+fn exit_status_of(status: &Status) -> ExitStatus {
+    if status.status.as_deref() == Some("Success") {
+        return ExitStatus::from_raw(0);
+    }
+    let code = status
+        .details
+        .as_ref()
+        .and_then(|details| details.causes.as_ref())
+        .and_then(|causes| {
+            causes
+                .iter()
+                .find(|cause| cause.reason.as_deref() == Some("ExitCode"))
+        })
+        .and_then(|cause| cause.message.as_deref()?.parse::<i32>().ok())
+        .unwrap_or(1);
+    ExitStatus::from_raw(code << 8)
+}
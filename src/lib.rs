@@ -0,0 +1,60 @@
+// https://github.com/zellij-org/zellij/blob/61a9b06237d1b84a6af5132f43b9f48902e2dc80/zellij-server/src/pty.rs#L426
+// https://man7.org/linux/man-pages/man3/termios.3.html
+// https://en.wikibooks.org/wiki/Serial_Programming/termios
+pub mod os_io;
+pub mod admin;
+pub mod audit;
+pub mod auth;
+pub mod capture;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod command;
+pub mod config;
+pub mod data;
+pub mod docker;
+pub mod error;
+pub mod filter;
+pub mod firewall;
+pub mod forward;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hardening;
+#[cfg(feature = "history")]
+pub mod history;
+pub mod http;
+#[cfg(feature = "kubernetes")]
+pub mod kubernetes;
+pub mod limits;
+pub mod listener;
+pub mod logging;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod namespaces;
+pub mod notify;
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub mod proxy;
+pub mod recording;
+pub mod redact;
+pub mod schedule;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod server;
+pub mod session;
+#[cfg(feature = "client")]
+pub mod socks;
+#[cfg(feature = "ssh")]
+pub mod ssh;
+pub mod subreaper;
+pub mod systemd;
+pub mod terminfo;
+pub mod tls;
+pub mod transfer;
+pub mod transport;
+pub mod utmp;
+#[cfg(feature = "web")]
+pub mod web;
+pub use anyhow;
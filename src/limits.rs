@@ -0,0 +1,359 @@
+//! Resource limits for spawned commands. CPU time and open files are always capped with
+//! `setrlimit`. Memory and the number of processes are capped by a cgroup v2 created for every
+//! session if a parent cgroup is configured and usable, and with `setrlimit` otherwise, where
+//! memory means address space and processes are counted per user.
+//!
+//! Also home to the [`RateLimiter`] keeping clients from connecting too often, the
+//! [`ConnectionCounts`] keeping them from holding too many connections open and the
+//! [`Throttle`] keeping sessions from sending output or taking input too fast.
+use crate::data::{Resource, SessionId};
+use anyhow::{anyhow, Context, Result};
+use nix::sys::resource::Resource as Rlimit;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    hash::Hash,
+    net::IpAddr,
+    os::unix::io::{AsRawFd, RawFd},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Buckets of a [`RateLimiter`] kept before the full ones are dropped
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceLimits {
+    /// Seconds of CPU time each process may use
+    pub cpu_time: Option<u64>,
+    /// Bytes of memory a session may use
+    pub memory: Option<u64>,
+    /// Files each process may have open
+    pub open_files: Option<u64>,
+    /// Processes a session may run at the same time
+    pub processes: Option<u64>,
+    /// cgroup v2 directory sessions get their own cgroup in, e.g. `/sys/fs/cgroup/shws`. It has
+    /// to be delegated to the user the server runs as.
+    pub cgroup: Option<PathBuf>,
+}
+
+impl ResourceLimits {
+    /// These limits with those `overrides` sets instead
+    pub fn overridden_by(&self, overrides: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            cpu_time: overrides.cpu_time.or(self.cpu_time),
+            memory: overrides.memory.or(self.memory),
+            open_files: overrides.open_files.or(self.open_files),
+            processes: overrides.processes.or(self.processes),
+            cgroup: overrides.cgroup.clone().or_else(|| self.cgroup.clone()),
+        }
+    }
+
+    /// The cgroup for session `id`, `None` if no parent cgroup is configured or there is nothing
+    /// for it to limit. Failing to set it up is not fatal, `setrlimit` is used instead then.
+    pub fn cgroup(&self, id: SessionId) -> Option<Cgroup> {
+        let parent = self.cgroup.as_ref()?;
+        if self.memory.is_none() && self.processes.is_none() {
+            return None;
+        }
+        match Cgroup::create(parent, &format!("session-{}", id), self) {
+            Ok(cgroup) => Some(cgroup),
+            Err(e) => {
+                warn!("{:#}, falling back to rlimits", e);
+                None
+            },
+        }
+    }
+
+    /// The rlimits to set in a spawned command as soft and hard limit, leaving out those handled
+    /// by a cgroup if there is one
+    pub fn rlimits(&self, cgroup: Option<&Cgroup>) -> Vec<(Rlimit, u64, u64)> {
+        let mut rlimits = vec![];
+        if let Some(cpu_time) = self.cpu_time {
+            // the soft limit raises SIGXCPU, which tells why the command died, the hard one a
+            // second later SIGKILL, which doesn't
+            rlimits.push((Rlimit::RLIMIT_CPU, cpu_time, cpu_time + 1));
+        }
+        if let Some(open_files) = self.open_files {
+            rlimits.push((Rlimit::RLIMIT_NOFILE, open_files, open_files));
+        }
+        if cgroup.is_none() {
+            if let Some(memory) = self.memory {
+                rlimits.push((Rlimit::RLIMIT_AS, memory, memory));
+            }
+            if let Some(processes) = self.processes {
+                rlimits.push((Rlimit::RLIMIT_NPROC, processes, processes));
+            }
+        }
+        rlimits
+    }
+}
+
+/// A cgroup holding the processes of one session, removed again when dropped
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    procs: File,
+}
+
+impl Cgroup {
+    fn create(parent: &Path, name: &str, limits: &ResourceLimits) -> Result<Cgroup> {
+        let path = parent.join(name);
+        let display = path.display().to_string();
+        let err_context = || format!("failed to set up cgroup '{}'", display);
+
+        if !parent.join("cgroup.controllers").is_file() {
+            return Err(anyhow!("'{}' is no cgroup v2", parent.display()))
+                .with_context(err_context);
+        }
+        // the controllers may have been enabled already, a failure surfaces below anyway
+        let _ = fs::write(parent.join("cgroup.subtree_control"), "+memory +pids");
+        fs::create_dir(&path).with_context(err_context)?;
+        let cgroup = Cgroup {
+            procs: OpenOptions::new()
+                .write(true)
+                .open(path.join("cgroup.procs"))
+                .with_context(err_context)?,
+            path,
+        };
+        if let Some(memory) = limits.memory {
+            cgroup
+                .write("memory.max", &memory.to_string())
+                .with_context(err_context)?;
+            // without swap limited as well the memory limit would just slow things down
+            let _ = cgroup.write("memory.swap.max", "0");
+        }
+        if let Some(processes) = limits.processes {
+            cgroup
+                .write("pids.max", &processes.to_string())
+                .with_context(err_context)?;
+        }
+        Ok(cgroup)
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<()> {
+        fs::write(self.path.join(file), value)
+            .with_context(|| format!("failed to write '{}' to {}", value, file))
+    }
+
+    /// Descriptor of `cgroup.procs`, a process writing `0` to it moves itself into the cgroup
+    pub fn procs_fd(&self) -> RawFd {
+        self.procs.as_raw_fd()
+    }
+
+    /// The resource the session ran out of, judging from the events the cgroup recorded
+    pub fn exceeded(&self) -> Option<Resource> {
+        if self.event_count("memory.events", "oom_kill") > 0 {
+            return Some(Resource::Memory);
+        }
+        if self.event_count("pids.events", "max") > 0 {
+            return Some(Resource::Processes);
+        }
+        None
+    }
+
+    fn event_count(&self, file: &str, event: &str) -> u64 {
+        fs::read_to_string(self.path.join(file))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .find(|(name, _)| *name == event)
+            .and_then(|(_, count)| count.trim().parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // only works once every process left, which is the case after the session was reaped
+        if let Err(e) = fs::remove_dir(&self.path) {
+            info!("failed to remove cgroup '{}': {}", self.path.display(), e);
+        }
+    }
+}
+
+/// How often something may happen
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Sustained rate
+    pub per_minute: u32,
+    /// How many may happen at once after a quiet period, `per_minute` if not set
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+impl RateLimit {
+    fn burst(&self) -> f64 {
+        f64::from(self.burst.unwrap_or(self.per_minute).max(1))
+    }
+}
+
+/// Token buckets, one for every key such as a client's address, each holding up to
+/// [`RateLimit::burst`] tokens and refilled at [`RateLimit::per_minute`]
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<K, (f64, Instant)>>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from the bucket of `key`, `false` if it is empty
+    pub fn allow(&self, key: K) -> bool {
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return true;
+        };
+        let now = Instant::now();
+        let burst = self.limit.burst();
+        let refill = |(tokens, since): (f64, Instant)| {
+            let elapsed = now.duration_since(since).as_secs_f64();
+            (tokens + elapsed * f64::from(self.limit.per_minute) / 60.0).min(burst)
+        };
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            // full buckets are no different from ones that don't exist yet
+            buckets.retain(|_, bucket| refill(*bucket) < burst);
+        }
+        let tokens = buckets.get(&key).copied().map_or(burst, refill);
+        if tokens < 1.0 {
+            return false;
+        }
+        buckets.insert(key, (tokens - 1.0, now));
+        true
+    }
+}
+
+/// Connections open from each address
+#[derive(Debug, Default)]
+pub struct ConnectionCounts {
+    counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnectionCounts {
+    /// Count a connection from `address` until the returned guard is dropped, `None` if `max`
+    /// connections from there are open already
+    pub fn open(self: &Arc<Self>, address: IpAddr, max: Option<usize>) -> Option<OpenConnection> {
+        let Ok(mut counts) = self.counts.lock() else {
+            return None;
+        };
+        let count = counts.entry(address).or_default();
+        if max.is_some_and(|max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+        Some(OpenConnection {
+            counts: self.clone(),
+            address,
+        })
+    }
+}
+
+/// A connection counted by [`ConnectionCounts`]
+#[derive(Debug)]
+pub struct OpenConnection {
+    counts: Arc<ConnectionCounts>,
+    address: IpAddr,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        let Ok(mut counts) = self.counts.counts.lock() else {
+            return;
+        };
+        if let Some(count) = counts.get_mut(&self.address) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.address);
+            }
+        }
+    }
+}
+
+/// How many bytes may flow
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bandwidth {
+    /// Sustained rate
+    pub bytes_per_second: u64,
+    /// How many bytes may flow at once after a quiet period, `bytes_per_second` if not set
+    #[serde(default)]
+    pub burst: Option<u64>,
+}
+
+impl Bandwidth {
+    fn burst(&self) -> f64 {
+        self.burst.unwrap_or(self.bytes_per_second) as f64
+    }
+}
+
+/// Token bucket holding up to [`Bandwidth::burst`] bytes and refilled at
+/// [`Bandwidth::bytes_per_second`]. Taking more than it holds runs it into debt, which is repaid
+/// by waiting before taking again.
+#[derive(Debug)]
+pub struct Throttle {
+    limit: Bandwidth,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    pub fn new(limit: Bandwidth) -> Self {
+        Throttle {
+            bucket: Mutex::new((limit.burst(), Instant::now())),
+            limit,
+        }
+    }
+
+    /// The limit of the throttle
+    pub fn limit(&self) -> Bandwidth {
+        self.limit
+    }
+
+    /// Take `bytes` from the bucket, returning how long to wait before they count as sent
+    pub fn take(&self, bytes: usize) -> Duration {
+        let Ok(mut bucket) = self.bucket.lock() else {
+            return Duration::ZERO;
+        };
+        let now = Instant::now();
+        let rate = self.rate();
+        let tokens = self.refilled(*bucket, now) - bytes as f64;
+        *bucket = (tokens, now);
+        match tokens < 0.0 {
+            true => Duration::from_secs_f64(-tokens / rate),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// Take `bytes` from the bucket only if it holds that many, without running into debt
+    pub fn try_take(&self, bytes: usize) -> bool {
+        let Ok(mut bucket) = self.bucket.lock() else {
+            return true;
+        };
+        let now = Instant::now();
+        let tokens = self.refilled(*bucket, now);
+        if tokens < bytes as f64 {
+            return false;
+        }
+        *bucket = (tokens - bytes as f64, now);
+        true
+    }
+
+    fn rate(&self) -> f64 {
+        self.limit.bytes_per_second.max(1) as f64
+    }
+
+    /// The tokens of `bucket` once refilled up to `now`
+    fn refilled(&self, (tokens, since): (f64, Instant), now: Instant) -> f64 {
+        let refilled = tokens + now.duration_since(since).as_secs_f64() * self.rate();
+        refilled.min(self.limit.burst())
+    }
+}
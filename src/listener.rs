@@ -0,0 +1,304 @@
+//! Where the server accepts connections: the TCP address it
+//! [listens on](crate::config::Config::listen) and optionally a
+//! [Unix domain socket](crate::config::UnixSocketConfig) for a reverse proxy on the same host.
+//! If [systemd](crate::systemd) passed the server sockets instead, it listens on those.
+//!
+//! [More listeners](crate::config::ListenerConfig) can be configured, each with policies of its
+//! own, say a Unix socket for an internal tool besides the public TCP address. The server serves
+//! the clients of each under a configuration of their own.
+//!
+//! Clients on a Unix socket count as connecting from `127.0.0.1`, every limit and log keyed by
+//! address sees them as one local peer.
+use crate::{
+    config::{Config, ListenerConfig, UnixSocketConfig},
+    systemd,
+};
+use anyhow::{anyhow, Context, Result};
+use nix::{
+    sys::socket::{getsockname, AddressFamily, SockaddrLike, SockaddrStorage},
+    unistd::{self, Group, User},
+};
+use std::{
+    fs::{self, Permissions},
+    future,
+    io::{self, IoSlice},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        io::{AsRawFd, OwnedFd},
+    },
+    path::PathBuf,
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+use tracing::{info, warn};
+
+/// Address of clients connecting through a Unix socket
+const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Accepts connections on all the sockets of a listener. The Unix socket the server created, if
+/// any, is removed when the listener is dropped.
+pub struct Listener {
+    /// The name of the listener, `None` for the server's own
+    name: Option<String>,
+    sockets: Vec<Socket>,
+    created: Option<PathBuf>,
+    /// Whether connections are never TLS
+    plaintext: bool,
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Listen on the sockets systemd passed, else where `config` says
+    pub async fn open(config: &Config) -> Result<Self> {
+        let scheme = if config.tls.is_some() { "wss" } else { "ws" };
+        let inherited = systemd::listen_fds()?;
+        if !inherited.is_empty() {
+            let sockets = inherited
+                .into_iter()
+                .map(|fd| inherit(fd, scheme))
+                .collect::<Result<_>>()?;
+            return Ok(Listener {
+                name: None,
+                sockets,
+                created: None,
+                plaintext: false,
+            });
+        }
+
+        let tcp = TcpListener::bind(config.listen)
+            .await
+            .with_context(|| format!("failed to listen on {}", config.listen))?;
+        info!("listening on {}://{}", scheme, config.listen);
+        let mut listener = Listener {
+            name: None,
+            sockets: vec![Socket::Tcp(tcp)],
+            created: None,
+            plaintext: false,
+        };
+        if let Some(unix) = config.unix_socket.as_ref() {
+            listener.sockets.push(Socket::Unix(bind_unix(unix)?));
+            listener.created = Some(unix.path.clone());
+        }
+        Ok(listener)
+    }
+
+    /// Listen where the additional listener `config` says, `tls` tells whether the server has
+    /// TLS set up
+    pub async fn bind(config: &ListenerConfig, tls: bool) -> Result<Self> {
+        let plaintext = config.plaintext || !tls;
+        let mut listener = Listener {
+            name: Some(config.name.clone()),
+            sockets: vec![],
+            created: None,
+            plaintext,
+        };
+        if let Some(address) = config.listen {
+            let tcp = TcpListener::bind(address).await.with_context(|| {
+                format!("failed to listen on {} for listener '{}'", address, config.name)
+            })?;
+            let scheme = if plaintext { "ws" } else { "wss" };
+            info!("listening on {}://{} for listener '{}'", scheme, address, config.name);
+            listener.sockets.push(Socket::Tcp(tcp));
+        }
+        if let Some(unix) = config.unix_socket.as_ref() {
+            listener.sockets.push(Socket::Unix(bind_unix(unix)?));
+            listener.created = Some(unix.path.clone());
+        }
+        Ok(listener)
+    }
+
+    /// The name of the listener, `None` for the server's own
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Whether connections are served without TLS, even if the server has it set up. Those on
+    /// Unix sockets always are.
+    pub fn plaintext(&self) -> bool {
+        self.plaintext
+    }
+
+    /// The next connection on any of the sockets, along with the address of the client
+    pub async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+        future::poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    /// Poll for the next connection on any of the sockets
+    pub fn poll_accept(&self, cx: &mut TaskContext<'_>) -> Poll<io::Result<(Stream, SocketAddr)>> {
+        for socket in self.sockets.iter() {
+            let accepted = match socket {
+                Socket::Tcp(listener) => listener
+                    .poll_accept(cx)
+                    .map_ok(|(stream, peer)| (Stream::Tcp(stream), peer)),
+                Socket::Unix(listener) => listener
+                    .poll_accept(cx)
+                    .map_ok(|(stream, _)| (Stream::Unix(stream), UNIX_PEER)),
+            };
+            if accepted.is_ready() {
+                return accepted;
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Some(path) = self.created.as_ref() {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("failed to remove '{}': {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Listen on socket `fd` passed by systemd, which is either a TCP or a Unix stream socket
+fn inherit(fd: OwnedFd, scheme: &str) -> Result<Socket> {
+    let raw_fd = fd.as_raw_fd();
+    let err_context = || format!("failed to listen on socket {} passed by systemd", raw_fd);
+
+    let address: SockaddrStorage = getsockname(raw_fd).with_context(err_context)?;
+    match address.family() {
+        Some(AddressFamily::Inet | AddressFamily::Inet6) => {
+            let listener = std::net::TcpListener::from(fd);
+            listener.set_nonblocking(true).with_context(err_context)?;
+            let listener = TcpListener::from_std(listener).with_context(err_context)?;
+            info!(
+                "listening on {}://{} passed by systemd",
+                scheme,
+                listener.local_addr().with_context(err_context)?
+            );
+            Ok(Socket::Tcp(listener))
+        },
+        Some(AddressFamily::Unix) => {
+            let listener = std::os::unix::net::UnixListener::from(fd);
+            listener.set_nonblocking(true).with_context(err_context)?;
+            let listener = UnixListener::from_std(listener).with_context(err_context)?;
+            let address = listener.local_addr().with_context(err_context)?;
+            match address.as_pathname() {
+                Some(path) => info!("listening on unix:{} passed by systemd", path.display()),
+                None => info!("listening on an unnamed Unix socket passed by systemd"),
+            }
+            Ok(Socket::Unix(listener))
+        },
+        _ => Err(anyhow!("only TCP and Unix sockets are supported")).with_context(err_context),
+    }
+}
+
+/// Create the Unix socket of `config`, replacing a socket left behind by an earlier run, and
+/// give it the permissions and owner asked for
+fn bind_unix(config: &UnixSocketConfig) -> Result<UnixListener> {
+    let path = &config.path;
+    let err_context = || format!("failed to listen on '{}'", path.display());
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("a file that is no socket is in the way"))
+                .with_context(err_context);
+        }
+        fs::remove_file(path).with_context(err_context)?;
+    }
+    let listener = UnixListener::bind(path).with_context(err_context)?;
+    if let Some(mode) = config.mode {
+        fs::set_permissions(path, Permissions::from_mode(mode)).with_context(err_context)?;
+    }
+    let owner = match config.owner.as_deref() {
+        Some(name) => Some(
+            User::from_name(name)
+                .with_context(err_context)?
+                .ok_or_else(|| anyhow!("no such user '{}'", name))
+                .with_context(err_context)?
+                .uid,
+        ),
+        None => None,
+    };
+    let group = match config.group.as_deref() {
+        Some(name) => Some(
+            Group::from_name(name)
+                .with_context(err_context)?
+                .ok_or_else(|| anyhow!("no such group '{}'", name))
+                .with_context(err_context)?
+                .gid,
+        ),
+        None => None,
+    };
+    if owner.is_some() || group.is_some() {
+        unistd::chown(path, owner, group).with_context(err_context)?;
+    }
+    info!("listening on unix:{}", path.display());
+    Ok(listener)
+}
+
+/// A connection accepted by a [`Listener`]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    // TLS hands over the records it encrypted in one go
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Tcp(stream) => stream.is_write_vectored(),
+            Stream::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Logging of the server, as text for humans or as one JSON object per line for log collectors.
+//! Connections and sessions open [`tracing`] spans, so that every event tells which peer,
+//! identity and session it is about without repeating them in the message:
+//!
+//! ```toml
+//! [log]
+//! format = "json"
+//! filter = "info,sh_over_ws_actuator::session=debug"
+//! ```
+//!
+//! The filter takes the directives `RUST_LOG` does, which overrides it if set. It is changed
+//! along with the rest of the configuration when that is [reloaded](crate::server), the format
+//! only with a restart.
+use crate::data::SessionId;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::{env, fmt, net::SocketAddr, str::FromStr, sync::OnceLock};
+use tracing::{field, info_span, Span};
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+/// Replaces the filter [`init`] set up
+type SetFilter = Box<dyn Fn(EnvFilter) -> Result<()> + Send + Sync>;
+
+static SET_FILTER: OnceLock<SetFilter> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// One line of text per event, with the fields of its spans in front of the message
+    #[default]
+    Text,
+    /// One JSON object per event, its spans listed under `spans`
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}'", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// Which events are logged, in the syntax of `RUST_LOG`
+    pub filter: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::default(),
+            filter: "info".to_string(),
+        }
+    }
+}
+
+/// Log events as `config` says to stderr, for the rest of the process. Events of crates still
+/// using `log` are logged as well. `traces` sees the spans and events that pass the filter too,
+/// pass [`Identity`](tracing_subscriber::layer::Identity) if nothing should.
+pub fn init<L>(config: &LogConfig, traces: L) -> Result<()>
+where
+    L: Layer<Registry> + Send + Sync + 'static,
+{
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => configured_filter(config)?,
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    let _ = SET_FILTER.set(Box::new(move |filter| {
+        handle
+            .reload(filter)
+            .map_err(|e| anyhow!("failed to change log filter: {}", e))
+    }));
+    let output = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let output = match config.format {
+        LogFormat::Text => output.boxed(),
+        LogFormat::Json => output
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(traces)
+        .with(filter)
+        .with(output)
+        .try_init()
+        .map_err(|e| anyhow!("failed to set up logging: {}", e))
+}
+
+/// Log the events the filter of `config` lets through from now on, unless `RUST_LOG` overrides
+/// it. Does nothing before [`init`].
+pub fn set_filter(config: &LogConfig) -> Result<()> {
+    let Some(set_filter) = SET_FILTER.get() else {
+        return Ok(());
+    };
+    if env::var_os(EnvFilter::DEFAULT_ENV).is_some() {
+        return Ok(());
+    }
+    set_filter(configured_filter(config)?)
+}
+
+fn configured_filter(config: &LogConfig) -> Result<EnvFilter> {
+    EnvFilter::try_new(&config.filter)
+        .map_err(|e| anyhow!("invalid log filter '{}': {}", config.filter, e))
+}
+
+/// The span of everything done for a connection from `peer`. The identity the client
+/// authenticated with is recorded once known.
+pub fn connection_span(peer: SocketAddr) -> Span {
+    info_span!("connection", %peer, identity = field::Empty)
+}
+
+/// The span of everything done for session `id`
+pub fn session_span(id: SessionId) -> Span {
+    info_span!("session", session = %id)
+}
@@ -1,11 +1,25 @@
-// https://github.com/zellij-org/zellij/blob/61a9b06237d1b84a6af5132f43b9f48902e2dc80/zellij-server/src/pty.rs#L426
-// https://man7.org/linux/man-pages/man3/termios.3.html
-// https://en.wikibooks.org/wiki/Serial_Programming/termios
-pub mod os_io;
-pub mod command;
-pub mod data;
-pub mod error;
-pub use anyhow;
-fn main() {
-    println!("Hello, world!");
+use clap::Parser;
+use sh_over_ws_actuator::{config::Cli, error::FatalError, logging, server::Server};
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let config = cli.clone().into_config().fatal();
+    #[cfg(feature = "otel")]
+    let telemetry = config
+        .otel
+        .as_ref()
+        .map(sh_over_ws_actuator::otel::Telemetry::start)
+        .transpose()
+        .fatal();
+    #[cfg(feature = "otel")]
+    let traces = telemetry.as_ref().map(|telemetry| telemetry.layer());
+    #[cfg(not(feature = "otel"))]
+    let traces = tracing_subscriber::layer::Identity::new();
+    logging::init(&config.log, traces).fatal();
+    Server::new(config)
+        .with_reload(move || cli.clone().into_config())
+        .run()
+        .await
+        .fatal();
 }
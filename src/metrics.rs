@@ -0,0 +1,297 @@
+//! Prometheus metrics of the server, exposed by the [admin listener](crate::admin). They are
+//! process-wide, so that anything spawning commands can count without having them passed in.
+use crate::data::SessionId;
+use anyhow::{Context, Result};
+use prometheus::{
+    core::Collector, proto::MetricType, Counter, Encoder, Gauge, IntCounter, IntCounterVec,
+    IntGauge, Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Prepended to the name of every metric
+const PREFIX: &str = "shws";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricKind {
+    /// Only ever goes up
+    Counter,
+    /// Goes up and down
+    Gauge,
+}
+
+pub struct Metrics {
+    registry: Registry,
+    /// Name, help and kind of every metric registered
+    descriptions: Vec<(String, String, MetricKind)>,
+    /// WebSocket connections accepted
+    pub connections: IntCounter,
+    /// Upgrade requests turned down for lacking a valid token
+    pub auth_rejections: IntCounter,
+    /// Clients locked out for failing to authenticate too often
+    pub lockouts: IntCounter,
+    /// Connections turned away for coming in too often
+    pub rate_limited: IntCounter,
+    /// Connections turned away for the address they come from
+    pub address_rejections: IntCounter,
+    /// Connections turned away before the handshake was done, by why
+    connection_rejections: IntCounterVec,
+    /// Sessions currently running, attached or not
+    pub sessions: IntGauge,
+    /// Bytes written to (`in`) and read from (`out`) each running session
+    session_bytes: IntCounterVec,
+    /// Bytes passed through [meters](crate::filter::Meter), by profile and direction
+    metered_bytes: IntCounterVec,
+    /// Commands that couldn't be started
+    pub spawn_failures: IntCounter,
+    /// Commands refused by the command policy
+    pub policy_violations: IntCounter,
+    /// Commands that exited, by exit code, `signal` if they were killed by one
+    exits: IntCounterVec,
+    /// Sessions that sent output faster than their bandwidth allows and wait for it to catch up
+    pub throttled_sessions: IntGauge,
+    /// Seconds sessions waited for their bandwidth to catch up
+    pub throttled_seconds: Counter,
+    /// Reads of output sent in the frame of an earlier read, see
+    /// [`Coalescing`](crate::config::Coalescing)
+    pub coalesced_reads: IntCounter,
+    /// Seconds output was held back waiting for more to send along with it
+    pub coalescing_seconds: Counter,
+    /// Seconds output is waited for after each read, as configured
+    pub coalescing_window: Gauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some(PREFIX.to_string()), None).expect("valid metrics prefix");
+        let mut metrics = Metrics {
+            connections: IntCounter::new("connections_total", "WebSocket connections accepted")
+                .expect("valid metric"),
+            auth_rejections: IntCounter::new(
+                "auth_rejections_total",
+                "Connections rejected for lacking a valid token",
+            )
+            .expect("valid metric"),
+            lockouts: IntCounter::new(
+                "lockouts_total",
+                "Clients locked out for failing to authenticate too often",
+            )
+            .expect("valid metric"),
+            rate_limited: IntCounter::new(
+                "rate_limited_total",
+                "Connections turned away for coming in too often",
+            )
+            .expect("valid metric"),
+            address_rejections: IntCounter::new(
+                "address_rejections_total",
+                "Connections turned away for the address they come from",
+            )
+            .expect("valid metric"),
+            connection_rejections: IntCounterVec::new(
+                Opts::new(
+                    "connection_rejections_total",
+                    "Connections turned away before the handshake was done, by why",
+                ),
+                &["reason"],
+            )
+            .expect("valid metric"),
+            sessions: IntGauge::new("sessions", "Sessions currently running")
+                .expect("valid metric"),
+            session_bytes: IntCounterVec::new(
+                Opts::new(
+                    "session_bytes_total",
+                    "Bytes written to or read from running sessions",
+                ),
+                &["session", "direction"],
+            )
+            .expect("valid metric"),
+            metered_bytes: IntCounterVec::new(
+                Opts::new(
+                    "metered_bytes_total",
+                    "Bytes passed through the meters of profiles",
+                ),
+                &["profile", "direction"],
+            )
+            .expect("valid metric"),
+            spawn_failures: IntCounter::new(
+                "spawn_failures_total",
+                "Commands that couldn't be started",
+            )
+            .expect("valid metric"),
+            policy_violations: IntCounter::new(
+                "policy_violations_total",
+                "Commands refused by the command policy",
+            )
+            .expect("valid metric"),
+            exits: IntCounterVec::new(
+                Opts::new("command_exits_total", "Commands that exited, by exit code"),
+                &["code"],
+            )
+            .expect("valid metric"),
+            throttled_sessions: IntGauge::new(
+                "throttled_sessions",
+                "Sessions held back by a bandwidth limit",
+            )
+            .expect("valid metric"),
+            throttled_seconds: Counter::new(
+                "throttled_seconds_total",
+                "Seconds sessions were held back by a bandwidth limit",
+            )
+            .expect("valid metric"),
+            coalesced_reads: IntCounter::new(
+                "coalesced_reads_total",
+                "Reads of output sent in the frame of an earlier read",
+            )
+            .expect("valid metric"),
+            coalescing_seconds: Counter::new(
+                "coalescing_seconds_total",
+                "Seconds output was held back waiting for more",
+            )
+            .expect("valid metric"),
+            coalescing_window: Gauge::new(
+                "coalescing_window_seconds",
+                "Seconds output is waited for after each read",
+            )
+            .expect("valid metric"),
+            registry,
+            descriptions: vec![],
+        };
+        for (collector, kind) in [
+            (
+                Box::new(metrics.connections.clone()) as Box<dyn Collector>,
+                MetricKind::Counter,
+            ),
+            (
+                Box::new(metrics.auth_rejections.clone()),
+                MetricKind::Counter,
+            ),
+            (Box::new(metrics.lockouts.clone()), MetricKind::Counter),
+            (Box::new(metrics.rate_limited.clone()), MetricKind::Counter),
+            (
+                Box::new(metrics.address_rejections.clone()),
+                MetricKind::Counter,
+            ),
+            (
+                Box::new(metrics.connection_rejections.clone()),
+                MetricKind::Counter,
+            ),
+            (Box::new(metrics.sessions.clone()), MetricKind::Gauge),
+            (Box::new(metrics.session_bytes.clone()), MetricKind::Counter),
+            (Box::new(metrics.metered_bytes.clone()), MetricKind::Counter),
+            (
+                Box::new(metrics.spawn_failures.clone()),
+                MetricKind::Counter,
+            ),
+            (
+                Box::new(metrics.policy_violations.clone()),
+                MetricKind::Counter,
+            ),
+            (Box::new(metrics.exits.clone()), MetricKind::Counter),
+            (
+                Box::new(metrics.throttled_sessions.clone()),
+                MetricKind::Gauge,
+            ),
+            (
+                Box::new(metrics.throttled_seconds.clone()),
+                MetricKind::Counter,
+            ),
+            (
+                Box::new(metrics.coalesced_reads.clone()),
+                MetricKind::Counter,
+            ),
+            (
+                Box::new(metrics.coalescing_seconds.clone()),
+                MetricKind::Counter,
+            ),
+            (
+                Box::new(metrics.coalescing_window.clone()),
+                MetricKind::Gauge,
+            ),
+        ] {
+            for desc in collector.desc() {
+                let name = format!("{}_{}", PREFIX, desc.fq_name);
+                metrics.descriptions.push((name, desc.help.clone(), kind));
+            }
+            metrics
+                .registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+        metrics
+    }
+
+    /// Count a connection turned away for `reason`, like `max_connections`
+    pub fn connection_rejected(&self, reason: &str) {
+        self.connection_rejections.with_label_values(&[reason]).inc();
+    }
+
+    /// Count `bytes` written to session `id`
+    pub fn bytes_in(&self, id: SessionId, bytes: usize) {
+        self.session_bytes
+            .with_label_values(&[&id.to_string(), "in"])
+            .inc_by(bytes as u64);
+    }
+
+    /// Count `bytes` read from session `id`
+    pub fn bytes_out(&self, id: SessionId, bytes: usize) {
+        self.session_bytes
+            .with_label_values(&[&id.to_string(), "out"])
+            .inc_by(bytes as u64);
+    }
+
+    /// The counter of the bytes metered for `profile` in `direction`, `in` or `out`
+    pub fn metered_bytes(&self, profile: &str, direction: &str) -> IntCounter {
+        self.metered_bytes.with_label_values(&[profile, direction])
+    }
+
+    /// Count the exit of the command of session `id` and forget about the session, so that the
+    /// number of series doesn't grow with every session ever run
+    pub fn session_exited(&self, id: SessionId, code: Option<i32>) {
+        let code = code.map_or_else(|| "signal".to_string(), |code| code.to_string());
+        self.exits.with_label_values(&[&code]).inc();
+        let id = id.to_string();
+        for direction in ["in", "out"] {
+            let _ = self.session_bytes.remove_label_values(&[&id, direction]);
+        }
+    }
+
+    /// All metrics in the Prometheus text format
+    pub fn render(&self) -> Result<String> {
+        let mut buf = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .context("failed to encode metrics")?;
+        String::from_utf8(buf).context("failed to encode metrics")
+    }
+
+    /// Name, help and kind of every metric, names as they are rendered
+    pub fn descriptions(&self) -> Vec<(String, String, MetricKind)> {
+        self.descriptions.clone()
+    }
+
+    /// Current value of each series of metric `name`, along with its labels
+    pub fn samples(&self, name: &str) -> Vec<(Vec<(String, String)>, f64)> {
+        let families = self.registry.gather();
+        let Some(family) = families.iter().find(|family| family.get_name() == name) else {
+            return vec![];
+        };
+        family
+            .get_metric()
+            .iter()
+            .map(|metric| {
+                let labels = metric
+                    .get_label()
+                    .iter()
+                    .map(|label| (label.get_name().to_string(), label.get_value().to_string()))
+                    .collect();
+                let value = match family.get_field_type() {
+                    MetricType::COUNTER => metric.get_counter().get_value(),
+                    _ => metric.get_gauge().get_value(),
+                };
+                (labels, value)
+            })
+            .collect()
+    }
+}
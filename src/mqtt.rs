@@ -0,0 +1,344 @@
+//! Bridge to an MQTT broker, for fleets of devices that already report and take orders over
+//! MQTT rather than keeping WebSocket connections open:
+//!
+//! ```toml
+//! [mqtt]
+//! host = "broker.example.com"
+//! tls = true
+//! client_id = "shws-gateway-1"
+//! username = "shws"
+//! password = "s3cr3t"
+//! events_topic = "fleet/gateway-1/events"
+//! jobs_topic = "fleet/gateway-1/jobs"
+//! commands_topic = "fleet/gateway-1/run"
+//! ```
+//!
+//! The same [notifications](crate::notify::Notification) webhooks get are published to
+//! `<events_topic>/<event>`, e.g. `fleet/gateway-1/events/session_ended`.
+//!
+//! If `commands_topic` is set, the bridge takes [`Message::Run`] messages published to it and
+//! runs them like a client connected as `user` would, scheduling and queueing included. Once a
+//! command exited its result is published to `<jobs_topic>/<session>` as
+//! [`Message::JobDetails`] with the tail of its output, commands that couldn't be run get a
+//! [`Message::Error`] instead.
+//!
+//! Only built with the `mqtt` feature.
+use crate::{
+    audit::{AuditLog, Client},
+    command::RunCommand,
+    config::Config,
+    data::{JobRecord, Message, Payload, SessionId},
+    notify::Notification,
+    session::{SessionManager, SessionRegistry},
+    tls,
+};
+use anyhow::Result;
+use rumqttc::{
+    AsyncClient, Event, EventLoop, MqttOptions, Packet, Publish, QoS, TlsConfiguration, Transport,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::mpsc, time};
+use tracing::{info, warn};
+
+/// How long to wait before connecting to the broker again after losing the connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Messages waiting to be sent to the broker before publishing fails
+const QUEUE_CAPACITY: usize = 256;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    /// Host name or address of the broker
+    pub host: String,
+    /// Port of the broker, 1883 by default and 8883 with TLS
+    pub port: Option<u16>,
+    /// Connect over TLS
+    #[serde(default)]
+    pub tls: bool,
+    /// PEM file with the CAs the broker's certificate must be signed by, the Mozilla root
+    /// certificates if not set
+    pub ca: Option<PathBuf>,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topic notifications are published under, followed by the event
+    #[serde(default = "default_events_topic")]
+    pub events_topic: String,
+    /// Topic the results of commands from `commands_topic` are published under, followed by the
+    /// session
+    #[serde(default = "default_jobs_topic")]
+    pub jobs_topic: String,
+    /// Topic commands are taken from, none are if not set
+    pub commands_topic: Option<String>,
+    /// Who clients publishing commands count as, for the [policies](crate::command::UserPolicy)
+    #[serde(default = "default_user")]
+    pub user: String,
+    /// Bytes of output published with the result of a command, the last it wrote
+    #[serde(default = "default_output_tail")]
+    pub output_tail: usize,
+}
+
+fn default_client_id() -> String {
+    "sh-over-ws-actuator".to_string()
+}
+
+fn default_events_topic() -> String {
+    "shws/events".to_string()
+}
+
+fn default_jobs_topic() -> String {
+    "shws/jobs".to_string()
+}
+
+fn default_user() -> String {
+    "mqtt".to_string()
+}
+
+fn default_output_tail() -> usize {
+    4096
+}
+
+/// Publishes notifications to the broker, handed to the [`SessionRegistry`]
+#[derive(Clone)]
+pub struct MqttPublisher {
+    client: AsyncClient,
+    events_topic: String,
+}
+
+impl MqttPublisher {
+    /// Publish `notification` to the events topic, dropping it if the connection to the broker
+    /// doesn't keep up
+    pub fn notify(&self, notification: &Notification) {
+        let topic = match serde_json::to_value(notification.event) {
+            Ok(serde_json::Value::String(event)) => format!("{}/{}", self.events_topic, event),
+            _ => self.events_topic.clone(),
+        };
+        match notification.to_json() {
+            Ok(json) => publish(&self.client, topic, json),
+            Err(e) => warn!("failed to encode notification: {}", e),
+        }
+    }
+}
+
+/// The connection to the broker, taking commands from it if asked to
+pub struct MqttBridge {
+    config: MqttConfig,
+    client: AsyncClient,
+    eventloop: EventLoop,
+}
+
+impl MqttBridge {
+    /// Set up the connection `config` describes, made once the bridge [runs](MqttBridge::run),
+    /// along with the publisher for notifications
+    pub fn new(config: &MqttConfig) -> Result<(Self, MqttPublisher)> {
+        let port = config.port.unwrap_or(if config.tls { 8883 } else { 1883 });
+        let mut options = MqttOptions::new(&config.client_id, &config.host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some(username) = config.username.as_ref() {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+        if config.tls {
+            let tls = tls::load_client_config(config.ca.as_deref(), None)?;
+            options.set_transport(Transport::tls_with_config(TlsConfiguration::Rustls(tls)));
+        }
+        let (client, eventloop) = AsyncClient::new(options, QUEUE_CAPACITY);
+        let publisher = MqttPublisher {
+            client: client.clone(),
+            events_topic: config.events_topic.clone(),
+        };
+        let bridge = MqttBridge {
+            config: config.clone(),
+            client,
+            eventloop,
+        };
+        Ok((bridge, publisher))
+    }
+
+    /// Keep connected to the broker, reconnecting whenever the connection is lost, and run the
+    /// commands published to the commands topic
+    pub async fn run(
+        mut self,
+        config: Arc<Config>,
+        registry: Arc<SessionRegistry>,
+        audit: Arc<AuditLog>,
+    ) {
+        let client = Client {
+            user: Some(self.config.user.clone()),
+            ..Client::new(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+        };
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let (due_tx, mut due_rx) = mpsc::unbounded_channel();
+        let sessions = SessionManager::new(config, registry, audit, client, events_tx, due_tx);
+        let backlog = sessions.backlog();
+        let mut jobs = HashMap::new();
+        loop {
+            tokio::select! {
+                polled = self.eventloop.poll() => match polled {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("connected to MQTT broker {}", self.config.host);
+                        // the broker forgets subscriptions of clients starting clean sessions
+                        if let Some(topic) = self.config.commands_topic.as_ref() {
+                            if let Err(e) = self.client.try_subscribe(topic, QoS::AtLeastOnce) {
+                                warn!("failed to subscribe to '{}': {}", topic, e);
+                            }
+                        }
+                    },
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        if self.config.commands_topic.as_deref() == Some(publish.topic.as_str()) {
+                            run_command(&sessions, &mut jobs, &publish).await;
+                        }
+                    },
+                    Ok(_) => {},
+                    Err(e) => {
+                        warn!("connection to MQTT broker {} failed: {}", self.config.host, e);
+                        time::sleep(RECONNECT_DELAY).await;
+                    },
+                },
+                Some(message) = events_rx.recv() => {
+                    backlog.sent(message.data_len());
+                    self.handle_event(&mut jobs, message);
+                },
+                Some(due) = due_rx.recv() => sessions.start_due(due),
+            }
+        }
+    }
+
+    /// Follow the commands taken from the broker, publishing their results once they exited
+    fn handle_event(&self, jobs: &mut HashMap<SessionId, Job>, message: Message) {
+        match message {
+            Message::Opened { session, .. } => {
+                if let Some(job) = jobs.get_mut(&session) {
+                    job.started_at = Some(SystemTime::now());
+                }
+            },
+            Message::Output { session, data, .. } => {
+                if let Some(job) = jobs.get_mut(&session) {
+                    job.output.extend_from_slice(&data.0);
+                    let excess = job.output.len().saturating_sub(self.config.output_tail);
+                    job.output.drain(..excess);
+                }
+            },
+            Message::Exit {
+                session,
+                code,
+                signal,
+                reason,
+            } => {
+                let Some(job) = jobs.remove(&session) else {
+                    return;
+                };
+                let now = SystemTime::now();
+                let job = JobRecord {
+                    session,
+                    command: job.command.to_string(),
+                    submitter: self.config.user.clone(),
+                    // commands cancelled before they started never did
+                    started_at: humantime::format_rfc3339_millis(job.started_at.unwrap_or(now))
+                        .to_string(),
+                    ended_at: humantime::format_rfc3339_millis(now).to_string(),
+                    code,
+                    signal,
+                    reason,
+                    output: Some(Payload::from(job.output)),
+                };
+                self.publish_result(session, &Message::JobDetails { job });
+            },
+            Message::Error {
+                session: Some(session),
+                error,
+            } => {
+                // commands that were started end with their exit, whatever failed on the way
+                let failed = jobs
+                    .get(&session)
+                    .is_some_and(|job| job.started_at.is_none());
+                if failed {
+                    jobs.remove(&session);
+                    self.publish_result(
+                        session,
+                        &Message::Error {
+                            session: Some(session),
+                            error,
+                        },
+                    );
+                }
+            },
+            Message::Error {
+                session: None,
+                error,
+            } => warn!("command from MQTT broker failed: {}", error),
+            _ => {},
+        }
+    }
+
+    fn publish_result(&self, session: SessionId, result: &Message) {
+        let topic = format!("{}/{}", self.config.jobs_topic, session);
+        match serde_json::to_vec(result) {
+            Ok(json) => publish(&self.client, topic, json),
+            Err(e) => warn!("failed to encode result of session {}: {}", session, e),
+        }
+    }
+}
+
+/// Run the command `publish` carries, if it does
+async fn run_command(
+    sessions: &SessionManager,
+    jobs: &mut HashMap<SessionId, Job>,
+    publish: &Publish,
+) {
+    match serde_json::from_slice::<Message>(&publish.payload) {
+        Ok(run @ Message::Run { .. }) => {
+            if let Message::Run {
+                session,
+                program,
+                args,
+                ..
+            } = &run
+            {
+                jobs.insert(*session, Job::new(program.clone(), args.clone()));
+            }
+            sessions.handle_message(run).await;
+        },
+        Ok(_) => warn!(
+            "ignoring message from '{}' that is no command",
+            publish.topic
+        ),
+        Err(e) => warn!("ignoring invalid command from '{}': {}", publish.topic, e),
+    }
+}
+
+/// A command taken from the broker
+struct Job {
+    command: RunCommand,
+    /// `None` until the command was started
+    started_at: Option<SystemTime>,
+    /// The tail of its output
+    output: Vec<u8>,
+}
+
+impl Job {
+    fn new(program: PathBuf, args: Vec<String>) -> Self {
+        Job {
+            command: RunCommand {
+                command: program,
+                args,
+                ..Default::default()
+            },
+            started_at: None,
+            output: vec![],
+        }
+    }
+}
+
+fn publish(client: &AsyncClient, topic: String, payload: Vec<u8>) {
+    if let Err(e) = client.try_publish(&topic, QoS::AtLeastOnce, false, payload) {
+        warn!("failed to publish to '{}': {}", topic, e);
+    }
+}
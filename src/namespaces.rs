@@ -0,0 +1,322 @@
+//! Isolating the command of a profile in Linux namespaces of its own, container-like without a
+//! container runtime. With `isolation = "namespaces"` the command gets new mount, PID, IPC and
+//! UTS namespaces, a network namespace with nothing but loopback unless `share_network` is set,
+//! and a user namespace mapping the server's user to itself if the server doesn't run as root:
+//!
+//! ```toml
+//! [profiles.isolated]
+//! cmd = "/bin/sh"
+//! isolation = "namespaces"
+//! rootfs = "/srv/shws/rootfs"
+//! binds = [{ source = "/usr" }, { source = "/srv/shws/work", target = "/work", writable = true }]
+//! ```
+//!
+//! With a `rootfs` the command sees that directory as `/`, which needs a `/proc` and the targets
+//! of the bind mounts in it. Bind mounts are read only unless `writable`. Either way the command
+//! gets a `/proc` of its own and runs as PID 1 of its namespace, so it only gets the signals it
+//! handles. A process waiting for it in between kills it once the session ends.
+use crate::command::Profile;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::{
+    ffi::CString,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// How the command of a [`Profile`] is isolated from the server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Isolation {
+    /// Not at all, beyond the jail, the user and the hardening all commands get
+    #[default]
+    None,
+    /// In namespaces of its own
+    Namespaces,
+}
+
+/// A directory of the server mounted for an isolated command
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BindMount {
+    pub source: PathBuf,
+    /// Where the command sees it, at the same path as on the server if not set
+    #[serde(default)]
+    pub target: Option<PathBuf>,
+    #[serde(default)]
+    pub writable: bool,
+}
+
+/// Namespaces for a command, prepared before forking as nothing may be allocated in between
+/// forking and executing it
+#[derive(Clone, Debug)]
+pub struct Namespaces {
+    /// `CLONE_NEW*` flags of the namespaces to create
+    flags: libc::c_int,
+    /// Lines for `uid_map` and `gid_map`, if a user namespace is created
+    id_maps: Option<(String, String)>,
+    share_network: bool,
+    rootfs: Option<CString>,
+    /// Source and target as seen before changing the root, and whether it is writable
+    binds: Vec<(CString, CString, bool)>,
+    /// Where the `/proc` of the PID namespace is mounted, as seen before changing the root
+    proc: CString,
+    /// Working directory once the root is changed
+    cwd: CString,
+}
+
+impl Namespaces {
+    /// The namespaces `profile` asks for, for a command started in `cwd`, which is within the
+    /// root filesystem of the profile if it has one
+    #[cfg(target_os = "linux")]
+    pub fn new(profile: &Profile, cwd: Option<&Path>) -> Result<Self> {
+        let c_path = |path: &Path| {
+            CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| anyhow!("'{}' contains a NUL byte", path.display()))
+        };
+        let rootfs = profile.rootfs.as_deref();
+        let mut flags =
+            libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWIPC | libc::CLONE_NEWUTS;
+        if !profile.share_network {
+            flags |= libc::CLONE_NEWNET;
+        }
+        let euid = nix::unistd::geteuid();
+        let id_maps = match euid.is_root() {
+            true => None,
+            false => {
+                flags |= libc::CLONE_NEWUSER;
+                let egid = nix::unistd::getegid();
+                Some((
+                    format!("{} {} 1", euid, euid),
+                    format!("{} {} 1", egid, egid),
+                ))
+            },
+        };
+        let binds = profile
+            .binds
+            .iter()
+            .map(|bind| {
+                let target = bind.target.as_deref().unwrap_or(&bind.source);
+                let target = match rootfs {
+                    Some(rootfs) => rootfs.join(target.strip_prefix("/").unwrap_or(target)),
+                    None => target.to_path_buf(),
+                };
+                Ok((c_path(&bind.source)?, c_path(&target)?, bind.writable))
+            })
+            .collect::<Result<_>>()?;
+        let proc = rootfs.map_or_else(|| PathBuf::from("/proc"), |rootfs| rootfs.join("proc"));
+        let cwd = Path::new("/").join(
+            cwd.zip(rootfs)
+                .and_then(|(cwd, rootfs)| cwd.strip_prefix(rootfs).ok())
+                .unwrap_or_else(|| Path::new("")),
+        );
+        Ok(Namespaces {
+            flags,
+            id_maps,
+            share_network: profile.share_network,
+            rootfs: rootfs.map(c_path).transpose()?,
+            binds,
+            proc: c_path(&proc)?,
+            cwd: c_path(&cwd)?,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(_profile: &Profile, _cwd: Option<&Path>) -> Result<Self> {
+        Err(anyhow!("namespaces are only available on Linux"))
+    }
+
+    /// Whether the command gets a root directory of its own
+    pub fn changes_root(&self) -> bool {
+        self.rootfs.is_some()
+    }
+
+    /// Create the namespaces and continue in the first process of the new PID namespace, only
+    /// meant to be called between forking and executing a command. The calling process stays
+    /// behind, waits for that one and exits like it.
+    #[cfg(target_os = "linux")]
+    pub fn enter(&self) -> io::Result<()> {
+        check(unsafe { libc::unshare(self.flags) })?;
+        if let Some((uid_map, gid_map)) = self.id_maps.as_ref() {
+            // unprivileged processes may only map their group once they can't drop groups
+            write_file(c"/proc/self/setgroups", b"deny")?;
+            write_file(c"/proc/self/uid_map", uid_map.as_bytes())?;
+            write_file(c"/proc/self/gid_map", gid_map.as_bytes())?;
+        }
+        // the new PID namespace is only for the children of the caller
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {},
+            child => wait_for(child),
+        }
+        check(unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) })?;
+        if !self.share_network {
+            loopback_up()?;
+        }
+
+        // nothing mounted from here on may show up outside
+        mount(c"none", c"/", None, libc::MS_REC | libc::MS_PRIVATE)?;
+        if let Some(rootfs) = self.rootfs.as_ref() {
+            // only mount points can become the root
+            mount(rootfs, rootfs, None, libc::MS_BIND | libc::MS_REC)?;
+        }
+        for (source, target, writable) in self.binds.iter() {
+            mount(source, target, None, libc::MS_BIND | libc::MS_REC)?;
+            if !writable {
+                remount_read_only(target)?;
+            }
+        }
+        // a user namespace may only mount one while another is visible, before changing the root
+        mount(
+            c"proc",
+            &self.proc,
+            Some(c"proc"),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        )?;
+        if let Some(rootfs) = self.rootfs.as_ref() {
+            check(unsafe { libc::chdir(rootfs.as_ptr()) })?;
+            // stacks the new root on the old one, which is then detached from beneath it
+            let dot = c".".as_ptr();
+            check(unsafe { libc::syscall(libc::SYS_pivot_root, dot, dot) } as libc::c_int)?;
+            check(unsafe { libc::umount2(c".".as_ptr(), libc::MNT_DETACH) })?;
+            check(unsafe { libc::chdir(self.cwd.as_ptr()) })?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn enter(&self) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod init {
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    /// The first process of the PID namespace, for the signal handler of the process waiting
+    /// for it
+    pub static CHILD: AtomicI32 = AtomicI32::new(0);
+
+    pub extern "C" fn kill_child(_signal: libc::c_int) {
+        unsafe {
+            libc::kill(CHILD.load(Ordering::Relaxed), libc::SIGKILL);
+        }
+    }
+}
+
+/// Wait for `child`, the first process of the new PID namespace, and exit like it did. Ending
+/// the session hangs up on or terminates the waiting process, which then kills the child.
+#[cfg(target_os = "linux")]
+fn wait_for(child: libc::pid_t) -> ! {
+    init::CHILD.store(child, std::sync::atomic::Ordering::Relaxed);
+    unsafe {
+        // the terminal sends these to the command itself
+        for signal in [
+            libc::SIGINT,
+            libc::SIGQUIT,
+            libc::SIGTSTP,
+            libc::SIGTTIN,
+            libc::SIGTTOU,
+        ] {
+            libc::signal(signal, libc::SIG_IGN);
+        }
+        for signal in [libc::SIGHUP, libc::SIGTERM] {
+            libc::signal(signal, init::kill_child as *const () as libc::sighandler_t);
+        }
+        // whoever spawned the command waits for these to be closed, the child holds them now
+        close_fds::close_open_fds(3, &[]);
+
+        let mut status = 0;
+        while libc::waitpid(child, &mut status, 0) == -1 {
+            if nix::errno::Errno::last() != nix::errno::Errno::EINTR {
+                libc::_exit(1);
+            }
+        }
+        if libc::WIFSIGNALED(status) {
+            let signal = libc::WTERMSIG(status);
+            libc::signal(signal, libc::SIG_DFL);
+            libc::kill(libc::getpid(), signal);
+            libc::_exit(128 + signal);
+        }
+        libc::_exit(libc::WEXITSTATUS(status))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check(result: libc::c_int) -> io::Result<()> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn mount(
+    source: &std::ffi::CStr,
+    target: &std::ffi::CStr,
+    fstype: Option<&std::ffi::CStr>,
+    flags: libc::c_ulong,
+) -> io::Result<()> {
+    let fstype = fstype.map_or(std::ptr::null(), |fstype| fstype.as_ptr());
+    check(unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype,
+            flags,
+            std::ptr::null(),
+        )
+    })
+}
+
+/// Make bind mount `target` read only. The flags the mount locks, which a user namespace may not
+/// clear, are kept.
+#[cfg(target_os = "linux")]
+fn remount_read_only(target: &std::ffi::CStr) -> io::Result<()> {
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    check(unsafe { libc::statvfs(target.as_ptr(), &mut stat) })?;
+    let mut flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY;
+    for (kept, flag) in [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+    ] {
+        if stat.f_flag & kept != 0 {
+            flags |= flag;
+        }
+    }
+    mount(c"none", target, None, flags)
+}
+
+#[cfg(target_os = "linux")]
+fn write_file(path: &std::ffi::CStr, contents: &[u8]) -> io::Result<()> {
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+    check(fd)?;
+    let written = unsafe { libc::write(fd, contents.as_ptr().cast(), contents.len()) };
+    let result = match written {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    };
+    unsafe { libc::close(fd) };
+    result
+}
+
+/// Bring up the loopback interface of a new network namespace, which starts out down
+#[cfg(target_os = "linux")]
+fn loopback_up() -> io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    check(fd)?;
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (to, from) in request.ifr_name.iter_mut().zip(b"lo") {
+        *to = *from as libc::c_char;
+    }
+    let mut result = check(unsafe { libc::ioctl(fd, libc::SIOCGIFFLAGS as _, &mut request) });
+    if result.is_ok() {
+        unsafe { request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+        result = check(unsafe { libc::ioctl(fd, libc::SIOCSIFFLAGS as _, &request) });
+    }
+    unsafe { libc::close(fd) };
+    result
+}
@@ -0,0 +1,313 @@
+//! Webhooks told about what happens on the server, for chat and paging integrations that would
+//! otherwise have to poll the [admin listener](crate::admin):
+//!
+//! ```toml
+//! [[webhooks]]
+//! url = "https://hooks.slack.com/services/T000/B000/XXXX"
+//! events = ["session_ended", "auth_failed", "policy_violation"]
+//!
+//! [[webhooks]]
+//! url = "http://127.0.0.1:8000/shws"
+//! headers = { Authorization = "Bearer s3cr3t" }
+//! ```
+//!
+//! Every [`Notification`] is POSTed as a JSON object, with a `text` summary for humans that
+//! Slack style webhooks show as is. Deliveries failing are retried with exponential backoff,
+//! nothing waits for them.
+use crate::{
+    audit::{serialize_time, Client},
+    data::SessionId,
+    tls,
+};
+use anyhow::{anyhow, Context, Result};
+use hyper::{
+    client::conn,
+    header::{CONTENT_TYPE, HOST, USER_AGENT},
+    Body, Method, Request, Uri,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time,
+};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+use tracing::warn;
+
+/// How long a delivery may take before it counts as failed
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before retrying a failed delivery the first time, twice as long every time
+/// after up to [`MAX_BACKOFF`]
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Where notifications are POSTed to, `http://` or `https://`
+    pub url: String,
+    /// Events the webhook is told about, all of them if not set
+    pub events: Option<Vec<NotifyEvent>>,
+    /// Headers sent along, to authenticate with for example
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Times a failed delivery is retried
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_retries() -> u32 {
+    5
+}
+
+/// What a [`Notification`] is about
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyEvent {
+    /// A session was opened and its command started
+    SessionStarted,
+    /// The command of a session exited and the session ended
+    SessionEnded,
+    /// A client was turned away for presenting no token or an invalid one
+    AuthFailed,
+    /// A client asked for something the server's policy doesn't allow
+    PolicyViolation,
+    /// A [script](crate::scripting) hook called `notify`
+    Script,
+}
+
+/// What webhooks are sent
+#[derive(Debug, Serialize)]
+pub struct Notification<'a> {
+    pub event: NotifyEvent,
+    #[serde(serialize_with = "serialize_time")]
+    pub at: SystemTime,
+    /// The client the event is about
+    pub client: &'a Client,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<SessionId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// `None` if the command was killed by a signal or hasn't exited yet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// The signal that killed the command, if one did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+    /// What went wrong, for failures
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl<'a> Notification<'a> {
+    /// Notification of `event` about `client` happening now
+    pub fn new(event: NotifyEvent, client: &'a Client) -> Self {
+        Notification {
+            event,
+            at: SystemTime::now(),
+            client,
+            session: None,
+            command: None,
+            exit_code: None,
+            signal: None,
+            message: None,
+        }
+    }
+
+    /// The notification as a JSON object, along with its `text`
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        let mut json = serde_json::to_value(self)?;
+        json["text"] = self.text().into();
+        serde_json::to_vec(&json)
+    }
+
+    /// One line telling a human what happened
+    fn text(&self) -> String {
+        let client = self.client.identity();
+        let session = self
+            .session
+            .map_or_else(String::new, |id| format!(" {}", id));
+        let command = self.command.as_deref().unwrap_or_default();
+        let message = self.message.as_deref().unwrap_or_default();
+        match self.event {
+            NotifyEvent::SessionStarted => {
+                format!("{} started '{}' in session{}", client, command, session)
+            },
+            NotifyEvent::SessionEnded => {
+                let status = match (self.exit_code, self.signal.as_deref()) {
+                    (Some(code), _) => format!("exited with code {}", code),
+                    (None, Some(signal)) => format!("was killed by {}", signal),
+                    (None, None) => "ended".to_string(),
+                };
+                format!(
+                    "'{}' of {} in session{} {}",
+                    command, client, session, status
+                )
+            },
+            NotifyEvent::AuthFailed => format!("{} failed to authenticate: {}", client, message),
+            NotifyEvent::PolicyViolation => {
+                format!("{} was refused by the policy: {}", client, message)
+            },
+            NotifyEvent::Script => format!("{}{}: {}", client, session, message),
+        }
+    }
+}
+
+/// Delivers notifications to the configured webhooks
+pub struct Notifier {
+    webhooks: Vec<Arc<Webhook>>,
+    tls: TlsConnector,
+}
+
+struct Webhook {
+    config: WebhookConfig,
+    uri: Uri,
+    host: String,
+    port: u16,
+    https: bool,
+}
+
+impl Notifier {
+    /// A notifier delivering to `webhooks`, checking their URLs
+    pub fn new(webhooks: &[WebhookConfig]) -> Result<Self> {
+        let webhooks = webhooks
+            .iter()
+            .map(|config| {
+                let err_context = || format!("invalid webhook URL '{}'", config.url);
+                let uri: Uri = config.url.parse().with_context(err_context)?;
+                let https = match uri.scheme_str() {
+                    Some("https") => true,
+                    Some("http") => false,
+                    _ => return Err(anyhow!("not an HTTP URL")).with_context(err_context),
+                };
+                let host = uri
+                    .host()
+                    .ok_or_else(|| anyhow!("no host"))
+                    .with_context(err_context)?
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string();
+                let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+                Ok(Arc::new(Webhook {
+                    config: config.clone(),
+                    uri,
+                    host,
+                    port,
+                    https,
+                }))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Notifier {
+            webhooks,
+            tls: TlsConnector::from(tls::load_client_config(None, None)?),
+        })
+    }
+
+    /// Deliver `notification` to the webhooks interested in it in the background
+    pub fn notify(&self, notification: &Notification) {
+        let interested = self.webhooks.iter().filter(|webhook| {
+            webhook
+                .config
+                .events
+                .as_ref()
+                .is_none_or(|events| events.contains(&notification.event))
+        });
+        let mut body = None;
+        for webhook in interested {
+            let body = match body.as_ref() {
+                Some(body) => body,
+                None => match notification.to_json() {
+                    Ok(json) => body.insert(json),
+                    Err(e) => {
+                        warn!("failed to encode notification: {}", e);
+                        return;
+                    },
+                },
+            };
+            tokio::spawn(deliver(webhook.clone(), self.tls.clone(), body.clone()));
+        }
+    }
+}
+
+/// POST `body` to `webhook` until it takes it or the retries ran out
+async fn deliver(webhook: Arc<Webhook>, tls: TlsConnector, body: Vec<u8>) {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 0..=webhook.config.retries {
+        let delivered = time::timeout(DELIVERY_TIMEOUT, post(&webhook, &tls, body.clone()))
+            .await
+            .map_err(|_| anyhow!("timed out"))
+            .and_then(|delivered| delivered);
+        let Err(e) = delivered else {
+            return;
+        };
+        if attempt == webhook.config.retries {
+            warn!("failed to notify {}, giving up: {:#}", webhook.uri, e);
+            return;
+        }
+        warn!(
+            "failed to notify {}, retrying in {:?}: {:#}",
+            webhook.uri, backoff, e
+        );
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn post(webhook: &Webhook, tls: &TlsConnector, body: Vec<u8>) -> Result<()> {
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(
+            webhook
+                .uri
+                .path_and_query()
+                .map_or("/", |path| path.as_str()),
+        )
+        .header(
+            HOST,
+            webhook
+                .uri
+                .authority()
+                .map_or("", |authority| authority.as_str()),
+        )
+        .header(CONTENT_TYPE, "application/json")
+        .header(
+            USER_AGENT,
+            concat!("sh-over-ws-actuator/", env!("CARGO_PKG_VERSION")),
+        );
+    for (name, value) in webhook.config.headers.iter() {
+        request = request.header(name, value);
+    }
+    let request = request.body(Body::from(body))?;
+
+    let stream = TcpStream::connect((webhook.host.as_str(), webhook.port)).await?;
+    let status = match webhook.https {
+        true => {
+            let name = ServerName::try_from(webhook.host.clone())?;
+            send(tls.connect(name, stream).await?, request).await?
+        },
+        false => send(stream, request).await?,
+    };
+    match status.is_success() {
+        true => Ok(()),
+        false => Err(anyhow!("webhook answered {}", status)),
+    }
+}
+
+/// Send `request` on `stream`, the status of the response
+async fn send(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    request: Request<Body>,
+) -> Result<hyper::StatusCode> {
+    let (mut sender, connection) = conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            warn!("connection to webhook failed: {}", e);
+        }
+    });
+    Ok(sender.send_request(request).await?.status())
+}
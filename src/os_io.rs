@@ -2,33 +2,55 @@
 // https://github.com/zellij-org/zellij/blob/main/zellij-server/src/os_input_output.rs
 
 use async_std::{fs::File as AsyncFile, io::ReadExt, os::unix::io::FromRawFd};
-use log::{warn, info, error};
 use nix::{
+    fcntl::{self, fcntl, FcntlArg, FdFlag, OFlag},
     pty::{openpty, OpenptyResult, Winsize},
     sys::{
-        signal::{kill, Signal},
+        signal::{kill, killpg, Signal},
+        socket,
+        stat::Mode,
         termios,
     },
     unistd,
 };
 use signal_hook::consts::*;
 use sysinfo::{ProcessExt, ProcessRefreshKind, System, SystemExt};
+use tracing::{error, warn};
 
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
-    env,
+    collections::{BTreeMap, HashSet},
+    env, fmt,
     fs::File,
+    future::Future,
     io::Write,
-    os::unix::{io::RawFd, process::CommandExt},
-    path::PathBuf,
-    process::{Child, Command},
+    os::unix::{
+        io::{AsRawFd, OwnedFd, RawFd},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    pin::Pin,
+    process::{Child, Command, ExitStatus, Stdio},
     sync::{Arc, Mutex},
+    task::{ready, Context as TaskContext, Poll},
+    time::Duration,
 };
-use crate::{command::{RunCommand, TerminalAction}, error::{FatalError, LoggableError, ToAnyhow}};
+use crate::{command::{Environment, RunCommand, Sandbox, TerminalAction}, data::{Parity, SerialSettings, TermMode, WindowSize}, error::{FatalError, LoggableError, ToAnyhow}, metrics::METRICS, subreaper};
 use tempfile::tempfile;
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf},
+    process::{ChildStderr, ChildStdout},
+    sync::{oneshot, watch},
+};
 
 pub use async_trait::async_trait;
 pub use nix::unistd::Pid;
+
+#[cfg(windows)]
+mod conpty;
+#[cfg(windows)]
+pub use conpty::{ConPty, ConPtyReader, ConPtyWriter};
+#[cfg(feature = "uring")]
+mod uring;
 use anyhow::{Result, Context, anyhow};
 
 fn set_terminal_size_using_fd(
@@ -74,7 +96,7 @@ fn handle_command_exit(mut child: Child) -> Result<Option<i32>> {
     let mut should_exit = false;
     let mut attempts = 3;
     let mut signals =
-        signal_hook::iterator::Signals::new(&[SIGINT, SIGTERM]).with_context(err_context)?;
+        signal_hook::iterator::Signals::new([SIGINT, SIGTERM]).with_context(err_context)?;
     'handle_exit: loop {
         // test whether the child process has exited
         match child.try_wait() {
@@ -111,17 +133,22 @@ fn handle_command_exit(mut child: Child) -> Result<Option<i32>> {
 }
 
 fn command_exists(cmd: &RunCommand) -> bool {
+    find_command(cmd).is_some()
+}
+
+/// Where the executable of `cmd` is found, looking in its cwd and `PATH`
+pub fn find_command(cmd: &RunCommand) -> Option<PathBuf> {
     let command = &cmd.command;
     match cmd.cwd.as_ref() {
         Some(cwd) => {
-            let full_command = cwd.join(&command);
+            let full_command = cwd.join(command);
             if full_command.exists() && full_command.is_file() {
-                return true;
+                return Some(full_command);
             }
         },
         None => {
             if command.exists() && command.is_file() {
-                return true;
+                return Some(command.clone());
             }
         },
     }
@@ -130,23 +157,23 @@ fn command_exists(cmd: &RunCommand) -> bool {
         for path in env::split_paths(&paths) {
             let full_command = path.join(command);
             if full_command.exists() && full_command.is_file() {
-                return true;
+                return Some(full_command);
             }
         }
     }
-    false
+    None
 }
 
 fn handle_openpty(
     open_pty_res: OpenptyResult,
     cmd: RunCommand,
     quit_cb: Box<dyn Fn(Option<i32>, RunCommand) + Send>, // u32 is the exit status
-    terminal_id: u32,
+    _terminal_id: u32,
 ) -> Result<(RawFd, RawFd)> {
     let err_context = |cmd: &RunCommand| {
         format!(
             "failed to open PTY for command '{}'",
-            cmd.command.to_string_lossy().to_string()
+            cmd.command.to_string_lossy()
         )
     };
 
@@ -162,7 +189,7 @@ fn handle_openpty(
                 if current_dir.exists() && current_dir.is_dir() {
                     command.current_dir(current_dir);
                 } else {
-                    log::error!(
+                    error!(
                         "Failed to set CWD for new pane. '{}' does not exist or is not a folder",
                         current_dir.display()
                     );
@@ -316,6 +343,7 @@ fn spawn_terminal(
                 cwd,
                 hold_on_close: false,
                 hold_on_start: false,
+                arg0: None,
             }
         },
         TerminalAction::RunCommand(command) => command,
@@ -346,7 +374,7 @@ struct RawFdAsyncReader {
 impl RawFdAsyncReader {
     fn new(fd: RawFd) -> RawFdAsyncReader {
         RawFdAsyncReader {
-            /// The supplied `RawFd` is consumed by the created `RawFdAsyncReader`, closing it when dropped
+            // The supplied `RawFd` is consumed by the created `RawFdAsyncReader`, closing it when dropped
             fd: unsafe { AsyncFile::from_raw_fd(fd) },
         }
     }
@@ -421,6 +449,8 @@ pub trait ServerOsApi: Send + Sync {
     fn apply_cached_resizes(&mut self) {}
 }
 
+type CachedResize = (u16, u16, Option<u16>, Option<u16>);
+
 #[derive(Clone)]
 pub struct ServerOsInputOutput {
     orig_termios: Arc<Mutex<termios::Termios>>,
@@ -430,7 +460,7 @@ pub struct ServerOsInputOutput {
     // not connected to an fd (eg.
     // a command pane with a
     // non-existing command)
-    cached_resizes: Arc<Mutex<Option<BTreeMap<u32, (u16, u16, Option<u16>, Option<u16>)>>>>, // <terminal_id, (cols, rows, width_in_pixels, height_in_pixels)>
+    cached_resizes: Arc<Mutex<Option<BTreeMap<u32, CachedResize>>>>, // <terminal_id, (cols, rows, width_in_pixels, height_in_pixels)>
 }
 
 impl ServerOsApi for ServerOsInputOutput {
@@ -497,7 +527,6 @@ impl ServerOsApi for ServerOsInputOutput {
                 .copied()
                 .collect();
             for i in 0..u32::MAX {
-                let i = i as u32;
                 if !current_ids.contains(&i) {
                     terminal_id = Some(i);
                     break;
@@ -544,7 +573,6 @@ impl ServerOsApi for ServerOsInputOutput {
                 .copied()
                 .collect();
             for i in 0..u32::MAX {
-                let i = i as u32;
                 if !current_ids.contains(&i) {
                     terminal_id = Some(i);
                     break;
@@ -686,8 +714,8 @@ impl ServerOsApi for ServerOsInputOutput {
                     *terminal_id,
                     *cols,
                     *rows,
-                    width_in_pixels.clone(),
-                    height_in_pixels.clone(),
+                    *width_in_pixels,
+                    *height_in_pixels,
                 );
             }
         }
@@ -720,3 +748,1082 @@ pub struct ChildId {
     /// field is it's parent process id.
     pub shell: Option<Pid>,
 }
+
+/// How long a child gets to exit after its [`Pty`] or [`Exec`] hung up before it is killed
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(3);
+
+/// Size of a pseudo terminal in character cells, optionally with its pixel dimensions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PtySize {
+    pub cols: u16,
+    pub rows: u16,
+    pub width_in_pixels: Option<u16>,
+    pub height_in_pixels: Option<u16>,
+}
+
+impl Default for PtySize {
+    fn default() -> Self {
+        PtySize {
+            cols: 80,
+            rows: 24,
+            width_in_pixels: None,
+            height_in_pixels: None,
+        }
+    }
+}
+
+impl PtySize {
+    /// Terminals need at least one cell, everything else is up to the client
+    pub fn check(&self) -> Result<()> {
+        if self.cols == 0 || self.rows == 0 {
+            return Err(anyhow!("invalid terminal size {}x{}", self.cols, self.rows));
+        }
+        Ok(())
+    }
+}
+
+impl From<WindowSize> for PtySize {
+    fn from(size: WindowSize) -> Self {
+        PtySize {
+            cols: size.cols,
+            rows: size.rows,
+            width_in_pixels: size.width_in_pixels,
+            height_in_pixels: size.height_in_pixels,
+        }
+    }
+}
+
+impl From<PtySize> for Winsize {
+    fn from(size: PtySize) -> Self {
+        Winsize {
+            ws_col: size.cols,
+            ws_row: size.rows,
+            ws_xpixel: size.width_in_pixels.unwrap_or(0),
+            ws_ypixel: size.height_in_pixels.unwrap_or(0),
+        }
+    }
+}
+
+/// Builds terminal attributes on top of the ones a terminal has, so that callers say what they
+/// want changed rather than spelling out every flag. See [`Pty::termios`] and
+/// [`Pty::set_termios`].
+#[derive(Clone)]
+pub struct TermiosBuilder {
+    termios: termios::Termios,
+}
+
+impl TermiosBuilder {
+    pub fn new(termios: termios::Termios) -> Self {
+        TermiosBuilder { termios }
+    }
+
+    /// Switch to `mode`, flags the mode doesn't care about are kept
+    pub fn mode(mut self, mode: TermMode) -> Self {
+        use termios::{InputFlags, LocalFlags, OutputFlags, SpecialCharacterIndices};
+
+        let t = &mut self.termios;
+        match mode {
+            TermMode::Raw => termios::cfmakeraw(t),
+            TermMode::Cbreak => {
+                t.local_flags.remove(LocalFlags::ICANON | LocalFlags::ECHO);
+                t.local_flags.insert(LocalFlags::ISIG);
+                t.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+                t.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+            },
+            TermMode::Canonical => {
+                // undoes what raw mode turned off
+                t.input_flags.insert(InputFlags::ICRNL | InputFlags::IXON);
+                t.output_flags.insert(OutputFlags::OPOST | OutputFlags::ONLCR);
+                t.local_flags.insert(
+                    LocalFlags::ICANON
+                        | LocalFlags::ECHO
+                        | LocalFlags::ECHOE
+                        | LocalFlags::ECHOK
+                        | LocalFlags::ISIG
+                        | LocalFlags::IEXTEN,
+                );
+            },
+        }
+        self
+    }
+
+    /// Echo input back
+    pub fn echo(mut self, on: bool) -> Self {
+        self.termios.local_flags.set(termios::LocalFlags::ECHO, on);
+        self
+    }
+
+    /// Turn the interrupt, quit and suspend characters into signals
+    pub fn isig(mut self, on: bool) -> Self {
+        self.termios.local_flags.set(termios::LocalFlags::ISIG, on);
+        self
+    }
+
+    /// Translate carriage returns in the input to newlines
+    pub fn icrnl(mut self, on: bool) -> Self {
+        self.termios.input_flags.set(termios::InputFlags::ICRNL, on);
+        self
+    }
+
+    pub fn build(self) -> termios::Termios {
+        self.termios
+    }
+}
+
+/// Reaps a spawned child in the background. Dropping the `Reaper` sends the child and whatever
+/// else is still in its session its hangup signal, and kills those still around after
+/// [`KILL_GRACE_PERIOD`], see [`subreaper`].
+struct Reaper {
+    pid: Pid,
+    hangup_signal: Signal,
+    exit_status: watch::Receiver<Option<ExitStatus>>,
+    // dropped together with the `Reaper` to tell its task the child should go away
+    _hangup: oneshot::Sender<()>,
+}
+
+impl Reaper {
+    fn new(mut child: tokio::process::Child, hangup_signal: Signal) -> Result<Reaper> {
+        let pid = Pid::from_raw(
+            child
+                .id()
+                .ok_or_else(|| anyhow!("child exited before its pid was known"))? as i32,
+        );
+        let (exit_tx, exit_status) = watch::channel(None);
+        let (hangup, mut hangup_rx) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let mut hung_up = false;
+            let status = tokio::select! {
+                status = child.wait() => status,
+                _ = &mut hangup_rx => {
+                    hung_up = true;
+                    match tokio::time::timeout(KILL_GRACE_PERIOD, child.wait()).await {
+                        Ok(status) => status,
+                        Err(_) => {
+                            warn!("child {} ignored {}, killing it", pid, hangup_signal);
+                            subreaper::signal_session(pid, Signal::SIGKILL);
+                            child.wait().await
+                        },
+                    }
+                },
+            };
+            subreaper::reaped(pid);
+            match status {
+                Ok(status) => {
+                    let _ = exit_tx.send(Some(status));
+                },
+                Err(e) => error!("failed to reap child {}: {}", pid, e),
+            }
+            // background jobs of the child go once the `Reaper` does, with the same grace period
+            if !hung_up {
+                let _ = hangup_rx.await;
+            }
+            if subreaper::has_processes(pid) {
+                tokio::time::sleep(KILL_GRACE_PERIOD).await;
+                if subreaper::signal_session(pid, Signal::SIGKILL) > 0 {
+                    warn!("processes left by child {} ignored {}, killed them", pid, hangup_signal);
+                }
+            }
+        });
+        Ok(Reaper {
+            pid,
+            hangup_signal,
+            exit_status,
+            _hangup: hangup,
+        })
+    }
+
+    fn try_exit_status(&self) -> Option<ExitStatus> {
+        *self.exit_status.borrow()
+    }
+
+    fn exited(&self) -> impl Future<Output = Option<ExitStatus>> + Send + 'static {
+        let mut exit_status = self.exit_status.clone();
+        async move {
+            loop {
+                if let Some(status) = *exit_status.borrow() {
+                    return Some(status);
+                }
+                if exit_status.changed().await.is_err() {
+                    return *exit_status.borrow();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        // the child leads its session, which outlives it as long as anything is left in it
+        subreaper::signal_session(self.pid, self.hangup_signal);
+    }
+}
+
+/// A tokio command running `cmd` with its arguments in its working directory, if that exists
+fn tokio_command(cmd: &RunCommand) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new(&cmd.command);
+    command.args(&cmd.args);
+    if let Some(arg0) = cmd.arg0.as_ref() {
+        command.arg0(arg0);
+    }
+    if let Some(current_dir) = cmd.cwd.as_ref() {
+        if current_dir.exists() && current_dir.is_dir() {
+            command.current_dir(current_dir);
+        } else {
+            error!(
+                "Failed to set CWD for '{}'. '{}' does not exist or is not a folder",
+                cmd,
+                current_dir.display()
+            );
+        }
+    }
+    command
+}
+
+/// The command `command` of a session could not be started
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpawnFailed {
+    pub command: String,
+}
+
+impl fmt::Display for SpawnFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to execute '{}'", self.command)
+    }
+}
+
+impl std::error::Error for SpawnFailed {}
+
+/// A command running on its own pseudo terminal.
+///
+/// Output of the command is read through a [`PtyReader`] and input is written through a
+/// [`PtyWriter`]; both share the primary side of the pty, which is closed once the `Pty` and all
+/// of its handles are dropped. Dropping the `Pty` hangs up the child and makes sure it gets reaped,
+/// killing it if it doesn't exit within [`KILL_GRACE_PERIOD`].
+///
+/// With the `uring` feature its handles go through io_uring instead, if the kernel offers it.
+pub struct Pty {
+    primary: Arc<AsyncFd<OwnedFd>>,
+    reaper: Reaper,
+    #[cfg(feature = "uring")]
+    ring: Option<&'static uring::Ring>,
+}
+
+impl Pty {
+    /// Spawn `cmd` on a new pseudo terminal of the given size. Must be called from within a tokio
+    /// runtime.
+    pub fn spawn(
+        cmd: &RunCommand,
+        env: &Environment,
+        sandbox: &Sandbox,
+        size: PtySize,
+    ) -> Result<Pty> {
+        let err_context = || format!("failed to spawn '{}' on a new PTY", cmd);
+
+        // commands run with a root directory of their own can't be looked up from here
+        if !sandbox.changes_root() && !command_exists(cmd) {
+            METRICS.spawn_failures.inc();
+            return Err(anyhow!("no such command"))
+                .context(SpawnFailed { command: cmd.to_string() });
+        }
+        size.check().with_context(err_context)?;
+
+        let OpenptyResult { master, slave } =
+            openpty(Some(&Winsize::from(size)), None).with_context(err_context)?;
+        // SAFETY: openpty just handed us both descriptors and nothing else owns them
+        let (primary, secondary) =
+            unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+        for fd in [primary.as_raw_fd(), secondary.as_raw_fd()] {
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).with_context(err_context)?;
+        }
+        #[cfg(feature = "uring")]
+        let ring = uring::Ring::get();
+        #[cfg(not(feature = "uring"))]
+        let ring: Option<()> = None;
+        // the ring waits for the terminal in the kernel, nonblocking it would only get EAGAIN
+        if ring.is_none() {
+            fcntl(primary.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+                .with_context(err_context)?;
+        }
+
+        let mut command = tokio_command(cmd);
+        env.apply(&mut command);
+        let secondary_fd = secondary.as_raw_fd();
+        // the sandbox moves the command into its cgroup after this
+        let keep_fds: Vec<RawFd> = sandbox.cgroup_procs.into_iter().collect();
+        unsafe {
+            command.pre_exec(move || -> std::io::Result<()> {
+                if libc::login_tty(secondary_fd) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                close_fds::close_open_fds(3, &keep_fds);
+                Ok(())
+            });
+        }
+        sandbox.apply(&mut command, cmd.cwd.as_deref());
+        let child = subreaper::spawn(&mut command)
+            .inspect_err(|_| METRICS.spawn_failures.inc())
+            .context(SpawnFailed { command: cmd.to_string() })?;
+        // the child holds its own copy now, keeping ours open would stop reads from ever
+        // reporting the hangup
+        drop(secondary);
+
+        Ok(Pty {
+            primary: Arc::new(AsyncFd::new(primary).with_context(err_context)?),
+            reaper: Reaper::new(child, Signal::SIGHUP).with_context(err_context)?,
+            #[cfg(feature = "uring")]
+            ring,
+        })
+    }
+
+    /// Process id of the command running on the pty
+    pub fn pid(&self) -> Pid {
+        self.reaper.pid
+    }
+
+    /// A handle reading the output of the command. Reads report end of file once the command and
+    /// everything it left running closed the terminal.
+    pub fn reader(&self) -> PtyReader {
+        PtyReader {
+            #[cfg(feature = "uring")]
+            ring: self
+                .ring
+                .map(|ring| uring::Reads::new(ring, self.primary.clone())),
+            ..PtyReader::new(self.primary.clone())
+        }
+    }
+
+    /// A handle writing to the input of the command
+    pub fn writer(&self) -> PtyWriter {
+        PtyWriter {
+            #[cfg(feature = "uring")]
+            ring: self
+                .ring
+                .map(|ring| uring::Writes::new(ring, self.primary.clone())),
+            ..PtyWriter::new(self.primary.clone())
+        }
+    }
+
+    /// Change the window size of the pty and let the programs in its foreground know about it
+    pub fn resize(&self, size: PtySize) -> Result<()> {
+        let err_context = || format!("failed to resize pty of child {}", self.pid());
+
+        size.check().with_context(err_context)?;
+        let winsize = Winsize::from(size);
+        // TIOCSWINSZ is an u32, but the second argument to ioctl is u64 on
+        // some platforms. When checked on Linux, clippy will complain about
+        // useless conversion.
+        #[allow(clippy::useless_conversion)]
+        let ret = unsafe {
+            libc::ioctl(self.primary.as_raw_fd(), libc::TIOCSWINSZ.into(), &winsize)
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error()).with_context(err_context);
+        }
+        // the kernel only signals the foreground process group if the size actually changed,
+        // full-screen programs started in between may have missed the last one though
+        let result = match self.foreground_process_group() {
+            Some(foreground) => killpg(foreground, Signal::SIGWINCH),
+            None => kill(self.pid(), Signal::SIGWINCH),
+        };
+        result.with_context(err_context)
+    }
+
+    /// The current attributes of the pty, to be changed and applied with [`Pty::set_termios`]
+    pub fn termios(&self) -> Result<TermiosBuilder> {
+        termios::tcgetattr(self.primary.as_raw_fd())
+            .map(TermiosBuilder::new)
+            .with_context(|| format!("failed to get attributes of pty of child {}", self.pid()))
+    }
+
+    /// Apply `termios` to the pty right away. Attributes set on the primary side apply to the
+    /// terminal the command sees.
+    pub fn set_termios(&self, termios: &termios::Termios) -> Result<()> {
+        termios::tcsetattr(self.primary.as_raw_fd(), termios::SetArg::TCSANOW, termios)
+            .with_context(|| format!("failed to set attributes of pty of child {}", self.pid()))
+    }
+
+    /// Process group in the foreground of the pty, usually the job the shell is running or the
+    /// shell itself
+    pub fn foreground_process_group(&self) -> Option<Pid> {
+        unistd::tcgetpgrp(self.primary.as_raw_fd()).ok()
+    }
+
+    /// Path of the secondary side of the pty, the terminal of the command
+    pub fn tty(&self) -> Option<PathBuf> {
+        let mut name = [0; 64];
+        let fd = self.primary.as_raw_fd();
+        if unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) } != 0 {
+            return None;
+        }
+        let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+        Some(PathBuf::from(name.to_str().ok()?))
+    }
+
+    /// Exit status of the command if it already terminated
+    pub fn try_exit_status(&self) -> Option<ExitStatus> {
+        self.reaper.try_exit_status()
+    }
+
+    /// Wait for the command to terminate. Returns `None` if the child could not be reaped.
+    pub async fn wait(&self) -> Option<ExitStatus> {
+        self.exited().await
+    }
+
+    /// Like [`Pty::wait`], but the returned future doesn't borrow the `Pty` and keeps working
+    /// after it was dropped.
+    pub fn exited(&self) -> impl Future<Output = Option<ExitStatus>> + Send + 'static {
+        self.reaper.exited()
+    }
+}
+
+/// Reading half of a [`Terminal`]
+pub type TerminalReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Writing half of a [`Terminal`]
+pub type TerminalWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// A command running on a terminal, what terminal sessions drive. Commands run on a [`Pty`] of
+/// their own, unless the sessions are set up with a [`Spawn`] starting them on something else,
+/// such as the fake terminals of tests.
+pub trait Terminal: Send + Sync {
+    /// Process id of the command, which leads the process group of the terminal
+    fn pid(&self) -> Pid;
+
+    /// A handle reading the output of the command, see [`Pty::reader`]
+    fn reader(&self) -> TerminalReader;
+
+    /// A handle writing to the input of the command
+    fn writer(&self) -> TerminalWriter;
+
+    /// Change the window size of the terminal, see [`Pty::resize`]
+    fn resize(&self, size: PtySize) -> Result<()>;
+
+    /// The current attributes of the terminal
+    fn termios(&self) -> Result<TermiosBuilder>;
+
+    /// Apply `termios` to the terminal right away
+    fn set_termios(&self, termios: &termios::Termios) -> Result<()>;
+
+    /// Process group in the foreground of the terminal
+    fn foreground_process_group(&self) -> Option<Pid>;
+
+    /// Path of the terminal device, such as `/dev/pts/3`, `None` if it has none
+    fn tty(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Exit status of the command if it already terminated
+    fn try_exit_status(&self) -> Option<ExitStatus>;
+
+    /// Resolves to the exit status of the command once it terminated, `None` if it could not be
+    /// reaped. Keeps working after the terminal was dropped.
+    fn exited(&self) -> Pin<Box<dyn Future<Output = Option<ExitStatus>> + Send>>;
+}
+
+impl Terminal for Pty {
+    fn pid(&self) -> Pid {
+        Pty::pid(self)
+    }
+
+    fn reader(&self) -> TerminalReader {
+        Box::new(Pty::reader(self))
+    }
+
+    fn writer(&self) -> TerminalWriter {
+        Box::new(Pty::writer(self))
+    }
+
+    fn resize(&self, size: PtySize) -> Result<()> {
+        Pty::resize(self, size)
+    }
+
+    fn termios(&self) -> Result<TermiosBuilder> {
+        Pty::termios(self)
+    }
+
+    fn set_termios(&self, termios: &termios::Termios) -> Result<()> {
+        Pty::set_termios(self, termios)
+    }
+
+    fn foreground_process_group(&self) -> Option<Pid> {
+        Pty::foreground_process_group(self)
+    }
+
+    fn tty(&self) -> Option<PathBuf> {
+        Pty::tty(self)
+    }
+
+    fn try_exit_status(&self) -> Option<ExitStatus> {
+        Pty::try_exit_status(self)
+    }
+
+    fn exited(&self) -> Pin<Box<dyn Future<Output = Option<ExitStatus>> + Send>> {
+        Box::pin(Pty::exited(self))
+    }
+}
+
+/// Starts the commands of terminal sessions, see
+/// [`SessionRegistry::with_spawner`](crate::session::SessionRegistry::with_spawner)
+pub trait Spawn: Send + Sync {
+    /// Start `cmd` with `env`, confined by `sandbox`, on a terminal of `size`. Fails with
+    /// [`SpawnFailed`] in its context if the command can't be started.
+    fn spawn(
+        &self,
+        cmd: &RunCommand,
+        env: &Environment,
+        sandbox: &Sandbox,
+        size: PtySize,
+    ) -> Result<Box<dyn Terminal>>;
+}
+
+/// Spawns commands on a [`Pty`] of their own, what sessions do unless told otherwise
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PtySpawner;
+
+impl Spawn for PtySpawner {
+    fn spawn(
+        &self,
+        cmd: &RunCommand,
+        env: &Environment,
+        sandbox: &Sandbox,
+        size: PtySize,
+    ) -> Result<Box<dyn Terminal>> {
+        Ok(Box::new(Pty::spawn(cmd, env, sandbox, size)?))
+    }
+}
+
+/// A command running without a terminal: its stdin is `/dev/null` and its stdout and stderr are
+/// pipes, or a single pipe if they are merged. Dropping the `Exec` terminates the child and makes
+/// sure it gets reaped, killing it if it doesn't exit within [`KILL_GRACE_PERIOD`].
+pub struct Exec {
+    stdout: Option<ChildStdout>,
+    stderr: Option<ChildStderr>,
+    merged: Option<PtyReader>,
+    reaper: Reaper,
+}
+
+impl Exec {
+    /// Spawn `cmd` in environment `env`. With `merge_stderr`, stdout and stderr share a pipe, so
+    /// that what the command writes to both arrives in the order it was written. Must be called
+    /// from within a tokio runtime.
+    pub fn spawn(
+        cmd: &RunCommand,
+        env: &Environment,
+        sandbox: &Sandbox,
+        merge_stderr: bool,
+    ) -> Result<Exec> {
+        let err_context = || format!("failed to execute '{}'", cmd);
+
+        // commands run with a root directory of their own can't be looked up from here
+        if !sandbox.changes_root() && !command_exists(cmd) {
+            METRICS.spawn_failures.inc();
+            return Err(anyhow!("no such command"))
+                .context(SpawnFailed { command: cmd.to_string() });
+        }
+        let mut command = tokio_command(cmd);
+        env.apply(&mut command);
+        // a session of its own, like commands on a pty get, so that whatever it starts can be
+        // terminated together with it
+        unsafe {
+            command.pre_exec(|| unistd::setsid().map(drop).map_err(std::io::Error::from));
+        }
+        sandbox.apply(&mut command, cmd.cwd.as_deref());
+        command.stdin(Stdio::null());
+        let merged = match merge_stderr {
+            true => {
+                let (output, input) =
+                    unistd::pipe2(OFlag::O_CLOEXEC).with_context(err_context)?;
+                // SAFETY: pipe2 just handed us both descriptors and nothing else owns them
+                let (output, input) =
+                    unsafe { (OwnedFd::from_raw_fd(output), OwnedFd::from_raw_fd(input)) };
+                fcntl(output.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+                    .with_context(err_context)?;
+                command.stdout(input.try_clone().with_context(err_context)?);
+                command.stderr(input);
+                Some(output)
+            },
+            false => {
+                command.stdout(Stdio::piped()).stderr(Stdio::piped());
+                None
+            },
+        };
+        let mut child = subreaper::spawn(&mut command)
+            .inspect_err(|_| METRICS.spawn_failures.inc())
+            .context(SpawnFailed { command: cmd.to_string() })?;
+        // the child holds its own copies of the pipe now, ours would keep reads from ever
+        // reporting end of file
+        drop(command);
+        let merged = match merged {
+            Some(output) => Some(PtyReader::new(Arc::new(
+                AsyncFd::new(output).with_context(err_context)?,
+            ))),
+            None => None,
+        };
+        Ok(Exec {
+            stdout: child.stdout.take(),
+            stderr: child.stderr.take(),
+            merged,
+            reaper: Reaper::new(child, Signal::SIGTERM).with_context(err_context)?,
+        })
+    }
+
+    /// Process id of the command
+    pub fn pid(&self) -> Pid {
+        self.reaper.pid
+    }
+
+    /// The stdout pipe of the command, can only be taken once
+    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+        self.stdout.take()
+    }
+
+    /// The stderr pipe of the command, can only be taken once
+    pub fn take_stderr(&mut self) -> Option<ChildStderr> {
+        self.stderr.take()
+    }
+
+    /// The pipe stdout and stderr of the command share if they are merged, can only be taken once
+    pub fn take_merged(&mut self) -> Option<PtyReader> {
+        self.merged.take()
+    }
+
+    /// Exit status of the command if it already terminated
+    pub fn try_exit_status(&self) -> Option<ExitStatus> {
+        self.reaper.try_exit_status()
+    }
+
+    /// A future resolving to the exit status of the command, see [`Pty::exited`]
+    pub fn exited(&self) -> impl Future<Output = Option<ExitStatus>> + Send + 'static {
+        self.reaper.exited()
+    }
+}
+
+/// A serial device, configured for the line settings it was opened with. Input and output go
+/// through the same [`PtyReader`] and [`PtyWriter`] as those of a [`Pty`], and the device is
+/// closed once the `Serial` and all of its handles are dropped.
+pub struct Serial {
+    device: Arc<AsyncFd<OwnedFd>>,
+}
+
+impl Serial {
+    /// Open `path` for exclusive use with `settings`. Must be called from within a tokio runtime.
+    pub fn open(path: &Path, settings: &SerialSettings) -> Result<Serial> {
+        let err_context = || format!("failed to open serial device '{}'", path.display());
+
+        let fd = fcntl::open(
+            path,
+            OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .with_context(err_context)?;
+        // SAFETY: open just handed us the descriptor and nothing else owns it
+        let device = unsafe { OwnedFd::from_raw_fd(fd) };
+        // other programs opening the device would steal part of its input
+        if unsafe { libc::ioctl(device.as_raw_fd(), libc::TIOCEXCL) } != 0 {
+            return Err(std::io::Error::last_os_error()).with_context(err_context);
+        }
+        let mut termios = termios::tcgetattr(device.as_raw_fd()).with_context(err_context)?;
+        configure_serial(&mut termios, settings).with_context(err_context)?;
+        termios::tcsetattr(device.as_raw_fd(), termios::SetArg::TCSANOW, &termios)
+            .with_context(err_context)?;
+        Ok(Serial {
+            device: Arc::new(AsyncFd::new(device).with_context(err_context)?),
+        })
+    }
+
+    /// A handle reading what the device receives. Reads report end of file once the device is
+    /// gone, e.g. when a USB adapter is unplugged.
+    pub fn reader(&self) -> PtyReader {
+        PtyReader::new(self.device.clone())
+    }
+
+    /// A handle writing to the device
+    pub fn writer(&self) -> PtyWriter {
+        PtyWriter::new(self.device.clone())
+    }
+}
+
+/// A connected socket carrying the terminal of a command running elsewhere, such as in a
+/// container. Like a [`Serial`] device it is read and written through a [`PtyReader`] and
+/// [`PtyWriter`].
+pub struct Socket {
+    stream: Arc<AsyncFd<OwnedFd>>,
+}
+
+impl Socket {
+    /// Take over `stream`. Must be called from within a tokio runtime.
+    pub fn new(stream: std::os::unix::net::UnixStream) -> Result<Socket> {
+        let err_context = || "failed to set up socket";
+
+        stream.set_nonblocking(true).with_context(err_context)?;
+        Ok(Socket {
+            stream: Arc::new(AsyncFd::new(OwnedFd::from(stream)).with_context(err_context)?),
+        })
+    }
+
+    /// A handle reading from the socket, reads report end of file once the peer closed it
+    pub fn reader(&self) -> PtyReader {
+        PtyReader::new(self.stream.clone())
+    }
+
+    /// A handle writing to the socket
+    pub fn writer(&self) -> PtyWriter {
+        PtyWriter::new(self.stream.clone())
+    }
+
+    /// Close both directions, ending reads of the handles still around
+    pub fn shutdown(&self) {
+        let _ = socket::shutdown(self.stream.as_raw_fd(), socket::Shutdown::Both);
+    }
+}
+
+/// Make `termios` pass bytes through as is with the line settings of `settings`
+fn configure_serial(termios: &mut termios::Termios, settings: &SerialSettings) -> Result<()> {
+    use termios::{ControlFlags, InputFlags, SpecialCharacterIndices};
+
+    termios::cfmakeraw(termios);
+    termios::cfsetspeed(termios, baud_rate(settings.baud_rate)?)?;
+    let flags = &mut termios.control_flags;
+    flags.remove(
+        ControlFlags::CSIZE
+            | ControlFlags::PARENB
+            | ControlFlags::PARODD
+            | ControlFlags::CSTOPB
+            | ControlFlags::CRTSCTS,
+    );
+    // no modem control lines to wait for, and receiving enabled
+    flags.insert(ControlFlags::CLOCAL | ControlFlags::CREAD);
+    flags.insert(match settings.data_bits {
+        5 => ControlFlags::CS5,
+        6 => ControlFlags::CS6,
+        7 => ControlFlags::CS7,
+        8 => ControlFlags::CS8,
+        bits => return Err(anyhow!("unsupported number of data bits {}", bits)),
+    });
+    match settings.parity {
+        Parity::None => {},
+        Parity::Even => flags.insert(ControlFlags::PARENB),
+        Parity::Odd => flags.insert(ControlFlags::PARENB | ControlFlags::PARODD),
+    }
+    match settings.stop_bits {
+        1 => {},
+        2 => flags.insert(ControlFlags::CSTOPB),
+        bits => return Err(anyhow!("unsupported number of stop bits {}", bits)),
+    }
+    termios.input_flags.set(InputFlags::INPCK, settings.parity != Parity::None);
+    termios.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+    termios.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+    Ok(())
+}
+
+/// The termios constant for `rate` bits per second
+fn baud_rate(rate: u32) -> Result<termios::BaudRate> {
+    use termios::BaudRate;
+
+    Ok(match rate {
+        50 => BaudRate::B50,
+        75 => BaudRate::B75,
+        110 => BaudRate::B110,
+        134 => BaudRate::B134,
+        150 => BaudRate::B150,
+        200 => BaudRate::B200,
+        300 => BaudRate::B300,
+        600 => BaudRate::B600,
+        1200 => BaudRate::B1200,
+        1800 => BaudRate::B1800,
+        2400 => BaudRate::B2400,
+        4800 => BaudRate::B4800,
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115200 => BaudRate::B115200,
+        230400 => BaudRate::B230400,
+        #[cfg(target_os = "linux")]
+        460800 => BaudRate::B460800,
+        #[cfg(target_os = "linux")]
+        500000 => BaudRate::B500000,
+        #[cfg(target_os = "linux")]
+        576000 => BaudRate::B576000,
+        #[cfg(target_os = "linux")]
+        921600 => BaudRate::B921600,
+        #[cfg(target_os = "linux")]
+        1000000 => BaudRate::B1000000,
+        #[cfg(target_os = "linux")]
+        1500000 => BaudRate::B1500000,
+        #[cfg(target_os = "linux")]
+        2000000 => BaudRate::B2000000,
+        #[cfg(target_os = "linux")]
+        3000000 => BaudRate::B3000000,
+        #[cfg(target_os = "linux")]
+        4000000 => BaudRate::B4000000,
+        _ => return Err(anyhow!("unsupported baud rate {}", rate)),
+    })
+}
+
+/// Reading half of a [`Pty`], [`Serial`] device or [`Socket`], also what reads the merged output of
+/// an [`Exec`]
+pub struct PtyReader {
+    primary: Arc<AsyncFd<OwnedFd>>,
+    /// Reads through io_uring instead, for terminals set up for it
+    #[cfg(feature = "uring")]
+    ring: Option<uring::Reads>,
+}
+
+impl PtyReader {
+    fn new(primary: Arc<AsyncFd<OwnedFd>>) -> Self {
+        PtyReader {
+            primary,
+            #[cfg(feature = "uring")]
+            ring: None,
+        }
+    }
+}
+
+impl AsyncRead for PtyReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        #[cfg(feature = "uring")]
+        if let Some(reads) = this.ring.as_mut() {
+            return reads.poll_read(cx, buf);
+        }
+        loop {
+            let mut guard = ready!(this.primary.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|fd| {
+                unistd::read(fd.as_raw_fd(), unfilled).map_err(std::io::Error::from)
+            }) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                },
+                // reading the primary side fails with EIO once the secondary side is closed
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => return Poll::Ready(Ok(())),
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Writing half of a [`Pty`], [`Serial`] device or [`Socket`]
+pub struct PtyWriter {
+    primary: Arc<AsyncFd<OwnedFd>>,
+    /// Writes through io_uring instead, for terminals set up for it
+    #[cfg(feature = "uring")]
+    ring: Option<uring::Writes>,
+}
+
+impl PtyWriter {
+    fn new(primary: Arc<AsyncFd<OwnedFd>>) -> Self {
+        PtyWriter {
+            primary,
+            #[cfg(feature = "uring")]
+            ring: None,
+        }
+    }
+}
+
+impl AsyncWrite for PtyWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        #[cfg(feature = "uring")]
+        if let Some(writes) = this.ring.as_mut() {
+            return writes.poll_write(cx, buf);
+        }
+        loop {
+            let mut guard = ready!(this.primary.poll_write_ready(cx))?;
+            match guard
+                .try_io(|fd| unistd::write(fd.as_raw_fd(), buf).map_err(std::io::Error::from))
+            {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        #[cfg(feature = "uring")]
+        if let Some(writes) = self.get_mut().ring.as_mut() {
+            return writes.poll_flush(_cx);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// What a [`LineEditor`] made of some input
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct EditedInput {
+    /// To be shown to the user right away
+    pub echo: Vec<u8>,
+    /// Complete lines and control characters, to be passed on to the program
+    pub input: Vec<u8>,
+}
+
+/// Where a [`LineEditor`] is within the escape sequence a key sent
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum KeySequence {
+    #[default]
+    None,
+    /// Right after ESC
+    Escape,
+    /// Within `ESC [` or `ESC O`, until the final byte
+    Parameters,
+    /// Within pasted text, `held` bytes into what may be the [`PASTE_END`]
+    Paste { held: usize },
+}
+
+/// What terminals send before pasted text once a program turned on bracketed paste
+const PASTE_START: &[u8] = b"\x1b[200~";
+
+/// What terminals send after pasted text
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// Echoes and edits input a line at a time, like the canonical mode of a terminal does, but on
+/// the server's end of a slow link.
+///
+/// Text is echoed as typed, backspace erases a character, ^W a word and ^U the whole line. CR or
+/// LF end the line, which is then passed on with the byte that ended it. ^C, ^\ and ^Z drop the
+/// line and are passed on, like any other control character; ^D passes on the line without ending
+/// it, or itself on an empty line. Keys sending escape sequences, like the arrow keys, are ignored.
+///
+/// Text pasted between the brackets of bracketed paste is passed on as is, brackets included and
+/// right after the line typed so far, so that programs asking for it see what was pasted.
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    line: Vec<u8>,
+    key: KeySequence,
+    /// The escape sequence of the key being sent so far, without the ESC
+    sequence: Vec<u8>,
+    /// Whether the last byte was a CR, the LF of a CRLF following it doesn't end another line
+    after_cr: bool,
+}
+
+impl LineEditor {
+    /// Edit the line with `data`, which may stop anywhere, even within a character
+    pub fn input(&mut self, data: &[u8]) -> EditedInput {
+        let mut edited = EditedInput::default();
+        for &byte in data {
+            let after_cr = std::mem::replace(&mut self.after_cr, byte == b'\r');
+            match (self.key, byte) {
+                (KeySequence::Paste { held }, byte) => {
+                    edited.input.push(byte);
+                    self.paste(held, byte, after_cr, &mut edited.echo);
+                },
+                (KeySequence::Escape, b'[' | b'O') => {
+                    self.key = KeySequence::Parameters;
+                    self.sequence = vec![byte];
+                },
+                (KeySequence::Parameters, 0x20..=0x3f) => self.sequence.push(byte),
+                (KeySequence::Parameters, b'~') if self.sequence == PASTE_START[1..5] => {
+                    self.key = KeySequence::Paste { held: 0 };
+                    edited.input.append(&mut self.line);
+                    edited.input.extend_from_slice(PASTE_START);
+                },
+                (KeySequence::Escape | KeySequence::Parameters, _) => {
+                    self.key = KeySequence::None
+                },
+                (KeySequence::None, 0x1b) => self.key = KeySequence::Escape,
+                (KeySequence::None, b'\n') if after_cr => {},
+                (KeySequence::None, b'\r' | b'\n') => {
+                    edited.input.append(&mut self.line);
+                    edited.input.push(byte);
+                    edited.echo.extend_from_slice(b"\r\n");
+                },
+                (KeySequence::None, 0x7f | 0x08) => {
+                    self.erase(1, &mut edited.echo);
+                },
+                // ^W
+                (KeySequence::None, 0x17) => {
+                    let spaces = self.line.iter().rev().take_while(|b| **b == b' ').count();
+                    let word = self.line[..self.line.len() - spaces]
+                        .iter()
+                        .rev()
+                        .take_while(|b| **b != b' ')
+                        .filter(|b| !is_continuation(**b))
+                        .count();
+                    self.erase(spaces + word, &mut edited.echo);
+                },
+                // ^U
+                (KeySequence::None, 0x15) => {
+                    let chars = self.line.iter().filter(|b| !is_continuation(**b)).count();
+                    self.erase(chars, &mut edited.echo);
+                },
+                // ^D
+                (KeySequence::None, 0x04) if !self.line.is_empty() => {
+                    edited.input.append(&mut self.line);
+                },
+                // ^C, ^\ and ^Z
+                (KeySequence::None, 0x03 | 0x1c | 0x1a) => {
+                    self.line.clear();
+                    edited.echo.extend_from_slice(&[b'^', byte + 0x40, b'\r', b'\n']);
+                    edited.input.push(byte);
+                },
+                (KeySequence::None, byte) if byte < 0x20 && byte != b'\t' => {
+                    edited.input.push(byte)
+                },
+                (KeySequence::None, byte) => {
+                    self.line.push(byte);
+                    edited.echo.push(byte);
+                },
+            }
+        }
+        edited
+    }
+
+    /// Echo `byte` of pasted text, except for what may turn out to be the end of the paste, after
+    /// `held` bytes of it were held back
+    fn paste(&mut self, held: usize, byte: u8, after_cr: bool, echo: &mut Vec<u8>) {
+        if byte == PASTE_END[held] {
+            self.key = match held + 1 == PASTE_END.len() {
+                true => KeySequence::None,
+                false => KeySequence::Paste { held: held + 1 },
+            };
+            return;
+        }
+        // it wasn't, the ESC that started it shows as little as others pasted
+        echo.extend_from_slice(&PASTE_END[1..held.max(1)]);
+        if byte == PASTE_END[0] {
+            self.key = KeySequence::Paste { held: 1 };
+            return;
+        }
+        self.key = KeySequence::Paste { held: 0 };
+        match byte {
+            b'\n' if after_cr => {},
+            b'\r' | b'\n' => echo.extend_from_slice(b"\r\n"),
+            b'\t' => echo.push(byte),
+            byte if byte < 0x20 || byte == 0x7f => {},
+            byte => echo.push(byte),
+        }
+    }
+
+    /// Remove the last `chars` characters of the line, also from the screen
+    fn erase(&mut self, chars: usize, echo: &mut Vec<u8>) {
+        for _ in 0..chars {
+            while self.line.last().is_some_and(|b| is_continuation(*b)) {
+                self.line.pop();
+            }
+            if self.line.pop().is_none() {
+                return;
+            }
+            echo.extend_from_slice(b"\x08 \x08");
+        }
+    }
+}
+
+/// Whether `byte` continues a UTF-8 sequence rather than starting a character
+fn is_continuation(byte: u8) -> bool {
+    byte & 0b1100_0000 == 0b1000_0000
+}
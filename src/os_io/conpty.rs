@@ -0,0 +1,395 @@
+//! Pseudo consoles on Windows. A [`ConPty`] runs a console program such as `cmd.exe` or
+//! PowerShell attached to a pseudo console instead of a window, which renders the console into VT
+//! sequences on a pipe just like a [`Pty`](super::Pty) does on Unix. Input written to the other
+//! pipe is turned into key events for the program.
+//!
+//! Unlike on Unix, reading the output doesn't see end of file when the program exits: the pseudo
+//! console keeps its end of the pipe open until it is closed. That is done as soon as the program
+//! is reaped, so readers still see end of file once all output is read.
+use super::PtySize;
+use crate::{
+    command::{Environment, RunCommand},
+    error::ToAnyhow,
+    metrics::METRICS,
+};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsStr,
+    fs::File,
+    future::Future,
+    iter, mem,
+    os::windows::{
+        ffi::OsStrExt,
+        io::{AsRawHandle, FromRawHandle, OwnedHandle, RawHandle},
+        process::ExitStatusExt,
+    },
+    process::ExitStatus,
+    ptr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+use tracing::warn;
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, HANDLE, S_OK, WAIT_OBJECT_0},
+    System::{
+        Console::{ClosePseudoConsole, CreatePseudoConsole, ResizePseudoConsole, COORD, HPCON},
+        Pipes::CreatePipe,
+        Threading::{
+            CreateProcessW, DeleteProcThreadAttributeList, GetExitCodeProcess,
+            InitializeProcThreadAttributeList, UpdateProcThreadAttribute, WaitForSingleObject,
+            CREATE_UNICODE_ENVIRONMENT, EXTENDED_STARTUPINFO_PRESENT, INFINITE,
+            PROCESS_INFORMATION, PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE, STARTUPINFOEXW,
+        },
+    },
+};
+
+/// Output of a [`ConPty`], reads block a thread of tokio's blocking pool
+pub type ConPtyReader = tokio::fs::File;
+/// Input of a [`ConPty`], writes block a thread of tokio's blocking pool
+pub type ConPtyWriter = tokio::fs::File;
+
+/// A pseudo console, closed when dropped
+struct PseudoConsole(HPCON);
+
+// SAFETY: a pseudo console handle may be used from any thread
+unsafe impl Send for PseudoConsole {}
+
+impl Drop for PseudoConsole {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from CreatePseudoConsole and is closed only here
+        unsafe { ClosePseudoConsole(self.0) };
+    }
+}
+
+/// A console program running on its own pseudo console, the Windows counterpart of a
+/// [`Pty`](super::Pty).
+///
+/// Dropping the `ConPty` closes the pseudo console, which ends the programs attached to it like a
+/// hangup does on Unix.
+pub struct ConPty {
+    /// Taken once the program exited, see the module docs
+    console: Arc<Mutex<Option<PseudoConsole>>>,
+    output: File,
+    input: File,
+    pid: u32,
+    exit_status: watch::Receiver<Option<ExitStatus>>,
+}
+
+impl ConPty {
+    /// Spawn `cmd` on a new pseudo console of the given size. Must be called from within a tokio
+    /// runtime.
+    pub fn spawn(cmd: &RunCommand, env: &Environment, size: PtySize) -> Result<ConPty> {
+        let err_context = || format!("failed to spawn '{}' on a new pseudo console", cmd);
+
+        size.check().with_context(err_context)?;
+        let (input_read, input) = pipe().with_context(err_context)?;
+        let (output, output_write) = pipe().with_context(err_context)?;
+        let mut console = 0;
+        // SAFETY: both pipe ends are valid, the pseudo console duplicates what it keeps
+        let result = unsafe {
+            CreatePseudoConsole(
+                coord(size),
+                input_read.as_raw_handle() as HANDLE,
+                output_write.as_raw_handle() as HANDLE,
+                0,
+                &mut console,
+            )
+        };
+        if result != S_OK {
+            return Err(std::io::Error::from_raw_os_error(result)).with_context(err_context);
+        }
+        let console = PseudoConsole(console);
+        // the pseudo console holds its own copies now
+        drop((input_read, output_write));
+
+        let process = create_process(cmd, env, &console)
+            .inspect_err(|_| METRICS.spawn_failures.inc())
+            .with_context(err_context)?;
+        let pid = process.1;
+        let console = Arc::new(Mutex::new(Some(console)));
+        let (exit_status_tx, exit_status) = watch::channel(None);
+        let reaped_console = console.clone();
+        tokio::task::spawn_blocking(move || {
+            let status = wait(&process.0);
+            if status.is_none() {
+                warn!("failed to wait for child {}", pid);
+            }
+            let _ = exit_status_tx.send(status);
+            // lets readers see end of file once the rest of the output is read
+            if let Ok(mut console) = reaped_console.lock() {
+                console.take();
+            }
+        });
+
+        Ok(ConPty {
+            console,
+            output: File::from(output),
+            input: File::from(input),
+            pid,
+            exit_status,
+        })
+    }
+
+    /// Process id of the program running on the pseudo console
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// A handle reading the output of the program
+    pub fn reader(&self) -> Result<ConPtyReader> {
+        let output = self
+            .output
+            .try_clone()
+            .context("failed to duplicate pseudo console output")?;
+        Ok(tokio::fs::File::from_std(output))
+    }
+
+    /// A handle writing to the input of the program
+    pub fn writer(&self) -> Result<ConPtyWriter> {
+        let input = self
+            .input
+            .try_clone()
+            .context("failed to duplicate pseudo console input")?;
+        Ok(tokio::fs::File::from_std(input))
+    }
+
+    /// Change the size of the pseudo console, which tells the program about it
+    pub fn resize(&self, size: PtySize) -> Result<()> {
+        let err_context = || format!("failed to resize pseudo console of child {}", self.pid);
+
+        size.check().with_context(err_context)?;
+        let console = self.console.lock().to_anyhow().with_context(err_context)?;
+        let console = console
+            .as_ref()
+            .ok_or_else(|| anyhow!("child exited"))
+            .with_context(err_context)?;
+        // SAFETY: the pseudo console stays open while we hold the lock
+        let result = unsafe { ResizePseudoConsole(console.0, coord(size)) };
+        if result != S_OK {
+            return Err(std::io::Error::from_raw_os_error(result)).with_context(err_context);
+        }
+        Ok(())
+    }
+
+    /// Exit status of the program if it already terminated
+    pub fn try_exit_status(&self) -> Option<ExitStatus> {
+        *self.exit_status.borrow()
+    }
+
+    /// Wait for the program to terminate. Returns `None` if it could not be waited for.
+    pub async fn wait(&self) -> Option<ExitStatus> {
+        self.exited().await
+    }
+
+    /// Like [`ConPty::wait`], but the returned future doesn't borrow the `ConPty` and keeps
+    /// working after it was dropped.
+    pub fn exited(&self) -> impl Future<Output = Option<ExitStatus>> + Send + 'static {
+        let mut exit_status = self.exit_status.clone();
+        async move {
+            match exit_status.wait_for(Option::is_some).await {
+                Ok(status) => *status,
+                Err(_) => None,
+            }
+        }
+    }
+}
+
+impl Drop for ConPty {
+    fn drop(&mut self) {
+        if let Ok(mut console) = self.console.lock() {
+            console.take();
+        }
+    }
+}
+
+fn coord(size: PtySize) -> COORD {
+    // pseudo consoles can't be larger anyway
+    let clamp = |n: u16| n.min(i16::MAX as u16) as i16;
+    COORD {
+        X: clamp(size.cols),
+        Y: clamp(size.rows),
+    }
+}
+
+/// An anonymous pipe, reading end first
+fn pipe() -> Result<(OwnedHandle, OwnedHandle)> {
+    let (mut read, mut write) = (0, 0);
+    // SAFETY: both handles are written by CreatePipe and owned by us afterwards
+    unsafe {
+        if CreatePipe(&mut read, &mut write, ptr::null(), 0) == 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to create pipe");
+        }
+        Ok((
+            OwnedHandle::from_raw_handle(read as RawHandle),
+            OwnedHandle::from_raw_handle(write as RawHandle),
+        ))
+    }
+}
+
+/// Start `cmd` attached to `console`, returning its process handle and id
+fn create_process(
+    cmd: &RunCommand,
+    env: &Environment,
+    console: &PseudoConsole,
+) -> Result<(OwnedHandle, u32)> {
+    let mut command_line = command_line(cmd);
+    let environment = environment_block(env);
+    let cwd = cmd.cwd.as_ref().map(|cwd| wide(cwd.as_os_str()));
+
+    // SAFETY: the attribute list is sized as Windows asks for and lives until the process is
+    // created, all strings are NUL-terminated
+    unsafe {
+        let mut attributes_size = 0;
+        InitializeProcThreadAttributeList(ptr::null_mut(), 1, 0, &mut attributes_size);
+        let mut attributes = vec![0u8; attributes_size];
+        let attribute_list = attributes.as_mut_ptr().cast();
+        if InitializeProcThreadAttributeList(attribute_list, 1, 0, &mut attributes_size) == 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("failed to initialize process attributes");
+        }
+        let result = (|| {
+            if UpdateProcThreadAttribute(
+                attribute_list,
+                0,
+                PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE as usize,
+                console.0 as *const _,
+                mem::size_of::<HPCON>(),
+                ptr::null_mut(),
+                ptr::null(),
+            ) == 0
+            {
+                return Err(std::io::Error::last_os_error())
+                    .context("failed to attach the pseudo console");
+            }
+            let mut startup_info: STARTUPINFOEXW = mem::zeroed();
+            startup_info.StartupInfo.cb = mem::size_of::<STARTUPINFOEXW>() as u32;
+            startup_info.lpAttributeList = attribute_list;
+            let mut process_info: PROCESS_INFORMATION = mem::zeroed();
+            if CreateProcessW(
+                ptr::null(),
+                command_line.as_mut_ptr(),
+                ptr::null(),
+                ptr::null(),
+                0,
+                EXTENDED_STARTUPINFO_PRESENT | CREATE_UNICODE_ENVIRONMENT,
+                environment.as_ptr().cast(),
+                cwd.as_ref().map_or(ptr::null(), |cwd| cwd.as_ptr()),
+                &startup_info.StartupInfo,
+                &mut process_info,
+            ) == 0
+            {
+                return Err(std::io::Error::last_os_error()).context("failed to create process");
+            }
+            CloseHandle(process_info.hThread);
+            Ok((
+                OwnedHandle::from_raw_handle(process_info.hProcess as RawHandle),
+                process_info.dwProcessId,
+            ))
+        })();
+        DeleteProcThreadAttributeList(attribute_list);
+        result
+    }
+}
+
+/// Block until `process` exits
+fn wait(process: &OwnedHandle) -> Option<ExitStatus> {
+    let handle = process.as_raw_handle() as HANDLE;
+    let mut code = 0;
+    // SAFETY: the handle stays open for the duration of both calls
+    unsafe {
+        if WaitForSingleObject(handle, INFINITE) != WAIT_OBJECT_0 {
+            return None;
+        }
+        if GetExitCodeProcess(handle, &mut code) == 0 {
+            return None;
+        }
+    }
+    Some(ExitStatus::from_raw(code))
+}
+
+/// `s` as a NUL-terminated UTF-16 string
+fn wide(s: &OsStr) -> Vec<u16> {
+    s.encode_wide().chain(Some(0)).collect()
+}
+
+/// The command line of `cmd`, quoted the way the C runtime splits it again
+fn command_line(cmd: &RunCommand) -> Vec<u16> {
+    let mut line = vec![];
+    let words = Some(cmd.command.as_os_str().to_os_string())
+        .into_iter()
+        .chain(cmd.args.iter().map(Into::into));
+    for (i, word) in words.enumerate() {
+        if i > 0 {
+            line.push(b' ' as u16);
+        }
+        quote(&word, &mut line);
+    }
+    line.push(0);
+    line
+}
+
+/// Append `word` to `line`, in quotes if it has to be
+fn quote(word: &OsStr, line: &mut Vec<u16>) {
+    let (quote, backslash) = (b'"' as u16, b'\\' as u16);
+    let needs_quotes = word.is_empty()
+        || word
+            .encode_wide()
+            .any(|c| c == b' ' as u16 || c == b'\t' as u16 || c == quote);
+    if !needs_quotes {
+        line.extend(word.encode_wide());
+        return;
+    }
+    line.push(quote);
+    let mut backslashes = 0;
+    for c in word.encode_wide() {
+        match c {
+            c if c == backslash => backslashes += 1,
+            // backslashes only escape when followed by a quote
+            c if c == quote => {
+                line.extend(iter::repeat_n(backslash, backslashes * 2 + 1));
+                line.push(quote);
+                backslashes = 0;
+            },
+            c => {
+                line.extend(iter::repeat_n(backslash, backslashes));
+                line.push(c);
+                backslashes = 0;
+            },
+        }
+    }
+    // ... including the closing one
+    line.extend(iter::repeat_n(backslash, backslashes * 2));
+    line.push(quote);
+}
+
+/// The environment block for a program the server runs with `env`
+fn environment_block(env: &Environment) -> Vec<u16> {
+    // names are case insensitive on Windows
+    let mut vars: BTreeMap<String, (String, String)> = BTreeMap::new();
+    if !env.clear {
+        for (name, value) in env::vars_os() {
+            let (name, value) = (name.to_string_lossy(), value.to_string_lossy());
+            vars.insert(name.to_uppercase(), (name.into(), value.into()));
+        }
+    }
+    for name in env.strip.iter() {
+        vars.remove(&name.to_uppercase());
+    }
+    for (name, value) in env.vars.iter() {
+        vars.insert(name.to_uppercase(), (name.clone(), value.clone()));
+    }
+
+    let mut block = vec![];
+    for (name, value) in vars.values() {
+        block.extend(OsStr::new(&format!("{}={}", name, value)).encode_wide());
+        block.push(0);
+    }
+    // an empty block still needs both terminators
+    if block.is_empty() {
+        block.push(0);
+    }
+    block.push(0);
+    block
+}
@@ -0,0 +1,332 @@
+//! Terminal I/O through io_uring. One ring on a thread of its own serves the terminals of all
+//! sessions: reads and writes are queued to it and it hands back what came of them, sparing the
+//! readiness checks and the extra system calls of going through epoll for every read.
+//!
+//! The ring is set up when the first terminal is opened. Kernels without io_uring, or with it
+//! turned off, leave terminals to epoll.
+use anyhow::{Context, Result};
+use io_uring::{opcode, squeue, types, IoUring};
+use nix::{
+    sys::eventfd::{eventfd, EfdFlags},
+    unistd,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io,
+    ops::Range,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, OnceLock,
+    },
+    task::{ready, Context as TaskContext, Poll},
+    thread,
+};
+use tokio::{io::ReadBuf, sync::oneshot};
+use tracing::{error, warn};
+
+/// Operations submitted at once at most, more wait for the next round
+const ENTRIES: u32 = 256;
+
+/// Bytes read at once at most
+const READ_SIZE: usize = 65536;
+
+/// What completions of the read of the wakeup counter and of cancellations are told apart by
+const WAKE: u64 = u64::MAX;
+const CANCEL: u64 = u64::MAX - 1;
+
+/// A descriptor kept open until the kernel is done with it
+pub(super) type Fd = Arc<dyn AsRawFd + Send + Sync>;
+
+/// What a read or write came to, handing back its buffer
+type Completion = (io::Result<usize>, Vec<u8>);
+
+/// A read into or write of `buf`
+struct Op {
+    fd: Fd,
+    buf: Vec<u8>,
+    write: bool,
+    done: oneshot::Sender<Completion>,
+}
+
+enum Request {
+    Submit(u64, Op),
+    Cancel(u64),
+}
+
+/// The ring shared by all terminals and the thread driving it
+pub(super) struct Ring {
+    requests: mpsc::Sender<Request>,
+    /// Counter wakes the driver up for new requests
+    wake: OwnedFd,
+    next_id: AtomicU64,
+}
+
+impl Ring {
+    /// The ring, `None` if the kernel doesn't offer io_uring
+    pub(super) fn get() -> Option<&'static Ring> {
+        static RING: OnceLock<Option<Ring>> = OnceLock::new();
+        RING.get_or_init(|| {
+            Ring::start()
+                .inspect_err(|e| warn!("terminals go through epoll, {:#}", e))
+                .ok()
+        })
+        .as_ref()
+    }
+
+    fn start() -> Result<Ring> {
+        let err_context = || "failed to set up io_uring";
+
+        let ring = IoUring::new(ENTRIES).with_context(err_context)?;
+        let wake = eventfd(0, EfdFlags::EFD_CLOEXEC).with_context(err_context)?;
+        // SAFETY: eventfd just handed us the descriptor and nothing else owns it
+        let wake = unsafe { OwnedFd::from_raw_fd(wake) };
+        let woken = wake.try_clone().with_context(err_context)?;
+        let (requests, received) = mpsc::channel();
+        thread::Builder::new()
+            .name("io_uring".to_string())
+            .spawn(move || {
+                if let Err(e) = drive(ring, woken, received) {
+                    error!("io_uring stopped: {}", e);
+                }
+            })
+            .with_context(err_context)?;
+        Ok(Ring {
+            requests,
+            wake,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    fn submit(&self, op: Op) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.send(Request::Submit(id, op));
+        id
+    }
+
+    fn cancel(&self, id: u64) {
+        self.send(Request::Cancel(id));
+    }
+
+    fn send(&self, request: Request) {
+        // without the driver the operation is dropped, which tells whoever waits for it
+        if self.requests.send(request).is_ok() {
+            let _ = unistd::write(self.wake.as_raw_fd(), &1u64.to_ne_bytes());
+        }
+    }
+}
+
+/// Submit what is requested and hand back what completed until the ring fails
+fn drive(mut ring: IoUring, wake: OwnedFd, requests: mpsc::Receiver<Request>) -> io::Result<()> {
+    let mut ops = HashMap::new();
+    // entries that didn't fit into the submission queue
+    let mut queued = VecDeque::new();
+    let mut counter = [0u8; 8];
+    let mut waiting = false;
+    loop {
+        if !waiting {
+            let entry = opcode::Read::new(
+                types::Fd(wake.as_raw_fd()),
+                counter.as_mut_ptr(),
+                counter.len() as u32,
+            );
+            queued.push_front(entry.build().user_data(WAKE));
+            waiting = true;
+        }
+        while let Ok(request) = requests.try_recv() {
+            match request {
+                Request::Submit(id, mut op) => {
+                    let fd = types::Fd(op.fd.as_raw_fd());
+                    let len = op.buf.len() as u32;
+                    let entry: squeue::Entry = match op.write {
+                        true => opcode::Write::new(fd, op.buf.as_ptr(), len)
+                            .offset(u64::MAX)
+                            .build(),
+                        false => opcode::Read::new(fd, op.buf.as_mut_ptr(), len)
+                            .offset(u64::MAX)
+                            .build(),
+                    };
+                    queued.push_back(entry.user_data(id));
+                    ops.insert(id, op);
+                },
+                Request::Cancel(id) if ops.contains_key(&id) => {
+                    queued.push_back(opcode::AsyncCancel::new(id).build().user_data(CANCEL));
+                },
+                Request::Cancel(_) => {},
+            }
+        }
+        {
+            let mut submission = ring.submission();
+            while let Some(entry) = queued.front() {
+                // SAFETY: the buffers stay in `ops`, and the counter on this stack, until the
+                // kernel is done with them
+                if unsafe { submission.push(entry) }.is_err() {
+                    break;
+                }
+                queued.pop_front();
+            }
+        }
+        match ring.submit_and_wait(1) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => result?,
+        };
+        for completion in ring.completion() {
+            match completion.user_data() {
+                WAKE => waiting = false,
+                CANCEL => {},
+                id => {
+                    let Some(op) = ops.remove(&id) else {
+                        continue;
+                    };
+                    let result = match completion.result() {
+                        n if n >= 0 => Ok(n as usize),
+                        e => Err(io::Error::from_raw_os_error(-e)),
+                    };
+                    // nobody waiting any more is fine, the buffer is free either way
+                    let _ = op.done.send((result, op.buf));
+                },
+            }
+        }
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("io_uring stopped")
+}
+
+/// Reads of a terminal through the ring, one in flight at a time
+pub(super) struct Reads {
+    ring: &'static Ring,
+    fd: Fd,
+    pending: Option<(u64, oneshot::Receiver<Completion>)>,
+    /// The buffer of the last read and what of it the caller had no room for yet
+    buf: Vec<u8>,
+    left: Range<usize>,
+}
+
+impl Reads {
+    pub(super) fn new(ring: &'static Ring, fd: Fd) -> Self {
+        Reads {
+            ring,
+            fd,
+            pending: None,
+            buf: Vec::new(),
+            left: 0..0,
+        }
+    }
+
+    /// Fill `out` with what the next read brings
+    pub(super) fn poll_read(
+        &mut self,
+        cx: &mut TaskContext<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if out.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if self.left.is_empty() {
+            let (_, done) = match self.pending.as_mut() {
+                Some(pending) => pending,
+                None => {
+                    let (done, completed) = oneshot::channel();
+                    let mut buf = std::mem::take(&mut self.buf);
+                    buf.resize(out.remaining().min(READ_SIZE), 0);
+                    let op = Op {
+                        fd: self.fd.clone(),
+                        buf,
+                        write: false,
+                        done,
+                    };
+                    self.pending.insert((self.ring.submit(op), completed))
+                },
+            };
+            let completed = ready!(Pin::new(done).poll(cx));
+            self.pending = None;
+            let Ok((result, buf)) = completed else {
+                return Poll::Ready(Err(stopped()));
+            };
+            self.buf = buf;
+            match result {
+                Ok(n) => self.left = 0..n,
+                // reading the primary side fails with EIO once the secondary side is closed
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => return Poll::Ready(Ok(())),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        let n = self.left.len().min(out.remaining());
+        out.put_slice(&self.buf[self.left.start..self.left.start + n]);
+        self.left.start += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Reads {
+    fn drop(&mut self) {
+        // a terminal nobody writes to would keep the read waiting forever
+        if let Some((id, _)) = self.pending.take() {
+            self.ring.cancel(id);
+        }
+    }
+}
+
+/// Writes to a terminal through the ring. Data is taken right away and written behind, the way
+/// [`tokio::fs::File`] does; a failed write shows in the next write or flush.
+pub(super) struct Writes {
+    ring: &'static Ring,
+    fd: Fd,
+    pending: Option<oneshot::Receiver<Completion>>,
+}
+
+impl Writes {
+    pub(super) fn new(ring: &'static Ring, fd: Fd) -> Self {
+        Writes {
+            ring,
+            fd,
+            pending: None,
+        }
+    }
+
+    pub(super) fn poll_write(
+        &mut self,
+        cx: &mut TaskContext<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_flush(cx))?;
+        self.write(data.to_vec());
+        Poll::Ready(Ok(data.len()))
+    }
+
+    /// Wait until all data taken is written
+    pub(super) fn poll_flush(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while let Some(done) = self.pending.as_mut() {
+            let completed = ready!(Pin::new(done).poll(cx));
+            self.pending = None;
+            let Ok((result, mut buf)) = completed else {
+                return Poll::Ready(Err(stopped()));
+            };
+            match result? {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n if n < buf.len() => {
+                    buf.drain(..n);
+                    self.write(buf);
+                },
+                _ => {},
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn write(&mut self, buf: Vec<u8>) {
+        let (done, completed) = oneshot::channel();
+        let op = Op {
+            fd: self.fd.clone(),
+            buf,
+            write: true,
+            done,
+        };
+        self.ring.submit(op);
+        self.pending = Some(completed);
+    }
+}
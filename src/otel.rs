@@ -0,0 +1,177 @@
+//! Export of traces and metrics over OTLP, so that the server can be observed with the rest of a
+//! fleet. The connection and session spans of [`logging`](crate::logging) become traces and the
+//! [metrics](crate::metrics) are sent along as they are on the admin listener:
+//!
+//! ```toml
+//! [otel]
+//! endpoint = "http://collector:4318"
+//! sampling = 0.1
+//! ```
+//!
+//! Only built with the `otel` feature.
+use crate::metrics::{MetricKind, METRICS};
+use anyhow::{anyhow, Context, Result};
+use opentelemetry::{
+    metrics::{AsyncInstrument, Meter, MeterProvider},
+    trace::TracerProvider,
+    KeyValue,
+};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    metrics::{PeriodicReader, SdkMeterProvider},
+    trace::{Sampler, SdkTracer, SdkTracerProvider},
+    Resource,
+};
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::{warn, Subscriber};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtelConfig {
+    /// Base URL of the OTLP/HTTP collector, `OTEL_EXPORTER_OTLP_ENDPOINT` or
+    /// `http://localhost:4318` if not set
+    pub endpoint: Option<String>,
+    /// Share of connections traced, from 0 to 1
+    pub sampling: f64,
+    /// Name the server reports itself as
+    pub service_name: String,
+    /// Seconds between exports of the metrics
+    pub metrics_interval: u64,
+}
+
+impl Default for OtelConfig {
+    fn default() -> Self {
+        OtelConfig {
+            endpoint: None,
+            sampling: 1.0,
+            service_name: env!("CARGO_PKG_NAME").to_string(),
+            metrics_interval: 60,
+        }
+    }
+}
+
+/// Exports traces and metrics until dropped, flushing what is left then
+pub struct Telemetry {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Telemetry {
+    /// Start exporting as `config` says
+    pub fn start(config: &OtelConfig) -> Result<Self> {
+        if !(0.0..=1.0).contains(&config.sampling) {
+            return Err(anyhow!("sampling must be between 0 and 1"));
+        }
+        let resource = Resource::builder()
+            .with_service_name(config.service_name.clone())
+            .build();
+        let endpoint = |path: &str| {
+            config
+                .endpoint
+                .as_ref()
+                .map(|base| format!("{}/{}", base.trim_end_matches('/'), path))
+        };
+
+        let mut spans = SpanExporter::builder().with_http();
+        if let Some(endpoint) = endpoint("v1/traces") {
+            spans = spans.with_endpoint(endpoint);
+        }
+        let spans = spans
+            .build()
+            .context("failed to set up the trace exporter")?;
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_batch_exporter(spans)
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                config.sampling,
+            ))))
+            .with_resource(resource.clone())
+            .build();
+
+        let mut metrics = MetricExporter::builder().with_http();
+        if let Some(endpoint) = endpoint("v1/metrics") {
+            metrics = metrics.with_endpoint(endpoint);
+        }
+        let metrics = metrics
+            .build()
+            .context("failed to set up the metrics exporter")?;
+        let reader = PeriodicReader::builder(metrics)
+            .with_interval(Duration::from_secs(config.metrics_interval))
+            .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource)
+            .build();
+        observe_metrics(&meter_provider.meter("sh_over_ws_actuator"));
+
+        Ok(Telemetry {
+            tracer_provider,
+            meter_provider,
+        })
+    }
+
+    /// Layer turning the spans it sees into traces
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, SdkTracer>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let tracer = self.tracer_provider.tracer("sh_over_ws_actuator");
+        tracing_opentelemetry::layer().with_tracer(tracer)
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.tracer_provider.shutdown() {
+            warn!("failed to export the last traces: {}", e);
+        }
+        if let Err(e) = self.meter_provider.shutdown() {
+            warn!("failed to export the last metrics: {}", e);
+        }
+    }
+}
+
+/// Report every metric through `meter` whenever the metrics are exported. Counters drop the
+/// `_total` of their Prometheus name, which exporters to Prometheus add back.
+fn observe_metrics(meter: &Meter) {
+    for (name, help, kind) in METRICS.descriptions() {
+        let read = {
+            let name = name.clone();
+            move || METRICS.samples(&name)
+        };
+        match kind {
+            MetricKind::Counter => {
+                let name = name.strip_suffix("_total").unwrap_or(&name).to_string();
+                meter
+                    .f64_observable_counter(name)
+                    .with_description(help)
+                    .with_callback(move |observer: &dyn AsyncInstrument<f64>| {
+                        for (labels, value) in read() {
+                            observer.observe(value, &attributes(labels));
+                        }
+                    })
+                    .build();
+            },
+            MetricKind::Gauge => {
+                meter
+                    .i64_observable_gauge(name)
+                    .with_description(help)
+                    .with_callback(move |observer: &dyn AsyncInstrument<i64>| {
+                        for (labels, value) in read() {
+                            observer.observe(value as i64, &attributes(labels));
+                        }
+                    })
+                    .build();
+            },
+        }
+    }
+}
+
+fn attributes(labels: Vec<(String, String)>) -> Vec<KeyValue> {
+    labels
+        .into_iter()
+        .map(|(key, value)| KeyValue::new(key, value))
+        .collect()
+}
@@ -0,0 +1,586 @@
+//! Authenticators, filters and backends loaded from WebAssembly modules, so that the server can
+//! be extended, with a single sign-on of its own say, without building it anew. Plugins are
+//! named in the configuration and used by that name:
+//!
+//! ```toml
+//! [plugins.sso]
+//! module = "/etc/shws/plugins/sso.wasm"
+//! fuel = 10000000
+//!
+//! [plugins.mask]
+//! module = "/etc/shws/plugins/mask.wasm"
+//!
+//! [plugins.simulator]
+//! module = "/etc/shws/plugins/simulator.wasm"
+//!
+//! [auth]
+//! backend = "plugin"
+//! plugin = "sso"
+//!
+//! [profiles.masked-shell]
+//! cmd = "/bin/bash"
+//! output_filters = ["mask"]
+//!
+//! [profiles.plc]
+//! backend = "plugin"
+//! plugin = "simulator"
+//! cmd = "plc"
+//! ```
+//!
+//! Modules run in a sandbox of their own, with nothing but their memory and the few functions
+//! the server imports into them. What a plugin provides is told by what its module exports,
+//! following version [`API_VERSION`] of the host API:
+//!
+//! - `memory`, `shws_api_version() -> i32` returning the version the plugin was written for and
+//!   `shws_alloc(len: i32) -> i32` returning where the server may put `len` bytes of arguments
+//! - `shws_authenticate(ptr: i32, len: i32) -> i64` to authenticate clients, see
+//!   [`PluginAuthenticator`]
+//! - `shws_filter_output(ptr: i32, len: i32) -> i64` and `shws_filter_input(ptr: i32, len: i32)
+//!   -> i64` to be an output or input filter, see [`PluginFilter`]
+//! - `shws_run(ptr: i32, len: i32) -> i32` to run the sessions of profiles, see [`PluginExec`]
+//!
+//! Arguments are written to what `shws_alloc` returned, results are returned as
+//! `ptr << 32 | len` and stay the plugin's to reuse once the call returned. The server imports
+//! from module `shws`:
+//!
+//! - `log(ptr: i32, len: i32)` to log a message of the plugin
+//! - `read(ptr: i32, len: i32) -> i32`, `write(ptr: i32, len: i32) -> i32` and
+//!   `terminal_size() -> i32` for backends
+//!
+//! Every call of a plugin may burn through `fuel` units of work, about one per instruction,
+//! before it is aborted, except for `shws_run` which takes as long as its session.
+//!
+//! Only built with the `plugins` feature.
+use crate::{
+    auth::{Authenticator, Identity},
+    command::{Environment, RunCommand},
+    error::ToAnyhow,
+    filter::{FilterRegistry, InputFilter, OutputFilter},
+    os_io::{PtyReader, PtySize, PtyWriter, Socket},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    io::{Read, Write},
+    net::{Shutdown, SocketAddr},
+    os::unix::{net::UnixStream, process::ExitStatusExt},
+    path::PathBuf,
+    process::ExitStatus,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::sync::watch;
+use tracing::{info, warn};
+use wasmtime::{Caller, Engine, Instance, Linker, Memory, Module, Store, TypedFunc};
+
+/// Version of the host API plugins are written for, modules reporting another one aren't loaded
+pub const API_VERSION: i32 = 1;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginConfig {
+    /// The compiled WebAssembly module
+    pub module: PathBuf,
+    /// Units of work a call of the plugin may take before it is aborted
+    #[serde(default = "default_fuel")]
+    pub fuel: u64,
+}
+
+fn default_fuel() -> u64 {
+    100_000_000
+}
+
+/// A compiled plugin, instantiated anew for every filter and session so that none share state
+pub struct Plugin {
+    name: String,
+    engine: Engine,
+    module: Module,
+    linker: Linker<Host>,
+    fuel: u64,
+}
+
+/// What the functions the server imports into an instance work with
+struct Host {
+    /// Name of the plugin, for its log messages
+    plugin: String,
+    /// The terminal of the session the instance runs, for backends
+    terminal: Option<Terminal>,
+}
+
+/// The end of a session's terminal a backend reads input from and writes output to
+struct Terminal {
+    stream: UnixStream,
+    /// Columns in the upper and rows in the lower 16 bits
+    size: Arc<AtomicU32>,
+}
+
+impl Plugin {
+    /// Compile the module of plugin `name` as `config` has it and check that it follows the
+    /// host API
+    pub fn load(name: &str, config: &PluginConfig) -> Result<Plugin> {
+        let err_context = || format!("failed to load plugin '{}'", name);
+
+        let mut engine_config = wasmtime::Config::new();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config)
+            .to_anyhow()
+            .with_context(err_context)?;
+        let module = Module::from_file(&engine, &config.module)
+            .to_anyhow()
+            .with_context(err_context)?;
+        let mut linker = Linker::new(&engine);
+        link_host(&mut linker).with_context(err_context)?;
+        let plugin = Plugin {
+            name: name.to_string(),
+            engine,
+            module,
+            linker,
+            fuel: config.fuel,
+        };
+        let mut guest = plugin.instantiate(None).with_context(err_context)?;
+        let version = guest
+            .instance
+            .get_typed_func::<(), i32>(&mut guest.store, "shws_api_version")
+            .and_then(|version| version.call(&mut guest.store, ()))
+            .to_anyhow()
+            .with_context(err_context)?;
+        if version != API_VERSION {
+            return Err(anyhow!(
+                "written for version {} of the host API instead of {}",
+                version,
+                API_VERSION
+            ))
+            .with_context(err_context);
+        }
+        info!(
+            "loaded plugin '{}' from {}, exporting {:?}",
+            name,
+            config.module.display(),
+            plugin.provides()
+        );
+        Ok(plugin)
+    }
+
+    /// The functions of the host API the module exports
+    fn provides(&self) -> Vec<&str> {
+        self.module
+            .exports()
+            .map(|export| export.name())
+            .filter(|name| name.starts_with("shws_") && *name != "shws_alloc")
+            .collect()
+    }
+
+    /// Whether the module exports `function`
+    fn exports(&self, function: &str) -> bool {
+        self.module.get_export(function).is_some()
+    }
+
+    /// A new instance of the module, running a session on `terminal` if given
+    fn instantiate(&self, terminal: Option<Terminal>) -> Result<Guest> {
+        let host = Host {
+            plugin: self.name.clone(),
+            terminal,
+        };
+        let mut store = Store::new(&self.engine, host);
+        store.set_fuel(self.fuel)?;
+        let instance = self.linker.instantiate(&mut store, &self.module)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("exports no memory"))?;
+        let alloc = instance.get_typed_func(&mut store, "shws_alloc")?;
+        Ok(Guest {
+            store,
+            instance,
+            memory,
+            alloc,
+            fuel: self.fuel,
+        })
+    }
+
+    /// `filters` with this plugin as output or input filter profiles can name, if it is one
+    pub fn register_filters(self: &Arc<Self>, mut filters: FilterRegistry) -> FilterRegistry {
+        if self.exports("shws_filter_output") {
+            let plugin = self.clone();
+            filters.register_output(&self.name, move |_| {
+                Box::new(PluginFilter::new(&plugin, "shws_filter_output"))
+            });
+        }
+        if self.exports("shws_filter_input") {
+            let plugin = self.clone();
+            filters.register_input(&self.name, move |_| {
+                Box::new(PluginFilter::new(&plugin, "shws_filter_input"))
+            });
+        }
+        filters
+    }
+}
+
+/// An instance of a plugin
+struct Guest {
+    store: Store<Host>,
+    instance: Instance,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    fuel: u64,
+}
+
+impl Guest {
+    /// Call `function` of the plugin with `input`, what it returned
+    fn call(&mut self, function: &str, input: &[u8]) -> Result<Vec<u8>> {
+        let err_context = || format!("failed to call {}", function);
+
+        self.store
+            .set_fuel(self.fuel)
+            .to_anyhow()
+            .with_context(err_context)?;
+        let call = self
+            .instance
+            .get_typed_func::<(i32, i32), i64>(&mut self.store, function)
+            .to_anyhow()
+            .with_context(err_context)?;
+        let (ptr, len) = self.pass(input).with_context(err_context)?;
+        let returned = call
+            .call(&mut self.store, (ptr, len))
+            .to_anyhow()
+            .with_context(err_context)?;
+        let (ptr, len) = ((returned >> 32) as u32 as usize, returned as u32 as usize);
+        let mut output = vec![0; len];
+        self.memory
+            .read(&self.store, ptr, &mut output)
+            .context("returned out of bounds")
+            .with_context(err_context)?;
+        Ok(output)
+    }
+
+    /// Copy `input` into the memory of the plugin, where it is and how long
+    fn pass(&mut self, input: &[u8]) -> Result<(i32, i32)> {
+        let len = i32::try_from(input.len()).context("argument too long")?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory.write(&mut self.store, ptr as u32 as usize, input)?;
+        Ok((ptr, len))
+    }
+}
+
+/// Import the host API into the modules of `linker`
+fn link_host(linker: &mut Linker<Host>) -> Result<()> {
+    linker.func_wrap(
+        "shws",
+        "log",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> wasmtime::Result<()> {
+            let (memory, host) = memory(&mut caller)?;
+            let message = guest_slice(memory, ptr, len)?;
+            info!("plugin '{}': {}", host.plugin, String::from_utf8_lossy(message));
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "shws",
+        "read",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> wasmtime::Result<i32> {
+            let (memory, host) = memory(&mut caller)?;
+            let terminal = host.terminal.as_mut().ok_or_else(not_a_backend)?;
+            let buf = guest_slice_mut(memory, ptr, len)?;
+            match terminal.stream.read(buf)? {
+                // the session hung up, nobody is left to run for
+                0 => Err(wasmtime::format_err!("hung up")),
+                read => Ok(read as i32),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "shws",
+        "write",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> wasmtime::Result<i32> {
+            let (memory, host) = memory(&mut caller)?;
+            let terminal = host.terminal.as_mut().ok_or_else(not_a_backend)?;
+            let data = guest_slice(memory, ptr, len)?;
+            terminal.stream.write_all(data)?;
+            Ok(len)
+        },
+    )?;
+    linker.func_wrap(
+        "shws",
+        "terminal_size",
+        |caller: Caller<'_, Host>| -> wasmtime::Result<i32> {
+            let terminal = caller.data().terminal.as_ref().ok_or_else(not_a_backend)?;
+            Ok(terminal.size.load(Ordering::Relaxed) as i32)
+        },
+    )?;
+    Ok(())
+}
+
+/// The memory of the plugin calling and the state of the server for it
+fn memory<'a>(caller: &'a mut Caller<'_, Host>) -> wasmtime::Result<(&'a mut [u8], &'a mut Host)> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or_else(|| wasmtime::format_err!("exports no memory"))?;
+    Ok(memory.data_and_store_mut(caller))
+}
+
+fn guest_slice(memory: &[u8], ptr: i32, len: i32) -> wasmtime::Result<&[u8]> {
+    let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+    memory
+        .get(ptr..ptr.saturating_add(len))
+        .ok_or_else(|| wasmtime::format_err!("out of bounds"))
+}
+
+fn guest_slice_mut(memory: &mut [u8], ptr: i32, len: i32) -> wasmtime::Result<&mut [u8]> {
+    let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+    memory
+        .get_mut(ptr..ptr.saturating_add(len))
+        .ok_or_else(|| wasmtime::format_err!("out of bounds"))
+}
+
+fn not_a_backend() -> wasmtime::Error {
+    wasmtime::format_err!("only backends have a terminal")
+}
+
+/// Leaves the decision whether a token grants access to a plugin. It gets
+/// `{"token": "...", "address": "192.0.2.1:50000"}` and returns who the token belongs to as
+/// `{"user": "alice", "claims": {...}, "expires": 1767225600}`, every field optional, or
+/// `{"error": "..."}` to turn the client away. Tokens are checked one after the other.
+pub struct PluginAuthenticator {
+    guest: Mutex<Guest>,
+}
+
+#[derive(Serialize)]
+struct AuthRequest<'a> {
+    token: &'a str,
+    address: SocketAddr,
+}
+
+#[derive(Deserialize)]
+struct AuthResponse {
+    error: Option<String>,
+    user: Option<String>,
+    claims: Option<Map<String, Value>>,
+    /// Seconds since the epoch
+    expires: Option<u64>,
+}
+
+impl PluginAuthenticator {
+    pub fn new(plugin: &Plugin) -> Result<Self> {
+        if !plugin.exports("shws_authenticate") {
+            bail!("plugin '{}' doesn't authenticate", plugin.name);
+        }
+        Ok(PluginAuthenticator {
+            guest: Mutex::new(plugin.instantiate(None)?),
+        })
+    }
+}
+
+impl Authenticator for PluginAuthenticator {
+    fn authenticate(&self, token: &str, address: SocketAddr) -> Result<Identity> {
+        let request = serde_json::to_vec(&AuthRequest { token, address })?;
+        let response = self
+            .guest
+            .lock()
+            .map_err(|_| anyhow!("plugin panicked before"))?
+            .call("shws_authenticate", &request)?;
+        let response: AuthResponse =
+            serde_json::from_slice(&response).context("plugin returned malformed response")?;
+        if let Some(error) = response.error {
+            bail!("{}", error);
+        }
+        Ok(Identity {
+            user: response.user,
+            claims: response.claims,
+            expires: response
+                .expires
+                .map(|expires| UNIX_EPOCH + Duration::from_secs(expires)),
+        })
+    }
+}
+
+/// Passes the output or input of a session through a plugin, which gets the bytes of every
+/// piece and returns those to pass on. Every stream gets an instance of its own, which may keep
+/// what it needs between the pieces. Failing calls pass the piece on as is.
+pub struct PluginFilter {
+    /// `None` if the plugin couldn't be instantiated
+    guest: Option<Guest>,
+    function: &'static str,
+}
+
+impl PluginFilter {
+    fn new(plugin: &Plugin, function: &'static str) -> Self {
+        let guest = plugin
+            .instantiate(None)
+            .map_err(|e| warn!("failed to instantiate plugin '{}': {:#}", plugin.name, e))
+            .ok();
+        PluginFilter { guest, function }
+    }
+
+    fn pass(&mut self, data: &[u8]) -> Vec<u8> {
+        let Some(guest) = self.guest.as_mut() else {
+            return data.to_vec();
+        };
+        match guest.call(self.function, data) {
+            Ok(filtered) => filtered,
+            Err(e) => {
+                warn!("{:#}", e);
+                data.to_vec()
+            },
+        }
+    }
+}
+
+impl OutputFilter for PluginFilter {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        self.pass(data)
+    }
+}
+
+impl InputFilter for PluginFilter {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        self.pass(data)
+    }
+}
+
+/// What a backend is asked to run with `shws_run`
+#[derive(Serialize)]
+struct RunRequest<'a> {
+    command: String,
+    args: &'a [String],
+    cwd: Option<String>,
+    env: &'a BTreeMap<String, String>,
+    cols: u16,
+    rows: u16,
+}
+
+/// A session a plugin runs, which gets the command of the profile and the environment as
+/// `{"command": "...", "args": [...], "cwd": "...", "env": {...}, "cols": 80, "rows": 24}`. It
+/// `read`s the input of the session, blocking until there is some, `write`s its output and
+/// returns its exit code once done. Reading fails once the session hung up, which ends the call.
+/// Dropping the `PluginExec` hangs the session up.
+pub struct PluginExec {
+    terminal: Socket,
+    size: Arc<AtomicU32>,
+    exit_status: watch::Receiver<Option<ExitStatus>>,
+}
+
+impl PluginExec {
+    /// Start `cmd` with `env` in an instance of `plugin` of its own, on a terminal of the given
+    /// size
+    pub fn start(
+        plugin: &Plugin,
+        cmd: &RunCommand,
+        env: &Environment,
+        size: PtySize,
+    ) -> Result<PluginExec> {
+        let err_context = || format!("failed to start '{}' in plugin '{}'", cmd, plugin.name);
+
+        if !plugin.exports("shws_run") {
+            return Err(anyhow!("plugin runs no sessions")).with_context(err_context);
+        }
+        size.check().with_context(err_context)?;
+        let request = serde_json::to_vec(&RunRequest {
+            command: cmd.command.display().to_string(),
+            args: &cmd.args,
+            cwd: cmd.cwd.as_ref().map(|cwd| cwd.display().to_string()),
+            env: &env.vars,
+            cols: size.cols,
+            rows: size.rows,
+        })
+        .with_context(err_context)?;
+        let (local, remote) = UnixStream::pair().with_context(err_context)?;
+        let packed = Arc::new(AtomicU32::new(pack(size)));
+        let terminal = Terminal {
+            stream: remote.try_clone().with_context(err_context)?,
+            size: packed.clone(),
+        };
+        let mut guest = plugin
+            .instantiate(Some(terminal))
+            .with_context(err_context)?;
+        // sessions take as long as they take
+        guest.fuel = u64::MAX;
+        guest
+            .store
+            .set_fuel(u64::MAX)
+            .to_anyhow()
+            .with_context(err_context)?;
+        let run = guest
+            .instance
+            .get_typed_func::<(i32, i32), i32>(&mut guest.store, "shws_run")
+            .to_anyhow()
+            .with_context(err_context)?;
+        let (ptr, len) = guest.pass(&request).with_context(err_context)?;
+        let (exit_tx, exit_status) = watch::channel(None);
+        let name = plugin.name.clone();
+        tokio::task::spawn_blocking(move || {
+            let code = match run.call(&mut guest.store, (ptr, len)) {
+                Ok(code) => code,
+                Err(e) => {
+                    warn!("plugin '{}' failed: {:#}", name, anyhow::Error::from(e));
+                    1
+                },
+            };
+            let _ = exit_tx.send(Some(ExitStatus::from_raw((code & 0xff) << 8)));
+            // the session's reads end once the plugin is done
+            let _ = remote.shutdown(Shutdown::Both);
+        });
+        Ok(PluginExec {
+            terminal: Socket::new(local).with_context(err_context)?,
+            size: packed,
+            exit_status,
+        })
+    }
+
+    /// A handle reading the output of the session
+    pub fn reader(&self) -> PtyReader {
+        self.terminal.reader()
+    }
+
+    /// A handle writing to the input of the session
+    pub fn writer(&self) -> PtyWriter {
+        self.terminal.writer()
+    }
+
+    /// Change the size of the terminal, which the plugin sees the next time it asks
+    pub fn resize(&self, size: PtySize) -> Result<()> {
+        size.check()?;
+        self.size.store(pack(size), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Resolves to the exit status of the session once the plugin returned
+    pub fn exited(&self) -> impl Future<Output = Option<ExitStatus>> + Send + 'static {
+        let mut exit_status = self.exit_status.clone();
+        async move {
+            loop {
+                if let Some(status) = *exit_status.borrow() {
+                    return Some(status);
+                }
+                if exit_status.changed().await.is_err() {
+                    return *exit_status.borrow();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for PluginExec {
+    fn drop(&mut self) {
+        self.terminal.shutdown();
+    }
+}
+
+/// `size` as `terminal_size` returns it
+fn pack(size: PtySize) -> u32 {
+    (u32::from(size.cols) << 16) | u32::from(size.rows)
+}
+
+/// The plugins of `config`, by name
+pub fn load_all(config: &BTreeMap<String, PluginConfig>) -> Result<BTreeMap<String, Arc<Plugin>>> {
+    config
+        .iter()
+        .map(|(name, config)| Ok((name.clone(), Arc::new(Plugin::load(name, config)?))))
+        .collect()
+}
+
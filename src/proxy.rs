@@ -0,0 +1,205 @@
+//! Real addresses of clients connecting through load balancers and reverse proxies, for the
+//! audit trail, the logs and the limits keyed by address:
+//!
+//! ```toml
+//! [proxy]
+//! protocol = true
+//! trusted = ["10.0.0.0/8", "127.0.0.1"]
+//! ```
+//!
+//! With `protocol` set every TCP connection has to start with the header of the
+//! [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt), version 1 or
+//! 2, which tells whom the load balancer accepted it from. Connections without one are closed,
+//! as are those from addresses not `trusted`: the load balancers have to be listed there.
+//!
+//! Proxies terminating HTTP tell instead with the `Forwarded` or `X-Forwarded-For` header of
+//! the upgrade request. Anybody can send those, so they are only believed for connections from
+//! `trusted` addresses. Each proxy on the way appends the address it got the request from, the
+//! client is the last one not trusted. Clients on the [Unix socket](crate::listener) count as
+//! connecting from `127.0.0.1`, so that is the address to trust for the proxy in front of it.
+use anyhow::{bail, Context, Result};
+use hyper::HeaderMap;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time,
+};
+
+/// How long a load balancer may take to send the PROXY protocol header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Starts the header of version 2
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest header of version 1, line break included
+const V1_MAX_LEN: usize = 107;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Expect the PROXY protocol header at the start of every TCP connection, which only
+    /// `trusted` proxies may connect with
+    pub protocol: bool,
+    /// Addresses or networks like `10.0.0.0/8` of the proxies whose PROXY protocol, `Forwarded`
+    /// and `X-Forwarded-For` headers are believed
+    #[serde(deserialize_with = "deserialize_networks")]
+    pub trusted: Vec<IpNet>,
+}
+
+impl ProxyConfig {
+    /// Whether the proxy at `address` is trusted to tell whom it forwards requests for
+    pub fn trusts(&self, address: IpAddr) -> bool {
+        self.trusted
+            .iter()
+            .any(|network| network.contains(&address))
+    }
+
+    /// The address of the client a request from `peer` with `headers` comes from, `peer`
+    /// itself unless it is a trusted proxy telling otherwise
+    pub fn client_address(&self, peer: SocketAddr, headers: &HeaderMap) -> SocketAddr {
+        if !self.trusts(peer.ip()) {
+            return peer;
+        }
+        let hops = forwarded_hops(headers);
+        // clients can make up the hops before theirs, only the trusted proxies' are believed
+        for hop in hops.iter().rev() {
+            match hop {
+                Some(hop) if self.trusts(hop.ip()) => continue,
+                Some(hop) => return *hop,
+                // obfuscated or unknown, whoever it is went unrecorded
+                None => return peer,
+            }
+        }
+        hops.first().copied().flatten().unwrap_or(peer)
+    }
+}
+
+/// The addresses requests with `headers` were forwarded for, from the client on, `None` for
+/// those not given. `Forwarded` has precedence over `X-Forwarded-For`.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<SocketAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values("forwarded");
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, value)| parse_node(value.trim().trim_matches('"')))
+            })
+            .collect();
+    }
+    values("x-forwarded-for")
+        .into_iter()
+        .map(parse_node)
+        .collect()
+}
+
+/// Parse an address like `192.0.2.60`, `192.0.2.60:4711`, `2001:db8::17` or
+/// `[2001:db8::17]:4711`, with port 0 if none is given
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    node.parse::<SocketAddr>().ok().or_else(|| {
+        let ip = node
+            .strip_prefix('[')
+            .and_then(|node| node.strip_suffix(']'));
+        Some(SocketAddr::new(ip.unwrap_or(node).parse().ok()?, 0))
+    })
+}
+
+/// Read the PROXY protocol header from the start of `stream`, leaving what follows it. The
+/// client address it tells, `None` if the load balancer connected on its own behalf, say for a
+/// health check.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    time::timeout(HEADER_TIMEOUT, read_header_now(stream))
+        .await
+        .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut).into()))
+        .context("failed to read PROXY protocol header")
+}
+
+async fn read_header_now<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    // the shortest header of version 1 is longer than the signature of version 2
+    let mut head = [0; 12];
+    stream.read_exact(&mut head).await?;
+    if head == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+    if !head.starts_with(b"PROXY ") {
+        bail!("connection doesn't start with one");
+    }
+    // read bytewise so that nothing after the header is consumed
+    let mut line = head.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            bail!("header is longer than {} bytes", V1_MAX_LEN);
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line)?.trim_end();
+    match line.split(' ').collect::<Vec<_>>()[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            Ok(Some(SocketAddr::new(source.parse()?, port.parse()?)))
+        },
+        _ => bail!("invalid header '{}'", line),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let mut head = [0; 4];
+    stream.read_exact(&mut head).await?;
+    let [version_command, family, len @ ..] = head;
+    if version_command >> 4 != 2 {
+        bail!("unsupported version {}", version_command >> 4);
+    }
+    let mut addresses = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut addresses).await?;
+    // LOCAL rather than PROXY
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    Ok(match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into()?;
+            Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8)))
+        },
+        2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into()?;
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32)))
+        },
+        1 | 2 => bail!("addresses are truncated"),
+        // Unix sockets and unspecified ones tell nothing about the client
+        _ => None,
+    })
+}
+
+/// Parse a network like `10.0.0.0/8`, or a single address
+pub fn parse_network(network: &str) -> Result<IpNet> {
+    IpNet::from_str(network)
+        .or_else(|_| IpAddr::from_str(network).map(IpNet::from))
+        .with_context(|| format!("invalid network '{}'", network))
+}
+
+pub fn deserialize_networks<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<IpNet>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|network| parse_network(network).map_err(serde::de::Error::custom))
+        .collect()
+}
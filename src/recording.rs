@@ -0,0 +1,193 @@
+//! Recordings of terminal sessions in the [asciinema v2] format, which `asciinema play` replays
+//! with the original timing. A recording has a header line describing the terminal followed by
+//! a line for every chunk of output and every resize.
+//!
+//! What the user types is left out unless the [configuration](RecordingConfig) or the profile
+//! of the session asks for it, see [`InputLogging`]: keystroke logs of privileged sessions may
+//! be called for, those of everybody else forbidden.
+//!
+//! ```toml
+//! [recording]
+//! dir = "/var/log/shws/recordings"
+//! input = "metadata"
+//!
+//! [profiles.root-shell]
+//! cmd = "/bin/bash"
+//! input = "full"
+//! ```
+//!
+//! [asciinema v2]: https://docs.asciinema.org/manual/asciicast/v2/
+use crate::{
+    command::RunCommand,
+    config::RecordingConfig,
+    data::SessionId,
+    os_io::PtySize,
+    redact::{RedactedOutput, RedactionConfig},
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+/// What is recorded of the input of a session
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputLogging {
+    /// Nothing
+    #[default]
+    None,
+    /// When and how much the user typed, every character recorded as `*`
+    Metadata,
+    /// What the user typed, scrubbed of [secrets](crate::redact) like the output
+    Full,
+}
+
+#[derive(Serialize)]
+struct Header<'a> {
+    version: u8,
+    width: u16,
+    height: u16,
+    timestamp: u64,
+    command: &'a str,
+    title: &'a str,
+}
+
+/// The recording of one session
+#[derive(Debug)]
+pub struct Recording {
+    path: PathBuf,
+    file: File,
+    started: Instant,
+    /// The start of a UTF-8 sequence cut off at the end of the last chunk of output, asciinema
+    /// only takes text
+    partial: Vec<u8>,
+    redacted: RedactedOutput,
+    input: InputLogging,
+}
+
+impl Recording {
+    /// Start recording session `id` running `command` on a terminal of `size`, in a new file in
+    /// the configured directory, scrubbing what `redaction` asks for from its output
+    pub fn create(
+        config: &RecordingConfig,
+        redaction: &RedactionConfig,
+        id: SessionId,
+        command: &RunCommand,
+        size: PtySize,
+    ) -> Result<Self> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = config.dir.join(
+            config
+                .file_name
+                .replace("{session}", &id.to_string())
+                .replace("{time}", &now.to_string()),
+        );
+        let err_context = || format!("failed to start recording '{}'", path.display());
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(err_context)?;
+        let mut recording = Recording {
+            path: path.clone(),
+            file,
+            started: Instant::now(),
+            partial: vec![],
+            redacted: RedactedOutput::new(redaction),
+            input: config.input,
+        };
+        let command = command.to_string();
+        let command = String::from_utf8_lossy(&redaction.redact(command.as_bytes())).into_owned();
+        let header = serde_json::to_string(&Header {
+            version: 2,
+            width: size.cols,
+            height: size.rows,
+            timestamp: now,
+            command: &command,
+            title: &id.to_string(),
+        })
+        .with_context(err_context)?;
+        writeln!(recording.file, "{}", header).with_context(err_context)?;
+        Ok(recording)
+    }
+
+    /// Record `data` the command output
+    pub fn output(&mut self, data: &[u8]) {
+        let data = self.redacted.push(data);
+        self.write_output(&data);
+    }
+
+    fn write_output(&mut self, data: &[u8]) {
+        self.partial.extend_from_slice(data);
+        // an incomplete sequence at the end may be completed by the next chunk
+        let complete = match std::str::from_utf8(&self.partial) {
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            _ => self.partial.len(),
+        };
+        let rest = self.partial.split_off(complete);
+        let text = String::from_utf8_lossy(&self.partial).into_owned();
+        self.partial = rest;
+        self.event("o", &text);
+    }
+
+    /// Record what of the input of the session `input` asks for rather than what the
+    /// configuration does
+    pub fn with_input(mut self, input: InputLogging) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// Whether what the user types is recorded
+    pub fn records_input(&self) -> bool {
+        self.input == InputLogging::Full
+    }
+
+    /// Record `data` the user typed, as far as that is recorded
+    pub fn input(&mut self, data: &[u8]) {
+        match self.input {
+            InputLogging::None => {},
+            InputLogging::Metadata => {
+                let typed = String::from_utf8_lossy(data).chars().count();
+                self.event("i", &"*".repeat(typed));
+            },
+            InputLogging::Full => {
+                let data = self.redacted.config().redact(data);
+                self.event("i", &String::from_utf8_lossy(&data));
+            },
+        }
+    }
+
+    /// Record the terminal having been resized to `size`
+    pub fn resize(&mut self, size: PtySize) {
+        self.event("r", &format!("{}x{}", size.cols, size.rows));
+    }
+
+    fn event(&mut self, kind: &str, data: &str) {
+        if data.is_empty() {
+            return;
+        }
+        let time = self.started.elapsed().as_secs_f64();
+        let written = serde_json::to_string(&(time, kind, data))
+            .map_err(std::io::Error::from)
+            .and_then(|line| writeln!(self.file, "{}", line));
+        if let Err(e) = written {
+            warn!("failed to write recording '{}': {}", self.path.display(), e);
+        }
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        let rest = self.redacted.finish();
+        self.write_output(&rest);
+    }
+}
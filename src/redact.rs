@@ -0,0 +1,164 @@
+//! Secrets scrubbed from what is kept of sessions, so that [recordings](crate::recording),
+//! [captured output](crate::capture) and the [audit trail](crate::audit) can be retained without
+//! leaking credentials. What clients see of their sessions is left alone, unless a profile
+//! asks for the `redact` [filter](crate::filter).
+//!
+//! ```toml
+//! [redaction]
+//! patterns = [
+//!     'AKIA[0-9A-Z]{16}',
+//!     '(?i)bearer\s+[a-z0-9._~+/-]+=*',
+//!     '(?i)password:\s*(?P<secret>\S+)',
+//! ]
+//! replacement = "[REDACTED]"
+//! ```
+//!
+//! Patterns are [regular expressions](https://docs.rs/regex/1/regex/#syntax). Whatever they
+//! match is replaced, only the group named `secret` if they have one, so that the prompt of a
+//! password echoed back is kept. Output is matched a line at a time, so recordings and captured
+//! files get it a line at a time as well.
+use crate::filter::OutputFilter;
+use regex::bytes::{Captures, Regex};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::borrow::Cow;
+
+/// Lines of output longer than this are scrubbed in pieces
+const MAX_LINE_LEN: usize = 4096;
+
+/// What is scrubbed from what is kept of sessions, see the [module docs](self)
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedactionConfig {
+    /// Regular expressions matching secrets, nothing is scrubbed if empty
+    #[serde(deserialize_with = "deserialize_regexes")]
+    pub patterns: Vec<Regex>,
+    /// What secrets are replaced with
+    pub replacement: String,
+}
+
+impl Default for RedactionConfig {
+    fn default() -> Self {
+        RedactionConfig {
+            patterns: vec![],
+            replacement: "[REDACTED]".to_string(),
+        }
+    }
+}
+
+impl RedactionConfig {
+    /// `data` with every secret replaced
+    pub fn redact<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        let replacement = self.replacement.as_bytes();
+        let replace = |captures: &Captures| -> Vec<u8> {
+            let matched = captures.get(0).expect("whole match");
+            match captures.name("secret") {
+                Some(secret) => {
+                    let start = secret.start() - matched.start();
+                    let end = secret.end() - matched.start();
+                    let matched = matched.as_bytes();
+                    [&matched[..start], replacement, &matched[end..]].concat()
+                },
+                None => replacement.to_vec(),
+            }
+        };
+        self.patterns
+            .iter()
+            .fold(Cow::Borrowed(data), |data, pattern| {
+                match pattern.replace_all(&data, replace) {
+                    Cow::Borrowed(_) => data,
+                    Cow::Owned(redacted) => Cow::Owned(redacted),
+                }
+            })
+    }
+
+    /// `value` with the secrets in any of its strings replaced
+    pub fn redact_json(&self, value: Value) -> Value {
+        match value {
+            Value::String(text) => match self.redact(text.as_bytes()) {
+                Cow::Borrowed(_) => Value::String(text),
+                Cow::Owned(redacted) => Value::String(String::from_utf8_lossy(&redacted).into()),
+            },
+            Value::Array(values) => Value::Array(
+                values
+                    .into_iter()
+                    .map(|value| self.redact_json(value))
+                    .collect(),
+            ),
+            Value::Object(fields) => Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(name, value)| (name, self.redact_json(value)))
+                    .collect(),
+            ),
+            value => value,
+        }
+    }
+}
+
+/// Output of a session scrubbed of secrets a line at a time, so that secrets written in several
+/// chunks, like the echo of what the user types, are caught as well
+#[derive(Debug)]
+pub struct RedactedOutput {
+    config: RedactionConfig,
+    /// Output since the last line break
+    pending: Vec<u8>,
+}
+
+impl RedactedOutput {
+    pub fn new(config: &RedactionConfig) -> Self {
+        RedactedOutput {
+            config: config.clone(),
+            pending: vec![],
+        }
+    }
+
+    /// What is scrubbed
+    pub fn config(&self) -> &RedactionConfig {
+        &self.config
+    }
+
+    /// The lines `data` completes, scrubbed. The rest is held back until a line break follows.
+    pub fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.config.patterns.is_empty() {
+            return data.to_vec();
+        }
+        self.pending.extend_from_slice(data);
+        let complete = match self.pending.iter().rposition(|byte| *byte == b'\n') {
+            Some(end) => end + 1,
+            None if self.pending.len() > MAX_LINE_LEN => self.pending.len(),
+            None => return vec![],
+        };
+        let rest = self.pending.split_off(complete);
+        let lines = std::mem::replace(&mut self.pending, rest);
+        self.config.redact(&lines).into_owned()
+    }
+
+    /// What was held back, scrubbed
+    pub fn finish(&mut self) -> Vec<u8> {
+        let pending = std::mem::take(&mut self.pending);
+        self.config.redact(&pending).into_owned()
+    }
+}
+
+/// Scrubs the live output as well, if a profile asks for it, see [`filter`](crate::filter)
+impl OutputFilter for RedactedOutput {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        self.push(data)
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        RedactedOutput::finish(self)
+    }
+}
+
+fn deserialize_regexes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Regex>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern).map_err(|e| {
+                serde::de::Error::custom(format!("invalid pattern '{}': {}", pattern, e))
+            })
+        })
+        .collect()
+}
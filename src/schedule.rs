@@ -0,0 +1,288 @@
+//! Commands run later. A [`Message::Run`](crate::data::Message::Run) with a `start_at` time or
+//! a `delay` in seconds is queued instead of started right away:
+//!
+//! ```json
+//! {"type": "run", "session": "0b9e6c2a-5d41-4f3e-8a7b-2c1d9e8f4a3b",
+//!  "program": "/usr/bin/backup", "start_at": "2026-10-15T02:00:00Z"}
+//! ```
+//!
+//! The server answers with [`Message::Scheduled`](crate::data::Message::Scheduled), sends
+//! [`Message::Started`](crate::data::Message::Started) once the time has come and goes on like
+//! for any other command. Until then the client may cancel it with
+//! [`Message::CancelScheduled`](crate::data::Message::CancelScheduled).
+//!
+//! Start times are in UTC. Scheduled commands belong to the connection that asked for them, those
+//! that didn't start before it closes never do.
+//!
+//! The server may also run no more than
+//! [`max_running_commands`](crate::config::Limits::max_running_commands) commands without a
+//! terminal at the same time. Further ones wait in the [`JobQueue`], their clients are told
+//! where they are in it with [`Message::Queued`] until they are started.
+use crate::data::{Message, SessionId};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    sync::mpsc::{self, error::SendError},
+    task::AbortHandle,
+    time,
+};
+
+/// A command of a connection that may be started now
+pub enum Due {
+    /// Its start time has come
+    Scheduled(SessionId),
+    /// It waited in the [`JobQueue`] and got the slot
+    Queued(SessionId, JobSlot),
+}
+
+/// The commands of a connection waiting for their time, `T` being whatever is needed to start
+/// them
+pub struct Schedule<T> {
+    jobs: Mutex<HashMap<SessionId, Job<T>>>,
+    /// Where sessions go once it's time to start them
+    due: mpsc::UnboundedSender<Due>,
+}
+
+struct Job<T> {
+    job: T,
+    timer: AbortHandle,
+}
+
+impl<T> Schedule<T> {
+    /// An empty schedule, sending the sessions that are due to `due`
+    pub fn new(due: mpsc::UnboundedSender<Due>) -> Self {
+        Schedule {
+            jobs: Mutex::default(),
+            due,
+        }
+    }
+
+    /// Queue `job` of session `id` to start at `at`, unless the schedule holds `limit` jobs
+    /// already
+    pub fn add(&self, id: SessionId, at: SystemTime, job: T, limit: Option<usize>) -> Result<()> {
+        let mut jobs = self.jobs.lock().map_err(|_| anyhow!("schedule poisoned"))?;
+        if jobs.contains_key(&id) {
+            return Err(anyhow!("session already scheduled"));
+        }
+        if let Some(limit) = limit.filter(|limit| jobs.len() >= *limit) {
+            return Err(anyhow!("no more than {} commands may be scheduled", limit));
+        }
+        let wait = at
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO);
+        let due = self.due.clone();
+        let timer = tokio::spawn(async move {
+            time::sleep(wait).await;
+            let _ = due.send(Due::Scheduled(id));
+        })
+        .abort_handle();
+        jobs.insert(id, Job { job, timer });
+        Ok(())
+    }
+
+    /// Whether session `id` is waiting to be started
+    pub fn contains(&self, id: SessionId) -> bool {
+        self.jobs.lock().is_ok_and(|jobs| jobs.contains_key(&id))
+    }
+
+    /// Take the job of session `id` off the schedule, `None` if it isn't on it (anymore)
+    pub fn take(&self, id: SessionId) -> Option<T> {
+        let job = self.jobs.lock().ok()?.remove(&id)?;
+        job.timer.abort();
+        Some(job.job)
+    }
+}
+
+impl<T> Drop for Schedule<T> {
+    fn drop(&mut self) {
+        if let Ok(jobs) = self.jobs.get_mut() {
+            for job in jobs.values() {
+                job.timer.abort();
+            }
+        }
+    }
+}
+
+/// When a command given `start_at` or `delay` is to be started, `None` if right away
+pub fn start_time(start_at: Option<&str>, delay: Option<u64>) -> Result<Option<SystemTime>> {
+    match (start_at, delay) {
+        (Some(_), Some(_)) => Err(anyhow!("either a start time or a delay may be given")),
+        (Some(start_at), None) => humantime::parse_rfc3339_weak(start_at)
+            .with_context(|| format!("invalid start time '{}'", start_at))
+            .map(Some),
+        (None, Some(delay)) => Ok(Some(SystemTime::now() + Duration::from_secs(delay))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// The commands running without a terminal on the server and those waiting to be run once
+/// fewer than the limit are. Clients take turns, the commands of each client are run in the
+/// order they were queued.
+pub struct JobQueue {
+    limit: Option<usize>,
+    state: Mutex<QueueState>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    running: usize,
+    /// The commands waiting by client, the client at the front is next
+    waiting: VecDeque<(String, VecDeque<Waiting>)>,
+}
+
+struct Waiting {
+    session: SessionId,
+    /// Where the slot is sent once the command gets one
+    due: mpsc::UnboundedSender<Due>,
+    /// Where the position of the command is reported
+    events: mpsc::UnboundedSender<Message>,
+    /// The position reported last
+    position: usize,
+}
+
+/// The right to run a command while the [`JobQueue`] is limited, handed on to the command
+/// waiting next when dropped
+pub struct JobSlot {
+    /// `None` once the slot was given back
+    queue: Option<Arc<JobQueue>>,
+}
+
+impl Drop for JobSlot {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            queue.release();
+        }
+    }
+}
+
+impl JobQueue {
+    /// A queue letting `limit` commands run at the same time, any number if `None`
+    pub fn new(limit: Option<usize>) -> Self {
+        JobQueue {
+            limit,
+            state: Mutex::default(),
+        }
+    }
+
+    /// A slot for the command of session `id` of `client` to run right away, `None` if the
+    /// command was queued instead. Its slot is sent to `due` once it gets one, and its position
+    /// in the queue is reported to `events` until then.
+    pub fn acquire(
+        self: &Arc<Self>,
+        client: String,
+        id: SessionId,
+        due: mpsc::UnboundedSender<Due>,
+        events: mpsc::UnboundedSender<Message>,
+    ) -> Result<Option<JobSlot>> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| anyhow!("job queue poisoned"))?;
+        let full = self.limit.is_some_and(|limit| state.running >= limit);
+        if !full && state.waiting.is_empty() {
+            state.running += 1;
+            return Ok(Some(JobSlot {
+                queue: Some(self.clone()),
+            }));
+        }
+        let waiting = Waiting {
+            session: id,
+            due,
+            events,
+            position: 0,
+        };
+        match state
+            .waiting
+            .iter_mut()
+            .find(|(queued_by, _)| *queued_by == client)
+        {
+            Some((_, jobs)) => jobs.push_back(waiting),
+            None => state.waiting.push_back((client, VecDeque::from([waiting]))),
+        }
+        state.report_positions();
+        Ok(None)
+    }
+
+    /// Take the command of session `id` out of the queue, `false` if it isn't in it (anymore)
+    pub fn remove(&self, id: SessionId) -> bool {
+        let Ok(mut state) = self.state.lock() else {
+            return false;
+        };
+        let mut removed = false;
+        state.waiting.retain_mut(|(_, jobs)| {
+            let before = jobs.len();
+            jobs.retain(|waiting| waiting.session != id);
+            removed |= jobs.len() < before;
+            !jobs.is_empty()
+        });
+        if removed {
+            state.report_positions();
+        }
+        removed
+    }
+
+    /// Hand the slot of a command that is done on to the command waiting next, if any
+    fn release(self: &Arc<Self>) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        loop {
+            let Some(next) = state.next() else {
+                state.running -= 1;
+                break;
+            };
+            let slot = JobSlot {
+                queue: Some(self.clone()),
+            };
+            let Err(SendError(Due::Queued(_, mut slot))) =
+                next.due.send(Due::Queued(next.session, slot))
+            else {
+                break;
+            };
+            // the connection is gone, dropping the slot would release it once more
+            slot.queue = None;
+        }
+        state.report_positions();
+    }
+}
+
+impl QueueState {
+    /// Take the command to run next out of the queue, it's the next client's turn after that
+    fn next(&mut self) -> Option<Waiting> {
+        let (client, mut jobs) = self.waiting.pop_front()?;
+        let next = jobs.pop_front();
+        if !jobs.is_empty() {
+            self.waiting.push_back((client, jobs));
+        }
+        next
+    }
+
+    /// Tell the clients whose commands moved in the queue where they are now, counting from 1
+    fn report_positions(&mut self) {
+        let mut position = 0;
+        for turn in 0.. {
+            let mut any = false;
+            for (_, jobs) in self.waiting.iter_mut() {
+                let Some(waiting) = jobs.get_mut(turn) else {
+                    continue;
+                };
+                any = true;
+                position += 1;
+                if waiting.position != position {
+                    waiting.position = position;
+                    let _ = waiting.events.send(Message::Queued {
+                        session: waiting.session,
+                        position,
+                    });
+                }
+            }
+            if !any {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,366 @@
+//! Hooks scripted in [Rhai](https://rhai.rs) on what happens on the server, for policies too
+//! particular to configure:
+//!
+//! ```toml
+//! [scripting]
+//! script = "/etc/shws/hooks.rhai"
+//! max_operations = 1000000
+//! ```
+//!
+//! The script defines whichever of these functions it needs:
+//!
+//! ```rhai
+//! // a client connecting to the WebSocket listener, after it authenticated
+//! fn on_connect(client) {
+//!     client.address.starts_with("10.") || client.user == "oncall"
+//! }
+//!
+//! // a session about to start a command, `command` has `command`, `args`, `cwd`, `env` and
+//! // `profile`
+//! fn on_command(client, session, command) {
+//!     if command.command.ends_with("/rm") {
+//!         throw "no rm on this box";
+//!     }
+//!     if command.profile == "deploy" {
+//!         notify(`${client.user} is deploying`);
+//!     }
+//!     #{ env: #{ SHWS_USER: client.user, SSH_AUTH_SOCK: () } }
+//! }
+//!
+//! // a piece of output of a session, returning a string sends that instead
+//! fn on_output_chunk(session, text) {
+//!     text.replace("hunter2", "*******");
+//!     text
+//! }
+//!
+//! // the command of a session exited, `exit` has `command`, `code` and `signal`
+//! fn on_exit(client, session, exit) {
+//!     if exit.code != 0 {
+//!         notify(`'${exit.command}' failed with ${exit.code}`);
+//!     }
+//! }
+//! ```
+//!
+//! `client` is the client as the [audit trail](crate::audit) has it, `session` the ID of the
+//! session. `on_connect` and `on_command` refuse what they return `false` for or throw on, the
+//! value thrown telling the client why. The `env` of the map `on_command` returns is applied on
+//! top of the environment the command was going to get, `()` removing a variable. `notify`
+//! sends a `script` [notification](crate::notify) to the webhooks and the MQTT broker.
+//!
+//! Hooks run to completion on the thread of the connection, so `max_operations` bounds how long
+//! one may take. Scripts failing otherwise refuse connections and commands, output and exits are
+//! let pass with a warning.
+//!
+//! Only built with the `scripting` feature.
+use crate::{
+    audit::Client,
+    command::{Environment, PolicyViolation, RunCommand},
+    data::SessionId,
+    filter::{OutputFilter, Utf8Boundaries},
+    notify::{Notification, NotifyEvent},
+    session::SessionRegistry,
+};
+use anyhow::{anyhow, Context, Result};
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Map, NativeCallContext, AST};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptingConfig {
+    /// The script defining the hooks
+    pub script: PathBuf,
+    /// Most operations a hook may take before it is aborted
+    #[serde(default = "default_max_operations")]
+    pub max_operations: u64,
+}
+
+fn default_max_operations() -> u64 {
+    1_000_000
+}
+
+/// What a script asked to be sent with `notify`
+pub struct Note {
+    pub client: Client,
+    pub session: Option<SessionId>,
+    pub message: String,
+}
+
+/// Whom a hook runs for, handed to `notify` as the tag of the call
+#[derive(Clone)]
+struct Caller {
+    client: Client,
+    session: Option<SessionId>,
+}
+
+/// The compiled script and the hooks it defines
+pub struct Scripts {
+    engine: Engine,
+    ast: AST,
+    hooks: HashSet<&'static str>,
+}
+
+impl Scripts {
+    /// Compile and run the script of `config`, handing what its hooks `notify` to `notes`
+    pub fn load(config: &ScriptingConfig, notes: mpsc::UnboundedSender<Note>) -> Result<Self> {
+        let err_context = || format!("failed to load script {}", config.script.display());
+
+        let mut engine = Engine::new();
+        engine.set_max_operations(config.max_operations);
+        engine.on_print(|text| info!("script: {}", text));
+        engine.on_debug(|text, _, position| debug!("script at {}: {}", position, text));
+        engine.register_fn("notify", move |context: NativeCallContext, message: &str| {
+            let Some(caller) = context.tag().and_then(|tag| tag.read_lock::<Caller>()) else {
+                return;
+            };
+            // the server is shutting down if nobody delivers them anymore
+            let _ = notes.send(Note {
+                client: caller.client.clone(),
+                session: caller.session,
+                message: message.to_string(),
+            });
+        });
+        let ast = engine
+            .compile_file(config.script.clone())
+            .map_err(|e| anyhow!("{}", e))
+            .with_context(err_context)?;
+        engine
+            .run_ast(&ast)
+            .map_err(|e| anyhow!("{}", e))
+            .with_context(err_context)?;
+        let hooks = [
+            ("on_connect", 1),
+            ("on_command", 3),
+            ("on_output_chunk", 2),
+            ("on_exit", 3),
+        ]
+        .into_iter()
+        .filter(|&(name, arity)| {
+            ast.iter_functions()
+                .any(|function| function.name == name && function.params.len() == arity)
+        })
+        .map(|(name, _)| name)
+        .collect::<HashSet<_>>();
+        info!("loaded script {} with hooks {:?}", config.script.display(), hooks);
+        Ok(Scripts { engine, ast, hooks })
+    }
+
+    /// Check with `on_connect` whether `client` may connect
+    pub fn on_connect(&self, client: &Client) -> Result<()> {
+        if !self.hooks.contains("on_connect") {
+            return Ok(());
+        }
+        let caller = Caller {
+            client: client.clone(),
+            session: None,
+        };
+        let returned = self.call("on_connect", caller, (client_map(client),));
+        match returned {
+            Ok(returned) if returned.as_bool() == Ok(false) => {
+                Err(anyhow!("connection refused by on_connect"))
+            },
+            Ok(_) => Ok(()),
+            Err(e) => Err(refusal("on_connect", *e).context("connection refused by on_connect")),
+        }
+    }
+
+    /// Check with `on_command` whether `client` may start `command` in session `id`, with
+    /// `profile` if it is one's, the environment it gets instead of `env`
+    pub fn on_command(
+        &self,
+        client: &Client,
+        id: SessionId,
+        profile: Option<&str>,
+        command: &RunCommand,
+        env: Environment,
+    ) -> Result<Environment> {
+        if !self.hooks.contains("on_command") {
+            return Ok(env);
+        }
+        let violation = |reason: String| PolicyViolation {
+            command: command.command.clone(),
+            rule: Some(format!("on_command: {}", reason)),
+        };
+        let mut map = Map::new();
+        map.insert("command".into(), command.command.display().to_string().into());
+        let args = command.args.iter().cloned().map(Dynamic::from).collect::<Vec<_>>();
+        map.insert("args".into(), args.into());
+        map.insert(
+            "cwd".into(),
+            command
+                .cwd
+                .as_ref()
+                .map_or(Dynamic::UNIT, |cwd| cwd.display().to_string().into()),
+        );
+        let vars = env
+            .resolve()
+            .into_iter()
+            .map(|(name, value)| (name.into(), value.into()))
+            .collect::<Map>();
+        map.insert("env".into(), vars.into());
+        map.insert(
+            "profile".into(),
+            profile.map_or(Dynamic::UNIT, |profile| profile.into()),
+        );
+        let caller = Caller {
+            client: client.clone(),
+            session: Some(id),
+        };
+        let args = (client_map(client), id.to_string(), map);
+        let returned = match self.call("on_command", caller, args) {
+            Ok(returned) => returned,
+            Err(e) => return Err(violation(refusal("on_command", *e).to_string()).into()),
+        };
+        if returned.as_bool() == Ok(false) {
+            return Err(violation("refused".to_string()).into());
+        }
+        let Some(vars) = returned
+            .try_cast::<Map>()
+            .and_then(|mut returned| returned.remove("env"))
+            .and_then(Dynamic::try_cast::<Map>)
+        else {
+            return Ok(env);
+        };
+        let mut overrides = Environment {
+            vars: BTreeMap::new(),
+            clear: false,
+            strip: vec![],
+        };
+        for (name, value) in vars {
+            match value.is_unit() {
+                true => overrides.strip.push(name.to_string()),
+                false => {
+                    overrides.vars.insert(name.to_string(), value.to_string());
+                },
+            }
+        }
+        Ok(env.overridden_by(&overrides))
+    }
+
+    /// The output filter running `on_output_chunk` for session `id` of `client`, if the script
+    /// defines it
+    pub fn output_filter(self: &Arc<Self>, client: &Client, id: SessionId) -> Option<ScriptFilter> {
+        self.hooks.contains("on_output_chunk").then(|| ScriptFilter {
+            scripts: self.clone(),
+            caller: Caller {
+                client: client.clone(),
+                session: Some(id),
+            },
+            boundaries: Utf8Boundaries::default(),
+        })
+    }
+
+    /// Tell `on_exit` that `command` of session `id` of `client` exited with `code` or was killed
+    /// by `signal`
+    pub fn on_exit(
+        &self,
+        client: &Client,
+        id: SessionId,
+        command: &RunCommand,
+        code: Option<i32>,
+        signal: Option<String>,
+    ) {
+        if !self.hooks.contains("on_exit") {
+            return;
+        }
+        let mut exit = Map::new();
+        exit.insert("command".into(), command.to_string().into());
+        exit.insert("code".into(), code.map_or(Dynamic::UNIT, |code| (code as i64).into()));
+        exit.insert("signal".into(), signal.map_or(Dynamic::UNIT, Dynamic::from));
+        let caller = Caller {
+            client: client.clone(),
+            session: Some(id),
+        };
+        let args = (client_map(client), id.to_string(), exit);
+        if let Err(e) = self.call("on_exit", caller, args) {
+            warn!("on_exit failed: {}", e);
+        }
+    }
+
+    fn call(
+        &self,
+        name: &str,
+        caller: Caller,
+        args: impl FuncArgs,
+    ) -> Result<Dynamic, Box<EvalAltResult>> {
+        let options = CallFnOptions::new().eval_ast(false).with_tag(caller);
+        self.engine
+            .call_fn_with_options(options, &mut Default::default(), &self.ast, name, args)
+    }
+}
+
+/// Passes the output of a session through `on_output_chunk`
+pub struct ScriptFilter {
+    scripts: Arc<Scripts>,
+    caller: Caller,
+    /// The hook gets whole characters
+    boundaries: Utf8Boundaries,
+}
+
+impl OutputFilter for ScriptFilter {
+    fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let chunk = self.boundaries.filter(data);
+        if chunk.is_empty() {
+            return chunk;
+        }
+        let session = self.caller.session.map(|id| id.to_string());
+        let text = String::from_utf8_lossy(&chunk).into_owned();
+        let args = (session.unwrap_or_default(), text);
+        match self
+            .scripts
+            .call("on_output_chunk", self.caller.clone(), args)
+        {
+            Ok(returned) if returned.is_string() => returned
+                .into_string()
+                .map_or(chunk, String::into_bytes),
+            Ok(_) => chunk,
+            Err(e) => {
+                warn!("on_output_chunk failed: {}", e);
+                chunk
+            },
+        }
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        self.boundaries.finish()
+    }
+}
+
+/// Send what scripts `notify` on to the webhooks and the MQTT broker of `registry`
+pub async fn deliver(mut notes: mpsc::UnboundedReceiver<Note>, registry: Arc<SessionRegistry>) {
+    while let Some(note) = notes.recv().await {
+        registry.notify(&Notification {
+            session: note.session,
+            message: Some(note.message),
+            ..Notification::new(NotifyEvent::Script, &note.client)
+        });
+    }
+}
+
+/// `client` as scripts see it, with `identity` along with what the audit trail keeps
+fn client_map(client: &Client) -> Dynamic {
+    let mut map = rhai::serde::to_dynamic(client)
+        .ok()
+        .and_then(Dynamic::try_cast::<Map>)
+        .unwrap_or_default();
+    map.insert("address".into(), client.address.ip().to_string().into());
+    map.insert("identity".into(), client.identity().into());
+    map.into()
+}
+
+/// Why a hook refused, the value it threw or else that it failed
+fn refusal(hook: &str, error: EvalAltResult) -> anyhow::Error {
+    match error {
+        EvalAltResult::ErrorRuntime(value, _) => anyhow!("{}", value),
+        e => {
+            warn!("{} failed: {}", hook, e);
+            anyhow!("script failed")
+        },
+    }
+}
@@ -0,0 +1,1349 @@
+//! WebSocket front end. Every accepted connection exchanges [`Message`]s with the server, encoded
+//! as JSON or, if the client connects with `?encoding=msgpack`, as MessagePack. MessagePack
+//! clients may also ask for their frames to be compressed with `?compression=zstd`, see
+//! [`Compression`]. It can host any number of PTY-backed shell sessions, see [`SessionManager`].
+//!
+//! Clients may offer the [`SUBPROTOCOL`] of the protocol version they speak in the upgrade request,
+//! which is then confirmed. Those offering only other versions are refused, as are those sending
+//! a [`Message::Hello`] of another version.
+//!
+//! Connections the server closes get a close frame saying why, see [`CloseReason`].
+//!
+//! The `permessage-deflate` extension isn't offered, tungstenite doesn't implement it. Clients
+//! asking for it in their handshake see it declined and talk uncompressed.
+//!
+//! With the `webtransport` feature the server accepts WebTransport sessions as well, serving
+//! their streams like WebSocket connections, see `webtransport`.
+use crate::{
+    admin::{self, AdminState},
+    audit::{AuditLog, Client},
+    auth::{self, Authenticator, Identity, Lockout, Tickets},
+    config::{Config, ListenerConfig},
+    data::{
+        CloseReason, Compression, Encoding, Message, CAPABILITIES, PROTOCOL_VERSION, SUBPROTOCOL,
+    },
+    error::{ErrorCode, FatalError, LoggableError, ProtocolError},
+    filter::{FilterContext, FilterRegistry, InputFilter, OutputFilter},
+    firewall::{self, AddressFilter},
+    forward::Forwards,
+    http,
+    limits::{ConnectionCounts, OpenConnection, RateLimiter},
+    listener::{Listener, Stream},
+    logging::{self, connection_span},
+    metrics::METRICS,
+    notify::{self, Notification, NotifyEvent},
+    proxy,
+    session::{RoundTrip, SessionManager, SessionRegistry},
+    subreaper,
+    systemd::Notifier,
+    tls::ReloadableAcceptor,
+    transfer::Transfers,
+    transport::{Frame, ReceiveError, Transport, WebSocket},
+};
+use anyhow::{anyhow, Context, Result};
+use hyper::{Body, Method, Request as HttpRequest, Response as HttpResponse};
+use std::{
+    future::{self, Future},
+    io,
+    iter,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    task::Poll,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch, Semaphore},
+    task::block_in_place,
+    time::{self, Instant, Interval, MissedTickBehavior},
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{
+            header::{
+                AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, HOST, ORIGIN, SEC_WEBSOCKET_PROTOCOL,
+                WWW_AUTHENTICATE,
+            },
+            HeaderName, HeaderValue, StatusCode,
+        },
+    },
+};
+use tracing::{info, warn, Instrument, Span};
+
+#[cfg(feature = "webtransport")]
+pub mod webtransport;
+
+/// How often sessions are checked for having been detached for too long
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long connections get to say goodbye to their clients when the server shuts down
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// How long the server stops accepting connections after failing to, which it mostly does for
+/// running out of file descriptors, rather than failing again right away
+const ACCEPT_ERROR_PAUSE: Duration = Duration::from_millis(100);
+
+pub struct Server {
+    config: Arc<Config>,
+    /// Checks of the addresses of clients besides the configured ones
+    address_filters: Vec<Arc<dyn AddressFilter>>,
+    /// The filters profiles can name
+    filters: FilterRegistry,
+    /// Reads the configuration again on `SIGHUP`, if it can be
+    reload: Option<Box<dyn Fn() -> Result<Config> + Send + Sync>>,
+}
+
+/// What all connections share. Connections made after the configuration was reloaded share
+/// another one, see [`Shared::reloaded`].
+struct Shared {
+    config: Arc<Config>,
+    registry: Arc<SessionRegistry>,
+    audit: Arc<AuditLog>,
+    /// Checks the tokens of clients, `None` if anybody may connect
+    authenticator: Option<Arc<dyn Authenticator>>,
+    /// How often clients may connect from the same address, if limited
+    ip_rate: Option<Arc<RateLimiter<IpAddr>>>,
+    /// How often each token may be used to connect, if limited
+    token_rate: Option<Arc<RateLimiter<String>>>,
+    /// Connections open from each address, to hold them to the configured maximum
+    connection_counts: Arc<ConnectionCounts>,
+    /// Issued to clients connecting without presenting their token, if configured
+    tickets: Option<Arc<Tickets>>,
+    /// Clients that failed to authenticate too often, if they are locked out
+    lockout: Option<Arc<Lockout>>,
+    /// Decide whom connections are accepted from, the configured networks first
+    address_filters: Vec<Arc<dyn AddressFilter>>,
+    /// Set once the server shuts down, connections close when they see it
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl Shared {
+    /// What connections made from now on share once `config` was loaded. Rate limits, tickets
+    /// and lockouts carry over unless their settings changed.
+    fn reloaded(&self, config: Arc<Config>) -> Result<Shared> {
+        let (old, new) = (&self.config, &config);
+        let ip_rate = match old.limits.connections_per_ip == new.limits.connections_per_ip {
+            true => self.ip_rate.clone(),
+            false => new.limits.connections_per_ip.map(RateLimiter::new).map(Arc::new),
+        };
+        let token_rate = match old.limits.connections_per_token == new.limits.connections_per_token
+        {
+            true => self.token_rate.clone(),
+            false => new
+                .limits
+                .connections_per_token
+                .map(RateLimiter::new)
+                .map(Arc::new),
+        };
+        let tickets = match old.auth.ticket_ttl == new.auth.ticket_ttl {
+            true => self.tickets.clone(),
+            false => new.auth.ticket_ttl.map(tickets),
+        };
+        let lockout = match old.auth.lockout == new.auth.lockout {
+            true => self.lockout.clone(),
+            false => new.auth.lockout.clone().map(Lockout::new).map(Arc::new),
+        };
+        // those the server was given follow the configured networks
+        let address_filters = iter::once(Arc::new(new.addresses.clone()) as Arc<_>)
+            .chain(self.address_filters.iter().skip(1).cloned())
+            .collect();
+        Ok(Shared {
+            authenticator: auth::authenticator(new)?.map(Arc::from),
+            ip_rate,
+            token_rate,
+            connection_counts: self.connection_counts.clone(),
+            tickets,
+            lockout,
+            address_filters,
+            config,
+            registry: self.registry.clone(),
+            audit: self.audit.clone(),
+            shutdown: self.shutdown.clone(),
+        })
+    }
+}
+
+impl Shared {
+    /// What connections to the additional listener `listener` share, under its policies
+    fn for_listener(&self, listener: &ListenerConfig) -> Result<Shared> {
+        let mut shared = self.reloaded(Arc::new(self.config.for_listener(listener)))?;
+        if listener.auth.is_some() {
+            // only good for the listener that issued them
+            shared.tickets = shared.config.auth.ticket_ttl.map(tickets);
+        }
+        Ok(shared)
+    }
+}
+
+/// Tickets valid for `ttl` seconds
+fn tickets(ttl: u64) -> Arc<Tickets> {
+    Arc::new(Tickets::new(Duration::from_secs(ttl)))
+}
+
+impl Server {
+    pub fn new(config: Config) -> Self {
+        Server {
+            config: Arc::new(config),
+            address_filters: vec![],
+            filters: FilterRegistry::default(),
+            reload: None,
+        }
+    }
+
+    /// Also turn away the clients `filter` refuses
+    pub fn with_address_filter(mut self, filter: impl AddressFilter + 'static) -> Self {
+        self.address_filters.push(Arc::new(filter));
+        self
+    }
+
+    /// Take over the configuration `load` returns on `SIGHUP`, see [`config`](crate::config)
+    pub fn with_reload<F>(mut self, load: F) -> Self
+    where
+        F: Fn() -> Result<Config> + Send + Sync + 'static,
+    {
+        self.reload = Some(Box::new(load));
+        self
+    }
+
+    /// Let profiles name the output filters `factory` makes `name`, see [`filter`](crate::filter)
+    pub fn with_output_filter<F>(mut self, name: &str, factory: F) -> Self
+    where
+        F: Fn(&FilterContext) -> Box<dyn OutputFilter> + Send + Sync + 'static,
+    {
+        self.filters.register_output(name, factory);
+        self
+    }
+
+    /// Let profiles name the input filters `factory` makes `name`
+    pub fn with_input_filter<F>(mut self, name: &str, factory: F) -> Self
+    where
+        F: Fn(&FilterContext) -> Box<dyn InputFilter> + Send + Sync + 'static,
+    {
+        self.filters.register_input(name, factory);
+        self
+    }
+
+    /// Accept connections until the process receives `SIGINT` or `SIGTERM`. `SIGHUP` reloads the
+    /// TLS certificates and the configuration, if there is a way to.
+    pub async fn run(self) -> Result<()> {
+        let filters = self.filters;
+        #[cfg(feature = "plugins")]
+        let plugins = crate::plugin::load_all(&self.config.plugins)?;
+        #[cfg(feature = "plugins")]
+        let filters = plugins
+            .values()
+            .fold(filters, |filters, plugin| plugin.register_filters(filters));
+        filters.check(&self.config)?;
+        let tls = match self.config.tls.as_ref() {
+            Some(tls) => Some(Arc::new(ReloadableAcceptor::new(tls.clone())?)),
+            None => None,
+        };
+        let mut listeners = vec![Listener::open(&self.config).await?];
+        for listener in self.config.listeners.iter() {
+            listeners.push(Listener::bind(listener, tls.is_some()).await?);
+        }
+
+        let limits = &self.config.limits;
+        let registry = SessionRegistry::new(limits.total_bandwidth, limits.max_running_commands)
+            .with_filters(filters.clone());
+        #[cfg(feature = "plugins")]
+        let registry = registry.with_plugins(plugins);
+        #[cfg(feature = "history")]
+        let registry = match self.config.history.as_ref() {
+            Some(history) => registry.with_history(crate::history::History::open(history)?),
+            None => registry,
+        };
+        let registry = match self.config.webhooks.is_empty() {
+            true => registry,
+            false => registry.with_notifier(notify::Notifier::new(&self.config.webhooks)?),
+        };
+        #[cfg(feature = "mqtt")]
+        let (registry, mqtt) = match self.config.mqtt.as_ref() {
+            Some(mqtt) => {
+                let (bridge, publisher) = crate::mqtt::MqttBridge::new(mqtt)?;
+                (registry.with_mqtt(publisher), Some(bridge))
+            },
+            None => (registry, None),
+        };
+        #[cfg(feature = "scripting")]
+        let (registry, notes) = match self.config.scripting.as_ref() {
+            Some(scripting) => {
+                let (notes_tx, notes_rx) = mpsc::unbounded_channel();
+                let scripts = crate::scripting::Scripts::load(scripting, notes_tx)?;
+                (registry.with_scripts(Arc::new(scripts)), Some(notes_rx))
+            },
+            None => (registry, None),
+        };
+        let registry = Arc::new(registry);
+        #[cfg(feature = "scripting")]
+        if let Some(notes) = notes {
+            tokio::spawn(crate::scripting::deliver(notes, registry.clone()));
+        }
+        let shared = Arc::new(Shared {
+            config: self.config.clone(),
+            registry: registry.clone(),
+            audit: Arc::new(AuditLog::open(&self.config.audit, &self.config.redaction)?),
+            authenticator: auth::authenticator(&self.config)?.map(Arc::from),
+            ip_rate: self
+                .config
+                .limits
+                .connections_per_ip
+                .map(RateLimiter::new)
+                .map(Arc::new),
+            token_rate: self
+                .config
+                .limits
+                .connections_per_token
+                .map(RateLimiter::new)
+                .map(Arc::new),
+            connection_counts: Arc::default(),
+            tickets: self.config.auth.ticket_ttl.map(tickets),
+            lockout: self
+                .config
+                .auth
+                .lockout
+                .clone()
+                .map(|lockout| Arc::new(Lockout::new(lockout))),
+            address_filters: iter::once(Arc::new(self.config.addresses.clone()) as Arc<_>)
+                .chain(self.address_filters)
+                .collect(),
+            shutdown: Arc::new(watch::channel(false).0),
+        });
+        let connections = Arc::new(Semaphore::new(max_connections(&self.config)));
+        METRICS.coalescing_window.set(coalescing_window(&self.config));
+        if let Err(e) = subreaper::start() {
+            warn!("{:#}, processes orphaned by commands are left to init", e);
+        }
+        if let Some(admin) = self.config.admin.as_ref() {
+            let state = AdminState {
+                config: self.config.clone(),
+                registry: registry.clone(),
+                audit: shared.audit.clone(),
+                connections: connections.clone(),
+            };
+            admin::spawn(admin.listen, state)?;
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = mqtt {
+            tokio::spawn(mqtt.run(self.config.clone(), registry.clone(), shared.audit.clone()));
+        }
+        #[cfg(feature = "grpc")]
+        if let Some(grpc) = self.config.grpc.as_ref() {
+            let service = crate::grpc::GrpcService::new(
+                self.config.clone(),
+                registry.clone(),
+                shared.audit.clone(),
+                shared.authenticator.clone(),
+                shared.lockout.clone(),
+                shared.shutdown.subscribe(),
+            );
+            service.spawn(grpc.listen)?;
+        }
+        let mut contexts = contexts(&shared, &listeners)?;
+        // what sessions of the WebTransport listener share, changing as the configuration does
+        #[cfg(feature = "webtransport")]
+        let current = watch::channel(contexts[0].clone()).0;
+        #[cfg(feature = "webtransport")]
+        if let (Some(webtransport), Some(tls)) = (&self.config.webtransport, &self.config.tls) {
+            let shared = current.subscribe();
+            webtransport::spawn(webtransport, tls, shared, connections.clone()).await?;
+        }
+        let mut hangups =
+            signal(SignalKind::hangup()).context("failed to install SIGHUP handler")?;
+        let mut terminations =
+            signal(SignalKind::terminate()).context("failed to install SIGTERM handler")?;
+        let notifier = Notifier::from_env();
+        let mut watchdog = notifier.watchdog_interval().map(time::interval);
+        notifier.notify("READY=1");
+        let mut reaping = time::interval(REAP_INTERVAL);
+        loop {
+            tokio::select! {
+                (i, accepted) = accept(&listeners) => match accepted {
+                    Ok((mut stream, peer)) => {
+                        let permit = match connections.clone().try_acquire_owned() {
+                            Ok(permit) => permit,
+                            Err(_) => {
+                                warn!("turning away {}, too many connections", peer);
+                                METRICS.connection_rejected("max_connections");
+                                continue;
+                            },
+                        };
+                        let shared = contexts[i].clone();
+                        let handshake = Instant::now() + shared.config.limits.handshake_timeout();
+                        // the proxy in front of the Unix socket did the TLS handshake already
+                        let tls = tls.clone().filter(|_| {
+                            matches!(stream, Stream::Tcp(_)) && !listeners[i].plaintext()
+                        });
+                        tokio::spawn(async move {
+                            let Some(peer) =
+                                proxied_address(&shared, &mut stream, peer, handshake).await
+                            else {
+                                return;
+                            };
+                            async move {
+                                let mut counted = None;
+                                // trusted proxies are checked by the clients they forward
+                                if !shared.config.proxy.trusts(peer.ip()) {
+                                    let Some(admitted) = admit(&shared, peer) else {
+                                        return;
+                                    };
+                                    counted = Some(admitted);
+                                }
+                                let _ = accept_connection(shared, tls, stream, peer, handshake)
+                                    .await
+                                    .to_log();
+                                drop(counted);
+                            }
+                            .instrument(connection_span(peer))
+                            .await;
+                            drop(permit);
+                        });
+                    },
+                    Err(e) => {
+                        warn!("failed to accept connection: {}", e);
+                        time::sleep(ACCEPT_ERROR_PAUSE).await;
+                    },
+                },
+                _ = reaping.tick() => {
+                    let config = &contexts[0].config;
+                    if let Some(ttl) = config.sessions.session_ttl() {
+                        registry.reap_detached(ttl);
+                    }
+                    registry.check_idle(&config.sessions);
+                },
+                _ = hangups.recv() => {
+                    if let Some(tls) = tls.as_ref() {
+                        tls.reload().non_fatal();
+                    }
+                    if let Some(load) = self.reload.as_ref() {
+                        let reloaded = load().and_then(|config| {
+                            reload(&contexts[0], config, &listeners, &filters, &connections)
+                        });
+                        reloaded.map(|reloaded| contexts = reloaded).non_fatal();
+                        #[cfg(feature = "webtransport")]
+                        current.send_replace(contexts[0].clone());
+                    }
+                },
+                // pinged from here, a loop that is stuck stops pinging
+                _ = tick(&mut watchdog) => notifier.notify("WATCHDOG=1"),
+                _ = tokio::signal::ctrl_c() => break shut_down(&shared, &notifier).await,
+                _ = terminations.recv() => break shut_down(&shared, &notifier).await,
+            }
+        }
+    }
+}
+
+/// What the connections to each of `listeners` share: `shared` for the server's own listener,
+/// the others serve their clients under policies of their own
+fn contexts(shared: &Arc<Shared>, listeners: &[Listener]) -> Result<Vec<Arc<Shared>>> {
+    listeners
+        .iter()
+        .map(|listener| {
+            let Some(name) = listener.name() else {
+                return Ok(shared.clone());
+            };
+            let config = shared
+                .config
+                .listeners
+                .iter()
+                .find(|config| config.name == name)
+                .ok_or_else(|| anyhow!("listener '{}' is gone, removing it takes a restart", name))?;
+            shared.for_listener(config).map(Arc::new)
+        })
+        .collect()
+}
+
+/// The next connection to any of `listeners`, along with the index of the listener
+async fn accept(listeners: &[Listener]) -> (usize, io::Result<(Stream, SocketAddr)>) {
+    future::poll_fn(|cx| {
+        for (i, listener) in listeners.iter().enumerate() {
+            if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                return Poll::Ready((i, accepted));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Take over `config` for the connections made from now on, what the connections to each of
+/// `listeners` share then. The logging filter and the connection limit change right away, what
+/// the server set up when it started stays as it is.
+fn reload(
+    shared: &Shared,
+    config: Config,
+    listeners: &[Listener],
+    filters: &FilterRegistry,
+    connections: &Arc<Semaphore>,
+) -> Result<Vec<Arc<Shared>>> {
+    let err_context = || "failed to reload config";
+
+    filters.check(&config).with_context(err_context)?;
+    let reloaded = shared
+        .reloaded(Arc::new(config))
+        .map(Arc::new)
+        .with_context(err_context)?;
+    let contexts = contexts(&reloaded, listeners).with_context(err_context)?;
+    logging::set_filter(&reloaded.config.log).with_context(err_context)?;
+    limit_connections(
+        connections,
+        max_connections(&shared.config),
+        max_connections(&reloaded.config),
+    );
+    METRICS.coalescing_window.set(coalescing_window(&reloaded.config));
+    for setting in shared.config.restart_needed(&reloaded.config) {
+        warn!("changing {} takes a restart", setting);
+    }
+    info!("reloaded config");
+    Ok(contexts)
+}
+
+/// Seconds output is waited for after each read, for the metrics
+fn coalescing_window(config: &Config) -> f64 {
+    config.coalescing.window().unwrap_or_default().as_secs_f64()
+}
+
+/// How many connections `config` lets in at once
+fn max_connections(config: &Config) -> usize {
+    config
+        .limits
+        .max_connections
+        .unwrap_or(Semaphore::MAX_PERMITS)
+}
+
+/// Let `max` connections in at once rather than `old`. Lowering the limit below the connections
+/// open takes effect once enough of them closed.
+fn limit_connections(connections: &Arc<Semaphore>, old: usize, max: usize) {
+    if max >= old {
+        connections.add_permits(max - old);
+        return;
+    }
+    let missing = old - max - connections.forget_permits(old - max);
+    if missing == 0 {
+        return;
+    }
+    let connections = connections.clone();
+    tokio::spawn(async move {
+        let missing = u32::try_from(missing).unwrap_or(u32::MAX);
+        if let Ok(permits) = connections.acquire_many_owned(missing).await {
+            permits.forget();
+        }
+    });
+}
+
+/// Close all connections, giving them a moment to say goodbye
+async fn shut_down(shared: &Shared, notifier: &Notifier) -> Result<()> {
+    info!("shutting down");
+    notifier.notify("STOPPING=1");
+    shared.shutdown.send_replace(true);
+    let _ = time::timeout(SHUTDOWN_GRACE_PERIOD, shared.shutdown.closed()).await;
+    Ok(())
+}
+
+/// Resolves once `interval` ticks, never if there is none
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        },
+        None => future::pending().await,
+    }
+}
+
+/// Whether the client at `peer` may connect, by its address, how often it did and how many
+/// connections it has open. Its connection counts as open until the returned guard is dropped.
+fn admit(shared: &Shared, peer: SocketAddr) -> Option<OpenConnection> {
+    if let Err(e) = firewall::check_address(&shared.address_filters, peer.ip()) {
+        warn!("turning away {}, {:#}", peer, e);
+        return None;
+    }
+    let ip_rate = shared.ip_rate.as_ref();
+    if ip_rate.is_some_and(|rate| !rate.allow(peer.ip())) {
+        warn!("turning away {}, connecting too often", peer);
+        METRICS.rate_limited.inc();
+        return None;
+    }
+    let max = shared.config.limits.max_connections_per_ip;
+    let counted = shared.connection_counts.open(peer.ip(), max);
+    if counted.is_none() {
+        warn!("turning away {}, too many connections from its address", peer);
+        METRICS.connection_rejected("max_connections_per_ip");
+    }
+    counted
+}
+
+/// Wait for `step` of the handshake with a client, giving up at `deadline`
+async fn in_time<T, E>(deadline: Instant, step: impl Future<Output = Result<T, E>>) -> Result<T>
+where
+    E: Into<anyhow::Error>,
+{
+    match time::timeout_at(deadline, step).await {
+        Ok(done) => done.map_err(Into::into),
+        Err(_) => {
+            METRICS.connection_rejected("handshake_timeout");
+            Err(anyhow!("handshake took too long"))
+        },
+    }
+}
+
+/// The address of the client connecting from `peer`, as the PROXY protocol header at the start
+/// of `stream` tells it if the server expects one. `None` if it doesn't have one by `deadline`,
+/// or if `peer` isn't a trusted proxy.
+async fn proxied_address(
+    shared: &Shared,
+    stream: &mut Stream,
+    peer: SocketAddr,
+    deadline: Instant,
+) -> Option<SocketAddr> {
+    let Stream::Tcp(tcp) = stream else {
+        return Some(peer);
+    };
+    if !shared.config.proxy.protocol {
+        return Some(peer);
+    }
+    // the header could claim any address, trusted proxies' included
+    if !shared.config.proxy.trusts(peer.ip()) {
+        warn!("turning away {}: not a trusted proxy", peer);
+        METRICS.connection_rejected("untrusted_proxy");
+        return None;
+    }
+    match in_time(deadline, proxy::read_header(tcp)).await {
+        Ok(proxied) => Some(proxied.unwrap_or(peer)),
+        Err(e) => {
+            warn!("turning away {}: {:#}", peer, e);
+            None
+        },
+    }
+}
+
+/// Run the TLS handshake if the server is set up for it, then serve the connection. The client
+/// has to be done with the handshakes by `handshake`.
+async fn accept_connection(
+    shared: Arc<Shared>,
+    tls: Option<Arc<ReloadableAcceptor>>,
+    stream: Stream,
+    peer: SocketAddr,
+    handshake: Instant,
+) -> Result<()> {
+    let mut client = Client::new(peer);
+    match tls {
+        Some(tls) => {
+            let stream = in_time(handshake, tls.acceptor()?.accept(stream))
+                .await
+                .with_context(|| format!("TLS handshake with {} failed", peer))?;
+            if let Some(certificate) = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(<[_]>::first)
+            {
+                client = client.with_certificate(certificate);
+            }
+            handle_connection(shared, stream, client, handshake).await
+        },
+        None => handle_connection(shared, stream, client, handshake).await,
+    }
+}
+
+/// The value of query parameter `key` of the upgrade request
+fn query_param<'a>(request: &'a Request, key: &str) -> Option<&'a str> {
+    request
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value)
+}
+
+/// The [`Encoding`] requested with the `encoding` query parameter of the upgrade request, JSON if
+/// the client didn't ask for one.
+fn requested_encoding(request: &Request) -> Result<Encoding, String> {
+    match query_param(request, "encoding") {
+        Some(value) => value.parse(),
+        None => Ok(Encoding::default()),
+    }
+}
+
+/// Compression the upgrade request asks for, only binary frames can be compressed
+fn requested_compression(request: &Request, encoding: Encoding) -> Result<Compression, String> {
+    let compression = match query_param(request, "compression") {
+        Some(value) => value.parse()?,
+        None => Compression::default(),
+    };
+    if compression != Compression::None && encoding != Encoding::MsgPack {
+        return Err(format!(
+            "{} compression needs the msgpack encoding",
+            compression
+        ));
+    }
+    Ok(compression)
+}
+
+/// Whether the upgrade request offers [`SUBPROTOCOL`]. Requests offering only other versions of
+/// it are refused, other subprotocols are left to be declined.
+fn offers_subprotocol(request: &Request) -> Result<bool, String> {
+    let offered: Vec<&str> = request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    if offered.contains(&SUBPROTOCOL) {
+        return Ok(true);
+    }
+    match offered.iter().find(|offered| offered.starts_with("shws.")) {
+        Some(other) => Err(format!(
+            "unsupported subprotocol {}, server speaks {}",
+            other, SUBPROTOCOL
+        )),
+        None => Ok(false),
+    }
+}
+
+/// Check the origin of the page the upgrade request comes from, if it comes from a browser, so
+/// that other sites' pages can't use the server on behalf of their visitors
+fn check_origin(request: &Request, config: &Config) -> Result<()> {
+    let header = |name: HeaderName| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    match header(ORIGIN) {
+        Some(origin) => config.origins.check(origin, header(HOST)),
+        None => Ok(()),
+    }
+}
+
+/// Check whether `client` is locked out for failing to authenticate too often, see [`Lockout`]
+fn check_lockout(shared: &Shared, client: &Client, what: &str) -> anyhow::Result<()> {
+    let checked = shared
+        .lockout
+        .as_ref()
+        .map_or(Ok(()), |lockout| lockout.check(client));
+    if let Err(e) = checked.as_ref() {
+        warn!("rejecting {}, {}", what, e);
+        METRICS.auth_rejections.inc();
+    }
+    checked
+}
+
+/// Count and report the failure `e` of `client` to authenticate
+fn reject_authentication(shared: &Shared, client: &Client, what: &str, e: &anyhow::Error) {
+    warn!("rejecting {}, not authorized: {:#}", what, e);
+    METRICS.auth_rejections.inc();
+    if let Some(lockout) = shared.lockout.as_ref() {
+        lockout.fail(client, &shared.audit);
+    }
+    shared.registry.notify(&Notification {
+        message: Some(format!("{:#}", e)),
+        ..Notification::new(NotifyEvent::AuthFailed, client)
+    });
+}
+
+/// Serve a request to the listener that doesn't ask for an upgrade, see [`http`]
+fn handle_http_request(
+    shared: &Shared,
+    client: &Client,
+    request: HttpRequest<Body>,
+) -> HttpResponse<Body> {
+    let mut client = client.clone();
+    let proxy = &shared.config.proxy;
+    if proxy.trusts(client.address.ip()) {
+        client.address = proxy.client_address(client.address, request.headers());
+        if let Err(e) = firewall::check_address(&shared.address_filters, client.address.ip()) {
+            warn!("refusing request for {}, {:#}", request.uri(), e);
+            return http::respond_text(StatusCode::FORBIDDEN, "not allowed from this address\n");
+        }
+    }
+    let origins = &shared.config.origins;
+    http::cors(request, origins, "POST", "authorization", |request| {
+        route_http_request(shared, &client, request)
+    })
+}
+
+fn route_http_request(
+    shared: &Shared,
+    client: &Client,
+    request: HttpRequest<Body>,
+) -> HttpResponse<Body> {
+    match (request.method(), request.uri().path()) {
+        (&Method::POST, "/tickets") => issue_ticket(shared, client, &request),
+        #[cfg(feature = "web")]
+        _ => crate::web::respond(&request),
+        #[cfg(not(feature = "web"))]
+        _ => http::respond_text(
+            StatusCode::UPGRADE_REQUIRED,
+            "only WebSocket connections are served here\n",
+        ),
+    }
+}
+
+/// Trade the token `request` presents for a ticket, see [`Tickets`]
+fn issue_ticket(
+    shared: &Shared,
+    client: &Client,
+    request: &HttpRequest<Body>,
+) -> HttpResponse<Body> {
+    let (Some(authenticator), Some(tickets)) =
+        (shared.authenticator.as_deref(), shared.tickets.as_ref())
+    else {
+        return http::respond_text(StatusCode::NOT_FOUND, "not found\n");
+    };
+    if let Err(e) = check_lockout(shared, client, "ticket request") {
+        return http::respond_text(StatusCode::TOO_MANY_REQUESTS, format!("{}\n", e));
+    }
+    let peer = client.address;
+    let issued = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| anyhow!("no token presented"))
+        .and_then(|token| {
+            let identity = block_in_place(|| authenticator.authenticate(token, peer))?;
+            tickets.issue(token, identity, peer.ip())
+        });
+    match issued {
+        Ok(ticket) => {
+            if let Some(lockout) = shared.lockout.as_ref() {
+                lockout.succeed(client);
+            }
+            let body = serde_json::json!({
+                "ticket": ticket,
+                "expires_in": tickets.ttl().as_secs(),
+            });
+            HttpResponse::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .header(CACHE_CONTROL, "no-store")
+                .body(Body::from(body.to_string() + "\n"))
+                .expect("valid response")
+        },
+        Err(e) => {
+            reject_authentication(shared, client, "ticket request", &e);
+            let mut response =
+                http::respond_text(StatusCode::UNAUTHORIZED, "missing or invalid token\n");
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            response
+        },
+    }
+}
+
+/// The token the upgrade request carries, either as bearer token or as `token` query parameter
+fn presented_token(request: &Request) -> Option<&str> {
+    let bearer = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    bearer.or_else(|| query_param(request, "token"))
+}
+
+/// Refuse the upgrade with `status`, telling the client why in a JSON encoded [`ProtocolError`]
+fn reject_upgrade(status: StatusCode, code: ErrorCode, reason: String) -> ErrorResponse {
+    // serializing a struct of strings doesn't fail
+    let body = serde_json::to_string(&ProtocolError::new(code, reason)).unwrap_or_default();
+    let mut response = ErrorResponse::new(Some(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+/// What a client is let in with, see [`check_request`]
+struct Admission {
+    /// The token the client presented and who it belongs to, `None` if anybody may connect
+    authenticated: Option<(String, Identity)>,
+    encoding: Encoding,
+    compression: Compression,
+}
+
+/// Why a client isn't let in, and what to answer its request with
+struct Rejection {
+    status: StatusCode,
+    code: ErrorCode,
+    reason: String,
+}
+
+impl Rejection {
+    fn new(status: StatusCode, code: ErrorCode, reason: impl Into<String>) -> Self {
+        Rejection {
+            status,
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Whether `client` may connect with `request`, by the origin of the page it comes from, its
+/// token and what scripts say, and with which encoding and compression
+fn check_request(
+    shared: &Shared,
+    client: &Client,
+    request: &Request,
+) -> Result<Admission, Rejection> {
+    let peer = client.address;
+    if let Err(e) = check_origin(request, &shared.config) {
+        warn!("rejecting connection, {}", e);
+        shared.registry.notify(&Notification {
+            message: Some(e.to_string()),
+            ..Notification::new(NotifyEvent::PolicyViolation, client)
+        });
+        return Err(Rejection::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::PolicyViolation,
+            e.to_string(),
+        ));
+    }
+    let mut authenticated = None;
+    if let Some(authenticator) = shared.authenticator.as_deref() {
+        if let Err(e) = check_lockout(shared, client, "connection") {
+            return Err(Rejection::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                ErrorCode::RateLimited,
+                e.to_string(),
+            ));
+        }
+        let ticket = query_param(request, "ticket");
+        let checked = match (ticket, shared.tickets.as_ref()) {
+            (Some(ticket), Some(tickets)) => tickets.redeem(ticket, peer.ip()),
+            _ => presented_token(request)
+                .ok_or_else(|| anyhow!("no token presented"))
+                .and_then(|token| {
+                    // checking a token may take a while, running a command especially
+                    let identity = block_in_place(|| authenticator.authenticate(token, peer))?;
+                    Ok((token.to_string(), identity))
+                }),
+        };
+        let (token, identity) = match checked {
+            Ok(checked) => {
+                if let Some(lockout) = shared.lockout.as_ref() {
+                    lockout.succeed(client);
+                }
+                checked
+            },
+            Err(e) => {
+                reject_authentication(shared, client, "connection", &e);
+                return Err(Rejection::new(
+                    StatusCode::UNAUTHORIZED,
+                    ErrorCode::AuthFailed,
+                    "missing or invalid token",
+                ));
+            },
+        };
+        let token_rate = shared.token_rate.as_ref();
+        if token_rate.is_some_and(|rate| !rate.allow(token.clone())) {
+            warn!("rejecting connection, token used too often");
+            METRICS.rate_limited.inc();
+            return Err(Rejection::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                ErrorCode::RateLimited,
+                "too many connections with this token",
+            ));
+        }
+        authenticated = Some((token, identity));
+    }
+    #[cfg(feature = "scripting")]
+    if let Some(scripts) = shared.registry.scripts() {
+        let client = match authenticated.as_ref() {
+            Some((token, identity)) => {
+                client.clone().with_token(token).with_identity(identity.clone())
+            },
+            None => client.clone(),
+        };
+        if let Err(e) = block_in_place(|| scripts.on_connect(&client)) {
+            warn!("rejecting connection, {:#}", e);
+            shared.registry.notify(&Notification {
+                message: Some(format!("{:#}", e)),
+                ..Notification::new(NotifyEvent::PolicyViolation, &client)
+            });
+            return Err(Rejection::new(
+                StatusCode::FORBIDDEN,
+                ErrorCode::PolicyViolation,
+                format!("{:#}", e),
+            ));
+        }
+    }
+    let encoding = requested_encoding(request)
+        .map_err(|e| Rejection::new(StatusCode::BAD_REQUEST, ErrorCode::BadRequest, e))?;
+    let compression = requested_compression(request, encoding)
+        .map_err(|e| Rejection::new(StatusCode::BAD_REQUEST, ErrorCode::BadRequest, e))?;
+    Ok(Admission {
+        authenticated,
+        encoding,
+        compression,
+    })
+}
+
+/// Encode `message` into a text frame for JSON and a binary frame, compressed with
+/// `compression`, for MessagePack
+fn encode_frame(encoding: Encoding, compression: Compression, message: &Message) -> Result<Frame> {
+    let bytes = encoding.encode(message)?;
+    Ok(match encoding {
+        Encoding::Json => Frame::Text(String::from_utf8(bytes)?),
+        Encoding::MsgPack => Frame::Binary(compression.compress(bytes)?),
+    })
+}
+
+// the handshake callback has to return tungstenite's `ErrorResponse`, however large it is
+#[allow(clippy::result_large_err)]
+async fn handle_connection<S>(
+    shared: Arc<Shared>,
+    stream: S,
+    mut client: Client,
+    handshake: Instant,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let config = &shared.config;
+    let peer = client.address;
+    let err_context = || format!("failed to serve connection from {}", peer);
+
+    let upgrade = in_time(handshake, http::peek_upgrade(stream)).await;
+    let stream = match upgrade.with_context(err_context)? {
+        (true, stream) => stream,
+        (false, stream) => {
+            let shared = shared.clone();
+            let handle = move |request| handle_http_request(&shared, &client, request);
+            return http::serve(stream, handle).await.with_context(err_context);
+        },
+    };
+
+    let mut encoding = Encoding::default();
+    let mut compression = Compression::default();
+    let mut authenticated = None;
+    let mut counted = None;
+    let handshaken = accept_hdr_async(stream, |request: &Request, mut response: Response| {
+        // connections of trusted proxies are limited by the address of the client they forward
+        let proxy = &config.proxy;
+        if proxy.trusts(peer.ip()) {
+            client.address = proxy.client_address(peer, request.headers());
+            if client.address != peer {
+                info!("forwarded for {}", client.address);
+            }
+            let filters = &shared.address_filters;
+            if let Err(e) = firewall::check_address(filters, client.address.ip()) {
+                warn!("rejecting connection, {:#}", e);
+                return Err(reject_upgrade(
+                    StatusCode::FORBIDDEN,
+                    ErrorCode::PolicyViolation,
+                    "connecting from this address is not allowed".to_string(),
+                ));
+            }
+            let ip_rate = shared.ip_rate.as_ref();
+            if ip_rate.is_some_and(|rate| !rate.allow(client.address.ip())) {
+                warn!("rejecting connection, connecting too often");
+                METRICS.rate_limited.inc();
+                return Err(reject_upgrade(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    ErrorCode::RateLimited,
+                    "too many connections from this address".to_string(),
+                ));
+            }
+            let max = config.limits.max_connections_per_ip;
+            counted = shared.connection_counts.open(client.address.ip(), max);
+            if counted.is_none() {
+                warn!("rejecting connection, too many connections open from this address");
+                METRICS.connection_rejected("max_connections_per_ip");
+                return Err(reject_upgrade(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    ErrorCode::RateLimited,
+                    "too many connections open from this address".to_string(),
+                ));
+            }
+        }
+        match offers_subprotocol(request) {
+            Ok(true) => {
+                response.headers_mut().insert(
+                    SEC_WEBSOCKET_PROTOCOL,
+                    HeaderValue::from_static(SUBPROTOCOL),
+                );
+            },
+            Ok(false) => {},
+            Err(e) => {
+                warn!("rejecting connection, {}", e);
+                return Err(reject_upgrade(
+                    StatusCode::BAD_REQUEST,
+                    ErrorCode::UnsupportedVersion,
+                    e,
+                ));
+            },
+        }
+        match check_request(&shared, &client, request) {
+            Ok(admission) => {
+                authenticated = admission.authenticated;
+                encoding = admission.encoding;
+                compression = admission.compression;
+                Ok(response)
+            },
+            Err(rejection) => Err(reject_upgrade(
+                rejection.status,
+                rejection.code,
+                rejection.reason,
+            )),
+        }
+    });
+    let ws = in_time(handshake, handshaken).await.with_context(err_context)?;
+    // the connection of the forwarded client counts until it is closed
+    let _counted = counted;
+    let (client, expires) = authenticated_client(client, authenticated);
+    let transport = WebSocket::new(ws);
+    serve(&shared, transport, client, encoding, compression, expires)
+        .await
+        .with_context(err_context)
+}
+
+/// `client` with the token it presented and who that belongs to, if it had to authenticate, and
+/// when its token expires
+fn authenticated_client(
+    client: Client,
+    authenticated: Option<(String, Identity)>,
+) -> (Client, Option<Instant>) {
+    let Some((token, identity)) = authenticated else {
+        return (client, None);
+    };
+    let expires = identity.expires.map(|expires| {
+        Instant::now()
+            + expires
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+    });
+    (client.with_token(&token).with_identity(identity), expires)
+}
+
+/// Serve the connection of `client` over `transport`, exchanging messages encoded with
+/// `encoding` and `compression` until either side closes it, or until `expires` if the token of
+/// the client expires
+async fn serve(
+    shared: &Shared,
+    mut transport: impl Transport,
+    client: Client,
+    encoding: Encoding,
+    compression: Compression,
+    expires: Option<Instant>,
+) -> Result<()> {
+    let config = &shared.config;
+    let mut shutdown = shared.shutdown.subscribe();
+    Span::current().record("identity", client.identity());
+    METRICS.connections.inc();
+    info!("connected using {}", encoding);
+
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let (due_tx, mut due_rx) = mpsc::unbounded_channel();
+    let sessions = SessionManager::new(
+        config.clone(),
+        shared.registry.clone(),
+        shared.audit.clone(),
+        client,
+        events_tx.clone(),
+        due_tx,
+    );
+    let backlog = sessions.backlog();
+    let round_trip = sessions.round_trip();
+    let transfers = Transfers::new(config.clone(), events_tx.clone(), backlog.clone());
+    let forwards = Forwards::new(config.clone(), events_tx.clone(), backlog.clone());
+    let _ = events_tx.send(Message::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.to_vec(),
+    });
+
+    // anything the client sends, pongs included, shows it's still there
+    let mut last_seen = Instant::now();
+    let mut heartbeat = time::interval_at(
+        Instant::now() + config.keepalive.interval(),
+        config.keepalive.interval(),
+    );
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut nonce = 0u64;
+    // the ping sent last and when, until either pong answers it
+    let mut pinged = None;
+    let expired = time::sleep_until(expires.unwrap_or_else(Instant::now));
+    tokio::pin!(expired);
+    let timeout = config.keepalive.timeout();
+
+    let result: Result<()> = loop {
+        tokio::select! {
+            frame = transport.receive() => {
+                if let Ok(Some(_)) = frame {
+                    last_seen = Instant::now();
+                }
+                let decoded = match frame {
+                    Ok(Some(Frame::Text(text))) => Encoding::Json.decode(text.as_bytes()),
+                    Ok(Some(Frame::Binary(bytes))) => compression
+                        .decompress(bytes)
+                        .and_then(|bytes| Encoding::MsgPack.decode(&bytes)),
+                    Ok(Some(Frame::Pong(payload))) => {
+                        if let Ok(answered) = <[u8; 8]>::try_from(payload.as_slice()) {
+                            pong(&mut pinged, u64::from_be_bytes(answered), &round_trip);
+                        }
+                        continue;
+                    },
+                    Ok(Some(Frame::Ping(_))) => continue,
+                    Ok(None) => break Ok(()),
+                    Err(ReceiveError { reason, error }) => {
+                        if let Some(reason) = reason {
+                            let message = error.to_string();
+                            let _ = close_connection(&mut transport, reason, message, timeout)
+                                .await;
+                        }
+                        break Err(error);
+                    },
+                };
+                match decoded {
+                    Ok(Message::Hello { version, .. }) if version != PROTOCOL_VERSION => {
+                        break close_unsupported(
+                            &mut transport, encoding, compression, version, timeout,
+                        )
+                        .await;
+                    },
+                    Ok(Message::Pong { nonce: answered }) => {
+                        pong(&mut pinged, answered, &round_trip)
+                    },
+                    Ok(message) => {
+                        handle_client_message(
+                            message, &sessions, &transfers, &forwards, &events_tx,
+                        )
+                        .await
+                    },
+                    Err(e) => {
+                        let _ = events_tx.send(Message::Error {
+                            session: None,
+                            error: ProtocolError::new(ErrorCode::BadRequest, format!("{:#}", e)),
+                        });
+                    },
+                }
+            },
+            Some(message) = events_rx.recv() => {
+                let frame = match encode_frame(encoding, compression, &message) {
+                    Ok(frame) => frame,
+                    Err(e) => break Err(e),
+                };
+                if let Err(e) = send_frame(&mut transport, frame, timeout).await {
+                    break Err(e);
+                }
+                backlog.sent(message.data_len());
+            },
+            Some(due) = due_rx.recv() => sessions.start_due(due),
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() >= timeout {
+                    warn!("timed out, nothing received for {:?}", last_seen.elapsed());
+                    let message =
+                        format!("nothing received for {}s", last_seen.elapsed().as_secs());
+                    let reason = CloseReason::IdleTimeout;
+                    break close_connection(&mut transport, reason, message, timeout).await;
+                }
+                // a WebSocket ping for clients that answer those on their own and a protocol one
+                // for those that can't see WebSocket control frames, such as browsers
+                nonce = nonce.wrapping_add(1);
+                let ping = Frame::Ping(nonce.to_be_bytes().to_vec());
+                if let Err(e) = send_frame(&mut transport, ping, timeout).await {
+                    break Err(e);
+                }
+                let _ = events_tx.send(Message::Ping { nonce });
+                pinged = Some((nonce, Instant::now()));
+            },
+            _ = &mut expired, if expires.is_some() => {
+                warn!("closing connection, token expired");
+                let message = "token expired".to_string();
+                let reason = CloseReason::AuthFailed;
+                break close_connection(&mut transport, reason, message, timeout).await;
+            },
+            _ = shutdown.changed() => {
+                let message = CloseReason::Shutdown.to_string();
+                let reason = CloseReason::Shutdown;
+                break close_connection(&mut transport, reason, message, timeout).await;
+            },
+        }
+    };
+
+    info!("disconnected");
+    result
+}
+
+/// Count in the round trip of the ping sent last if `nonce` answers it. Whichever pong arrives
+/// first counts, the other one finds nothing to answer.
+fn pong(pinged: &mut Option<(u64, Instant)>, nonce: u64, round_trip: &RoundTrip) {
+    if let Some((sent, at)) = *pinged {
+        if sent == nonce {
+            round_trip.measured(at.elapsed());
+            *pinged = None;
+        }
+    }
+}
+
+/// Send `frame`, giving up if the client doesn't take it within `timeout`. Without that, a client
+/// that stopped reading would block the connection forever once the socket buffers are full.
+async fn send_frame(transport: &mut impl Transport, frame: Frame, timeout: Duration) -> Result<()> {
+    time::timeout(timeout, transport.send(frame))
+        .await
+        .context("client stopped receiving")?
+}
+
+/// Tell a client speaking protocol `version` that the server doesn't, then close the connection
+async fn close_unsupported(
+    transport: &mut impl Transport,
+    encoding: Encoding,
+    compression: Compression,
+    version: u32,
+    timeout: Duration,
+) -> Result<()> {
+    let reason = format!(
+        "unsupported protocol version {}, server speaks {}",
+        version, PROTOCOL_VERSION
+    );
+    warn!("closing connection, {}", reason);
+    // not queued as an event, the connection is closed before those are sent
+    let error = Message::Error {
+        session: None,
+        error: ProtocolError::new(ErrorCode::UnsupportedVersion, reason.clone()),
+    };
+    send_frame(transport, encode_frame(encoding, compression, &error)?, timeout).await?;
+    close_connection(transport, CloseReason::UnsupportedVersion, reason, timeout).await
+}
+
+/// Close the connection for `reason`, telling the client more in `message`. Like frames sent,
+/// the close has to be taken within `timeout`.
+async fn close_connection(
+    transport: &mut impl Transport,
+    reason: CloseReason,
+    message: String,
+    timeout: Duration,
+) -> Result<()> {
+    time::timeout(timeout, transport.close(reason, message))
+        .await
+        .context("client stopped receiving")?
+}
+
+/// Handle messages concerning the connection itself and pass everything else on to the
+/// transfers, forwarding channels or sessions
+async fn handle_client_message(
+    message: Message,
+    sessions: &SessionManager,
+    transfers: &Transfers,
+    forwards: &Forwards,
+    events: &mpsc::UnboundedSender<Message>,
+) {
+    match message {
+        // nothing the server does depends on what the client supports yet
+        Message::Hello { .. } => {},
+        Message::Ping { nonce } => {
+            let _ = events.send(Message::Pong { nonce });
+        },
+        // receiving it already counts as a sign of life
+        Message::Pong { .. } => {},
+        message if message.transfer().is_some() => transfers.handle_message(message),
+        message if message.channel().is_some() => forwards.handle_message(message).await,
+        message => sessions.handle_message(message).await,
+    }
+}
@@ -0,0 +1,194 @@
+//! Experimental WebTransport listener, for browsers on lossy networks that do better over QUIC
+//! than over the TCP connection of a WebSocket:
+//!
+//! ```toml
+//! [webtransport]
+//! listen = "0.0.0.0:8443"
+//! ```
+//!
+//! Clients open a WebTransport session at the listener, presenting their token and asking for an
+//! encoding with the query parameters WebSocket clients use, then open a bidirectional stream
+//! for every connection they want. Each stream carries [`Framed`] messages and is served like a
+//! WebSocket connection of its own. The sessions of all streams share one QUIC connection and
+//! one authentication, without a lost packet of one holding up the others.
+//!
+//! WebTransport needs TLS, the listener presents the certificate of the `[tls]` settings as they
+//! were when the server started. Client certificates aren't asked for. Address policies, rate
+//! limits, lockouts and the connection limits hold for WebTransport sessions as they hold for
+//! WebSocket connections, each session counting as one connection however many streams it opens.
+//!
+//! Only built with the `webtransport` feature.
+use super::{admit, authenticated_client, check_request, in_time, serve, Request, Shared, HOST};
+use crate::{
+    audit::Client, error::LoggableError, logging::connection_span, metrics::METRICS,
+    tls::TlsConfig, transport::Framed,
+};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{future::IntoFuture, net::SocketAddr, sync::Arc};
+use tokio::{
+    sync::{watch, OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+    time::Instant,
+};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tracing::{info, info_span, warn, Instrument, Span};
+use wtransport::{
+    endpoint::{endpoint_side, IncomingSession, SessionRequest},
+    Endpoint, Identity, ServerConfig, VarInt,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebTransportConfig {
+    /// UDP address the WebTransport listener binds to
+    pub listen: SocketAddr,
+}
+
+/// Bind the listener described by `config`, presenting the certificate of `tls`, and accept
+/// sessions in the background until the server shuts down. Sessions share what `shared` holds
+/// when they are accepted and hold one of `connections` while they are open.
+pub(super) async fn spawn(
+    config: &WebTransportConfig,
+    tls: &TlsConfig,
+    shared: watch::Receiver<Arc<Shared>>,
+    connections: Arc<Semaphore>,
+) -> Result<()> {
+    let err_context = || format!("failed to listen for WebTransport on {}", config.listen);
+
+    let identity = Identity::load_pemfiles(&tls.cert, &tls.key)
+        .await
+        .with_context(err_context)?;
+    let server_config = ServerConfig::builder()
+        .with_bind_address(config.listen)
+        .with_identity(identity)
+        .build();
+    let endpoint = Endpoint::server(server_config).with_context(err_context)?;
+    info!("WebTransport listening on {}", config.listen);
+    tokio::spawn(accept_sessions(endpoint, shared, connections));
+    Ok(())
+}
+
+async fn accept_sessions(
+    endpoint: Endpoint<endpoint_side::Server>,
+    shared: watch::Receiver<Arc<Shared>>,
+    connections: Arc<Semaphore>,
+) {
+    let mut shutdown = shared.borrow().shutdown.subscribe();
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => incoming,
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+        };
+        let peer = incoming.remote_address();
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            warn!("turning away {}, too many connections", peer);
+            METRICS.connection_rejected("max_connections");
+            incoming.refuse();
+            continue;
+        };
+        let shared = shared.borrow().clone();
+        let Some(counted) = admit(&shared, peer) else {
+            incoming.refuse();
+            continue;
+        };
+        let handshake = Instant::now() + shared.config.limits.handshake_timeout();
+        let accepted = async move {
+            let _ = accept_session(shared, incoming, peer, handshake, permit)
+                .await
+                .to_log();
+            drop(counted);
+        };
+        tokio::spawn(accepted.instrument(connection_span(peer)));
+    }
+    endpoint.close(VarInt::from_u32(0), b"shutting down");
+}
+
+/// Check the request opening the session coming in from `peer`, then serve the streams the
+/// client opens until it closes the session. The client has to be done with the handshakes by
+/// `handshake`.
+async fn accept_session(
+    shared: Arc<Shared>,
+    incoming: IncomingSession,
+    peer: SocketAddr,
+    handshake: Instant,
+    _permit: OwnedSemaphorePermit,
+) -> Result<()> {
+    let err_context = || format!("failed to serve WebTransport session from {}", peer);
+
+    let session = in_time(handshake, incoming.into_future())
+        .await
+        .with_context(err_context)?;
+    let request = upgrade_request(&session).with_context(err_context)?;
+    let admission = match check_request(&shared, &Client::new(peer), &request) {
+        Ok(admission) => admission,
+        Err(rejection) => {
+            match rejection.status {
+                StatusCode::TOO_MANY_REQUESTS => session.too_many_requests().await,
+                _ => session.forbidden().await,
+            }
+            // checking the request told why already
+            return Ok(());
+        },
+    };
+    let connection = in_time(handshake, session.accept())
+        .await
+        .with_context(err_context)?;
+    let (client, expires) = authenticated_client(Client::new(peer), admission.authenticated);
+    let (encoding, compression) = (admission.encoding, admission.compression);
+    // the connections of the streams are told apart by their stream
+    Span::current().record("identity", client.identity());
+    info!("WebTransport session established");
+
+    let mut shutdown = shared.shutdown.subscribe();
+    let mut streams = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = connection.accept_bi() => match accepted {
+                Ok((send, receive)) => {
+                    let span = info_span!("stream", id = %send.id());
+                    let (shared, client) = (shared.clone(), client.clone());
+                    let served = async move {
+                        let transport = Framed::new(receive, send);
+                        serve(&shared, transport, client, encoding, compression, expires).await
+                    };
+                    streams.spawn(served.instrument(span));
+                },
+                // the client closed the session, or it was lost
+                Err(_) => break,
+            },
+            Some(served) = streams.join_next() => log_served(served),
+            // the streams close on their own, the session once they did
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+        }
+    }
+    drop(shutdown);
+    while let Some(served) = streams.join_next().await {
+        log_served(served);
+    }
+    connection.close(VarInt::from_u32(0), b"");
+    info!("WebTransport session closed");
+    Ok(())
+}
+
+fn log_served(served: Result<Result<()>, tokio::task::JoinError>) {
+    let _ = served
+        .context("stream panicked")
+        .and_then(|served| served.context("failed to serve stream"))
+        .to_log();
+}
+
+/// The request opening `session` as if it was the upgrade request of a WebSocket connection,
+/// for the checks of those to apply
+fn upgrade_request(session: &SessionRequest) -> Result<Request> {
+    let mut request = Request::builder()
+        .uri(format!("https://{}{}", session.authority(), session.path()))
+        .header(HOST, session.authority());
+    // pseudo-headers are what the URI was made of
+    for (name, value) in session.headers() {
+        if !name.starts_with(':') {
+            request = request.header(name, value);
+        }
+    }
+    request.body(()).context("malformed session request")
+}
@@ -0,0 +1,3148 @@
+//! Shell sessions. Every session runs its own command, either on its own [`Terminal`] or, in exec
+//! mode, on pipes ([`Exec`]), and is addressed by the [`SessionId`] the client picked when
+//! starting it.
+//!
+//! Sessions live in the server wide [`SessionRegistry`] and are attached to connections, which
+//! receive their output: at most one writer, which types into it, and any number of viewers. A
+//! client can detach a session and attach it again later, possibly from another connection, and
+//! gets its recent output replayed.
+//!
+//! Only clients of the [identity](Client::identity) that opened a session can attach to it, in
+//! either role. Pairing and hand-offs work between connections of the same user, or of clients
+//! sharing a token, but sessions can't be shared with other users. Operators watch any session
+//! through the [admin listener](crate::admin), see [`SessionManager::watch`].
+#[cfg(feature = "kubernetes")]
+use crate::kubernetes::PodExec;
+#[cfg(feature = "mqtt")]
+use crate::mqtt::MqttPublisher;
+#[cfg(feature = "plugins")]
+use crate::plugin::{Plugin, PluginExec};
+#[cfg(feature = "ssh")]
+use crate::ssh::{SshExec, SshTarget};
+use crate::{
+    audit::{serialize_time, AuditLog, BroadcastRecord, Client, Event, Record},
+    capture::Capture,
+    command::{
+        send_signal, Backend, Credentials, Environment, Jail, Profile, RunCommand, Sandbox,
+        UserPolicy,
+    },
+    config::{Coalescing, Config, SessionConfig},
+    data::{
+        AttachRole, AttachedClient, ColorDepth, ExitReason, ExitSignal, IdleAction, LimitScope,
+        LineMode, Message, Payload, Resource, SerialSettings, SessionId, SignalSpec, StdStream,
+        TermMode,
+    },
+    docker::ContainerExec,
+    error::{ErrorCode, ProtocolError, ToAnyhow},
+    filter::{
+        ColorDowngrade, FilterChain, FilterContext, FilterRegistry, InputChain, InputFilter,
+        InterceptOsc, OutputEvent, OutputFilter, StripAnsi, Utf8Boundaries,
+    },
+    hardening::Hardening,
+    limits::{Bandwidth, Cgroup, ResourceLimits, Throttle},
+    logging::session_span,
+    metrics::METRICS,
+    namespaces::{Isolation, Namespaces},
+    notify::{Notification, Notifier, NotifyEvent},
+    os_io::{Exec, LineEditor, PtySize, PtySpawner, Serial, Spawn, Terminal, TerminalWriter},
+    recording::Recording,
+    schedule::{self, Due, JobQueue, JobSlot, Schedule},
+    terminfo,
+    utmp::LoginRecord,
+};
+#[cfg(feature = "history")]
+use crate::{data::JobRecord, history::History};
+#[cfg(feature = "scripting")]
+use crate::scripting::Scripts;
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use nix::{
+    sys::signal::{killpg, Signal},
+    unistd::Pid,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt, fs,
+    future::{self, Future},
+    os::unix::process::ExitStatusExt,
+    path::PathBuf,
+    pin::Pin,
+    process::ExitStatus,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+#[cfg(any(feature = "history", feature = "scripting"))]
+use tokio::task::block_in_place;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, Notify},
+    task::{AbortHandle, JoinHandle},
+    time,
+};
+use tracing::{info, warn, Instrument, Span};
+
+/// How long a command may take to exit after closing its output before its session is hung up
+const EXIT_GRACE_PERIOD: Duration = Duration::from_millis(500);
+
+/// How long a command that timed out gets to exit after SIGTERM before it is killed
+const TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Bytes of output read at once at most
+const OUTPUT_CHUNK_SIZE: usize = 16384;
+
+/// Bytes of output sent at once at most to clients close by and far away, see
+/// [`Coalescing::adaptive`]
+const MIN_FRAME_SIZE: usize = 4096;
+const MAX_FRAME_SIZE: usize = 65536;
+
+/// What a session is running
+enum Process {
+    Pty(Box<dyn Terminal>),
+    Exec(Exec),
+    /// A command on a terminal in a container
+    Container(ContainerExec),
+    /// A command on a terminal in a pod
+    #[cfg(feature = "kubernetes")]
+    Pod(PodExec),
+    /// A command on a terminal of another host
+    #[cfg(feature = "ssh")]
+    Ssh(SshExec),
+    /// A session a plugin runs
+    #[cfg(feature = "plugins")]
+    Plugin(PluginExec),
+    /// A serial device and the task reading it
+    Serial(Serial, AbortHandle),
+}
+
+impl Process {
+    /// Whether the command is on a terminal, or on a device as interactive as one
+    fn is_interactive(&self) -> bool {
+        !matches!(self, Process::Exec(_))
+    }
+
+    /// Resolves to the exit status of the command once it exited, right away to `None` for
+    /// devices
+    fn exited(&self) -> Pin<Box<dyn Future<Output = Option<ExitStatus>> + Send>> {
+        match self {
+            Process::Pty(pty) => pty.exited(),
+            Process::Exec(exec) => Box::pin(exec.exited()),
+            Process::Container(exec) => Box::pin(exec.exited()),
+            #[cfg(feature = "kubernetes")]
+            Process::Pod(exec) => Box::pin(exec.exited()),
+            #[cfg(feature = "ssh")]
+            Process::Ssh(exec) => Box::pin(exec.exited()),
+            #[cfg(feature = "plugins")]
+            Process::Plugin(exec) => Box::pin(exec.exited()),
+            Process::Serial(..) => Box::pin(future::ready(None)),
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // nothing hangs up a device like closing a terminal hangs up a command, the output would
+        // be read forever
+        if let Process::Serial(_, pump) = self {
+            pump.abort();
+        }
+    }
+}
+
+/// What a terminal session runs, as asked for by the client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Program {
+    /// A command line, if the command policies allow it
+    Command(RunCommand),
+    /// The [`Profile`] of this name
+    Profile(String),
+    /// The server's default shell
+    Shell,
+}
+
+/// How a terminal session was opened, to open another one like it
+#[derive(Clone, Debug)]
+struct Origin {
+    program: Program,
+    /// The working directory the client asked for
+    cwd: Option<PathBuf>,
+    /// The environment the client asked for
+    env: Environment,
+    options: SessionOptions,
+}
+
+/// How a session treats its command and output, as asked for by the client
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SessionOptions {
+    /// Seconds the command may run, capped by the server's timeout
+    pub timeout: Option<u64>,
+    /// Send output as read, without waiting for the rest of UTF-8 sequences split between reads
+    pub raw_output: bool,
+    /// Remove escape sequences from the output, for clients that only want its text
+    pub strip_ansi: bool,
+    /// Turn the OSC 52 sequences of the output into messages about the clipboard
+    pub clipboard: bool,
+    /// Report the window title and current directory the command sets
+    pub meta: bool,
+    /// Remove the sequences setting the window title and current directory from the output
+    pub strip_meta: bool,
+    /// Colors the terminal of the client shows, those of the output are brought down to them
+    pub colors: Option<ColorDepth>,
+}
+
+impl SessionOptions {
+    /// The filters output is passed through, a new set for every stream
+    fn output_filter(&self) -> FilterChain {
+        let mut filter = FilterChain::default();
+        // before the sequences are stripped along with all others
+        if self.clipboard || self.meta || self.strip_meta {
+            filter.push(InterceptOsc::new(
+                self.clipboard,
+                self.meta,
+                self.strip_meta,
+            ));
+        }
+        match self.colors {
+            // no colors are left to bring down
+            _ if self.strip_ansi => filter.push(StripAnsi::default()),
+            Some(depth) if depth < ColorDepth::TrueColor => {
+                filter.push(ColorDowngrade::new(depth))
+            },
+            _ => {},
+        }
+        if !self.raw_output {
+            filter.push(Utf8Boundaries::default());
+        }
+        filter
+    }
+}
+
+/// The most recent output of a session, replayed to clients attaching to it
+struct Scrollback {
+    /// Output kept along with the `seq` it was sent with, sharing the bytes sent to clients
+    chunks: VecDeque<(u64, Option<StdStream>, Bytes)>,
+    len: usize,
+    capacity: usize,
+    /// Bytes output ever, the `seq` of the latest output
+    seq: u64,
+}
+
+impl Scrollback {
+    fn new(capacity: usize) -> Self {
+        Scrollback {
+            chunks: VecDeque::new(),
+            len: 0,
+            capacity,
+            seq: 0,
+        }
+    }
+
+    /// Append `data`, forgetting the oldest output beyond the capacity. Returns the `seq` of
+    /// `data`.
+    fn push(&mut self, stream: Option<StdStream>, data: &Bytes) -> u64 {
+        self.seq += data.len() as u64;
+        if self.capacity == 0 {
+            return self.seq;
+        }
+        self.chunks.push_back((self.seq, stream, data.clone()));
+        self.len += data.len();
+        while self.len > self.capacity {
+            let excess = self.len - self.capacity;
+            let Some((_, _, oldest)) = self.chunks.front_mut() else {
+                break;
+            };
+            if oldest.len() <= excess {
+                self.len -= oldest.len();
+                self.chunks.pop_front();
+            } else {
+                oldest.advance(excess);
+                self.len -= excess;
+            }
+        }
+        self.seq
+    }
+
+    /// The last `len` bytes of the output kept, of all streams together
+    #[cfg(feature = "history")]
+    fn tail(&self, len: usize) -> Vec<u8> {
+        let mut parts = vec![];
+        let mut left = len;
+        for (_, _, data) in self.chunks.iter().rev() {
+            if left == 0 {
+                break;
+            }
+            let part = &data[data.len().saturating_sub(left)..];
+            left -= part.len();
+            parts.push(part);
+        }
+        parts.into_iter().rev().flatten().copied().collect()
+    }
+
+    /// The output kept that followed `seq`, as messages for session `id`
+    fn replay(&self, id: SessionId, seq: u64) -> impl Iterator<Item = Message> + '_ {
+        self.chunks
+            .iter()
+            .filter(move |(end, _, _)| *end > seq)
+            .map(move |(end, stream, data)| {
+                let start = end - data.len() as u64;
+                let skipped = seq.saturating_sub(start) as usize;
+                Message::Output {
+                    session: id,
+                    data: Payload(data.slice(skipped..)),
+                    stream: *stream,
+                    seq: *end,
+                }
+            })
+    }
+}
+
+/// Where a terminal session is in being left idle, see [`SessionRegistry::check_idle`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Idleness {
+    Active,
+    /// Its clients were told that it will be closed or suspended
+    Warned,
+    /// Its programs were stopped until it is used again
+    Suspended,
+    /// It was hung up after `timeout` seconds of idling
+    Closed {
+        timeout: u64,
+    },
+}
+
+/// Counts a session as throttled until dropped, which an aborted output task does as well
+struct Throttled;
+
+impl Throttled {
+    fn start() -> Self {
+        METRICS.throttled_sessions.inc();
+        Throttled
+    }
+}
+
+impl Drop for Throttled {
+    fn drop(&mut self) {
+        METRICS.throttled_sessions.dec();
+    }
+}
+
+struct Session {
+    /// `None` once the session was closed and its command is being hung up
+    process: Option<Process>,
+    command: RunCommand,
+    scrollback: Scrollback,
+    /// Limits the resources of the command, kept until it was reaped
+    cgroup: Option<Cgroup>,
+    /// The connection controlling the session, `None` while detached
+    writer: Option<Attachment>,
+    /// Connections only watching the session
+    viewers: Vec<Attachment>,
+    /// When the last connection detached, to hang the session up once it stayed detached for too
+    /// long
+    detached_at: Option<Instant>,
+    /// Where the output of terminal sessions is recorded, if it is
+    recording: Option<Recording>,
+    /// Where the output is captured to, if it is
+    capture: Option<Capture>,
+    /// The timeout in seconds the command was terminated after, if it was
+    timed_out: Option<u64>,
+    /// Whether an operator killed the command
+    killed: bool,
+    /// The `seq` of the output the writer acknowledged last
+    acked: u64,
+    /// Holds the output back to the bandwidth of a session, if limited
+    throttle: Option<Throttle>,
+    /// Refuses input sent faster than the server allows, if limited
+    input_throttle: Option<Throttle>,
+    /// When the session last saw input or output
+    last_active: Instant,
+    idleness: Idleness,
+    /// Echoes and edits input while the server does so, see [`LineMode::Server`]
+    line_editor: Option<LineEditor>,
+    /// What input passes through before it is written to the command, see
+    /// [`Profile::input_filters`]
+    input_filter: InputChain,
+    /// The selection the command asked for the clipboard of, until the writer answers
+    clipboard_query: Option<String>,
+    /// The window title the command set last, if it reports it
+    title: Option<String>,
+    /// The directory the command told it is in last, if it reports it
+    cwd: Option<PathBuf>,
+    /// How the client opened the session, `None` unless on a terminal
+    origin: Option<Origin>,
+    /// The share of the commands running without a terminal, handed on when the session is gone
+    slot: Option<JobSlot>,
+    /// The environment the command was started with, as far as the server knows it
+    environment: BTreeMap<String, String>,
+    /// The client that opened the session, for the audit trail
+    opened_by: Client,
+    started_at: SystemTime,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// How the output was cut into frames last
+    framing: Option<Framing>,
+    /// The login the session was recorded as in utmp and wtmp, if its profile asks for that
+    login: Option<LoginRecord>,
+}
+
+impl Session {
+    fn process(&self) -> Result<&Process> {
+        self.process
+            .as_ref()
+            .ok_or_else(|| anyhow!("session is closing"))
+    }
+
+    /// The connections receiving the session's output, the writer first
+    fn attachments(&self) -> impl Iterator<Item = &Attachment> {
+        self.writer.iter().chain(&self.viewers)
+    }
+
+    fn is_attached_to(&self, client: &mpsc::UnboundedSender<Message>) -> bool {
+        self.attachments().any(|attached| attached.is(client))
+    }
+
+    fn is_writer(&self, client: &mpsc::UnboundedSender<Message>) -> bool {
+        self.writer
+            .as_ref()
+            .is_some_and(|attached| attached.is(client))
+    }
+
+    /// Stop sending output to `client`, the session counts as detached once nobody is attached
+    fn detach(&mut self, client: &mpsc::UnboundedSender<Message>) {
+        if self.is_writer(client) {
+            self.writer = None;
+        }
+        self.viewers.retain(|viewer| !viewer.is(client));
+        if self.writer.is_none() && self.viewers.is_empty() {
+            self.detached_at = Some(Instant::now());
+        }
+    }
+
+    /// Who the session is attached to, the writer first
+    fn attached_clients(&self) -> Vec<AttachedClient> {
+        let writer = self.writer.iter().map(|w| (w, AttachRole::Writer));
+        let viewers = self.viewers.iter().map(|v| (v, AttachRole::Viewer));
+        writer
+            .chain(viewers)
+            .map(|(attached, role)| AttachedClient {
+                address: attached.client.address,
+                identity: attached.client.identity(),
+                role,
+            })
+            .collect()
+    }
+
+    /// What operators are told about the session
+    fn info(&self, id: SessionId) -> SessionInfo {
+        SessionInfo {
+            id,
+            owner: self.opened_by.clone(),
+            command: self.command.to_string(),
+            kind: match self.process {
+                Some(Process::Pty(_)) => Some(SessionKind::Pty),
+                Some(Process::Exec(_)) => Some(SessionKind::Exec),
+                Some(Process::Container(_)) => Some(SessionKind::Container),
+                #[cfg(feature = "kubernetes")]
+                Some(Process::Pod(_)) => Some(SessionKind::Pod),
+                #[cfg(feature = "ssh")]
+                Some(Process::Ssh(_)) => Some(SessionKind::Ssh),
+                #[cfg(feature = "plugins")]
+                Some(Process::Plugin(_)) => Some(SessionKind::Plugin),
+                Some(Process::Serial(..)) => Some(SessionKind::Serial),
+                None => None,
+            },
+            started_at: self.started_at,
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            attached: self.attached_clients(),
+            framing: self.framing,
+        }
+    }
+
+    /// Send `message` to every connection attached
+    fn broadcast(&self, message: Message) {
+        for attached in self.attachments() {
+            attached.send(message.clone());
+        }
+    }
+
+    /// Note that the session saw input or output, continuing its programs if they were suspended
+    /// for idling
+    fn active(&mut self) {
+        self.last_active = Instant::now();
+        if self.idleness == Idleness::Suspended {
+            if let Some(Process::Pty(pty)) = self.process.as_ref() {
+                info!("used again, continuing");
+                if let Err(e) = killpg(pty.pid(), Signal::SIGCONT) {
+                    warn!("failed to continue '{}': {}", self.command, e);
+                }
+            }
+        }
+        self.idleness = Idleness::Active;
+    }
+
+    /// Whether what is typed into the session is recorded
+    fn records_input(&self) -> bool {
+        self.recording
+            .as_ref()
+            .is_some_and(Recording::records_input)
+    }
+
+    /// Where input for the command goes
+    fn input_writer(&self) -> Result<TerminalWriter> {
+        Ok(match self.process()? {
+            Process::Pty(pty) => pty.writer(),
+            Process::Container(exec) => Box::new(exec.writer()),
+            #[cfg(feature = "kubernetes")]
+            Process::Pod(exec) => Box::new(exec.writer()),
+            #[cfg(feature = "ssh")]
+            Process::Ssh(exec) => Box::new(exec.writer()),
+            #[cfg(feature = "plugins")]
+            Process::Plugin(exec) => Box::new(exec.writer()),
+            Process::Exec(_) => {
+                return Err(anyhow!("commands run without a terminal take no input"))
+            },
+            Process::Serial(serial, _) => Box::new(serial.writer()),
+        })
+    }
+
+    /// Pass on what the command told its terminal. Only the writer is asked what is on the
+    /// clipboard, and only if `readable`.
+    fn output_event(&mut self, id: SessionId, event: OutputEvent, readable: bool) {
+        match event {
+            OutputEvent::ClipboardSet(data) => self.broadcast(Message::ClipboardSet {
+                session: id,
+                data: Payload::from(data),
+            }),
+            OutputEvent::ClipboardGet { selection } if readable => {
+                if let Some(writer) = self.writer.as_ref() {
+                    writer.send(Message::ClipboardGet { session: id });
+                    self.clipboard_query = Some(selection);
+                }
+            },
+            OutputEvent::ClipboardGet { .. } => {},
+            OutputEvent::Title(title) => {
+                self.title = Some(title.clone());
+                self.broadcast(Message::SessionMeta {
+                    session: id,
+                    title: Some(title),
+                    cwd: None,
+                });
+            },
+            OutputEvent::Directory(cwd) => {
+                self.cwd = Some(cwd.clone());
+                self.broadcast(Message::SessionMeta {
+                    session: id,
+                    title: None,
+                    cwd: Some(cwd),
+                });
+            },
+        }
+    }
+
+    /// Show `data` to the user as if the command had written it
+    fn echo(&mut self, id: SessionId, data: Vec<u8>) {
+        if data.is_empty() {
+            return;
+        }
+        if let Some(recording) = self.recording.as_mut() {
+            recording.output(&data);
+        }
+        let data = Bytes::from(data);
+        let seq = self.scrollback.push(None, &data);
+        self.broadcast(Message::Output {
+            session: id,
+            data: Payload(data),
+            stream: None,
+            seq,
+        });
+    }
+}
+
+/// What a session runs on, see [`SessionInfo`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionKind {
+    Pty,
+    Exec,
+    Serial,
+    Container,
+    Pod,
+    Ssh,
+    Plugin,
+}
+
+/// A session as reported by the [admin API](crate::admin)
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    /// The client that opened the session
+    pub owner: Client,
+    pub command: String,
+    /// `None` while the session is closing
+    pub kind: Option<SessionKind>,
+    #[serde(serialize_with = "serialize_time")]
+    pub started_at: SystemTime,
+    /// Bytes the clients wrote to the session
+    pub bytes_in: u64,
+    /// Bytes the command output
+    pub bytes_out: u64,
+    /// The connections attached, none while detached
+    pub attached: Vec<AttachedClient>,
+    /// How the output is cut into frames, `None` until there was any
+    pub framing: Option<Framing>,
+}
+
+/// How the output of a session is cut into frames, see [`Coalescing`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Framing {
+    /// The shortest round trip to the clients attached in milliseconds, if measured yet
+    pub rtt: Option<u64>,
+    /// Bytes of output sent in one frame at most
+    pub frame_size: usize,
+    /// Milliseconds more output is waited for after each read
+    pub window: u64,
+}
+
+impl Framing {
+    /// The framing `coalescing` asks for with clients `rtt` away
+    fn new(coalescing: &Coalescing, rtt: Option<Duration>) -> Self {
+        let rtt = rtt.map(|rtt| rtt.as_millis() as u64);
+        match rtt.filter(|_| coalescing.adaptive) {
+            Some(ms) => Framing {
+                rtt,
+                // a kibibyte for every millisecond to go, rounded up to a power of two
+                frame_size: (ms as usize)
+                    .saturating_mul(1024)
+                    .clamp(MIN_FRAME_SIZE, MAX_FRAME_SIZE)
+                    .next_power_of_two(),
+                // holding output back for a tenth of the round trip adds little to it
+                window: (ms / 10).min(coalescing.max_delay),
+            },
+            None => Framing {
+                rtt,
+                frame_size: OUTPUT_CHUNK_SIZE,
+                window: coalescing.window,
+            },
+        }
+    }
+
+    /// How long to wait for more output after each read, `None` if not at all
+    fn window(&self) -> Option<Duration> {
+        (self.window > 0).then(|| Duration::from_millis(self.window))
+    }
+}
+
+/// Output queued for a connection but not sent yet, telling how far behind its client is. See
+/// [`FlowControl`](crate::config::FlowControl).
+#[derive(Debug, Default)]
+pub struct Backlog {
+    bytes: AtomicUsize,
+    drained: Notify,
+}
+
+impl Backlog {
+    /// Count `bytes` of output as queued for the client
+    pub(crate) fn queued(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count `bytes` of output as sent to the client
+    pub fn sent(&self, bytes: usize) {
+        let _ = self
+            .bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                Some(queued.saturating_sub(bytes))
+            });
+        self.drained.notify_waiters();
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wait until no more than `bytes` are queued
+    pub(crate) async fn drained_to(&self, bytes: usize) {
+        loop {
+            // registered before checking, so that output sent in between isn't missed
+            let drained = self.drained.notified();
+            if self.len() <= bytes {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+/// How long a round trip to the client of a connection takes, as measured by pings and smoothed
+/// the way TCP does
+#[derive(Debug, Default)]
+pub struct RoundTrip {
+    /// Microseconds, 0 until measured
+    micros: AtomicU64,
+}
+
+impl RoundTrip {
+    /// Count in a round trip that took `rtt`
+    pub fn measured(&self, rtt: Duration) {
+        let rtt = (rtt.as_micros() as u64).max(1);
+        let _ = self
+            .micros
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |smoothed| {
+                Some(match smoothed {
+                    0 => rtt,
+                    smoothed => (smoothed * 7 + rtt) / 8,
+                })
+            });
+    }
+
+    pub fn get(&self) -> Option<Duration> {
+        match self.micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+}
+
+/// A connection a session is attached to
+#[derive(Clone)]
+struct Attachment {
+    events: mpsc::UnboundedSender<Message>,
+    backlog: Arc<Backlog>,
+    round_trip: Arc<RoundTrip>,
+    /// Who is on the other end of the connection
+    client: Client,
+}
+
+impl Attachment {
+    fn is(&self, events: &mpsc::UnboundedSender<Message>) -> bool {
+        self.events.same_channel(events)
+    }
+
+    fn send(&self, message: Message) {
+        self.backlog.queued(message.data_len());
+        // the connection is going away if nobody listens anymore, dropping the message is fine
+        let _ = self.events.send(message);
+    }
+}
+
+/// All sessions of the server, whichever connection they are attached to
+pub struct SessionRegistry {
+    sessions: Mutex<HashMap<SessionId, Session>>,
+    /// Holds the output of all sessions together back to `bandwidth`, if limited
+    throttle: Option<Throttle>,
+    /// Commands without a terminal, running or waiting for others to exit
+    jobs: Arc<JobQueue>,
+    /// Where those commands are kept once they exited, if anywhere
+    #[cfg(feature = "history")]
+    history: Option<Arc<History>>,
+    /// Tells webhooks about sessions and what clients did wrong, if any are configured
+    notifier: Option<Notifier>,
+    /// Tells the MQTT broker the same, if the server is connected to one
+    #[cfg(feature = "mqtt")]
+    mqtt: Option<MqttPublisher>,
+    /// The filters profiles can name
+    filters: FilterRegistry,
+    /// Hooks run on what sessions do, if a script is configured
+    #[cfg(feature = "scripting")]
+    scripts: Option<Arc<Scripts>>,
+    /// The plugins profiles can run in, by name
+    #[cfg(feature = "plugins")]
+    plugins: BTreeMap<String, Arc<Plugin>>,
+    /// Starts the commands of terminal sessions
+    spawner: Arc<dyn Spawn>,
+}
+
+impl SessionRegistry {
+    /// A registry holding output back to `bandwidth` and running no more than `max_jobs`
+    /// commands without a terminal at the same time, if limited
+    pub fn new(bandwidth: Option<Bandwidth>, max_jobs: Option<usize>) -> Self {
+        SessionRegistry {
+            sessions: Mutex::default(),
+            throttle: bandwidth.map(Throttle::new),
+            jobs: Arc::new(JobQueue::new(max_jobs)),
+            #[cfg(feature = "history")]
+            history: None,
+            notifier: None,
+            #[cfg(feature = "mqtt")]
+            mqtt: None,
+            filters: FilterRegistry::default(),
+            #[cfg(feature = "scripting")]
+            scripts: None,
+            #[cfg(feature = "plugins")]
+            plugins: BTreeMap::new(),
+            spawner: Arc::new(PtySpawner),
+        }
+    }
+
+    /// Let profiles name `filters` rather than only those built in
+    pub fn with_filters(mut self, filters: FilterRegistry) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// Start the commands of terminal sessions with `spawner` rather than on a pty of their own
+    pub fn with_spawner(mut self, spawner: Arc<dyn Spawn>) -> Self {
+        self.spawner = spawner;
+        self
+    }
+
+    /// Run the hooks of `scripts` on commands starting and exiting and on their output
+    #[cfg(feature = "scripting")]
+    pub fn with_scripts(mut self, scripts: Arc<Scripts>) -> Self {
+        self.scripts = Some(scripts);
+        self
+    }
+
+    /// Let profiles with the `plugin` backend run in `plugins`
+    #[cfg(feature = "plugins")]
+    pub fn with_plugins(mut self, plugins: BTreeMap<String, Arc<Plugin>>) -> Self {
+        self.plugins = plugins;
+        self
+    }
+
+    /// Tell `notifier` about sessions starting and ending and what clients did wrong
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Publish notifications to the MQTT broker as well
+    #[cfg(feature = "mqtt")]
+    pub fn with_mqtt(mut self, publisher: MqttPublisher) -> Self {
+        self.mqtt = Some(publisher);
+        self
+    }
+
+    /// Deliver `notification` to the webhooks and the MQTT broker, if there are any
+    pub fn notify(&self, notification: &Notification) {
+        if let Some(notifier) = self.notifier.as_ref() {
+            notifier.notify(notification);
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = self.mqtt.as_ref() {
+            mqtt.notify(notification);
+        }
+    }
+
+    /// Keep the commands run without a terminal in `history` once they exited
+    #[cfg(feature = "history")]
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = Some(Arc::new(history));
+        self
+    }
+
+    /// The job history, if the server keeps one
+    #[cfg(feature = "history")]
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_deref()
+    }
+
+    /// The hooks run on what happens, if a script is configured
+    #[cfg(feature = "scripting")]
+    pub fn scripts(&self) -> Option<&Arc<Scripts>> {
+        self.scripts.as_ref()
+    }
+
+    /// Number of sessions running, attached or not
+    pub fn count(&self) -> usize {
+        self.sessions.lock().map_or(0, |sessions| sessions.len())
+    }
+
+    /// All sessions, attached or not, in no particular order
+    pub fn list(&self) -> Vec<SessionInfo> {
+        self.sessions.lock().map_or(vec![], |sessions| {
+            sessions
+                .iter()
+                .map(|(&id, session)| session.info(id))
+                .collect()
+        })
+    }
+
+    /// Session `id`, if it exists
+    pub fn get(&self, id: SessionId) -> Option<SessionInfo> {
+        let sessions = self.sessions.lock().ok()?;
+        sessions.get(&id).map(|session| session.info(id))
+    }
+
+    /// Kill the command of session `id` along with its process group and hang the session up,
+    /// whoever it is attached to. Its clients are told with [`ExitReason::Killed`].
+    pub fn kill(&self, id: SessionId) -> Result<()> {
+        let mut sessions = self.sessions.lock().to_anyhow()?;
+        let session = sessions.get_mut(&id).ok_or(SessionNotFound)?;
+        let group = match session.process()? {
+            // both lead a process group of their own
+            Process::Pty(pty) => Some(pty.pid()),
+            Process::Exec(exec) => Some(exec.pid()),
+            Process::Container(exec) => exec.pid(),
+            #[cfg(feature = "kubernetes")]
+            Process::Pod(_) => None,
+            #[cfg(feature = "ssh")]
+            Process::Ssh(_) => None,
+            #[cfg(feature = "plugins")]
+            Process::Plugin(_) => None,
+            Process::Serial(..) => None,
+        };
+        let _span = session_span(id).entered();
+        info!("killing '{}'", session.command);
+        if let Some(group) = group {
+            killpg(group, Signal::SIGKILL)
+                .with_context(|| format!("failed to kill session {}", id))?;
+        }
+        session.killed = true;
+        session.process = None;
+        Ok(())
+    }
+
+    /// Warn the clients of terminal sessions that saw neither input nor output for the idle
+    /// timeout of `config`, and close or suspend those still idle once the grace period is over
+    pub fn check_idle(&self, config: &SessionConfig) {
+        let Some(timeout) = config.idle_timeout() else {
+            return;
+        };
+        let grace = Duration::from_secs(config.idle_grace);
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return;
+        };
+        for (id, session) in sessions.iter_mut() {
+            let Some(Process::Pty(pty)) = session.process.as_ref() else {
+                continue;
+            };
+            let group = pty.pid();
+            let idle = session.last_active.elapsed();
+            let _span = session_span(*id).entered();
+            match session.idleness {
+                Idleness::Active if idle >= timeout => {
+                    info!(
+                        "idle for {:?}, {} in {:?}",
+                        timeout, config.idle_action, grace
+                    );
+                    session.idleness = Idleness::Warned;
+                    session.broadcast(Message::Idle {
+                        session: *id,
+                        action: config.idle_action,
+                        seconds: config.idle_grace,
+                    });
+                },
+                Idleness::Warned if idle >= timeout + grace => {
+                    match config.idle_action {
+                        IdleAction::Close => {
+                            info!("still idle, hanging up");
+                            session.idleness = Idleness::Closed {
+                                timeout: config.idle_timeout,
+                            };
+                            session.process = None;
+                        },
+                        IdleAction::Suspend => {
+                            info!("still idle, suspending");
+                            if let Err(e) = killpg(group, Signal::SIGSTOP) {
+                                warn!("failed to suspend '{}': {}", session.command, e);
+                                continue;
+                            }
+                            session.idleness = Idleness::Suspended;
+                        },
+                    }
+                    session.broadcast(Message::Idle {
+                        session: *id,
+                        action: config.idle_action,
+                        seconds: 0,
+                    });
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Hang up sessions that stayed detached for longer than `ttl`
+    pub fn reap_detached(&self, ttl: Duration) {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return;
+        };
+        for (id, session) in sessions.iter_mut() {
+            let expired = session
+                .detached_at
+                .is_some_and(|detached_at| detached_at.elapsed() >= ttl);
+            if expired && session.process.is_some() {
+                let _span = session_span(*id).entered();
+                info!("detached for longer than {:?}, hanging up", ttl);
+                session.process = None;
+            }
+        }
+    }
+}
+
+/// The sessions of one connection. Messages for the client (output, exits, errors) are sent to
+/// the `events` channel handed to [`SessionManager::new`]. The connection reports the output it
+/// sent to the client to its [`SessionManager::backlog`] and the pings it answered to its
+/// [`SessionManager::round_trip`].
+pub struct SessionManager {
+    registry: Arc<SessionRegistry>,
+    config: Arc<Config>,
+    audit: Arc<AuditLog>,
+    /// Who is on the other end of the connection
+    client: Client,
+    events: mpsc::UnboundedSender<Message>,
+    backlog: Arc<Backlog>,
+    round_trip: Arc<RoundTrip>,
+    /// Where commands go once they may be started
+    due: mpsc::UnboundedSender<Due>,
+    /// Commands waiting for their start time, dropped with the connection
+    schedule: Schedule<PendingRun>,
+    /// Commands waiting in the job queue, taken out of it with the connection
+    queued: Mutex<HashMap<SessionId, PendingRun>>,
+}
+
+/// How to run a command that wasn't started right away, see [`SessionManager::start_run`]
+struct PendingRun {
+    command: RunCommand,
+    env: Environment,
+    merge_stderr: bool,
+    options: SessionOptions,
+}
+
+impl SessionManager {
+    /// A manager for the sessions of a connection. Commands that were scheduled or queued are
+    /// sent to `due` when it's time to [start](SessionManager::start_due) them.
+    pub fn new(
+        config: Arc<Config>,
+        registry: Arc<SessionRegistry>,
+        audit: Arc<AuditLog>,
+        client: Client,
+        events: mpsc::UnboundedSender<Message>,
+        due: mpsc::UnboundedSender<Due>,
+    ) -> Self {
+        SessionManager {
+            registry,
+            config,
+            audit,
+            client,
+            events,
+            backlog: Arc::default(),
+            round_trip: Arc::default(),
+            schedule: Schedule::new(due.clone()),
+            due,
+            queued: Mutex::default(),
+        }
+    }
+
+    /// Output sent to the connection's `events` but not to the client yet
+    pub fn backlog(&self) -> Arc<Backlog> {
+        self.backlog.clone()
+    }
+
+    /// How long a round trip to the client takes
+    pub fn round_trip(&self) -> Arc<RoundTrip> {
+        self.round_trip.clone()
+    }
+
+    fn attachment(&self) -> Attachment {
+        Attachment {
+            events: self.events.clone(),
+            backlog: self.backlog.clone(),
+            round_trip: self.round_trip.clone(),
+            client: self.client.clone(),
+        }
+    }
+
+    /// Act on a message received from the client. Failures are reported back to the client as
+    /// [`Message::Error`].
+    pub async fn handle_message(&self, message: Message) {
+        let session = message.session();
+        // tasks spawned for the session stay in its span
+        let span = session.map_or_else(Span::none, session_span);
+        if let Err(e) = self.dispatch(message).instrument(span).await {
+            self.send_error(session, &e);
+        }
+    }
+
+    /// Report `error` to the client, and to the webhooks if the policy refused something
+    fn send_error(&self, session: Option<SessionId>, error: &anyhow::Error) {
+        let error = ProtocolError::from(error);
+        if error.code == ErrorCode::PolicyViolation {
+            self.registry.notify(&Notification {
+                session,
+                message: Some(error.message.clone()),
+                ..Notification::new(NotifyEvent::PolicyViolation, &self.client)
+            });
+        }
+        self.send(Message::Error { session, error });
+    }
+
+    async fn dispatch(&self, message: Message) -> Result<()> {
+        match message {
+            Message::Open {
+                session,
+                command,
+                profile,
+                size,
+                terminal,
+                cwd,
+                env,
+                clear_env,
+                strip_env,
+                timeout,
+                raw_output,
+                strip_ansi,
+                clipboard,
+                meta,
+                strip_meta,
+            } => {
+                let program = match (command, profile) {
+                    (Some(_), Some(_)) => {
+                        return Err(anyhow!("either a command or a profile may be given"))
+                    },
+                    (Some(command), None) => Program::Command(command),
+                    (None, Some(profile)) => Program::Profile(profile),
+                    (None, None) => Program::Shell,
+                };
+                let mut env = Environment {
+                    vars: env,
+                    clear: clear_env,
+                    strip: strip_env,
+                };
+                if let Some(terminal) = terminal.as_ref() {
+                    let dirs = terminfo::search_path();
+                    for (name, value) in terminfo::environment(terminal, &dirs) {
+                        // what the client sets itself wins
+                        env.vars.entry(name).or_insert(value);
+                    }
+                }
+                let size = size.map(PtySize::from).unwrap_or_default();
+                let options = SessionOptions {
+                    timeout,
+                    raw_output,
+                    strip_ansi,
+                    clipboard,
+                    meta,
+                    strip_meta,
+                    colors: terminal.and_then(|terminal| terminal.colors),
+                };
+                self.open_program(session, program, cwd, env, size, options)
+                    .await
+            },
+            Message::Run {
+                session,
+                program,
+                args,
+                env,
+                clear_env,
+                strip_env,
+                cwd,
+                merge_stderr,
+                timeout,
+                raw_output,
+                strip_ansi,
+                start_at,
+                delay,
+            } => {
+                let command = RunCommand {
+                    command: program,
+                    args,
+                    cwd,
+                    ..Default::default()
+                };
+                let env = Environment {
+                    vars: env,
+                    clear: clear_env,
+                    strip: strip_env,
+                };
+                let options = SessionOptions {
+                    timeout,
+                    raw_output,
+                    strip_ansi,
+                    ..Default::default()
+                };
+                let job = PendingRun {
+                    command,
+                    env,
+                    merge_stderr,
+                    options,
+                };
+                match schedule::start_time(start_at.as_deref(), delay)? {
+                    Some(at) => self.schedule_run(session, at, job),
+                    None => self.start_run(session, job, false),
+                }
+            },
+            Message::CancelScheduled { session } => self.cancel_scheduled(session),
+            Message::SerialOpen {
+                session,
+                device,
+                settings,
+                raw_output,
+            } => self.open_serial(session, device, &settings, raw_output),
+            Message::CloneSession {
+                session,
+                from,
+                size,
+            } => {
+                let size = size.map(PtySize::from).unwrap_or_default();
+                self.clone_session(session, from, size).await
+            },
+            Message::Attach { session, role } => self.attach(session, role, Some(0)),
+            Message::ResumeFrom { session, seq, role } => self.attach(session, role, seq),
+            Message::Detach { session } => self.detach(session),
+            Message::TakeControl { session } => self.take_control(session),
+            Message::ListAttachedClients { session } => self.list_attached_clients(session),
+            Message::SnapshotEnvironment { session } => self.snapshot_environment(session),
+            #[cfg(feature = "history")]
+            Message::ListJobs { limit, since } => self.list_jobs(limit, since.as_deref()),
+            #[cfg(feature = "history")]
+            Message::FetchJob { session } => self.fetch_job(session),
+            #[cfg(not(feature = "history"))]
+            Message::ListJobs { .. } | Message::FetchJob { .. } => {
+                Err(anyhow!("the server is built without job history support"))
+            },
+            Message::Input { session, data } => self.write(session, &data.0).await,
+            Message::BroadcastInput { sessions, data } => {
+                self.broadcast_input(&sessions, &data.0).await
+            },
+            Message::Ack { session, seq } => self.ack(session, seq),
+            Message::Resize { session, size } => self.resize(session, size.into()),
+            Message::SetTermMode {
+                session,
+                mode,
+                echo,
+                isig,
+                icrnl,
+            } => self.set_term_mode(session, mode, echo, isig, icrnl),
+            Message::SetLineMode { session, mode } => self.set_line_mode(session, mode),
+            Message::ClipboardContent { session, data } => {
+                self.answer_clipboard(session, &data.0).await
+            },
+            Message::Signal { session, signal } => self.signal(session, signal),
+            Message::Close { session } => self.close(session),
+            Message::Hello { .. }
+            | Message::Ping { .. }
+            | Message::Pong { .. }
+            | Message::Scheduled { .. }
+            | Message::Queued { .. }
+            | Message::Started { .. }
+            | Message::Opened { .. }
+            | Message::RoleChanged { .. }
+            | Message::AttachedClients { .. }
+            | Message::EnvironmentSnapshot { .. }
+            | Message::JobList { .. }
+            | Message::JobDetails { .. }
+            | Message::Detached { .. }
+            | Message::Output { .. }
+            | Message::Paused { .. }
+            | Message::Resumed { .. }
+            | Message::Idle { .. }
+            | Message::SessionMeta { .. }
+            | Message::ClipboardSet { .. }
+            | Message::ClipboardGet { .. }
+            | Message::Exit { .. }
+            | Message::Error { .. }
+            | Message::FileUploadStart { .. }
+            | Message::FileUploadChunk { .. }
+            | Message::FileUploadEnd { .. }
+            | Message::FileUploaded { .. }
+            | Message::FileDownloadRequest { .. }
+            | Message::FileDownloadStart { .. }
+            | Message::FileDownloadChunk { .. }
+            | Message::FileDownloadEnd { .. }
+            | Message::TransferFailed { .. }
+            | Message::TcpForward { .. }
+            | Message::ForwardOpened { .. }
+            | Message::ForwardData { .. }
+            | Message::ForwardClose { .. }
+            | Message::ForwardFailed { .. } => Err(anyhow!("unexpected message from client")),
+        }
+    }
+
+    /// Start a new session running `program` on a terminal, on the server or wherever its profile
+    /// runs, and remember how for cloning it
+    pub async fn open_program(
+        &self,
+        id: SessionId,
+        program: Program,
+        cwd: Option<PathBuf>,
+        env: Environment,
+        size: PtySize,
+        options: SessionOptions,
+    ) -> Result<()> {
+        let origin = Origin {
+            program: program.clone(),
+            cwd: cwd.clone(),
+            env: env.clone(),
+            options,
+        };
+        match self.remote_profile(&program) {
+            Some(profile) => {
+                self.open_remote(id, profile, cwd, &env, size, options)
+                    .await?
+            },
+            None => self.open(id, program, cwd, &env, size, options)?,
+        }
+        // the command may be gone already, then there is nothing left to clone
+        let _ = self.with_attached_session(id, |session| session.origin = Some(origin));
+        Ok(())
+    }
+
+    /// Start session `id` like session `from`, see [`Message::CloneSession`]
+    pub async fn clone_session(&self, id: SessionId, from: SessionId, size: PtySize) -> Result<()> {
+        let err_context = || format!("failed to clone session {}", from);
+
+        let (origin, cwd) = self
+            .with_session(from, |session| {
+                let origin = session
+                    .origin
+                    .clone()
+                    .ok_or_else(|| anyhow!("only sessions opened on a terminal can be cloned"))?;
+                let cwd = session
+                    .cwd
+                    .clone()
+                    .or_else(|| match session.process() {
+                        Ok(Process::Pty(pty)) if self.sees_cwd_of(&origin.program) => {
+                            fs::read_link(format!("/proc/{}/cwd", pty.pid())).ok()
+                        },
+                        _ => None,
+                    })
+                    .or_else(|| origin.cwd.clone());
+                Ok((origin, cwd))
+            })
+            .and_then(|result: Result<_>| result)
+            .with_context(err_context)?;
+        self.open_program(id, origin.program, cwd, origin.env, size, origin.options)
+            .await
+    }
+
+    /// Whether the server sees the working directory of the command `program` runs on the server
+    /// like the command itself does, which it doesn't if the command has a root of its own
+    fn sees_cwd_of(&self, program: &Program) -> bool {
+        if self.config.jail.as_ref().is_some_and(|jail| jail.chroot) {
+            return false;
+        }
+        match program {
+            // the process waiting for an isolated command is in the directory it started in
+            Program::Profile(name) => self
+                .config
+                .profiles
+                .get(name)
+                .is_some_and(|profile| profile.isolation == Isolation::None),
+            Program::Command(_) | Program::Shell => true,
+        }
+    }
+
+    /// Start a new session running `program` on a terminal. The environment and resource limits
+    /// of a profile are applied on top of `env` and the server's limits, the server's
+    /// environment overrides on top of both. The command is terminated after the timeout of
+    /// `options`, or the server's or profile's timeout if that is shorter.
+    pub fn open(
+        &self,
+        id: SessionId,
+        program: Program,
+        cwd: Option<PathBuf>,
+        env: &Environment,
+        size: PtySize,
+        options: SessionOptions,
+    ) -> Result<()> {
+        let err_context = || format!("failed to open session {}", id);
+
+        let mut sessions = self
+            .registry
+            .sessions
+            .lock()
+            .to_anyhow()
+            .with_context(err_context)?;
+        if sessions.contains_key(&id) {
+            return Err(anyhow!("session already exists")).with_context(err_context);
+        }
+        self.check_session_limits(&sessions, true)
+            .with_context(err_context)?;
+        let policy = self.policy();
+        if let Some(policy) = policy {
+            policy.check_pty().with_context(err_context)?;
+        }
+        let (mut command, profile, name) = match program {
+            Program::Command(command) => {
+                self.config
+                    .commands
+                    .check(&command)
+                    .with_context(err_context)?;
+                if let Some(policy) = policy {
+                    policy.check(&command).with_context(err_context)?;
+                }
+                (command, None, None)
+            },
+            Program::Profile(name) => {
+                let profile = self.config.profile(&name).with_context(err_context)?;
+                let command = profile.command();
+                if let Some(policy) = policy {
+                    policy.check_profile(&command).with_context(err_context)?;
+                }
+                (command, Some(profile), Some(name))
+            },
+            Program::Shell => {
+                let command = self.config.shell.clone();
+                if let Some(policy) = policy {
+                    policy.check_profile(&command).with_context(err_context)?;
+                }
+                (command, None, None)
+            },
+        };
+        if cwd.is_some() {
+            command.cwd = cwd;
+        }
+        let resources = match profile {
+            Some(profile) => self
+                .config
+                .limits
+                .resources
+                .overridden_by(&profile.resources),
+            None => self.config.limits.resources.clone(),
+        };
+        let hardening = match profile {
+            Some(profile) => self.config.hardening.overridden_by(&profile.hardening),
+            None => self.config.hardening.clone(),
+        };
+        let (sandbox, cgroup) = self
+            .confine(id, &mut command, &resources, &hardening, profile)
+            .with_context(err_context)?;
+        let login = profile.map(|profile| profile.login).unwrap_or_default();
+        let user = match (login.user_env || login.utmp, sandbox.user.as_ref()) {
+            (false, _) => None,
+            (true, Some(user)) => Some(user.clone()),
+            (true, None) => Some(Credentials::current().with_context(err_context)?),
+        };
+        let env = match profile {
+            Some(profile) => {
+                let mut env = env.overridden_by(&profile.env);
+                if let Some(user) = user.as_ref().filter(|_| login.user_env) {
+                    env = env.overridden_by(&user.environment());
+                }
+                self.environment(&env, &sandbox)
+            },
+            None => self.environment(env, &sandbox),
+        };
+        let env = self
+            .scripted(id, name.as_deref(), &command, env)
+            .with_context(err_context)?;
+        let recording = match self.config.recording.as_ref() {
+            Some(config) => {
+                let recording =
+                    Recording::create(config, &self.config.redaction, id, &command, size)
+                        .with_context(err_context)?;
+                match profile.and_then(|profile| profile.input) {
+                    Some(input) => Some(recording.with_input(input)),
+                    None => Some(recording),
+                }
+            },
+            None => None,
+        };
+        let capture = self.capture(id).with_context(err_context)?;
+        let pty = self
+            .registry
+            .spawner
+            .spawn(&command, &env, &sandbox, size)
+            .with_context(err_context)?;
+        info!("spawned '{}' with pid {}", command, pty.pid());
+        let login = match (user.filter(|_| login.utmp), pty.tty()) {
+            (Some(user), Some(tty)) => {
+                let host = self.client.address.ip().to_string();
+                LoginRecord::login(&tty, &user.name, &host, pty.pid())
+                    .inspect_err(|e| warn!("{:#}", e))
+                    .ok()
+            },
+            _ => None,
+        };
+
+        let (output_filter, input_filter) = self
+            .filters(id, name.as_deref().zip(profile), &options)
+            .with_context(err_context)?;
+        let pumps = vec![self.pump_output(id, pty.reader(), None, output_filter)];
+        let requested = shortest(options.timeout, profile.and_then(|profile| profile.timeout));
+        if let Some(timeout) = self.command_timeout(requested) {
+            self.enforce_timeout(id, pty.pid(), timeout, pty.exited());
+        }
+        let exited = pty.exited();
+        let mut session = self.new_session(id, Process::Pty(pty), command, cgroup);
+        session.input_filter = input_filter;
+        session.recording = recording;
+        session.capture = capture;
+        session.login = login;
+        session.environment = env.resolve();
+        self.send(Message::Opened {
+            session: id,
+            recording: session.recording.is_some(),
+            recording_input: session.records_input(),
+            role: AttachRole::Writer,
+        });
+        sessions.insert(id, session);
+        self.finish_when_done(id, pumps, exited);
+        Ok(())
+    }
+
+    /// The name of the profile `program` asks for and the profile, if it runs elsewhere than on
+    /// the server
+    fn remote_profile<'a>(&'a self, program: &'a Program) -> Option<(&'a str, &'a Profile)> {
+        match program {
+            Program::Profile(name) => self
+                .config
+                .profiles
+                .get(name)
+                .filter(|profile| profile.backend != Backend::Local)
+                .map(|profile| (name.as_str(), profile)),
+            _ => None,
+        }
+    }
+
+    /// The filters of the output and the input of session `id`: those of `profile`, given by
+    /// name, if it runs one, followed by those `options` ask for
+    fn filters(
+        &self,
+        id: SessionId,
+        profile: Option<(&str, &Profile)>,
+        options: &SessionOptions,
+    ) -> Result<(FilterChain, InputChain)> {
+        let Some((name, profile)) = profile else {
+            let output = self.scripted_output(id, options.output_filter());
+            return Ok((output, InputChain::default()));
+        };
+        let filters = &self.registry.filters;
+        let context = FilterContext {
+            session: id,
+            profile: name,
+            config: &self.config,
+        };
+        let mut output = filters.output_chain(&profile.output_filters, &context)?;
+        output.push(options.output_filter());
+        let input = filters.input_chain(&profile.input_filters, &context)?;
+        Ok((self.scripted_output(id, output), input))
+    }
+
+    /// `output` followed by the `on_output_chunk` hook of the script, if there is one
+    fn scripted_output(&self, id: SessionId, output: FilterChain) -> FilterChain {
+        #[cfg(feature = "scripting")]
+        if let Some(filter) = self
+            .registry
+            .scripts()
+            .and_then(|scripts| scripts.output_filter(&self.client, id))
+        {
+            let mut output = output;
+            output.push(filter);
+            return output;
+        }
+        #[cfg(not(feature = "scripting"))]
+        let _ = id;
+        output
+    }
+
+    /// The environment session `id` runs `command` with instead of `env`, as the `on_command`
+    /// hook of the script has it if there is one, `profile` being the name of the profile it
+    /// runs if any. Fails if the hook refuses the command.
+    fn scripted(
+        &self,
+        id: SessionId,
+        profile: Option<&str>,
+        command: &RunCommand,
+        env: Environment,
+    ) -> Result<Environment> {
+        #[cfg(feature = "scripting")]
+        if let Some(scripts) = self.registry.scripts() {
+            // hooks run to completion, there's no telling how long that takes
+            return block_in_place(|| {
+                scripts.on_command(&self.client, id, profile, command, env)
+            });
+        }
+        #[cfg(not(feature = "scripting"))]
+        let _ = (id, profile, command);
+        Ok(env)
+    }
+
+    /// Start a new session running the command of `profile`, given along with its name, on a
+    /// terminal in its container, pod or host. The environment of the profile is applied on top
+    /// of `env`, the timeout is that of [`SessionManager::open`].
+    pub async fn open_remote(
+        &self,
+        id: SessionId,
+        (name, profile): (&str, &Profile),
+        cwd: Option<PathBuf>,
+        env: &Environment,
+        size: PtySize,
+        options: SessionOptions,
+    ) -> Result<()> {
+        let err_context = || format!("failed to open session {}", id);
+
+        {
+            let sessions = self
+                .registry
+                .sessions
+                .lock()
+                .to_anyhow()
+                .with_context(err_context)?;
+            if sessions.contains_key(&id) {
+                return Err(anyhow!("session already exists")).with_context(err_context);
+            }
+            self.check_session_limits(&sessions, true)
+                .with_context(err_context)?;
+            if let Some(policy) = self.policy() {
+                policy.check_pty().with_context(err_context)?;
+                policy
+                    .check_profile(&profile.command())
+                    .with_context(err_context)?;
+                // the directory of the profile is the operator's choice, not the client's
+                if let Some(cwd) = cwd.as_deref() {
+                    policy.check_remote_cwd(cwd).with_context(err_context)?;
+                }
+            }
+        }
+        let mut command = profile.command();
+        if cwd.is_some() {
+            command.cwd = cwd;
+        }
+        let env = self
+            .scripted(id, Some(name), &command, env.overridden_by(&profile.env))
+            .with_context(err_context)?;
+        let (process, output, pid): (Process, Box<dyn AsyncRead + Unpin + Send>, _) =
+            match profile.backend {
+                Backend::Docker => {
+                    let container = profile
+                        .container
+                        .as_deref()
+                        .ok_or_else(|| anyhow!("profile names no container to run in"))
+                        .with_context(err_context)?;
+                    let mut exec =
+                        ContainerExec::start(&self.config.docker, container, &command, &env, size)
+                            .await
+                            .with_context(err_context)?;
+                    info!("started '{}' in container '{}'", command, container);
+                    let output = Box::new(exec.reader());
+                    let pid = exec.pid();
+                    (Process::Container(exec), output, pid)
+                },
+                #[cfg(feature = "kubernetes")]
+                Backend::Kubernetes => {
+                    let pod = profile
+                        .pod
+                        .as_deref()
+                        .ok_or_else(|| anyhow!("profile names no pod to run in"))
+                        .with_context(err_context)?;
+                    let exec = PodExec::start(
+                        profile.namespace.as_deref(),
+                        pod,
+                        profile.container.as_deref(),
+                        &command,
+                        &env,
+                        size,
+                    )
+                    .await
+                    .with_context(err_context)?;
+                    info!("started '{}' in pod '{}'", command, pod);
+                    let output = Box::new(exec.reader());
+                    (Process::Pod(exec), output, None)
+                },
+                #[cfg(not(feature = "kubernetes"))]
+                Backend::Kubernetes => {
+                    return Err(anyhow!("the server is built without Kubernetes support"))
+                        .with_context(err_context)
+                },
+                #[cfg(feature = "ssh")]
+                Backend::Ssh => {
+                    let missing = |what| anyhow!("profile names no {} to log in with", what);
+                    let target = SshTarget {
+                        host: profile
+                            .host
+                            .as_deref()
+                            .ok_or_else(|| anyhow!("profile names no host to run on"))
+                            .with_context(err_context)?,
+                        user: profile
+                            .user
+                            .as_deref()
+                            .ok_or_else(|| missing("user"))
+                            .with_context(err_context)?,
+                        key: profile
+                            .key
+                            .as_deref()
+                            .ok_or_else(|| missing("key"))
+                            .with_context(err_context)?,
+                        known_hosts: profile.known_hosts.as_deref(),
+                    };
+                    let exec = SshExec::start(target, &command, &env, size)
+                        .await
+                        .with_context(err_context)?;
+                    info!("started '{}' on '{}'", command, target.host);
+                    let output = Box::new(exec.reader());
+                    (Process::Ssh(exec), output, None)
+                },
+                #[cfg(not(feature = "ssh"))]
+                Backend::Ssh => {
+                    return Err(anyhow!("the server is built without SSH support"))
+                        .with_context(err_context)
+                },
+                #[cfg(feature = "plugins")]
+                Backend::Plugin => {
+                    let name = profile
+                        .plugin
+                        .as_deref()
+                        .ok_or_else(|| anyhow!("profile names no plugin to run in"))
+                        .with_context(err_context)?;
+                    let plugin = self
+                        .registry
+                        .plugins
+                        .get(name)
+                        .ok_or_else(|| anyhow!("no plugin named '{}'", name))
+                        .with_context(err_context)?;
+                    let exec = PluginExec::start(plugin, &command, &env, size)
+                        .with_context(err_context)?;
+                    info!("started '{}' in plugin '{}'", command, name);
+                    let output = Box::new(exec.reader());
+                    (Process::Plugin(exec), output, None)
+                },
+                #[cfg(not(feature = "plugins"))]
+                Backend::Plugin => {
+                    return Err(anyhow!("the server is built without plugin support"))
+                        .with_context(err_context)
+                },
+                Backend::Local => {
+                    return Err(anyhow!("profile runs on the server")).with_context(err_context)
+                },
+            };
+
+        let mut sessions = self
+            .registry
+            .sessions
+            .lock()
+            .to_anyhow()
+            .with_context(err_context)?;
+        // the client may have reused the id while the command was being started, dropping
+        // `process` hangs it up again
+        if sessions.contains_key(&id) {
+            return Err(anyhow!("session already exists")).with_context(err_context);
+        }
+        let recording = match self.config.recording.as_ref() {
+            Some(config) => {
+                let recording =
+                    Recording::create(config, &self.config.redaction, id, &command, size)
+                        .with_context(err_context)?;
+                match profile.input {
+                    Some(input) => Some(recording.with_input(input)),
+                    None => Some(recording),
+                }
+            },
+            None => None,
+        };
+        let capture = self.capture(id).with_context(err_context)?;
+        let (output_filter, input_filter) = self
+            .filters(id, Some((name, profile)), &options)
+            .with_context(err_context)?;
+        let pumps = vec![self.pump_output(id, output, None, output_filter)];
+        let requested = shortest(options.timeout, profile.timeout);
+        match (self.command_timeout(requested), pid) {
+            (Some(timeout), Some(pid)) => self.enforce_timeout(id, pid, timeout, process.exited()),
+            (Some(_), None) => warn!("can't time out '{}' without knowing its pid", command),
+            (None, _) => {},
+        }
+        let exited = process.exited();
+        let mut session = self.new_session(id, process, command, None);
+        session.input_filter = input_filter;
+        session.recording = recording;
+        session.capture = capture;
+        session.environment = env.vars;
+        self.send(Message::Opened {
+            session: id,
+            recording: session.recording.is_some(),
+            recording_input: session.records_input(),
+            role: AttachRole::Writer,
+        });
+        sessions.insert(id, session);
+        self.finish_when_done(id, pumps, exited);
+        Ok(())
+    }
+
+    /// Start a new session running `command` without a terminal, with its stderr sent along with
+    /// its stdout if `merge_stderr` is set. See [`SessionManager::open`] for `options`.
+    pub fn run(
+        &self,
+        id: SessionId,
+        mut command: RunCommand,
+        env: &Environment,
+        merge_stderr: bool,
+        options: SessionOptions,
+        slot: JobSlot,
+    ) -> Result<()> {
+        let err_context = || format!("failed to run session {}", id);
+
+        let mut sessions = self
+            .registry
+            .sessions
+            .lock()
+            .to_anyhow()
+            .with_context(err_context)?;
+        if sessions.contains_key(&id) {
+            return Err(anyhow!("session already exists")).with_context(err_context);
+        }
+        self.check_session_limits(&sessions, true)
+            .with_context(err_context)?;
+        self.config
+            .commands
+            .check(&command)
+            .with_context(err_context)?;
+        if let Some(policy) = self.policy() {
+            policy.check(&command).with_context(err_context)?;
+        }
+        let (sandbox, cgroup) = self
+            .confine(
+                id,
+                &mut command,
+                &self.config.limits.resources,
+                &self.config.hardening,
+                None,
+            )
+            .with_context(err_context)?;
+        let env = self
+            .scripted(id, None, &command, self.environment(env, &sandbox))
+            .with_context(err_context)?;
+        let capture = self.capture(id).with_context(err_context)?;
+        let mut exec =
+            Exec::spawn(&command, &env, &sandbox, merge_stderr).with_context(err_context)?;
+        info!("executing '{}' with pid {}", command, exec.pid());
+
+        let mut pumps = vec![];
+        if let Some(stdout) = exec.take_stdout() {
+            pumps.push(self.pump_output(
+                id,
+                stdout,
+                Some(StdStream::Stdout),
+                self.scripted_output(id, options.output_filter()),
+            ));
+        }
+        if let Some(stderr) = exec.take_stderr() {
+            pumps.push(self.pump_output(
+                id,
+                stderr,
+                Some(StdStream::Stderr),
+                self.scripted_output(id, options.output_filter()),
+            ));
+        }
+        if let Some(output) = exec.take_merged() {
+            let filter = self.scripted_output(id, options.output_filter());
+            pumps.push(self.pump_output(id, output, None, filter));
+        }
+        if let Some(timeout) = self.command_timeout(options.timeout) {
+            self.enforce_timeout(id, exec.pid(), timeout, exec.exited());
+        }
+        let exited = exec.exited();
+        let mut session = self.new_session(id, Process::Exec(exec), command, cgroup);
+        session.environment = env.resolve();
+        session.capture = capture;
+        session.slot = Some(slot);
+        sessions.insert(id, session);
+        self.send(Message::Opened {
+            session: id,
+            recording: false,
+            recording_input: false,
+            role: AttachRole::Writer,
+        });
+        self.finish_when_done(id, pumps, exited);
+        Ok(())
+    }
+
+    /// Check that session `id` could run `command` before it is put off, it is checked again
+    /// when it is started
+    fn check_pending(&self, id: SessionId, command: &RunCommand) -> Result<()> {
+        let exists = self.registry.sessions.lock().to_anyhow()?.contains_key(&id);
+        let queued = self.queued.lock().to_anyhow()?.contains_key(&id);
+        if exists || queued || self.schedule.contains(id) {
+            return Err(anyhow!("session already exists"));
+        }
+        self.config.commands.check(command)?;
+        if let Some(policy) = self.policy() {
+            policy.check(command)?;
+        }
+        Ok(())
+    }
+
+    /// Queue the command of session `id` to be [started](SessionManager::start_run) at `at`
+    fn schedule_run(&self, id: SessionId, at: SystemTime, job: PendingRun) -> Result<()> {
+        let err_context = || format!("failed to schedule session {}", id);
+
+        self.check_pending(id, &job.command)
+            .with_context(err_context)?;
+        let at_text = humantime::format_rfc3339_seconds(at).to_string();
+        info!("scheduled '{}' for {}", job.command, at_text);
+        self.schedule
+            .add(id, at, job, self.config.limits.max_scheduled)
+            .with_context(err_context)?;
+        self.send(Message::Scheduled {
+            session: id,
+            at: at_text,
+        });
+        Ok(())
+    }
+
+    /// [Run](SessionManager::run) the command of session `id` if the job queue has a slot for
+    /// it, else queue it until there is one. Clients are told that commands that were
+    /// `scheduled` are started.
+    fn start_run(&self, id: SessionId, job: PendingRun, scheduled: bool) -> Result<()> {
+        let err_context = || format!("failed to run session {}", id);
+
+        self.check_pending(id, &job.command)
+            .with_context(err_context)?;
+        let slot = self
+            .registry
+            .jobs
+            .acquire(
+                self.client.identity(),
+                id,
+                self.due.clone(),
+                self.events.clone(),
+            )
+            .with_context(err_context)?;
+        let Some(slot) = slot else {
+            info!("queued '{}' until fewer commands run", job.command);
+            self.queued
+                .lock()
+                .to_anyhow()
+                .with_context(err_context)?
+                .insert(id, job);
+            return Ok(());
+        };
+        if scheduled {
+            self.send(Message::Started { session: id });
+        }
+        self.run(
+            id,
+            job.command,
+            &job.env,
+            job.merge_stderr,
+            job.options,
+            slot,
+        )
+    }
+
+    /// Start the command of the connection that is `due`, unless it was cancelled in the
+    /// meantime. Failing to start it is reported to the client like any other failure of the
+    /// session.
+    pub fn start_due(&self, due: Due) {
+        let (id, result) = match due {
+            Due::Scheduled(id) => {
+                let Some(job) = self.schedule.take(id) else {
+                    return;
+                };
+                (
+                    id,
+                    session_span(id).in_scope(|| self.start_run(id, job, true)),
+                )
+            },
+            Due::Queued(id, slot) => {
+                let job = self
+                    .queued
+                    .lock()
+                    .ok()
+                    .and_then(|mut queued| queued.remove(&id));
+                // the slot goes to the next command if this one is gone
+                let Some(job) = job else {
+                    return;
+                };
+                let result = session_span(id).in_scope(|| {
+                    self.send(Message::Started { session: id });
+                    self.run(
+                        id,
+                        job.command,
+                        &job.env,
+                        job.merge_stderr,
+                        job.options,
+                        slot,
+                    )
+                });
+                (id, result)
+            },
+        };
+        if let Err(e) = result {
+            self.send_error(Some(id), &e);
+        }
+    }
+
+    /// Send the client up to `limit` of the jobs it ran that started `since` or later, see
+    /// [`Message::ListJobs`]
+    #[cfg(feature = "history")]
+    fn list_jobs(&self, limit: Option<usize>, since: Option<&str>) -> Result<()> {
+        let history = self.history()?;
+        let since = since
+            .map(|since| {
+                humantime::parse_rfc3339_weak(since)
+                    .with_context(|| format!("invalid time '{}'", since))
+            })
+            .transpose()?;
+        let identity = self.client.identity();
+        let jobs = block_in_place(|| history.list(Some(&identity), since, limit))?;
+        self.send(Message::JobList { jobs });
+        Ok(())
+    }
+
+    /// Send the client its job `id` along with the tail of its output
+    #[cfg(feature = "history")]
+    fn fetch_job(&self, id: SessionId) -> Result<()> {
+        let history = self.history()?;
+        let identity = self.client.identity();
+        let job = block_in_place(|| history.get(id, Some(&identity)))?
+            .ok_or(SessionNotFound)
+            .with_context(|| format!("failed to fetch job {}", id))?;
+        self.send(Message::JobDetails { job });
+        Ok(())
+    }
+
+    #[cfg(feature = "history")]
+    fn history(&self) -> Result<&History> {
+        self.registry
+            .history()
+            .ok_or_else(|| anyhow!("the server keeps no job history"))
+    }
+
+    /// Take the command of session `id` off the schedule or out of the job queue, telling the
+    /// client it exited
+    fn cancel_scheduled(&self, id: SessionId) -> Result<()> {
+        let queued = || {
+            let job = self
+                .queued
+                .lock()
+                .ok()
+                .and_then(|mut queued| queued.remove(&id));
+            self.registry.jobs.remove(id);
+            job
+        };
+        let cancelled = self.schedule.take(id).is_some() || queued().is_some();
+        if !cancelled {
+            return Err(SessionNotFound)
+                .with_context(|| format!("failed to cancel session {}", id));
+        }
+        info!("cancelled command before it started");
+        self.send(Message::Exit {
+            session: id,
+            code: None,
+            signal: None,
+            reason: Some(ExitReason::Cancelled),
+        });
+        Ok(())
+    }
+
+    /// Start a new session on serial `device`, configured with `settings`. Its output is sent as
+    /// read if `raw_output` is set.
+    pub fn open_serial(
+        &self,
+        id: SessionId,
+        device: PathBuf,
+        settings: &SerialSettings,
+        raw_output: bool,
+    ) -> Result<()> {
+        let err_context = || format!("failed to open session {}", id);
+
+        let mut sessions = self
+            .registry
+            .sessions
+            .lock()
+            .to_anyhow()
+            .with_context(err_context)?;
+        if sessions.contains_key(&id) {
+            return Err(anyhow!("session already exists")).with_context(err_context);
+        }
+        self.check_session_limits(&sessions, true)
+            .with_context(err_context)?;
+        // a terminal on the device is as interactive as one on a pty
+        if let Some(policy) = self.policy() {
+            policy.check_pty().with_context(err_context)?;
+        }
+        self.config
+            .serial
+            .check(&device)
+            .with_context(err_context)?;
+        let capture = self.capture(id).with_context(err_context)?;
+        let serial = Serial::open(&device, settings).with_context(err_context)?;
+        info!(
+            "opened serial device '{}' at {} baud",
+            device.display(),
+            settings.baud_rate
+        );
+
+        let options = SessionOptions {
+            raw_output,
+            ..Default::default()
+        };
+        let pump = self.pump_output(id, serial.reader(), None, options.output_filter());
+        let process = Process::Serial(serial, pump.abort_handle());
+        // the device stands in for the command in the audit trail
+        let command = RunCommand {
+            command: device,
+            ..Default::default()
+        };
+        let mut session = self.new_session(id, process, command, None);
+        session.capture = capture;
+        sessions.insert(id, session);
+        self.send(Message::Opened {
+            session: id,
+            recording: false,
+            recording_input: false,
+            role: AttachRole::Writer,
+        });
+        self.finish_when_done(id, vec![pump], future::ready(None));
+        Ok(())
+    }
+
+    fn new_session(
+        &self,
+        id: SessionId,
+        process: Process,
+        command: RunCommand,
+        cgroup: Option<Cgroup>,
+    ) -> Session {
+        METRICS.sessions.inc();
+        let started_at = SystemTime::now();
+        self.audit
+            .record(&Record::started(id, &self.client, &command, started_at));
+        self.registry.notify(&Notification {
+            session: Some(id),
+            command: Some(command.to_string()),
+            ..Notification::new(NotifyEvent::SessionStarted, &self.client)
+        });
+        Session {
+            process: Some(process),
+            command,
+            cgroup,
+            scrollback: Scrollback::new(self.config.sessions.scrollback),
+            writer: Some(self.attachment()),
+            viewers: vec![],
+            detached_at: None,
+            recording: None,
+            capture: None,
+            timed_out: None,
+            killed: false,
+            acked: 0,
+            throttle: self.config.limits.session_bandwidth.map(Throttle::new),
+            input_throttle: self.config.limits.input_rate.map(Throttle::new),
+            last_active: Instant::now(),
+            idleness: Idleness::Active,
+            line_editor: None,
+            input_filter: InputChain::default(),
+            clipboard_query: None,
+            title: None,
+            cwd: None,
+            origin: None,
+            slot: None,
+            environment: BTreeMap::new(),
+            opened_by: self.client.clone(),
+            started_at,
+            bytes_in: 0,
+            bytes_out: 0,
+            framing: None,
+            login: None,
+        }
+    }
+
+    /// Where the output of session `id` is captured to, `None` if it isn't
+    fn capture(&self, id: SessionId) -> Result<Option<Capture>> {
+        match self.config.capture.as_ref() {
+            Some(config) => Capture::create(config, &self.config.redaction, id).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// What the client may do on top of what the server allows anybody
+    fn policy(&self) -> Option<&UserPolicy> {
+        self.config.policy_for(&self.client)
+    }
+
+    /// Confine `command` of session `id` to the jail, if one is configured, resolving its working
+    /// directory and checking it against the client's policy, isolate it like `profile` says,
+    /// limit the resources it may use to `resources` and switch it to the configured user
+    fn confine(
+        &self,
+        id: SessionId,
+        command: &mut RunCommand,
+        resources: &ResourceLimits,
+        hardening: &Hardening,
+        profile: Option<&Profile>,
+    ) -> Result<(Sandbox, Option<Cgroup>)> {
+        let isolated = profile.filter(|profile| profile.isolation == Isolation::Namespaces);
+        let rootfs = isolated.and_then(|profile| profile.rootfs.as_ref());
+        let mut sandbox = match (rootfs, self.config.jail.as_ref()) {
+            // the working directory is within the root filesystem like in a chrooted jail, and
+            // the config may not have both
+            (Some(rootfs), _) => {
+                let jail = Jail {
+                    root: rootfs.clone(),
+                    chroot: true,
+                };
+                command.cwd = Some(jail.resolve(command.cwd.as_deref())?);
+                Sandbox::default()
+            },
+            (None, Some(jail)) => {
+                command.cwd = Some(jail.resolve(command.cwd.as_deref())?);
+                jail.sandbox()?
+            },
+            (None, None) => Sandbox::default(),
+        };
+        if let Some(profile) = isolated {
+            sandbox.namespaces = Some(Namespaces::new(profile, command.cwd.as_deref())?);
+        }
+        if let Some(policy) = self.policy() {
+            policy.check_cwd(command.cwd.as_deref())?;
+        }
+        let cgroup = resources.cgroup(id);
+        sandbox.rlimits = resources.rlimits(cgroup.as_ref());
+        sandbox.cgroup_procs = cgroup.as_ref().map(Cgroup::procs_fd);
+        if let Some(run_as) = self.config.run_as.as_ref() {
+            sandbox.user = Some(run_as.credentials()?);
+        }
+        sandbox.no_new_privs = hardening.no_new_privs();
+        sandbox.seccomp = hardening.seccomp_filter()?;
+        Ok((sandbox, cgroup))
+    }
+
+    /// The environment for a command in `sandbox` the client asked for `env` for. It starts out
+    /// describing the user the command runs as, and the configured variables win over all else.
+    fn environment(&self, env: &Environment, sandbox: &Sandbox) -> Environment {
+        let env = match sandbox.user.as_ref() {
+            Some(user) => user.environment().overridden_by(env),
+            None => env.clone(),
+        };
+        env.overridden_by(&self.config.env)
+    }
+
+    /// The timeout for a command the client asked for `requested` seconds for, the server's
+    /// timeout wins if it is shorter
+    fn command_timeout(&self, requested: Option<u64>) -> Option<u64> {
+        shortest(requested, self.config.limits.command_timeout)
+    }
+
+    /// Terminate the command of session `id`, running as `pid`, if it hasn't `exited` within
+    /// `timeout` seconds: with SIGTERM first and SIGKILL if it is still around after
+    /// [`TIMEOUT_GRACE_PERIOD`]. Both go to its process group, which would otherwise keep the
+    /// session's output open.
+    fn enforce_timeout(
+        &self,
+        id: SessionId,
+        pid: Pid,
+        timeout: u64,
+        exited: impl Future<Output = Option<ExitStatus>> + Send + 'static,
+    ) {
+        let registry = self.registry.clone();
+        tokio::spawn(
+            async move {
+                tokio::pin!(exited);
+                if time::timeout(Duration::from_secs(timeout), &mut exited)
+                    .await
+                    .is_ok()
+                {
+                    return;
+                }
+                info!("timed out after {}s, terminating it", timeout);
+                if let Ok(mut sessions) = registry.sessions.lock() {
+                    if let Some(session) = sessions.get_mut(&id) {
+                        session.timed_out = Some(timeout);
+                    }
+                }
+                let _ = killpg(pid, Signal::SIGTERM);
+                if time::timeout(TIMEOUT_GRACE_PERIOD, &mut exited)
+                    .await
+                    .is_ok()
+                {
+                    return;
+                }
+                warn!("still running after SIGTERM, killing it");
+                let _ = killpg(pid, Signal::SIGKILL);
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Refuse to attach another session if the connection has as many as it may have. A `new`
+    /// one, rather than one attached again, also counts towards the limits of the client and of
+    /// the server.
+    fn check_session_limits(
+        &self,
+        sessions: &HashMap<SessionId, Session>,
+        new: bool,
+    ) -> Result<(), TooManySessions> {
+        let limits = &self.config.limits;
+        if let Some(limit) = limits.max_sessions {
+            let attached = sessions
+                .values()
+                .filter(|session| session.is_attached_to(&self.events))
+                .count();
+            if attached >= limit {
+                let scope = LimitScope::Connection;
+                return Err(TooManySessions { limit, scope });
+            }
+        }
+        if !new {
+            return Ok(());
+        }
+        let client_limit = limits.max_client_sessions.into_iter();
+        let policy_limit = self.policy().and_then(|policy| policy.max_sessions);
+        if let Some(limit) = client_limit.chain(policy_limit).min() {
+            let identity = self.client.identity();
+            let opened = sessions
+                .values()
+                .filter(|session| session.opened_by.identity() == identity)
+                .count();
+            if opened >= limit {
+                let scope = LimitScope::Client;
+                return Err(TooManySessions { limit, scope });
+            }
+        }
+        if let Some(limit) = limits.max_total_sessions {
+            if sessions.len() >= limit {
+                let scope = LimitScope::Server;
+                return Err(TooManySessions { limit, scope });
+            }
+        }
+        Ok(())
+    }
+
+    /// Forward everything read from `reader` to the client attached to session `id`, keeping it
+    /// in the session's scrollback as well. Output passes through `filter` first.
+    ///
+    /// Output is read into a buffer that is used again once all that was read into it was sent.
+    /// Clients attached, the scrollback and the message sent share the bytes read, only filters
+    /// changing them copy them. Reads following one another closely are sent together and the
+    /// frames sized for the clients attached, see [`Coalescing`].
+    fn pump_output(
+        &self,
+        id: SessionId,
+        mut reader: impl AsyncRead + Unpin + Send + 'static,
+        stream: Option<StdStream>,
+        mut filter: FilterChain,
+    ) -> JoinHandle<()> {
+        let registry = self.registry.clone();
+        let flow_control = self.config.flow_control.clone();
+        let coalescing = self.config.coalescing.clone();
+        let clipboard_read = self.config.sessions.clipboard_read;
+        tokio::spawn(
+            async move {
+                let mut buf = BytesMut::new();
+                let mut framing = Framing::new(&coalescing, None);
+                loop {
+                    // takes back the room of output every client and the scrollback are done with
+                    buf.reserve(framing.frame_size);
+                    let done =
+                        read_output(&mut reader, &mut buf, &framing, coalescing.max_delay()).await;
+                    let read = buf.split().freeze();
+                    let mut data = match read.is_empty() {
+                        true => Bytes::new(),
+                        false => filter.filter_bytes(read),
+                    };
+                    // whatever is held back goes out with the end of the output, incomplete or not
+                    if done {
+                        let held_back = filter.finish();
+                        if !held_back.is_empty() {
+                            data = [data.as_ref(), &held_back].concat().into();
+                        }
+                    }
+                    let events = filter.events();
+                    if !events.is_empty() {
+                        let Ok(mut sessions) = registry.sessions.lock() else {
+                            break;
+                        };
+                        let Some(session) = sessions.get_mut(&id) else {
+                            break;
+                        };
+                        for event in events {
+                            session.output_event(id, event, clipboard_read);
+                        }
+                    }
+                    if data.is_empty() {
+                        match done {
+                            true => break,
+                            false => continue,
+                        }
+                    }
+                    let n = data.len();
+                    let (congested, throttled) = {
+                        let Ok(mut sessions) = registry.sessions.lock() else {
+                            break;
+                        };
+                        let Some(session) = sessions.get_mut(&id) else {
+                            break;
+                        };
+                        METRICS.bytes_out(id, n);
+                        session.bytes_out += n as u64;
+                        session.active();
+                        if let Some(recording) = session.recording.as_mut() {
+                            recording.output(&data);
+                        }
+                        if let Some(capture) = session.capture.as_mut() {
+                            capture.output(&data);
+                        }
+                        let seq = session.scrollback.push(stream, &data);
+                        let output = Message::Output {
+                            session: id,
+                            data: Payload(data),
+                            stream,
+                            seq,
+                        };
+                        // the client closest by decides, the others make do with frames a bit
+                        // smaller than they could take
+                        let rtt = session
+                            .attachments()
+                            .filter_map(|client| client.round_trip.get())
+                            .min();
+                        framing = Framing::new(&coalescing, rtt);
+                        session.framing = Some(framing);
+                        let congested = session
+                            .attachments()
+                            .filter_map(|client| {
+                                client.send(output.clone());
+                                (client.backlog.len() >= flow_control.high_watermark)
+                                    .then(|| client.clone())
+                            })
+                            .collect::<Vec<_>>();
+                        let throttled = [session.throttle.as_ref(), registry.throttle.as_ref()]
+                            .into_iter()
+                            .flatten()
+                            .map(|throttle| throttle.take(n))
+                            .max()
+                            .unwrap_or_default();
+                        (congested, throttled)
+                    };
+                    // not reading leaves the output to the kernel, which blocks the command once the
+                    // buffer of its terminal or pipe is full. The slowest of the clients attached sets
+                    // the pace for all of them.
+                    for client in congested.iter() {
+                        client.send(Message::Paused { session: id });
+                    }
+                    for client in congested.iter() {
+                        tokio::select! {
+                            _ = client.backlog.drained_to(flow_control.low_watermark) => {},
+                            // its backlog is gone with the connection
+                            _ = client.events.closed() => {},
+                        }
+                    }
+                    for client in congested.iter() {
+                        client.send(Message::Resumed { session: id });
+                    }
+                    if done {
+                        break;
+                    }
+                    // the same goes for output sent faster than the bandwidth allows, which
+                    // waits until it catches up. Others sharing the link don't notice.
+                    if !throttled.is_zero() {
+                        let _throttled = Throttled::start();
+                        time::sleep(throttled).await;
+                        METRICS.throttled_seconds.inc_by(throttled.as_secs_f64());
+                    }
+                }
+            }
+            .in_current_span(),
+        )
+    }
+
+    /// Once all output of session `id` was forwarded, drop the session and tell the client it is
+    /// attached to how its command exited
+    fn finish_when_done(
+        &self,
+        id: SessionId,
+        pumps: Vec<JoinHandle<()>>,
+        exited: impl Future<Output = Option<ExitStatus>> + Send + 'static,
+    ) {
+        let registry = self.registry.clone();
+        let audit = self.audit.clone();
+        tokio::spawn(
+            async move {
+                for pump in pumps {
+                    let _ = pump.await;
+                }
+                // closing its output usually means the command is about to exit, give it a moment
+                // before dropping the session hangs up whatever is still running
+                tokio::pin!(exited);
+                let status = time::timeout(EXIT_GRACE_PERIOD, &mut exited).await.ok();
+                let mut session = registry
+                    .sessions
+                    .lock()
+                    .ok()
+                    .and_then(|mut sessions| sessions.remove(&id));
+                // only commands without a terminal are jobs for the history
+                #[cfg(feature = "history")]
+                let history = registry.history.clone().filter(|_| {
+                    session
+                        .as_ref()
+                        .is_some_and(|session| matches!(session.process, Some(Process::Exec(_))))
+                });
+                if let Some(session) = session.as_mut() {
+                    session.process = None;
+                }
+                METRICS.sessions.dec();
+                let status = match status {
+                    Some(status) => status,
+                    None => exited.await,
+                };
+                let code = status.and_then(|status| status.code());
+                let signal = status.and_then(exit_signal);
+                METRICS.session_exited(id, code);
+                match signal.as_ref() {
+                    Some(signal) => info!("terminated by {}", signal),
+                    None => info!("exited with code {:?}", code),
+                }
+                let Some(session) = session else {
+                    return;
+                };
+                let reason = match (session.killed, session.timed_out, session.idleness) {
+                    (true, _, _) => Some(ExitReason::Killed),
+                    (false, Some(timeout), _) => Some(ExitReason::TimedOut { timeout }),
+                    (false, None, Idleness::Closed { timeout }) => {
+                        Some(ExitReason::Idle { timeout })
+                    },
+                    (false, None, _) => exit_reason(status, session.cgroup.as_ref()),
+                };
+                if let Some(reason) = reason.as_ref() {
+                    info!("{:?}", reason);
+                }
+                #[cfg(feature = "scripting")]
+                if let Some(scripts) = registry.scripts() {
+                    let signal = signal.as_ref().map(ExitSignal::to_string);
+                    block_in_place(|| {
+                        scripts.on_exit(&session.opened_by, id, &session.command, code, signal)
+                    });
+                }
+                audit.record(&Record {
+                    event: Event::Exited,
+                    ended_at: Some(SystemTime::now()),
+                    exit_code: code,
+                    signal: signal.as_ref().map(ExitSignal::to_string),
+                    bytes_in: session.bytes_in,
+                    bytes_out: session.bytes_out,
+                    ..Record::started(id, &session.opened_by, &session.command, session.started_at)
+                });
+                registry.notify(&Notification {
+                    session: Some(id),
+                    command: Some(session.command.to_string()),
+                    exit_code: code,
+                    signal: signal.as_ref().map(ExitSignal::to_string),
+                    ..Notification::new(NotifyEvent::SessionEnded, &session.opened_by)
+                });
+                #[cfg(feature = "history")]
+                if let Some(history) = history {
+                    let job = JobRecord {
+                        session: id,
+                        command: session.command.to_string(),
+                        submitter: session.opened_by.identity(),
+                        started_at: humantime::format_rfc3339_millis(session.started_at)
+                            .to_string(),
+                        ended_at: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+                        code,
+                        signal: signal.clone(),
+                        reason: reason.clone(),
+                        output: Some(Payload::from(session.scrollback.tail(history.output_tail()))),
+                    };
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = history.record(&job) {
+                            warn!("{:#}", e);
+                        }
+                    });
+                }
+                session.broadcast(Message::Exit {
+                    session: id,
+                    code,
+                    signal,
+                    reason,
+                });
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Attach a running session to this connection in `role` and replay its scrollback after
+    /// `seq`, after the output its writer acknowledged last if not given. Writers take the
+    /// session away from its current writer, if any. Only sessions opened by a client of the same
+    /// [identity](Client::identity) can be attached, in either role.
+    pub fn attach(&self, id: SessionId, role: AttachRole, seq: Option<u64>) -> Result<()> {
+        self.attach_as(id, role, seq, false)
+    }
+
+    /// Watch session `id` like a viewer [attached](Self::attach) from `seq` on does, whoever
+    /// opened it. Only for operators the caller authorized itself, like those of the
+    /// [admin listener](crate::admin).
+    pub fn watch(&self, id: SessionId, seq: u64) -> Result<()> {
+        self.attach_as(id, AttachRole::Viewer, Some(seq), true)
+    }
+
+    /// Attach session `id` like [`attach`](Self::attach) does, for an operator if `operator`,
+    /// whom neither the owner of the session nor a policy stands in the way of
+    fn attach_as(
+        &self,
+        id: SessionId,
+        role: AttachRole,
+        seq: Option<u64>,
+        operator: bool,
+    ) -> Result<()> {
+        let err_context = || format!("failed to attach session {}", id);
+
+        let mut sessions = self
+            .registry
+            .sessions
+            .lock()
+            .to_anyhow()
+            .with_context(err_context)?;
+        self.check_session_limits(&sessions, false)
+            .with_context(err_context)?;
+        let session = sessions
+            .get_mut(&id)
+            .ok_or(SessionNotFound)
+            .with_context(err_context)?;
+        // the sessions of others don't even exist as far as the client is concerned
+        if !operator && !self.owns(session) {
+            return Err(SessionNotFound).with_context(err_context);
+        }
+        if session.process().with_context(err_context)?.is_interactive() && !operator {
+            if let Some(policy) = self.policy() {
+                policy.check_pty().with_context(err_context)?;
+            }
+        }
+        if session.is_attached_to(&self.events) {
+            return Err(anyhow!("session is attached already")).with_context(err_context);
+        }
+
+        match role {
+            AttachRole::Writer => {
+                if let Some(previous) = session.writer.replace(self.attachment()) {
+                    previous.send(Message::Detached { session: id });
+                }
+            },
+            AttachRole::Viewer => session.viewers.push(self.attachment()),
+        }
+        session.detached_at = None;
+        let seq = seq.unwrap_or(session.acked);
+        match seq {
+            0 => info!("{} attached as {}", self.client.address, role),
+            seq => info!("{} resumed as {} from {}", self.client.address, role, seq),
+        }
+        self.send(Message::Opened {
+            session: id,
+            recording: session.recording.is_some(),
+            recording_input: session.records_input(),
+            role,
+        });
+        if session.title.is_some() || session.cwd.is_some() {
+            self.send(Message::SessionMeta {
+                session: id,
+                title: session.title.clone(),
+                cwd: session.cwd.clone(),
+            });
+        }
+        for output in session.scrollback.replay(id, seq) {
+            self.send(output);
+        }
+        Ok(())
+    }
+
+    /// Remember that the writer of a session received its output up to `seq`, acknowledgements
+    /// of viewers are of no use
+    pub fn ack(&self, id: SessionId, seq: u64) -> Result<()> {
+        self.with_attached_session(id, |session| {
+            if session.is_writer(&self.events) {
+                session.acked = seq.min(session.scrollback.seq).max(session.acked);
+            }
+        })
+        .with_context(|| format!("failed to acknowledge output of session {}", id))
+    }
+
+    /// Stop sending the output of a session to this connection, leaving its command running
+    pub fn detach(&self, id: SessionId) -> Result<()> {
+        self.with_attached_session(id, |session| session.detach(&self.events))
+            .with_context(|| format!("failed to detach session {}", id))?;
+        info!("detached");
+        self.send(Message::Detached { session: id });
+        Ok(())
+    }
+
+    /// Make this connection, watching a session, its writer instead of the current one, which
+    /// keeps watching it
+    pub fn take_control(&self, id: SessionId) -> Result<()> {
+        self.with_attached_session(id, |session| {
+            if !self.owns(session) {
+                return Err(anyhow!("session was opened by another client"));
+            }
+            let Some(position) = session.viewers.iter().position(|v| v.is(&self.events)) else {
+                return Err(anyhow!("session is controlled by this connection already"));
+            };
+            let viewer = session.viewers.remove(position);
+            if let Some(previous) = session.writer.replace(viewer) {
+                previous.send(Message::RoleChanged {
+                    session: id,
+                    role: AttachRole::Viewer,
+                });
+                session.viewers.push(previous);
+            }
+            info!("{} took control", self.client.address);
+            self.send(Message::RoleChanged {
+                session: id,
+                role: AttachRole::Writer,
+            });
+            Ok(())
+        })
+        .and_then(|result| result)
+        .with_context(|| format!("failed to take control of session {}", id))
+    }
+
+    /// Tell the client who is attached to a session
+    pub fn list_attached_clients(&self, id: SessionId) -> Result<()> {
+        let clients = self
+            .with_attached_session(id, |session| session.attached_clients())
+            .with_context(|| format!("failed to list the clients of session {}", id))?;
+        self.send(Message::AttachedClients {
+            session: id,
+            clients,
+        });
+        Ok(())
+    }
+
+    /// Tell the writer of a session the environment its command was started with
+    pub fn snapshot_environment(&self, id: SessionId) -> Result<()> {
+        let env = self
+            .with_session(id, |session| session.environment.clone())
+            .with_context(|| format!("failed to snapshot the environment of session {}", id))?;
+        self.send(Message::EnvironmentSnapshot { session: id, env });
+        Ok(())
+    }
+
+    /// Write `data` to the input of all sessions `ids`, see [`Message::BroadcastInput`]. Nothing
+    /// is written unless this connection is the writer of every one of them.
+    pub async fn broadcast_input(&self, ids: &[SessionId], data: &[u8]) -> Result<()> {
+        let err_context = || "failed to broadcast input";
+
+        let mut targets: Vec<SessionId> = vec![];
+        for id in ids {
+            if !targets.contains(id) {
+                targets.push(*id);
+            }
+        }
+        if let Some(limit) = self.config.limits.max_broadcast_sessions {
+            if targets.len() > limit {
+                return Err(anyhow!(
+                    "input may be broadcast to no more than {} sessions",
+                    limit
+                ))
+                .with_context(err_context);
+            }
+        }
+        for id in targets.iter() {
+            self.with_session(*id, |_| ())
+                .with_context(|| format!("failed to broadcast input to session {}", id))?;
+        }
+        self.audit
+            .record(&BroadcastRecord::new(&targets, &self.client, data.len()));
+        for id in targets {
+            if let Err(e) = self.write(id, data).await {
+                self.send_error(Some(id), &e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `data` to the input of a session
+    pub async fn write(&self, id: SessionId, data: &[u8]) -> Result<()> {
+        let err_context = || format!("failed to write to session {}", id);
+
+        if let Some(limit) = self.config.limits.max_input_size {
+            if data.len() > limit {
+                return Err(InputTooLarge { limit }).with_context(err_context);
+            }
+        }
+        let (mut writer, input) = self
+            .with_session(id, |session| {
+                if let Some(throttle) = session.input_throttle.as_ref() {
+                    if !throttle.try_take(data.len()) {
+                        let bytes_per_second = throttle.limit().bytes_per_second;
+                        return Err(InputTooFast { bytes_per_second }.into());
+                    }
+                }
+                session.active();
+                let writer = session.input_writer()?;
+                if let Some(recording) = session.recording.as_mut() {
+                    recording.input(data);
+                }
+                let data = session.input_filter.filter(data);
+                let Some(editor) = session.line_editor.as_mut() else {
+                    return Ok((writer, data));
+                };
+                let edited = editor.input(&data);
+                session.echo(id, edited.echo);
+                Ok((writer, edited.input))
+            })
+            .and_then(|writer| writer)
+            .with_context(err_context)?;
+        writer.write_all(&input).await.with_context(err_context)?;
+        METRICS.bytes_in(id, data.len());
+        // the session may have exited meanwhile, then there is nothing left to account for
+        let _ = self.with_session(id, |session| session.bytes_in += data.len() as u64);
+        Ok(())
+    }
+
+    /// Answer the clipboard query of the command of a session with `data`, the way a terminal
+    /// answers OSC 52
+    pub async fn answer_clipboard(&self, id: SessionId, data: &[u8]) -> Result<()> {
+        let err_context = || format!("failed to answer the clipboard query of session {}", id);
+
+        let (mut writer, selection) = self
+            .with_session(id, |session| {
+                let selection = session
+                    .clipboard_query
+                    .take()
+                    .ok_or_else(|| anyhow!("the command didn't ask for the clipboard"))?;
+                Ok::<_, anyhow::Error>((session.input_writer()?, selection))
+            })
+            .and_then(|answer| answer)
+            .with_context(err_context)?;
+        let answer = format!("\x1b]52;{};{}\x07", selection, BASE64.encode(data));
+        writer
+            .write_all(answer.as_bytes())
+            .await
+            .with_context(err_context)
+    }
+
+    /// Change the terminal size of a session
+    pub fn resize(&self, id: SessionId, size: PtySize) -> Result<()> {
+        self.with_session(id, |session| {
+            match session.process()? {
+                Process::Pty(pty) => pty.resize(size)?,
+                Process::Container(exec) => exec.resize(size)?,
+                #[cfg(feature = "kubernetes")]
+                Process::Pod(exec) => exec.resize(size)?,
+                #[cfg(feature = "ssh")]
+                Process::Ssh(exec) => exec.resize(size)?,
+                #[cfg(feature = "plugins")]
+                Process::Plugin(exec) => exec.resize(size)?,
+                Process::Exec(_) => {
+                    return Err(anyhow!("commands run without a terminal have no size"))
+                },
+                Process::Serial(..) => return Err(anyhow!("serial devices have no size")),
+            }
+            if let Some(recording) = session.recording.as_mut() {
+                recording.resize(size);
+            }
+            Ok(())
+        })
+        .and_then(|result| result)
+        .with_context(|| format!("failed to resize session {}", id))
+    }
+
+    /// Switch the terminal of a session to `mode`, then turn the given flags on or off
+    pub fn set_term_mode(
+        &self,
+        id: SessionId,
+        mode: Option<TermMode>,
+        echo: Option<bool>,
+        isig: Option<bool>,
+        icrnl: Option<bool>,
+    ) -> Result<()> {
+        self.with_session(id, |session| {
+            let pty = match session.process()? {
+                Process::Pty(pty) => pty,
+                Process::Exec(_) => {
+                    return Err(anyhow!(
+                        "commands run without a terminal have no terminal mode"
+                    ))
+                },
+                Process::Serial(..) => {
+                    return Err(anyhow!("serial devices are set up when opened"))
+                },
+                Process::Container(_) => {
+                    return Err(anyhow!("the terminals of containers are up to Docker"))
+                },
+                #[cfg(feature = "kubernetes")]
+                Process::Pod(_) => {
+                    return Err(anyhow!("the terminals of pods are up to Kubernetes"))
+                },
+                #[cfg(feature = "ssh")]
+                Process::Ssh(_) => {
+                    return Err(anyhow!(
+                        "the terminals of other hosts are up to their SSH server"
+                    ))
+                },
+                #[cfg(feature = "plugins")]
+                Process::Plugin(_) => {
+                    return Err(anyhow!("the terminals of plugins are up to the plugin"))
+                },
+            };
+            let mut termios = pty.termios()?;
+            if let Some(mode) = mode {
+                termios = termios.mode(mode);
+            }
+            if let Some(echo) = echo {
+                termios = termios.echo(echo);
+            }
+            if let Some(isig) = isig {
+                termios = termios.isig(isig);
+            }
+            if let Some(icrnl) = icrnl {
+                termios = termios.icrnl(icrnl);
+            }
+            pty.set_termios(&termios.build())
+        })
+        .and_then(|result| result)
+        .with_context(|| format!("failed to set terminal mode of session {}", id))
+    }
+
+    /// Change who echoes and edits the input of a session. A line being edited by the server is
+    /// dropped when switching back to [`LineMode::Passthrough`].
+    pub fn set_line_mode(&self, id: SessionId, mode: LineMode) -> Result<()> {
+        self.with_session(id, |session| {
+            if let Process::Exec(_) = session.process()? {
+                return Err(anyhow!("commands run without a terminal take no input"));
+            }
+            session.line_editor = match mode {
+                LineMode::Passthrough => None,
+                LineMode::Server => Some(session.line_editor.take().unwrap_or_default()),
+            };
+            Ok(())
+        })
+        .and_then(|result| result)
+        .with_context(|| format!("failed to set line mode of session {}", id))
+    }
+
+    /// Deliver `signal` to the foreground process group of a session, as if the user pressed
+    /// ^C and friends in a terminal. Commands run without a terminal get the signal themselves.
+    pub fn signal(&self, id: SessionId, signal: SignalSpec) -> Result<()> {
+        self.with_session(id, |session| {
+            let target = match session.process()? {
+                Process::Pty(pty) => match pty.foreground_process_group() {
+                    Some(group) => Pid::from_raw(-group.as_raw()),
+                    None => pty.pid(),
+                },
+                Process::Exec(exec) => exec.pid(),
+                Process::Container(exec) => exec
+                    .pid()
+                    .ok_or_else(|| anyhow!("the command's pid in the container isn't known"))?,
+                #[cfg(feature = "kubernetes")]
+                Process::Pod(exec) => return exec.signal(&signal),
+                #[cfg(feature = "ssh")]
+                Process::Ssh(exec) => return exec.signal(&signal),
+                #[cfg(feature = "plugins")]
+                Process::Plugin(_) => return Err(anyhow!("plugins take no signals")),
+                Process::Serial(..) => return Err(anyhow!("serial devices take no signals")),
+            };
+            send_signal(target, &signal)
+        })
+        .and_then(|result| result)
+        .with_context(|| format!("failed to signal session {}", id))
+    }
+
+    /// End a session. The client is notified with [`Message::Exit`] once its command terminated.
+    pub fn close(&self, id: SessionId) -> Result<()> {
+        self.with_session(id, |session| {
+            session.process()?;
+            info!("closing '{}'", session.command);
+            // dropping the process hangs it up, the session goes away once its output is drained
+            session.process = None;
+            Ok(())
+        })
+        .and_then(|result| result)
+        .with_context(|| format!("failed to close session {}", id))
+    }
+
+    /// Run `f` on session `id` if this connection is its writer
+    fn with_session<T>(&self, id: SessionId, f: impl FnOnce(&mut Session) -> T) -> Result<T> {
+        self.with_attached_session(id, |session| {
+            if !session.is_writer(&self.events) {
+                return Err(anyhow!("session is only watched by this connection"));
+            }
+            Ok(f(session))
+        })
+        .and_then(|result| result)
+    }
+
+    /// Run `f` on session `id` if it is attached to this connection, in whatever role
+    fn with_attached_session<T>(
+        &self,
+        id: SessionId,
+        f: impl FnOnce(&mut Session) -> T,
+    ) -> Result<T> {
+        let mut sessions = self.registry.sessions.lock().to_anyhow()?;
+        let session = sessions.get_mut(&id).ok_or(SessionNotFound)?;
+        if !session.is_attached_to(&self.events) {
+            return Err(anyhow!("session is not attached to this connection"));
+        }
+        Ok(f(session))
+    }
+
+    /// Whether `session` was opened by a client of the same identity as this connection's
+    fn owns(&self, session: &Session) -> bool {
+        session.opened_by.identity() == self.client.identity()
+    }
+
+    fn send(&self, message: Message) {
+        self.attachment().send(message);
+    }
+}
+
+/// Read the next output of `reader` into `buf`, along with what follows within the window of
+/// `framing` while the frame has room but no longer than `max_delay`, `true` once the output
+/// ended
+async fn read_output(
+    reader: &mut (impl AsyncRead + Unpin),
+    buf: &mut BytesMut,
+    framing: &Framing,
+    max_delay: Duration,
+) -> bool {
+    match reader.read_buf(&mut (&mut *buf).limit(framing.frame_size)).await {
+        Ok(0) => return true,
+        Ok(_) => {},
+        Err(e) => {
+            warn!("failed to read output: {}", e);
+            return true;
+        },
+    }
+    let Some(window) = framing.window() else {
+        return false;
+    };
+    let held = time::Instant::now();
+    let deadline = held + max_delay;
+    let mut ended = false;
+    while buf.len() < framing.frame_size {
+        let until = deadline.min(time::Instant::now() + window);
+        let room = framing.frame_size - buf.len();
+        // reading is cancel safe, nothing read is lost when the window passes first
+        match time::timeout_at(until, reader.read_buf(&mut (&mut *buf).limit(room))).await {
+            Ok(Ok(0)) => ended = true,
+            Ok(Ok(_)) => {
+                METRICS.coalesced_reads.inc();
+                continue;
+            },
+            Ok(Err(e)) => {
+                warn!("failed to read output: {}", e);
+                ended = true;
+            },
+            Err(_) => {},
+        }
+        break;
+    }
+    METRICS.coalescing_seconds.inc_by(held.elapsed().as_secs_f64());
+    ended
+}
+
+/// The signal that terminated a command exiting with `status`, if one did
+fn exit_signal(status: ExitStatus) -> Option<ExitSignal> {
+    let number = status.signal()?;
+    Some(ExitSignal {
+        number,
+        name: Signal::try_from(number)
+            .ok()
+            .map(|signal| signal.as_str().to_string()),
+        core_dumped: status.core_dumped(),
+    })
+}
+
+/// The shorter of two timeouts, either if only one is set
+fn shortest(timeout: Option<u64>, other: Option<u64>) -> Option<u64> {
+    match (timeout, other) {
+        (Some(timeout), Some(other)) => Some(timeout.min(other)),
+        (timeout, other) => timeout.or(other),
+    }
+}
+
+/// Why a command exited with `status`, as far as the client is concerned
+fn exit_reason(status: Option<ExitStatus>, cgroup: Option<&Cgroup>) -> Option<ExitReason> {
+    let resource = match status.and_then(|status| status.signal()) {
+        Some(signal) if signal == Signal::SIGXCPU as i32 => Some(Resource::CpuTime),
+        _ => cgroup.and_then(Cgroup::exceeded),
+    };
+    resource.map(|resource| ExitReason::ResourceExceeded { resource })
+}
+
+/// A session was refused because `limit` sessions are running within `scope` already
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TooManySessions {
+    pub limit: usize,
+    pub scope: LimitScope,
+}
+
+impl fmt::Display for TooManySessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scope = match self.scope {
+            LimitScope::Connection => "per connection",
+            LimitScope::Client => "per client",
+            LimitScope::Server => "on this server",
+        };
+        write!(f, "no more than {} sessions allowed {}", self.limit, scope)
+    }
+}
+
+impl std::error::Error for TooManySessions {}
+
+/// An input message carried more bytes than
+/// [`max_input_size`](crate::config::Limits::max_input_size) allows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputTooLarge {
+    pub limit: usize,
+}
+
+impl fmt::Display for InputTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "input messages may carry no more than {} bytes",
+            self.limit
+        )
+    }
+}
+
+impl std::error::Error for InputTooLarge {}
+
+/// Input arrived faster than [`input_rate`](crate::config::Limits::input_rate) allows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputTooFast {
+    pub bytes_per_second: u64,
+}
+
+impl fmt::Display for InputTooFast {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "input may arrive no faster than {} bytes per second",
+            self.bytes_per_second
+        )
+    }
+}
+
+impl std::error::Error for InputTooFast {}
+
+/// A client named a session that doesn't exist
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionNotFound;
+
+impl fmt::Display for SessionNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no such session")
+    }
+}
+
+impl std::error::Error for SessionNotFound {}
+
+impl Drop for SessionManager {
+    fn drop(&mut self) {
+        // commands that never started go with the connection
+        if let Ok(queued) = self.queued.get_mut() {
+            for id in queued.keys() {
+                self.registry.jobs.remove(*id);
+            }
+        }
+        // detached sessions keep running in any case, attached ones only if configured so
+        let detach = self.config.sessions.detach_on_disconnect;
+        if let Ok(mut sessions) = self.registry.sessions.lock() {
+            for (id, session) in sessions.iter_mut() {
+                if !session.is_attached_to(&self.events) {
+                    continue;
+                }
+                let writer = session.is_writer(&self.events);
+                session.detach(&self.events);
+                // watching a session doesn't make it the viewer's to end
+                if !writer {
+                    continue;
+                }
+                if detach {
+                    session_span(*id).in_scope(|| info!("detached on disconnect"));
+                } else {
+                    session.process = None;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! SOCKS5 front end for the forwarding channels of an [`ActuatorClient`](crate::client), which
+//! lets programs on the client's host reach whatever the server's
+//! [allow list](crate::config::ForwardingConfig::allow) has without forwarding each port on its
+//! own. Every CONNECT a SOCKS client asks for becomes a channel the server opens on demand.
+//!
+//! Only CONNECT without authentication is supported, the listener is meant to be bound to
+//! loopback. Only built with the `client` feature.
+use crate::client::Forwarder;
+use anyhow::{anyhow, Context, Result};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+use tracing::{info, warn};
+
+/// Version byte every SOCKS5 message starts with
+const VERSION: u8 = 0x05;
+/// Authentication method of clients that don't authenticate
+const NO_AUTHENTICATION: u8 = 0x00;
+/// Answer to clients offering no method the listener supports
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+/// The only command supported, connecting to a destination
+const CONNECT: u8 = 0x01;
+
+/// Address types of destinations
+const IPV4: u8 = 0x01;
+const DOMAIN_NAME: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+/// Replies to requests
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Time SOCKS clients have to say where to connect to
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accept SOCKS clients on `listener` and give each a channel opened with `forwarder`, until
+/// accepting fails
+pub async fn serve(listener: TcpListener, forwarder: Forwarder) -> Result<()> {
+    loop {
+        let (stream, peer) = listener
+            .accept()
+            .await
+            .context("failed to accept SOCKS client")?;
+        let forwarder = forwarder.clone();
+        tokio::spawn(async move {
+            if let Err(e) = relay(stream, &forwarder).await {
+                warn!("SOCKS client {}: {:#}", peer, e);
+            }
+        });
+    }
+}
+
+/// Open the channel that SOCKS client `stream` asks for and relay between the two until both
+/// are done
+async fn relay(mut stream: TcpStream, forwarder: &Forwarder) -> Result<()> {
+    let (host, port) = time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut stream))
+        .await
+        .map_err(|_| anyhow!("timed out waiting for the request"))??;
+    let mut channel = match forwarder.forward(&host, port).await {
+        Ok(channel) => channel,
+        Err(e) => {
+            let _ = reply(&mut stream, GENERAL_FAILURE).await;
+            return Err(e);
+        },
+    };
+    reply(&mut stream, SUCCEEDED).await?;
+    info!("channel {}: forwarding to {}:{}", channel.id(), host, port);
+    io::copy_bidirectional(&mut stream, &mut channel)
+        .await
+        .with_context(|| format!("failed to forward to {}:{}", host, port))?;
+    Ok(())
+}
+
+/// Agree on no authentication with the client and read the destination it wants to connect to
+async fn handshake(stream: &mut TcpStream) -> Result<(String, u16)> {
+    let err_context = || "failed to read SOCKS request";
+
+    let [version, methods] = read_array(stream).await.with_context(err_context)?;
+    if version != VERSION {
+        return Err(anyhow!("SOCKS version {} is not supported", version));
+    }
+    let mut offered = vec![0; methods.into()];
+    stream
+        .read_exact(&mut offered)
+        .await
+        .with_context(err_context)?;
+    if !offered.contains(&NO_AUTHENTICATION) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        return Err(anyhow!("client insists on authenticating"));
+    }
+    stream.write_all(&[VERSION, NO_AUTHENTICATION]).await?;
+
+    let [version, command, _, address_type] = read_array(stream).await.with_context(err_context)?;
+    if version != VERSION {
+        return Err(anyhow!("SOCKS version {} is not supported", version));
+    }
+    if command != CONNECT {
+        reply(stream, COMMAND_NOT_SUPPORTED).await?;
+        return Err(anyhow!("SOCKS command {} is not supported", command));
+    }
+    let host = match address_type {
+        IPV4 => {
+            Ipv4Addr::from(read_array::<4>(stream).await.with_context(err_context)?).to_string()
+        },
+        IPV6 => {
+            Ipv6Addr::from(read_array::<16>(stream).await.with_context(err_context)?).to_string()
+        },
+        DOMAIN_NAME => {
+            let [len] = read_array(stream).await.with_context(err_context)?;
+            let mut name = vec![0; len.into()];
+            stream
+                .read_exact(&mut name)
+                .await
+                .with_context(err_context)?;
+            String::from_utf8(name).context("destination is not a valid host name")?
+        },
+        _ => {
+            reply(stream, ADDRESS_TYPE_NOT_SUPPORTED).await?;
+            return Err(anyhow!(
+                "SOCKS address type {} is not supported",
+                address_type
+            ));
+        },
+    };
+    let port = u16::from_be_bytes(read_array(stream).await.with_context(err_context)?);
+    Ok((host, port))
+}
+
+/// Answer the request of the client with `code`. The server's end of a channel has no address
+/// the client could use, the one given is unspecified.
+async fn reply(stream: &mut TcpStream, code: u8) -> Result<()> {
+    stream
+        .write_all(&[VERSION, code, 0, IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+        .context("failed to answer SOCKS request")
+}
+
+async fn read_array<const N: usize>(stream: &mut TcpStream) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
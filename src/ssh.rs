@@ -0,0 +1,371 @@
+//! Sessions running on other hosts over SSH, making the server a gateway from WebSockets to SSH.
+//! A [profile](crate::command::Profile) with the `ssh` backend logs in to its host with a key
+//! and starts its command there, on a terminal of the host:
+//!
+//! ```toml
+//! [profiles.build-box]
+//! backend = "ssh"
+//! host = "build.example.org:2222"
+//! user = "ci"
+//! key = "/etc/shws/id_ed25519"
+//! known_hosts = "/etc/shws/known_hosts"
+//! cmd = "/bin/bash"
+//! args = ["-l"]
+//! ```
+//!
+//! Hosts are only logged in to if their key is in `known_hosts`, `~/.ssh/known_hosts` of the user
+//! the server runs as if not given.
+//!
+//! Only built with the `ssh` feature.
+use crate::{
+    command::{signal_number, Environment, RunCommand},
+    data::SignalSpec,
+    os_io::{PtyReader, PtySize, PtyWriter, Socket},
+};
+use anyhow::{anyhow, Context, Result};
+use nix::sys::signal::Signal;
+use russh::{
+    client::{self, Handle, Msg},
+    keys::{self, PrivateKeyWithHashAlg, PublicKeyOrCertificate},
+    Channel, ChannelMsg, ChannelWriteHalf, Disconnect, Sig,
+};
+use std::{
+    future::Future,
+    os::unix::{net::UnixStream as StdUnixStream, process::ExitStatusExt},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncWriteExt},
+    net::UnixStream,
+    sync::watch,
+    time,
+};
+use tracing::warn;
+
+/// How long logging in to a host may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a profile logs in to, see [`SshExec::start`]
+#[derive(Clone, Copy, Debug)]
+pub struct SshTarget<'a> {
+    /// `host` or `host:port`, port 22 if not given
+    pub host: &'a str,
+    pub user: &'a str,
+    /// Private key to log in with, in the OpenSSH format
+    pub key: &'a Path,
+    /// Keys the host may have, `~/.ssh/known_hosts` if not given
+    pub known_hosts: Option<&'a Path>,
+}
+
+/// A command running on a terminal of another host. Dropping the `SshExec` closes its channel,
+/// which hangs up the terminal on the host.
+pub struct SshExec {
+    channel: Arc<ChannelWriteHalf<Msg>>,
+    terminal: Socket,
+    exit_status: watch::Receiver<Option<ExitStatus>>,
+}
+
+impl SshExec {
+    /// Log in to `target` and start `cmd` there on a terminal of the given size. The command
+    /// line is run by the login shell of the user, which also sets the variables of `env`.
+    pub async fn start(
+        target: SshTarget<'_>,
+        cmd: &RunCommand,
+        env: &Environment,
+        size: PtySize,
+    ) -> Result<SshExec> {
+        let err_context = || format!("failed to start '{}' on '{}'", cmd, target.host);
+
+        size.check().with_context(err_context)?;
+        let key = keys::load_secret_key(target.key, None)
+            .with_context(|| format!("failed to load key '{}'", target.key.display()))
+            .with_context(err_context)?;
+        let session = time::timeout(CONNECT_TIMEOUT, log_in(target, key))
+            .await
+            .map_err(|_| anyhow!("timed out logging in"))
+            .and_then(|result| result)
+            .with_context(err_context)?;
+        let mut channel = session
+            .channel_open_session()
+            .await
+            .with_context(err_context)?;
+        let term = env.vars.get("TERM").map_or("xterm", String::as_str);
+        channel
+            .request_pty(
+                true,
+                term,
+                size.cols.into(),
+                size.rows.into(),
+                size.width_in_pixels.unwrap_or(0).into(),
+                size.height_in_pixels.unwrap_or(0).into(),
+                &[],
+            )
+            .await
+            .with_context(err_context)?;
+        confirmed(&mut channel, "a terminal")
+            .await
+            .with_context(err_context)?;
+        channel
+            .exec(true, command_line(cmd, env))
+            .await
+            .with_context(err_context)?;
+        confirmed(&mut channel, "the command")
+            .await
+            .with_context(err_context)?;
+
+        let (mut output, channel) = channel.split();
+        let channel = Arc::new(channel);
+        let (local, remote) = StdUnixStream::pair().with_context(err_context)?;
+        remote.set_nonblocking(true).with_context(err_context)?;
+        let remote = UnixStream::from_std(remote).with_context(err_context)?;
+        let (mut from_session, mut to_session) = remote.into_split();
+        let (exit_tx, exit_status) = watch::channel(None);
+        tokio::spawn(async move {
+            while let Some(message) = output.wait().await {
+                match message {
+                    ChannelMsg::Data { data } | ChannelMsg::ExtendedData { data, .. } => {
+                        if let Err(e) = to_session.write_all(&data).await {
+                            warn!("failed to relay SSH terminal: {}", e);
+                            break;
+                        }
+                    },
+                    ChannelMsg::ExitStatus { exit_status } => {
+                        let _ = exit_tx.send(Some(ExitStatus::from_raw((exit_status as i32) << 8)));
+                    },
+                    ChannelMsg::ExitSignal { signal_name, .. } => {
+                        // signals the server has no number for count as exiting with 1
+                        let status = match signal_of(&signal_name) {
+                            Some(signal) => signal as i32,
+                            None => 1 << 8,
+                        };
+                        let _ = exit_tx.send(Some(ExitStatus::from_raw(status)));
+                    },
+                    ChannelMsg::Close => break,
+                    _ => {},
+                }
+            }
+            // the session's reads end once the host's output did
+            let _ = to_session.shutdown().await;
+        });
+        let input = channel.clone();
+        tokio::spawn(async move {
+            if let Err(e) = io::copy(&mut from_session, &mut input.make_writer()).await {
+                warn!("failed to relay SSH terminal: {}", e);
+            }
+            // only once the session is done with the terminal
+            let _ = input.close().await;
+            let _ = session
+                .disconnect(Disconnect::ByApplication, "session closed", "en")
+                .await;
+        });
+        Ok(SshExec {
+            channel,
+            terminal: Socket::new(local).with_context(err_context)?,
+            exit_status,
+        })
+    }
+
+    /// A handle reading the terminal's output
+    pub fn reader(&self) -> PtyReader {
+        self.terminal.reader()
+    }
+
+    /// A handle writing to the input of the command
+    pub fn writer(&self) -> PtyWriter {
+        self.terminal.writer()
+    }
+
+    /// Change the size of the terminal. The host is told in the background, failures are only
+    /// logged.
+    pub fn resize(&self, size: PtySize) -> Result<()> {
+        size.check()?;
+        let channel = self.channel.clone();
+        tokio::spawn(async move {
+            let result = channel
+                .window_change(
+                    size.cols.into(),
+                    size.rows.into(),
+                    size.width_in_pixels.unwrap_or(0).into(),
+                    size.height_in_pixels.unwrap_or(0).into(),
+                )
+                .await;
+            if let Err(e) = result {
+                warn!("failed to resize SSH terminal: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Ask the host to deliver `signal` to the command, which not all of them do
+    pub fn signal(&self, signal: &SignalSpec) -> Result<()> {
+        let signal = Signal::try_from(signal_number(signal)?)?;
+        let channel = self.channel.clone();
+        tokio::spawn(async move {
+            if let Err(e) = channel.signal(sig_of(signal)).await {
+                warn!("failed to send signal over SSH: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Resolves to the exit status of the command once it exited
+    pub fn exited(&self) -> impl Future<Output = Option<ExitStatus>> + Send + 'static {
+        let mut exit_status = self.exit_status.clone();
+        async move {
+            loop {
+                if let Some(status) = *exit_status.borrow() {
+                    return Some(status);
+                }
+                if exit_status.changed().await.is_err() {
+                    return *exit_status.borrow();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SshExec {
+    fn drop(&mut self) {
+        self.terminal.shutdown();
+    }
+}
+
+/// Checks the key of the host against the known hosts
+struct KnownHosts {
+    host: String,
+    port: u16,
+    path: Option<PathBuf>,
+}
+
+impl client::Handler for KnownHosts {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        key: &PublicKeyOrCertificate,
+    ) -> Result<bool, Self::Error> {
+        let PublicKeyOrCertificate::PublicKey { key, .. } = key else {
+            warn!(
+                "'{}' presented a certificate, only keys are accepted",
+                self.host
+            );
+            return Ok(false);
+        };
+        let known = match self.path.as_deref() {
+            Some(path) => keys::check_known_hosts_path(&self.host, self.port, key, path)?,
+            None => keys::check_known_hosts(&self.host, self.port, key)?,
+        };
+        if !known {
+            warn!("the key of '{}' isn't known", self.host);
+        }
+        Ok(known)
+    }
+}
+
+async fn log_in(target: SshTarget<'_>, key: keys::PrivateKey) -> Result<Handle<KnownHosts>> {
+    let (host, port) = match target.host.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().context("invalid port")?),
+        None => (target.host, 22),
+    };
+    let handler = KnownHosts {
+        host: host.to_string(),
+        port,
+        path: target.known_hosts.map(Path::to_path_buf),
+    };
+    let mut session = client::connect(Arc::new(client::Config::default()), (host, port), handler)
+        .await
+        .context("failed to connect")?;
+    let hash = session.best_supported_rsa_hash().await?.flatten();
+    let result = session
+        .authenticate_publickey(target.user, PrivateKeyWithHashAlg::new(Arc::new(key), hash))
+        .await?;
+    match result.success() {
+        true => Ok(session),
+        false => Err(anyhow!("'{}' refused the key", target.user)),
+    }
+}
+
+/// Wait for the host to answer the last request on `channel`
+async fn confirmed(channel: &mut Channel<Msg>, what: &str) -> Result<()> {
+    loop {
+        match channel.wait().await {
+            Some(ChannelMsg::Success) => return Ok(()),
+            Some(ChannelMsg::Failure) => return Err(anyhow!("the host refused {}", what)),
+            Some(_) => {},
+            None => return Err(anyhow!("the host closed the channel")),
+        }
+    }
+}
+
+/// `cmd` as a line for the shell of the host, with the variables of `env` set and in the working
+/// directory of `cmd`
+fn command_line(cmd: &RunCommand, env: &Environment) -> String {
+    let mut line = String::new();
+    if let Some(cwd) = cmd.cwd.as_ref() {
+        line.push_str("cd ");
+        line.push_str(&quote(&cwd.to_string_lossy()));
+        line.push_str(" && ");
+    }
+    line.push_str("exec");
+    if !env.vars.is_empty() {
+        line.push_str(" env");
+        for (name, value) in env.vars.iter() {
+            line.push(' ');
+            line.push_str(&quote(&format!("{}={}", name, value)));
+        }
+    }
+    line.push(' ');
+    line.push_str(&quote(&cmd.command.to_string_lossy()));
+    for arg in cmd.args.iter() {
+        line.push(' ');
+        line.push_str(&quote(arg));
+    }
+    line
+}
+
+/// `word` in single quotes, which POSIX shells take literally
+fn quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+fn sig_of(signal: Signal) -> Sig {
+    match signal {
+        Signal::SIGABRT => Sig::ABRT,
+        Signal::SIGALRM => Sig::ALRM,
+        Signal::SIGFPE => Sig::FPE,
+        Signal::SIGHUP => Sig::HUP,
+        Signal::SIGILL => Sig::ILL,
+        Signal::SIGINT => Sig::INT,
+        Signal::SIGKILL => Sig::KILL,
+        Signal::SIGPIPE => Sig::PIPE,
+        Signal::SIGQUIT => Sig::QUIT,
+        Signal::SIGSEGV => Sig::SEGV,
+        Signal::SIGTERM => Sig::TERM,
+        Signal::SIGUSR1 => Sig::USR1,
+        // SSH names signals without their prefix
+        signal => Sig::Custom(signal.as_str().trim_start_matches("SIG").to_string()),
+    }
+}
+
+fn signal_of(sig: &Sig) -> Option<Signal> {
+    let signal = match sig {
+        Sig::ABRT => Signal::SIGABRT,
+        Sig::ALRM => Signal::SIGALRM,
+        Sig::FPE => Signal::SIGFPE,
+        Sig::HUP => Signal::SIGHUP,
+        Sig::ILL => Signal::SIGILL,
+        Sig::INT => Signal::SIGINT,
+        Sig::KILL => Signal::SIGKILL,
+        Sig::PIPE => Signal::SIGPIPE,
+        Sig::QUIT => Signal::SIGQUIT,
+        Sig::SEGV => Signal::SIGSEGV,
+        Sig::TERM => Signal::SIGTERM,
+        Sig::USR1 => Signal::SIGUSR1,
+        Sig::Custom(name) => return Signal::from_str(&format!("SIG{}", name)).ok(),
+    };
+    Some(signal)
+}
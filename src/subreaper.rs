@@ -0,0 +1,132 @@
+//! What the commands of sessions leave behind. Commands run in a session of their own, see
+//! setsid(2), and once they are torn down everything still in their session is signalled along
+//! with them, so that background jobs a shell started don't outlive it, in a process group of
+//! their own or not.
+//!
+//! Processes the commands orphan, like the grandchildren of a shell that exited, are reparented
+//! to the server rather than to init once it is a child subreaper (`PR_SET_CHILD_SUBREAPER`, see
+//! prctl(2)), and [`start`] reaps them as they exit. Otherwise they would be left as zombies.
+//!
+//! Linux only, elsewhere orphans go to init as they always did.
+use anyhow::{Context, Result};
+use nix::{
+    sys::{
+        signal::{kill, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{self, Pid},
+};
+use std::{fs, io, sync::Mutex};
+use tokio::{
+    process::{Child, Command},
+    signal::unix::{signal, SignalKind},
+};
+use tracing::debug;
+
+/// The commands spawned for sessions, which the tasks waiting for them reap rather than
+/// [`reap_orphans`]. Held while spawning, so that commands exiting right away aren't taken for
+/// orphans.
+static SPAWNED: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+
+/// Make the server the child subreaper of everything it spawns, then reap the orphans reparented
+/// to it in the background. Must be called from within a tokio runtime.
+pub fn start() -> Result<()> {
+    let err_context = || "failed to become a child subreaper";
+
+    become_subreaper().with_context(err_context)?;
+    let mut exited = signal(SignalKind::child()).with_context(err_context)?;
+    tokio::spawn(async move {
+        loop {
+            reap_orphans();
+            if exited.recv().await.is_none() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn become_subreaper() -> io::Result<()> {
+    match unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn become_subreaper() -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Reap the orphans that exited, returning how many there were. Children of the server other
+/// than orphans are left to whoever spawned them: the commands of sessions and those sharing the
+/// session of the server, which commands can't have orphaned.
+pub fn reap_orphans() -> usize {
+    let spawned = SPAWNED.lock().unwrap_or_else(|e| e.into_inner());
+    let (server, own_session) = (unistd::getpid(), unistd::getsid(None).ok());
+    processes()
+        .filter(|stat| stat.parent == server && stat.zombie)
+        .filter(|stat| Some(stat.session) != own_session && !spawned.contains(&stat.pid.as_raw()))
+        .filter(|stat| {
+            let reaped = waitpid(stat.pid, Some(WaitPidFlag::WNOHANG));
+            matches!(reaped, Ok(WaitStatus::Exited(..) | WaitStatus::Signaled(..)))
+        })
+        .inspect(|stat| debug!("reaped orphan {}", stat.pid))
+        .count()
+}
+
+/// Spawn `command` as the command of a session, to be reaped by whoever waits for it
+pub(crate) fn spawn(command: &mut Command) -> io::Result<Child> {
+    let mut spawned = SPAWNED.lock().unwrap_or_else(|e| e.into_inner());
+    let child = command.spawn()?;
+    spawned.extend(child.id().map(|pid| pid as i32));
+    Ok(child)
+}
+
+/// The command of a session running as `pid` was reaped
+pub(crate) fn reaped(pid: Pid) {
+    let mut spawned = SPAWNED.lock().unwrap_or_else(|e| e.into_inner());
+    spawned.retain(|spawned| *spawned != pid.as_raw());
+}
+
+/// Send `signal` to every process in `session`, returning how many there were
+pub fn signal_session(session: Pid, signal: Signal) -> usize {
+    processes()
+        .filter(|stat| stat.session == session && !stat.zombie)
+        .filter(|stat| kill(stat.pid, signal).is_ok())
+        .count()
+}
+
+/// Whether there are processes in `session` that haven't exited
+pub fn has_processes(session: Pid) -> bool {
+    processes().any(|stat| stat.session == session && !stat.zombie)
+}
+
+/// What `/proc/<pid>/stat` tells about a process
+struct Stat {
+    pid: Pid,
+    zombie: bool,
+    parent: Pid,
+    session: Pid,
+}
+
+/// The processes running, as far as they are still around when looked at
+fn processes() -> impl Iterator<Item = Stat> {
+    fs::read_dir("/proc")
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter_map(|pid| {
+            let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+            // the name in parentheses before them may contain anything
+            let fields: Vec<&str> = stat.get(stat.rfind(')')? + 2..)?.split(' ').collect();
+            let number = |i: usize| fields.get(i)?.parse().ok().map(Pid::from_raw);
+            Some(Stat {
+                pid: Pid::from_raw(pid),
+                zombie: *fields.first()? == "Z",
+                parent: number(1)?,
+                session: number(3)?,
+            })
+        })
+}
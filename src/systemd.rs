@@ -0,0 +1,131 @@
+//! Running as a systemd service. The server takes over the sockets of a socket unit if systemd
+//! passes any, tells the service manager once it is ready and when it stops, and pings it while
+//! the unit has a watchdog (`WatchdogSec=`), making `Type=notify` units work:
+//!
+//! ```ini
+//! [Service]
+//! Type=notify
+//! ExecStart=/usr/bin/sh-over-ws-actuator -c /etc/shws/config.toml
+//! WatchdogSec=30
+//! ```
+//!
+//! Started any other way, none of this does anything. The variables systemd sets for the server
+//! are removed from its environment, commands spawned for sessions don't inherit them.
+use anyhow::{anyhow, Context, Result};
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    unistd,
+};
+use std::{
+    env,
+    os::unix::{
+        io::{FromRawFd, OwnedFd, RawFd},
+        net::{SocketAddr, UnixDatagram},
+    },
+    time::Duration,
+};
+use tracing::warn;
+
+/// The first file descriptor systemd passes sockets as, see sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Take over the sockets systemd passed the server, none unless it was socket activated
+pub fn listen_fds() -> Result<Vec<OwnedFd>> {
+    let err_context = || "failed to take over the sockets passed by systemd";
+
+    let pid = env::var("LISTEN_PID").ok();
+    let count = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    // the variables may have been inherited from a parent that was passed the sockets
+    if pid.and_then(|pid| pid.parse().ok()) != Some(unistd::getpid().as_raw()) {
+        return Ok(vec![]);
+    }
+    let count: RawFd = count
+        .unwrap_or_default()
+        .parse()
+        .map_err(|_| anyhow!("LISTEN_FDS is not a number"))
+        .with_context(err_context)?;
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // the sockets are passed on to whatever the server executes otherwise
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).with_context(err_context)?;
+            // systemd hands them over to the process and nothing else in it owns them
+            Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+        })
+        .collect()
+}
+
+/// Talks to the service manager that started the server, see sd_notify(3)
+pub struct Notifier {
+    /// Where notifications go, `None` if not started by a service manager
+    socket: Option<SocketAddr>,
+    /// Time the service manager waits for a ping before it considers the server hung
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Take the socket and the watchdog timeout of the service manager from the environment
+    pub fn from_env() -> Self {
+        let socket = env::var("NOTIFY_SOCKET").ok();
+        let watchdog_pid = env::var("WATCHDOG_PID").ok();
+        let watchdog_usec = env::var("WATCHDOG_USEC").ok();
+        for name in ["NOTIFY_SOCKET", "WATCHDOG_PID", "WATCHDOG_USEC"] {
+            env::remove_var(name);
+        }
+        let socket = socket.and_then(|socket| match notify_address(&socket) {
+            Ok(address) => Some(address),
+            Err(e) => {
+                warn!("ignoring NOTIFY_SOCKET '{}': {}", socket, e);
+                None
+            },
+        });
+        let for_this_process =
+            watchdog_pid.is_none_or(|pid| pid.parse().ok() == Some(unistd::getpid().as_raw()));
+        let watchdog = watchdog_usec
+            .filter(|_| for_this_process)
+            .and_then(|usec| usec.parse().ok())
+            .filter(|usec| *usec > 0)
+            .map(Duration::from_micros);
+        Notifier { socket, watchdog }
+    }
+
+    /// Tell the service manager `state`, like `READY=1`. Failures are logged, the server runs on
+    /// just the same.
+    pub fn notify(&self, state: &str) {
+        let Some(socket) = self.socket.as_ref() else {
+            return;
+        };
+        let sent = UnixDatagram::unbound()
+            .and_then(|datagram| datagram.send_to_addr(state.as_bytes(), socket));
+        if let Err(e) = sent {
+            warn!("failed to notify systemd of {}: {}", state, e);
+        }
+    }
+
+    /// How often to send `WATCHDOG=1`, half the timeout as sd_watchdog_enabled(3) advises.
+    /// `None` if the service manager doesn't watch the server.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.socket.as_ref()?;
+        self.watchdog.map(|timeout| timeout / 2)
+    }
+}
+
+/// The address of `NOTIFY_SOCKET`, a path or a name in the abstract namespace if it starts with
+/// `@`
+fn notify_address(socket: &str) -> std::io::Result<SocketAddr> {
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)
+        },
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "abstract sockets are Linux only",
+        )),
+        None => SocketAddr::from_pathname(socket),
+    }
+}
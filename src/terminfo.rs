@@ -0,0 +1,82 @@
+//! `TERM` of terminal sessions. Clients say which terminal they emulate when opening a session,
+//! see [`TerminalInfo`], and its command gets that as `TERM` if the server has a terminfo entry
+//! for it. Otherwise it gets the first of [`FALLBACKS`] the server has one for, rather than a
+//! terminal programs don't know and fail to start on, or send sequences for the client doesn't
+//! understand. Clients showing 24-bit colors get `COLORTERM=truecolor` as well.
+//!
+//! Entries are looked up where ncurses looks for them on the server, commands of profiles
+//! running on other hosts may know other terminals.
+use crate::data::{ColorDepth, TerminalInfo};
+use std::{
+    collections::BTreeMap,
+    env,
+    path::{Path, PathBuf},
+};
+use tracing::info;
+
+/// What commands get as `TERM` when there is no entry for the terminal of the client, the first
+/// the server has an entry for. The last one is as good as any when there is no entry at all.
+pub const FALLBACKS: &[&str] = &["xterm-256color", "vt100"];
+
+/// Where ncurses looks for terminfo entries besides `$TERMINFO`, `~/.terminfo` and
+/// `$TERMINFO_DIRS`
+const SYSTEM_DIRS: &[&str] = &[
+    "/etc/terminfo",
+    "/lib/terminfo",
+    "/usr/share/terminfo",
+    "/usr/lib/terminfo",
+];
+
+/// The directories terminfo entries are looked up in, in the order ncurses does
+pub fn search_path() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    dirs.extend(env::var_os("TERMINFO").map(PathBuf::from));
+    dirs.extend(env::var_os("HOME").map(|home| Path::new(&home).join(".terminfo")));
+    if let Some(listed) = env::var_os("TERMINFO_DIRS") {
+        // empty entries stand for the system directories, which come next anyway
+        dirs.extend(env::split_paths(&listed).filter(|dir| !dir.as_os_str().is_empty()));
+    }
+    dirs.extend(SYSTEM_DIRS.iter().map(PathBuf::from));
+    dirs
+}
+
+/// Whether one of `dirs` has an entry for `term`. Entries are in a directory named after their
+/// first letter, or after its hexadecimal code on case-insensitive file systems.
+pub fn has_entry(dirs: &[PathBuf], term: &str) -> bool {
+    let Some(first) = term.chars().next().filter(|_| is_valid_name(term)) else {
+        return false;
+    };
+    dirs.iter().any(|dir| {
+        dir.join(first.to_string()).join(term).is_file()
+            || dir.join(format!("{:x}", first as u32)).join(term).is_file()
+    })
+}
+
+/// Entries are files, names that would be looked up elsewhere are refused
+fn is_valid_name(term: &str) -> bool {
+    !term.starts_with('.') && term.chars().all(|c| c.is_ascii_graphic() && c != '/')
+}
+
+/// `TERM` for a client emulating `term`: itself if one of `dirs` has an entry for it, the first
+/// of [`FALLBACKS`] that has one otherwise
+pub fn negotiate(term: &str, dirs: &[PathBuf]) -> String {
+    if has_entry(dirs, term) {
+        return term.to_string();
+    }
+    let fallback = FALLBACKS
+        .iter()
+        .find(|fallback| has_entry(dirs, fallback))
+        .or(FALLBACKS.last())
+        .expect("fallbacks aren't empty");
+    info!("no terminfo entry for '{}', using {}", term.escape_debug(), fallback);
+    fallback.to_string()
+}
+
+/// The variables a command running on `terminal` gets, entries looked up in `dirs`
+pub fn environment(terminal: &TerminalInfo, dirs: &[PathBuf]) -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::from([("TERM".to_string(), negotiate(&terminal.term, dirs))]);
+    if terminal.colors == Some(ColorDepth::TrueColor) {
+        vars.insert("COLORTERM".to_string(), "truecolor".to_string());
+    }
+    vars
+}
@@ -0,0 +1,147 @@
+//! `wss://` support. Certificates are read from PEM files and can be reloaded at runtime, e.g. when
+//! the server receives `SIGHUP`, without touching established connections. This also sets up the
+//! TLS side of [webhooks](crate::notify) and, with the `client` feature, of
+//! [`ActuatorClient`](crate::client::ActuatorClient).
+use crate::error::ToAnyhow;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::info;
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain presented to clients
+    pub cert: PathBuf,
+    /// PEM file with the private key of the certificate
+    pub key: PathBuf,
+    /// PEM file with the CAs client certificates must be signed by. Clients without a valid
+    /// certificate are rejected if this is set.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let err_context = || format!("failed to load certificates from '{}'", path.display());
+
+    let mut reader = BufReader::new(File::open(path).with_context(err_context)?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(err_context)?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificates found")).with_context(err_context);
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let err_context = || format!("failed to load private key from '{}'", path.display());
+
+    let mut reader = BufReader::new(File::open(path).with_context(err_context)?);
+    rustls_pemfile::private_key(&mut reader)
+        .with_context(err_context)?
+        .ok_or_else(|| anyhow!("no private key found"))
+        .with_context(err_context)
+}
+
+/// Build the rustls config described by `config`, reading all files anew
+pub fn load_server_config(config: &TlsConfig) -> Result<Arc<ServerConfig>> {
+    let err_context = || "failed to set up TLS".to_string();
+
+    let builder = ServerConfig::builder();
+    let builder = match config.client_ca.as_ref() {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca).with_context(err_context)? {
+                roots.add(cert).with_context(err_context)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .with_context(err_context)?;
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder
+        .with_single_cert(load_certs(&config.cert)?, load_key(&config.key)?)
+        .with_context(err_context)?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(server_config))
+}
+
+/// The rustls config of a client trusting the CAs in PEM file `ca`, or the Mozilla root
+/// certificates if `None`. `identity` is the certificate chain and private key to present to
+/// servers requiring client certificates.
+pub fn load_client_config(
+    ca: Option<&Path>,
+    identity: Option<(&Path, &Path)>,
+) -> Result<Arc<tokio_rustls::rustls::ClientConfig>> {
+    let err_context = || "failed to set up TLS".to_string();
+
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in load_certs(ca).with_context(err_context)? {
+                roots.add(cert).with_context(err_context)?;
+            }
+        },
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let builder = tokio_rustls::rustls::ClientConfig::builder().with_root_certificates(roots);
+    let client_config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .with_context(err_context)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(client_config))
+}
+
+/// A [`TlsAcceptor`] whose certificates can be swapped while the server is running
+pub struct ReloadableAcceptor {
+    config: TlsConfig,
+    current: RwLock<TlsAcceptor>,
+}
+
+impl ReloadableAcceptor {
+    pub fn new(config: TlsConfig) -> Result<Self> {
+        let acceptor = TlsAcceptor::from(load_server_config(&config)?);
+        Ok(ReloadableAcceptor {
+            config,
+            current: RwLock::new(acceptor),
+        })
+    }
+
+    /// The acceptor for the next connection
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        Ok(self.current.read().to_anyhow()?.clone())
+    }
+
+    /// Read the certificate files again. The previous certificates stay in use if that fails.
+    pub fn reload(&self) -> Result<()> {
+        let acceptor = TlsAcceptor::from(load_server_config(&self.config)?);
+        *self
+            .current
+            .write()
+            .to_anyhow()
+            .context("failed to reload TLS certificates")? = acceptor;
+        info!(
+            "reloaded TLS certificates from '{}'",
+            self.config.cert.display()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,464 @@
+//! File transfers over the connection of a client. Uploads are written to a temporary file next
+//! to their destination, which only replaces the destination once all of the file arrived with
+//! the digest the client announced, so that a failed upload leaves nothing behind. Downloads are
+//! streamed in chunks, paced by the [`Backlog`] of the connection like session output is.
+//!
+//! Paths are taken relative to the configured [root](FileTransferConfig::root) and may not lead
+//! out of it, neither through `..` nor through symlinks.
+use crate::{
+    audit::hex,
+    config::{Config, FileTransferConfig},
+    data::{Blob, Message, TransferId},
+    error::{ProtocolError, ToAnyhow},
+    session::Backlog,
+};
+use anyhow::{anyhow, Context, Result};
+use nix::unistd;
+use ring::digest::{self, SHA256};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, File, Permissions},
+    io::Write,
+    os::unix::{fs::PermissionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tempfile::NamedTempFile;
+use tokio::{io::AsyncReadExt, sync::mpsc, task::AbortHandle};
+use tracing::info;
+
+/// Size of the chunks downloads are sent in
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// Failed uploads remembered to drop the rest of their chunks quietly
+const MAX_ABANDONED: usize = 1024;
+
+/// A file outside of the file root was asked for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutsideFileRoot {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for OutsideFileRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' is outside of the file root", self.path.display())
+    }
+}
+
+impl std::error::Error for OutsideFileRoot {}
+
+/// A file larger than [`FileTransferConfig::max_size`] was to be transferred
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileTooLarge {
+    pub limit: u64,
+}
+
+impl fmt::Display for FileTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "files may be no larger than {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for FileTooLarge {}
+
+/// An upload arrived with a different digest than the client announced
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "expected SHA-256 digest {}, the file arrived with {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// An upload in progress
+struct Upload {
+    /// The path as the client gave it
+    requested: PathBuf,
+    /// Where the file goes once complete
+    path: PathBuf,
+    file: NamedTempFile,
+    size: u64,
+    received: u64,
+    digest: digest::Context,
+}
+
+/// The file transfers of one connection. Messages for the client are sent to the `events`
+/// channel handed to [`Transfers::new`].
+pub struct Transfers {
+    config: Arc<Config>,
+    uploads: Mutex<HashMap<TransferId, Upload>>,
+    /// Uploads that failed while the client may still be sending them
+    abandoned: Mutex<HashSet<TransferId>>,
+    downloads: Arc<Mutex<HashMap<TransferId, AbortHandle>>>,
+    events: mpsc::UnboundedSender<Message>,
+    backlog: Arc<Backlog>,
+}
+
+impl Transfers {
+    pub fn new(
+        config: Arc<Config>,
+        events: mpsc::UnboundedSender<Message>,
+        backlog: Arc<Backlog>,
+    ) -> Self {
+        Transfers {
+            config,
+            uploads: Mutex::default(),
+            abandoned: Mutex::default(),
+            downloads: Arc::default(),
+            events,
+            backlog,
+        }
+    }
+
+    /// Act on a transfer message received from the client. Failures are reported back to the
+    /// client as [`Message::TransferFailed`] and end the transfer.
+    pub fn handle_message(&self, message: Message) {
+        let Some(transfer) = message.transfer() else {
+            return;
+        };
+        let result = match message {
+            Message::FileUploadStart {
+                transfer,
+                path,
+                size,
+                mode,
+            } => self.start_upload(transfer, &path, size, mode),
+            Message::FileUploadChunk { transfer, data } => self.write(transfer, &data.0),
+            Message::FileUploadEnd { transfer, sha256 } => self.finish_upload(transfer, &sha256),
+            Message::FileDownloadRequest { transfer, path } => self.download(transfer, &path),
+            Message::TransferFailed { transfer, .. } => {
+                self.cancel(transfer);
+                Ok(())
+            },
+            _ => Err(anyhow!("unexpected message from client")),
+        };
+        if let Err(e) = result {
+            self.abandon(transfer);
+            let _ = self.events.send(Message::TransferFailed {
+                transfer,
+                error: ProtocolError::from(&e),
+            });
+        }
+    }
+
+    fn files(&self) -> Result<&FileTransferConfig> {
+        self.config
+            .files
+            .as_ref()
+            .ok_or_else(|| anyhow!("file transfers are disabled"))
+    }
+
+    /// Start receiving the `size` bytes of a file to be stored at `path`
+    fn start_upload(
+        &self,
+        id: TransferId,
+        path: &Path,
+        size: u64,
+        mode: Option<u32>,
+    ) -> Result<()> {
+        let err_context = || format!("failed to upload '{}'", path.display());
+
+        let files = self.files().with_context(err_context)?;
+        if size > files.max_size {
+            return Err(FileTooLarge {
+                limit: files.max_size,
+            })
+            .with_context(err_context);
+        }
+        let target = resolve(&files.root, path).with_context(err_context)?;
+        let dir = target.parent().expect("resolved files are in a directory");
+        let file = NamedTempFile::new_in(dir).with_context(err_context)?;
+        // no setuid and friends from clients
+        let mode = mode.unwrap_or(0o644) & 0o777;
+        file.as_file()
+            .set_permissions(Permissions::from_mode(mode))
+            .with_context(err_context)?;
+        // files go to whoever runs the commands that will work with them
+        if let Some(run_as) = self.config.run_as.as_ref() {
+            let user = run_as.credentials().with_context(err_context)?;
+            unistd::fchown(file.as_file().as_raw_fd(), Some(user.uid), Some(user.gid))
+                .with_context(err_context)?;
+        }
+
+        let mut uploads = self.uploads.lock().to_anyhow().with_context(err_context)?;
+        if uploads.contains_key(&id) {
+            return Err(anyhow!("transfer {} is in progress already", id));
+        }
+        info!(
+            "upload {}: receiving {} bytes for '{}'",
+            id,
+            size,
+            target.display()
+        );
+        uploads.insert(
+            id,
+            Upload {
+                requested: path.to_path_buf(),
+                path: target,
+                file,
+                size,
+                received: 0,
+                digest: digest::Context::new(&SHA256),
+            },
+        );
+        Ok(())
+    }
+
+    /// Append `data` to upload `id`
+    fn write(&self, id: TransferId, data: &[u8]) -> Result<()> {
+        let err_context = || format!("failed to upload transfer {}", id);
+
+        let mut uploads = self.uploads.lock().to_anyhow().with_context(err_context)?;
+        let Some(upload) = uploads.get_mut(&id) else {
+            // the client was told already
+            if self.is_abandoned(id) {
+                return Ok(());
+            }
+            return Err(anyhow!("no such transfer")).with_context(err_context);
+        };
+        if upload.received + data.len() as u64 > upload.size {
+            return Err(anyhow!("more than the announced {} bytes", upload.size))
+                .with_context(err_context);
+        }
+        upload.file.write_all(data).with_context(err_context)?;
+        upload.digest.update(data);
+        upload.received += data.len() as u64;
+        Ok(())
+    }
+
+    /// Store upload `id` if it arrived complete with digest `sha256`
+    fn finish_upload(&self, id: TransferId, sha256: &str) -> Result<()> {
+        let err_context = || format!("failed to upload transfer {}", id);
+
+        let upload = self
+            .uploads
+            .lock()
+            .to_anyhow()
+            .with_context(err_context)?
+            .remove(&id);
+        let Some(upload) = upload else {
+            if let Ok(mut abandoned) = self.abandoned.lock() {
+                if abandoned.remove(&id) {
+                    return Ok(());
+                }
+            }
+            return Err(anyhow!("no such transfer")).with_context(err_context);
+        };
+        let err_context = || format!("failed to upload '{}'", upload.requested.display());
+        if upload.received != upload.size {
+            return Err(anyhow!(
+                "only {} of {} bytes arrived",
+                upload.received,
+                upload.size
+            ))
+            .with_context(err_context);
+        }
+        let actual = hex(upload.digest.finish());
+        if !actual.eq_ignore_ascii_case(sha256) {
+            return Err(ChecksumMismatch {
+                expected: sha256.to_string(),
+                actual,
+            })
+            .with_context(err_context);
+        }
+        upload.file.as_file().sync_all().with_context(err_context)?;
+        upload
+            .file
+            .persist(&upload.path)
+            .map_err(|e| e.error)
+            .with_context(err_context)?;
+        info!("upload {}: stored '{}'", id, upload.path.display());
+        let _ = self.events.send(Message::FileUploaded { transfer: id });
+        Ok(())
+    }
+
+    /// Send the file at `path` to the client in the background
+    fn download(&self, id: TransferId, path: &Path) -> Result<()> {
+        let err_context = || format!("failed to download '{}'", path.display());
+
+        let files = self.files().with_context(err_context)?;
+        let resolved = resolve(&files.root, path).with_context(err_context)?;
+        let file = File::open(&resolved).with_context(err_context)?;
+        let metadata = file.metadata().with_context(err_context)?;
+        if !metadata.is_file() {
+            return Err(anyhow!("not a file")).with_context(err_context);
+        }
+        if metadata.len() > files.max_size {
+            return Err(FileTooLarge {
+                limit: files.max_size,
+            })
+            .with_context(err_context);
+        }
+
+        let mut downloads = self
+            .downloads
+            .lock()
+            .to_anyhow()
+            .with_context(err_context)?;
+        if downloads.contains_key(&id) {
+            return Err(anyhow!("transfer {} is in progress already", id));
+        }
+        info!("download {}: sending '{}'", id, resolved.display());
+        let sender = Sender {
+            id,
+            events: self.events.clone(),
+            backlog: self.backlog.clone(),
+            high_watermark: self.config.flow_control.high_watermark,
+            low_watermark: self.config.flow_control.low_watermark,
+        };
+        let running = self.downloads.clone();
+        let requested = path.to_path_buf();
+        let task = tokio::spawn(async move {
+            if let Err(e) = sender.send_file(file, metadata.len()).await {
+                let e = e.context(format!("failed to download '{}'", requested.display()));
+                // the connection may be gone already, then nobody is left to tell
+                let _ = sender.events.send(Message::TransferFailed {
+                    transfer: id,
+                    error: ProtocolError::from(&e),
+                });
+            }
+            if let Ok(mut running) = running.lock() {
+                running.remove(&id);
+            }
+        });
+        downloads.insert(id, task.abort_handle());
+        Ok(())
+    }
+
+    /// Stop transfer `id` on behalf of the client
+    fn cancel(&self, id: TransferId) {
+        if let Ok(mut uploads) = self.uploads.lock() {
+            uploads.remove(&id);
+        }
+        if let Some(download) = self.downloads.lock().ok().and_then(|mut d| d.remove(&id)) {
+            download.abort();
+        }
+        info!("transfer {}: cancelled by the client", id);
+    }
+
+    /// Forget about upload `id` after it failed, its temporary file goes with it
+    fn abandon(&self, id: TransferId) {
+        let was_upload = self
+            .uploads
+            .lock()
+            .ok()
+            .and_then(|mut uploads| uploads.remove(&id))
+            .is_some();
+        if let Ok(mut abandoned) = self.abandoned.lock() {
+            if abandoned.len() >= MAX_ABANDONED {
+                abandoned.clear();
+            }
+            if was_upload || !self.downloads.lock().is_ok_and(|d| d.contains_key(&id)) {
+                abandoned.insert(id);
+            }
+        }
+    }
+
+    fn is_abandoned(&self, id: TransferId) -> bool {
+        self.abandoned
+            .lock()
+            .is_ok_and(|abandoned| abandoned.contains(&id))
+    }
+}
+
+impl Drop for Transfers {
+    fn drop(&mut self) {
+        if let Ok(downloads) = self.downloads.lock() {
+            for download in downloads.values() {
+                download.abort();
+            }
+        }
+    }
+}
+
+/// Sends one download to the client
+struct Sender {
+    id: TransferId,
+    events: mpsc::UnboundedSender<Message>,
+    backlog: Arc<Backlog>,
+    high_watermark: usize,
+    low_watermark: usize,
+}
+
+impl Sender {
+    async fn send_file(&self, file: File, size: u64) -> Result<()> {
+        self.send(Message::FileDownloadStart {
+            transfer: self.id,
+            size,
+        })?;
+        // a file growing meanwhile is cut off at the size announced
+        let mut file = tokio::fs::File::from_std(file).take(size);
+        let mut digest = digest::Context::new(&SHA256);
+        let mut sent = 0;
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let n = file.read(&mut buf).await.context("failed to read file")?;
+            if n == 0 {
+                break;
+            }
+            digest.update(&buf[..n]);
+            sent += n as u64;
+            self.backlog.queued(n);
+            self.send(Message::FileDownloadChunk {
+                transfer: self.id,
+                data: Blob(buf[..n].to_vec()),
+            })?;
+            if self.backlog.len() >= self.high_watermark {
+                tokio::select! {
+                    _ = self.backlog.drained_to(self.low_watermark) => {},
+                    _ = self.events.closed() => return Err(anyhow!("connection closed")),
+                }
+            }
+        }
+        if sent != size {
+            return Err(anyhow!("file shrank to {} bytes while being sent", sent));
+        }
+        self.send(Message::FileDownloadEnd {
+            transfer: self.id,
+            sha256: hex(digest.finish()),
+        })
+    }
+
+    fn send(&self, message: Message) -> Result<()> {
+        self.events
+            .send(message)
+            .map_err(|_| anyhow!("connection closed"))
+    }
+}
+
+/// The file `path` refers to within `root`. Its directory has to exist; the file itself is
+/// resolved as well if it exists, so that a symlink can't lead out of the root.
+fn resolve(root: &Path, path: &Path) -> Result<PathBuf> {
+    let outside = || OutsideFileRoot {
+        path: path.to_path_buf(),
+    };
+
+    let root = fs::canonicalize(root)
+        .with_context(|| format!("failed to find file root '{}'", root.display()))?;
+    let relative = path.strip_prefix("/").unwrap_or(path);
+    let Some(name) = relative.file_name() else {
+        return Err(anyhow!("'{}' does not name a file", path.display()));
+    };
+    let dir = root.join(relative.parent().unwrap_or(Path::new("")));
+    // resolving `..` and symlinks first catches every way out of the root
+    let dir = fs::canonicalize(&dir)
+        .with_context(|| format!("failed to find the directory of '{}'", path.display()))?;
+    if !dir.starts_with(&root) {
+        return Err(outside().into());
+    }
+    let file = dir.join(name);
+    match fs::canonicalize(&file) {
+        Ok(resolved) if !resolved.starts_with(&root) => Err(outside().into()),
+        _ => Ok(file),
+    }
+}
@@ -0,0 +1,272 @@
+//! What connections exchange their frames over. Serving a connection takes nothing but a
+//! [`Transport`] carrying the encoded [`Message`](crate::data::Message)s both ways: the
+//! [server](crate::server) serves the WebSocket connections it accepts as a [`WebSocket`], other
+//! transports carry the same protocol without the sessions telling them apart.
+//!
+//! Byte streams without frames of their own, such as the QUIC streams of WebTransport sessions,
+//! carry them as [`Framed`].
+use crate::data::CloseReason;
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use std::io::ErrorKind;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_tungstenite::{
+    tungstenite::{
+        error::ProtocolError,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Error as WsError, Message as WsMessage,
+    },
+    WebSocketStream,
+};
+
+/// A frame of a connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    /// A JSON encoded message
+    Text(String),
+    /// A MessagePack encoded message, compressed if the client asked for it
+    Binary(Vec<u8>),
+    /// Asks the peer to answer with a [`Frame::Pong`] carrying the same payload. Transports
+    /// without pings of their own drop it, clients are pinged with a message as well.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+}
+
+/// Receiving from a transport failed. The connection is closed for `reason` if given, telling
+/// the peer what it did wrong.
+#[derive(Debug)]
+pub struct ReceiveError {
+    pub reason: Option<CloseReason>,
+    pub error: anyhow::Error,
+}
+
+/// Carries the frames of a connection
+#[async_trait]
+pub trait Transport: Send {
+    /// Send `frame`, waiting until the transport took it
+    async fn send(&mut self, frame: Frame) -> Result<()>;
+
+    /// The next frame, `None` once the peer closed the connection or went away without saying
+    /// goodbye. Cancel safe: dropping the future before it resolved loses no frame.
+    async fn receive(&mut self) -> Result<Option<Frame>, ReceiveError>;
+
+    /// Close the connection for `reason`, telling the peer more in `message`
+    async fn close(&mut self, reason: CloseReason, message: String) -> Result<()>;
+}
+
+/// A WebSocket connection the handshake was done for
+pub struct WebSocket<S> {
+    stream: WebSocketStream<S>,
+}
+
+impl<S> WebSocket<S> {
+    pub fn new(stream: WebSocketStream<S>) -> Self {
+        WebSocket { stream }
+    }
+}
+
+#[async_trait]
+impl<S> Transport for WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn send(&mut self, frame: Frame) -> Result<()> {
+        let frame = match frame {
+            Frame::Text(text) => WsMessage::Text(text),
+            Frame::Binary(bytes) => WsMessage::Binary(bytes),
+            Frame::Ping(payload) => WsMessage::Ping(payload),
+            Frame::Pong(payload) => WsMessage::Pong(payload),
+        };
+        self.stream.send(frame).await.context("failed to send frame")
+    }
+
+    async fn receive(&mut self) -> Result<Option<Frame>, ReceiveError> {
+        loop {
+            let failed = |reason, e: WsError| ReceiveError {
+                reason,
+                error: e.into(),
+            };
+            return match self.stream.next().await {
+                Some(Ok(WsMessage::Text(text))) => Ok(Some(Frame::Text(text))),
+                Some(Ok(WsMessage::Binary(bytes))) => Ok(Some(Frame::Binary(bytes))),
+                Some(Ok(WsMessage::Ping(payload))) => Ok(Some(Frame::Ping(payload))),
+                Some(Ok(WsMessage::Pong(payload))) => Ok(Some(Frame::Pong(payload))),
+                Some(Ok(WsMessage::Close(_))) | None => Ok(None),
+                // only ever sent, never received
+                Some(Ok(WsMessage::Frame(_))) => continue,
+                // clients vanishing without saying goodbye is nothing to worry about
+                Some(Err(WsError::Protocol(ProtocolError::ResetWithoutClosingHandshake))) => {
+                    Ok(None)
+                },
+                Some(Err(WsError::Io(e))) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+                Some(Err(e @ WsError::Capacity(_))) => {
+                    Err(failed(Some(CloseReason::PolicyViolation), e))
+                },
+                Some(Err(e @ (WsError::Protocol(_) | WsError::Utf8))) => {
+                    Err(failed(Some(CloseReason::ProtocolError), e))
+                },
+                Some(Err(e)) => Err(failed(None, e)),
+            };
+        }
+    }
+
+    async fn close(&mut self, reason: CloseReason, message: String) -> Result<()> {
+        let close = CloseFrame {
+            code: CloseCode::from(reason.code()),
+            reason: message.into(),
+        };
+        self.stream
+            .send(WsMessage::Close(Some(close)))
+            .await
+            .context("failed to send frame")
+    }
+}
+
+/// Kind byte of the frames of a [`Framed`] stream, see there
+const TEXT: u8 = 0;
+const BINARY: u8 = 1;
+const PING: u8 = 2;
+const PONG: u8 = 3;
+const CLOSE: u8 = 4;
+
+/// Kind byte and length the payload of each frame of a [`Framed`] stream starts with
+const HEADER_LEN: usize = 5;
+
+/// Largest payload of a frame of a [`Framed`] stream, as large as WebSocket messages may be
+pub const MAX_FRAME_LEN: usize = 64 << 20;
+
+/// Frames carried over a byte stream, read from `R` and written to `W`. Each frame is a kind
+/// byte, the length of its payload as big endian `u32` and the payload:
+///
+/// | Kind | Frame                                                             |
+/// |------|-------------------------------------------------------------------|
+/// | 0    | [`Frame::Text`], UTF-8                                            |
+/// | 1    | [`Frame::Binary`]                                                 |
+/// | 2    | [`Frame::Ping`], answered by the peer with a pong of its own      |
+/// | 3    | [`Frame::Pong`]                                                   |
+/// | 4    | close, the [`CloseReason`] code as big endian `u16` and a message |
+///
+/// Either side closing finishes its half of the stream after the close frame.
+pub struct Framed<R, W> {
+    reader: R,
+    writer: W,
+    /// What was read of frames not complete yet
+    buffer: Vec<u8>,
+}
+
+impl<R, W> Framed<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Framed {
+            reader,
+            writer,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Kind and payload of the frame at the start of the buffer, if it is complete
+    fn next_frame(&mut self) -> Result<Option<(u8, Vec<u8>)>, ReceiveError> {
+        let Some(header) = self.buffer.get(..HEADER_LEN) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(ReceiveError {
+                reason: Some(CloseReason::PolicyViolation),
+                error: anyhow!("frame of {} bytes exceeds the limit of {}", len, MAX_FRAME_LEN),
+            });
+        }
+        if self.buffer.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+        let kind = header[0];
+        let payload = self.buffer.drain(..HEADER_LEN + len).skip(HEADER_LEN).collect();
+        Ok(Some((kind, payload)))
+    }
+}
+
+impl<R, W: AsyncWrite + Unpin> Framed<R, W> {
+    async fn write_frame(&mut self, kind: u8, payload: &[u8]) -> Result<()> {
+        let len = u32::try_from(payload.len())
+            .ok()
+            .filter(|&len| len as usize <= MAX_FRAME_LEN)
+            .ok_or_else(|| anyhow!("frame of {} bytes is too large", payload.len()))?;
+        let mut header = [kind, 0, 0, 0, 0];
+        header[1..].copy_from_slice(&len.to_be_bytes());
+        self.writer.write_all(&header).await?;
+        self.writer.write_all(payload).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<R, W> Transport for Framed<R, W>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn send(&mut self, frame: Frame) -> Result<()> {
+        let written = match frame {
+            Frame::Text(text) => self.write_frame(TEXT, text.as_bytes()).await,
+            Frame::Binary(bytes) => self.write_frame(BINARY, &bytes).await,
+            Frame::Ping(payload) => self.write_frame(PING, &payload).await,
+            Frame::Pong(payload) => self.write_frame(PONG, &payload).await,
+        };
+        written.context("failed to send frame")
+    }
+
+    async fn receive(&mut self) -> Result<Option<Frame>, ReceiveError> {
+        let violation = |error| ReceiveError {
+            reason: Some(CloseReason::ProtocolError),
+            error,
+        };
+        loop {
+            let Some((kind, payload)) = self.next_frame()? else {
+                // reading into the buffer loses nothing when cancelled
+                let read = self.reader.read_buf(&mut self.buffer).await;
+                match read {
+                    Ok(0) => return Ok(None),
+                    Ok(_) => continue,
+                    // peers going away without closing, resetting the stream or the connection
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            ErrorKind::UnexpectedEof
+                                | ErrorKind::ConnectionReset
+                                | ErrorKind::NotConnected
+                        ) =>
+                    {
+                        return Ok(None)
+                    },
+                    Err(e) => {
+                        return Err(ReceiveError {
+                            reason: None,
+                            error: anyhow::Error::from(e).context("failed to receive frame"),
+                        })
+                    },
+                }
+            };
+            return match kind {
+                TEXT => match String::from_utf8(payload) {
+                    Ok(text) => Ok(Some(Frame::Text(text))),
+                    Err(e) => Err(violation(e.into())),
+                },
+                BINARY => Ok(Some(Frame::Binary(payload))),
+                PING => Ok(Some(Frame::Ping(payload))),
+                PONG => Ok(Some(Frame::Pong(payload))),
+                CLOSE => Ok(None),
+                kind => Err(violation(anyhow!("unknown frame kind {}", kind))),
+            };
+        }
+    }
+
+    async fn close(&mut self, reason: CloseReason, message: String) -> Result<()> {
+        let mut payload = reason.code().to_be_bytes().to_vec();
+        payload.extend_from_slice(message.as_bytes());
+        self.write_frame(CLOSE, &payload)
+            .await
+            .context("failed to send frame")?;
+        self.writer.shutdown().await.context("failed to send frame")
+    }
+}
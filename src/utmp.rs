@@ -0,0 +1,118 @@
+//! Logins recorded in utmp and wtmp, for `who`, `w` and `last` to show the terminal sessions of
+//! profiles asking for it, see [`Login::utmp`](crate::command::Login::utmp). Writing the records
+//! takes root, or the group owning the files on most systems.
+//!
+//! Linux only.
+use crate::os_io::Pid;
+use anyhow::{anyhow, Context, Result};
+use std::{
+    ffi::c_char,
+    io,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+/// The login a session was recorded as, recorded as over once dropped
+pub struct LoginRecord {
+    /// The terminal relative to `/dev`, such as `pts/3`
+    line: String,
+    pid: Pid,
+}
+
+impl LoginRecord {
+    /// Record that `user` logged in from `host` on `tty`, such as `/dev/pts/3`, running the
+    /// command `pid`
+    pub fn login(tty: &Path, user: &str, host: &str, pid: Pid) -> Result<LoginRecord> {
+        let err_context = || format!("failed to record login of '{}' on {}", user, tty.display());
+
+        let line = tty
+            .strip_prefix("/dev")
+            .ok()
+            .and_then(Path::to_str)
+            .ok_or_else(|| anyhow!("not a terminal device"))
+            .with_context(err_context)?;
+        let record = LoginRecord {
+            line: line.to_string(),
+            pid,
+        };
+        record
+            .write(Kind::User, user, host)
+            .with_context(err_context)?;
+        Ok(record)
+    }
+
+    /// Add an entry of `kind` to utmp and wtmp
+    #[cfg(target_os = "linux")]
+    fn write(&self, kind: Kind, user: &str, host: &str) -> io::Result<()> {
+        // the functions keep their own state, which can't be shared
+        static UTMP: Mutex<()> = Mutex::new(());
+        extern "C" {
+            fn updwtmpx(wtmpx_file: *const c_char, utmpx: *const libc::utmpx);
+        }
+
+        // SAFETY: utmpx is plain old data, for which zeroes are the default
+        let mut entry: libc::utmpx = unsafe { std::mem::zeroed() };
+        entry.ut_type = match kind {
+            Kind::User => libc::USER_PROCESS,
+            Kind::Dead => libc::DEAD_PROCESS,
+        };
+        entry.ut_pid = self.pid.as_raw();
+        entry.ut_session = self.pid.as_raw() as _;
+        // names the terminal by the end of its line, like login(1) does
+        let id = &self.line.as_bytes()[self.line.len().saturating_sub(entry.ut_id.len())..];
+        copy(&mut entry.ut_id, id);
+        copy(&mut entry.ut_line, self.line.as_bytes());
+        copy(&mut entry.ut_user, user.as_bytes());
+        copy(&mut entry.ut_host, host.as_bytes());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        entry.ut_tv.tv_sec = now.as_secs() as _;
+        entry.ut_tv.tv_usec = now.subsec_micros() as _;
+
+        let _utmp = UTMP.lock().unwrap_or_else(|e| e.into_inner());
+        // SAFETY: the entry outlives the calls, which copy it, and the lock keeps them apart
+        unsafe {
+            libc::setutxent();
+            let written = libc::pututxline(&entry);
+            let error = io::Error::last_os_error();
+            libc::endutxent();
+            updwtmpx(c"/var/log/wtmp".as_ptr(), &entry);
+            match written.is_null() {
+                true => Err(error),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn write(&self, _kind: Kind, _user: &str, _host: &str) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Drop for LoginRecord {
+    fn drop(&mut self) {
+        if let Err(e) = self.write(Kind::Dead, "", "") {
+            warn!("failed to record logout on /dev/{}: {}", self.line, e);
+        }
+    }
+}
+
+/// What an entry records
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// A user logged in
+    User,
+    /// The user logged out again
+    Dead,
+}
+
+/// Copy as much of `value` into the field `field` as fits, the rest of which stays zeroed.
+/// Fields filled up aren't terminated.
+#[cfg(target_os = "linux")]
+fn copy(field: &mut [c_char], value: &[u8]) {
+    for (c, byte) in field.iter_mut().zip(value) {
+        *c = *byte as c_char;
+    }
+}
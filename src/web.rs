@@ -0,0 +1,48 @@
+//! Web terminal served by the WebSocket listener, so that trying the server out takes nothing but
+//! a browser. Requests to the listener that don't ask for a WebSocket upgrade get the page, the
+//! files in `web/` of the source tree built into the binary, see [`http`](crate::http). The
+//! page loads xterm.js from jsDelivr.
+//!
+//! The page opens a session running the default shell on the listener it was loaded from. If
+//! the server turns it away it asks for a token, which it trades for a
+//! [ticket](crate::auth::Tickets) to connect with if the server issues them, and presents as
+//! `?token=` otherwise since browsers can't set headers for WebSockets. It follows the size of
+//! the window and reconnects when the connection drops, resuming its session. Reloading the page
+//! resumes it as well, as long as the server keeps sessions of lost connections, see
+//! [`detach_on_disconnect`](crate::config::SessionConfig::detach_on_disconnect).
+//!
+//! Only built with the `web` feature.
+use crate::http::respond_text;
+use hyper::{
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    Body, Method, Request, Response, StatusCode,
+};
+use include_dir::{include_dir, Dir};
+use std::path::Path;
+
+static ASSETS: Dir = include_dir!("$CARGO_MANIFEST_DIR/web");
+
+/// Answer `request` with the file of the page it asks for
+pub fn respond(request: &Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return respond_text(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n");
+    }
+    let path = match request.uri().path().trim_start_matches('/') {
+        "" => "index.html",
+        path => path,
+    };
+    let Some(file) = ASSETS.get_file(path) else {
+        return respond_text(StatusCode::NOT_FOUND, "not found\n");
+    };
+    let content_type = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        _ => "application/octet-stream",
+    };
+    Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .header(CACHE_CONTROL, "no-cache")
+        .body(Body::from(file.contents()))
+        .expect("valid response")
+}
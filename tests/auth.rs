@@ -0,0 +1,381 @@
+//! Authentication of clients: JSON Web Tokens and the public keys they are verified with, the
+//! tickets standing in for tokens and the lockout of clients failing to authenticate
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use ring::{
+    hmac,
+    rand::SystemRandom,
+    signature::{RsaKeyPair, RSA_PKCS1_SHA256},
+};
+use serde_json::{json, Value};
+use sh_over_ws_actuator::{
+    audit::{AuditLog, Client},
+    auth::{
+        AuthCommand, Authenticator, ExternalCommand, Identity, Jwt, JwtAlgorithm, JwtConfig,
+        Lockout, LockoutConfig, StaticTokens, Tickets,
+    },
+    config::AuditConfig,
+    redact::RedactionConfig,
+};
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+const SECRET: &str = "s3cr3t";
+
+fn data(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data").join(name)
+}
+
+fn hs256() -> JwtConfig {
+    JwtConfig {
+        algorithm: JwtAlgorithm::Hs256,
+        secret: Some(SECRET.to_string()),
+        public_key: None,
+        issuer: None,
+        audience: None,
+        leeway: 0,
+    }
+}
+
+fn rs256() -> JwtConfig {
+    JwtConfig {
+        algorithm: JwtAlgorithm::Rs256,
+        secret: None,
+        public_key: Some(data("jwt.pub.pem")),
+        ..hs256()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The header and claims of a token, before they are signed
+fn unsigned(alg: &str, claims: &Value) -> String {
+    let header = URL_SAFE_NO_PAD.encode(json!({ "alg": alg, "typ": "JWT" }).to_string());
+    format!("{}.{}", header, URL_SAFE_NO_PAD.encode(claims.to_string()))
+}
+
+/// A token with `claims` signed with HS256 and `secret`, claiming to be signed with `alg`
+fn hmac_token(alg: &str, secret: &[u8], claims: &Value) -> String {
+    let signed = unsigned(alg, claims);
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    let signature = hmac::sign(&key, signed.as_bytes());
+    format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature))
+}
+
+/// A token with `claims` signed with RS256 and the private key of the tests
+fn rsa_token(claims: &Value) -> String {
+    let pem = fs::read(data("jwt.pem")).unwrap();
+    let key = rustls_pemfile::private_key(&mut &pem[..]).unwrap().unwrap();
+    let key = RsaKeyPair::from_pkcs8(key.secret_der()).unwrap();
+    let signed = unsigned("RS256", claims);
+    let mut signature = vec![0; key.public().modulus_len()];
+    key.sign(&RSA_PKCS1_SHA256, &SystemRandom::new(), signed.as_bytes(), &mut signature)
+        .unwrap();
+    format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature))
+}
+
+fn authenticate(config: &JwtConfig, token: &str) -> anyhow::Result<Option<String>> {
+    let address: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let identity = Jwt::new(config)?.authenticate(token, address)?;
+    Ok(identity.user)
+}
+
+#[test]
+fn static_tokens_have_to_match_exactly() {
+    let tokens = StaticTokens::new(vec!["s3cr3t".to_string(), "other".to_string()]);
+    let address: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    assert!(tokens.authenticate("s3cr3t", address).is_ok());
+    assert!(tokens.authenticate("other", address).is_ok());
+    for guess in ["", "s3cr3", "s3cr3t ", "S3CR3T", "s3cr3tother"] {
+        assert!(tokens.authenticate(guess, address).is_err(), "accepted '{}'", guess);
+    }
+    assert!(StaticTokens::new(vec![]).authenticate("", address).is_err());
+}
+
+/// The program deciding on tokens running `script`, allowed `timeout` seconds
+fn external(script: &str, timeout: u64) -> ExternalCommand {
+    ExternalCommand::new(AuthCommand {
+        command: "/bin/sh".into(),
+        args: vec!["-c".to_string(), script.to_string()],
+        timeout,
+    })
+}
+
+#[test]
+fn programs_decide_on_tokens_whatever_they_output() {
+    let address: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let accepting = external("read token; [ \"$token\" = s3cr3t ] && echo alice", 5);
+    let identity = accepting.authenticate("s3cr3t", address).unwrap();
+    assert_eq!(identity.user.as_deref(), Some("alice"));
+    assert!(accepting.authenticate("guessed", address).is_err());
+    // more than fits in a pipe
+    let chatty = external("echo bob; head -c 1000000 /dev/zero", 5);
+    let identity = chatty.authenticate("s3cr3t", address).unwrap();
+    assert_eq!(identity.user.as_deref(), Some("bob"));
+}
+
+#[test]
+fn programs_taking_too_long_are_killed() {
+    let address: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let started = std::time::Instant::now();
+    assert!(external("sleep 10", 1).authenticate("s3cr3t", address).is_err());
+    // or leaving something behind holding their output
+    assert!(external("sleep 10 & echo alice", 1)
+        .authenticate("s3cr3t", address)
+        .is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn valid_tokens_authenticate_as_their_subject() {
+    let exp = now() + 60;
+    let claims = json!({ "sub": "alice", "exp": exp, "role": "ops" });
+    let token = hmac_token("HS256", SECRET.as_bytes(), &claims);
+    let config = JwtConfig { leeway: 5, ..hs256() };
+    let identity = Jwt::new(&config)
+        .unwrap()
+        .authenticate(&token, "127.0.0.1:40000".parse().unwrap())
+        .unwrap();
+    assert_eq!(identity.user.as_deref(), Some("alice"));
+    assert_eq!(identity.claims.unwrap()["role"], "ops");
+    assert_eq!(identity.expires, Some(UNIX_EPOCH + Duration::from_secs(exp + 5)));
+
+    let token = rsa_token(&json!({ "sub": "bob" }));
+    assert_eq!(authenticate(&rs256(), &token).unwrap().as_deref(), Some("bob"));
+}
+
+#[test]
+fn tokens_with_a_wrong_signature_are_refused() {
+    let claims = json!({ "sub": "alice" });
+    let forged = hmac_token("HS256", b"guessed", &claims);
+    assert!(authenticate(&hs256(), &forged).is_err());
+    // claims swapped under a valid signature
+    let token = hmac_token("HS256", SECRET.as_bytes(), &claims);
+    let (_, signature) = token.rsplit_once('.').unwrap();
+    let tampered = format!("{}.{}", unsigned("HS256", &json!({ "sub": "root" })), signature);
+    assert!(authenticate(&hs256(), &tampered).is_err());
+    let tampered = format!("{}.{}", unsigned("RS256", &json!({ "sub": "root" })), signature);
+    assert!(authenticate(&rs256(), &tampered).is_err());
+
+    for malformed in ["", "a.b", "a.b.c", &format!("{}.!!", unsigned("HS256", &claims))] {
+        assert!(authenticate(&hs256(), malformed).is_err(), "accepted '{}'", malformed);
+    }
+}
+
+#[test]
+fn tokens_are_only_verified_with_the_configured_algorithm() {
+    let claims = json!({ "sub": "alice" });
+    let unsecured = format!("{}.", unsigned("none", &claims));
+    assert!(authenticate(&hs256(), &unsecured).is_err());
+    assert!(authenticate(&rs256(), &unsecured).is_err());
+    let mislabeled = hmac_token("RS256", SECRET.as_bytes(), &claims);
+    assert!(authenticate(&hs256(), &mislabeled).is_err());
+    // the public key is no secret to sign with
+    let public_key = fs::read(data("jwt.pub.pem")).unwrap();
+    let confused = hmac_token("HS256", &public_key, &claims);
+    assert!(authenticate(&rs256(), &confused).is_err());
+}
+
+#[test]
+fn tokens_are_only_valid_between_nbf_and_exp() {
+    let expired = hmac_token("HS256", SECRET.as_bytes(), &json!({ "exp": now() - 30 }));
+    assert!(authenticate(&hs256(), &expired).is_err());
+    let premature = hmac_token("HS256", SECRET.as_bytes(), &json!({ "nbf": now() + 30 }));
+    assert!(authenticate(&hs256(), &premature).is_err());
+    // clocks a little apart are forgiven
+    let lenient = JwtConfig { leeway: 60, ..hs256() };
+    assert!(authenticate(&lenient, &expired).is_ok());
+    assert!(authenticate(&lenient, &premature).is_ok());
+
+    let not_a_time = hmac_token("HS256", SECRET.as_bytes(), &json!({ "exp": "tomorrow" }));
+    assert!(authenticate(&lenient, &not_a_time).is_err());
+}
+
+#[test]
+fn tokens_of_other_issuers_or_for_other_audiences_are_refused() {
+    let config = JwtConfig {
+        issuer: Some("https://sso.example.com".to_string()),
+        audience: Some("shws".to_string()),
+        ..hs256()
+    };
+    let token = |claims| hmac_token("HS256", SECRET.as_bytes(), &claims);
+    let iss = "https://sso.example.com";
+    assert!(authenticate(&config, &token(json!({ "iss": iss, "aud": "shws" }))).is_ok());
+    assert!(authenticate(&config, &token(json!({ "iss": iss, "aud": ["x", "shws"] }))).is_ok());
+
+    let other_issuer = json!({ "iss": "https://evil.example.com", "aud": "shws" });
+    assert!(authenticate(&config, &token(other_issuer)).is_err());
+    assert!(authenticate(&config, &token(json!({ "aud": "shws" }))).is_err());
+    assert!(authenticate(&config, &token(json!({ "iss": iss, "aud": "other" }))).is_err());
+    assert!(authenticate(&config, &token(json!({ "iss": iss, "aud": ["other"] }))).is_err());
+    assert!(authenticate(&config, &token(json!({ "iss": iss }))).is_err());
+}
+
+#[test]
+fn malformed_public_keys_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let rsa_oid = [
+        0x30, 0x0d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01, 0x05, 0x00,
+    ];
+    let spki = |key: &[u8]| {
+        let mut content = rsa_oid.to_vec();
+        content.extend([0x03, key.len() as u8]);
+        content.extend(key);
+        let mut spki = vec![0x30, content.len() as u8];
+        spki.extend(content);
+        spki
+    };
+    let write = |name: &str, der: &[u8]| {
+        let path = dir.path().join(name);
+        let pem = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            STANDARD.encode(der)
+        );
+        fs::write(&path, pem).unwrap();
+        JwtConfig { public_key: Some(path), ..rs256() }
+    };
+    let malformed: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![0x30],
+        // not a sequence
+        vec![0x02, 0x01, 0x00],
+        // longer than there is
+        vec![0x30, 0x7f, 0x30],
+        vec![0x30, 0x82, 0xff],
+        vec![0x30, 0x84, 0xff, 0xff, 0xff, 0xff, 0x30, 0x00],
+        // lengths of more than 4 bytes and the indefinite one aren't DER
+        vec![0x30, 0x85, 0x00, 0x00, 0x00, 0x00, 0x02],
+        vec![0x30, 0x80, 0x30, 0x00, 0x00, 0x00],
+        vec![0x30, 0x02, 0x30, 0x00],
+        // bit strings start with the unused bits
+        spki(&[]),
+        spki(&[0x07, 0x30, 0x00]),
+    ];
+    for (i, der) in malformed.iter().enumerate() {
+        let config = write(&format!("{}.pem", i), der);
+        assert!(Jwt::new(&config).is_err(), "loaded key {:02x?}", der);
+    }
+
+    // a bit string that holds no RSA key only fails once tokens are verified with it
+    let config = write("empty.pem", &spki(&[0x00, 0x30, 0x00]));
+    let token = rsa_token(&json!({ "sub": "alice" }));
+    assert!(authenticate(&config, &token).is_err());
+}
+
+fn address(address: &str) -> IpAddr {
+    address.parse().unwrap()
+}
+
+#[test]
+fn tickets_are_redeemed_once() {
+    let tickets = Tickets::new(Duration::from_secs(10));
+    let identity = Identity { user: Some("alice".to_string()), ..Identity::default() };
+    let ticket = tickets
+        .issue("s3cr3t", identity.clone(), address("192.0.2.1"))
+        .unwrap();
+    let redeemed = tickets.redeem(&ticket, address("192.0.2.1")).unwrap();
+    assert_eq!(redeemed, ("s3cr3t".to_string(), identity));
+    assert!(tickets.redeem(&ticket, address("192.0.2.1")).is_err());
+    assert!(tickets.redeem("made-up", address("192.0.2.1")).is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn tickets_expire() {
+    let tickets = Tickets::new(Duration::from_secs(10));
+    let fresh = tickets
+        .issue("s3cr3t", Identity::default(), address("192.0.2.1"))
+        .unwrap();
+    let stale = tickets
+        .issue("s3cr3t", Identity::default(), address("192.0.2.1"))
+        .unwrap();
+    time::advance(Duration::from_secs(9)).await;
+    assert!(tickets.redeem(&fresh, address("192.0.2.1")).is_ok());
+    time::advance(Duration::from_secs(1)).await;
+    assert!(tickets.redeem(&stale, address("192.0.2.1")).is_err());
+}
+
+#[test]
+fn tickets_are_refused_from_other_addresses() {
+    let tickets = Tickets::new(Duration::from_secs(10));
+    let ticket = tickets
+        .issue("s3cr3t", Identity::default(), address("192.0.2.1"))
+        .unwrap();
+    assert!(tickets.redeem(&ticket, address("192.0.2.2")).is_err());
+    // whoever tried it used it up
+    assert!(tickets.redeem(&ticket, address("192.0.2.1")).is_err());
+}
+
+/// Lockouts of 60s, then 120s, capped at 150s
+fn lockout() -> Lockout {
+    Lockout::new(LockoutConfig {
+        max_failures: 3,
+        duration: 60,
+        max_duration: 150,
+    })
+}
+
+fn fail(lockout: &Lockout, client: &Client, times: usize) {
+    let audit = AuditLog::open(&AuditConfig::default(), &RedactionConfig::default()).unwrap();
+    for _ in 0..times {
+        lockout.fail(client, &audit);
+    }
+}
+
+/// Check that `client` is locked out for `duration` from now on, and not a moment longer
+async fn assert_locked_out_for(lockout: &Lockout, client: &Client, duration: u64) {
+    assert!(lockout.check(client).is_err(), "not locked out");
+    time::advance(Duration::from_secs(duration - 1)).await;
+    assert!(lockout.check(client).is_err(), "locked out for less than {}s", duration);
+    time::advance(Duration::from_secs(1)).await;
+    assert!(lockout.check(client).is_ok(), "locked out for more than {}s", duration);
+}
+
+#[tokio::test(start_paused = true)]
+async fn lockouts_last_twice_as_long_each_time_up_to_the_maximum() {
+    let lockout = lockout();
+    let client = Client::new("192.0.2.1:4711".parse().unwrap());
+    fail(&lockout, &client, 2);
+    assert!(lockout.check(&client).is_ok());
+    fail(&lockout, &client, 1);
+    // others aren't held up by it
+    assert!(lockout
+        .check(&Client::new("192.0.2.2:4711".parse().unwrap()))
+        .is_ok());
+    assert_locked_out_for(&lockout, &client, 60).await;
+    fail(&lockout, &client, 3);
+    assert_locked_out_for(&lockout, &client, 120).await;
+    fail(&lockout, &client, 3);
+    assert_locked_out_for(&lockout, &client, 150).await;
+    fail(&lockout, &client, 3);
+    assert_locked_out_for(&lockout, &client, 150).await;
+
+    // clients that didn't fail for as long as the longest lockout start over
+    time::advance(Duration::from_secs(150)).await;
+    fail(&lockout, &client, 3);
+    assert_locked_out_for(&lockout, &client, 60).await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn authenticating_forgets_failures_but_not_lockouts() {
+    let lockout = lockout();
+    let client = Client::new("192.0.2.1:4711".parse().unwrap());
+    fail(&lockout, &client, 2);
+    lockout.succeed(&client);
+    fail(&lockout, &client, 2);
+    assert!(lockout.check(&client).is_ok());
+    fail(&lockout, &client, 1);
+    assert_locked_out_for(&lockout, &client, 60).await;
+
+    lockout.succeed(&client);
+    fail(&lockout, &client, 3);
+    assert_locked_out_for(&lockout, &client, 120).await;
+}
@@ -0,0 +1,46 @@
+//! Bringing the colors of the output down to those the terminal of a client shows
+use sh_over_ws_actuator::{
+    data::ColorDepth,
+    filter::{ColorDowngrade, OutputFilter},
+};
+
+fn downgrade(depth: ColorDepth, pieces: &[&str]) -> String {
+    let mut filter = ColorDowngrade::new(depth);
+    let mut output = Vec::new();
+    for piece in pieces {
+        output.extend(filter.filter(piece.as_bytes()));
+    }
+    output.extend(filter.finish());
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn true_colors_become_the_closest_of_256() {
+    let output = downgrade(ColorDepth::Ansi256, &["\x1b[1;38;2;255;0;0mred\x1b[0m"]);
+    assert_eq!(output, "\x1b[1;38;5;196mred\x1b[0m");
+    let output = downgrade(ColorDepth::Ansi256, &["\x1b[48:2::30:30:30m\x1b[38;5;3m"]);
+    assert_eq!(output, "\x1b[48;5;234m\x1b[38;5;3m");
+}
+
+#[test]
+fn colors_become_the_closest_of_16() {
+    let output = downgrade(ColorDepth::Ansi16, &["\x1b[38;2;255;0;0;48;5;21m"]);
+    assert_eq!(output, "\x1b[91;44m");
+    let output = downgrade(ColorDepth::Ansi16, &["\x1b[38:2::0:205:0;48;5;15m"]);
+    assert_eq!(output, "\x1b[32;107m");
+    // there are no colors of underlines to bring them down to
+    let output = downgrade(ColorDepth::Ansi16, &["\x1b[4;58:5:1m", "\x1b[58;2;1;2;3mx"]);
+    assert_eq!(output, "\x1b[4mx");
+}
+
+#[test]
+fn sequences_split_between_reads_are_brought_down_whole() {
+    let output = downgrade(ColorDepth::Ansi256, &["a\x1b", "[38;2;0;0", ";255mb"]);
+    assert_eq!(output, "a\x1b[38;5;21mb");
+}
+
+#[test]
+fn other_sequences_pass_unchanged() {
+    let other = "\x1b[m\x1b[?25h\x1b]0;title\x07\x1b(B\x1b[2J\x1b[38;2;1m\x1b";
+    assert_eq!(downgrade(ColorDepth::Ansi16, &[other]), other);
+}
@@ -0,0 +1,96 @@
+//! Working directories of sessions confined to the directory tree of a jail
+use sh_over_ws_actuator::command::{Jail, OutsideJail};
+use std::{
+    fs,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+};
+use tempfile::TempDir;
+
+/// A jail with the directory `work` and the file `file` in it, next to the directory `outside`
+/// with links to both from within the jail
+struct Fixture {
+    _dir: TempDir,
+    root: PathBuf,
+    outside: PathBuf,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let base = fs::canonicalize(dir.path()).unwrap();
+        let (root, outside) = (base.join("jail"), base.join("outside"));
+        fs::create_dir_all(root.join("work")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        fs::write(root.join("file"), "").unwrap();
+        symlink(&outside, root.join("escape")).unwrap();
+        symlink("../../outside", root.join("work/up")).unwrap();
+        symlink(root.join("work"), root.join("inside")).unwrap();
+        Fixture { _dir: dir, root, outside }
+    }
+
+    fn jail(&self, chroot: bool) -> Jail {
+        Jail { root: self.root.clone(), chroot }
+    }
+}
+
+fn is_outside(jail: &Jail, cwd: &str) -> bool {
+    match jail.resolve(Some(Path::new(cwd))) {
+        Ok(resolved) => panic!("'{}' resolved to {}", cwd, resolved.display()),
+        Err(e) => e.is::<OutsideJail>(),
+    }
+}
+
+#[test]
+fn directories_are_taken_relative_to_the_root() {
+    let fixture = Fixture::new();
+    let jail = fixture.jail(false);
+    assert_eq!(jail.resolve(None).unwrap(), fixture.root);
+    let work = fixture.root.join("work");
+    assert_eq!(jail.resolve(Some(Path::new("work"))).unwrap(), work);
+    assert_eq!(jail.resolve(Some(Path::new("work/../work/."))).unwrap(), work);
+    assert_eq!(jail.resolve(Some(&work)).unwrap(), work);
+}
+
+#[test]
+fn climbing_out_of_the_root_is_refused() {
+    let fixture = Fixture::new();
+    let jail = fixture.jail(false);
+    assert!(is_outside(&jail, ".."));
+    assert!(is_outside(&jail, "work/../../outside"));
+    assert!(is_outside(&jail, fixture.outside.to_str().unwrap()));
+    assert!(is_outside(&jail, "/"));
+    assert!(is_outside(&jail, &format!("{}/../outside", fixture.root.display())));
+}
+
+#[test]
+fn symlinks_pointing_out_of_the_root_are_refused() {
+    let fixture = Fixture::new();
+    for chroot in [false, true] {
+        let jail = fixture.jail(chroot);
+        assert!(is_outside(&jail, "escape"));
+        assert!(is_outside(&jail, "work/up"));
+        let inside = jail.resolve(Some(Path::new("inside"))).unwrap();
+        assert_eq!(inside, fixture.root.join("work"));
+    }
+}
+
+#[test]
+fn chrooted_commands_see_absolute_paths_below_the_root() {
+    let fixture = Fixture::new();
+    let jail = fixture.jail(true);
+    assert_eq!(jail.resolve(Some(Path::new("/"))).unwrap(), fixture.root);
+    let work = jail.resolve(Some(Path::new("/work"))).unwrap();
+    assert_eq!(work, fixture.root.join("work"));
+    assert!(is_outside(&jail, "/.."));
+}
+
+#[test]
+fn only_existing_directories_can_be_changed_into() {
+    let fixture = Fixture::new();
+    let jail = fixture.jail(false);
+    assert!(!is_outside(&jail, "file"));
+    assert!(!is_outside(&jail, "missing"));
+    let gone = Jail { root: fixture.root.join("missing"), chroot: false };
+    assert!(gone.resolve(None).is_err());
+}
@@ -0,0 +1,1081 @@
+use sh_over_ws_actuator::{
+    command::RunCommand,
+    data::{
+        AttachRole, AttachedClient, Blob, Capability, ChannelId, CloseReason, ColorDepth,
+        Compression, Encoding, ErrorDetail, ExitReason, ExitSignal, IdleAction, JobRecord,
+        LimitScope, LineMode, Message, Parity, Payload, Resource, SerialSettings, SessionId,
+        SignalSpec, StdStream, TermMode, TerminalInfo, TransferId, WindowSize, CAPABILITIES,
+        MAX_DECOMPRESSED_LEN, PROTOCOL_VERSION,
+    },
+    error::{ErrorCode, ProtocolError},
+};
+use proptest::prelude::*;
+use std::path::PathBuf;
+
+fn session() -> SessionId {
+    "7d4f0c1e-2f7a-4a55-9b1e-0c0f6a3c9d21".parse().unwrap()
+}
+
+fn transfer() -> TransferId {
+    "3b9e61d2-8c4f-4b0a-a7d5-5e2f1c6b9a40".parse().unwrap()
+}
+
+fn channel() -> ChannelId {
+    "c5a0e3f4-1d2b-4e6f-9a8c-7b3d5f1e2a60".parse().unwrap()
+}
+
+fn assert_round_trip(message: Message) {
+    let json = serde_json::to_string(&message).unwrap();
+    let decoded: Message = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, message, "round trip through {}", json);
+}
+
+fn all_variants() -> Vec<Message> {
+    vec![
+        Message::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.to_vec(),
+        },
+        Message::Open {
+            session: session(),
+            command: None,
+            profile: None,
+            size: None,
+            terminal: None,
+            cwd: None,
+            env: Default::default(),
+            clear_env: false,
+            strip_env: vec![],
+            timeout: None,
+            raw_output: false,
+            strip_ansi: false,
+            clipboard: false,
+            meta: false,
+            strip_meta: false,
+        },
+        Message::Open {
+            session: session(),
+            command: Some(RunCommand {
+                command: PathBuf::from("/bin/echo"),
+                args: vec!["hi".to_string()],
+                cwd: Some(PathBuf::from("/tmp")),
+                ..Default::default()
+            }),
+            profile: None,
+            size: Some(WindowSize {
+                cols: 80,
+                rows: 24,
+                width_in_pixels: None,
+                height_in_pixels: None,
+            }),
+            terminal: Some(TerminalInfo {
+                term: "xterm-kitty".to_string(),
+                colors: Some(ColorDepth::TrueColor),
+            }),
+            cwd: Some(PathBuf::from("projects")),
+            env: [("TERM".to_string(), "xterm-256color".to_string())].into(),
+            clear_env: true,
+            strip_env: vec!["SSH_AUTH_SOCK".to_string()],
+            timeout: Some(30),
+            raw_output: true,
+            strip_ansi: false,
+            clipboard: false,
+            meta: false,
+            strip_meta: false,
+        },
+        Message::Open {
+            session: session(),
+            command: None,
+            profile: Some("python-repl".to_string()),
+            size: None,
+            terminal: None,
+            cwd: None,
+            env: Default::default(),
+            clear_env: false,
+            strip_env: vec![],
+            timeout: None,
+            raw_output: false,
+            strip_ansi: false,
+            clipboard: true,
+            meta: true,
+            strip_meta: true,
+        },
+        Message::SerialOpen {
+            session: session(),
+            device: "/dev/ttyUSB0".into(),
+            settings: SerialSettings::default(),
+            raw_output: false,
+        },
+        Message::SerialOpen {
+            session: session(),
+            device: "/dev/serial/by-id/usb-FTDI_FT232R-if00".into(),
+            settings: SerialSettings {
+                baud_rate: 9600,
+                data_bits: 7,
+                parity: Parity::Even,
+                stop_bits: 2,
+            },
+            raw_output: true,
+        },
+        Message::CloneSession {
+            session: session(),
+            from: "0b9e6c2a-5d41-4f3e-8a7b-2c1d9e8f4a3b".parse().unwrap(),
+            size: Some(WindowSize {
+                cols: 132,
+                rows: 50,
+                width_in_pixels: Some(1320),
+                height_in_pixels: Some(1000),
+            }),
+        },
+        Message::CloneSession {
+            session: session(),
+            from: session(),
+            size: None,
+        },
+        Message::Opened {
+            session: session(),
+            recording: true,
+            recording_input: true,
+            role: AttachRole::Writer,
+        },
+        Message::Opened {
+            session: session(),
+            recording: false,
+            recording_input: false,
+            role: AttachRole::Viewer,
+        },
+        Message::Attach {
+            session: session(),
+            role: AttachRole::Writer,
+        },
+        Message::Attach {
+            session: session(),
+            role: AttachRole::Viewer,
+        },
+        Message::ResumeFrom {
+            session: session(),
+            seq: Some(4096),
+            role: AttachRole::Writer,
+        },
+        Message::ResumeFrom {
+            session: session(),
+            seq: None,
+            role: AttachRole::Viewer,
+        },
+        Message::TakeControl { session: session() },
+        Message::RoleChanged {
+            session: session(),
+            role: AttachRole::Viewer,
+        },
+        Message::ListAttachedClients { session: session() },
+        Message::AttachedClients {
+            session: session(),
+            clients: vec![
+                AttachedClient {
+                    address: "192.0.2.7:41234".parse().unwrap(),
+                    identity: "sha256:9f86d081884c7d65".to_string(),
+                    role: AttachRole::Writer,
+                },
+                AttachedClient {
+                    address: "[2001:db8::1]:52000".parse().unwrap(),
+                    identity: "2001:db8::1".to_string(),
+                    role: AttachRole::Viewer,
+                },
+            ],
+        },
+        Message::ListJobs {
+            limit: Some(20),
+            since: Some("2026-10-01T00:00:00Z".to_string()),
+        },
+        Message::ListJobs {
+            limit: None,
+            since: None,
+        },
+        Message::JobList {
+            jobs: vec![JobRecord {
+                session: session(),
+                command: "/usr/bin/backup --full".to_string(),
+                submitter: "ops".to_string(),
+                started_at: "2026-10-14T02:00:00.000Z".to_string(),
+                ended_at: "2026-10-14T02:13:37.250Z".to_string(),
+                code: Some(0),
+                signal: None,
+                reason: None,
+                output: None,
+            }],
+        },
+        Message::FetchJob { session: session() },
+        Message::JobDetails {
+            job: JobRecord {
+                session: session(),
+                command: "/usr/bin/backup --full".to_string(),
+                submitter: "ops".to_string(),
+                started_at: "2026-10-14T02:00:00.000Z".to_string(),
+                ended_at: "2026-10-14T02:00:05.000Z".to_string(),
+                code: None,
+                signal: Some(ExitSignal {
+                    number: 9,
+                    name: Some("SIGKILL".to_string()),
+                    core_dumped: false,
+                }),
+                reason: Some(ExitReason::Killed),
+                output: Some(Payload::from(b"copying /var/lib\n".to_vec())),
+            },
+        },
+        Message::SnapshotEnvironment { session: session() },
+        Message::EnvironmentSnapshot {
+            session: session(),
+            env: [
+                ("HOME".to_string(), "/home/shws".to_string()),
+                ("TERM".to_string(), "xterm-256color".to_string()),
+            ]
+            .into(),
+        },
+        Message::Detach { session: session() },
+        Message::Detached { session: session() },
+        Message::Input {
+            session: session(),
+            data: Payload::from("ls -l\r"),
+        },
+        Message::BroadcastInput {
+            sessions: vec![
+                session(),
+                "0b9e6c2a-5d41-4f3e-8a7b-2c1d9e8f4a3b".parse().unwrap(),
+            ],
+            data: Payload::from("uptime\r"),
+        },
+        Message::Run {
+            session: session(),
+            program: PathBuf::from("make"),
+            args: vec!["-j4".to_string()],
+            env: [("CC".to_string(), "clang".to_string())].into(),
+            clear_env: false,
+            strip_env: vec![],
+            cwd: None,
+            merge_stderr: false,
+            timeout: None,
+            raw_output: false,
+            strip_ansi: false,
+            start_at: None,
+            delay: None,
+        },
+        Message::Run {
+            session: session(),
+            program: PathBuf::from("cargo"),
+            args: vec!["build".to_string()],
+            env: Default::default(),
+            clear_env: true,
+            strip_env: vec![],
+            cwd: Some(PathBuf::from("projects/shws")),
+            merge_stderr: true,
+            timeout: Some(600),
+            raw_output: false,
+            strip_ansi: true,
+            start_at: None,
+            delay: Some(90),
+        },
+        Message::Run {
+            session: session(),
+            program: PathBuf::from("/usr/bin/backup"),
+            args: vec![],
+            env: Default::default(),
+            clear_env: false,
+            strip_env: vec![],
+            cwd: None,
+            merge_stderr: false,
+            timeout: None,
+            raw_output: false,
+            strip_ansi: false,
+            start_at: Some("2026-10-15T02:00:00Z".to_string()),
+            delay: None,
+        },
+        Message::Scheduled {
+            session: session(),
+            at: "2026-10-15T02:00:00Z".to_string(),
+        },
+        Message::Queued {
+            session: session(),
+            position: 3,
+        },
+        Message::Started { session: session() },
+        Message::CancelScheduled { session: session() },
+        Message::Output {
+            session: session(),
+            data: Payload::from("\u{1b}[1mtotal 0\u{1b}[0m\r\n"),
+            stream: None,
+            seq: 23,
+        },
+        Message::Output {
+            session: session(),
+            data: Payload::from("warning: unused variable\n"),
+            stream: Some(StdStream::Stderr),
+            seq: 48,
+        },
+        Message::Ack {
+            session: session(),
+            seq: 48,
+        },
+        Message::Paused { session: session() },
+        Message::Resumed { session: session() },
+        Message::Idle {
+            session: session(),
+            action: IdleAction::Suspend,
+            seconds: 60,
+        },
+        Message::Idle {
+            session: session(),
+            action: IdleAction::Close,
+            seconds: 0,
+        },
+        Message::Resize {
+            session: session(),
+            size: WindowSize {
+                cols: 120,
+                rows: 40,
+                width_in_pixels: Some(1200),
+                height_in_pixels: Some(800),
+            },
+        },
+        Message::SetTermMode {
+            session: session(),
+            mode: Some(TermMode::Raw),
+            echo: None,
+            isig: None,
+            icrnl: None,
+        },
+        Message::SetTermMode {
+            session: session(),
+            mode: None,
+            echo: Some(false),
+            isig: Some(true),
+            icrnl: Some(false),
+        },
+        Message::SetLineMode {
+            session: session(),
+            mode: LineMode::Server,
+        },
+        Message::SessionMeta {
+            session: session(),
+            title: Some("vim README.md".to_string()),
+            cwd: Some(PathBuf::from("/home/ada/projects")),
+        },
+        Message::SessionMeta {
+            session: session(),
+            title: None,
+            cwd: Some(PathBuf::from("/tmp")),
+        },
+        Message::ClipboardSet {
+            session: session(),
+            data: Payload::from(b"copied".to_vec()),
+        },
+        Message::ClipboardGet { session: session() },
+        Message::ClipboardContent {
+            session: session(),
+            data: Payload::from(b"pasted".to_vec()),
+        },
+        Message::Signal {
+            session: session(),
+            signal: SignalSpec::Number(2),
+        },
+        Message::Signal {
+            session: session(),
+            signal: SignalSpec::Name("SIGTERM".to_string()),
+        },
+        Message::Close { session: session() },
+        Message::Exit {
+            session: session(),
+            code: Some(0),
+            signal: None,
+            reason: None,
+        },
+        Message::Exit {
+            session: session(),
+            code: None,
+            signal: Some(ExitSignal {
+                number: 11,
+                name: Some("SIGSEGV".to_string()),
+                core_dumped: true,
+            }),
+            reason: None,
+        },
+        Message::Exit {
+            session: session(),
+            code: None,
+            signal: Some(ExitSignal {
+                number: 24,
+                name: None,
+                core_dumped: false,
+            }),
+            reason: Some(ExitReason::ResourceExceeded {
+                resource: Resource::CpuTime,
+            }),
+        },
+        Message::Exit {
+            session: session(),
+            code: None,
+            signal: Some(ExitSignal {
+                number: 15,
+                name: Some("SIGTERM".to_string()),
+                core_dumped: false,
+            }),
+            reason: Some(ExitReason::TimedOut { timeout: 30 }),
+        },
+        Message::Exit {
+            session: session(),
+            code: None,
+            signal: None,
+            reason: Some(ExitReason::Idle { timeout: 3600 }),
+        },
+        Message::Exit {
+            session: session(),
+            code: None,
+            signal: None,
+            reason: Some(ExitReason::Cancelled),
+        },
+        Message::Error {
+            session: Some(session()),
+            error: ProtocolError {
+                code: ErrorCode::SessionNotFound,
+                message: "no such session".to_string(),
+                detail: None,
+            },
+        },
+        Message::Error {
+            session: None,
+            error: ProtocolError {
+                code: ErrorCode::BadRequest,
+                message: "invalid message".to_string(),
+                detail: None,
+            },
+        },
+        Message::Error {
+            session: Some(session()),
+            error: ProtocolError {
+                code: ErrorCode::PolicyViolation,
+                message: "command '/usr/bin/sudo' is denied by rule '/usr/bin/su*'".to_string(),
+                detail: Some(ErrorDetail::PolicyViolation {
+                    command: "/usr/bin/sudo".into(),
+                    rule: Some("/usr/bin/su*".to_string()),
+                }),
+            },
+        },
+        Message::Error {
+            session: Some(session()),
+            error: ProtocolError {
+                code: ErrorCode::PolicyViolation,
+                message: "'../../etc' is outside of the jail".to_string(),
+                detail: Some(ErrorDetail::OutsideJail {
+                    path: "../../etc".into(),
+                }),
+            },
+        },
+        Message::Error {
+            session: Some(session()),
+            error: ProtocolError {
+                code: ErrorCode::LimitExceeded,
+                message: "no more than 4 sessions allowed per client".to_string(),
+                detail: Some(ErrorDetail::TooManySessions {
+                    limit: 4,
+                    scope: LimitScope::Client,
+                }),
+            },
+        },
+        Message::Error {
+            session: Some(session()),
+            error: ProtocolError {
+                code: ErrorCode::LimitExceeded,
+                message: "input messages may carry no more than 65536 bytes".to_string(),
+                detail: Some(ErrorDetail::InputTooLarge { limit: 65536 }),
+            },
+        },
+        Message::Error {
+            session: Some(session()),
+            error: ProtocolError {
+                code: ErrorCode::RateLimited,
+                message: "input may arrive no faster than 4096 bytes per second".to_string(),
+                detail: Some(ErrorDetail::InputTooFast {
+                    bytes_per_second: 4096,
+                }),
+            },
+        },
+        Message::Error {
+            session: Some(session()),
+            error: ProtocolError {
+                code: ErrorCode::BadRequest,
+                message: "no profile named 'busybox'".to_string(),
+                detail: Some(ErrorDetail::UnknownProfile {
+                    profile: "busybox".to_string(),
+                }),
+            },
+        },
+        Message::Ping { nonce: 42 },
+        Message::Pong { nonce: 42 },
+        Message::FileUploadStart {
+            transfer: transfer(),
+            path: "uploads/build.tar".into(),
+            size: 4,
+            mode: Some(0o600),
+        },
+        Message::FileUploadChunk {
+            transfer: transfer(),
+            data: Blob(vec![b'a', 0xff, 0x00, b'b']),
+        },
+        Message::FileUploadEnd {
+            transfer: transfer(),
+            sha256: "6b86b273ff34fce19d6b804eff5a3f5747ada4eaa22f1d49c01e52ddb7875b4b".to_string(),
+        },
+        Message::FileUploaded {
+            transfer: transfer(),
+        },
+        Message::FileDownloadRequest {
+            transfer: transfer(),
+            path: "logs/build.log".into(),
+        },
+        Message::FileDownloadStart {
+            transfer: transfer(),
+            size: 0,
+        },
+        Message::FileDownloadChunk {
+            transfer: transfer(),
+            data: Blob(vec![]),
+        },
+        Message::FileDownloadEnd {
+            transfer: transfer(),
+            sha256: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string(),
+        },
+        Message::TransferFailed {
+            transfer: transfer(),
+            error: ProtocolError {
+                code: ErrorCode::LimitExceeded,
+                message: "files may be no larger than 1024 bytes".to_string(),
+                detail: Some(ErrorDetail::FileTooLarge { limit: 1024 }),
+            },
+        },
+        Message::TransferFailed {
+            transfer: transfer(),
+            error: ProtocolError {
+                code: ErrorCode::PolicyViolation,
+                message: "'../etc/passwd' is outside of the file root".to_string(),
+                detail: Some(ErrorDetail::OutsideFileRoot {
+                    path: "../etc/passwd".into(),
+                }),
+            },
+        },
+        Message::TransferFailed {
+            transfer: transfer(),
+            error: ProtocolError {
+                code: ErrorCode::ChecksumMismatch,
+                message: "checksum mismatch".to_string(),
+                detail: Some(ErrorDetail::ChecksumMismatch {
+                    expected: "00".to_string(),
+                    actual: "ff".to_string(),
+                }),
+            },
+        },
+        Message::TcpForward {
+            channel: channel(),
+            host: "localhost".to_string(),
+            port: 9229,
+        },
+        Message::ForwardOpened { channel: channel() },
+        Message::ForwardData {
+            channel: channel(),
+            data: Blob(vec![0, 1, 2, 255]),
+        },
+        Message::ForwardClose { channel: channel() },
+        Message::ForwardFailed {
+            channel: channel(),
+            error: ProtocolError {
+                code: ErrorCode::Other,
+                message: "failed to forward to localhost:22: forwarding to localhost:22 is not \
+                          allowed"
+                    .to_string(),
+                detail: None,
+            },
+        },
+    ]
+}
+
+#[test]
+fn every_variant_round_trips() {
+    for message in all_variants() {
+        assert_round_trip(message);
+    }
+}
+
+#[test]
+fn messages_are_tagged_by_type() {
+    let json = serde_json::to_value(Message::Close { session: session() }).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({ "type": "close", "session": session().to_string() })
+    );
+}
+
+#[test]
+fn optional_fields_may_be_omitted() {
+    let decoded: Message = serde_json::from_str(r#"{"type":"hello","version":1}"#).unwrap();
+    assert_eq!(
+        decoded,
+        Message::Hello {
+            version: 1,
+            capabilities: vec![],
+        }
+    );
+    let decoded: Message =
+        serde_json::from_str(&format!(r#"{{"type":"open","session":"{}"}}"#, session())).unwrap();
+    assert_eq!(
+        decoded,
+        Message::Open {
+            session: session(),
+            command: None,
+            profile: None,
+            size: None,
+            terminal: None,
+            cwd: None,
+            env: Default::default(),
+            clear_env: false,
+            strip_env: vec![],
+            timeout: None,
+            raw_output: false,
+            strip_ansi: false,
+            clipboard: false,
+            meta: false,
+            strip_meta: false,
+        }
+    );
+    let decoded: Message = serde_json::from_str(&format!(
+        r#"{{"type":"resize","session":"{}","cols":100,"rows":30}}"#,
+        session()
+    ))
+    .unwrap();
+    assert_eq!(
+        decoded,
+        Message::Resize {
+            session: session(),
+            size: WindowSize {
+                cols: 100,
+                rows: 30,
+                width_in_pixels: None,
+                height_in_pixels: None,
+            },
+        }
+    );
+    let decoded: Message = serde_json::from_str(&format!(
+        r#"{{"type":"serial_open","session":"{}","device":"/dev/ttyACM0"}}"#,
+        session()
+    ))
+    .unwrap();
+    assert_eq!(
+        decoded,
+        Message::SerialOpen {
+            session: session(),
+            device: "/dev/ttyACM0".into(),
+            settings: SerialSettings {
+                baud_rate: 115200,
+                data_bits: 8,
+                parity: Parity::None,
+                stop_bits: 1,
+            },
+            raw_output: false,
+        }
+    );
+    let decoded: Message = serde_json::from_str(r#"{"type":"error","message":"oops"}"#).unwrap();
+    assert_eq!(
+        decoded,
+        Message::Error {
+            session: None,
+            error: ProtocolError {
+                code: ErrorCode::Other,
+                message: "oops".to_string(),
+                detail: None,
+            },
+        }
+    );
+}
+
+#[test]
+fn errors_carry_their_code_next_to_the_message() {
+    let message = Message::Error {
+        session: None,
+        error: ProtocolError::new(ErrorCode::SpawnFailed, "failed to execute 'nope'"),
+    };
+    assert_eq!(
+        serde_json::to_value(&message).unwrap(),
+        serde_json::json!({
+            "type": "error",
+            "session": null,
+            "code": "spawn_failed",
+            "message": "failed to execute 'nope'",
+        })
+    );
+    // codes added by newer servers
+    let decoded: Message =
+        serde_json::from_str(r#"{"type":"error","code":"out_of_luck","message":"oops"}"#).unwrap();
+    let Message::Error { error, .. } = decoded else {
+        panic!("not an error: {:?}", decoded);
+    };
+    assert_eq!(error.code, ErrorCode::Other);
+}
+
+#[test]
+fn terminal_colors_are_named_by_their_count() {
+    let terminal: TerminalInfo =
+        serde_json::from_str(r#"{"term":"xterm","colors":"256"}"#).unwrap();
+    assert_eq!(terminal.colors, Some(ColorDepth::Ansi256));
+    let terminal = TerminalInfo {
+        term: "xterm-kitty".to_string(),
+        colors: Some(ColorDepth::TrueColor),
+    };
+    assert_eq!(
+        serde_json::to_value(&terminal).unwrap(),
+        serde_json::json!({ "term": "xterm-kitty", "colors": "truecolor" })
+    );
+}
+
+#[test]
+fn capabilities_of_newer_peers_are_kept_as_unknown() {
+    let decoded: Message = serde_json::from_str(
+        r#"{"type":"hello","version":1,"capabilities":["msgpack","teleport","resume"]}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        decoded,
+        Message::Hello {
+            version: 1,
+            capabilities: vec![Capability::MsgPack, Capability::Unknown, Capability::Resume],
+        }
+    );
+}
+
+#[test]
+fn close_reasons_have_distinct_application_codes() {
+    let mut codes = vec![];
+    for reason in CloseReason::ALL {
+        let code = reason.code();
+        assert!(
+            (4000..5000).contains(&code),
+            "{:?} has code {}",
+            reason,
+            code
+        );
+        assert_eq!(CloseReason::from_code(code), Some(reason));
+        codes.push(code);
+    }
+    codes.sort();
+    codes.dedup();
+    assert_eq!(codes.len(), CloseReason::ALL.len());
+    assert_eq!(CloseReason::from_code(4999), None);
+    assert_eq!(CloseReason::from_code(1000), None);
+}
+
+#[test]
+fn every_variant_round_trips_through_msgpack() {
+    for message in all_variants() {
+        let bytes = Encoding::MsgPack.encode(&message).unwrap();
+        assert_eq!(Encoding::MsgPack.decode(&bytes).unwrap(), message);
+    }
+}
+
+#[test]
+fn payloads_keep_invalid_utf8_in_msgpack() {
+    let message = Message::Output {
+        session: session(),
+        data: Payload::from(vec![b'a', 0xff, 0xfe, b'b']),
+        stream: None,
+        seq: 4,
+    };
+    let bytes = Encoding::MsgPack.encode(&message).unwrap();
+    assert_eq!(Encoding::MsgPack.decode(&bytes).unwrap(), message);
+
+    // JSON can only carry strings and replaces what isn't UTF-8
+    let bytes = Encoding::Json.encode(&message).unwrap();
+    assert_eq!(
+        Encoding::Json.decode(&bytes).unwrap(),
+        Message::Output {
+            session: session(),
+            data: Payload::from("a\u{fffd}\u{fffd}b"),
+            stream: None,
+            seq: 4,
+        }
+    );
+}
+
+#[test]
+fn blobs_keep_any_bytes_in_json() {
+    let message = Message::FileDownloadChunk {
+        transfer: transfer(),
+        data: Blob(vec![b'a', 0xff, 0xfe, 0x00, b'b']),
+    };
+    let bytes = Encoding::Json.encode(&message).unwrap();
+    assert!(String::from_utf8(bytes.clone())
+        .unwrap()
+        .contains(r#""data":"Yf/+AGI=""#));
+    assert_eq!(Encoding::Json.decode(&bytes).unwrap(), message);
+}
+
+#[test]
+fn encodings_parse_from_their_names() {
+    assert_eq!("json".parse::<Encoding>(), Ok(Encoding::Json));
+    assert_eq!("msgpack".parse::<Encoding>(), Ok(Encoding::MsgPack));
+    assert!("cbor".parse::<Encoding>().is_err());
+}
+
+#[test]
+fn every_variant_round_trips_through_zstd() {
+    for message in all_variants() {
+        let bytes = Encoding::MsgPack.encode(&message).unwrap();
+        let compressed = Compression::Zstd.compress(bytes).unwrap();
+        let decompressed = Compression::Zstd.decompress(compressed).unwrap();
+        assert_eq!(Encoding::MsgPack.decode(&decompressed).unwrap(), message);
+    }
+    assert_eq!("zstd".parse::<Compression>(), Ok(Compression::Zstd));
+    assert!(Compression::Zstd.decompress(b"not zstd".to_vec()).is_err());
+}
+
+/// Bytes of output the server puts into one frame at most
+const MAX_FRAME_SIZE: usize = 65536;
+
+fn any_id() -> impl Strategy<Value = SessionId> {
+    any::<u128>().prop_map(SessionId::from_u128)
+}
+
+/// Up to a full frame of bytes, most of them short and any of them invalid UTF-8
+fn any_bytes() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        4 => prop::collection::vec(any::<u8>(), 0..256),
+        1 => prop::collection::vec(any::<u8>(), 0..=MAX_FRAME_SIZE),
+        1 => Just(vec![0xff; MAX_FRAME_SIZE]),
+    ]
+}
+
+/// One of the messages carrying a [`Payload`], picked by `kind`
+fn with_payload(kind: u8, session: SessionId, data: Payload, seq: u64) -> Message {
+    match kind {
+        0 => Message::Input { session, data },
+        1 => Message::BroadcastInput {
+            sessions: vec![session, self::session()],
+            data,
+        },
+        2 => Message::Output {
+            session,
+            data,
+            stream: None,
+            seq,
+        },
+        3 => Message::Output {
+            session,
+            data,
+            stream: Some(StdStream::Stderr),
+            seq,
+        },
+        4 => Message::ClipboardSet { session, data },
+        _ => Message::ClipboardContent { session, data },
+    }
+}
+
+/// Messages carrying a [`Blob`]
+fn any_blob_message() -> impl Strategy<Value = Message> {
+    (0..3u8, any_id(), any_bytes()).prop_map(|(kind, id, bytes)| match kind {
+        0 => Message::FileUploadChunk {
+            transfer: id,
+            data: Blob(bytes),
+        },
+        1 => Message::FileDownloadChunk {
+            transfer: id,
+            data: Blob(bytes),
+        },
+        _ => Message::ForwardData {
+            channel: id,
+            data: Blob(bytes),
+        },
+    })
+}
+
+/// Messages carrying text, numbers and sizes the client or the command made up
+fn any_other_message() -> impl Strategy<Value = Message> {
+    let text = || any::<String>();
+    let size = || {
+        (any::<u16>(), any::<u16>(), any::<Option<u16>>(), any::<Option<u16>>()).prop_map(
+            |(cols, rows, width_in_pixels, height_in_pixels)| WindowSize {
+                cols,
+                rows,
+                width_in_pixels,
+                height_in_pixels,
+            },
+        )
+    };
+    let terminal = || {
+        let colors = vec![ColorDepth::Ansi16, ColorDepth::Ansi256, ColorDepth::TrueColor];
+        (text(), prop::option::of(prop::sample::select(colors)))
+            .prop_map(|(term, colors)| TerminalInfo { term, colors })
+    };
+    prop_oneof![
+        (any::<u32>(), prop::collection::vec(prop::sample::select(CAPABILITIES), 0..4))
+            .prop_map(|(version, capabilities)| Message::Hello {
+                version,
+                capabilities,
+            }),
+        (
+            any_id(),
+            prop::option::of(text()),
+            prop::option::of(size()),
+            prop::option::of(terminal()),
+            prop::option::of(text()),
+            prop::collection::btree_map(text(), text(), 0..4),
+            prop::collection::vec(text(), 0..4),
+            any::<Option<u64>>(),
+            any::<[bool; 6]>(),
+        )
+            .prop_map(
+                |(session, profile, size, terminal, cwd, env, strip_env, timeout, flags)| {
+                    Message::Open {
+                        session,
+                        command: None,
+                        profile,
+                        size,
+                        terminal,
+                        cwd: cwd.map(PathBuf::from),
+                        env,
+                        clear_env: flags[0],
+                        strip_env,
+                        timeout,
+                        raw_output: flags[1],
+                        strip_ansi: flags[2],
+                        clipboard: flags[3],
+                        meta: flags[4],
+                        strip_meta: flags[5],
+                    }
+                }
+            ),
+        (
+            any_id(),
+            text(),
+            prop::collection::vec(text(), 0..4),
+            any::<Option<u64>>(),
+            any::<Option<u64>>(),
+        )
+            .prop_map(|(session, program, args, timeout, delay)| Message::Run {
+                session,
+                program: program.into(),
+                args,
+                env: Default::default(),
+                clear_env: false,
+                strip_env: vec![],
+                cwd: None,
+                merge_stderr: true,
+                timeout,
+                raw_output: false,
+                strip_ansi: false,
+                start_at: None,
+                delay,
+            }),
+        (any_id(), any::<u64>()).prop_map(|(session, seq)| Message::Ack { session, seq }),
+        (any_id(), size()).prop_map(|(session, size)| Message::Resize { session, size }),
+        (any_id(), prop::option::of(text()), prop::option::of(text())).prop_map(
+            |(session, title, cwd)| Message::SessionMeta {
+                session,
+                title,
+                cwd: cwd.map(PathBuf::from),
+            }
+        ),
+        (any_id(), any::<Option<i32>>(), any::<Option<(i32, bool)>>()).prop_map(
+            |(session, code, signal)| Message::Exit {
+                session,
+                code,
+                signal: signal.map(|(number, core_dumped)| ExitSignal {
+                    number,
+                    name: None,
+                    core_dumped,
+                }),
+                reason: None,
+            }
+        ),
+        (prop::option::of(any_id()), text()).prop_map(|(session, message)| Message::Error {
+            session,
+            error: ProtocolError::new(ErrorCode::Other, message),
+        }),
+        (any_id(), text(), any::<u16>()).prop_map(|(channel, host, port)| {
+            Message::TcpForward {
+                channel,
+                host,
+                port,
+            }
+        }),
+        any::<u64>().prop_map(|nonce| Message::Ping { nonce }),
+        any::<u64>().prop_map(|nonce| Message::Pong { nonce }),
+    ]
+}
+
+/// Any message: the handpicked ones of [`all_variants`] or made up ones
+fn any_message() -> impl Strategy<Value = Message> {
+    prop_oneof![
+        prop::sample::select(all_variants()),
+        (0..6u8, any_id(), any_bytes(), any::<u64>()).prop_map(|(kind, session, bytes, seq)| {
+            with_payload(kind, session, Payload::from(bytes), seq)
+        }),
+        any_blob_message(),
+        any_other_message(),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn any_message_round_trips_through_msgpack(message in any_message()) {
+        let bytes = Encoding::MsgPack.encode(&message).unwrap();
+        prop_assert_eq!(Encoding::MsgPack.decode(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn any_message_round_trips_through_zstd(message in any_message()) {
+        let bytes = Compression::Zstd.compress(Encoding::MsgPack.encode(&message).unwrap());
+        let decompressed = Compression::Zstd.decompress(bytes.unwrap()).unwrap();
+        prop_assert_eq!(Encoding::MsgPack.decode(&decompressed).unwrap(), message);
+    }
+
+    #[test]
+    fn messages_without_payloads_round_trip_through_json(
+        message in prop_oneof![any_blob_message(), any_other_message()],
+    ) {
+        let bytes = Encoding::Json.encode(&message).unwrap();
+        prop_assert_eq!(Encoding::Json.decode(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn payloads_lose_only_invalid_utf8_in_json(
+        kind in 0..6u8,
+        session in any_id(),
+        bytes in any_bytes(),
+        seq in any::<u64>(),
+    ) {
+        let message = with_payload(kind, session, Payload::from(bytes.clone()), seq);
+        let decoded = Encoding::Json.decode(&Encoding::Json.encode(&message).unwrap()).unwrap();
+        let lossy = Payload::from(String::from_utf8_lossy(&bytes).as_ref());
+        prop_assert_eq!(&decoded, &with_payload(kind, session, lossy, seq));
+        if std::str::from_utf8(&bytes).is_ok() {
+            prop_assert_eq!(decoded, message);
+        }
+    }
+}
+
+#[test]
+fn frames_up_to_the_decompression_limit_round_trip() {
+    let output = |len: usize| Message::Output {
+        session: session(),
+        data: Payload::from((0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>()),
+        stream: None,
+        seq: u64::MAX,
+    };
+    // the length of the payload is encoded in the same number of bytes either way
+    let overhead = Encoding::MsgPack.encode(&output(1 << 20)).unwrap().len() - (1 << 20);
+    let message = output(MAX_DECOMPRESSED_LEN - overhead);
+    let bytes = Encoding::MsgPack.encode(&message).unwrap();
+    assert_eq!(bytes.len(), MAX_DECOMPRESSED_LEN);
+    let compressed = Compression::Zstd.compress(bytes).unwrap();
+    let decompressed = Compression::Zstd.decompress(compressed).unwrap();
+    assert_eq!(Encoding::MsgPack.decode(&decompressed).unwrap(), message);
+
+    let bytes = Encoding::MsgPack.encode(&output(MAX_DECOMPRESSED_LEN - overhead + 1)).unwrap();
+    let compressed = Compression::Zstd.compress(bytes).unwrap();
+    assert!(Compression::Zstd.decompress(compressed).is_err());
+}
@@ -0,0 +1,172 @@
+//! Addresses of clients behind proxies: the PROXY protocol header load balancers start
+//! connections with, and the hops of `Forwarded` and `X-Forwarded-For`
+use hyper::{header::HeaderValue, HeaderMap};
+use sh_over_ws_actuator::proxy::{parse_network, read_header, ProxyConfig};
+use std::net::SocketAddr;
+
+/// Read the header from the start of `bytes`, returning what it tells and what follows it
+async fn header(bytes: &[u8]) -> (anyhow::Result<Option<SocketAddr>>, &[u8]) {
+    let mut rest = bytes;
+    let address = read_header(&mut rest).await;
+    (address, rest)
+}
+
+/// A header of version 2 with `command`, `family` and `addresses`
+fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    header.extend([0x20 | command, family]);
+    header.extend((addresses.len() as u16).to_be_bytes());
+    header.extend(addresses);
+    header
+}
+
+#[tokio::test]
+async fn version_1_tells_the_client_and_leaves_what_follows() {
+    let (address, rest) = header(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /").await;
+    assert_eq!(address.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+    assert_eq!(rest, b"GET /");
+    let (address, rest) = header(b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\n").await;
+    assert_eq!(address.unwrap(), Some("[2001:db8::1]:4711".parse().unwrap()));
+    assert!(rest.is_empty());
+    let (address, rest) = header(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\nGET /").await;
+    assert_eq!(address.unwrap(), None);
+    assert_eq!(rest, b"GET /");
+}
+
+#[tokio::test]
+async fn version_2_tells_the_client_and_leaves_what_follows() {
+    let inet = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+    let mut bytes = v2(1, 0x11, &inet);
+    bytes.extend(b"GET /");
+    let (address, rest) = header(&bytes).await;
+    assert_eq!(address.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+    assert_eq!(rest, b"GET /");
+
+    let mut inet6 = [0; 36];
+    inet6[..2].copy_from_slice(&[0x20, 0x01]);
+    inet6[15] = 1;
+    inet6[32..].copy_from_slice(&[0x12, 0x67, 0x01, 0xbb]);
+    let bytes = v2(1, 0x21, &inet6);
+    let (address, rest) = header(&bytes).await;
+    assert_eq!(address.unwrap(), Some("[2001::1]:4711".parse().unwrap()));
+    assert!(rest.is_empty());
+
+    // TLVs after the addresses are part of the header
+    let mut with_tlvs = inet.to_vec();
+    with_tlvs.extend([0x04, 0x00, 0x01, 0xff]);
+    let mut bytes = v2(1, 0x11, &with_tlvs);
+    bytes.extend(b"GET /");
+    let (address, rest) = header(&bytes).await;
+    assert_eq!(address.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+    assert_eq!(rest, b"GET /");
+}
+
+#[tokio::test]
+async fn health_checks_of_the_load_balancer_tell_no_client() {
+    let mut bytes = v2(0, 0x00, &[]);
+    bytes.extend(b"GET /");
+    let (address, rest) = header(&bytes).await;
+    assert_eq!(address.unwrap(), None);
+    assert_eq!(rest, b"GET /");
+    // LOCAL ignores the addresses even if there are some
+    let inet = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+    let (address, _) = header(&v2(0, 0x11, &inet)).await;
+    assert_eq!(address.unwrap(), None);
+    // so does any family other than IPv4 and IPv6
+    let (address, _) = header(&v2(1, 0x31, &[0; 216])).await;
+    assert_eq!(address.unwrap(), None);
+}
+
+#[tokio::test]
+async fn truncated_and_oversize_headers_are_refused() {
+    // addresses shorter than their family
+    let (address, _) = header(&v2(1, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1])).await;
+    assert!(address.is_err());
+    let (address, _) = header(&v2(1, 0x21, &[0; 12])).await;
+    assert!(address.is_err());
+    // the connection ends before the addresses announced
+    let mut bytes = v2(1, 0x11, &[0; 12]);
+    bytes.truncate(bytes.len() - 4);
+    assert!(header(&bytes).await.0.is_err());
+    // or before the line ends
+    assert!(header(b"PROXY TCP4 192.0.2.1 198.51.100.1").await.0.is_err());
+    assert!(header(b"PROXY").await.0.is_err());
+
+    let mut long = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443".to_vec();
+    long.resize(200, b' ');
+    long.extend(b"\r\n");
+    let (address, rest) = header(&long).await;
+    assert!(address.is_err());
+    assert!(!rest.is_empty(), "read past the longest header there is");
+}
+
+#[tokio::test]
+async fn connections_without_a_valid_header_are_refused() {
+    assert!(header(b"GET / HTTP/1.1\r\n\r\n").await.0.is_err());
+    assert!(header(b"PROXY TCP4 192.0.2.1\r\n").await.0.is_err());
+    assert!(header(b"PROXY TCP4 192.0.2.x 198.51.100.1 56324 443\r\n").await.0.is_err());
+    let mut version_1 = v2(1, 0x11, &[0; 12]);
+    version_1[12] = 0x11;
+    assert!(header(&version_1).await.0.is_err());
+}
+
+fn trusting(networks: &[&str]) -> ProxyConfig {
+    ProxyConfig {
+        protocol: false,
+        trusted: networks.iter().map(|net| parse_network(net).unwrap()).collect(),
+    }
+}
+
+fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        headers.append(*name, HeaderValue::from_static(value));
+    }
+    headers
+}
+
+fn address(address: &str) -> SocketAddr {
+    address.parse().unwrap()
+}
+
+#[test]
+fn forwarded_headers_of_untrusted_peers_are_ignored() {
+    let config = trusting(&["10.0.0.0/8"]);
+    let peer = address("192.0.2.1:4711");
+    let forwarded = headers(&[("x-forwarded-for", "203.0.113.7")]);
+    assert_eq!(config.client_address(peer, &forwarded), peer);
+}
+
+#[test]
+fn the_client_is_the_last_hop_not_trusted() {
+    let config = trusting(&["10.0.0.0/8", "127.0.0.1"]);
+    let peer = address("127.0.0.1:4711");
+    let forwarded = headers(&[("x-forwarded-for", "203.0.113.7, 10.0.0.2")]);
+    assert_eq!(config.client_address(peer, &forwarded), address("203.0.113.7:0"));
+    // hops made up by the client before its own are skipped
+    let spoofed = headers(&[
+        ("x-forwarded-for", "10.0.0.9, 198.51.100.3"),
+        ("x-forwarded-for", "203.0.113.7:1234, 10.0.0.2"),
+    ]);
+    assert_eq!(config.client_address(peer, &spoofed), address("203.0.113.7:1234"));
+    // nobody but trusted proxies on the way, the first of them is the client
+    let internal = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+    assert_eq!(config.client_address(peer, &internal), address("10.0.0.3:0"));
+    assert_eq!(config.client_address(peer, &HeaderMap::new()), peer);
+}
+
+#[test]
+fn forwarded_takes_precedence_over_x_forwarded_for() {
+    let config = trusting(&["10.0.0.0/8"]);
+    let peer = address("10.0.0.1:4711");
+    let forwarded = headers(&[
+        ("forwarded", "for=192.0.2.43, For=\"[2001:db8:cafe::17]:4711\";proto=https"),
+        ("forwarded", "for=10.0.0.2;by=10.0.0.1"),
+        ("x-forwarded-for", "203.0.113.7"),
+    ]);
+    let client = config.client_address(peer, &forwarded);
+    assert_eq!(client, address("[2001:db8:cafe::17]:4711"));
+    // whoever went unrecorded is not to be confused with a hop before it
+    let unknown = headers(&[("forwarded", "for=192.0.2.43, for=unknown, for=10.0.0.2")]);
+    assert_eq!(config.client_address(peer, &unknown), peer);
+}
@@ -0,0 +1,57 @@
+//! Terminals read and written alike whichever way the build takes, through epoll or with the
+//! `uring` feature through io_uring: `cargo test --features uring --test pty`
+use sh_over_ws_actuator::{
+    command::{Environment, RunCommand, Sandbox},
+    os_io::{Pty, PtySize},
+};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time,
+};
+
+fn spawn(script: &str) -> Pty {
+    let command = RunCommand {
+        command: "/bin/sh".into(),
+        args: vec!["-c".to_string(), script.to_string()],
+        ..RunCommand::default()
+    };
+    Pty::spawn(&command, &Environment::default(), &Sandbox::default(), PtySize::default()).unwrap()
+}
+
+async fn read_to_end(pty: &Pty) -> String {
+    let mut output = Vec::new();
+    pty.reader().read_to_end(&mut output).await.unwrap();
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+#[tokio::test]
+async fn output_is_read_until_the_command_exits() {
+    let pty = spawn("seq 1 20000");
+    let output = read_to_end(&pty).await;
+    assert_eq!(output.lines().count(), 20000);
+    assert!(output.ends_with("19999\r\n20000\r\n"));
+}
+
+#[tokio::test]
+async fn input_reaches_the_command() {
+    let pty = spawn("read line; echo \"got $line\"");
+    let mut writer = pty.writer();
+    writer.write_all(b"hello\n").await.unwrap();
+    writer.flush().await.unwrap();
+    let output = read_to_end(&pty).await;
+    assert!(output.contains("got hello"), "{:?}", output);
+}
+
+#[tokio::test]
+async fn dropping_a_waiting_reader_leaves_the_terminal_usable() {
+    let pty = spawn("read line; echo \"got $line\"");
+    let mut reader = pty.reader();
+    let mut buf = [0; 64];
+    let waited = time::timeout(Duration::from_millis(100), reader.read(&mut buf)).await;
+    assert!(waited.is_err(), "nothing to read yet");
+    drop(reader);
+    pty.writer().write_all(b"hello\n").await.unwrap();
+    let output = read_to_end(&pty).await;
+    assert!(output.contains("got hello"), "{:?}", output);
+}
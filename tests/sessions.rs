@@ -0,0 +1,767 @@
+//! Sessions driven the way a connection drives them, with their commands on fake terminals
+//! instead of forked shells: what a test scripts is the output of the command, what the session
+//! writes to the command reaches the test, and the test decides when and how the command exits.
+use anyhow::{anyhow, Context, Result};
+use hyper::body::HttpBody;
+use nix::{
+    sys::termios::Termios,
+    unistd::{self, Pid, User},
+};
+use sh_over_ws_actuator::{
+    admin::{self, AdminState},
+    audit::{AuditLog, Client},
+    command::{Environment, RunCommand, Sandbox},
+    config::{AdminConfig, AuditConfig, Config},
+    data::{AttachRole, ColorDepth, Message, Payload, SessionId, TerminalInfo, WindowSize},
+    error::{ErrorCode, ProtocolError},
+    os_io::{
+        PtySize, Spawn, SpawnFailed, Terminal, TerminalReader, TerminalWriter, TermiosBuilder,
+    },
+    redact::RedactionConfig,
+    schedule::Due,
+    session::{SessionManager, SessionRegistry},
+    terminfo::FALLBACKS,
+};
+use std::{
+    future::Future,
+    io,
+    os::unix::process::ExitStatusExt,
+    pin::Pin,
+    process::ExitStatus,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{mpsc, watch, Semaphore},
+    time,
+};
+
+/// What the command of a fake terminal outputs, an error to fail the read with or the end of
+/// its output once the sender is dropped
+type Output = io::Result<Vec<u8>>;
+
+/// A terminal with nothing running on it, played by a [`FakeCommand`]
+struct FakePty {
+    output: Mutex<Option<mpsc::UnboundedReceiver<Output>>>,
+    input: mpsc::UnboundedSender<Vec<u8>>,
+    broken: Arc<AtomicBool>,
+    sizes: Arc<Mutex<Vec<PtySize>>>,
+    exit: Arc<watch::Sender<Option<ExitStatus>>>,
+}
+
+/// The test's end of a [`FakePty`]
+struct FakeCommand {
+    command: RunCommand,
+    env: Environment,
+    output: Option<mpsc::UnboundedSender<Output>>,
+    input: mpsc::UnboundedReceiver<Vec<u8>>,
+    broken: Arc<AtomicBool>,
+    sizes: Arc<Mutex<Vec<PtySize>>>,
+    exit: Arc<watch::Sender<Option<ExitStatus>>>,
+}
+
+impl FakeCommand {
+    fn new(command: RunCommand, env: Environment) -> (FakeCommand, FakePty) {
+        let (output, outputs) = mpsc::unbounded_channel();
+        let (inputs, input) = mpsc::unbounded_channel();
+        let broken = Arc::new(AtomicBool::new(false));
+        let sizes = Arc::new(Mutex::new(vec![]));
+        let exit = Arc::new(watch::channel(None).0);
+        let pty = FakePty {
+            output: Mutex::new(Some(outputs)),
+            input: inputs,
+            broken: broken.clone(),
+            sizes: sizes.clone(),
+            exit: exit.clone(),
+        };
+        let command = FakeCommand {
+            command,
+            env,
+            output: Some(output),
+            input,
+            broken,
+            sizes,
+            exit,
+        };
+        (command, pty)
+    }
+
+    fn print(&self, data: &str) {
+        let output = self.output.as_ref().expect("output already closed");
+        output.send(Ok(data.as_bytes().to_vec())).unwrap();
+    }
+
+    /// Fail the next read of the output
+    fn fail_read(&self, kind: io::ErrorKind) {
+        let output = self.output.as_ref().expect("output already closed");
+        output.send(Err(kind.into())).unwrap();
+    }
+
+    fn close_output(&mut self) {
+        self.output = None;
+    }
+
+    /// Fail writes to the terminal from now on
+    fn break_input(&self) {
+        self.broken.store(true, Ordering::SeqCst);
+    }
+
+    async fn typed(&mut self) -> Vec<u8> {
+        time::timeout(Duration::from_secs(5), self.input.recv())
+            .await
+            .expect("no input")
+            .expect("terminal gone")
+    }
+
+    fn exit(&mut self, status: ExitStatus) {
+        self.close_output();
+        self.exit.send_replace(Some(status));
+    }
+}
+
+impl Drop for FakePty {
+    fn drop(&mut self) {
+        // like a real one hangs its command up
+        self.exit.send_if_modified(|exit| {
+            exit.get_or_insert(ExitStatus::from_raw(libc::SIGHUP));
+            true
+        });
+    }
+}
+
+impl Terminal for FakePty {
+    fn pid(&self) -> Pid {
+        // beyond any pid the kernel hands out, signals to it fail
+        Pid::from_raw(i32::MAX)
+    }
+
+    fn reader(&self) -> TerminalReader {
+        let output = self.output.lock().unwrap().take();
+        Box::new(FakeReader {
+            output,
+            left: vec![],
+        })
+    }
+
+    fn writer(&self) -> TerminalWriter {
+        Box::new(FakeWriter {
+            input: self.input.clone(),
+            broken: self.broken.clone(),
+        })
+    }
+
+    fn resize(&self, size: PtySize) -> Result<()> {
+        size.check()?;
+        self.sizes.lock().unwrap().push(size);
+        Ok(())
+    }
+
+    fn termios(&self) -> Result<TermiosBuilder> {
+        Err(anyhow!("fake terminals have no attributes"))
+    }
+
+    fn set_termios(&self, _termios: &Termios) -> Result<()> {
+        Err(anyhow!("fake terminals have no attributes"))
+    }
+
+    fn foreground_process_group(&self) -> Option<Pid> {
+        None
+    }
+
+    fn try_exit_status(&self) -> Option<ExitStatus> {
+        *self.exit.borrow()
+    }
+
+    fn exited(&self) -> Pin<Box<dyn Future<Output = Option<ExitStatus>> + Send>> {
+        let mut exit = self.exit.subscribe();
+        Box::pin(async move { *exit.wait_for(Option::is_some).await.ok()? })
+    }
+}
+
+struct FakeReader {
+    /// `None` for readers after the first, which see the end right away
+    output: Option<mpsc::UnboundedReceiver<Output>>,
+    left: Vec<u8>,
+}
+
+impl AsyncRead for FakeReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.left.is_empty() {
+            let Some(output) = this.output.as_mut() else {
+                return Poll::Ready(Ok(()));
+            };
+            match output.poll_recv(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(Some(Ok(data))) => this.left = data,
+            }
+        }
+        let n = this.left.len().min(buf.remaining());
+        buf.put_slice(&this.left[..n]);
+        this.left.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct FakeWriter {
+    input: mpsc::UnboundedSender<Vec<u8>>,
+    broken: Arc<AtomicBool>,
+}
+
+impl AsyncWrite for FakeWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.broken.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let sent = self.input.send(buf.to_vec());
+        Poll::Ready(sent.map(|_| buf.len()).map_err(|_| io::ErrorKind::BrokenPipe.into()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Hands the test a [`FakeCommand`] for every command started, fails those named `missing`
+struct FakeSpawner {
+    spawned: mpsc::UnboundedSender<FakeCommand>,
+}
+
+impl Spawn for FakeSpawner {
+    fn spawn(
+        &self,
+        cmd: &RunCommand,
+        env: &Environment,
+        _sandbox: &Sandbox,
+        size: PtySize,
+    ) -> Result<Box<dyn Terminal>> {
+        if cmd.command.ends_with("missing") {
+            return Err(anyhow!("no such command"))
+                .context(SpawnFailed { command: cmd.to_string() });
+        }
+        let (command, pty) = FakeCommand::new(cmd.clone(), env.clone());
+        pty.resize(size)?;
+        let _ = self.spawned.send(command);
+        Ok(Box::new(pty))
+    }
+}
+
+/// A connection's sessions, with what they send to the client and the commands they start
+struct Harness {
+    manager: SessionManager,
+    config: Arc<Config>,
+    registry: Arc<SessionRegistry>,
+    events: mpsc::UnboundedReceiver<Message>,
+    spawned: mpsc::UnboundedReceiver<FakeCommand>,
+    _due: mpsc::UnboundedReceiver<Due>,
+}
+
+impl Harness {
+    fn new() -> Self {
+        Harness::with_config(Config::default())
+    }
+
+    fn with_config(config: Config) -> Self {
+        Harness::with_client(config, Client::new("127.0.0.1:40000".parse().unwrap()))
+    }
+
+    /// The connection of `client`
+    fn with_client(config: Config, client: Client) -> Self {
+        let (spawner, spawned) = mpsc::unbounded_channel();
+        let registry = SessionRegistry::new(None, None)
+            .with_spawner(Arc::new(FakeSpawner { spawned: spawner }));
+        Harness::connect(Arc::new(config), Arc::new(registry), client, spawned)
+    }
+
+    /// Another connection to the sessions of this one, of `client`. The commands it starts
+    /// reach this harness.
+    fn join(&self, client: Client) -> Self {
+        let (_, spawned) = mpsc::unbounded_channel();
+        Harness::connect(self.config.clone(), self.registry.clone(), client, spawned)
+    }
+
+    fn connect(
+        config: Arc<Config>,
+        registry: Arc<SessionRegistry>,
+        client: Client,
+        spawned: mpsc::UnboundedReceiver<FakeCommand>,
+    ) -> Self {
+        let audit = AuditLog::open(&AuditConfig::default(), &RedactionConfig::default()).unwrap();
+        let (events, received) = mpsc::unbounded_channel();
+        let (due, due_received) = mpsc::unbounded_channel();
+        let manager = SessionManager::new(
+            config.clone(),
+            registry.clone(),
+            Arc::new(audit),
+            client,
+            events,
+            due,
+        );
+        Harness {
+            manager,
+            config,
+            registry,
+            events: received,
+            spawned,
+            _due: due_received,
+        }
+    }
+
+    async fn send(&self, message: Message) {
+        self.manager.handle_message(message).await;
+    }
+
+    /// The next message for the client
+    async fn next(&mut self) -> Message {
+        time::timeout(Duration::from_secs(5), self.events.recv())
+            .await
+            .expect("no message for the client")
+            .expect("connection gone")
+    }
+
+    /// Open session `id` running `command`, returning the fake it runs on
+    async fn open(&mut self, id: SessionId, command: &str) -> FakeCommand {
+        self.start(id, open(id, command)).await
+    }
+
+    /// Open session `id` with `message`, returning the fake its command runs on
+    async fn start(&mut self, id: SessionId, message: Message) -> FakeCommand {
+        self.send(message).await;
+        match self.next().await {
+            Message::Opened { session, .. } => assert_eq!(session, id),
+            message => panic!("not opened: {:?}", message),
+        }
+        self.spawned.try_recv().expect("nothing spawned")
+    }
+
+    /// Everything the session output until it exited, and how it exited
+    async fn output_until_exit(&mut self, id: SessionId) -> (String, Message) {
+        let mut output = vec![];
+        loop {
+            match self.next().await {
+                Message::Output { session, data, .. } if session == id => {
+                    output.extend_from_slice(&data.0)
+                },
+                exit @ Message::Exit { .. } => {
+                    return (String::from_utf8(output).unwrap(), exit);
+                },
+                message => panic!("unexpected message: {:?}", message),
+            }
+        }
+    }
+}
+
+fn session(n: u128) -> SessionId {
+    SessionId::from_u128(n)
+}
+
+fn open(id: SessionId, command: &str) -> Message {
+    Message::Open {
+        session: id,
+        command: Some(RunCommand {
+            command: command.into(),
+            ..RunCommand::default()
+        }),
+        profile: None,
+        size: None,
+        terminal: None,
+        cwd: None,
+        env: Default::default(),
+        clear_env: false,
+        strip_env: vec![],
+        timeout: None,
+        raw_output: false,
+        strip_ansi: false,
+        clipboard: false,
+        meta: false,
+        strip_meta: false,
+    }
+}
+
+#[tokio::test]
+async fn scripted_output_reaches_the_client_until_the_command_exits() {
+    let mut harness = Harness::new();
+    let mut command = harness.open(session(1), "/bin/fake").await;
+    assert_eq!(command.command.command.to_str(), Some("/bin/fake"));
+    command.print("hello ");
+    command.print("world\r\n");
+    command.exit(ExitStatus::from_raw(3 << 8));
+
+    let (output, exit) = harness.output_until_exit(session(1)).await;
+    assert_eq!(output, "hello world\r\n");
+    let Message::Exit { code, signal, .. } = exit else {
+        unreachable!()
+    };
+    assert_eq!((code, signal), (Some(3), None));
+}
+
+#[tokio::test]
+async fn output_keeps_counting_across_frames() {
+    let mut harness = Harness::new();
+    let command = harness.open(session(1), "/bin/fake").await;
+    command.print("one");
+    let Message::Output { seq, .. } = harness.next().await else {
+        panic!("no output");
+    };
+    assert_eq!(seq, 3);
+    command.print("three");
+    let Message::Output { data, seq, .. } = harness.next().await else {
+        panic!("no output");
+    };
+    assert_eq!((data, seq), (Payload::from("three"), 8));
+}
+
+#[tokio::test]
+async fn input_and_resizes_reach_the_terminal() {
+    let mut harness = Harness::new();
+    let mut command = harness.open(session(1), "/bin/fake").await;
+    harness
+        .send(Message::Input {
+            session: session(1),
+            data: Payload::from("ls\r"),
+        })
+        .await;
+    assert_eq!(command.typed().await, b"ls\r");
+
+    let size = WindowSize {
+        cols: 132,
+        rows: 43,
+        width_in_pixels: None,
+        height_in_pixels: None,
+    };
+    harness
+        .send(Message::Resize {
+            session: session(1),
+            size,
+        })
+        .await;
+    assert_eq!(
+        *command.sizes.lock().unwrap(),
+        [PtySize::default(), PtySize::from(size)]
+    );
+}
+
+#[tokio::test]
+async fn failing_input_is_reported_for_the_session() {
+    let mut harness = Harness::new();
+    let command = harness.open(session(1), "/bin/fake").await;
+    command.break_input();
+    harness
+        .send(Message::Input {
+            session: session(1),
+            data: Payload::from("ls\r"),
+        })
+        .await;
+    match harness.next().await {
+        Message::Error { session, .. } => assert_eq!(session, Some(self::session(1))),
+        message => panic!("no error: {:?}", message),
+    }
+}
+
+#[tokio::test]
+async fn a_failing_read_ends_the_output() {
+    let mut harness = Harness::new();
+    let command = harness.open(session(1), "/bin/fake").await;
+    command.print("partial");
+    command.fail_read(io::ErrorKind::ConnectionReset);
+    // still running when its output failed, the session hangs it up
+    let (output, exit) = harness.output_until_exit(session(1)).await;
+    assert_eq!(output, "partial");
+    let Message::Exit { code, signal, .. } = exit else {
+        unreachable!()
+    };
+    assert_eq!(code, None);
+    assert_eq!(signal.map(|signal| signal.number), Some(libc::SIGHUP));
+}
+
+#[tokio::test]
+async fn commands_that_fail_to_start_are_reported() {
+    let mut harness = Harness::new();
+    harness.send(open(session(1), "/bin/missing")).await;
+    match harness.next().await {
+        Message::Error { session, error } => {
+            assert_eq!(session, Some(self::session(1)));
+            assert_eq!(error.code, ErrorCode::SpawnFailed);
+        },
+        message => panic!("no error: {:?}", message),
+    }
+    assert!(harness.spawned.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn sessions_are_independent() {
+    let mut harness = Harness::new();
+    let mut first = harness.open(session(1), "/bin/fake").await;
+    let mut second = harness.open(session(2), "/bin/fake").await;
+    second.print("second");
+    second.exit(ExitStatus::from_raw(0));
+    let (output, exit) = harness.output_until_exit(session(2)).await;
+    assert_eq!(output, "second");
+    assert_eq!(exit.session(), Some(session(2)));
+
+    first.print("first");
+    first.exit(ExitStatus::from_raw(0));
+    let (output, exit) = harness.output_until_exit(session(1)).await;
+    assert_eq!(output, "first");
+    assert_eq!(exit.session(), Some(session(1)));
+}
+
+/// `open` with the client on `terminal`, setting `env`
+fn open_on(id: SessionId, terminal: TerminalInfo, vars: &[(&str, &str)]) -> Message {
+    let mut message = open(id, "/bin/fake");
+    if let Message::Open {
+        terminal: advertised,
+        env,
+        ..
+    } = &mut message
+    {
+        *advertised = Some(terminal);
+        env.extend(vars.iter().map(|(name, value)| (name.to_string(), value.to_string())));
+    }
+    message
+}
+
+#[tokio::test]
+async fn the_terminal_of_the_client_sets_term() {
+    let mut harness = Harness::new();
+    let terminal = TerminalInfo {
+        term: "no-such-terminal".to_string(),
+        colors: Some(ColorDepth::TrueColor),
+    };
+    let command = harness.start(session(1), open_on(session(1), terminal, &[])).await;
+    let vars = &command.env.vars;
+    assert!(FALLBACKS.contains(&vars["TERM"].as_str()), "{:?}", vars);
+    assert_eq!(vars["COLORTERM"], "truecolor");
+
+    // what the client sets itself wins
+    let terminal = TerminalInfo {
+        term: "xterm-256color".to_string(),
+        colors: None,
+    };
+    let message = open_on(session(2), terminal, &[("TERM", "dumb")]);
+    let command = harness.start(session(2), message).await;
+    assert_eq!(command.env.vars["TERM"], "dumb");
+    assert!(!command.env.vars.contains_key("COLORTERM"));
+}
+
+#[tokio::test]
+async fn login_profiles_start_login_shells_of_the_user() {
+    let mut config = Config::default();
+    let profile = "cmd = '/bin/bash'\nlogin = { shell = true, user_env = true }";
+    config
+        .profiles
+        .insert("login".to_string(), toml::from_str(profile).unwrap());
+    let mut harness = Harness::with_config(config);
+    let mut message = open(session(1), "/bin/fake");
+    if let Message::Open {
+        command,
+        profile,
+        env,
+        ..
+    } = &mut message
+    {
+        *command = None;
+        *profile = Some("login".to_string());
+        env.insert("HOME".to_string(), "/nowhere".to_string());
+    }
+    let command = harness.start(session(1), message).await;
+    assert_eq!(command.command.arg0.as_deref(), Some("-bash"));
+    let user = User::from_uid(unistd::getuid()).unwrap().unwrap();
+    let vars = &command.env.vars;
+    assert_eq!(vars["HOME"], user.dir.to_str().unwrap());
+    assert_eq!((&vars["USER"], &vars["LOGNAME"]), (&user.name, &user.name));
+}
+
+/// A client that authenticated as `user`
+fn user(user: &str, port: u16) -> Client {
+    let mut client = Client::new(([127, 0, 0, 1], port).into());
+    client.user = Some(user.to_string());
+    client
+}
+
+/// The error the client was sent next
+async fn error(harness: &mut Harness) -> ProtocolError {
+    match harness.next().await {
+        Message::Error { error, .. } => error,
+        message => panic!("no error: {:?}", message),
+    }
+}
+
+#[tokio::test]
+async fn restricted_users_get_neither_the_shell_nor_profiles_outside_their_policy() {
+    let mut config = Config::default();
+    let policy = "subject = 'alice'\ncommands = { allow = ['/usr/bin/uptime'] }";
+    config.policies.push(toml::from_str(policy).unwrap());
+    config
+        .profiles
+        .insert("bash".to_string(), toml::from_str("cmd = '/bin/bash'").unwrap());
+    let mut harness = Harness::with_client(config, user("alice", 40000));
+    let mut message = open(session(1), "/bin/fake");
+    if let Message::Open { command, .. } = &mut message {
+        *command = None;
+    }
+    harness.send(message.clone()).await;
+    assert_eq!(error(&mut harness).await.code, ErrorCode::PolicyViolation);
+    if let Message::Open { profile, .. } = &mut message {
+        *profile = Some("bash".to_string());
+    }
+    harness.send(message).await;
+    assert_eq!(error(&mut harness).await.code, ErrorCode::PolicyViolation);
+    assert!(harness.spawned.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn remote_profiles_are_held_to_the_working_directories_of_user_policies() {
+    let mut config = Config::default();
+    let policy = "subject = 'alice'\ncwd = ['/srv/alice', '/srv/alice/*']";
+    config.policies.push(toml::from_str(policy).unwrap());
+    let profile = "cmd = '/bin/bash'\nbackend = 'docker'\ncontainer = 'web'";
+    config
+        .profiles
+        .insert("web".to_string(), toml::from_str(profile).unwrap());
+    let mut harness = Harness::with_client(config, user("alice", 40000));
+    let mut message = open(session(1), "/bin/fake");
+    let mut open_in = |cwd: &str| {
+        if let Message::Open { command, profile, cwd: requested, .. } = &mut message {
+            *command = None;
+            *profile = Some("web".to_string());
+            *requested = Some(cwd.into());
+        }
+        message.clone()
+    };
+    for cwd in ["/etc", "/srv/alice/../bob", "/srv/bob"] {
+        harness.send(open_in(cwd)).await;
+        let error = error(&mut harness).await;
+        assert_eq!(error.code, ErrorCode::PolicyViolation, "{} allowed", cwd);
+    }
+    // there is no Docker to run it in, but the policy lets it through
+    harness.send(open_in("/srv/alice/src")).await;
+    assert_ne!(error(&mut harness).await.code, ErrorCode::PolicyViolation);
+}
+
+#[tokio::test]
+async fn only_clients_of_the_same_identity_attach_to_sessions() {
+    let mut alice = Harness::with_client(Config::default(), user("alice", 40000));
+    let _command = alice.open(session(1), "/bin/fake").await;
+    let mut mallory = alice.join(user("mallory", 40001));
+    for role in [AttachRole::Writer, AttachRole::Viewer] {
+        mallory
+            .send(Message::Attach { session: session(1), role })
+            .await;
+        assert_eq!(error(&mut mallory).await.code, ErrorCode::SessionNotFound);
+    }
+    mallory
+        .send(Message::TakeControl { session: session(1) })
+        .await;
+    error(&mut mallory).await;
+
+    // another connection of alice's may watch the session and take it over
+    let mut laptop = alice.join(user("alice", 40002));
+    laptop
+        .send(Message::Attach { session: session(1), role: AttachRole::Viewer })
+        .await;
+    assert!(matches!(laptop.next().await, Message::Opened { role: AttachRole::Viewer, .. }));
+    laptop
+        .send(Message::TakeControl { session: session(1) })
+        .await;
+    let taken = Message::RoleChanged { session: session(1), role: AttachRole::Writer };
+    assert_eq!(laptop.next().await, taken);
+
+    // operators watch whatever session they like
+    let mut operator = alice.join(Client::new("127.0.0.1:40003".parse().unwrap()));
+    operator.manager.watch(session(1), 0).unwrap();
+    assert!(matches!(operator.next().await, Message::Opened { role: AttachRole::Viewer, .. }));
+}
+
+#[tokio::test]
+async fn clients_refused_a_terminal_cannot_attach_to_one() {
+    let mut config = Config::default();
+    config
+        .policies
+        .push(toml::from_str("claims = { role = 'ci' }\npty = false").unwrap());
+    let mut alice = Harness::with_client(config, user("alice", 40000));
+    let _command = alice.open(session(1), "/bin/fake").await;
+    let mut ci = user("alice", 40001);
+    ci.claims = Some(serde_json::from_str(r#"{"role": "ci"}"#).unwrap());
+    let mut ci = alice.join(ci);
+    ci.send(Message::Attach { session: session(1), role: AttachRole::Viewer })
+        .await;
+    assert_eq!(error(&mut ci).await.code, ErrorCode::PolicyViolation);
+}
+
+#[tokio::test]
+async fn operators_stream_sessions_of_authenticated_clients() {
+    let listen = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = Config {
+        admin: Some(AdminConfig {
+            listen,
+            tokens: vec!["operator".to_string()],
+        }),
+        ..Config::default()
+    };
+    let mut client = Client::new("192.0.2.1:40000".parse().unwrap());
+    client.token = Some("sha256:0123".to_string());
+    let mut harness = Harness::with_client(config, client);
+    let mut command = harness.open(session(1), "/bin/fake").await;
+    command.print("hello\r\n");
+    let audit = AuditLog::open(&AuditConfig::default(), &RedactionConfig::default()).unwrap();
+    let state = AdminState {
+        config: harness.config.clone(),
+        registry: harness.registry.clone(),
+        audit: Arc::new(audit),
+        connections: Arc::new(Semaphore::new(1)),
+    };
+    admin::spawn(listen, state).unwrap();
+
+    let stream = |token: &str| {
+        hyper::Request::get(format!("http://{}/sessions/{}/stream", listen, session(1)))
+            .header("authorization", format!("Bearer {}", token))
+            .body(hyper::Body::empty())
+            .unwrap()
+    };
+    for guess in ["operato", "operator2", ""] {
+        let response = hyper::Client::new().request(stream(guess)).await.unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::UNAUTHORIZED, "'{}' let in", guess);
+    }
+    let response = hyper::Client::new().request(stream("operator")).await.unwrap();
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+    let mut body = response.into_body();
+    let mut events = String::new();
+    while !events.contains("data: hello") {
+        let chunk = time::timeout(Duration::from_secs(5), body.data())
+            .await
+            .expect("no output streamed")
+            .unwrap()
+            .unwrap();
+        events.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+    assert!(events.starts_with("event: output\n"), "{}", events);
+    command.exit(ExitStatus::from_raw(0));
+    let rest = time::timeout(Duration::from_secs(5), hyper::body::to_bytes(body))
+        .await
+        .expect("stream not ended")
+        .unwrap();
+    assert!(std::str::from_utf8(&rest).unwrap().contains("event: exit"));
+}
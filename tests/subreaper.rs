@@ -0,0 +1,68 @@
+//! What commands leave behind: orphans reaped by the test process as their subreaper, background
+//! jobs going along with the command
+use sh_over_ws_actuator::{
+    command::{Environment, RunCommand, Sandbox},
+    os_io::{Exec, Pty, PtySize},
+    subreaper,
+};
+use std::{path::Path, time::Duration};
+use tokio::{io::AsyncReadExt, time};
+
+fn script(script: &str) -> RunCommand {
+    RunCommand {
+        command: "/bin/sh".into(),
+        args: vec!["-c".to_string(), script.to_string()],
+        ..RunCommand::default()
+    }
+}
+
+/// Whether process `pid` is gone within a few seconds, zombies count as still there
+async fn gone(pid: u32) -> bool {
+    let proc = format!("/proc/{}", pid);
+    for _ in 0..100 {
+        if !Path::new(&proc).exists() {
+            return true;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    false
+}
+
+#[tokio::test]
+async fn orphaned_grandchildren_are_reaped() {
+    subreaper::start().unwrap();
+    let command = script("sleep 0.2 >/dev/null & echo $!");
+    let mut exec = Exec::spawn(&command, &Environment::default(), &Sandbox::default(), false)
+        .unwrap();
+    let mut output = String::new();
+    exec.take_stdout()
+        .unwrap()
+        .read_to_string(&mut output)
+        .await
+        .unwrap();
+    exec.exited().await;
+    // the shell is gone, nothing but the test process could reap the sleep
+    let orphan = output.trim().parse().unwrap();
+    assert!(gone(orphan).await, "orphan {} not reaped", orphan);
+}
+
+#[tokio::test]
+async fn background_jobs_go_along_with_the_command() {
+    subreaper::start().unwrap();
+    // job control puts the job in a process group of its own
+    let command = script("set -m; sleep 100 >/dev/null 2>&1 & echo $!; sleep 100");
+    let sandbox = Sandbox::default();
+    let pty = Pty::spawn(&command, &Environment::default(), &sandbox, PtySize::default()).unwrap();
+    let mut reader = pty.reader();
+    let mut output = Vec::new();
+    while !output.ends_with(b"\n") {
+        let mut buf = [0; 64];
+        let read = reader.read(&mut buf).await.unwrap();
+        assert!(read > 0, "no pid in {:?}", output);
+        output.extend_from_slice(&buf[..read]);
+    }
+    let job = String::from_utf8(output).unwrap().trim().parse().unwrap();
+    drop(reader);
+    drop(pty);
+    assert!(gone(job).await, "background job {} still running", job);
+}
@@ -0,0 +1,64 @@
+//! `TERM` of sessions, negotiated against terminfo directories made up for the tests
+use sh_over_ws_actuator::{
+    data::{ColorDepth, TerminalInfo},
+    terminfo::{environment, has_entry, negotiate},
+};
+use std::{fs, path::PathBuf};
+use tempfile::TempDir;
+
+/// A terminfo directory with `entries`, each a path below it
+fn terminfo(entries: &[&str]) -> (TempDir, Vec<PathBuf>) {
+    let dir = tempfile::tempdir().unwrap();
+    for entry in entries {
+        let path = dir.path().join(entry);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"").unwrap();
+    }
+    let dirs = vec![dir.path().to_path_buf()];
+    (dir, dirs)
+}
+
+#[test]
+fn entries_are_found_by_their_first_letter_or_its_code() {
+    let (_dir, dirs) = terminfo(&["x/xterm-kitty", "61/alacritty"]);
+    assert!(has_entry(&dirs, "xterm-kitty"));
+    assert!(has_entry(&dirs, "alacritty"));
+    assert!(!has_entry(&dirs, "xterm"));
+    assert!(!has_entry(&[], "xterm-kitty"));
+}
+
+#[test]
+fn names_leaving_the_directory_are_refused() {
+    let (_dir, dirs) = terminfo(&["x/x/xterm", "./.hidden"]);
+    assert!(!has_entry(&dirs, "x/xterm"));
+    assert!(!has_entry(&dirs, ".hidden"));
+    assert!(!has_entry(&dirs, ""));
+}
+
+#[test]
+fn unknown_terminals_fall_back() {
+    let (_dir, dirs) = terminfo(&["x/xterm-kitty", "x/xterm-256color", "v/vt100"]);
+    assert_eq!(negotiate("xterm-kitty", &dirs), "xterm-kitty");
+    assert_eq!(negotiate("exotic", &dirs), "xterm-256color");
+
+    let (_dir, dirs) = terminfo(&["v/vt100"]);
+    assert_eq!(negotiate("exotic", &dirs), "vt100");
+    // nothing to check against at all
+    assert_eq!(negotiate("exotic", &[]), "vt100");
+}
+
+#[test]
+fn terminals_showing_24_bit_colors_say_so() {
+    let (_dir, dirs) = terminfo(&["x/xterm-256color"]);
+    let mut terminal = TerminalInfo {
+        term: "xterm-256color".to_string(),
+        colors: Some(ColorDepth::Ansi256),
+    };
+    let vars = environment(&terminal, &dirs);
+    assert_eq!(vars.len(), 1);
+    assert_eq!(vars["TERM"], "xterm-256color");
+
+    terminal.colors = Some(ColorDepth::TrueColor);
+    let vars = environment(&terminal, &dirs);
+    assert_eq!(vars["COLORTERM"], "truecolor");
+}
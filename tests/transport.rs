@@ -0,0 +1,156 @@
+//! The transports connections are served over, over in-memory streams: WebSockets talking to a
+//! tungstenite client and frames of byte streams talking to raw bytes or each other
+use futures_util::{SinkExt, StreamExt};
+use sh_over_ws_actuator::{
+    data::CloseReason,
+    transport::{Frame, Framed, Transport, WebSocket, MAX_FRAME_LEN},
+};
+use std::time::Duration;
+use tokio::{
+    io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+    time,
+};
+use tokio_tungstenite::{
+    tungstenite::{protocol::Role, Message as WsMessage},
+    WebSocketStream,
+};
+
+async fn connected() -> (WebSocket<DuplexStream>, WebSocketStream<DuplexStream>) {
+    let (server, client) = duplex(65536);
+    let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+    let client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+    (WebSocket::new(server), client)
+}
+
+#[tokio::test]
+async fn frames_pass_both_ways() {
+    let (mut server, mut client) = connected().await;
+    server.send(Frame::Text("{}".to_string())).await.unwrap();
+    server.send(Frame::Binary(vec![0x80])).await.unwrap();
+    assert_eq!(client.next().await.unwrap().unwrap(), WsMessage::Text("{}".to_string()));
+    assert_eq!(client.next().await.unwrap().unwrap(), WsMessage::Binary(vec![0x80]));
+
+    client.send(WsMessage::Binary(vec![1, 2, 3])).await.unwrap();
+    client.send(WsMessage::Pong(vec![7; 8])).await.unwrap();
+    assert_eq!(server.receive().await.unwrap(), Some(Frame::Binary(vec![1, 2, 3])));
+    assert_eq!(server.receive().await.unwrap(), Some(Frame::Pong(vec![7; 8])));
+}
+
+#[tokio::test]
+async fn closing_tells_the_reason() {
+    let (mut server, mut client) = connected().await;
+    server
+        .close(CloseReason::IdleTimeout, "nothing received for 60s".to_string())
+        .await
+        .unwrap();
+    let Some(Ok(WsMessage::Close(Some(close)))) = client.next().await else {
+        panic!("not closed");
+    };
+    assert_eq!(u16::from(close.code), CloseReason::IdleTimeout.code());
+    assert_eq!(close.reason, "nothing received for 60s");
+    // tungstenite answers the close on its own, once flushed
+    client.flush().await.unwrap();
+    assert_eq!(server.receive().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn peers_going_away_end_the_frames() {
+    let (mut server, client) = connected().await;
+    drop(client);
+    assert_eq!(server.receive().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn protocol_violations_close_the_connection() {
+    let (server, mut client) = duplex(65536);
+    let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+    let mut server = WebSocket::new(server);
+    // clients have to mask their frames
+    client.write_all(&[0x81, 0x01, b'a']).await.unwrap();
+    let error = server.receive().await.unwrap_err();
+    assert_eq!(error.reason, Some(CloseReason::ProtocolError));
+}
+
+type FramedDuplex = Framed<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>;
+
+fn framed(stream: DuplexStream) -> FramedDuplex {
+    let (reader, writer) = split(stream);
+    Framed::new(reader, writer)
+}
+
+#[tokio::test]
+async fn framed_frames_pass_both_ways() {
+    let (server, client) = duplex(65536);
+    let (mut server, mut client) = (framed(server), framed(client));
+    server.send(Frame::Text("{}".to_string())).await.unwrap();
+    server.send(Frame::Binary(Vec::new())).await.unwrap();
+    server.send(Frame::Ping(vec![7; 8])).await.unwrap();
+    assert_eq!(client.receive().await.unwrap(), Some(Frame::Text("{}".to_string())));
+    assert_eq!(client.receive().await.unwrap(), Some(Frame::Binary(Vec::new())));
+    assert_eq!(client.receive().await.unwrap(), Some(Frame::Ping(vec![7; 8])));
+
+    client.send(Frame::Pong(vec![7; 8])).await.unwrap();
+    assert_eq!(server.receive().await.unwrap(), Some(Frame::Pong(vec![7; 8])));
+}
+
+#[tokio::test]
+async fn framed_frames_are_kind_length_and_payload() {
+    let (server, mut client) = duplex(65536);
+    let mut server = framed(server);
+    server.send(Frame::Text("{}".to_string())).await.unwrap();
+    server
+        .close(CloseReason::IdleTimeout, "idle".to_string())
+        .await
+        .unwrap();
+    let mut bytes = Vec::new();
+    client.read_to_end(&mut bytes).await.unwrap();
+    let code = CloseReason::IdleTimeout.code().to_be_bytes();
+    let close = [&[4, 0, 0, 0, 6], &code[..], b"idle"].concat();
+    assert_eq!(bytes, [&[0, 0, 0, 0, 2, b'{', b'}'], &close[..]].concat());
+}
+
+#[tokio::test]
+async fn framed_closing_ends_the_frames() {
+    let (server, client) = duplex(65536);
+    let (mut server, mut client) = (framed(server), framed(client));
+    server
+        .close(CloseReason::Shutdown, "shutting down".to_string())
+        .await
+        .unwrap();
+    assert_eq!(client.receive().await.unwrap(), None);
+    drop(client);
+    assert_eq!(server.receive().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn framed_receiving_cancelled_midway_loses_nothing() {
+    let (server, mut client) = duplex(65536);
+    let mut server = framed(server);
+    client.write_all(&[1, 0, 0, 0, 4, 1, 2]).await.unwrap();
+    let waited = time::timeout(Duration::from_millis(50), server.receive()).await;
+    assert!(waited.is_err(), "frame not complete yet");
+    client.write_all(&[3, 4]).await.unwrap();
+    assert_eq!(server.receive().await.unwrap(), Some(Frame::Binary(vec![1, 2, 3, 4])));
+}
+
+#[tokio::test]
+async fn framed_violations_close_the_connection() {
+    let (server, mut client) = duplex(65536);
+    let mut server = framed(server);
+    let len = (MAX_FRAME_LEN as u32 + 1).to_be_bytes();
+    client.write_all(&[&[1], &len[..]].concat()).await.unwrap();
+    let error = server.receive().await.unwrap_err();
+    assert_eq!(error.reason, Some(CloseReason::PolicyViolation));
+
+    let (server, mut client) = duplex(65536);
+    let mut server = framed(server);
+    client.write_all(&[0, 0, 0, 0, 1, 0xff]).await.unwrap();
+    let error = server.receive().await.unwrap_err();
+    assert_eq!(error.reason, Some(CloseReason::ProtocolError));
+
+    let (server, mut client) = duplex(65536);
+    let mut server = framed(server);
+    client.write_all(&[9, 0, 0, 0, 0]).await.unwrap();
+    let error = server.receive().await.unwrap_err();
+    assert_eq!(error.reason, Some(CloseReason::ProtocolError));
+}
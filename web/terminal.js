@@ -0,0 +1,254 @@
+// Web terminal speaking the JSON flavour of the actuator's WebSocket protocol, see `src/data.rs`.
+// It opens a session running the server's default shell and resumes it whenever the connection
+// drops, the session and the output seen of it are kept for the tab across reloads.
+"use strict";
+
+const PROTOCOL_VERSION = 1;
+const SUBPROTOCOL = "shws.v1";
+// close codes of `CloseReason`
+const CLOSE_UNSUPPORTED_VERSION = 4000;
+const CLOSE_AUTH_FAILED = 4001;
+const INITIAL_BACKOFF = 1000;
+const MAX_BACKOFF = 30000;
+
+const term = new Terminal({ cursorBlink: true });
+const fit = new FitAddon.FitAddon();
+term.loadAddon(fit);
+term.open(document.getElementById("terminal"));
+fit.fit();
+term.focus();
+
+const statusLine = document.getElementById("status");
+const login = document.getElementById("login");
+const loginReason = document.getElementById("login-reason");
+const tokenInput = document.getElementById("token");
+
+let socket = null;
+let session = sessionStorage.getItem("shws.session");
+// output of the session seen so far, resumed from after reconnecting
+let seq = Number(sessionStorage.getItem("shws.seq") || 0);
+// whether the session is attached to the current connection, as its writer
+let attached = false;
+// set once the session exited or was taken over, the next key press starts or attaches again
+let waiting = null;
+let backoff = INITIAL_BACKOFF;
+let retry = null;
+
+function setStatus(text, connected = false) {
+  statusLine.textContent = text;
+  statusLine.classList.toggle("connected", connected);
+}
+
+function send(message) {
+  if (socket && socket.readyState === WebSocket.OPEN) {
+    socket.send(JSON.stringify(message));
+  }
+}
+
+// `crypto.randomUUID` is only there on HTTPS and localhost
+function uuid() {
+  if (crypto.randomUUID) {
+    return crypto.randomUUID();
+  }
+  const bytes = crypto.getRandomValues(new Uint8Array(16));
+  bytes[6] = (bytes[6] & 0x0f) | 0x40;
+  bytes[8] = (bytes[8] & 0x3f) | 0x80;
+  const hex = Array.from(bytes, (byte) => byte.toString(16).padStart(2, "0")).join("");
+  return [hex.slice(0, 8), hex.slice(8, 12), hex.slice(12, 16), hex.slice(16, 20), hex.slice(20)]
+    .join("-");
+}
+
+function remember() {
+  if (session) {
+    sessionStorage.setItem("shws.session", session);
+    sessionStorage.setItem("shws.seq", String(seq));
+  } else {
+    sessionStorage.removeItem("shws.session");
+    sessionStorage.removeItem("shws.seq");
+  }
+}
+
+function openSession() {
+  session = uuid();
+  seq = 0;
+  waiting = null;
+  remember();
+  term.reset();
+  send({
+    type: "open",
+    session,
+    size: { cols: term.cols, rows: term.rows },
+    // xterm.js emulates xterm, 24-bit colors included
+    terminal: { term: "xterm-256color", colors: "truecolor" },
+    meta: true,
+  });
+}
+
+function resumeSession() {
+  waiting = null;
+  send({ type: "resume_from", session, seq, role: "writer" });
+}
+
+// trades `token` for a ticket to connect with, `null` if the server issues none
+async function fetchTicket(token) {
+  const response = await fetch("/tickets", {
+    method: "POST",
+    headers: { Authorization: `Bearer ${token}` },
+  });
+  if (response.status === 404) {
+    return null;
+  }
+  if (!response.ok) {
+    throw new Error((await response.text()).trim() || response.statusText);
+  }
+  return (await response.json()).ticket;
+}
+
+function reconnectLater() {
+  setStatus(`disconnected, reconnecting in ${backoff / 1000}s`);
+  retry = setTimeout(connect, backoff);
+  backoff = Math.min(backoff * 2, MAX_BACKOFF);
+}
+
+async function connect() {
+  retry = null;
+  const url = new URL("/", location.href);
+  url.protocol = location.protocol === "https:" ? "wss:" : "ws:";
+  setStatus("connecting");
+  // browsers can't set the `Authorization` header of WebSockets, a ticket keeps the token out of
+  // the URL if the server issues them
+  const token = sessionStorage.getItem("shws.token");
+  if (token) {
+    try {
+      const ticket = await fetchTicket(token);
+      url.searchParams.set(ticket ? "ticket" : "token", ticket || token);
+    } catch (e) {
+      // failing to reach the server at all is no reason to ask for another token
+      if (!(e instanceof TypeError)) {
+        askForToken(`No ticket: ${e.message}`);
+      }
+      reconnectLater();
+      return;
+    }
+  }
+  let connected = false;
+  socket = new WebSocket(url, SUBPROTOCOL);
+  socket.onopen = () => {
+    connected = true;
+    backoff = INITIAL_BACKOFF;
+    login.hidden = true;
+    send({ type: "hello", version: PROTOCOL_VERSION, capabilities: ["resume", "meta"] });
+    if (session) {
+      resumeSession();
+    } else {
+      openSession();
+    }
+  };
+  socket.onmessage = (event) => handle(JSON.parse(event.data));
+  socket.onclose = (event) => {
+    attached = false;
+    if (event.code === CLOSE_UNSUPPORTED_VERSION) {
+      setStatus(`disconnected: ${event.reason}`);
+      return;
+    }
+    // rejected upgrades look like any other failure to connect, a missing token is likely
+    if (event.code === CLOSE_AUTH_FAILED) {
+      askForToken(`Disconnected: ${event.reason}`);
+    } else if (!connected && !sessionStorage.getItem("shws.token")) {
+      askForToken("The server may want a token.");
+    }
+    reconnectLater();
+  };
+}
+
+function askForToken(reason) {
+  loginReason.textContent = reason;
+  login.hidden = false;
+  tokenInput.focus();
+}
+
+login.addEventListener("submit", (event) => {
+  event.preventDefault();
+  sessionStorage.setItem("shws.token", tokenInput.value);
+  login.hidden = true;
+  backoff = INITIAL_BACKOFF;
+  clearTimeout(retry);
+  if (socket && socket.readyState !== WebSocket.CLOSED) {
+    socket.onclose = null;
+    socket.close();
+  }
+  connect();
+  term.focus();
+});
+
+function handle(message) {
+  switch (message.type) {
+    case "ping":
+      send({ type: "pong", nonce: message.nonce });
+      break;
+    case "opened":
+      attached = true;
+      setStatus("connected", true);
+      // the window may have changed while disconnected
+      send({ type: "resize", session, cols: term.cols, rows: term.rows });
+      break;
+    case "output":
+      term.write(message.data);
+      seq = message.seq;
+      remember();
+      break;
+    case "session_meta":
+      if (message.title) {
+        document.title = message.title;
+      }
+      break;
+    case "idle":
+      if (message.seconds > 0) {
+        term.write(`\r\n[idle, ${message.action} in ${message.seconds}s]\r\n`);
+      }
+      break;
+    case "exit": {
+      attached = false;
+      session = null;
+      remember();
+      const status = message.code !== undefined && message.code !== null
+        ? `exited with code ${message.code}`
+        : `killed by ${message.signal ? message.signal.name || message.signal.number : "a signal"}`;
+      term.write(`\r\n[${status}, press any key for a new session]\r\n`);
+      waiting = openSession;
+      break;
+    }
+    case "detached":
+      attached = false;
+      term.write("\r\n[taken over by another client, press any key to take it back]\r\n");
+      waiting = resumeSession;
+      break;
+    case "error":
+      if (message.session === session && !attached && message.code === "session_not_found") {
+        // the session ended while the connection was down
+        term.write("\r\n[session is gone, starting a new one]\r\n");
+        openSession();
+      } else {
+        term.write(`\r\n[error: ${message.message}]\r\n`);
+      }
+      break;
+  }
+}
+
+term.onData((data) => {
+  if (waiting) {
+    waiting();
+  } else if (attached) {
+    send({ type: "input", session, data });
+  }
+});
+
+term.onResize(({ cols, rows }) => {
+  if (attached) {
+    send({ type: "resize", session, cols, rows });
+  }
+});
+
+window.addEventListener("resize", () => fit.fit());
+
+connect();